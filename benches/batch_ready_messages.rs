@@ -0,0 +1,76 @@
+//! The proposals of 32 groups on a 3-node cluster over the local transport
+//! and the memory storage, with and without `batch_ready_messages`. Every
+//! iteration proposes one entry to each group on its leader concurrently, the
+//! batched messages of the readies are handed to the transport at once. Run it
+//! with `cargo bench --features test-util --bench batch_ready_messages`.
+use std::time::Duration;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+use futures::future::join_all;
+use smol_raft::multiraft::GroupId;
+use smol_raft::MultiRaftConfig;
+use tokio::runtime::Runtime;
+use tokio::sync::watch;
+
+#[path = "../tests/fixture/mod.rs"]
+mod fixture;
+
+use fixture::FixtureCluster;
+
+const GROUPS: u64 = 32;
+const NODES: u64 = 3;
+
+fn bench_batch_ready_messages(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("batch_ready_messages");
+    group.throughput(Throughput::Elements(GROUPS));
+    for batch in [false, true] {
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let (cluster, leaders) = rt.block_on(async {
+            let config = MultiRaftConfig {
+                election_tick: 2,
+                heartbeat_tick: 1,
+                manual_tick: true,
+                batch_ready_messages: batch,
+                ..Default::default()
+            };
+            let mut cluster = FixtureCluster::make_with_config(NODES, config, stop_rx).await;
+            let mut leaders = vec![];
+            for group_id in 1..=GROUPS {
+                cluster.make_group(group_id, 0, NODES as usize).await;
+                let leader_id = cluster
+                    .tick_until_leader(group_id, &[0, 1, 2])
+                    .await
+                    .unwrap();
+                leaders.push((group_id, leader_id as usize - 1));
+            }
+            cluster.ack_applies();
+            (cluster, leaders)
+        });
+
+        group.bench_with_input(BenchmarkId::new("batch", batch), &batch, |b, _| {
+            b.to_async(&rt).iter(|| async {
+                let proposals = leaders.iter().map(|(group_id, leader_index)| {
+                    cluster.multirafts[*leader_index].propose_timeout(
+                        GroupId(*group_id),
+                        vec![0],
+                        vec![],
+                        Duration::from_secs(5),
+                    )
+                });
+                for res in join_all(proposals).await {
+                    res.unwrap();
+                }
+            })
+        });
+        let _ = stop_tx.send(true);
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_batch_ready_messages);
+criterion_main!(benches);
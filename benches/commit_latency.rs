@@ -0,0 +1,107 @@
+//! The end-to-end latency of the proposals of one group, from proposing on
+//! the leader to the proposal completes after it's applied, on a 3-node
+//! cluster over the local transport and the memory storage. Run it with
+//! `cargo bench --features test-util --bench commit_latency`.
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+use futures::future::join_all;
+use smol_raft::multiraft::GroupId;
+use tokio::runtime::Runtime;
+use tokio::sync::watch;
+
+#[path = "../tests/fixture/mod.rs"]
+mod fixture;
+
+use fixture::FixtureCluster;
+
+const GROUP_ID: u64 = 1;
+const PAYLOAD_SIZES: [usize; 3] = [64, 4 * 1024, 64 * 1024];
+const CONCURRENCY: [usize; 3] = [1, 8, 64];
+
+/// Returns the `p`-th percentile of the sorted latencies.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((sorted.len() as f64 * p / 100.0).ceil() as usize).max(1);
+    sorted[rank.min(sorted.len()) - 1]
+}
+
+fn bench_commit_latency(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let (cluster, leader_index) = rt.block_on(async {
+        let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+        cluster.make_group(GROUP_ID, 0, 3).await;
+        let leader_id = cluster
+            .tick_until_leader(GROUP_ID, &[0, 1, 2])
+            .await
+            .unwrap();
+        cluster.ack_applies();
+        (cluster, leader_id as usize - 1)
+    });
+    let leader = &cluster.multirafts[leader_index];
+
+    let mut group = c.benchmark_group("commit_latency");
+    for payload in PAYLOAD_SIZES {
+        for concurrency in CONCURRENCY {
+            // the latency of each proposal, criterion only measures the time
+            // of the batch of the concurrent proposals.
+            let latencies = Mutex::new(vec![]);
+            group.throughput(Throughput::Elements(concurrency as u64));
+            group.bench_with_input(
+                BenchmarkId::new(format!("payload_{}", payload), concurrency),
+                &concurrency,
+                |b, &concurrency| {
+                    b.to_async(&rt).iter_custom(|iters| {
+                        let latencies = &latencies;
+                        async move {
+                            let start = Instant::now();
+                            for _ in 0..iters {
+                                let proposals = (0..concurrency).map(|_| async {
+                                    let start = Instant::now();
+                                    leader
+                                        .propose_timeout(
+                                            GroupId(GROUP_ID),
+                                            vec![0; payload],
+                                            vec![],
+                                            Duration::from_secs(5),
+                                        )
+                                        .await
+                                        .unwrap();
+                                    start.elapsed()
+                                });
+                                let elapsed = join_all(proposals).await;
+                                latencies.lock().unwrap().extend(elapsed);
+                            }
+                            start.elapsed()
+                        }
+                    })
+                },
+            );
+
+            let mut latencies = latencies.into_inner().unwrap();
+            latencies.sort_unstable();
+            println!(
+                "commit_latency/payload_{}/{}: {} proposals, p50 {:?}, p99 {:?}",
+                payload,
+                concurrency,
+                latencies.len(),
+                percentile(&latencies, 50.0),
+                percentile(&latencies, 99.0)
+            );
+        }
+    }
+    group.finish();
+    let _ = stop_tx.send(true);
+}
+
+criterion_group!(benches, bench_commit_latency);
+criterion_main!(benches);
@@ -0,0 +1,73 @@
+//! The steady-state replication of a group on the disk-backed segmented
+//! storage, every batch of entries is appended and read back right away to
+//! be sent to the followers. The reads are served by the entry cache if it's
+//! enabled, the segment reads are printed after each run. Run it with
+//! `cargo bench --bench entry_cache`.
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+use futures::executor::block_on;
+use smol_raft::proto::Entry;
+use smol_raft::storage::MultiRaftStorage;
+use smol_raft::storage::RaftStorage;
+use smol_raft::storage::SegmentConfig;
+use smol_raft::storage::SegmentedStorage;
+
+const GROUP_ID: u64 = 1;
+const BATCH: u64 = 16;
+const ENTRY_SIZE: usize = 256;
+// the entry cache is disabled by 0.
+const CACHE_SIZES: [usize; 2] = [0, 4 * 1024 * 1024];
+
+fn bench_entry_cache(c: &mut Criterion) {
+    let mut group = c.benchmark_group("entry_cache");
+    group.throughput(Throughput::Elements(BATCH));
+    for cache_size in CACHE_SIZES {
+        let dir = std::env::temp_dir().join(format!(
+            "smol-raft-bench-entry-cache-{}-{}",
+            cache_size,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let storage = SegmentedStorage::open(SegmentConfig::new(&dir)).unwrap();
+        let gs = block_on(storage.group_storage(GROUP_ID, 1))
+            .unwrap()
+            .with_entry_cache(cache_size);
+
+        let mut last_index = 0;
+        group.bench_with_input(
+            BenchmarkId::new("cache_size", cache_size),
+            &cache_size,
+            |b, _| {
+                b.iter(|| {
+                    let entries = (last_index + 1..=last_index + BATCH)
+                        .map(|index| Entry {
+                            index,
+                            term: 1,
+                            data: vec![0; ENTRY_SIZE],
+                            ..Default::default()
+                        })
+                        .collect();
+                    block_on(gs.append_entries(entries)).unwrap();
+                    last_index += BATCH;
+                    gs.entries(last_index + 1 - BATCH, last_index + 1, u64::MAX)
+                        .unwrap()
+                })
+            },
+        );
+
+        println!(
+            "entry_cache/cache_size/{}: {} entries appended, {} segment reads",
+            cache_size,
+            last_index,
+            storage.stats().reads
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_entry_cache);
+criterion_main!(benches);
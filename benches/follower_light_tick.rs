@@ -0,0 +1,78 @@
+//! The tick passes of 1000 idle groups on 3 nodes, whose leaders are all on
+//! the first node, with and without `follower_light_tick`. The light tick of
+//! a follower which hears from its leader skips raft and the bookkeeping, the
+//! cost of the last tick pass of a follower node is printed after each run.
+//! Run it with `cargo bench --features test-util --bench follower_light_tick`.
+use std::time::Duration;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+use smol_raft::MultiRaftConfig;
+use tokio::runtime::Runtime;
+use tokio::sync::watch;
+
+#[path = "../tests/fixture/mod.rs"]
+mod fixture;
+
+use fixture::FixtureCluster;
+
+const GROUPS: u64 = 1000;
+const NODES: u64 = 3;
+
+fn bench_follower_light_tick(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("follower_light_tick");
+    group.throughput(Throughput::Elements(GROUPS));
+    for light in [false, true] {
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let cluster = rt.block_on(async {
+            let config = MultiRaftConfig {
+                election_tick: 10,
+                heartbeat_tick: 2,
+                manual_tick: true,
+                follower_light_tick: light,
+                ..Default::default()
+            };
+            let mut cluster = FixtureCluster::make_with_config(NODES, config, stop_rx).await;
+            cluster.ack_applies();
+            for group_id in 1..=GROUPS {
+                // only the first replica campaigns, so the leaders are on the
+                // first node.
+                for i in 0..NODES as usize {
+                    cluster
+                        .make_group_replica(group_id, 0, NODES as usize, i, i == 0)
+                        .await;
+                }
+            }
+            for group_id in 1..=GROUPS {
+                cluster
+                    .tick_until_status(group_id, 1, |status| status.leader_id != 0)
+                    .await;
+            }
+            cluster
+        });
+
+        group.bench_with_input(BenchmarkId::new("light", light), &light, |b, _| {
+            b.to_async(&rt).iter(|| cluster.tick_all())
+        });
+
+        // the follower passes after the heartbeats of the bench are settled.
+        let health = rt.block_on(async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            cluster.tick_all().await;
+            cluster.multirafts[1].health().await
+        });
+        println!(
+            "follower_light_tick/light/{}: the last follower pass ticked {} groups in {:?}",
+            light, health.last_tick_groups, health.last_tick_cost
+        );
+        let _ = stop_tx.send(true);
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_follower_light_tick);
+criterion_main!(benches);
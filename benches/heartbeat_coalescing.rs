@@ -0,0 +1,83 @@
+//! The heartbeats of 1000 groups on a 3-node cluster over the local transport
+//! and the memory storage. Without the coalescing, every group sends one
+//! heartbeat to each follower and receives one response per heartbeat tick,
+//! the coalesced heartbeats are sent in at most one message per direction of
+//! each node pair. Run it with
+//! `cargo bench --features test-util --bench heartbeat_coalescing`.
+use std::time::Duration;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::Criterion;
+use criterion::Throughput;
+use smol_raft::multiraft::Transport;
+use smol_raft::proto::MessageType;
+use tokio::runtime::Runtime;
+use tokio::sync::watch;
+
+#[path = "../tests/fixture/mod.rs"]
+mod fixture;
+
+use fixture::FixtureCluster;
+
+const GROUPS: u64 = 1000;
+const NODES: u64 = 3;
+const ROUNDS: u64 = 10;
+
+fn bench_heartbeat_coalescing(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let cluster = rt.block_on(async {
+        let mut cluster = FixtureCluster::make_with_manual_tick(NODES, stop_rx).await;
+        cluster.ack_applies();
+        for group_id in 1..=GROUPS {
+            // only the first replica campaigns, so the votes aren't split.
+            for i in 0..NODES as usize {
+                cluster
+                    .make_group_replica(group_id, 0, NODES as usize, i, i == 0)
+                    .await;
+            }
+        }
+        for group_id in 1..=GROUPS {
+            cluster
+                .tick_until_status(group_id, 0, |status| status.leader_id != 0)
+                .await;
+        }
+        cluster
+    });
+
+    // the heartbeat tick is 1, every tick is a heartbeat round of all groups.
+    let mut group = c.benchmark_group("heartbeat_coalescing");
+    group.throughput(Throughput::Elements(GROUPS));
+    group.bench_function(format!("groups_{}", GROUPS), |b| {
+        b.to_async(&rt).iter(|| cluster.tick_all())
+    });
+    group.finish();
+
+    // count the messages of the heartbeat rounds after the bench is settled.
+    let (heartbeats, responses) = rt.block_on(async {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        cluster.transport.reset_stats();
+        for _ in 0..ROUNDS {
+            cluster.tick_all().await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let stats = cluster.transport.stats();
+        let count = |msg_type| stats.sent.get(&Some(msg_type)).copied().unwrap_or(0);
+        (
+            count(MessageType::MsgHeartbeat),
+            count(MessageType::MsgHeartbeatResponse),
+        )
+    });
+    let uncoalesced = GROUPS * (NODES - 1) * ROUNDS;
+    println!(
+        "heartbeat_coalescing/groups_{}: {} rounds, heartbeats {} (uncoalesced {}), \
+         responses {} (uncoalesced {})",
+        GROUPS, ROUNDS, heartbeats, uncoalesced, responses, uncoalesced
+    );
+    let _ = stop_tx.send(true);
+}
+
+criterion_group!(benches, bench_heartbeat_coalescing);
+criterion_main!(benches);
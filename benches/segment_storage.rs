@@ -0,0 +1,139 @@
+//! The segmented storage of many groups. The commit throughput of 100 groups
+//! with one fsync per write and with one fsync per round of writes of all
+//! groups, and the open time and the write amplification of 10k groups whose
+//! logs are compacted and rewritten. Run it with
+//! `cargo bench --bench segment_storage`.
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+use futures::executor::block_on;
+use smol_raft::proto::Entry;
+use smol_raft::proto::HardState;
+use smol_raft::storage::MultiRaftStorage;
+use smol_raft::storage::RaftStorage;
+use smol_raft::storage::SegmentConfig;
+use smol_raft::storage::SegmentedStorage;
+use smol_raft::storage::SyncPolicy;
+use smol_raft::storage::WriteBatch;
+use tokio::runtime::Runtime;
+
+const SYNC_GROUPS: u64 = 100;
+const OPEN_GROUPS: u64 = 10_000;
+const ENTRY_SIZE: usize = 64;
+
+fn bench_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "smol-raft-bench-segment-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+fn new_entry(index: u64) -> Entry {
+    Entry {
+        index,
+        term: 1,
+        data: vec![0; ENTRY_SIZE],
+        ..Default::default()
+    }
+}
+
+fn bench_batched_sync(c: &mut Criterion) {
+    // the fsync of `sync_writes` runs on the blocking thread of the runtime.
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("segment_storage_sync");
+    group.throughput(Throughput::Elements(SYNC_GROUPS));
+    for defer_sync in [false, true] {
+        let dir = bench_dir(&format!("sync-{}", defer_sync));
+        let mut config = SegmentConfig::new(&dir);
+        config.sync_policy = SyncPolicy::Always;
+        let storage = SegmentedStorage::open(config).unwrap();
+        let storages = (1..=SYNC_GROUPS)
+            .map(|group_id| block_on(storage.group_storage(group_id, 1)).unwrap())
+            .collect::<Vec<_>>();
+
+        // every iteration is a round of writes of all groups.
+        let mut last_index = 0;
+        group.bench_with_input(
+            BenchmarkId::new("defer_sync", defer_sync),
+            &defer_sync,
+            |b, defer_sync| {
+                b.iter(|| {
+                    last_index += 1;
+                    for gs in storages.iter() {
+                        block_on(gs.write_ready(WriteBatch {
+                            entries: vec![new_entry(last_index)],
+                            defer_sync: *defer_sync,
+                            ..Default::default()
+                        }))
+                        .unwrap();
+                    }
+                    rt.block_on(storage.sync_writes()).unwrap()
+                })
+            },
+        );
+
+        println!(
+            "segment_storage_sync/defer_sync/{}: {} rounds of {} groups, {} fsyncs",
+            defer_sync,
+            last_index,
+            SYNC_GROUPS,
+            storage.stats().write_syncs
+        );
+        drop(storages);
+        drop(storage);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    group.finish();
+}
+
+fn bench_open(c: &mut Criterion) {
+    let dir = bench_dir("open");
+    let mut config = SegmentConfig::new(&dir);
+    config.sync_policy = SyncPolicy::Never;
+    config.segment_size = 16 * 1024 * 1024;
+    let storage = SegmentedStorage::open(config.clone()).unwrap();
+    for round in 0..10 {
+        for group_id in 1..=OPEN_GROUPS {
+            let gs = block_on(storage.group_storage(group_id, 1)).unwrap();
+            let low = round * 10 + 1;
+            block_on(gs.write_ready(WriteBatch {
+                entries: (low..low + 10).map(new_entry).collect(),
+                hard_state: Some(HardState {
+                    term: 1,
+                    vote: 1,
+                    commit: low + 9,
+                }),
+                ..Default::default()
+            }))
+            .unwrap();
+        }
+        for group_id in 1..=OPEN_GROUPS {
+            storage.compact(group_id, round * 10 + 6).unwrap();
+        }
+        storage.rewrite_segments().unwrap();
+    }
+    let stats = storage.stats();
+    drop(storage);
+    println!(
+        "segment_storage_open/groups_{}: {:?}, write amplification {:.2}",
+        OPEN_GROUPS,
+        stats,
+        stats.write_amplification()
+    );
+
+    let mut group = c.benchmark_group("segment_storage_open");
+    group.sample_size(10);
+    group.bench_function(format!("groups_{}", OPEN_GROUPS), |b| {
+        b.iter(|| SegmentedStorage::open(config.clone()).unwrap())
+    });
+    group.finish();
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+criterion_group!(benches, bench_batched_sync, bench_open);
+criterion_main!(benches);
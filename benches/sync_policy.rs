@@ -0,0 +1,87 @@
+//! The throughput of `write_ready` of a group on the disk-backed segmented
+//! storage by the `SyncPolicy`, each write is a batch of entries and the hard
+//! state like the ready of a busy group. Run it with
+//! `cargo bench --bench sync_policy`.
+use std::time::Duration;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+use futures::executor::block_on;
+use smol_raft::proto::Entry;
+use smol_raft::proto::HardState;
+use smol_raft::storage::MultiRaftStorage;
+use smol_raft::storage::RaftStorage;
+use smol_raft::storage::SegmentConfig;
+use smol_raft::storage::SegmentedStorage;
+use smol_raft::storage::SyncPolicy;
+use smol_raft::storage::WriteBatch;
+
+const GROUP_ID: u64 = 1;
+const BATCH: u64 = 16;
+const ENTRY_SIZE: usize = 256;
+
+fn bench_sync_policy(c: &mut Criterion) {
+    let policies = [
+        ("always", SyncPolicy::Always),
+        (
+            "periodic_10ms",
+            SyncPolicy::Periodic(Duration::from_millis(10)),
+        ),
+        ("never", SyncPolicy::Never),
+    ];
+    let mut group = c.benchmark_group("sync_policy");
+    group.throughput(Throughput::Elements(BATCH));
+    for (name, policy) in policies {
+        let dir = std::env::temp_dir().join(format!(
+            "smol-raft-bench-sync-policy-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut config = SegmentConfig::new(&dir);
+        config.sync_policy = policy;
+        let storage = SegmentedStorage::open(config).unwrap();
+        let gs = block_on(storage.group_storage(GROUP_ID, 1)).unwrap();
+
+        let mut last_index = 0;
+        group.bench_with_input(BenchmarkId::new("policy", name), &policy, |b, _| {
+            b.iter(|| {
+                let entries = (last_index + 1..=last_index + BATCH)
+                    .map(|index| Entry {
+                        index,
+                        term: 1,
+                        data: vec![0; ENTRY_SIZE],
+                        ..Default::default()
+                    })
+                    .collect();
+                last_index += BATCH;
+                let hard_state = HardState {
+                    term: 1,
+                    vote: 1,
+                    commit: last_index,
+                };
+                block_on(gs.write_ready(WriteBatch {
+                    entries,
+                    hard_state: Some(hard_state),
+                    ..Default::default()
+                }))
+                .unwrap()
+            })
+        });
+
+        println!(
+            "sync_policy/policy/{}: {} entries written, {} fsyncs",
+            name,
+            last_index,
+            storage.stats().write_syncs
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_sync_policy);
+criterion_main!(benches);
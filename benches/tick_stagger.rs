@@ -0,0 +1,61 @@
+//! The tick passes of 10k groups on one node, with all groups ticked in one
+//! pass and staggered over 10 slots. Each group is ticked once per
+//! `tick_interval` either way, the staggering spreads the cost over the
+//! interval, so a pass ticks a tenth of the groups. The cost of each pass is
+//! the CPU spike of the timer. Run it with
+//! `cargo bench --features test-util --bench tick_stagger`.
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use smol_raft::MultiRaftConfig;
+use tokio::runtime::Runtime;
+use tokio::sync::watch;
+
+#[path = "../tests/fixture/mod.rs"]
+mod fixture;
+
+use fixture::FixtureCluster;
+
+const GROUPS: u64 = 10000;
+// the groups are ticked in one pass by 1.
+const STAGGER_SLOTS: [usize; 2] = [1, 10];
+
+fn bench_tick_stagger(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("tick_stagger");
+    for slots in STAGGER_SLOTS {
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let cluster = rt.block_on(async {
+            let config = MultiRaftConfig {
+                election_tick: 10,
+                heartbeat_tick: 2,
+                manual_tick: true,
+                tick_stagger_slots: slots,
+                ..Default::default()
+            };
+            let mut cluster = FixtureCluster::make_with_config(1, config, stop_rx).await;
+            cluster.ack_applies();
+            for group_id in 1..=GROUPS {
+                cluster.make_group_with_campaign(group_id, 0, 1, true).await;
+            }
+            cluster.tick_all().await;
+            cluster
+        });
+
+        group.bench_with_input(BenchmarkId::new("slots", slots), &slots, |b, _| {
+            b.to_async(&rt).iter(|| cluster.tick_all())
+        });
+
+        let health = rt.block_on(cluster.multirafts[0].health());
+        println!(
+            "tick_stagger/slots/{}: the last pass ticked {} groups in {:?}",
+            slots, health.last_tick_groups, health.last_tick_cost
+        );
+        let _ = stop_tx.send(true);
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_tick_stagger);
+criterion_main!(benches);
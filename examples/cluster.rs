@@ -0,0 +1,186 @@
+//! A 3-node cluster in one process over the local transport and the memory
+//! storage. The nodes host one group, each line read from the stdin proposes
+//! an increment of the counter replicated by the group, the applied entries
+//! and the leader elections are printed from the event stream of each node.
+//! Run it with `cargo run --example cluster`, then type:
+//!
+//! - `inc [n]` or an empty line to increment the counter by `n` (1 if absent).
+//! - `status` to print the status of the group on each node.
+//! - `quit` to stop the cluster.
+use std::error::Error;
+use std::time::Duration;
+
+use smol_raft::multiraft::Event;
+use smol_raft::multiraft::GroupId;
+use smol_raft::multiraft::NodeId;
+use smol_raft::multiraft::StoreId;
+use smol_raft::multiraft::Transport;
+use smol_raft::proto::ReplicaDesc;
+use smol_raft::storage::MemStorage;
+use smol_raft::storage::MultiRaftMemoryStorage;
+use smol_raft::LocalTransport;
+use smol_raft::MultiRaft;
+use smol_raft::MultiRaftConfig;
+use smol_raft::MultiRaftMessageSender;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::BufReader;
+use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::watch;
+
+type ClusterMultiRaft = MultiRaft<
+    MultiRaftMessageSender,
+    LocalTransport<MultiRaftMessageSender>,
+    MemStorage,
+    MultiRaftMemoryStorage,
+>;
+
+const GROUP_ID: GroupId = GroupId(1);
+const NODES: u64 = 3;
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Apply the increments of the group to the counter of the node and respond
+/// the proposals, the counters of all nodes are the same at the same index.
+async fn apply_events(node_id: u64, mut events: Receiver<Vec<Event>>) {
+    let mut counter = 0u64;
+    while let Some(events) = events.recv().await {
+        for event in events {
+            match event {
+                Event::LederElection(election) => println!(
+                    "node {}: group {} elects leader {} at term {}",
+                    node_id, election.group_id, election.leader_id, election.committed_term
+                ),
+                Event::Apply(apply) => {
+                    if !apply.is_conf_change {
+                        let mut delta = [0; 8];
+                        if apply.entry.data.len() == delta.len() {
+                            delta.copy_from_slice(&apply.entry.data);
+                            counter += u64::from_le_bytes(delta);
+                        }
+                        println!(
+                            "node {}: group {} applies index {}, counter = {}",
+                            node_id, apply.group_id, apply.entry.index, counter
+                        );
+                    }
+                    if let Some(tx) = apply.tx {
+                        let _ = tx.send(Ok(()));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Returns the node which hosts the leader of the group.
+async fn leader(nodes: &[ClusterMultiRaft]) -> &ClusterMultiRaft {
+    loop {
+        if let Some(node) = nodes.iter().find(|node| node.is_leader(GROUP_ID)) {
+            return node;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 10,
+        heartbeat_tick: 2,
+        tick_interval: 50,
+        ..Default::default()
+    };
+
+    // all nodes share the same local transport.
+    let transport = LocalTransport::new();
+    let mut nodes = vec![];
+    for node_id in 1..=NODES {
+        let (event_tx, event_rx) = channel(64);
+        let node = ClusterMultiRaft::new(
+            config.clone(),
+            NodeId(node_id),
+            StoreId(node_id),
+            transport.clone(),
+            MultiRaftMemoryStorage::new(node_id, node_id),
+            stop_rx.clone(),
+            event_tx,
+        );
+        transport
+            .listen(
+                node_id,
+                &format!("local://{}", node_id),
+                node.message_sender(),
+            )
+            .await?;
+        tokio::spawn(apply_events(node_id, event_rx));
+        nodes.push(node);
+    }
+
+    // the replica i of the group is on the node i, the node 1 campaigns.
+    let replicas = (1..=NODES)
+        .map(|node_id| ReplicaDesc {
+            node_id,
+            replica_id: node_id,
+        })
+        .collect::<Vec<_>>();
+    for (node_id, node) in (1..=NODES).zip(nodes.iter()) {
+        node.bootstrap_group(GROUP_ID, replicas.clone(), node_id == 1)
+            .await?;
+    }
+    leader(&nodes).await;
+
+    println!("type `inc [n]`, `status` or `quit`");
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            None | Some("inc") => {
+                let delta = match words.next().map(str::parse::<u64>) {
+                    None => 1,
+                    Some(Ok(delta)) => delta,
+                    Some(Err(err)) => {
+                        println!("invalid increment: {}", err);
+                        continue;
+                    }
+                };
+                let node = leader(&nodes).await;
+                match node
+                    .propose_timeout(
+                        GROUP_ID,
+                        delta.to_le_bytes().to_vec(),
+                        vec![],
+                        PROPOSE_TIMEOUT,
+                    )
+                    .await
+                {
+                    Ok(token) => {
+                        println!("increment {} is applied at index {}", delta, token.index())
+                    }
+                    Err(err) => println!("increment {} fails: {}", delta, err),
+                }
+            }
+            Some("status") => {
+                for (node_id, node) in (1..=NODES).zip(nodes.iter()) {
+                    match node.group_status(GROUP_ID).await {
+                        Some(status) => println!(
+                            "node {}: {:?} at term {}, leader {}, commit {}, applied {}",
+                            node_id,
+                            status.role,
+                            status.term,
+                            status.leader_id,
+                            status.commit_index,
+                            status.applied_index
+                        ),
+                        None => println!("node {}: group {} not found", node_id, GROUP_ID),
+                    }
+                }
+            }
+            Some("quit") => break,
+            Some(command) => println!("unknown command `{}`", command),
+        }
+    }
+
+    let _ = stop_tx.send(true);
+    Ok(())
+}
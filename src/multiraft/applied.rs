@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use tokio::sync::broadcast;
+
+use super::error::Error;
+use super::event::AppliedEntry;
+
+struct RetainedEntries {
+    entries: VecDeque<AppliedEntry>,
+    // the bytes of the data and context of the entries.
+    size: usize,
+    // the entries applied from the index are retained unless evicted, the
+    // ones below it are applied before the retention began or are evicted.
+    first_index: u64,
+}
+
+impl RetainedEntries {
+    fn new(first_index: u64) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            size: 0,
+            first_index,
+        }
+    }
+}
+
+#[inline]
+fn entry_size(applied: &AppliedEntry) -> usize {
+    applied.data.len() + applied.context.len()
+}
+
+/// AppliedEntries publishes the applied entries to the subscribers of
+/// `MultiRaft::apply_results`, and retains the last `retention` entries of
+/// each group, at most `retention_size` bytes, for the late subscribers of
+/// `MultiRaft::apply_results_from`, the clones share the same entries.
+///
+/// The entries are retained in memory only, from the first entry the group
+/// applies since the node started or since it installed a snapshot, so the
+/// entries before it and the evicted ones are unavailable.
+#[derive(Clone)]
+pub struct AppliedEntries {
+    tx: broadcast::Sender<AppliedEntry>,
+    retention: usize,
+    retention_size: usize,
+    groups: Arc<Mutex<HashMap<u64, RetainedEntries>>>,
+}
+
+impl AppliedEntries {
+    pub fn new(capacity: usize, retention: usize, retention_size: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        Self {
+            tx,
+            retention,
+            retention_size,
+            groups: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The group is about to apply the entries from `first_index`, the
+    /// retention of the group begins at the index if it's the first apply
+    /// since the node started. If the group installed a snapshot, the
+    /// retained entries before it are dropped, since the entries between them
+    /// and the snapshot are never applied by this node.
+    pub fn begin_apply(&self, group_id: u64, first_index: u64, snapshot: bool) {
+        if self.retention == 0 {
+            return;
+        }
+        let mut groups = self.groups.lock().unwrap();
+        let group = groups
+            .entry(group_id)
+            .or_insert_with(|| RetainedEntries::new(first_index));
+        if snapshot {
+            *group = RetainedEntries::new(first_index);
+        }
+    }
+
+    /// Publish the applied entry. The send of broadcast never blocks the
+    /// apply loop, it fails only if there are no subscribers.
+    pub fn publish(&self, applied: AppliedEntry) {
+        if self.retention == 0 {
+            let _ = self.tx.send(applied);
+            return;
+        }
+
+        // the entry is retained and sent under the lock, so a subscriber of
+        // `subscribe_from` observes it either in the replay or live, not both.
+        let mut groups = self.groups.lock().unwrap();
+        let group = groups
+            .entry(applied.group_id)
+            .or_insert_with(|| RetainedEntries::new(applied.index));
+        group.size += entry_size(&applied);
+        group.entries.push_back(applied.clone());
+        while group.entries.len() > self.retention
+            || (self.retention_size != 0 && group.size > self.retention_size)
+        {
+            match group.entries.pop_front() {
+                Some(evicted) => {
+                    group.size -= entry_size(&evicted);
+                    group.first_index = evicted.index + 1;
+                }
+                None => break,
+            }
+        }
+        let _ = self.tx.send(applied);
+    }
+
+    #[inline]
+    pub fn subscribe(&self) -> broadcast::Receiver<AppliedEntry> {
+        self.tx.subscribe()
+    }
+
+    /// Returns the retained entries of the group from `from_index` and the
+    /// receiver of the entries published after them. Fails if the entries
+    /// aren't retained, or the entries from `from_index` may be applied before
+    /// the first retained index, including the group which hasn't applied any
+    /// entry since the node started.
+    pub fn subscribe_from(
+        &self,
+        group_id: u64,
+        from_index: u64,
+    ) -> Result<(Vec<AppliedEntry>, broadcast::Receiver<AppliedEntry>), Error> {
+        if self.retention == 0 {
+            return Err(Error::BadParameter(
+                "the apply results aren't retained, apply_results_retention is 0".to_owned(),
+            ));
+        }
+
+        let groups = self.groups.lock().unwrap();
+        let rx = self.tx.subscribe();
+        // the group which hasn't applied since the node started may have applied
+        // the entries before.
+        let first_index = groups
+            .get(&group_id)
+            .map_or(from_index.saturating_add(1), |group| group.first_index);
+        if from_index < first_index {
+            return Err(Error::ApplyResultsEvicted(
+                group_id,
+                from_index,
+                first_index.saturating_sub(1),
+            ));
+        }
+        let group = &groups[&group_id];
+        let replay = group
+            .entries
+            .iter()
+            .filter(|applied| applied.index >= from_index)
+            .cloned()
+            .collect();
+        Ok((replay, rx))
+    }
+
+    #[inline]
+    pub fn remove(&self, group_id: u64) {
+        self.groups.lock().unwrap().remove(&group_id);
+    }
+}
+
+#[test]
+fn test_applied_entries_retention() {
+    let applied = |index| AppliedEntry {
+        group_id: 1,
+        index,
+        term: 1,
+        context: vec![],
+        data: vec![0; 10],
+        is_conf_change: false,
+        apply_round: index,
+    };
+
+    let entries = AppliedEntries::new(16, 3, 0);
+    entries.begin_apply(1, 1, false);
+    for index in 1..=5 {
+        entries.publish(applied(index));
+    }
+    let (replay, _) = entries.subscribe_from(1, 4).unwrap();
+    assert_eq!(
+        replay.iter().map(|e| e.index).collect::<Vec<_>>(),
+        vec![4, 5]
+    );
+    let (replay, _) = entries.subscribe_from(1, 3).unwrap();
+    assert_eq!(replay.len(), 3);
+    assert!(matches!(
+        entries.subscribe_from(1, 2),
+        Err(Error::ApplyResultsEvicted(1, 2, 2))
+    ));
+
+    // the group which hasn't applied since the node started may have applied
+    // the entries before it.
+    assert!(matches!(
+        entries.subscribe_from(2, 1),
+        Err(Error::ApplyResultsEvicted(2, 1, _))
+    ));
+    entries.begin_apply(2, 1, false);
+    let (replay, mut rx) = entries.subscribe_from(2, 1).unwrap();
+    assert!(replay.is_empty());
+    entries.publish(applied(6));
+    assert_eq!(rx.try_recv().unwrap().index, 6);
+
+    // the snapshot drops the retained entries before it.
+    entries.begin_apply(1, 11, true);
+    assert!(matches!(
+        entries.subscribe_from(1, 6),
+        Err(Error::ApplyResultsEvicted(1, 6, 10))
+    ));
+
+    entries.remove(1);
+    assert!(entries.subscribe_from(1, 1).is_err());
+    assert!(AppliedEntries::new(16, 0, 0).subscribe_from(1, 1).is_err());
+}
+
+#[test]
+fn test_applied_entries_retention_after_restart() {
+    let applied = |index| AppliedEntry {
+        group_id: 1,
+        index,
+        term: 1,
+        context: vec![],
+        data: vec![0; 10],
+        is_conf_change: false,
+        apply_round: index,
+    };
+
+    // the node restarts at applied index 9, the entries before are unknown.
+    let entries = AppliedEntries::new(16, 8, 25);
+    entries.begin_apply(1, 10, false);
+    assert!(matches!(
+        entries.subscribe_from(1, 5),
+        Err(Error::ApplyResultsEvicted(1, 5, 9))
+    ));
+    for index in 10..=13 {
+        entries.publish(applied(index));
+    }
+    // only 2 entries of 10 bytes fit in 25 bytes.
+    let (replay, _) = entries.subscribe_from(1, 12).unwrap();
+    assert_eq!(
+        replay.iter().map(|e| e.index).collect::<Vec<_>>(),
+        vec![12, 13]
+    );
+    assert!(matches!(
+        entries.subscribe_from(1, 11),
+        Err(Error::ApplyResultsEvicted(1, 11, 11))
+    ));
+}
@@ -1,26 +1,53 @@
 use std::collections::HashMap;
 use std::collections::VecDeque;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
 use std::vec::IntoIter;
 
 use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::unbounded_channel;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
 use tokio::sync::watch;
 use tokio::task::JoinHandle;
 use prost::Message as ProstMessage;
+use tracing::debug_span;
+use tracing::warn;
 
+use crate::proto::AppWriteRequest;
 use crate::proto::Entry;
 use crate::proto::EntryType;
 use crate::proto::ConfChange;
-use crate::proto::ConfChangeType;
+use crate::proto::ConfChangeV2;
+use crate::proto::MembershipChangeData;
 use crate::proto::MembershipChangeRequest;
+use crate::proto::ProposalContext;
+use crate::proto::Snapshot;
+use crate::proto::SnapshotData;
+use crate::proto::SnapshotMetadata;
 
 // use super::apply_command::ApplyCommand;
+use super::applied::AppliedEntries;
+#[cfg(feature = "serde-config")]
+use super::config::GroupConfig;
+use super::dedup::DedupTables;
 use super::error::Error;
 use super::error::ProposalError;
+use super::event::AppliedEntry;
 use super::event::ApplyEvent;
+use super::entry;
+use super::entry::EntryPayload;
 use super::event::Event;
+use super::multiraft_actor::panic_message;
 use super::proposal::Proposal;
+use super::state_machine;
+use super::state_machine::ApplyError;
+use super::state_machine::ApplyHandle;
+use super::state_machine::StateMachine;
 
 const MAX_APPLY_BATCH_SIZE: usize = 64 * 1024 * 1024;
 
@@ -34,12 +61,21 @@ pub struct Apply {
     pub entries: Vec<Entry>,
     pub entries_size: usize,
     pub proposals: VecDeque<Proposal>,
+    // if true, the data entries are not applied to the state machine.
+    pub witness: bool,
+    // the snapshot installed by the replica, the applied state of the group
+    // is restored from it before the entries are applied.
+    pub snapshot: Option<Snapshot>,
 }
 
 impl Apply {
     fn try_batch(&mut self, that: &mut Apply) -> bool {
         assert_eq!(self.replica_id, that.replica_id);
         assert_eq!(self.group_id, that.group_id);
+        // the entries before the snapshot are applied first.
+        if that.snapshot.is_some() {
+            return false;
+        }
         if self.entries_size + that.entries_size > MAX_APPLY_BATCH_SIZE {
             return false;
         }
@@ -52,6 +88,7 @@ impl Apply {
         self.commit_term = that.commit_term;
         self.entries.append(&mut that.entries);
         self.entries_size += that.entries_size;
+        self.witness = that.witness;
         self.proposals.append(&mut that.proposals);
         return true;
     }
@@ -67,6 +104,40 @@ impl Apply {
 
 pub enum ApplyTask {
     Apply(Apply),
+    Snapshot(SnapshotTask),
+}
+
+/// Snapshot the applied state of the group, it's taken after the entries
+/// sent to the apply actor before it are applied, so the state is at `index`.
+pub struct SnapshotTask {
+    /// The index of the last entry sent to be applied before the task.
+    pub index: u64,
+    pub target: SnapshotTarget,
+}
+
+pub enum SnapshotTarget {
+    /// Save the snapshot and compact the log up to it, see
+    /// `MultiRaft::trigger_snapshot`.
+    Compact(Option<oneshot::Sender<Result<SnapshotMetadata, Error>>>),
+    /// Return the snapshot without saving it, see `MultiRaft::export_snapshot`.
+    Export(oneshot::Sender<Result<Snapshot, Error>>),
+    /// Save the snapshot like `Compact`, but the log is compacted only up to
+    /// the index matched by all the replicas, see `MultiRaftConfig::max_log_entries`.
+    CompactMatched,
+}
+
+impl SnapshotTarget {
+    pub fn fail(self, err: Error) {
+        match self {
+            SnapshotTarget::Compact(tx) => {
+                tx.map(|tx| tx.send(Err(err)));
+            }
+            SnapshotTarget::Export(tx) => {
+                let _ = tx.send(Err(err));
+            }
+            SnapshotTarget::CompactMatched => {}
+        }
+    }
 }
 
 /// Apply membership change results. 
@@ -82,6 +153,18 @@ pub struct MembershipChangeResult {
 
 pub enum ApplyResult {
     MembershipChange(MembershipChangeResult),
+    /// The config of the group is set by the admin entry, the proposal is
+    /// responded after the config is set.
+    #[cfg(feature = "serde-config")]
+    GroupConfig(GroupConfig, Option<oneshot::Sender<Result<(), Error>>>),
+    /// The follow-up write enqueued by the state machine by `ApplyHandle`,
+    /// it's proposed after the results of the batch are handled.
+    Propose(AppWriteRequest, oneshot::Sender<Result<(), Error>>),
+    /// The data of the snapshot taken by `ApplyTask::Snapshot`, see
+    /// `SnapshotData`, or the error of `StateMachine::snapshot`.
+    Snapshot(SnapshotTask, Result<Vec<u8>, Error>),
+    /// Applying the entries of the group panicked, the group is poisoned.
+    Failed(String),
 }
 
 #[derive(Default)]
@@ -93,17 +176,30 @@ pub struct ApplyTaskResponse {
     pub groups: HashMap<u64, Vec<ApplyResult>>,
 }
 
+/// The response channel is unbounded, because the multiraft actor sends
+/// requests and receives responses in the same loop, a bounded response
+/// channel could deadlock both actors.
 pub struct ApplyActorAddress {
     pub tx: Sender<ApplyTaskRequest>,
-    pub rx: Receiver<ApplyTaskResponse>,
+    pub rx: UnboundedReceiver<ApplyTaskResponse>,
 }
 
 pub struct ApplyActor {
     rx: Receiver<ApplyTaskRequest>,
-    tx: Sender<ApplyTaskResponse>,
+    tx: UnboundedSender<ApplyTaskResponse>,
     event_tx: Sender<Vec<Event>>,
+    applied_entries: AppliedEntries,
+    dedup_tables: DedupTables,
+    // if some, the data entries are applied by it rather than the events.
+    state_machine: Option<Arc<dyn StateMachine>>,
     // apply_to_tx: Sender<Vec<ApplyCommand>>,
     group_pending_apply: HashMap<u64, Apply>,
+    // if true, the entries applied in a round are delivered in one batch
+    // ordered by (group_id, index), see `MultiRaftConfig::ordered_apply`.
+    ordered_apply: bool,
+    apply_round: u64,
+    round_applys: Vec<Event>,
+    round_applied: Vec<AppliedEntry>,
 }
 
 impl ApplyActor {
@@ -111,9 +207,16 @@ impl ApplyActor {
 }
 
 impl ApplyActor {
-    pub fn spawn(event_tx: Sender<Vec<Event>>, stop_rx: watch::Receiver<bool>) -> (JoinHandle<()>, ApplyActorAddress) {
+    pub fn spawn(
+        event_tx: Sender<Vec<Event>>,
+        applied_entries: AppliedEntries,
+        dedup_tables: DedupTables,
+        state_machine: Option<Arc<dyn StateMachine>>,
+        ordered_apply: bool,
+        stop_rx: watch::Receiver<bool>,
+    ) -> (JoinHandle<()>, ApplyActorAddress) {
         let (request_tx, request_rx) = channel(1);
-        let (response_tx, response_rx) = channel(1);
+        let (response_tx, response_rx) = unbounded_channel();
 
         let address = ApplyActorAddress {
             tx: request_tx,
@@ -122,9 +225,16 @@ impl ApplyActor {
 
         let actor = ApplyActor {
             event_tx,
+            applied_entries,
+            dedup_tables,
+            state_machine,
             rx: request_rx,
             tx: response_tx,
             group_pending_apply: HashMap::new(),
+            ordered_apply,
+            apply_round: 0,
+            round_applys: Vec::new(),
+            round_applied: Vec::new(),
         };
 
         let join_handle = tokio::spawn(async move {
@@ -148,6 +258,45 @@ impl ApplyActor {
     }
 
     async fn handle_request(&mut self, request: ApplyTaskRequest) {
+        self.apply_round += 1;
+        let mut results = HashMap::new();
+        self.batch_request(request, &mut results).await;
+        // batch the requests which are already in the channel.
+        while let Ok(request) = self.rx.try_recv() {
+            self.batch_request(request, &mut results).await;
+        }
+
+        let mut pending = std::mem::take(&mut self.group_pending_apply)
+            .into_iter()
+            .collect::<Vec<_>>();
+        if self.ordered_apply {
+            pending.sort_by_key(|(group_id, _)| *group_id);
+        }
+        for (group_id, apply) in pending.into_iter() {
+            let mut apply_results = self.handle_apply(apply).await;
+            results
+                .entry(group_id)
+                .or_insert_with(Vec::new)
+                .append(&mut apply_results);
+            // yield between groups so that a burst of applies doesn't
+            // monopolize the worker thread of the multiraft actor.
+            tokio::task::yield_now().await;
+        }
+
+        if self.ordered_apply {
+            self.flush_round().await;
+        }
+
+        if let Err(_error) = self.tx.send(ApplyTaskResponse { groups: results }) {
+            warn!("apply response receiver dropped");
+        }
+    }
+
+    async fn batch_request(
+        &mut self,
+        request: ApplyTaskRequest,
+        results: &mut HashMap<u64, Vec<ApplyResult>>,
+    ) {
         for (group_id, task) in request.groups.into_iter() {
             match task {
                 ApplyTask::Apply(mut apply) => {
@@ -157,48 +306,170 @@ impl ApplyActor {
                                 continue;
                             }
 
+                            // the batch is full, apply it and start a new batch.
                             let take_batch = self.group_pending_apply.remove(&group_id).unwrap();
-                            self.handle_apply(take_batch).await;
+                            let mut apply_results = self.handle_apply(take_batch).await;
+                            results
+                                .entry(group_id)
+                                .or_insert_with(Vec::new)
+                                .append(&mut apply_results);
+                            self.group_pending_apply.insert(group_id, apply);
                         }
                         None => {
                             self.group_pending_apply.insert(group_id, apply);
                         }
                     };
                 }
+                ApplyTask::Snapshot(task) => {
+                    // the snapshot is taken after the pending entries of the
+                    // group are applied.
+                    let group_results = results.entry(group_id).or_insert_with(Vec::new);
+                    if let Some(batch) = self.group_pending_apply.remove(&group_id) {
+                        group_results.append(&mut self.handle_apply(batch).await);
+                    }
+                    let data = self.snapshot_state(group_id).map(|state| {
+                        SnapshotData {
+                            state,
+                            dedup: self.dedup_tables.to_data(group_id),
+                        }
+                        .encode_to_vec()
+                    });
+                    group_results.push(ApplyResult::Snapshot(task, data));
+                }
             }
         }
     }
 
-    async fn handle_apply(&mut self, apply: Apply) {
-        // let mut proposals = VecDeque::new();
-        // for proposal in apply.proposals {
-        //     proposals.push_back(proposal)
-        // }
+    /// Returns the state of the group snapshotted by the state machine, the
+    /// state is empty without the state machine.
+    fn snapshot_state(&self, group_id: u64) -> Result<Vec<u8>, Error> {
+        let state_machine = match self.state_machine.as_ref() {
+            Some(state_machine) => state_machine,
+            None => return Ok(vec![]),
+        };
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            state_machine::snapshot_state(state_machine.as_ref(), group_id)
+        }));
+        let reason = match res {
+            Ok(Ok(state)) => return Ok(state),
+            Ok(Err(ApplyError::Reject(reason) | ApplyError::Fatal(reason))) => reason,
+            Err(payload) => panic_message(payload.as_ref()),
+        };
+        warn!(
+            "group {} state machine fails to snapshot: {}",
+            group_id, reason
+        );
+        Err(Error::SnapshotFailed(group_id, reason))
+    }
 
+    async fn handle_apply(&mut self, apply: Apply) -> Vec<ApplyResult> {
         let mut delegate = ApplyDelegate {
             group_id: apply.group_id,
+            witness: apply.witness,
+            dedup_tables: self.dedup_tables.clone(),
+            state_machine: self.state_machine.clone(),
             pending_proposals: apply.proposals,
             staging_applys: Vec::new(),
+            apply_results: Vec::new(),
+            applied_entries: Vec::new(),
+            apply_round: self.apply_round,
+            fatal: None,
+            handle: ApplyHandle::default(),
         };
 
-        delegate.handle_committed_entries(apply.entries);
-        self.event_tx.send(delegate.staging_applys).await;
-        // delegate.apply_to(&self.apply_to_tx);
-        // take entries
-        // transervel entry
-        //   get proposal if exists
-        //
-        //   match entry type
-        //      if cc apply inner data structure and
-        //      if other, together entry and proposal to vec
-        // send batch apply to user interface
+        // a panic or a fatal error of the state machine in applying the
+        // entries poisons the group only, the entries applied before it are
+        // still delivered.
+        let entries = apply.entries;
+        let snapshot = apply.snapshot;
+        match (snapshot.as_ref(), entries.first()) {
+            (Some(snapshot), _) => self.applied_entries.begin_apply(
+                apply.group_id,
+                snapshot.get_metadata().index + 1,
+                true,
+            ),
+            (None, Some(entry)) => {
+                self.applied_entries
+                    .begin_apply(apply.group_id, entry.index, false)
+            }
+            (None, None) => {}
+        }
+        let failed = match panic::catch_unwind(AssertUnwindSafe(|| {
+            if let Some(snapshot) = snapshot {
+                delegate.restore_snapshot(snapshot);
+            }
+            delegate.handle_committed_entries(entries)
+        })) {
+            Err(payload) => Some(panic_message(payload.as_ref())),
+            Ok(_) => delegate.fatal.take(),
+        };
+        for (request, tx) in delegate.handle.take_proposals() {
+            delegate.apply_results.push(ApplyResult::Propose(request, tx));
+        }
+        if let Some(reason) = failed {
+            for p in delegate.pending_proposals.drain(..) {
+                p.tx.map(|tx| tx.send(Err(Error::GroupPoisoned(apply.group_id))));
+            }
+            delegate.apply_results.push(ApplyResult::Failed(reason));
+        }
+
+        // the entries are delivered after all groups of the round are applied.
+        if self.ordered_apply {
+            self.round_applied.append(&mut delegate.applied_entries);
+            self.round_applys.append(&mut delegate.staging_applys);
+            return delegate.apply_results;
+        }
+
+        for applied in delegate.applied_entries.drain(..) {
+            self.applied_entries.publish(applied);
+        }
+        if !delegate.staging_applys.is_empty() {
+            if let Err(_error) = self.event_tx.send(delegate.staging_applys).await {
+                warn!("event receiver dropped");
+            }
+        }
+
+        delegate.apply_results
+    }
+
+    /// Deliver the entries applied in the round as one batch ordered by
+    /// (group_id, index). The sort is stable, so the entries of a group which
+    /// is applied in more than one batch of the round keep their order.
+    async fn flush_round(&mut self) {
+        self.round_applied.sort_by_key(|applied| (applied.group_id, applied.index));
+        for applied in self.round_applied.drain(..) {
+            self.applied_entries.publish(applied);
+        }
+
+        if self.round_applys.is_empty() {
+            return;
+        }
+        let mut applys = std::mem::take(&mut self.round_applys);
+        applys.sort_by_key(|event| match event {
+            Event::Apply(apply) => (apply.group_id, apply.entry.index),
+            _ => (u64::MAX, u64::MAX),
+        });
+        if let Err(_error) = self.event_tx.send(applys).await {
+            warn!("event receiver dropped");
+        }
     }
 }
 
 pub struct ApplyDelegate {
     group_id: u64,
+    witness: bool,
+    dedup_tables: DedupTables,
+    state_machine: Option<Arc<dyn StateMachine>>,
     pending_proposals: VecDeque<Proposal>,
     staging_applys: Vec<Event>,
+    apply_results: Vec<ApplyResult>,
+    applied_entries: Vec<AppliedEntry>,
+    apply_round: u64,
+    // the reason of the fatal error of the state machine, the entries after
+    // it aren't applied.
+    fatal: Option<String>,
+    // the follow-up writes enqueued by the state machine.
+    handle: ApplyHandle,
 }
 
 impl ApplyDelegate {
@@ -234,8 +505,52 @@ impl ApplyDelegate {
     // fn set_apply_state<'life0>(&'life0 mut self, apply_state: &ApplyState) {
     // }
 
+    /// Replace the applied state of the group by the installed snapshot, the
+    /// entries after it are applied on top of it.
+    fn restore_snapshot(&mut self, snapshot: Snapshot) {
+        let index = snapshot.get_metadata().index;
+        let data = match SnapshotData::decode(&snapshot.data[..]) {
+            Ok(data) => data,
+            Err(err) => {
+                self.fatal = Some(format!("decode snapshot at index {} error: {}", index, err));
+                return;
+            }
+        };
+        self.dedup_tables.restore(self.group_id, data.dedup);
+        // the witness doesn't store the data of state machine.
+        if self.witness {
+            return;
+        }
+        if let Some(state_machine) = self.state_machine.as_ref() {
+            if let Err(ApplyError::Reject(reason) | ApplyError::Fatal(reason)) =
+                state_machine::restore_state(state_machine.as_ref(), self.group_id, &data.state)
+            {
+                self.fatal = Some(format!(
+                    "restore snapshot at index {} error: {}",
+                    index, reason
+                ));
+            }
+        }
+    }
+
+    /// Apply the entries strictly in the order of the log, the conf change is
+    /// applied in place, so it takes effect before the entries after it. A gap
+    /// or reorder of the entries fails the group rather than applying out of
+    /// order.
     fn handle_committed_entries(&mut self, ents: Vec<Entry>) {
+        let mut last_index = None;
         for entry in ents.into_iter() {
+            if self.fatal.is_some() {
+                break;
+            }
+            if let Some(last) = last_index.filter(|last| entry.index != last + 1) {
+                self.fatal = Some(format!(
+                    "entry {} is applied out of order after {}",
+                    entry.index, last
+                ));
+                break;
+            }
+            last_index = Some(entry.index);
             match entry.entry_type() {
                 EntryType::EntryNormal => self.handle_committed_normal(entry),
                 EntryType::EntryConfChange | EntryType::EntryConfChangeV2 => {
@@ -268,58 +583,227 @@ impl ApplyDelegate {
         let entry_index = entry.index;
         let entry_term = entry.term;
 
-        if entry.data.is_empty() {
-            // TODO: detect user propose empty data and if there any pending proposal, we don't
-            // apply it and notify client.
-            // info!(
-            //     self.ctx.root_logger,
-            //     "{} skip no-op log index = {}, term = {}",
-            //     self.ctx.peer_id,
-            //     entry_index,
-            //     entry_term
-            // );
-            // self.apply_state.applied_term = entry_term;
-            // self.apply_state.applied_index = entry_index;
-            self.response_stale_proposals(entry_index, entry_term);
-            return;
+        // the entry is routed by the type tag of its frame.
+        match entry::decode_entry(&entry.data) {
+            EntryPayload::Empty => {
+                // the no-op entry appended by the new leader carries no data, so
+                // it's neither passed to the state machine nor emitted as the
+                // apply event, the applied index still advances past it.
+                self.response_stale_proposals(entry_index, entry_term);
+            }
+            EntryPayload::Data(_) => self.handle_committed_data(entry),
+            #[cfg(feature = "serde-config")]
+            EntryPayload::Admin {
+                kind: entry::ADMIN_GROUP_CONFIG,
+                payload,
+            } => self.handle_committed_group_config(entry_index, entry_term, payload),
+            EntryPayload::Admin { kind, .. } => {
+                // the admin command of unknown kind, or the kind whose feature
+                // isn't enabled.
+                warn!(
+                    "group {} skip unsupported admin command {} at index = {}, term = {}",
+                    self.group_id, kind, entry_index, entry_term
+                );
+                self.response_failed_proposal(
+                    entry_index,
+                    entry_term,
+                    format!("unsupported admin command {}", kind),
+                );
+            }
+            EntryPayload::Malformed => {
+                warn!(
+                    "group {} skip malformed entry at index = {}, term = {}",
+                    self.group_id, entry_index, entry_term
+                );
+                self.response_failed_proposal(
+                    entry_index,
+                    entry_term,
+                    format!("malformed entry at index {}", entry_index),
+                );
+            }
         }
+    }
+
+    /// The config of the group is set by the admin entry, see
+    /// `MultiRaft::propose_group_config`.
+    #[cfg(feature = "serde-config")]
+    fn handle_committed_group_config(&mut self, index: u64, term: u64, payload: &[u8]) {
+        match super::config::decode_config::<GroupConfig>(payload) {
+            Ok(config) => {
+                let tx = self.find_pending(term, index).map_or(None, |p| p.tx);
+                self.apply_results
+                    .push(ApplyResult::GroupConfig(config, tx));
+            }
+            Err(err) => {
+                warn!(
+                    "group {} skip group config at index = {}, term = {}: {}",
+                    self.group_id, index, term, err
+                );
+                self.response_failed_proposal(index, term, err.to_string());
+            }
+        }
+    }
+
+    fn handle_committed_data(&mut self, mut entry: Entry) {
+        let entry_index = entry.index;
+        // the application data is passed through opaque.
+        entry.data.drain(..entry::DATA_HEADER_LEN);
         let tx = self.find_pending(entry.term, entry.index).map_or(None, |p| p.tx);
+        let ctx = match ProposalContext::decode(&entry.context[..]) {
+            Ok(ctx) => ctx,
+            Err(err) => {
+                warn!(
+                    "group {} decode proposal context of entry {} error: {}",
+                    self.group_id, entry_index, err
+                );
+                ProposalContext::default()
+            }
+        };
+        let (client_id, sequence) = (ctx.client_id, ctx.sequence);
+        if client_id != 0 {
+            if let Some(result) =
+                self.dedup_tables
+                    .applied_result(self.group_id, client_id, sequence)
+            {
+                // the retry of the applied proposal isn't applied again, it's
+                // responded with the result of the original.
+                let result =
+                    result.map_err(|reason| Error::Proposal(ProposalError::Rejected(reason)));
+                tx.map(|tx| tx.send(result));
+                return;
+            }
+        }
+        entry.context = ctx.context.into();
+        let precondition = ctx.precondition;
+        let correlation_id = ctx.correlation_id;
 
-        let apply_command = Event::Apply(ApplyEvent{
+        // the witness doesn't store the data of state machine, nor the results
+        // of it.
+        if self.witness {
+            self.record_applied(client_id, sequence, entry_index, None);
+            return;
+        }
+
+        let _span = (correlation_id != 0).then(|| {
+            debug_span!(
+                "apply",
+                group_id = self.group_id,
+                index = entry_index,
+                correlation_id
+            )
+            .entered()
+        });
+        let apply = ApplyEvent {
             group_id: self.group_id,
             is_conf_change: false,
             entry,
+            precondition,
+            correlation_id,
             tx,
-        });
-        self.staging_applys.push(apply_command);
+        };
+        if let Some(state_machine) = self.state_machine.as_ref() {
+            let res = match state_machine::apply_entry(
+                state_machine.as_ref(),
+                &apply,
+                &mut self.handle,
+            ) {
+                Ok(_) => {
+                    self.record_applied(client_id, sequence, entry_index, None);
+                    Ok(())
+                }
+                Err(ApplyError::Reject(reason)) => {
+                    self.record_applied(client_id, sequence, entry_index, Some(reason.clone()));
+                    Err(Error::Proposal(ProposalError::Rejected(reason)))
+                }
+                Err(ApplyError::Fatal(reason)) => {
+                    warn!(
+                        "group {} state machine fails to apply entry {}: {}",
+                        self.group_id, entry_index, reason
+                    );
+                    apply
+                        .tx
+                        .map(|tx| tx.send(Err(Error::GroupPoisoned(self.group_id))));
+                    self.fatal = Some(reason);
+                    return;
+                }
+            };
+            self.push_applied_entry(&apply.entry, false);
+            apply.tx.map(|tx| tx.send(res));
+            return;
+        }
+
+        self.record_applied(client_id, sequence, entry_index, None);
+        self.push_applied_entry(&apply.entry, false);
+        self.staging_applys.push(Event::Apply(apply));
+    }
+
+    /// Record the result of the deduplicated proposal applied at `index`.
+    fn record_applied(&self, client_id: u64, sequence: u64, index: u64, rejection: Option<String>) {
+        if client_id != 0 {
+            self.dedup_tables
+                .record(self.group_id, client_id, sequence, index, rejection);
+        }
     }
 
     fn handle_committed_conf_change(&mut self, entry: Entry) {
         // TODO: empty adta?
 
         let proposal = self.find_pending(entry.term, entry.index);
-        match entry.entry_type() {
+        let result = match entry.entry_type() {
             EntryType::EntryNormal => unreachable!(),
             EntryType::EntryConfChange => {
                 let mut cc = ConfChange::default();
                 cc.merge(entry.data.as_ref()).unwrap();
-                match cc.change_type() {
-                    ConfChangeType::AddNode => {},
-                    ConfChangeType::RemoveNode => {},
-                    ConfChangeType::AddLearnerNode => {},
+                let changes = decode_membership_changes(self.group_id, &cc.context);
+
+                let mut single = raft::prelude::ConfChangeSingle::default();
+                single.change_type = cc.change_type;
+                single.node_id = cc.node_id;
+                let mut conf_change = raft::prelude::ConfChangeV2::default();
+                conf_change.changes.push(single);
+                conf_change.context = cc.context.into();
+
+                MembershipChangeResult {
+                    index: entry.index,
+                    conf_change,
+                    changes,
                 }
             }
             EntryType::EntryConfChangeV2 => {
-                unimplemented!();
+                let mut cc = ConfChangeV2::default();
+                cc.merge(entry.data.as_ref()).unwrap();
+                // the changes is empty if the entry leaves the joint consensus.
+                let changes = decode_membership_changes(self.group_id, &cc.context);
+
+                let mut conf_change = raft::prelude::ConfChangeV2::default();
+                conf_change.transition = cc.transition;
+                for change in cc.changes.iter() {
+                    let mut single = raft::prelude::ConfChangeSingle::default();
+                    single.change_type = change.change_type;
+                    single.node_id = change.node_id;
+                    conf_change.changes.push(single);
+                }
+                conf_change.context = cc.context.into();
+
+                MembershipChangeResult {
+                    index: entry.index,
+                    conf_change,
+                    changes,
+                }
             }
-        }
+        };
+        self.apply_results
+            .push(ApplyResult::MembershipChange(result));
 
         let tx = if let Some(proposal) = proposal {proposal.tx} else { None};
+        self.push_applied_entry(&entry, true);
 
         let apply_command = Event::Apply(ApplyEvent {
             group_id: self.group_id,
             is_conf_change: true,
             entry,
+            precondition: None,
+            correlation_id: 0,
             tx
         });
         self.staging_applys.push(apply_command);
@@ -329,9 +813,216 @@ impl ApplyDelegate {
         // self.apply_state.applied_term = entry.term;
     }
 
+    fn push_applied_entry(&mut self, entry: &Entry, is_conf_change: bool) {
+        self.applied_entries.push(AppliedEntry {
+            group_id: self.group_id,
+            index: entry.index,
+            term: entry.term,
+            context: entry.context.to_vec(),
+            data: entry.data.to_vec(),
+            is_conf_change,
+            apply_round: self.apply_round,
+        });
+    }
+
     fn response_stale_proposals(&mut self, index: u64, term: u64) {
         while let Some(p) = self.pop_normal(index, term) {
             p.tx.map(|tx| tx.send(Err(Error::Proposal(ProposalError::Stale(p.term)))));
         }
     }
+
+    fn response_failed_proposal(&mut self, index: u64, term: u64, reason: String) {
+        let tx = self.find_pending(term, index).map_or(None, |p| p.tx);
+        tx.map(|tx| tx.send(Err(Error::Proposal(ProposalError::Other(reason.into())))));
+    }
+}
+
+/// Decode the `MembershipChangeData` from the context of conf change,
+/// returns empty changes if the context is empty.
+fn decode_membership_changes(group_id: u64, context: &[u8]) -> Vec<MembershipChangeRequest> {
+    if context.is_empty() {
+        return vec![];
+    }
+
+    match MembershipChangeData::decode(context) {
+        Ok(data) => data.changes,
+        Err(err) => {
+            warn!(
+                "group {} decode membership change data error: {}",
+                group_id, err
+            );
+            vec![]
+        }
+    }
+}
+
+#[cfg(test)]
+impl ApplyDelegate {
+    /// The delegate of group 1 in the first apply round.
+    fn for_test(
+        witness: bool,
+        state_machine: Option<Arc<dyn StateMachine>>,
+        pending_proposals: VecDeque<Proposal>,
+    ) -> Self {
+        ApplyDelegate {
+            group_id: 1,
+            witness,
+            dedup_tables: DedupTables::new(0),
+            state_machine,
+            pending_proposals,
+            staging_applys: Vec::new(),
+            apply_results: Vec::new(),
+            applied_entries: Vec::new(),
+            apply_round: 1,
+            fatal: None,
+            handle: ApplyHandle::default(),
+        }
+    }
+}
+
+#[test]
+fn test_witness_skip_apply_normal_entries() {
+    let entry = |index: u64| {
+        let mut entry = Entry::default();
+        entry.set_entry_type(EntryType::EntryNormal);
+        entry.index = index;
+        entry.term = 1;
+        entry.data = entry::encode_data(b"data");
+        entry
+    };
+
+    for witness in [false, true] {
+        let mut delegate = ApplyDelegate::for_test(witness, None, VecDeque::new());
+        delegate.handle_committed_entries(vec![entry(2), entry(3)]);
+        let expected = if witness { 0 } else { 2 };
+        assert_eq!(delegate.staging_applys.len(), expected);
+        assert_eq!(delegate.applied_entries.len(), expected);
+    }
+}
+
+#[test]
+fn test_state_machine_reject_and_fatal() {
+    use super::state_machine::ApplyOutput;
+
+    struct TestStateMachine;
+    impl StateMachine for TestStateMachine {
+        fn apply(
+            &self,
+            apply: &ApplyEvent,
+            _: &mut ApplyHandle,
+        ) -> Result<ApplyOutput, ApplyError> {
+            match &apply.entry.data[..] {
+                b"reject" => Err(ApplyError::Reject("rejected".to_owned())),
+                b"fatal" => Err(ApplyError::Fatal("corrupted".to_owned())),
+                _ => Ok(ApplyOutput),
+            }
+        }
+    }
+
+    let entry = |index: u64, data: &[u8]| {
+        let mut entry = Entry::default();
+        entry.set_entry_type(EntryType::EntryNormal);
+        entry.index = index;
+        entry.term = 1;
+        entry.data = entry::encode_data(data);
+        entry
+    };
+
+    let mut pending_proposals = VecDeque::new();
+    let mut rxs = vec![];
+    for index in 1..=4 {
+        let (tx, rx) = oneshot::channel();
+        pending_proposals.push_back(Proposal {
+            index,
+            term: 1,
+            is_conf_change: false,
+            client_id: 0,
+            tx: Some(tx),
+        });
+        rxs.push(rx);
+    }
+    let mut delegate =
+        ApplyDelegate::for_test(false, Some(Arc::new(TestStateMachine)), pending_proposals);
+    delegate.handle_committed_entries(vec![
+        entry(1, b"data"),
+        entry(2, b"reject"),
+        entry(3, b"fatal"),
+        entry(4, b"data"),
+    ]);
+
+    // the apply events aren't emitted, the rejected entry is still applied.
+    assert!(delegate.staging_applys.is_empty());
+    assert_eq!(
+        delegate
+            .applied_entries
+            .iter()
+            .map(|applied| applied.index)
+            .collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+    assert_eq!(delegate.fatal, Some("corrupted".to_owned()));
+
+    let mut rxs = rxs.into_iter();
+    assert!(matches!(rxs.next().unwrap().try_recv(), Ok(Ok(()))));
+    assert!(matches!(
+        rxs.next().unwrap().try_recv(),
+        Ok(Err(Error::Proposal(ProposalError::Rejected(_))))
+    ));
+    assert!(matches!(
+        rxs.next().unwrap().try_recv(),
+        Ok(Err(Error::GroupPoisoned(1)))
+    ));
+    // the entry after the fatal one isn't applied, its proposal is failed by
+    // the caller of the delegate.
+    assert_eq!(delegate.pending_proposals.len(), 1);
+}
+
+#[test]
+fn test_apply_in_log_order() {
+    let data = |index: u64| {
+        let mut entry = Entry::default();
+        entry.set_entry_type(EntryType::EntryNormal);
+        entry.index = index;
+        entry.term = 1;
+        entry.data = entry::encode_data(b"data");
+        entry
+    };
+    let conf_change = |index: u64| {
+        let mut single = crate::proto::ConfChangeSingle::default();
+        single.set_change_type(crate::proto::ConfChangeType::AddNode);
+        single.node_id = 4;
+        let mut cc = ConfChangeV2::default();
+        cc.changes.push(single);
+        let mut entry = Entry::default();
+        entry.set_entry_type(EntryType::EntryConfChangeV2);
+        entry.index = index;
+        entry.term = 1;
+        entry.data = cc.encode_to_vec();
+        entry
+    };
+    let delegate = || ApplyDelegate::for_test(false, None, VecDeque::new());
+
+    // the conf change is applied between the data entries around it.
+    let mut applying = delegate();
+    applying.handle_committed_entries(vec![data(2), conf_change(3), data(4)]);
+    let applied = applying
+        .staging_applys
+        .iter()
+        .map(|event| match event {
+            Event::Apply(apply) => (apply.entry.index, apply.is_conf_change),
+            _ => unreachable!(),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(applied, vec![(2, false), (3, true), (4, false)]);
+    match &applying.apply_results[..] {
+        [ApplyResult::MembershipChange(result)] => assert_eq!(result.index, 3),
+        _ => panic!("expected the membership change of entry 3"),
+    }
+
+    // the entries after the gap aren't applied.
+    let mut applying = delegate();
+    applying.handle_committed_entries(vec![data(2), data(4), conf_change(5)]);
+    assert_eq!(applying.applied_entries.len(), 1);
+    assert!(applying.apply_results.is_empty());
+    assert!(applying.fatal.is_some());
 }
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::interval;
+use tracing::info;
+use tracing::warn;
+
+use crate::proto::ReplicaDesc;
+
+use super::config::MultiRaftConfig;
+use super::multiraft_actor::MultiRaftActorAddress;
+use super::multiraft_actor::QueryGroup;
+use super::raft_group::TransferLeaderPolicy;
+
+/// The leader and voters of a group seen by this node.
+#[derive(Debug, Clone)]
+pub struct GroupLeadership {
+    pub group_id: u64,
+    pub leader: ReplicaDesc,
+    pub voters: Vec<ReplicaDesc>,
+    // the leader is within its tenure, it's not transferred away, see
+    // `MultiRaftConfig::min_leader_tenure`.
+    pub in_tenure: bool,
+}
+
+/// LeaderBalancer periodically checks the number of leaders this node holds
+/// versus its peers, and transfers the leadership of some groups to the
+/// under-loaded nodes if this node holds more than `leader_balance_ratio`
+/// times the average. At most `max_leader_transfers` transfers are issued per
+/// interval to avoid thundering-herd elections.
+pub struct LeaderBalancer {
+    node_id: u64,
+    ratio: f64,
+    max_transfers: usize,
+    interval: Duration,
+    actor_address: MultiRaftActorAddress,
+}
+
+impl LeaderBalancer {
+    pub fn spawn(
+        cfg: &MultiRaftConfig,
+        node_id: u64,
+        actor_address: MultiRaftActorAddress,
+        stop: watch::Receiver<bool>,
+    ) -> JoinHandle<()> {
+        let balancer = LeaderBalancer {
+            node_id,
+            ratio: cfg.leader_balance_ratio,
+            max_transfers: cfg.max_leader_transfers,
+            interval: Duration::from_millis(cfg.leader_balance_interval),
+            actor_address,
+        };
+
+        tokio::spawn(async move {
+            balancer.start(stop).await;
+        })
+    }
+
+    async fn start(self, mut stop: watch::Receiver<bool>) {
+        let mut ticker = interval(self.interval);
+        // the first tick completes immediately, skip it.
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                _ = stop.changed() => {
+                    if *stop.borrow() {
+                        break
+                    }
+                }
+                _ = ticker.tick() => self.balance().await,
+            }
+        }
+    }
+
+    async fn balance(&self) {
+        let (tx, rx) = oneshot::channel();
+        if let Err(_) = self
+            .actor_address
+            .query_group_tx
+            .send(QueryGroup::Leaderships(tx))
+            .await
+        {
+            return;
+        }
+
+        let leaderships = match rx.await {
+            Err(_) => return,
+            Ok(leaderships) => leaderships,
+        };
+
+        let transfers = plan_leader_transfers(
+            self.node_id,
+            &leaderships,
+            self.ratio,
+            self.max_transfers,
+        );
+        for (group_id, transferee) in transfers {
+            info!(
+                "node {} transfer leader of group {} to replica {}",
+                self.node_id, group_id, transferee
+            );
+            let (tx, rx) = oneshot::channel();
+            if let Err(_) = self
+                .actor_address
+                .transfer_leader_tx
+                .send((group_id, transferee, TransferLeaderPolicy::CatchUp, tx))
+                .await
+            {
+                return;
+            }
+            if let Ok(Err(err)) = rx.await {
+                warn!(
+                    "node {} transfer leader of group {} error: {}",
+                    self.node_id, group_id, err
+                );
+            }
+        }
+    }
+}
+
+/// Returns the `(group_id, transferee)` list of the leadership transfers which
+/// moves the leaders from `node_id` to the least loaded nodes, if `node_id`
+/// holds more than `ratio` times the average number of leaders.
+fn plan_leader_transfers(
+    node_id: u64,
+    leaderships: &[GroupLeadership],
+    ratio: f64,
+    max_transfers: usize,
+) -> Vec<(u64, u64)> {
+    let mut counts: HashMap<u64, usize> = HashMap::new();
+    for leadership in leaderships.iter() {
+        for voter in leadership.voters.iter() {
+            counts.entry(voter.node_id).or_insert(0);
+        }
+        if leadership.leader.node_id != 0 {
+            *counts.entry(leadership.leader.node_id).or_insert(0) += 1;
+        }
+    }
+
+    if counts.len() <= 1 {
+        return vec![];
+    }
+
+    // the node which hosts no voter, e.g. all its replicas are learners, leads
+    // no group.
+    let count =
+        |counts: &HashMap<u64, usize>, node_id: u64| counts.get(&node_id).copied().unwrap_or(0);
+    let total = counts.values().sum::<usize>();
+    let avg = total as f64 / counts.len() as f64;
+    let mut transfers = vec![];
+    for leadership in leaderships.iter() {
+        if transfers.len() >= max_transfers || count(&counts, node_id) as f64 <= avg * ratio {
+            break;
+        }
+
+        if leadership.leader.node_id != node_id || leadership.in_tenure {
+            continue;
+        }
+
+        let target = leadership
+            .voters
+            .iter()
+            .filter(|voter| voter.node_id != node_id)
+            .min_by_key(|voter| count(&counts, voter.node_id));
+        let target = match target {
+            None => continue,
+            Some(target) => target,
+        };
+
+        // moving the leader must not make the target more loaded than this node.
+        if count(&counts, target.node_id) + 1 >= count(&counts, node_id) {
+            continue;
+        }
+
+        *counts.entry(node_id).or_insert(0) -= 1;
+        *counts.entry(target.node_id).or_insert(0) += 1;
+        transfers.push((leadership.group_id, target.replica_id));
+    }
+
+    transfers
+}
+
+#[test]
+fn test_plan_leader_transfers() {
+    let replica = |node_id, replica_id| ReplicaDesc {
+        node_id,
+        replica_id,
+    };
+    // node 1 leads all 6 groups, replica i is on node i.
+    let leaderships = (1..=6)
+        .map(|group_id| GroupLeadership {
+            group_id,
+            leader: replica(1, 1),
+            voters: vec![replica(1, 1), replica(2, 2), replica(3, 3)],
+            in_tenure: false,
+        })
+        .collect::<Vec<_>>();
+
+    let transfers = plan_leader_transfers(1, &leaderships, 1.2, 10);
+    assert_eq!(transfers.len(), 4);
+    let to_node2 = transfers.iter().filter(|(_, to)| *to == 2).count();
+    let to_node3 = transfers.iter().filter(|(_, to)| *to == 3).count();
+    assert_eq!((to_node2, to_node3), (2, 2));
+
+    // rate limited.
+    assert_eq!(plan_leader_transfers(1, &leaderships, 1.2, 1).len(), 1);
+
+    // the under-loaded node does not shed.
+    assert!(plan_leader_transfers(2, &leaderships, 1.2, 10).is_empty());
+
+    // the node which hosts no voter of the groups leads none.
+    assert!(plan_leader_transfers(4, &leaderships, 1.2, 10).is_empty());
+
+    // the leaders within their tenure are not transferred.
+    let leaderships = leaderships
+        .into_iter()
+        .map(|leadership| GroupLeadership {
+            in_tenure: leadership.group_id <= 4,
+            ..leadership
+        })
+        .collect::<Vec<_>>();
+    let transfers = plan_leader_transfers(1, &leaderships, 1.2, 10);
+    assert!(transfers.iter().all(|(group_id, _)| *group_id > 4));
+    assert_eq!(transfers.len(), 2);
+}
@@ -0,0 +1,104 @@
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// Clock is the source of time of the multiraft, e.g. the staleness of the
+/// follower read, the last tick of the health check, the timeouts of the
+/// forwarded proposals and the inflight snapshots, and the round trips of the
+/// heartbeats. It's replaced by `MockClock` in tests to advance the time
+/// without real sleeps.
+pub trait Clock: Send + Sync + 'static {
+    /// Returns the monotonic time.
+    fn now(&self) -> Instant;
+
+    /// Returns the wall-clock time.
+    fn system_now(&self) -> SystemTime;
+
+    /// Returns the time elapsed since `earlier`, zero if it's in the future.
+    #[inline]
+    fn elapsed(&self, earlier: Instant) -> Duration {
+        self.now().saturating_duration_since(earlier)
+    }
+
+    /// Returns the microseconds since the unix epoch of `system_now`.
+    #[inline]
+    fn now_micros(&self) -> u64 {
+        self.system_now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64)
+    }
+}
+
+/// SystemClock reads the time of the system, it's the default clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    #[inline]
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// MockClock only moves forward by `advance`, the clones share the same time.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    instant: Instant,
+    system: SystemTime,
+    offset: Arc<Mutex<Duration>>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            instant: Instant::now(),
+            system: SystemTime::now(),
+            offset: Default::default(),
+        }
+    }
+
+    /// Move the time forward by `d`.
+    pub fn advance(&self, d: Duration) {
+        *self.offset.lock().unwrap() += d;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.instant + *self.offset.lock().unwrap()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.system + *self.offset.lock().unwrap()
+    }
+}
+
+#[test]
+fn test_mock_clock_advance() {
+    let clock = MockClock::new();
+    let start = clock.now();
+    let micros = clock.now_micros();
+    assert_eq!(clock.elapsed(start), Duration::ZERO);
+
+    clock.clone().advance(Duration::from_secs(3));
+    assert_eq!(clock.elapsed(start), Duration::from_secs(3));
+    assert_eq!(clock.now_micros() - micros, 3_000_000);
+    assert_eq!(
+        clock.elapsed(clock.now() + Duration::from_secs(1)),
+        Duration::ZERO
+    );
+}
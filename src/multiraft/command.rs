@@ -0,0 +1,52 @@
+use super::error::Error;
+
+/// Command is the typed proposal of the state machine, it's encoded to the
+/// data of the entry by `MultiRaft::propose_command` and decoded from the
+/// applied entry by `ApplyEvent::command`, so that the wire format of the
+/// commands is defined in one place. The raw `Vec<u8>` proposals are still
+/// accepted, the state machine must decode the data in the same format.
+pub trait Command: Sized + Send + 'static {
+    fn encode(&self) -> Result<Vec<u8>, Error>;
+
+    fn decode(data: &[u8]) -> Result<Self, Error>;
+}
+
+/// BincodeCommand encodes any serde type by bincode.
+#[cfg(feature = "bincode-command")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BincodeCommand<T>(pub T);
+
+#[cfg(feature = "bincode-command")]
+impl<T> Command for BincodeCommand<T>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned + Send + 'static,
+{
+    fn encode(&self) -> Result<Vec<u8>, Error> {
+        bincode::serialize(&self.0).map_err(|err| Error::Codec(err.to_string()))
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, Error> {
+        bincode::deserialize(data)
+            .map(BincodeCommand)
+            .map_err(|err| Error::Codec(err.to_string()))
+    }
+}
+
+#[cfg(feature = "bincode-command")]
+#[test]
+fn test_bincode_command() {
+    #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+    enum Counter {
+        Add(u64),
+        Reset,
+    }
+
+    for cmd in [Counter::Add(3), Counter::Reset] {
+        let data = BincodeCommand(cmd.clone()).encode().unwrap();
+        assert_eq!(BincodeCommand::<Counter>::decode(&data).unwrap().0, cmd);
+    }
+    assert!(matches!(
+        BincodeCommand::<Counter>::decode(&[0xff; 3]),
+        Err(Error::Codec(_))
+    ));
+}
@@ -0,0 +1,365 @@
+use std::sync::Arc;
+
+use prost::Message as ProstMessage;
+use tracing::warn;
+
+use crate::proto::ConfChange;
+use crate::proto::ConfChangeSingle;
+use crate::proto::ConfChangeTransition;
+use crate::proto::ConfChangeType;
+use crate::proto::ConfChangeV2;
+use crate::proto::ConfState;
+use crate::proto::Entry;
+use crate::proto::EntryType;
+use crate::proto::MembershipChangeData;
+use crate::proto::SnapshotMetadata;
+
+use super::error::ConfChangeError;
+use super::error::Error;
+use super::resolver::NodeResolver;
+
+/// Reconcile the stored `ConfState` of the recovering group with its latest
+/// snapshot and the committed conf changes in the log after the snapshot,
+/// which are read by `committed` only if the stored conf state differs from
+/// the one of the snapshot.
+///
+/// The conf state is saved after a conf change is applied while the applied
+/// index isn't persisted, so the stored one is valid if it's the conf state of
+/// the snapshot replayed with any prefix of the `committed` conf changes. The
+/// stored conf state which is lost (no voters and learners) is repaired to the
+/// conf state of the snapshot, the conf changes after it are applied again by
+/// raft. Any other mismatch fails with `ConfStateInconsistent`, rather than
+/// starting the group with the wrong membership.
+///
+/// Returns the repaired conf state which should be saved, or `None` if the
+/// stored one is consistent or it can't be verified, i.e. the group has no
+/// snapshot or the log isn't contiguous to the snapshot.
+pub(crate) fn reconcile_conf_state(
+    group_id: u64,
+    stored: &ConfState,
+    snapshot: &SnapshotMetadata,
+    first_index: u64,
+    committed: impl FnOnce() -> Result<Vec<Entry>, Error>,
+) -> Result<Option<ConfState>, Error> {
+    if snapshot.index == 0 || first_index != snapshot.index.saturating_add(1) {
+        return Ok(None);
+    }
+
+    let base = snapshot.conf_state.clone().unwrap_or_default();
+    if stored.voters.is_empty() && stored.learners.is_empty() {
+        return Ok(Some(base));
+    }
+
+    let mut expected = base;
+    if same_conf_state(stored, &expected) {
+        return Ok(None);
+    }
+    for entry in committed()?.iter() {
+        let changed = apply_conf_change_entry(&expected, entry).map_err(|reason| {
+            Error::ConfStateInconsistent(group_id, format!("entry {}: {}", entry.index, reason))
+        })?;
+        match changed {
+            None => continue,
+            Some(changed) => expected = changed,
+        }
+        if same_conf_state(stored, &expected) {
+            return Ok(None);
+        }
+    }
+
+    Err(Error::ConfStateInconsistent(
+        group_id,
+        format!(
+            "stored {:?} doesn't match the snapshot at {} or the conf changes after it, \
+             expected {:?}",
+            stored, snapshot.index, expected
+        ),
+    ))
+}
+
+fn same_conf_state(a: &ConfState, b: &ConfState) -> bool {
+    let sorted = |ids: &Vec<u64>| {
+        let mut ids = ids.clone();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    };
+    sorted(&a.voters) == sorted(&b.voters)
+        && sorted(&a.learners) == sorted(&b.learners)
+        && sorted(&a.voters_outgoing) == sorted(&b.voters_outgoing)
+        && sorted(&a.learners_next) == sorted(&b.learners_next)
+        && a.auto_leave == b.auto_leave
+}
+
+/// Returns the conf state after the conf change of the entry is applied, or
+/// `None` if the entry isn't a conf change. It follows the `Changer` of raft.
+fn apply_conf_change_entry(cs: &ConfState, entry: &Entry) -> Result<Option<ConfState>, String> {
+    let (transition, changes) = match entry.entry_type() {
+        EntryType::EntryNormal => return Ok(None),
+        EntryType::EntryConfChange => {
+            let cc = ConfChange::decode(entry.data.as_ref()).map_err(|err| err.to_string())?;
+            let mut single = ConfChangeSingle::default();
+            single.change_type = cc.change_type;
+            single.node_id = cc.node_id;
+            (ConfChangeTransition::Auto, vec![single])
+        }
+        EntryType::EntryConfChangeV2 => {
+            let cc = ConfChangeV2::decode(entry.data.as_ref()).map_err(|err| err.to_string())?;
+            (cc.transition(), cc.changes)
+        }
+    };
+    apply_conf_change(cs, transition, &changes).map(Some)
+}
+
+/// Returns the conf state after the changes are applied.
+fn apply_conf_change(
+    cs: &ConfState,
+    transition: ConfChangeTransition,
+    changes: &[ConfChangeSingle],
+) -> Result<ConfState, String> {
+    let mut cs = cs.clone();
+    let joint = !cs.voters_outgoing.is_empty();
+
+    // the empty conf change v2 leaves the joint consensus.
+    if transition == ConfChangeTransition::Auto && changes.is_empty() {
+        if !joint {
+            return Err("leave joint but the config isn't joint".to_owned());
+        }
+        cs.voters_outgoing.clear();
+        let learners_next = std::mem::take(&mut cs.learners_next);
+        cs.learners.extend(learners_next);
+        cs.auto_leave = false;
+        return Ok(cs);
+    }
+
+    if joint {
+        return Err("the config is already joint".to_owned());
+    }
+    if transition != ConfChangeTransition::Auto || changes.len() > 1 {
+        cs.voters_outgoing = cs.voters.clone();
+        cs.auto_leave = transition != ConfChangeTransition::Explicit;
+    }
+    for change in changes.iter() {
+        let id = change.node_id;
+        cs.voters.retain(|voter| *voter != id);
+        cs.learners.retain(|learner| *learner != id);
+        cs.learners_next.retain(|learner| *learner != id);
+        match change.change_type() {
+            ConfChangeType::AddNode => cs.voters.push(id),
+            // the outgoing voter becomes the learner after leaving the joint.
+            ConfChangeType::AddLearnerNode if cs.voters_outgoing.contains(&id) => {
+                cs.learners_next.push(id)
+            }
+            ConfChangeType::AddLearnerNode => cs.learners.push(id),
+            ConfChangeType::RemoveNode => {}
+        }
+    }
+    Ok(cs)
+}
+
+/// Returns the `ConfChangeV2` proposed for the membership change, the replica
+/// ids are the node ids of raft and `data` is carried as the context.
+pub fn membership_conf_change(data: &MembershipChangeData) -> ConfChangeV2 {
+    let mut cc = ConfChangeV2::default();
+    cc.transition = data.transition;
+    for change in data.changes.iter() {
+        let mut single = ConfChangeSingle::default();
+        single.change_type = change.change_type;
+        single.node_id = change.replica_id;
+        cc.changes.push(single);
+    }
+    cc.context = data.encode_to_vec().into();
+    cc
+}
+
+/// Check the conf change against the conf state of the group, returns the
+/// prospective conf state after it's applied.
+pub(crate) fn validate_conf_change(
+    group_id: u64,
+    cs: &ConfState,
+    cc: &ConfChangeV2,
+    resolver: Option<&Arc<dyn NodeResolver>>,
+) -> Result<ConfState, ConfChangeError> {
+    let changed =
+        apply_conf_change(cs, cc.transition(), &cc.changes).map_err(ConfChangeError::Invalid)?;
+    if changed.voters.is_empty() {
+        return Err(ConfChangeError::NoVoter);
+    }
+
+    let data = if cc.context.is_empty() {
+        MembershipChangeData::default()
+    } else {
+        MembershipChangeData::decode(cc.context.as_ref())
+            .map_err(|err| ConfChangeError::Invalid(err.to_string()))?
+    };
+    for change in cc.changes.iter() {
+        match change.change_type() {
+            ConfChangeType::AddNode | ConfChangeType::AddLearnerNode => {}
+            ConfChangeType::RemoveNode => continue,
+        }
+        let replica_id = change.node_id;
+        let node_id = data
+            .changes
+            .iter()
+            .find(|request| request.replica_id == replica_id)
+            .map(|request| request.node_id)
+            .ok_or(ConfChangeError::UnknownNode(replica_id))?;
+        if let Some(resolver) = resolver {
+            if resolver.resolve(node_id).is_none() {
+                return Err(ConfChangeError::UnresolvedNode(replica_id, node_id));
+            }
+        }
+    }
+
+    if changed.voters.len() % 2 == 0 {
+        warn!(
+            "the group {} has even {} voters after the conf change, \
+             which tolerates no more failures than one voter less",
+            group_id,
+            changed.voters.len()
+        );
+    }
+    Ok(changed)
+}
+
+#[test]
+fn test_reconcile_conf_state() {
+    let conf_state = |voters: Vec<u64>| ConfState {
+        voters,
+        ..Default::default()
+    };
+    let add_node = |index: u64, node_id: u64| {
+        let mut cc = ConfChange::default();
+        cc.set_change_type(ConfChangeType::AddNode);
+        cc.node_id = node_id;
+        let mut entry = Entry::default();
+        entry.set_entry_type(EntryType::EntryConfChange);
+        entry.index = index;
+        entry.data = cc.encode_to_vec();
+        entry
+    };
+    let mut snapshot = SnapshotMetadata::default();
+    snapshot.index = 5;
+    snapshot.conf_state = Some(conf_state(vec![1, 2, 3]));
+    let committed = || Ok(vec![add_node(6, 4), add_node(7, 5)]);
+    let unread = || -> Result<Vec<Entry>, Error> { panic!("the committed entries are read") };
+
+    // the conf state of the snapshot is verified without reading the log.
+    let stored = conf_state(vec![3, 2, 1]);
+    assert!(reconcile_conf_state(1, &stored, &snapshot, 6, unread)
+        .unwrap()
+        .is_none());
+
+    // the stored conf state after any prefix of the conf changes is valid.
+    for voters in [vec![1, 2, 3, 4], vec![1, 2, 3, 4, 5]] {
+        let stored = conf_state(voters);
+        assert!(reconcile_conf_state(1, &stored, &snapshot, 6, committed)
+            .unwrap()
+            .is_none());
+    }
+
+    // the lost conf state is repaired by the snapshot.
+    let repaired = reconcile_conf_state(1, &ConfState::default(), &snapshot, 6, unread);
+    assert_eq!(repaired.unwrap().unwrap().voters, vec![1, 2, 3]);
+
+    let err = reconcile_conf_state(1, &conf_state(vec![1, 2]), &snapshot, 6, committed);
+    assert!(matches!(err, Err(Error::ConfStateInconsistent(1, _))));
+
+    // the log compacted beyond the snapshot can't be verified.
+    let stored = conf_state(vec![1, 2]);
+    assert!(reconcile_conf_state(1, &stored, &snapshot, 7, unread)
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn test_validate_conf_change() {
+    use super::resolver::MemNodeResolver;
+    use super::resolver::NodeAddress;
+    use crate::proto::MembershipChangeRequest;
+
+    let change = |change_type: ConfChangeType, replica_id: u64| {
+        let mut request = MembershipChangeRequest::default();
+        request.set_change_type(change_type);
+        request.group_id = 1;
+        request.node_id = replica_id + 100;
+        request.replica_id = replica_id;
+        request
+    };
+    let conf_change = |changes: Vec<MembershipChangeRequest>| {
+        membership_conf_change(&MembershipChangeData {
+            group_id: 1,
+            changes,
+            ..Default::default()
+        })
+    };
+    let cs = ConfState {
+        voters: vec![1, 2, 3],
+        ..Default::default()
+    };
+
+    // the replicas resolve without the resolver.
+    let cc = conf_change(vec![change(ConfChangeType::AddNode, 4)]);
+    let changed = validate_conf_change(1, &cs, &cc, None).unwrap();
+    assert_eq!(changed.voters, vec![1, 2, 3, 4]);
+    assert!(changed.voters_outgoing.is_empty());
+
+    // the node of the added replica isn't resolved.
+    let mem_resolver = MemNodeResolver::new();
+    let resolver: Arc<dyn NodeResolver> = Arc::new(mem_resolver.clone());
+    assert_eq!(
+        validate_conf_change(1, &cs, &cc, Some(&resolver)),
+        Err(ConfChangeError::UnresolvedNode(4, 104))
+    );
+    mem_resolver.update(NodeAddress {
+        node_id: 104,
+        store_id: 104,
+        addr: "local://104".to_owned(),
+    });
+    assert!(validate_conf_change(1, &cs, &cc, Some(&resolver)).is_ok());
+
+    // the added replica isn't carried in the context.
+    let mut cc = conf_change(vec![change(ConfChangeType::AddLearnerNode, 5)]);
+    cc.changes[0].node_id = 6;
+    assert_eq!(
+        validate_conf_change(1, &cs, &cc, None),
+        Err(ConfChangeError::UnknownNode(6))
+    );
+
+    // remove the last voter.
+    let single = ConfState {
+        voters: vec![1],
+        learners: vec![2],
+        ..Default::default()
+    };
+    let cc = conf_change(vec![change(ConfChangeType::RemoveNode, 1)]);
+    assert_eq!(
+        validate_conf_change(1, &single, &cc, None),
+        Err(ConfChangeError::NoVoter)
+    );
+    let cc = conf_change(vec![
+        change(ConfChangeType::RemoveNode, 1),
+        change(ConfChangeType::RemoveNode, 2),
+        change(ConfChangeType::RemoveNode, 3),
+    ]);
+    assert_eq!(
+        validate_conf_change(1, &cs, &cc, None),
+        Err(ConfChangeError::NoVoter)
+    );
+
+    // the config is already joint.
+    let joint = ConfState {
+        voters: vec![1, 2, 4],
+        voters_outgoing: vec![1, 2, 3],
+        ..Default::default()
+    };
+    let cc = conf_change(vec![change(ConfChangeType::AddNode, 5)]);
+    assert!(matches!(
+        validate_conf_change(1, &joint, &cc, None),
+        Err(ConfChangeError::Invalid(_))
+    ));
+    // leaving the joint consensus is valid.
+    let changed = validate_conf_change(1, &joint, &conf_change(vec![]), None).unwrap();
+    assert_eq!(changed.voters, vec![1, 2, 4]);
+    assert!(changed.voters_outgoing.is_empty());
+}
@@ -1,7 +1,677 @@
+use std::time::Duration;
+
+use super::error::Error;
+use super::raft_group::AutoPromotePolicy;
+
+/// The max bytes of the entries in a `MsgApp` sent by the leader, an entry
+/// larger than it is still sent alone in a `MsgApp`.
+pub const MAX_SIZE_PER_MSG: u64 = 1024 * 1024;
+
+/// The version of the encoding of the configs, see `encode_config`.
+#[cfg(feature = "serde-config")]
+pub const CONFIG_ENCODING_VERSION: u8 = 1;
+
 #[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde-config",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
 /// RaftGroup configuration in physical node.
 pub struct MultiRaftConfig {
     pub election_tick: usize,
     pub heartbeat_tick: usize,
     pub tick_interval: u64, // ms
+
+    /// The election timeout of each group is randomized in the range
+    /// `[min_election_tick, max_election_tick]`. 0 falls back to `election_tick`
+    /// for the min and `2 * min_election_tick - 1` for the max, which is the
+    /// default range of raft. It's required that
+    /// `heartbeat_tick < min_election_tick <= max_election_tick`.
+    pub min_election_tick: usize,
+    pub max_election_tick: usize,
+
+    /// The initial election timer of each group created on this node starts
+    /// after a random delay in `[0, startup_election_jitter]` ticks, which
+    /// spreads the elections of the groups when the whole cluster restarts at
+    /// once. The delay ends early once the group learns the leader. 0 disables
+    /// the jitter.
+    pub startup_election_jitter: usize,
+
+    /// If not 0, a follower which heard from its leader within the last
+    /// `election_extension_window` ticks extends its election timeout by at
+    /// most `max_election_extension` ticks, i.e. the ticks are withheld from
+    /// the election timer while it's about to expire, until the next contact
+    /// with the leader. It reduces the spurious elections when the heartbeats
+    /// are intermittently delayed or dropped by a jittery network, while the
+    /// dead leader is still detected within `max_election_tick +
+    /// max_election_extension` ticks. The window should be larger than
+    /// `max_election_tick`, otherwise the timeout is rarely extended.
+    pub election_extension_window: usize,
+    pub max_election_extension: usize,
+
+    /// The groups are ticked by a single timer. If `tick_stagger_slots` > 1,
+    /// the groups are divided into slots by `group_id % tick_stagger_slots`,
+    /// the timer fires every `tick_interval / tick_stagger_slots` ms and ticks
+    /// the groups of one slot per pass, so each group is still ticked once per
+    /// `tick_interval` and the cost of ticking many groups is spread over the
+    /// interval. 0 or 1 ticks all groups in one pass.
+    pub tick_stagger_slots: usize,
+
+    /// If true, the tick of a follower which heard from its leader within the
+    /// last `heartbeat_tick` ticks and has no pending work is skipped, neither
+    /// raft nor the health, proposal and quiesce bookkeeping is ticked. The
+    /// skipped ticks are replayed to raft once the heartbeat is missed, so the
+    /// leader loss is still detected within the election timeout. It reduces
+    /// the cost of ticking many idle follower groups.
+    pub follower_light_tick: bool,
+
+    /// The group which has no leader or doesn't advance the commit index despite
+    /// proposals for `group_unhealthy_multiple` times the max election timeout
+    /// is reported by the `GroupUnhealthy` event. 0 disables the detection.
+    pub group_unhealthy_multiple: usize,
+
+    /// The max number of groups whose ready are handled per iteration of the
+    /// actor loop, the rest are handled in the next iterations in round-robin,
+    /// so that ticks and messages are not starved by many busy groups.
+    /// 0 means unlimited.
+    pub ready_groups_budget: usize,
+
+    /// If true, the messages of the readies handled in one iteration of the
+    /// actor loop, across all groups, are handed to the transport in one
+    /// `Transport::send_batch`, otherwise they're sent one by one. Either way
+    /// they're sent at the end of the iteration.
+    pub batch_ready_messages: bool,
+
+    /// The max number of pending proposals per group, the proposal beyond it is
+    /// rejected by `ProposalError::ProposalQueueFull` until the pending
+    /// proposals are applied. 0 means unlimited.
+    pub max_pending_proposals: usize,
+
+    /// The max number of pending proposals of a client per group, the client
+    /// is identified by the `client_id` of the idempotent proposal. The
+    /// proposal beyond it is rejected by `ProposalError::ClientQuotaExceeded`
+    /// until the previous proposals of the client are applied, so a flooding
+    /// client doesn't monopolize the group shared with other clients. The
+    /// proposals without client are not limited. 0 means unlimited.
+    pub max_client_pending_proposals: usize,
+
+    /// The max bytes of committed entries delivered to the apply actor in a
+    /// ready of group, at least one entry is delivered. It bounds the apply
+    /// bursts, e.g. when the node recovers a large committed backlog, so that
+    /// a ready doesn't block the actor for long. 0 means unlimited.
+    pub max_committed_size_per_ready: u64,
+
+    /// The max bytes of the entries returned by a `MultiRaft::read_committed`,
+    /// at least one entry is returned, so the reader makes progress by
+    /// continuing from the index after the last returned entry. 0 means
+    /// unlimited.
+    pub max_read_committed_size: u64,
+
+    /// The max bytes of the uncommitted entries of the leader, the proposal
+    /// beyond it is dropped by raft and rejected by `ProposalError::Dropped`,
+    /// which bounds the memory of a leader which can't reach the quorum but
+    /// still accepts the writes. At least one proposal is accepted if there
+    /// are no uncommitted entries. 0 means unlimited.
+    pub max_uncommitted_size: u64,
+
+    /// The max bytes of the data and context of a proposal, the proposal
+    /// beyond it is rejected by `ProposalError::EntryTooLarge` before it's
+    /// appended to the log. An entry larger than `MAX_SIZE_PER_MSG` can't be
+    /// batched with others in a `MsgApp`, so it should be less than
+    /// `MAX_SIZE_PER_MSG` with headroom for the entry header, the proposal
+    /// context and the framing of the message. 0 means unlimited.
+    pub max_entry_size: u64,
+
+    /// The max number of the committed entries of the leader which aren't
+    /// applied yet, the proposal beyond it is rejected by
+    /// `ProposalError::ApplyBacklogFull` until the apply catches up, which
+    /// bounds the backlog when the state machine applies slower than the
+    /// entries are committed. The uncommitted entries are not counted, e.g.
+    /// the leader which loses the quorum doesn't reject by it. The rejected
+    /// proposal can be retried, e.g. by `MultiRaft::propose_with_retry`. 0
+    /// means unlimited.
+    pub max_apply_backlog: u64,
+
+    /// The max bytes of the entries buffered in the logs of all groups on the
+    /// node, which dominates the memory of the memory storage with many
+    /// groups. Once it's exceeded, the groups with the largest logs are
+    /// snapshotted and compacted up to their applied index on the tick until
+    /// the logs fit in it, see `NodeHealth::log_bytes`. It's a safety valve of
+    /// the memory pressure rather than the regular compaction, the lagging
+    /// followers of the compacted groups catch up by the snapshot. The logs
+    /// are compacted only with `MultiRaftExtensions::state_machine`, whose
+    /// state is carried by the snapshot. 0 means unlimited.
+    pub max_log_memory: u64,
+
+    /// The max number of the entries in the log of a group which aren't
+    /// compacted yet, the log over it is snapshotted and compacted up to the
+    /// applied index on the tick. The leader keeps the entries which a
+    /// replica hasn't matched, so the log is compacted up to the least matched
+    /// index, and if a lagging replica pins the log at the limit so it can't
+    /// be compacted further, the proposal is rejected by
+    /// `ProposalError::LogFull` and the `LogPinned` event names the replica,
+    /// rather than the log grows without bound. Like `max_log_memory`, the
+    /// logs are compacted only with `MultiRaftExtensions::state_machine`.
+    /// 0 means unlimited.
+    pub max_log_entries: u64,
+
+    /// The max number of clients whose latest applied sequence is recorded per
+    /// group for `MultiRaft::propose_idempotent`, the client applied least
+    /// recently is evicted beyond it. 0 disables the deduplication.
+    pub proposal_dedup_capacity: usize,
+
+    /// If non-zero, the raft messages of the group which isn't created on this
+    /// node yet are buffered, at most `pending_group_messages` per group of at
+    /// most `pending_message_groups` groups for `pending_group_message_ttl` ms,
+    /// and replayed after the group is created explicitly, e.g. by
+    /// `MultiRaft::initial_raft_group`. The messages beyond the limits or
+    /// expired are dropped, see `DropReason::PendingOverflow` and
+    /// `DropReason::PendingExpired`. If zero, the group is created by its
+    /// first message with an empty storage.
+    pub pending_group_messages: usize,
+    pub pending_message_groups: usize,
+    pub pending_group_message_ttl: u64, // ms
+
+    /// If true, the write proposed to a follower which knows the leader is
+    /// forwarded to the leader and the result is relayed back, rather than
+    /// failed with `NotLeader`.
+    pub proposal_forwarding: bool,
+
+    /// If true, the node is an observer, e.g. a dedicated analytics node, its
+    /// replicas only follow and apply the log: they are never ticked to start
+    /// an election, and ignore the campaign and `MsgTimeoutNow`, so they never
+    /// become the leader and the proposals and the read index are routed to the
+    /// leader like any follower. The replicas of the observer should be added
+    /// as learners, so that they are excluded from the quorum, an observer
+    /// voter still votes.
+    pub observer: bool,
+
+    /// If true, a group which has a stable leader and no proposals for
+    /// `quiesce_ticks` ticks is quiesced, the quiesced group is not ticked
+    /// and does not send heartbeats until it is woken by activity. The nodes
+    /// keep in contact by the empty coalesced heartbeats, the quiesced
+    /// follower which hears nothing from the node of its leader for an
+    /// election timeout wakes, so that a dead leader is replaced.
+    pub enable_quiesce: bool,
+    pub quiesce_ticks: usize,
+
+    /// If true, the followers report their applied index to the leader in the
+    /// heartbeat responses, which is required by `MultiRaft::wait_quorum_applied`.
+    /// No extra message is sent unless `applied_report_batch` is set, but each
+    /// heartbeat response grows by one varint (up to 10 bytes), and the
+    /// reported index is as stale as the heartbeat interval. The quiesced
+    /// group doesn't report.
+    pub report_applied_index: bool,
+
+    /// If not 0, the follower which has applied `applied_report_batch` entries
+    /// since its last report also reports the applied index on the next tick,
+    /// rather than waiting for the heartbeat of the leader. The reports are
+    /// sent with the coalesced heartbeat responses, so at most one message is
+    /// sent per node per tick no matter how many entries are applied. It
+    /// requires `report_applied_index`.
+    pub applied_report_batch: usize,
+
+    /// The max bytes of recently appended entries cached in memory per group,
+    /// which reduces the reads of `RaftStorage`. 0 disables the cache.
+    pub entry_cache_size: usize,
+
+    /// The ready which fails to be persisted is not advanced, it's retried
+    /// after `storage_write_retry_backoff` ms, and the backoff doubles per
+    /// failure. The group is poisoned after `storage_write_retries` consecutive
+    /// failures, 0 poisons the group at the first failure.
+    pub storage_write_retries: usize,
+    pub storage_write_retry_backoff: u64, // ms
+
+    /// If not 0, the readies of the groups handled in one iteration of the
+    /// actor are written without fsync, and synced together by one
+    /// `MultiRaftStorage::sync_writes` before their messages are sent and
+    /// they are advanced, which amortizes the fsync across groups. The batch
+    /// is synced once its writes take `write_sync_batch_latency` ms, which
+    /// bounds the latency added to the first write of the batch. 0 disables
+    /// the batching, each ready is synced by its `write_ready`.
+    pub write_sync_batch_latency: u64, // ms
+
+    /// The number of the workers which persist the readies of the groups
+    /// handled in one iteration of the actor concurrently. A group is always
+    /// persisted by the same worker, so the readies of it are written in
+    /// order. Taking the readies and advancing them stay in the actor, so it
+    /// only scales the storage writes, e.g. the fsyncs of `SegmentedStorage`
+    /// which are issued out of its log lock, while its appends still take the
+    /// lock one by one. 0 and 1 persist the readies in the actor one by one.
+    pub ready_workers: usize,
+
+    /// The max number of groups whose replica metadata is cached in memory,
+    /// the least recently used group which isn't led by this node is evicted
+    /// beyond it. 0 means unlimited.
+    pub replica_cache_capacity: usize,
+
+    /// The capacities of the mailboxes of the actor: the proposals (each of
+    /// the writes, the read indexes and the membership changes has its own
+    /// mailbox) and the raft messages received from the peer nodes. Once a
+    /// mailbox is full, `MultiRaft::write` and the others, and the intake of
+    /// the transport await until the actor catches up, rather than queue
+    /// without bound. The depths are reported by `MultiRaft::mailbox_stats`.
+    /// The other mailboxes, e.g. the group management and the queries, hold
+    /// one command.
+    pub proposal_mailbox_capacity: usize,
+    pub message_mailbox_capacity: usize,
+
+    /// The capacity of the channel which publishes the applied entries to the
+    /// subscribers of `MultiRaft::apply_results`.
+    pub apply_results_capacity: usize,
+
+    /// The number of the last applied entries retained in memory for each
+    /// group, which are replayed to the late subscribers of
+    /// `MultiRaft::apply_results_from`. The entries of a group hold at most
+    /// `apply_results_retention` entries including their data and context.
+    /// 0 disables the retention.
+    pub apply_results_retention: usize,
+
+    /// The max bytes of the data and context of the entries retained for each
+    /// group by `apply_results_retention`, the oldest entries are evicted
+    /// beyond it. 0 means unlimited.
+    pub apply_results_retention_size: u64,
+
+    /// If true, the entries applied on this node are delivered as one stream
+    /// ordered by (apply_round, group_id, index): the `Event::Apply` of all
+    /// groups applied in a round are sent in one batch sorted by
+    /// (group_id, index), and `MultiRaft::apply_results` is published in the
+    /// same order. It's required by the state machine which keeps an order
+    /// across groups, e.g. a shared secondary index.
+    ///
+    /// It trades the parallelism for the ordering, the entries of a round are
+    /// not delivered until all groups of the round are applied, so the apply
+    /// latency of a group grows with the number of groups applied together,
+    /// and the state machine can't apply the groups of a batch in parallel
+    /// without breaking the order. By default the entries are delivered per
+    /// group as soon as the group is applied.
+    pub ordered_apply: bool,
+
+    /// If true, the `LeaderBalancer` transfers the leadership of groups to the
+    /// under-loaded nodes every `leader_balance_interval` ms, when this node holds
+    /// more than `leader_balance_ratio` times the average number of leaders. At
+    /// most `max_leader_transfers` transfers are issued per interval.
+    pub enable_leader_balance: bool,
+    pub leader_balance_interval: u64, // ms
+    pub leader_balance_ratio: f64,
+    pub max_leader_transfers: usize,
+
+    /// The snapshot larger than `snapshot_chunk_size` bytes is streamed in chunks,
+    /// at most `snapshot_chunk_window` chunks are inflight to a replica. 0 disables
+    /// the chunking.
+    pub snapshot_chunk_size: usize,
+    pub snapshot_chunk_window: usize,
+
+    /// The chunked snapshot larger than `max_snapshot_size` bytes is rejected
+    /// by the receiver before its data is buffered.
+    pub max_snapshot_size: u64,
+
+    /// At most one snapshot is inflight to a replica, a new snapshot can be
+    /// sent to the replica after the inflight one is installed or not finished
+    /// within `snapshot_inflight_timeout` ms.
+    pub snapshot_inflight_timeout: u64, // ms
+
+    /// At most `max_concurrent_snapshots` snapshots are inflight from the node
+    /// across all groups, the others are queued until the inflight ones are
+    /// finished, so that the lagging followers of many groups don't saturate
+    /// the disk and network at once. 0 disables the limit.
+    pub max_concurrent_snapshots: usize,
+
+    /// Suppress the messages the replica can't use while it installs a
+    /// chunked snapshot. The follower drops the appends and the vote requests
+    /// and defers its election until the snapshot is reassembled, and the
+    /// leader pauses the appends to the replica with an inflight snapshot,
+    /// whose `snapshot_inflight_timeout` restarts on each acked chunk, so the
+    /// slow but progressing snapshot isn't sent again.
+    pub snapshot_install_suppression: bool,
+
+    /// Once a leader is elected, the replicas of the group which know it don't
+    /// vote for the other candidates for `min_leader_tenure` ticks, and the
+    /// leader isn't transferred away by the `LeaderBalancer` or the leader
+    /// priorities, which damps the leadership churn in an unstable network.
+    /// The explicit `MultiRaft::transfer_leader` is still honored. It only
+    /// delays the elections and never overrides the safety of raft: the leader
+    /// still steps down once it hears the higher term from a new leader elected
+    /// by the others, and with check_quorum the leader which loses the quorum
+    /// steps down regardless. 0 disables it.
+    pub min_leader_tenure: usize,
+
+    /// `MultiRaft::prepare_shutdown` waits at most `shutdown_transfer_timeout`
+    /// ms for the leadership of groups to be transferred before stopping.
+    pub shutdown_transfer_timeout: u64, // ms
+
+    /// If true, the actor which dies unexpectedly, e.g. of a panic which isn't
+    /// isolated to a group, is restarted by the watchdog with the same
+    /// mailboxes, and its replicas are recreated from the storage, so their
+    /// in-memory settings (e.g. the leader priority) are lost and the
+    /// committed entries since the last snapshot are applied again. Either way
+    /// the death is emitted as `Event::NodeFailure`.
+    pub restart_actor_on_panic: bool,
+
+    /// The watchdog waits `actor_restart_backoff` ms before restarting the
+    /// dead actor, doubled on each restart, so a deterministic panic doesn't
+    /// spin in a restart loop.
+    pub actor_restart_backoff: u64, // ms
+
+    /// The actor is restarted at most `max_actor_restarts` times, after that
+    /// the dead actor isn't restarted as if `restart_actor_on_panic` is false.
+    pub max_actor_restarts: usize,
+
+    /// If true, the groups are not ticked by the `tick_interval` timer,
+    /// the ticks are advanced explicitly via `MultiRaft::tick`, which is
+    /// used to drive deterministic tests.
+    #[cfg(feature = "test-util")]
+    pub manual_tick: bool,
+}
+
+impl Default for MultiRaftConfig {
+    fn default() -> Self {
+        Self {
+            election_tick: 10,
+            heartbeat_tick: 2,
+            tick_interval: 100,
+            min_election_tick: 0,
+            max_election_tick: 0,
+            startup_election_jitter: 0,
+            election_extension_window: 0,
+            max_election_extension: 0,
+            tick_stagger_slots: 1,
+            follower_light_tick: false,
+            group_unhealthy_multiple: 3,
+            ready_groups_budget: 256,
+            batch_ready_messages: true,
+            max_pending_proposals: 0,
+            max_client_pending_proposals: 0,
+            max_committed_size_per_ready: 0,
+            max_read_committed_size: 1024 * 1024,
+            max_uncommitted_size: 0,
+            max_entry_size: 0,
+            max_apply_backlog: 0,
+            max_log_memory: 0,
+            max_log_entries: 0,
+            proposal_dedup_capacity: 1024,
+            pending_group_messages: 0,
+            pending_message_groups: 1024,
+            pending_group_message_ttl: 1000,
+            proposal_forwarding: false,
+            observer: false,
+            enable_quiesce: false,
+            quiesce_ticks: 20,
+            report_applied_index: false,
+            applied_report_batch: 0,
+            entry_cache_size: 1024 * 1024,
+            storage_write_retries: 10,
+            storage_write_retry_backoff: 100,
+            write_sync_batch_latency: 0,
+            ready_workers: 1,
+            replica_cache_capacity: 0,
+            proposal_mailbox_capacity: 256,
+            message_mailbox_capacity: 256,
+            apply_results_capacity: 1024,
+            apply_results_retention: 0,
+            apply_results_retention_size: 1024 * 1024,
+            ordered_apply: false,
+            enable_leader_balance: false,
+            leader_balance_interval: 60 * 1000,
+            leader_balance_ratio: 1.2,
+            max_leader_transfers: 4,
+            snapshot_chunk_size: 1024 * 1024,
+            snapshot_chunk_window: 4,
+            max_snapshot_size: 1024 * 1024 * 1024,
+            snapshot_inflight_timeout: 60 * 1000,
+            max_concurrent_snapshots: 4,
+            snapshot_install_suppression: false,
+            min_leader_tenure: 0,
+            shutdown_transfer_timeout: 1000,
+            restart_actor_on_panic: false,
+            actor_restart_backoff: 100,
+            max_actor_restarts: 3,
+            #[cfg(feature = "test-util")]
+            manual_tick: false,
+        }
+    }
+}
+
+impl MultiRaftConfig {
+    /// Returns the inclusive range of the randomized election timeout ticks.
+    pub fn election_tick_range(&self) -> (usize, usize) {
+        let min = if self.min_election_tick == 0 {
+            self.election_tick
+        } else {
+            self.min_election_tick
+        };
+        let max = if self.max_election_tick == 0 {
+            (2 * min).saturating_sub(1)
+        } else {
+            self.max_election_tick
+        };
+        (min, max)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        let (min, max) = self.election_tick_range();
+        if self.heartbeat_tick >= min {
+            return Err(Error::BadParameter(format!(
+                "heartbeat_tick ({}) must be less than min_election_tick ({})",
+                self.heartbeat_tick, min
+            )));
+        }
+
+        if min > max {
+            return Err(Error::BadParameter(format!(
+                "min_election_tick ({}) must be less than or equal to max_election_tick ({})",
+                min, max
+            )));
+        }
+
+        if self.election_extension_window != 0 && self.max_election_extension == 0 {
+            return Err(Error::BadParameter(format!(
+                "max_election_extension must be positive if election_extension_window ({}) is set",
+                self.election_extension_window
+            )));
+        }
+
+        if self.tick_stagger_slots as u64 > self.tick_interval {
+            return Err(Error::BadParameter(format!(
+                "tick_stagger_slots ({}) must be less than or equal to tick_interval ({})",
+                self.tick_stagger_slots, self.tick_interval
+            )));
+        }
+
+        if self.proposal_mailbox_capacity == 0 || self.message_mailbox_capacity == 0 {
+            return Err(Error::BadParameter(format!(
+                "proposal_mailbox_capacity ({}) and message_mailbox_capacity ({}) must be positive",
+                self.proposal_mailbox_capacity, self.message_mailbox_capacity
+            )));
+        }
+
+        if self.max_entry_size > MAX_SIZE_PER_MSG {
+            return Err(Error::BadParameter(format!(
+                "max_entry_size ({}) must be less than or equal to max_size_per_msg ({})",
+                self.max_entry_size, MAX_SIZE_PER_MSG
+            )));
+        }
+
+        if self.applied_report_batch != 0 && !self.report_applied_index {
+            return Err(Error::BadParameter(format!(
+                "applied_report_batch ({}) requires report_applied_index",
+                self.applied_report_batch
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the interval of the tick passes and the number of slots. The
+    /// interval is truncated to nanoseconds rather than milliseconds, so that
+    /// the passes of all slots add up to `tick_interval` within a few
+    /// nanoseconds, e.g. 3 slots of 100ms don't tick every 99ms.
+    pub fn tick_pass_interval(&self) -> (Duration, usize) {
+        let slots = self.tick_stagger_slots.max(1);
+        (Duration::from_millis(self.tick_interval) / slots as u32, slots)
+    }
+}
+
+/// The per-group overrides of `MultiRaftConfig`. It's set on one node by
+/// `MultiRaft::set_group_config`, or proposed by
+/// `MultiRaft::propose_group_config` as an admin entry, which is applied by
+/// every replica of the group at the same index.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde-config",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct GroupConfig {
+    /// Overrides `MultiRaftConfig::max_pending_proposals`, `None` follows the
+    /// node.
+    pub max_pending_proposals: Option<usize>,
+
+    /// The policy of promoting the caught-up learners, `None` disables it, see
+    /// `MultiRaft::set_auto_promote`.
+    pub auto_promote: Option<AutoPromotePolicy>,
+}
+
+/// Encode the config as `version | json`, the fields are encoded in the order
+/// they're declared, so the same config is always encoded to the same bytes.
+///
+/// The missing fields are decoded as their defaults and the unknown fields are
+/// ignored, so adding a field doesn't bump `CONFIG_ENCODING_VERSION`, and the
+/// nodes of the old and the new versions decode the configs of each other.
+/// The version is bumped only by the incompatible change, e.g. the meaning of
+/// a field changes.
+#[cfg(feature = "serde-config")]
+pub fn encode_config<T: serde::Serialize>(config: &T) -> Vec<u8> {
+    let mut buf = vec![CONFIG_ENCODING_VERSION];
+    serde_json::to_writer(&mut buf, config).unwrap();
+    buf
+}
+
+/// Decode the config encoded by `encode_config`, returns `Codec` if the data
+/// is malformed or encoded by a newer version.
+#[cfg(feature = "serde-config")]
+pub fn decode_config<T: serde::de::DeserializeOwned>(data: &[u8]) -> Result<T, Error> {
+    match data.first() {
+        None => Err(Error::Codec(format!("empty config"))),
+        Some(&version) if version == 0 || version > CONFIG_ENCODING_VERSION => Err(Error::Codec(
+            format!("unsupported config encoding version {}", version),
+        )),
+        Some(_) => serde_json::from_slice(&data[1..]).map_err(|err| Error::Codec(err.to_string())),
+    }
+}
+
+#[test]
+fn test_election_tick_range() {
+    let cfg = MultiRaftConfig {
+        election_tick: 10,
+        heartbeat_tick: 2,
+        ..Default::default()
+    };
+    assert_eq!(cfg.election_tick_range(), (10, 19));
+    assert!(cfg.validate().is_ok());
+
+    let cfg = MultiRaftConfig {
+        min_election_tick: 5,
+        max_election_tick: 5,
+        ..cfg
+    };
+    assert_eq!(cfg.election_tick_range(), (5, 5));
+    assert!(cfg.validate().is_ok());
+
+    let bad = MultiRaftConfig {
+        heartbeat_tick: 5,
+        ..cfg.clone()
+    };
+    assert!(bad.validate().is_err());
+
+    let bad = MultiRaftConfig {
+        max_election_tick: 4,
+        ..cfg
+    };
+    assert!(bad.validate().is_err());
+}
+
+#[test]
+fn test_max_entry_size() {
+    let cfg = MultiRaftConfig {
+        max_entry_size: MAX_SIZE_PER_MSG / 2,
+        ..Default::default()
+    };
+    assert!(cfg.validate().is_ok());
+
+    let bad = MultiRaftConfig {
+        max_entry_size: MAX_SIZE_PER_MSG + 1,
+        ..cfg
+    };
+    assert!(bad.validate().is_err());
+}
+
+#[test]
+fn test_tick_stagger_slots() {
+    let cfg = MultiRaftConfig::default();
+    assert_eq!(cfg.tick_pass_interval(), (Duration::from_millis(100), 1));
+
+    let cfg = MultiRaftConfig {
+        tick_stagger_slots: 4,
+        ..cfg
+    };
+    assert!(cfg.validate().is_ok());
+    assert_eq!(cfg.tick_pass_interval(), (Duration::from_millis(25), 4));
+
+    // the passes of the slots add up to the tick interval.
+    let cfg = MultiRaftConfig {
+        tick_stagger_slots: 3,
+        ..cfg
+    };
+    let (interval, slots) = cfg.tick_pass_interval();
+    assert!(Duration::from_millis(100) - interval * slots as u32 < Duration::from_micros(1));
+
+    let bad = MultiRaftConfig {
+        tick_stagger_slots: 101,
+        ..cfg
+    };
+    assert!(bad.validate().is_err());
+}
+
+#[cfg(feature = "serde-config")]
+#[test]
+fn test_config_encoding() {
+    let cfg = MultiRaftConfig {
+        election_tick: 20,
+        leader_balance_ratio: 1.5,
+        ..Default::default()
+    };
+    let data = encode_config(&cfg);
+    assert_eq!(data, encode_config(&cfg.clone()));
+    let decoded = decode_config::<MultiRaftConfig>(&data).unwrap();
+    assert_eq!(decoded.election_tick, 20);
+    assert_eq!(decoded.leader_balance_ratio, 1.5);
+
+    let group_cfg = GroupConfig {
+        max_pending_proposals: Some(8),
+        auto_promote: Some(AutoPromotePolicy {
+            max_lag: 1,
+            stable_ticks: 2,
+        }),
+    };
+    assert_eq!(
+        decode_config::<GroupConfig>(&encode_config(&group_cfg)).unwrap(),
+        group_cfg
+    );
+
+    // the missing field is the default and the unknown field is ignored.
+    let mut data = vec![CONFIG_ENCODING_VERSION];
+    data.extend_from_slice(br#"{"max_pending_proposals":8,"added_later":true}"#);
+    let decoded = decode_config::<GroupConfig>(&data).unwrap();
+    assert_eq!(decoded.max_pending_proposals, Some(8));
+    assert_eq!(decoded.auto_promote, None);
+
+    let mut data = encode_config(&group_cfg);
+    data[0] = CONFIG_ENCODING_VERSION + 1;
+    assert!(matches!(
+        decode_config::<GroupConfig>(&data),
+        Err(Error::Codec(_))
+    ));
+    assert!(decode_config::<GroupConfig>(&[]).is_err());
 }
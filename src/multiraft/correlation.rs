@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+
+use crate::proto::Message;
+use crate::proto::MessageType;
+
+/// CorrelationIds tracks the correlation ids of the entries proposed on the
+/// leader, keyed by the entry index, they're carried by the appends until the
+/// entries are applied, see `AppWriteRequest::correlation_id`.
+///
+/// The ids are tracked for the term of the leader which proposes them, since
+/// the indexes may be re-proposed by the leader of another term. They are
+/// cleared once the term or the role of the replica changes.
+#[derive(Debug, Default)]
+pub struct CorrelationIds {
+    term: u64,
+    ids: BTreeMap<u64, u64>,
+}
+
+impl CorrelationIds {
+    /// Track the id of the entry proposed at `index` by the leader of `term`,
+    /// the ids of the previous terms are cleared.
+    pub fn insert(&mut self, term: u64, index: u64, id: u64) {
+        if self.term != term {
+            self.ids.clear();
+            self.term = term;
+        }
+        self.ids.insert(index, id);
+    }
+
+    /// The entries up to `index` are applied, their ids are not carried by
+    /// the appends since then.
+    pub fn applied_to(&mut self, index: u64) {
+        if !self.ids.is_empty() {
+            self.ids = self.ids.split_off(&(index + 1));
+        }
+    }
+
+    /// Clear the ids, e.g. the term or the role of the replica changes.
+    pub fn clear(&mut self) {
+        self.ids.clear();
+    }
+
+    /// Returns the correlation ids of the entries carried by the append, which
+    /// are proposed in the term of the tracked ids. Empty if the message is not
+    /// an append or none of its entries is correlated.
+    pub fn of_append(&self, msg: &Message) -> Vec<u64> {
+        if self.ids.is_empty() || msg.msg_type() != MessageType::MsgAppend {
+            return vec![];
+        }
+        msg.entries
+            .iter()
+            .filter(|entry| entry.term == self.term)
+            .filter_map(|entry| self.ids.get(&entry.index).cloned())
+            .collect()
+    }
+}
+
+#[test]
+fn test_correlation_ids() {
+    let append = |entries: &[(u64, u64)]| {
+        let mut msg = Message::default();
+        msg.set_msg_type(MessageType::MsgAppend);
+        msg.entries = entries
+            .iter()
+            .map(|(index, term)| {
+                let mut entry = crate::proto::Entry::default();
+                entry.index = *index;
+                entry.term = *term;
+                entry
+            })
+            .collect();
+        msg
+    };
+
+    let mut ids = CorrelationIds::default();
+    ids.insert(2, 5, 50);
+    ids.insert(2, 7, 70);
+    // every correlated entry of the append is stamped.
+    assert_eq!(
+        ids.of_append(&append(&[(5, 2), (6, 2), (7, 2)])),
+        vec![50, 70]
+    );
+    // the index re-proposed in another term is not the correlated entry.
+    assert_eq!(ids.of_append(&append(&[(5, 3)])), Vec::<u64>::new());
+
+    ids.applied_to(5);
+    assert_eq!(ids.of_append(&append(&[(5, 2), (7, 2)])), vec![70]);
+
+    // the ids of the previous term are cleared by the proposal of a new term.
+    ids.insert(3, 8, 80);
+    assert_eq!(ids.of_append(&append(&[(7, 2), (8, 3)])), vec![80]);
+
+    ids.clear();
+    assert_eq!(ids.of_append(&append(&[(8, 3)])), Vec::<u64>::new());
+}
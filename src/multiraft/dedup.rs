@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use crate::proto::ClientSequence;
+use crate::proto::DedupTableData;
+
+/// DedupTable records the latest applied sequence of each client of the
+/// group with its result, a proposal whose sequence isn't greater than the
+/// recorded one is a retry of the applied proposal. The table is derived from
+/// the log only, so it's identical on all replicas at the same applied index,
+/// and it's carried by the snapshot of the group.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DedupTable {
+    capacity: usize,
+    applied_index: u64,
+    clients: HashMap<u64, ClientSequence>,
+}
+
+impl DedupTable {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            applied_index: 0,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Returns the result of the `sequence` of the client if it has been
+    /// applied, the reason if it's rejected by the state machine. Only the
+    /// result of the latest sequence is kept, the earlier sequences are known
+    /// to be applied, they are responded with success.
+    pub fn applied_result(&self, client_id: u64, sequence: u64) -> Option<Result<(), String>> {
+        let applied = self.clients.get(&client_id)?;
+        if sequence > applied.sequence {
+            return None;
+        }
+        if sequence == applied.sequence && applied.rejected {
+            return Some(Err(applied.reject_reason.clone()));
+        }
+        Some(Ok(()))
+    }
+
+    /// Record the `sequence` of the client applied at `index`, `rejection` is
+    /// the reason if it's rejected. If the table exceeds the capacity, the
+    /// client applied least recently is evicted.
+    pub fn record(&mut self, client_id: u64, sequence: u64, index: u64, rejection: Option<String>) {
+        self.clients.insert(
+            client_id,
+            ClientSequence {
+                client_id,
+                sequence,
+                index,
+                rejected: rejection.is_some(),
+                reject_reason: rejection.unwrap_or_default(),
+            },
+        );
+        self.applied_index = std::cmp::max(self.applied_index, index);
+        if self.clients.len() > self.capacity {
+            let evicted = self
+                .clients
+                .values()
+                .min_by_key(|applied| applied.index)
+                .map(|applied| applied.client_id);
+            if let Some(client_id) = evicted {
+                self.clients.remove(&client_id);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn to_data(&self) -> DedupTableData {
+        let mut sequences = self.clients.values().cloned().collect::<Vec<_>>();
+        sequences.sort_by_key(|seq| seq.index);
+        DedupTableData {
+            applied_index: self.applied_index,
+            sequences,
+        }
+    }
+
+    pub fn from_data(capacity: usize, data: DedupTableData) -> Self {
+        let mut table = DedupTable::new(capacity);
+        for seq in data.sequences.into_iter() {
+            let rejection = seq.rejected.then(|| seq.reject_reason);
+            table.record(seq.client_id, seq.sequence, seq.index, rejection);
+        }
+        table.applied_index = data.applied_index;
+        table
+    }
+}
+
+/// The dedup tables of groups shared by the apply actor and the multiraft,
+/// the clones share the same tables.
+#[derive(Debug, Clone)]
+pub struct DedupTables {
+    capacity: usize,
+    tables: Arc<Mutex<HashMap<u64, DedupTable>>>,
+}
+
+impl DedupTables {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tables: Default::default(),
+        }
+    }
+
+    /// Returns the result of the proposal if it's a duplicate, see
+    /// `DedupTable::applied_result`.
+    pub fn applied_result(
+        &self,
+        group_id: u64,
+        client_id: u64,
+        sequence: u64,
+    ) -> Option<Result<(), String>> {
+        self.tables
+            .lock()
+            .unwrap()
+            .get(&group_id)
+            .and_then(|table| table.applied_result(client_id, sequence))
+    }
+
+    /// Record the proposal applied at `index` with its result.
+    pub fn record(
+        &self,
+        group_id: u64,
+        client_id: u64,
+        sequence: u64,
+        index: u64,
+        rejection: Option<String>,
+    ) {
+        self.tables
+            .lock()
+            .unwrap()
+            .entry(group_id)
+            .or_insert_with(|| DedupTable::new(self.capacity))
+            .record(client_id, sequence, index, rejection);
+    }
+
+    /// Returns the dedup table of the group to be carried by its snapshot.
+    pub fn to_data(&self, group_id: u64) -> Option<DedupTableData> {
+        self.tables
+            .lock()
+            .unwrap()
+            .get(&group_id)
+            .map(|table| table.to_data())
+    }
+
+    /// Replace the dedup table of the group with the one carried by the
+    /// installed snapshot, the table is emptied if the snapshot has none.
+    pub fn restore(&self, group_id: u64, data: Option<DedupTableData>) {
+        let mut tables = self.tables.lock().unwrap();
+        match data {
+            None => {
+                tables.remove(&group_id);
+            }
+            Some(data) => {
+                tables.insert(group_id, DedupTable::from_data(self.capacity, data));
+            }
+        }
+    }
+
+    pub fn remove(&self, group_id: u64) {
+        self.tables.lock().unwrap().remove(&group_id);
+    }
+}
+
+#[test]
+fn test_dedup_table_evict_least_recently_applied() {
+    let mut table = DedupTable::new(2);
+    table.record(1, 1, 10, None);
+    table.record(2, 1, 11, None);
+    assert_eq!(table.applied_result(1, 1), Some(Ok(())));
+    assert_eq!(table.applied_result(1, 2), None);
+
+    table.record(1, 2, 12, None);
+    table.record(3, 1, 13, None);
+    assert_eq!(table.len(), 2);
+    // client 2 is evicted.
+    assert_eq!(table.applied_result(2, 1), None);
+    assert_eq!(table.applied_result(1, 2), Some(Ok(())));
+    assert_eq!(table.applied_result(3, 1), Some(Ok(())));
+
+    let restored = DedupTable::from_data(2, table.to_data());
+    assert_eq!(restored, table);
+}
+
+#[test]
+fn test_dedup_table_rejected_result() {
+    let mut table = DedupTable::new(16);
+    table.record(1, 1, 10, None);
+    table.record(1, 2, 11, Some("precondition".to_owned()));
+    assert_eq!(
+        table.applied_result(1, 2),
+        Some(Err("precondition".to_owned()))
+    );
+    // the result of the earlier sequence isn't kept.
+    assert_eq!(table.applied_result(1, 1), Some(Ok(())));
+
+    let restored = DedupTable::from_data(16, table.to_data());
+    assert_eq!(
+        restored.applied_result(1, 2),
+        Some(Err("precondition".to_owned()))
+    );
+}
+
+#[test]
+fn test_dedup_tables_restore() {
+    let tables = DedupTables::new(16);
+    assert_eq!(tables.applied_result(1, 7, 1), None);
+    tables.record(1, 7, 1, 5, None);
+    assert_eq!(tables.applied_result(1, 7, 1), Some(Ok(())));
+
+    let data = tables.to_data(1);
+    let other = DedupTables::new(16);
+    other.restore(1, data);
+    assert_eq!(other.applied_result(1, 7, 1), Some(Ok(())));
+    assert_eq!(other.applied_result(1, 7, 2), None);
+
+    // the snapshot without the dedup table empties it.
+    other.restore(1, None);
+    assert_eq!(other.applied_result(1, 7, 1), None);
+}
@@ -0,0 +1,231 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use tracing::debug;
+
+use crate::proto::Message;
+use crate::proto::MessageType;
+use crate::proto::RaftMessage;
+
+use super::multiraft::NO_NODE;
+
+/// The reason why the message is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    /// The group of the message doesn't exist on this node.
+    UnknownGroup,
+    /// The group of the message has been removed from this node.
+    RemovedGroup,
+    /// The group of the message is poisoned.
+    PoisonedGroup,
+    /// The message fails to be sent, e.g. the node is unreachable or can't
+    /// be resolved, or it fails to be delivered after it's sent.
+    SendFailed,
+    /// The message is dropped by the filter or the partition of the transport.
+    Filtered,
+    /// The message is dropped by the transport since the queue of the
+    /// messages to the destination node is full, e.g. see
+    /// `LocalTransport::set_queue_capacity`.
+    QueueOverflow,
+    /// The message is rejected by the replica, e.g. the witness rejects
+    /// `MsgTimeoutNow`, or raft fails to step it.
+    Rejected,
+    /// The persisted messages of the ready which fails to be persisted.
+    NotPersisted,
+    /// The message is sent by a replica which has been removed from the group.
+    StaleReplica,
+    /// The message buffered for the group which isn't created yet is evicted
+    /// by a newer message, or it isn't buffered because the messages of too
+    /// many groups are buffered, see `MultiRaftConfig::pending_group_messages`.
+    PendingOverflow,
+    /// The message buffered for the group which isn't created yet expires.
+    PendingExpired,
+    /// The append or vote request to the replica which is installing a
+    /// snapshot, see `MultiRaftConfig::snapshot_install_suppression`.
+    InstallingSnapshot,
+    /// The vote request to the replica whose leader is within its tenure, see
+    /// `MultiRaftConfig::min_leader_tenure`.
+    LeaderTenure,
+    /// The group of the message fails to be created on this node, e.g. its
+    /// stored conf state is inconsistent.
+    GroupCreationFailed,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 14] = [
+        DropReason::UnknownGroup,
+        DropReason::RemovedGroup,
+        DropReason::PoisonedGroup,
+        DropReason::SendFailed,
+        DropReason::Filtered,
+        DropReason::QueueOverflow,
+        DropReason::Rejected,
+        DropReason::NotPersisted,
+        DropReason::StaleReplica,
+        DropReason::PendingOverflow,
+        DropReason::PendingExpired,
+        DropReason::InstallingSnapshot,
+        DropReason::LeaderTenure,
+        DropReason::GroupCreationFailed,
+    ];
+
+    #[inline]
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// The metadata of the dropped message, the node or replica which is unknown
+/// where the message is dropped is `NO_NODE`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DroppedMessage {
+    pub group_id: u64,
+    pub from_node: u64,
+    pub to_node: u64,
+    pub from_replica: u64,
+    pub to_replica: u64,
+    /// None if the message doesn't carry a raft message, e.g. the snapshot
+    /// chunk ack or the forwarded proposal.
+    pub msg_type: Option<MessageType>,
+    /// The correlation ids of the entries carried by the message, empty if it's
+    /// not correlated, see `AppWriteRequest::correlation_id`.
+    pub correlation_ids: Vec<u64>,
+}
+
+impl DroppedMessage {
+    pub fn from_raft_message(msg: &RaftMessage) -> Self {
+        let (from_replica, to_replica, msg_type) = match msg.msg.as_ref() {
+            Some(m) => (m.from, m.to, Some(m.msg_type())),
+            None => match msg.snapshot_chunk.as_ref() {
+                Some(chunk) => (chunk.from_replica, chunk.to_replica, Some(MessageType::MsgSnapshot)),
+                None => (NO_NODE, NO_NODE, None),
+            },
+        };
+        Self {
+            group_id: msg.group_id,
+            from_node: msg.from_node,
+            to_node: msg.to_node,
+            from_replica,
+            to_replica,
+            msg_type,
+            correlation_ids: msg.correlation_ids.clone(),
+        }
+    }
+
+    /// Make the metadata of the raft message of the group, the `to_node` is
+    /// `NO_NODE` if the destination is not resolved yet.
+    pub fn from_message(group_id: u64, from_node: u64, to_node: u64, msg: &Message) -> Self {
+        Self {
+            group_id,
+            from_node,
+            to_node,
+            from_replica: msg.from,
+            to_replica: msg.to,
+            msg_type: Some(msg.msg_type()),
+            correlation_ids: vec![],
+        }
+    }
+}
+
+/// DroppedMessageObserver is called whenever a message is dropped anywhere in
+/// the pipeline, it's called in the actor loop, so it should not block.
+pub trait DroppedMessageObserver: Send + Sync + 'static {
+    fn on_dropped(&self, reason: DropReason, msg: &DroppedMessage);
+}
+
+/// The default observer which emits the dropped message as a debug event.
+pub struct TraceDroppedMessageObserver;
+
+impl DroppedMessageObserver for TraceDroppedMessageObserver {
+    fn on_dropped(&self, reason: DropReason, msg: &DroppedMessage) {
+        debug!(
+            correlation_ids = ?msg.correlation_ids,
+            "group {} drop {:?} message {}({}) -> {}({}): {:?}",
+            msg.group_id,
+            msg.msg_type,
+            msg.from_node,
+            msg.from_replica,
+            msg.to_node,
+            msg.to_replica,
+            reason
+        );
+    }
+}
+
+/// DroppedMessages notifies the observer of the dropped messages and counts
+/// them per reason, the clones share the same counters. It's also the observer
+/// injected into the transport, so the messages dropped by the transport are
+/// counted too, see `Transport::set_dropped_observer`.
+#[derive(Clone)]
+pub struct DroppedMessages {
+    observer: Arc<dyn DroppedMessageObserver>,
+    counters: Arc<[AtomicU64; DropReason::ALL.len()]>,
+}
+
+impl Default for DroppedMessages {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl DroppedMessages {
+    pub fn new(observer: Option<Arc<dyn DroppedMessageObserver>>) -> Self {
+        Self {
+            observer: observer.unwrap_or_else(|| Arc::new(TraceDroppedMessageObserver)),
+            counters: Default::default(),
+        }
+    }
+
+    pub fn record(&self, reason: DropReason, msg: &DroppedMessage) {
+        self.counters[reason.index()].fetch_add(1, Ordering::Relaxed);
+        self.observer.on_dropped(reason, msg);
+    }
+
+    /// Returns the number of the messages dropped for the `reason`.
+    pub fn count(&self, reason: DropReason) -> u64 {
+        self.counters[reason.index()].load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of the dropped messages of each reason.
+    pub fn counts(&self) -> Vec<(DropReason, u64)> {
+        DropReason::ALL
+            .iter()
+            .map(|reason| (*reason, self.count(*reason)))
+            .collect()
+    }
+}
+
+impl DroppedMessageObserver for DroppedMessages {
+    fn on_dropped(&self, reason: DropReason, msg: &DroppedMessage) {
+        self.record(reason, msg)
+    }
+}
+
+#[test]
+fn test_dropped_messages_count_per_reason() {
+    let dropped = DroppedMessages::default();
+    let msg = RaftMessage {
+        group_id: 1,
+        from_node: 1,
+        to_node: 2,
+        correlation_ids: vec![7, 8],
+        ..Default::default()
+    };
+    let meta = DroppedMessage::from_raft_message(&msg);
+    assert_eq!(meta.msg_type, None);
+    assert_eq!(meta.correlation_ids, vec![7, 8]);
+
+    dropped.record(DropReason::SendFailed, &meta);
+    dropped.clone().record(DropReason::SendFailed, &meta);
+    dropped.record(DropReason::RemovedGroup, &meta);
+    assert_eq!(dropped.count(DropReason::SendFailed), 2);
+    assert_eq!(dropped.count(DropReason::RemovedGroup), 1);
+    assert_eq!(dropped.count(DropReason::Filtered), 0);
+    assert_eq!(dropped.counts().len(), DropReason::ALL.len());
+
+    // the drops reported by the transport are counted.
+    let observer: Arc<dyn DroppedMessageObserver> = Arc::new(dropped.clone());
+    observer.on_dropped(DropReason::Filtered, &meta);
+    assert_eq!(dropped.count(DropReason::Filtered), 1);
+}
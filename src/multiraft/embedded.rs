@@ -0,0 +1,140 @@
+use raft::StateRole;
+use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::watch;
+
+use super::config::MultiRaftConfig;
+use super::error::Error;
+use super::event::Event;
+use super::ids::GroupId;
+use super::ids::NodeId;
+use super::ids::StoreId;
+use super::multiraft::MultiRaft;
+use super::multiraft_message::MultiRaftMessageSender;
+use super::transport::Transport;
+use super::transport_local::LocalTransport;
+
+use crate::proto::ReplicaDesc;
+use crate::storage::MemStorage;
+use crate::storage::MultiRaftMemoryStorage;
+
+/// The capacity of `EmbeddedNode::events`, the events beyond it are dropped
+/// until the receiver catches up.
+pub const EMBEDDED_EVENTS_CAPACITY: usize = 1024;
+
+/// The multiraft wired with the local transport and the memory storage.
+pub type EmbeddedMultiRaft = MultiRaft<
+    MultiRaftMessageSender,
+    LocalTransport<MultiRaftMessageSender>,
+    MemStorage,
+    MultiRaftMemoryStorage,
+>;
+
+/// The node created by `MultiRaft::embedded`.
+pub struct EmbeddedNode {
+    pub node_id: u64,
+    pub multiraft: EmbeddedMultiRaft,
+    /// The transport which the node listens on, the partitions and the
+    /// message filters are injected by it, and it's shared by the other
+    /// nodes created by `MultiRaft::embedded_with_transport`.
+    pub transport: LocalTransport<MultiRaftMessageSender>,
+    pub storage: MultiRaftMemoryStorage,
+    /// The events of the node, the apply events are acked before they are
+    /// forwarded, so the `tx` of `ApplyEvent` is always none. The events are
+    /// dropped rather than block the node if the receiver falls behind by
+    /// `EMBEDDED_EVENTS_CAPACITY`, it can be dropped if the events are not
+    /// interested.
+    pub events: Receiver<Vec<Event>>,
+    /// Stop the actors of the node by sending true.
+    pub stop_tx: watch::Sender<bool>,
+}
+
+impl EmbeddedMultiRaft {
+    /// Create the multiraft of node `node_id` with the default config, the
+    /// local transport and the memory storage, see `embedded_with_transport`.
+    pub async fn embedded(node_id: u64) -> Result<EmbeddedNode, Error> {
+        Self::embedded_with_transport(MultiRaftConfig::default(), node_id, LocalTransport::new())
+            .await
+    }
+
+    /// Create the multiraft of node `node_id` which listens on `transport`
+    /// at `local://<node_id>`, so that several embedded nodes sharing the
+    /// transport make a cluster. The applied entries are published by
+    /// `apply_results` rather than handled by the event consumer.
+    pub async fn embedded_with_transport(
+        config: MultiRaftConfig,
+        node_id: u64,
+        transport: LocalTransport<MultiRaftMessageSender>,
+    ) -> Result<EmbeddedNode, Error> {
+        let store_id = node_id;
+        let storage = MultiRaftMemoryStorage::new(node_id, store_id);
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let (event_tx, mut event_rx) = channel(1);
+        let multiraft = MultiRaft::new(
+            config,
+            NodeId(node_id),
+            StoreId(store_id),
+            transport.clone(),
+            storage.clone(),
+            stop_rx,
+            event_tx,
+        );
+
+        transport
+            .listen(
+                node_id,
+                &format!("local://{}", node_id),
+                multiraft.message_sender(),
+            )
+            .await?;
+
+        let (forward_tx, forward_rx) = channel(EMBEDDED_EVENTS_CAPACITY);
+        tokio::spawn(async move {
+            while let Some(mut events) = event_rx.recv().await {
+                for event in events.iter_mut() {
+                    if let Event::Apply(apply) = event {
+                        if let Some(tx) = apply.tx.take() {
+                            let _ = tx.send(Ok(()));
+                        }
+                    }
+                }
+                // keeps acking the apply events after the receiver is dropped
+                // or while it's full.
+                let _ = forward_tx.try_send(events);
+            }
+        });
+
+        Ok(EmbeddedNode {
+            node_id,
+            multiraft,
+            transport,
+            storage,
+            events: forward_rx,
+            stop_tx,
+        })
+    }
+}
+
+impl EmbeddedNode {
+    /// Bootstrap the group whose only replica is on this node and wait until
+    /// it's elected, so the group accepts the writes once it returns. The
+    /// groups of several embedded nodes are bootstrapped by
+    /// `MultiRaft::bootstrap_group` on each node.
+    pub async fn create_group(&self, group_id: GroupId) -> Result<(), Error> {
+        let replicas = vec![ReplicaDesc {
+            node_id: self.node_id,
+            replica_id: 1,
+        }];
+        self.multiraft
+            .bootstrap_group(group_id, replicas, true)
+            .await?;
+
+        let mut role = self.multiraft.role_watch(group_id).await?;
+        while *role.borrow_and_update() != StateRole::Leader {
+            role.changed()
+                .await
+                .map_err(|_| Error::RaftGroupNotFound(group_id.0))?;
+        }
+        Ok(())
+    }
+}
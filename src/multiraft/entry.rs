@@ -0,0 +1,87 @@
+/// The data of the normal entry is framed with a type tag prefix, so that the
+/// apply path tells the internal admin commands from the application data,
+/// and the application data is never misinterpreted as an admin command.
+///
+/// The frames are:
+///
+/// - data: `DATA_TAG | data`, the data is opaque to the multiraft.
+/// - admin: `ADMIN_TAG | kind | payload`, the payload is interpreted by the
+///   handler of the kind.
+///
+/// The no-op entry proposed by the new leader has empty data and no frame.
+pub const DATA_TAG: u8 = 1;
+pub const ADMIN_TAG: u8 = 2;
+
+/// The kind of the admin entry which sets the config of the group, whose
+/// payload is the `GroupConfig` encoded by `encode_config`.
+#[cfg(feature = "serde-config")]
+pub const ADMIN_GROUP_CONFIG: u8 = 1;
+
+/// The length of the frame header of the data entry.
+pub const DATA_HEADER_LEN: usize = 1;
+/// The length of the frame header of the admin entry.
+pub const ADMIN_HEADER_LEN: usize = 2;
+
+/// The decoded payload of the normal entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryPayload<'a> {
+    /// The no-op entry, e.g. proposed by the new leader.
+    Empty,
+    /// The opaque application data.
+    Data(&'a [u8]),
+    /// The admin command of `kind`.
+    Admin { kind: u8, payload: &'a [u8] },
+    /// The tag is unknown or the frame is truncated.
+    Malformed,
+}
+
+/// Frame the application data as a data entry.
+pub fn encode_data(data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(DATA_HEADER_LEN + data.len());
+    buf.push(DATA_TAG);
+    buf.extend_from_slice(data);
+    buf
+}
+
+/// Frame the payload as an admin entry of `kind`.
+pub fn encode_admin(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(ADMIN_HEADER_LEN + payload.len());
+    buf.push(ADMIN_TAG);
+    buf.push(kind);
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Decode the framed data of the normal entry.
+pub fn decode_entry(data: &[u8]) -> EntryPayload {
+    match data.first() {
+        None => EntryPayload::Empty,
+        Some(&DATA_TAG) => EntryPayload::Data(&data[DATA_HEADER_LEN..]),
+        Some(&ADMIN_TAG) if data.len() >= ADMIN_HEADER_LEN => EntryPayload::Admin {
+            kind: data[1],
+            payload: &data[ADMIN_HEADER_LEN..],
+        },
+        Some(_) => EntryPayload::Malformed,
+    }
+}
+
+#[test]
+fn test_entry_framing() {
+    assert_eq!(decode_entry(&[]), EntryPayload::Empty);
+    // the application data which looks like an admin frame is still data.
+    let admin_like = encode_admin(7, b"payload");
+    assert_eq!(
+        decode_entry(&encode_data(&admin_like)),
+        EntryPayload::Data(&admin_like)
+    );
+    assert_eq!(decode_entry(&encode_data(b"")), EntryPayload::Data(b""));
+    assert_eq!(
+        decode_entry(&admin_like),
+        EntryPayload::Admin {
+            kind: 7,
+            payload: b"payload"
+        }
+    );
+    assert_eq!(decode_entry(&[ADMIN_TAG]), EntryPayload::Malformed);
+    assert_eq!(decode_entry(&[0xff, 1]), EntryPayload::Malformed);
+}
@@ -1,4 +1,6 @@
 use crate::storage::StorageError;
+
+use super::raft_group::GroupState;
  
 #[derive(thiserror::Error, Debug, Clone, PartialEq)]
 pub enum TransportError {
@@ -10,6 +12,12 @@ pub enum TransportError {
 
     #[error("server error: {0}")]
     Server(String),
+
+    #[error("the node {0} can't be resolved")]
+    UnknownNode(u64),
+
+    #[error("the node {0} is unreachable")]
+    Unreachable(u64),
 }
 
 #[derive(thiserror::Error, Debug, PartialEq)]
@@ -39,6 +47,10 @@ pub enum Error {
     #[error("raft group ({0}) already exists")]
     RaftGroupAlreayExists(u64),
 
+    // the tuple is (group_id, reason)
+    #[error("raft group ({0}) already exists with a different config: {1}")]
+    GroupConflict(u64, String),
+
     #[error(
         "inconsistent replica id: passed {0}, but found {1} by scanning conf_state for store {2}"
     )]
@@ -47,6 +59,124 @@ pub enum Error {
     // the tuple is (group_id, store_id)s
     #[error("couldn't find replica id for this store ({1}) in group ({0})")]
     ReplicaNotFound(u64, u64),
+
+    #[error("raft group ({0}) not found")]
+    RaftGroupNotFound(u64),
+
+    // the tuple is (group_id, replica_id)
+    #[error("replica ({1}) is not a voter of group ({0})")]
+    ReplicaNotVoter(u64, u64),
+
+    // the tuple is (group_id, replica_id)
+    #[error("replica ({1}) of group ({0}) is too stale to serve the follower read")]
+    TooStale(u64, u64),
+
+    // the tuple is (group_id, token_group_id)
+    #[error("the commit token of group ({1}) can't be used to read group ({0})")]
+    TokenMismatch(u64, u64),
+
+    // the tuple is (group_id, token_index, applied_index)
+    #[error("the applied index {2} of group ({0}) doesn't reach the token index {1} in time")]
+    TokenNotReached(u64, u64, u64),
+
+    // the tuple is (group_id, index, replicas which have applied the index)
+    #[error("the index {1} of group ({0}) isn't applied by a quorum in time, applied by {2:?}")]
+    QuorumAppliedNotReached(u64, u64, Vec<u64>),
+
+    // the tuple is (group_id, replica_id, matched, last_index)
+    #[error("the transferee replica ({1}) of group ({0}) lags behind, matched {2} but last index {3}")]
+    TargetLagging(u64, u64, u64, u64),
+
+    #[error("raft group ({0}) is poisoned, it must be removed or reinitialized")]
+    GroupPoisoned(u64),
+
+    // the tuple is (group_id, reason)
+    #[error("the conf state of group ({0}) is inconsistent on recovery: {1}")]
+    ConfStateInconsistent(u64, String),
+
+    // the tuple is (group_id, replica_id)
+    #[error("replica ({1}) is present in or was removed from group ({0})")]
+    DuplicateReplica(u64, u64),
+
+    /// The `Command` fails to be encoded or decoded.
+    #[error("command codec error: {0}")]
+    Codec(String),
+
+    /// The group is drained by `MultiRaft::drain_group`, it doesn't accept
+    /// proposals any more.
+    #[error("raft group ({0}) is draining")]
+    GroupDraining(u64),
+
+    /// The group is frozen by `MultiRaft::freeze_group`, the local replica
+    /// doesn't serve the proposals and reads until it's unfrozen.
+    #[error("raft group ({0}) is frozen")]
+    GroupFrozen(u64),
+
+    /// The node is stopped before the operation completes, e.g. the pending
+    /// proposal may or may not be committed.
+    #[error("the node is shutting down")]
+    Shutdown,
+
+    // the tuple is (group_id, from, to)
+    #[error("raft group ({0}) can't transit from {1:?} to {2:?}")]
+    InvalidGroupState(u64, GroupState, GroupState),
+
+    // the tuple is (group_id, replica_id)
+    #[error("reset replica ({1}) of group ({0}) leaves too few up-to-date voters")]
+    UnsafeReset(u64, u64),
+
+    /// The apply results of the group from the index are no longer retained,
+    /// see `MultiRaft::apply_results_from`. The tuple is (group_id,
+    /// from_index, evicted_index).
+    #[error("the apply results of group ({0}) from index {1} are evicted up to index {2}")]
+    ApplyResultsEvicted(u64, u64, u64),
+
+    /// The log of the group from the index is compacted, the entries must be
+    /// caught up by the snapshot first, see `MultiRaft::read_committed`. The
+    /// tuple is (group_id, from_index, snapshot_index).
+    #[error("the log of group ({0}) from index {1} is compacted up to snapshot index {2}")]
+    LogCompacted(u64, u64, u64),
+
+    /// The leader can't be demoted to learner, the leadership must be
+    /// transferred first. The tuple is (group_id, replica_id).
+    #[error("replica ({1}) is the leader of group ({0}), transfer the leadership first")]
+    DemoteLeader(u64, u64),
+
+    /// The demotion leaves the group without any voter. The tuple is
+    /// (group_id, replica_id).
+    #[error("replica ({1}) is the last voter of group ({0})")]
+    LastVoter(u64, u64),
+
+    /// The state machine fails to snapshot the group, see
+    /// `StateMachine::snapshot`. The tuple is (group_id, reason).
+    #[error("the state machine fails to snapshot group ({0}): {1}")]
+    SnapshotFailed(u64, String),
+}
+
+/// The reason why the conf change is unsafe, see
+/// `MultiRaft::validate_conf_change`.
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum ConfChangeError {
+    #[error("the group {0} not found")]
+    GroupNotFound(u64),
+
+    /// The conf change can't be applied on the current config, e.g. it
+    /// enters the joint consensus twice.
+    #[error("the conf change is invalid: {0}")]
+    Invalid(String),
+
+    /// The conf change removes the last voter of the group.
+    #[error("the conf change removes the last voter of the group")]
+    NoVoter,
+
+    /// The node of the added replica isn't carried by the `MembershipChangeData`
+    /// in the context of the conf change.
+    #[error("the node of the added replica {0} is unknown")]
+    UnknownNode(u64),
+
+    // the tuple is (replica_id, node_id).
+    #[error("the node {1} of the added replica {0} can't be resolved")]
+    UnresolvedNode(u64, u64),
 }
 
 #[derive(thiserror::Error, Debug, PartialEq)]
@@ -66,6 +196,54 @@ pub enum ProposalError {
     #[error("stale at term = {0}")]
     Stale(u64),
 
+    #[error("proposal is not applied within the timeout")]
+    Timeout,
+
+    /// The pending proposals of the group reach `max_pending_proposals`, the
+    /// proposal can be retried after the pending proposals are applied.
+    #[error("the pending proposals reach the limit {0}")]
+    ProposalQueueFull(usize),
+
+    /// The pending proposals of the client reach
+    /// `max_client_pending_proposals`, the proposal can be retried after the
+    /// previous proposals of the client are applied. The tuple is
+    /// (client_id, max_client_pending_proposals).
+    #[error("the pending proposals of client {0} reach the limit {1}")]
+    ClientQuotaExceeded(u64, usize),
+
+    /// The proposal is dropped by raft, e.g. the uncommitted entries of the
+    /// leader reach `max_uncommitted_size`, the proposal can be retried after
+    /// the uncommitted entries are committed.
+    #[error("the proposal is dropped")]
+    Dropped,
+
+    /// The data and context of the proposal exceed `max_entry_size`, the
+    /// tuple is (size, max_entry_size).
+    #[error("the entry size {0} exceeds the limit {1}")]
+    EntryTooLarge(u64, u64),
+
+    /// The committed but unapplied entries of the group reach
+    /// `max_apply_backlog`, the proposal can be retried after the apply
+    /// catches up.
+    #[error("the apply backlog reaches the limit {0}")]
+    ApplyBacklogFull(u64),
+
+    /// The log of the group reaches `max_log_entries` and it can't be
+    /// compacted, e.g. a lagging replica pins it, the proposal can be retried
+    /// after the replica catches up, see the `LogPinned` event.
+    #[error("the log entries reach the limit {0}")]
+    LogFull(u64),
+
+    /// The precondition of the conditional proposal doesn't hold on the
+    /// applied state, the entry is committed but its effect is skipped.
+    #[error("the precondition of the proposal failed")]
+    PreconditionFailed,
+
+    /// The entry is committed but rejected by the `StateMachine`, see
+    /// `ApplyError::Reject`.
+    #[error("the proposal is rejected by the state machine: {0}")]
+    Rejected(String),
+
     #[error("{0}")]
     Other(#[from] Box<dyn std::error::Error + Sync + Send>),
 }
@@ -75,18 +253,44 @@ impl PartialEq for ProposalError {
         match self {
             ProposalError::Unexpected(v1) => match other {
                 ProposalError::Unexpected(v2) => v1 == v2,
-                ProposalError::Stale(_) => false,
-                ProposalError::Other(_) => false,
+                _ => false,
             },
             ProposalError::Stale(v1) => match other {
-                ProposalError::Unexpected(_) => false,
                 ProposalError::Stale(v2) => v1 == v2,
-                ProposalError::Other(_) => false,
+                _ => false,
+            },
+            ProposalError::Timeout => matches!(other, ProposalError::Timeout),
+            ProposalError::ProposalQueueFull(v1) => match other {
+                ProposalError::ProposalQueueFull(v2) => v1 == v2,
+                _ => false,
+            },
+            ProposalError::ClientQuotaExceeded(c1, l1) => match other {
+                ProposalError::ClientQuotaExceeded(c2, l2) => c1 == c2 && l1 == l2,
+                _ => false,
+            },
+            ProposalError::Dropped => matches!(other, ProposalError::Dropped),
+            ProposalError::ApplyBacklogFull(v1) => match other {
+                ProposalError::ApplyBacklogFull(v2) => v1 == v2,
+                _ => false,
+            },
+            ProposalError::LogFull(v1) => match other {
+                ProposalError::LogFull(v2) => v1 == v2,
+                _ => false,
+            },
+            ProposalError::EntryTooLarge(v1, l1) => match other {
+                ProposalError::EntryTooLarge(v2, l2) => v1 == v2 && l1 == l2,
+                _ => false,
+            },
+            ProposalError::PreconditionFailed => {
+                matches!(other, ProposalError::PreconditionFailed)
+            }
+            ProposalError::Rejected(v1) => match other {
+                ProposalError::Rejected(v2) => v1 == v2,
+                _ => false,
             },
             ProposalError::Other(v1) => match other {
-                ProposalError::Unexpected(_) => false,
-                ProposalError::Stale(_) => false,
                 ProposalError::Other(v2) => matches!(v1, v2),
+                _ => false,
             },
         }
     }
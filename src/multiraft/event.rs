@@ -1,8 +1,12 @@
 use tokio::sync::oneshot;
 
 use crate::proto::Entry;
+use crate::proto::Precondition;
 
+use super::command::Command;
 use super::error::Error;
+use super::error::ProposalError;
+use super::raft_group::GroupState;
 
 #[derive(Debug)]
 pub struct LeaderElectionEvent {
@@ -14,14 +18,272 @@ pub struct LeaderElectionEvent {
 #[derive(Debug)]
 pub struct ApplyEvent {
     pub group_id: u64,
+    /// The data and the context of the normal entry are the ones of the
+    /// original proposal, they are replicated with the entry, so that every
+    /// replica sees the same context no matter which node proposed it.
     pub entry: Entry,
     pub is_conf_change: bool,
+    /// The precondition of the conditional proposal, which must be verified
+    /// by the state machine before the entry is applied, see
+    /// `check_precondition`.
+    pub precondition: Option<Precondition>,
+    /// The correlation id of the proposal, it's the same on every replica,
+    /// 0 if the proposal isn't correlated, see
+    /// `AppWriteRequest::correlation_id`.
+    pub correlation_id: u64,
     pub tx: Option<oneshot::Sender<Result<(), Error>>>,
 }
 
+impl ApplyEvent {
+    /// Returns the context of the proposal, which is used by the state
+    /// machine to correlate the applied result with the proposal. It's
+    /// empty for the conf change.
+    #[inline]
+    pub fn context(&self) -> &[u8] {
+        &self.entry.context
+    }
+
+    /// Decode the data of the normal entry as the typed command proposed by
+    /// `MultiRaft::propose_command`.
+    #[inline]
+    pub fn command<C: Command>(&self) -> Result<C, Error> {
+        C::decode(&self.entry.data)
+    }
+
+    /// Verify the precondition against the current value of its key, which is
+    /// `None` if the key is absent, and `written_index`, the index of the entry
+    /// which wrote the value last, 0 if unknown. The precondition with the
+    /// non-zero `read_index` also fails if the key is written after the read,
+    /// even if the value is the same again, or if the read isn't before the
+    /// entry. If `PreconditionFailed` is returned, the state machine must skip
+    /// the effect of the entry but still advance its applied index, and
+    /// respond the error by `tx`. Every replica gets the same result since the
+    /// check is deterministic on the applied state.
+    pub fn check_precondition(
+        &self,
+        current: Option<&[u8]>,
+        written_index: u64,
+    ) -> Result<(), Error> {
+        let precondition = match self.precondition.as_ref() {
+            None => return Ok(()),
+            Some(precondition) => precondition,
+        };
+        let read_index = precondition.read_index;
+        let stale =
+            read_index != 0 && (written_index > read_index || read_index >= self.entry.index);
+        if stale || precondition.expected_hash != value_hash(current) {
+            return Err(Error::Proposal(ProposalError::PreconditionFailed));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the hash of the value compared by the `Precondition`, which is the
+/// 64-bit FNV-1a of the value, 0 if the value is absent. It's stable across
+/// nodes and versions, so it can be computed by the client.
+pub fn value_hash(value: Option<&[u8]>) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+    match value {
+        None => 0,
+        Some(value) => value.iter().fold(FNV_OFFSET, |hash, b| {
+            (hash ^ *b as u64).wrapping_mul(FNV_PRIME)
+        }),
+    }
+}
+
+/// AppliedEntry is published to the subscribers of `MultiRaft::apply_results`
+/// after the entry is committed and handed over to the state machine.
+#[derive(Debug, Clone)]
+pub struct AppliedEntry {
+    pub group_id: u64,
+    pub index: u64,
+    pub term: u64,
+    /// The context of the proposal, which is used by the client to
+    /// correlate its proposal with the applied result.
+    pub context: Vec<u8>,
+    pub data: Vec<u8>,
+    pub is_conf_change: bool,
+    /// The round of the apply actor in which the entry is applied, it grows
+    /// monotonically on the node. The entries are published in the order of
+    /// (apply_round, group_id, index) if `MultiRaftConfig::ordered_apply`.
+    pub apply_round: u64,
+}
+
+/// Emitted when the leader starts to bring the lagging transferee up to date
+/// before transferring the leadership, the result of transfer is observed via
+/// the `LederElection` event.
+#[derive(Debug)]
+pub struct LeaderTransferEvent {
+    pub group_id: u64,
+    pub transferee: u64,
+    pub matched: u64,
+    pub last_index: u64,
+}
+
+/// The reason why the group is unhealthy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnhealthyReason {
+    /// The group has no leader, which indicates the quorum is lost or
+    /// the configuration is wrong.
+    Leaderless,
+    /// The commit index of the group doesn't advance despite proposals.
+    NoProgress,
+}
+
+/// Emitted when the group has no leader or can't make progress for longer
+/// than `group_unhealthy_multiple` times the election timeout.
+#[derive(Debug)]
+pub struct GroupUnhealthyEvent {
+    pub group_id: u64,
+    pub reason: UnhealthyReason,
+}
+
+/// Emitted when the unhealthy group returns to normal.
+#[derive(Debug)]
+pub struct GroupRecoveredEvent {
+    pub group_id: u64,
+}
+
+/// Emitted when the replica of the group is initialized on this node, the
+/// groups initialized in a batch are emitted in one event set.
+#[derive(Debug)]
+pub struct GroupCreatedEvent {
+    pub group_id: u64,
+    pub replica_id: u64,
+}
+
+/// Emitted when handling the group panicked or the ready of it fails to be
+/// persisted beyond the retries, the group is poisoned and isolated from
+/// other groups until it's removed or reinitialized.
+#[derive(Debug)]
+pub struct GroupFailedEvent {
+    pub group_id: u64,
+    pub reason: String,
+}
+
+/// Emitted by the watchdog when the actor of the node dies unexpectedly, e.g.
+/// of a panic which isn't isolated to a group. If `restarted`, the actor is
+/// restarted and recreates its replicas from the storage, otherwise the node
+/// is silent until it's restarted, see `MultiRaftConfig::restart_actor_on_panic`.
+#[derive(Debug)]
+pub struct NodeFailureEvent {
+    pub node_id: u64,
+    pub reason: String,
+    pub restarted: bool,
+}
+
+/// Emitted when the local replica becomes the leader of the group. raft
+/// appends the empty no-op entry at `noop_index` as the first entry of the
+/// `term`, which is followed by the leadership epoch entry at `epoch_index` if
+/// the `LeaderHook` returns the data, see `MultiRaftExtensions::leader_hook`.
+#[derive(Debug)]
+pub struct LeaderEpochEvent {
+    pub group_id: u64,
+    pub term: u64,
+    pub noop_index: u64,
+    pub epoch_index: Option<u64>,
+}
+
+/// Emitted when the log of the group led by the local replica reaches
+/// `max_log_entries` but `replica_id`, which only matches the log up to
+/// `matched` below the applied index, pins it from being compacted. It's
+/// emitted once until the log is back under the limit.
+#[derive(Debug)]
+pub struct LogPinnedEvent {
+    pub group_id: u64,
+    pub replica_id: u64,
+    pub matched: u64,
+    pub log_entries: u64,
+}
+
+/// Emitted when the ready of the group fails to be persisted, the ready is
+/// not advanced and it's retried later.
+#[derive(Debug)]
+pub struct GroupStorageErrorEvent {
+    pub group_id: u64,
+    pub error: String,
+    /// The number of consecutive failures of the ready.
+    pub failures: usize,
+}
+
+/// Emitted when the group transits to another lifecycle state, see
+/// `GroupState` for the valid transitions.
+#[derive(Debug)]
+pub struct GroupStateChangedEvent {
+    pub group_id: u64,
+    pub from: GroupState,
+    pub to: GroupState,
+}
+
 #[derive(Debug)]
 pub enum Event {
     LederElection(LeaderElectionEvent),
 
     Apply(ApplyEvent),
+
+    GroupUnhealthy(GroupUnhealthyEvent),
+
+    GroupRecovered(GroupRecoveredEvent),
+
+    LeaderTransfer(LeaderTransferEvent),
+
+    GroupCreated(GroupCreatedEvent),
+
+    GroupFailed(GroupFailedEvent),
+
+    GroupStorageError(GroupStorageErrorEvent),
+
+    GroupStateChanged(GroupStateChangedEvent),
+
+    NodeFailure(NodeFailureEvent),
+
+    LeaderEpoch(LeaderEpochEvent),
+
+    LogPinned(LogPinnedEvent),
+}
+
+#[test]
+fn test_check_precondition() {
+    let event_at = |expected_hash, read_index| ApplyEvent {
+        group_id: 1,
+        entry: Entry {
+            index: 10,
+            ..Default::default()
+        },
+        is_conf_change: false,
+        precondition: Some(Precondition {
+            key: b"k".to_vec(),
+            expected_hash,
+            read_index,
+        }),
+        correlation_id: 0,
+        tx: None,
+    };
+    let event = |expected_hash| event_at(expected_hash, 0);
+    assert_eq!(value_hash(None), 0);
+    assert_ne!(value_hash(Some(b"")), 0);
+    assert_ne!(value_hash(Some(b"v1")), value_hash(Some(b"v2")));
+
+    // expect absent.
+    assert!(event(0).check_precondition(None, 0).is_ok());
+    assert_eq!(
+        event(0).check_precondition(Some(b"v1"), 1).unwrap_err(),
+        Error::Proposal(ProposalError::PreconditionFailed)
+    );
+    let hash = value_hash(Some(b"v1"));
+    assert!(event(hash).check_precondition(Some(b"v1"), 5).is_ok());
+    assert!(event(hash).check_precondition(Some(b"v2"), 5).is_err());
+    assert!(event(hash).check_precondition(None, 0).is_err());
+
+    // the key isn't written after the read.
+    assert!(event_at(hash, 5).check_precondition(Some(b"v1"), 5).is_ok());
+    // the same value is written again after the read.
+    assert!(event_at(hash, 5)
+        .check_precondition(Some(b"v1"), 6)
+        .is_err());
+    // the read isn't before the entry.
+    assert!(event_at(hash, 10)
+        .check_precondition(Some(b"v1"), 5)
+        .is_err());
 }
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use tokio::sync::oneshot;
+
+use super::clock::Clock;
+use super::error::Error;
+use super::error::ProposalError;
+use super::error::RaftError;
+
+use crate::proto::AppWriteRequest;
+use crate::proto::ForwardedProposal;
+use crate::proto::ForwardedProposalResponse;
+use crate::proto::RaftMessage;
+
+/// The proposal is forwarded at most `MAX_FORWARD_HOPS` times, so that the
+/// proposal doesn't loop between the nodes which see stale leaders.
+pub const MAX_FORWARD_HOPS: u32 = 2;
+
+struct PendingForward {
+    group_id: u64,
+    replica_id: u64,
+    tx: oneshot::Sender<Result<(), Error>>,
+    forwarded_at: Instant,
+}
+
+/// ProposalForwards tracks the proposals forwarded to the leaders until
+/// their responses are relayed back or `timeout` elapsed.
+pub struct ProposalForwards {
+    next_id: u64,
+    timeout: Duration,
+    clock: Arc<dyn Clock>,
+    pending: HashMap<u64, PendingForward>,
+}
+
+impl ProposalForwards {
+    pub fn new(timeout: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            next_id: 1,
+            timeout,
+            clock,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Returns the timeout of the forwarded proposals.
+    #[inline]
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Track the proposal of the local `replica_id`, returns the id which
+    /// correlates the response.
+    pub fn register(
+        &mut self,
+        group_id: u64,
+        replica_id: u64,
+        tx: oneshot::Sender<Result<(), Error>>,
+    ) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(
+            id,
+            PendingForward {
+                group_id,
+                replica_id,
+                tx,
+                forwarded_at: self.clock.now(),
+            },
+        );
+        id
+    }
+
+    /// Stop tracking the forwarded proposal, returns the tx and the result
+    /// converted from the response.
+    pub fn complete(
+        &mut self,
+        response: &ForwardedProposalResponse,
+    ) -> Option<(oneshot::Sender<Result<(), Error>>, Result<(), Error>)> {
+        let pending = self.pending.remove(&response.id)?;
+        let res = if response.not_leader {
+            Err(Error::Raft(RaftError::NotLeader(
+                pending.group_id,
+                pending.replica_id,
+                response.leader_id,
+            )))
+        } else if !response.error.is_empty() {
+            Err(Error::Proposal(ProposalError::Other(
+                response.error.clone().into(),
+            )))
+        } else {
+            Ok(())
+        };
+        Some((pending.tx, res))
+    }
+
+    /// Fail the forwarded proposal with `err`, e.g. the forward can't be sent.
+    pub fn fail(&mut self, id: u64, err: Error) {
+        if let Some(pending) = self.pending.remove(&id) {
+            let _ = pending.tx.send(Err(err));
+        }
+    }
+
+    /// Fail the forwarded proposals which aren't responded within the timeout.
+    pub fn expire(&mut self) {
+        let timeout = self.timeout;
+        let now = self.clock.now();
+        let expired = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| now.saturating_duration_since(pending.forwarded_at) >= timeout)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in expired {
+            self.fail(id, Error::Proposal(ProposalError::Timeout));
+        }
+    }
+}
+
+pub fn forward_message(
+    from_node: u64,
+    to_node: u64,
+    id: u64,
+    request: AppWriteRequest,
+    hops: u32,
+) -> RaftMessage {
+    RaftMessage {
+        group_id: request.group_id,
+        from_node,
+        to_node,
+        msg: None,
+        heartbeats: vec![],
+        snapshot_chunk: None,
+        snapshot_chunk_ack: None,
+        snapshot_checksum: 0,
+        forward_proposal: Some(ForwardedProposal {
+            id,
+            request: Some(request),
+            hops,
+        }),
+        forward_response: None,
+        heartbeat_sent_at: 0,
+        reset_replica: None,
+        correlation_ids: vec![],
+    }
+}
+
+/// Make the message which relays the `res` of the forwarded proposal back.
+pub fn response_message(
+    group_id: u64,
+    from_node: u64,
+    to_node: u64,
+    id: u64,
+    res: Result<(), Error>,
+    commit_index: u64,
+) -> RaftMessage {
+    let mut response = ForwardedProposalResponse {
+        id,
+        error: String::new(),
+        not_leader: false,
+        leader_id: 0,
+        commit_index,
+    };
+    match res {
+        Ok(_) => {}
+        Err(Error::Raft(RaftError::NotLeader(_, _, leader_id))) => {
+            response.not_leader = true;
+            response.leader_id = leader_id;
+        }
+        Err(err) => response.error = err.to_string(),
+    }
+
+    RaftMessage {
+        group_id,
+        from_node,
+        to_node,
+        msg: None,
+        heartbeats: vec![],
+        snapshot_chunk: None,
+        snapshot_chunk_ack: None,
+        snapshot_checksum: 0,
+        forward_proposal: None,
+        forward_response: Some(response),
+        heartbeat_sent_at: 0,
+        reset_replica: None,
+        correlation_ids: vec![],
+    }
+}
+
+#[test]
+fn test_forward_response_result() {
+    use super::clock::SystemClock;
+
+    let mut forwards = ProposalForwards::new(Duration::from_secs(1), Arc::new(SystemClock));
+    let (tx, _rx) = oneshot::channel();
+    let id = forwards.register(1, 2, tx);
+
+    let res = Err(Error::Raft(RaftError::NotLeader(1, 3, 0)));
+    let msg = response_message(1, 3, 2, id, res, 0);
+    let (_, res) = forwards.complete(msg.forward_response.as_ref().unwrap()).unwrap();
+    assert_eq!(res, Err(Error::Raft(RaftError::NotLeader(1, 2, 0))));
+    // the response is relayed once.
+    assert!(forwards
+        .complete(msg.forward_response.as_ref().unwrap())
+        .is_none());
+}
+
+#[test]
+fn test_forward_expire() {
+    use super::clock::MockClock;
+
+    let clock = MockClock::new();
+    let mut forwards = ProposalForwards::new(Duration::from_secs(1), Arc::new(clock.clone()));
+    let (tx, mut rx) = oneshot::channel();
+    forwards.register(1, 2, tx);
+    forwards.expire();
+    assert!(rx.try_recv().is_err());
+
+    clock.advance(Duration::from_secs(1));
+    forwards.expire();
+    assert_eq!(
+        rx.try_recv().unwrap(),
+        Err(Error::Proposal(ProposalError::Timeout))
+    );
+}
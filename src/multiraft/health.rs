@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use tokio::sync::mpsc::Sender;
+
+/// The liveness summary of the node, it's used by the orchestration to
+/// probe the readiness of the node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeHealth {
+    /// If false, the actor loop is exited or wedged, it doesn't tick or
+    /// respond to the query in time.
+    pub actor_running: bool,
+    /// The elapsed time since the last tick of groups.
+    pub last_tick_elapsed: Duration,
+    /// The number of groups ticked by the last tick pass and its cost, the
+    /// passes are staggered if `tick_stagger_slots` > 1.
+    pub last_tick_groups: usize,
+    pub last_tick_cost: Duration,
+    pub group_count: usize,
+    /// The number of groups which the local replica knows the leader.
+    pub leader_known_count: usize,
+    pub quiesced_count: usize,
+    /// The groups which have no leader beyond the election timeout.
+    pub stuck_groups: Vec<u64>,
+    /// The depth of the mailboxes of the actor, the actor falls behind if they
+    /// are near the capacity.
+    pub mailboxes: MailboxStats,
+    /// The number of the snapshots inflight from the node and queued by
+    /// `max_concurrent_snapshots`.
+    pub inflight_snapshots: usize,
+    pub queued_snapshots: usize,
+    /// The bytes of the entries buffered in the logs of the groups, see
+    /// `MultiRaftConfig::max_log_memory`.
+    pub log_bytes: u64,
+}
+
+impl NodeHealth {
+    /// Returns the health of the node whose actor loop is not running.
+    pub fn not_running(last_tick_elapsed: Duration) -> Self {
+        Self {
+            actor_running: false,
+            last_tick_elapsed,
+            last_tick_groups: 0,
+            last_tick_cost: Duration::ZERO,
+            group_count: 0,
+            leader_known_count: 0,
+            quiesced_count: 0,
+            stuck_groups: vec![],
+            mailboxes: MailboxStats::default(),
+            inflight_snapshots: 0,
+            queued_snapshots: 0,
+            log_bytes: 0,
+        }
+    }
+
+    /// Returns true if the actor loop is running and no group is stuck.
+    #[inline]
+    pub fn is_healthy(&self) -> bool {
+        self.actor_running && self.stuck_groups.is_empty()
+    }
+}
+
+/// The number of the commands queued in a mailbox of the actor, and the
+/// capacity of the mailbox. The producers await once the mailbox is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MailboxDepth {
+    pub depth: usize,
+    pub capacity: usize,
+}
+
+impl MailboxDepth {
+    pub(crate) fn of<T>(tx: &Sender<T>) -> Self {
+        Self {
+            depth: tx.max_capacity() - tx.capacity(),
+            capacity: tx.max_capacity(),
+        }
+    }
+
+    /// Returns true if at least 90% of the capacity is used.
+    #[inline]
+    pub fn is_near_full(&self) -> bool {
+        self.depth * 10 >= self.capacity * 9
+    }
+}
+
+/// The depth of the mailboxes of the actor, see `MultiRaftConfig` for their
+/// capacities.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MailboxStats {
+    /// The writes proposed by `MultiRaft::write` and its variants.
+    pub writes: MailboxDepth,
+    pub read_indexes: MailboxDepth,
+    pub membership_changes: MailboxDepth,
+    /// The raft messages received from the peer nodes.
+    pub messages: MailboxDepth,
+}
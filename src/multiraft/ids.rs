@@ -0,0 +1,69 @@
+use std::fmt;
+
+/// Define the newtype of a `u64` id, which converts from and into `u64`.
+///
+/// The public API of `MultiRaft` takes and returns the ids by value, so a bare
+/// `u64` or an id of another kind passed in the wrong place is rejected at
+/// compile time, the caller wraps the `u64` explicitly, e.g. `GroupId(1)`. The
+/// proto types keep the `u64` ids of the wire format, they're converted at the
+/// boundary by `From`.
+macro_rules! define_id {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name(pub u64);
+
+        impl From<u64> for $name {
+            #[inline]
+            fn from(id: u64) -> Self {
+                Self(id)
+            }
+        }
+
+        impl From<$name> for u64 {
+            #[inline]
+            fn from(id: $name) -> u64 {
+                id.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", self.0)
+            }
+        }
+    };
+}
+
+define_id!(
+    /// The id of a raft group.
+    GroupId
+);
+
+define_id!(
+    /// The id of a node, i.e. a `MultiRaft`.
+    NodeId
+);
+
+define_id!(
+    /// The id of a store of a node.
+    StoreId
+);
+
+define_id!(
+    /// The id of a replica of a group, it's unique in the group.
+    ReplicaId
+);
+
+#[test]
+fn test_ids_convert() {
+    fn group(group_id: GroupId) -> u64 {
+        group_id.0
+    }
+
+    assert_eq!(group(GroupId(3)), 3);
+    assert_eq!(group(GroupId::from(3)), 3);
+    assert_eq!(u64::from(ReplicaId::from(7)), 7);
+    assert_eq!(NodeId(1).to_string(), "1");
+    assert!(StoreId(1) < StoreId(2));
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::clock::Clock;
+
+/// The weight of the latest sample in the EWMA of the round trip.
+const EWMA_ALPHA: f64 = 0.2;
+
+/// The round-trip latency of the link from this node to a peer node, which is
+/// measured by the coalesced heartbeats and their responses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NodeLatency {
+    pub from_node: u64,
+    pub to_node: u64,
+    /// The EWMA of the round trips.
+    pub rtt: Duration,
+    /// The latest round trip.
+    pub last_rtt: Duration,
+    pub samples: u64,
+}
+
+/// LatencyObserver is called whenever a round trip to a peer node is measured,
+/// it's called in the actor loop, so it should not block.
+pub trait LatencyObserver: Send + Sync + 'static {
+    fn on_round_trip(&self, latency: &NodeLatency);
+}
+
+/// NodeLatencies tracks the EWMA of the round trips from this node to the peer
+/// nodes, the clones share the same latencies.
+#[derive(Clone)]
+pub struct NodeLatencies {
+    node_id: u64,
+    observer: Option<Arc<dyn LatencyObserver>>,
+    clock: Arc<dyn Clock>,
+    latencies: Arc<Mutex<HashMap<u64, NodeLatency>>>,
+}
+
+impl NodeLatencies {
+    pub fn new(
+        node_id: u64,
+        observer: Option<Arc<dyn LatencyObserver>>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            node_id,
+            observer,
+            clock,
+            latencies: Default::default(),
+        }
+    }
+
+    /// Returns the microseconds since the unix epoch, which stamps the
+    /// coalesced heartbeat. The stamp is only compared with the clock of the
+    /// same node.
+    #[inline]
+    pub fn now_micros(&self) -> u64 {
+        self.clock.now_micros()
+    }
+
+    /// Record the round trip of the heartbeat sent to `to_node` at `sent_at`,
+    /// the stamp in the future, e.g. the clock goes backwards, is ignored.
+    pub fn observe(&self, to_node: u64, sent_at: u64) {
+        let now = self.now_micros();
+        if sent_at == 0 || sent_at > now {
+            return;
+        }
+        let sample = Duration::from_micros(now - sent_at);
+        let latency = {
+            let mut latencies = self.latencies.lock().unwrap();
+            let latency = latencies.entry(to_node).or_insert(NodeLatency {
+                from_node: self.node_id,
+                to_node,
+                rtt: sample,
+                last_rtt: sample,
+                samples: 0,
+            });
+            if latency.samples != 0 {
+                latency.rtt = latency.rtt.mul_f64(1.0 - EWMA_ALPHA) + sample.mul_f64(EWMA_ALPHA);
+            }
+            latency.last_rtt = sample;
+            latency.samples += 1;
+            *latency
+        };
+        if let Some(observer) = self.observer.as_ref() {
+            observer.on_round_trip(&latency);
+        }
+    }
+
+    /// Returns the EWMA of the round trips to the node, none if it's not
+    /// measured yet.
+    pub fn rtt(&self, to_node: u64) -> Option<Duration> {
+        self.latencies
+            .lock()
+            .unwrap()
+            .get(&to_node)
+            .map(|latency| latency.rtt)
+    }
+
+    /// Returns the latencies to all measured nodes, ordered by the node.
+    pub fn latencies(&self) -> Vec<NodeLatency> {
+        let mut latencies = self
+            .latencies
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        latencies.sort_by_key(|latency| latency.to_node);
+        latencies
+    }
+}
+
+#[test]
+fn test_node_latencies_ewma() {
+    use super::clock::MockClock;
+
+    let clock = MockClock::new();
+    let latencies = NodeLatencies::new(1, None, Arc::new(clock.clone()));
+    assert_eq!(latencies.rtt(2), None);
+
+    let sent_at = latencies.now_micros();
+    clock.advance(Duration::from_millis(10));
+    latencies.observe(2, sent_at);
+    assert_eq!(latencies.rtt(2), Some(Duration::from_millis(10)));
+
+    // the EWMA moves towards the latest sample by `EWMA_ALPHA`.
+    let sent_at = latencies.now_micros();
+    clock.advance(Duration::from_millis(110));
+    latencies.observe(2, sent_at);
+    let latency = latencies.latencies()[0];
+    assert_eq!(latency.samples, 2);
+    assert_eq!(latency.last_rtt, Duration::from_millis(110));
+    assert!((latency.rtt.as_secs_f64() - 0.03).abs() < 1e-6);
+
+    // the stamps not set or in the future are ignored.
+    latencies.observe(3, 0);
+    latencies.observe(3, latencies.now_micros() + 1_000_000);
+    assert_eq!(latencies.rtt(3), None);
+}
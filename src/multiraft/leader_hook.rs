@@ -0,0 +1,16 @@
+/// LeaderHook is called by the actor when the local replica becomes the
+/// leader of a group, exactly once per leadership acquisition.
+///
+/// raft appends an empty no-op entry at the start of every term of the leader,
+/// which commits the entries of the previous terms and can't be disabled. The
+/// hook lets the application piggyback its own leadership epoch data, which is
+/// proposed right behind the no-op, so it's the first data entry of the term
+/// before any proposal of the term. The indexes of both are emitted by the
+/// `LeaderEpoch` event. The hook is called in the actor loop, so it should not
+/// block for long.
+pub trait LeaderHook: Send + Sync + 'static {
+    /// Returns the leadership epoch data of the `term` of the group, nothing
+    /// is proposed if it's none. The proposal isn't retried if the leadership
+    /// is lost before it's committed.
+    fn on_leader(&self, group_id: u64, term: u64) -> Option<Vec<u8>>;
+}
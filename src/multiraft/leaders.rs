@@ -0,0 +1,62 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+/// LocalLeaders caches the groups whose replica on this node is the leader,
+/// the clones share the same groups.
+///
+/// It's updated by the actor when the soft state of a group changes, so the
+/// callers read it without a round trip to the actor. The cache is briefly
+/// stale around a leadership change, e.g. the deposed leader is still cached
+/// until it observes the higher term.
+#[derive(Clone, Default)]
+pub struct LocalLeaders {
+    groups: Arc<RwLock<HashSet<u64>>>,
+}
+
+impl LocalLeaders {
+    /// Record whether the replica of the group on this node is the leader.
+    pub fn update(&self, group_id: u64, is_leader: bool) {
+        let mut groups = self.groups.write().unwrap();
+        if is_leader {
+            groups.insert(group_id);
+        } else {
+            groups.remove(&group_id);
+        }
+    }
+
+    #[inline]
+    pub fn remove(&self, group_id: u64) {
+        self.update(group_id, false)
+    }
+
+    /// Forget all leaderships, e.g. the actor died and its replicas are
+    /// recreated as followers.
+    pub fn clear(&self) {
+        self.groups.write().unwrap().clear();
+    }
+
+    #[inline]
+    pub fn is_leader(&self, group_id: u64) -> bool {
+        self.groups.read().unwrap().contains(&group_id)
+    }
+}
+
+#[test]
+fn test_local_leaders_shared_by_clones() {
+    let leaders = LocalLeaders::default();
+    let cloned = leaders.clone();
+    leaders.update(1, true);
+    leaders.update(2, true);
+    assert!(cloned.is_leader(1));
+    assert!(cloned.is_leader(2));
+
+    leaders.update(1, false);
+    cloned.remove(2);
+    assert!(!leaders.is_leader(1));
+    assert!(!leaders.is_leader(2));
+
+    leaders.update(3, true);
+    leaders.clear();
+    assert!(!cloned.is_leader(3));
+}
@@ -0,0 +1,110 @@
+use std::collections::VecDeque;
+
+use crate::proto::Entry;
+
+/// LogSize tracks the bytes of the entries in the log of a group, which are
+/// buffered by the storage until they are compacted. It's maintained by the
+/// actor as the entries are persisted and the log is compacted, so the size
+/// is known without reading the storage, see `MultiRaftConfig::max_log_memory`.
+#[derive(Debug, Default)]
+pub struct LogSize {
+    // the index of the first entry of `sizes`.
+    first_index: u64,
+    sizes: VecDeque<u64>,
+    total: u64,
+}
+
+impl LogSize {
+    /// Returns the bytes of the data and context of the entries in the log.
+    #[inline]
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Returns the bytes of the entries after `index`, which are left after
+    /// the log is compacted up to it.
+    pub fn total_after(&self, index: u64) -> u64 {
+        let skip = index.saturating_add(1).saturating_sub(self.first_index);
+        self.sizes.iter().skip(skip as usize).sum()
+    }
+
+    /// Record the persisted entries, the entries conflicting with them, i.e.
+    /// from the index of the first one, are truncated.
+    pub fn append(&mut self, entries: &[Entry]) {
+        let first = match entries.first() {
+            None => return,
+            Some(entry) => entry.index,
+        };
+        if self.sizes.is_empty() || first < self.first_index {
+            self.clear(first);
+        } else {
+            let keep = std::cmp::min((first - self.first_index) as usize, self.sizes.len());
+            for size in self.sizes.drain(keep..) {
+                self.total -= size;
+            }
+        }
+        for entry in entries {
+            let size = (entry.data.len() + entry.context.len()) as u64;
+            self.sizes.push_back(size);
+            self.total += size;
+        }
+    }
+
+    /// The entries up to `index` are compacted.
+    pub fn compact_to(&mut self, index: u64) {
+        while self.first_index <= index {
+            match self.sizes.pop_front() {
+                None => break,
+                Some(size) => self.total -= size,
+            }
+            self.first_index += 1;
+        }
+    }
+
+    /// The log is emptied and the next entry is at `next_index`, e.g. it's
+    /// replaced by the installed snapshot.
+    pub fn clear(&mut self, next_index: u64) {
+        self.first_index = next_index;
+        self.sizes.clear();
+        self.total = 0;
+    }
+}
+
+#[test]
+fn test_log_size() {
+    let entries = |first: u64, sizes: &[usize]| {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(n, size)| {
+                let mut entry = Entry::default();
+                entry.index = first + n as u64;
+                entry.data = vec![0; *size];
+                entry
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut log = LogSize::default();
+    log.append(&entries(1, &[10, 20, 30]));
+    assert_eq!(log.total(), 60);
+
+    // the conflicting entries are truncated, the retried write is idempotent.
+    log.append(&entries(3, &[5, 5]));
+    assert_eq!(log.total(), 40);
+    log.append(&entries(3, &[5, 5]));
+    assert_eq!(log.total(), 40);
+
+    assert_eq!(log.total_after(1), 30);
+    assert_eq!(log.total_after(0), 40);
+    log.compact_to(2);
+    assert_eq!(log.total(), 10);
+    log.append(&entries(5, &[1]));
+    assert_eq!(log.total(), 11);
+    log.compact_to(10);
+    assert_eq!(log.total(), 0);
+
+    log.clear(20);
+    log.append(&entries(20, &[7]));
+    assert_eq!(log.total(), 7);
+}
@@ -1,5 +1,15 @@
+mod applied;
 mod apply;
+mod balancer;
+mod clock;
+mod command;
 mod config;
+mod conf_state;
+mod correlation;
+mod dedup;
+mod dropped;
+mod embedded;
+pub mod entry;
 mod error;
 mod multiraft;
 mod multiraft_actor;
@@ -9,16 +19,114 @@ mod transport;
 mod transport_local;
 // mod write;
 mod event;
+mod forward;
+mod health;
+mod ids;
+mod latency;
+mod leader_hook;
+mod leaders;
+mod log_size;
 mod node;
+mod pending;
+mod placement;
 mod raft_group;
+mod ready_hook;
+mod ready_worker;
 mod replica_cache;
+mod resolver;
+mod retry;
+mod snapshot;
+mod state_machine;
+mod topology;
+mod watchdog;
 
+pub use clock::Clock;
+pub use clock::MockClock;
+pub use clock::SystemClock;
+#[cfg(feature = "bincode-command")]
+pub use command::BincodeCommand;
+pub use command::Command;
+pub use conf_state::membership_conf_change;
+pub use dropped::DropReason;
+pub use dropped::DroppedMessage;
+pub use dropped::DroppedMessageObserver;
+pub use dropped::TraceDroppedMessageObserver;
+pub use embedded::EmbeddedMultiRaft;
+pub use embedded::EmbeddedNode;
+pub use embedded::EMBEDDED_EVENTS_CAPACITY;
+pub use error::ConfChangeError;
+pub use error::Error;
+pub use error::ProposalError;
+pub use event::AppliedEntry;
 pub use event::Event;
 pub use event::ApplyEvent;
+pub use event::value_hash;
+pub use event::GroupCreatedEvent;
+pub use event::GroupFailedEvent;
+pub use event::GroupRecoveredEvent;
+pub use event::GroupStateChangedEvent;
+pub use event::GroupStorageErrorEvent;
+pub use event::GroupUnhealthyEvent;
 pub use event::LeaderElectionEvent;
+pub use event::LeaderEpochEvent;
+pub use event::LeaderTransferEvent;
+pub use event::LogPinnedEvent;
+pub use event::NodeFailureEvent;
+pub use event::UnhealthyReason;
+pub use health::MailboxDepth;
+pub use health::MailboxStats;
+pub use health::NodeHealth;
+pub use ids::GroupId;
+pub use ids::NodeId;
+pub use ids::ReplicaId;
+pub use ids::StoreId;
+pub use latency::LatencyObserver;
+pub use latency::NodeLatency;
+pub use leader_hook::LeaderHook;
 pub use multiraft::MultiRaft;
+pub use multiraft::MultiRaftExtensions;
 pub use multiraft_message::MultiRaftMessageSender;
+pub use placement::ReplicaPlacer;
+pub use placement::RoundRobinPlacer;
+pub use raft_group::CommitToken;
+#[cfg(feature = "test-util")]
+pub use raft_group::GroupCounters;
+pub use raft_group::GroupState;
+pub use raft_group::GroupStatus;
+pub use raft_group::InitResult;
+pub use raft_group::ReadState;
+pub use raft_group::ReplicaProgress;
+pub use raft_group::ReplicaRole;
+pub use raft_group::TransferLeaderPolicy;
+pub use raft_group::AutoPromotePolicy;
+pub use ready_hook::ReadyHook;
+pub use ready_hook::ReadyStage;
+pub use replica_cache::ReplicaCacheStats;
+pub use resolver::MemNodeResolver;
+pub use resolver::NodeAddress;
+pub use resolver::NodeResolver;
+pub use retry::RetryPolicy;
+pub use state_machine::ApplyError;
+pub use state_machine::ApplyHandle;
+pub use state_machine::ApplyOutput;
+pub use state_machine::StateMachine;
+pub use topology::GroupTopology;
+pub use topology::Topology;
 
+pub use config::GroupConfig;
 pub use config::MultiRaftConfig;
+#[cfg(feature = "serde-config")]
+pub use config::decode_config;
+#[cfg(feature = "serde-config")]
+pub use config::encode_config;
+#[cfg(feature = "serde-config")]
+pub use config::CONFIG_ENCODING_VERSION;
 
+pub use transport::message_priority;
+pub use transport::MessageInterface;
+pub use transport::MessagePriority;
+pub use transport::Transport;
+pub use transport::TransportStats;
+pub use transport_local::FilterAction;
 pub use transport_local::LocalTransport;
+pub use transport_local::SimNetwork;
@@ -1,30 +1,141 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
 
+use futures::Stream;
+use futures::StreamExt;
+use raft::StateRole;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::oneshot;
 use tokio::sync::watch;
 use tokio::task::JoinHandle;
+use tracing::warn;
 
+use super::applied::AppliedEntries;
 use super::apply::ApplyActor;
+use super::balancer::LeaderBalancer;
+use super::clock::Clock;
+use super::clock::SystemClock;
+use super::command::Command;
+use super::conf_state::validate_conf_change;
+#[cfg(feature = "serde-config")]
+use super::config::encode_config;
+use super::config::GroupConfig;
 use super::config::MultiRaftConfig;
+use super::dedup::DedupTables;
+use super::dropped::DropReason;
+use super::dropped::DroppedMessageObserver;
+use super::dropped::DroppedMessages;
+#[cfg(feature = "serde-config")]
+use super::entry::ADMIN_GROUP_CONFIG;
+use super::error::ConfChangeError;
 use super::error::Error;
+use super::error::ProposalError;
+use super::error::RaftError;
+use super::event::AppliedEntry;
 use super::event::Event;
+use super::health::MailboxDepth;
+use super::health::MailboxStats;
+use super::health::NodeHealth;
+use super::ids::GroupId;
+use super::ids::NodeId;
+use super::ids::ReplicaId;
+use super::ids::StoreId;
+use super::latency::LatencyObserver;
+use super::latency::NodeLatencies;
+use super::leaders::LocalLeaders;
+use super::latency::NodeLatency;
+use super::placement::ReplicaPlacer;
+use super::placement::RoundRobinPlacer;
+use super::raft_group::AutoPromotePolicy;
+use super::raft_group::CommitToken;
+#[cfg(feature = "test-util")]
+use super::raft_group::GroupCounters;
+use super::raft_group::GroupStatus;
+use super::raft_group::InitResult;
+use super::raft_group::ReadState;
+use super::raft_group::ReplicaRole;
+use super::raft_group::TransferLeaderPolicy;
+use super::leader_hook::LeaderHook;
+use super::ready_hook::ReadyHook;
+use super::replica_cache::ReplicaCacheStats;
+use super::resolver::NodeResolver;
+use super::retry::retry_action;
+use super::retry::RetryAction;
+use super::retry::RetryPolicy;
+use super::state_machine;
+use super::state_machine::StateMachine;
+use super::topology::Topology;
+use super::multiraft_actor::ActorMailboxes;
+use super::multiraft_actor::ActorRemains;
 use super::multiraft_actor::MultiRaftActor;
 use super::multiraft_actor::MultiRaftActorAddress;
+use super::multiraft_actor::QueryGroup;
+use super::multiraft_message::MultiRaftMessageSender;
 use super::transport::MessageInterface;
 use super::transport::Transport;
+use super::watchdog::ActorWatchdog;
 
 use crate::proto::AppReadIndexRequest;
 use crate::proto::AppWriteRequest;
+use crate::proto::ConfChangeTransition;
+use crate::proto::ConfChangeType;
+use crate::proto::ConfChangeV2;
+use crate::proto::ConfState;
+use crate::proto::Entry;
+use crate::proto::MembershipChangeData;
+use crate::proto::MembershipChangeRequest;
+use crate::proto::Precondition;
+use crate::proto::ReplicaDesc;
 use crate::proto::RaftGroupManagementMessage;
 use crate::proto::RaftGroupManagementMessageType;
+use crate::proto::Snapshot;
+use crate::proto::SnapshotMetadata;
 
 use crate::storage::MultiRaftStorage;
 use crate::storage::RaftStorage;
+use crate::storage::SyncPolicy;
 
 pub const NO_GORUP: u64 = 0;
 pub const NO_NODE: u64 = 0;
 
+/// The optional extensions injected into the multiraft.
+#[derive(Clone, Default)]
+pub struct MultiRaftExtensions {
+    /// Called after each stage of handling the `Ready` of groups, see
+    /// `ReadyStage`.
+    pub ready_hook: Option<Arc<dyn ReadyHook>>,
+    /// Called when the local replica becomes the leader of a group, its data
+    /// is proposed as the leadership epoch entry of the term, see `LeaderHook`.
+    pub leader_hook: Option<Arc<dyn LeaderHook>>,
+    /// Resolves the nodes which the messages are sent to, it's injected into
+    /// the transport by `Transport::set_resolver`, the messages to the unknown
+    /// node are failed with `TransportError::UnknownNode`.
+    pub node_resolver: Option<Arc<dyn NodeResolver>>,
+    /// Called whenever a message is dropped, the dropped messages are emitted
+    /// as debug events if it's none.
+    pub dropped_message_observer: Option<Arc<dyn DroppedMessageObserver>>,
+    /// Called whenever the round trip of a coalesced heartbeat to a peer node
+    /// is measured.
+    pub latency_observer: Option<Arc<dyn LatencyObserver>>,
+    /// The source of time, e.g. the staleness of the follower read and the
+    /// last tick of the health check, `SystemClock` if it's none.
+    pub clock: Option<Arc<dyn Clock>>,
+    /// Decides the nodes of the replicas of the new groups created by
+    /// `MultiRaft::bootstrap_groups`. If it's none, the replicas are placed by
+    /// the `RoundRobinPlacer` on the nodes of the `node_resolver`, or on this
+    /// node only if there is no resolver.
+    pub replica_placer: Option<Arc<dyn ReplicaPlacer>>,
+    /// Applies the committed data entries in the apply actor, the `Apply`
+    /// events of the data entries aren't emitted if it's set.
+    pub state_machine: Option<Arc<dyn StateMachine>>,
+}
+
 /// MultiRaft represents a group of raft replicas
 pub struct MultiRaft<MI, T, RS, MRS>
 where
@@ -37,8 +148,20 @@ where
     config: MultiRaftConfig,
     node_id: u64,
     actor_address: MultiRaftActorAddress,
+    applied_entries: AppliedEntries,
+    dedup_tables: DedupTables,
+    dropped_messages: DroppedMessages,
+    node_latencies: NodeLatencies,
+    local_leaders: LocalLeaders,
+    clock: Arc<dyn Clock>,
+    replica_placer: Arc<dyn ReplicaPlacer>,
+    node_resolver: Option<Arc<dyn NodeResolver>>,
+    transport: Arc<T>,
+    storage: MRS,
+    stop_tx: Arc<watch::Sender<bool>>,
     apply_join_handle: JoinHandle<()>,
     actor_join_handle: JoinHandle<()>,
+    balancer_join_handle: Option<JoinHandle<()>>,
     _m1: PhantomData<MI>,
     _m2: PhantomData<T>,
     _m3: PhantomData<RS>,
@@ -52,36 +175,190 @@ where
     RS: RaftStorage,
     MRS: MultiRaftStorage<RS>,
 {
+    /// Create the multiraft of node and spawn its actors, it panics if the
+    /// config is invalid, see `MultiRaftConfig::validate`.
     pub fn new(
         config: MultiRaftConfig,
-        node_id: u64,
-        store_id: u64,
+        node_id: NodeId,
+        store_id: StoreId,
         transport: T,
         storage: MRS,
         stop_rx: watch::Receiver<bool>,
         event_tx: Sender<Vec<Event>>,
     ) -> Self {
-        let (apply_join_handle, apply_actor_address) =
-            ApplyActor::spawn(event_tx.clone(), stop_rx.clone());
-
-        let (actor_join_handle, actor_address) = MultiRaftActor::spawn(
-            &config,
+        Self::new_with_extensions(
+            config,
             node_id,
             store_id,
             transport,
-            apply_actor_address,
-            event_tx.clone(),
             storage,
+            stop_rx,
+            event_tx,
+            MultiRaftExtensions::default(),
+        )
+    }
+
+    /// Create the multiraft like `new` with the `extensions`, see
+    /// `MultiRaftExtensions`.
+    pub fn new_with_extensions(
+        config: MultiRaftConfig,
+        node_id: NodeId,
+        store_id: StoreId,
+        transport: T,
+        storage: MRS,
+        stop_rx: watch::Receiver<bool>,
+        event_tx: Sender<Vec<Event>>,
+        extensions: MultiRaftExtensions,
+    ) -> Self {
+        let node_id = node_id.0;
+        let store_id = store_id.0;
+        if let Err(err) = config.validate() {
+            panic!("invalid multiraft config: {}", err)
+        }
+
+        let group_policies = storage.group_sync_policies();
+        let policies = group_policies.iter().map(|(_, policy)| *policy);
+        for policy in policies.chain(std::iter::once(storage.sync_policy())) {
+            if let Err(err) = check_sync_policy(policy, cfg!(feature = "test-util")) {
+                panic!("invalid storage: {}", err)
+            }
+        }
+
+        // the actors are stopped by either the `stop_rx` or `prepare_shutdown`.
+        let (stop_tx, inner_stop_rx) = watch::channel(false);
+        let stop_tx = Arc::new(stop_tx);
+        let forward_stop_tx = stop_tx.clone();
+        let mut outer_stop_rx = stop_rx;
+        tokio::spawn(async move {
+            while outer_stop_rx.changed().await.is_ok() {
+                if *outer_stop_rx.borrow() {
+                    let _ = forward_stop_tx.send(true);
+                    break;
+                }
+            }
+        });
+        let stop_rx = inner_stop_rx;
+
+        let applied_entries = AppliedEntries::new(
+            config.apply_results_capacity,
+            config.apply_results_retention,
+            config.apply_results_retention_size as usize,
+        );
+        let dedup_tables = DedupTables::new(config.proposal_dedup_capacity);
+        let (apply_join_handle, apply_actor_address) = ApplyActor::spawn(
+            event_tx.clone(),
+            applied_entries.clone(),
+            dedup_tables.clone(),
+            extensions.state_machine.clone(),
+            config.ordered_apply,
+            stop_rx.clone(),
+        );
+
+        let dropped_messages = DroppedMessages::new(extensions.dropped_message_observer.clone());
+        let clock = extensions
+            .clock
+            .clone()
+            .unwrap_or_else(|| Arc::new(SystemClock));
+        let node_latencies = NodeLatencies::new(
+            node_id,
+            extensions.latency_observer.clone(),
+            clock.clone(),
+        );
+        let local_leaders = LocalLeaders::default();
+        let node_resolver = extensions.node_resolver.clone();
+        let replica_placer = extensions.replica_placer.clone().unwrap_or_else(|| {
+            let placer = match node_resolver.as_ref() {
+                None => RoundRobinPlacer::new(vec![node_id]),
+                Some(resolver) => RoundRobinPlacer::with_resolver(resolver.clone()),
+            };
+            Arc::new(placer)
+        });
+        if let Some(resolver) = node_resolver.as_ref() {
+            transport.set_resolver(node_id, resolver.clone());
+        }
+        transport.set_dropped_observer(node_id, Arc::new(dropped_messages.clone()));
+        let transport = Arc::new(transport);
+        let (mailboxes, actor_address) =
+            ActorMailboxes::new(&config, apply_actor_address, clock.as_ref());
+        // the dead actor hands back its mailboxes and replicas, with which the
+        // watchdog restarts the actor if `restart_actor_on_panic`.
+        let remains = Arc::new(Mutex::new(None));
+        let spawn_actor = {
+            let config = config.clone();
+            let transport = transport.clone();
+            let storage = storage.clone();
+            let event_tx = event_tx.clone();
+            let dropped_messages = dropped_messages.clone();
+            let node_latencies = node_latencies.clone();
+            let local_leaders = local_leaders.clone();
+            let clock = clock.clone();
+            let remains = remains.clone();
+            let stop_rx = stop_rx.clone();
+            move |actor_remains: ActorRemains| {
+                MultiRaftActor::spawn(
+                    &config,
+                    node_id,
+                    store_id,
+                    transport.clone(),
+                    event_tx.clone(),
+                    storage.clone(),
+                    extensions.clone(),
+                    dropped_messages.clone(),
+                    node_latencies.clone(),
+                    local_leaders.clone(),
+                    clock.clone(),
+                    actor_remains.mailboxes,
+                    actor_remains.replicas,
+                    remains.clone(),
+                    stop_rx.clone(),
+                )
+            }
+        };
+        let actor = spawn_actor(ActorRemains {
+            mailboxes,
+            replicas: vec![],
+        });
+        let actor_join_handle = ActorWatchdog::spawn(
+            &config,
+            node_id,
+            actor,
+            spawn_actor,
+            remains,
+            local_leaders.clone(),
+            event_tx.clone(),
             stop_rx.clone(),
         );
 
+        let balancer_join_handle = if config.enable_leader_balance {
+            Some(LeaderBalancer::spawn(
+                &config,
+                node_id,
+                actor_address.clone(),
+                stop_rx.clone(),
+            ))
+        } else {
+            None
+        };
+
         Self {
             node_id,
             store_id,
             config,
             apply_join_handle,
             actor_address,
+            applied_entries,
+            dedup_tables,
+            dropped_messages,
+            node_latencies,
+            local_leaders,
+            clock,
+            replica_placer,
+            node_resolver,
+            transport,
+            storage,
+            stop_tx,
             actor_join_handle,
+            balancer_join_handle,
             _m1: PhantomData,
             _m2: PhantomData,
             _m3: PhantomData,
@@ -89,7 +366,112 @@ where
         }
     }
 
-    pub async fn write(&self, request: AppWriteRequest) -> Result<(), Error> {
+    /// Returns the number of the messages dropped on this node of each reason,
+    /// including the ones sent by this node and dropped by the transport, see
+    /// `DropReason`.
+    pub fn dropped_message_counts(&self) -> Vec<(DropReason, u64)> {
+        self.dropped_messages.counts()
+    }
+
+    /// Returns the round-trip latencies from this node to the peer nodes,
+    /// which are measured by the coalesced heartbeats.
+    pub fn node_latencies(&self) -> Vec<NodeLatency> {
+        self.node_latencies.latencies()
+    }
+
+    /// Returns whether the replica of the group on this node is the leader.
+    ///
+    /// It's answered from a cache updated by the actor when the soft state of
+    /// the group changes instead of querying the actor, so it's cheap but may
+    /// be briefly stale around a leadership change: a deposed leader is
+    /// reported as the leader until it observes the new term. Use
+    /// `read_index` or a proposal when the leadership must be confirmed.
+    pub fn is_leader(&self, group_id: GroupId) -> bool {
+        let group_id = group_id.0;
+        self.local_leaders.is_leader(group_id)
+    }
+
+    /// Returns the transport which the messages of this node are sent by, e.g.
+    /// to isolate the node in the tests or export the connection stats. The
+    /// transport is shared with the actor, so its methods must be internally
+    /// synchronized.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Returns the sender which is used by the transport to deliver the
+    /// messages received from other nodes to this node.
+    pub fn message_sender(&self) -> MultiRaftMessageSender {
+        MultiRaftMessageSender::new(self.actor_address.clone())
+    }
+
+    /// Returns a stream of the entries applied on this node, including the
+    /// proposal context so a client can correlate its proposal with the result.
+    ///
+    /// The delivery is at-most-once: the entries applied before subscribing are
+    /// not observed unless replayed by `apply_results_from`, and the apply loop
+    /// never waits for a subscriber. If the
+    /// subscriber lags more than `apply_results_capacity` entries, the oldest
+    /// entries are skipped and a warning is logged.
+    pub fn apply_results(&self) -> impl Stream<Item = AppliedEntry> {
+        let rx = self.applied_entries.subscribe();
+        futures::stream::unfold(rx, |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(applied) => return Some((applied, rx)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("apply results subscriber lagged, skipped {} entries", skipped);
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    /// Returns a stream of the entries applied on this node of the group from
+    /// `from_index`, e.g. for a client which reconnects to resume its results.
+    /// The retained entries are replayed first, then the stream switches to the
+    /// live entries of the group without a gap, with the same delivery as
+    /// `apply_results`.
+    ///
+    /// Only the last `apply_results_retention` entries of each group, at most
+    /// `apply_results_retention_size` bytes, applied since the node started
+    /// are retained in memory. If the entries from `from_index` aren't all
+    /// retained, e.g. they are evicted or applied before the node restarted,
+    /// `Error::ApplyResultsEvicted` is returned and the client must read the
+    /// state from the storage or snapshot instead.
+    pub fn apply_results_from(
+        &self,
+        group_id: GroupId,
+        from_index: u64,
+    ) -> Result<impl Stream<Item = AppliedEntry>, Error> {
+        let group_id = group_id.0;
+        let (replay, rx) = self.applied_entries.subscribe_from(group_id, from_index)?;
+        let live = futures::stream::unfold(rx, move |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(applied) if applied.group_id == group_id && applied.index >= from_index => {
+                        return Some((applied, rx))
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("apply results subscriber lagged, skipped {} entries", skipped);
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+        Ok(futures::stream::iter(replay).chain(live))
+    }
+
+    /// Propose the write to the group and wait until it's applied, returns the
+    /// `CommitToken` of the write. The token carries the commit index after the
+    /// write is applied, which is not less than the index of the write. The
+    /// write larger than `max_entry_size` is rejected before it's proposed.
+    pub async fn write(&self, request: AppWriteRequest) -> Result<CommitToken, Error> {
+        check_not_applying();
+        let group_id = request.group_id;
+        check_entry_size(&request, self.config.max_entry_size)?;
         let (tx, rx) = oneshot::channel();
         if let Err(_) = self
             .actor_address
@@ -98,10 +480,190 @@ where
             .await
         {}
 
-        rx.await.unwrap()
+        rx.await.map_err(|_| self.dropped_error())??;
+        let index = self
+            .query(|tx| QueryGroup::CommitIndex(group_id, tx))
+            .await??;
+        Ok(CommitToken::new(group_id, index))
+    }
+
+    /// Propose the `data` to the group and wait until it's applied, returns
+    /// `ProposalError::Timeout` if it isn't applied within `timeout`. The
+    /// proposal is no longer tracked once it's timeout or the returned future is
+    /// dropped, the entry committed later is applied without response.
+    pub async fn propose_timeout(
+        &self,
+        group_id: GroupId,
+        data: Vec<u8>,
+        context: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<CommitToken, Error> {
+        let group_id = group_id.0;
+        let request = AppWriteRequest {
+            group_id,
+            term: 0,
+            data,
+            context,
+            ..Default::default()
+        };
+        match tokio::time::timeout(timeout, self.write(request)).await {
+            Err(_) => Err(Error::Proposal(ProposalError::Timeout)),
+            Ok(res) => res,
+        }
+    }
+
+    /// Propose the `data` like `write`, but the proposal is identified by
+    /// `(client_id, sequence)`, so the retry of a proposal which has actually
+    /// been applied returns the original result, i.e. the success or the
+    /// rejection by the state machine, instead of applying twice. The dedup
+    /// table is carried by the snapshots of the group, so it survives the
+    /// restart and the snapshot install. The sequences of a client must be
+    /// increasing, and `client_id` must not be 0.
+    pub async fn propose_idempotent(
+        &self,
+        group_id: GroupId,
+        data: Vec<u8>,
+        client_id: u64,
+        sequence: u64,
+    ) -> Result<CommitToken, Error> {
+        let group_id = group_id.0;
+        if client_id == 0 {
+            return Err(Error::BadParameter(format!("client_id must not be 0")));
+        }
+        let request = AppWriteRequest {
+            group_id,
+            term: 0,
+            data,
+            context: vec![],
+            client_id,
+            sequence,
+            ..Default::default()
+        };
+        self.write(request).await
+    }
+
+    /// Propose the `data` like `propose_timeout`, but the retryable failures,
+    /// e.g. `NotLeader` while the leader is being elected, `ProposalQueueFull`
+    /// and `Dropped`, are retried with the backoff of `policy`, see `RetryPolicy`.
+    /// Returns the last error once the attempts are exhausted or the deadline
+    /// is passed.
+    ///
+    /// The attempts are proposed as one idempotent proposal of a random
+    /// client, so the retry of an attempt which actually has been applied,
+    /// e.g. after it's timeout, isn't applied twice. It requires the
+    /// `proposal_dedup_capacity` to be non-zero, the client is deduplicated
+    /// until it's evicted from the dedup table.
+    pub async fn propose_with_retry(
+        &self,
+        group_id: GroupId,
+        data: Vec<u8>,
+        context: Vec<u8>,
+        policy: RetryPolicy,
+    ) -> Result<CommitToken, Error> {
+        let group_id = group_id.0;
+        if self.config.proposal_dedup_capacity == 0 {
+            return Err(Error::BadParameter(format!(
+                "propose_with_retry requires proposal_dedup_capacity to be non-zero"
+            )));
+        }
+        let deadline = self.clock.now() + policy.deadline;
+        let client_id = rand::random::<u64>().max(1);
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let request = AppWriteRequest {
+                group_id,
+                term: 0,
+                data: data.clone(),
+                context: context.clone(),
+                client_id,
+                sequence: 1,
+                ..Default::default()
+            };
+            let timeout = std::cmp::min(
+                policy.attempt_timeout,
+                deadline.saturating_duration_since(self.clock.now()),
+            );
+            let err = match tokio::time::timeout(timeout, self.write(request)).await {
+                Ok(Ok(token)) => return Ok(token),
+                Ok(Err(err)) => err,
+                Err(_) => Error::Proposal(ProposalError::Timeout),
+            };
+            if attempt >= policy.max_attempts {
+                return Err(err);
+            }
+
+            let backoff = match retry_action(&err, self.config.proposal_forwarding) {
+                RetryAction::GiveUp => return Err(err),
+                RetryAction::Immediately => Duration::ZERO,
+                RetryAction::Backoff => policy.backoff(attempt),
+            };
+            if self.clock.now() + backoff >= deadline {
+                return Err(err);
+            }
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    /// Propose the `data` which takes effect only if the `precondition` holds,
+    /// i.e. the value of `precondition.key` hashed by `value_hash` equals
+    /// `precondition.expected_hash`, which builds the compare-and-set. If
+    /// `precondition.read_index` is set, the key also must not be written after
+    /// it, which catches the value changed and changed back. The
+    /// precondition is verified by the state machine on apply by
+    /// `ApplyEvent::check_precondition`, and the proposal fails with
+    /// `ProposalError::PreconditionFailed` if it doesn't hold.
+    ///
+    /// The entry of the failed proposal is still committed, raft can't
+    /// un-commit it, but its effect is a no-op, the applied index advances past
+    /// it as usual.
+    pub async fn propose_conditional(
+        &self,
+        group_id: GroupId,
+        data: Vec<u8>,
+        context: Vec<u8>,
+        precondition: Precondition,
+    ) -> Result<CommitToken, Error> {
+        let group_id = group_id.0;
+        let request = AppWriteRequest {
+            group_id,
+            term: 0,
+            data,
+            context,
+            precondition: Some(precondition),
+            ..Default::default()
+        };
+        self.write(request).await
+    }
+
+    /// Propose the typed command to the group and wait until it's applied, the
+    /// command is encoded by `Command::encode`, and the state machine decodes
+    /// it by `ApplyEvent::command`.
+    pub async fn propose_command<C: Command>(
+        &self,
+        group_id: GroupId,
+        cmd: &C,
+    ) -> Result<CommitToken, Error> {
+        let group_id = group_id.0;
+        let request = AppWriteRequest {
+            group_id,
+            term: 0,
+            data: cmd.encode()?,
+            context: vec![],
+            ..Default::default()
+        };
+        self.write(request).await
     }
 
-    pub async fn read_index(&self, request: AppReadIndexRequest) -> Result<(), Error> {
+    /// Request the read index of the group, returns it once the leader confirms
+    /// its leadership by a round of heartbeats. The read served by the state
+    /// machine applied up to the read index is linearizable. The request on a
+    /// follower is forwarded to the leader, and it's failed by `NotLeader` if
+    /// the leader is unknown or changes before it's confirmed, or by `Dropped`
+    /// if it isn't confirmed within an election timeout, e.g. the forwarded
+    /// request is lost.
+    pub async fn read_index(&self, request: AppReadIndexRequest) -> Result<u64, Error> {
+        check_not_applying();
         let (tx, rx) = oneshot::channel();
         if let Err(_) = self
             .actor_address
@@ -110,31 +672,864 @@ where
             .await
         {}
 
-        rx.await.unwrap()
+        rx.await.map_err(|_| self.dropped_error())?
+    }
+
+    /// Request the read index of each of the groups led by this node, returns
+    /// once the local replica of every group has applied up to its read index,
+    /// so a transaction spanning the groups reads them at a consistent-enough
+    /// point. The `ReadState` of each group is returned in the order of
+    /// `group_ids`, its `commit_index` is the read index of the group.
+    ///
+    /// The reads are linearizable per group, but it isn't a snapshot across the
+    /// groups, which raft alone can't provide: a write committed to one group
+    /// after its read index is confirmed may be observed, while the write to
+    /// another group committed at the same time isn't. The whole call fails if
+    /// any of the groups isn't led by this node.
+    pub async fn multi_read_index(&self, group_ids: &[GroupId]) -> Result<Vec<ReadState>, Error> {
+        let mut replica_ids = Vec::with_capacity(group_ids.len());
+        for group_id in group_ids.iter().copied() {
+            let status = self
+                .group_status(group_id)
+                .await
+                .ok_or(Error::RaftGroupNotFound(group_id.0))?;
+            if status.role != StateRole::Leader {
+                return Err(Error::Raft(RaftError::NotLeader(
+                    group_id.0,
+                    status.replica_id.0,
+                    status.leader_id.0,
+                )));
+            }
+            replica_ids.push(status.replica_id);
+        }
+
+        // the read indexes are confirmed concurrently, e.g. by the coalesced
+        // heartbeats of one round.
+        let read_indexes = futures::future::try_join_all(group_ids.iter().map(|group_id| {
+            self.read_index(AppReadIndexRequest {
+                group_id: group_id.0,
+                context: None,
+            })
+        }))
+        .await?;
+
+        let mut read_states = Vec::with_capacity(group_ids.len());
+        for (i, group_id) in group_ids.iter().copied().enumerate() {
+            let applied_index = self.wait_applied(group_id.0, read_indexes[i]).await?;
+            read_states.push(ReadState {
+                group_id,
+                replica_id: replica_ids[i],
+                applied_index,
+                commit_index: read_indexes[i],
+            });
+        }
+        Ok(read_states)
+    }
+
+    /// Wait until the applied index of the local replica reaches `index`,
+    /// returns the applied index.
+    async fn wait_applied(&self, group_id: u64, index: u64) -> Result<u64, Error> {
+        let mut applied = self.applied_watch(GroupId(group_id)).await?;
+        loop {
+            let applied_index = *applied.borrow();
+            if applied_index >= index {
+                return Ok(applied_index);
+            }
+            // the watch is closed if the group is removed.
+            if applied.changed().await.is_err() {
+                return Err(Error::RaftGroupNotFound(group_id));
+            }
+        }
+    }
+
+    /// Serve the read of the group by the applied state of the local replica
+    /// without going through the leader, as long as the last contact of the
+    /// replica with the leader (heartbeat or append) is within `max_staleness`.
+    /// The read may observe the state up to `max_staleness` behind the leader.
+    /// `Error::TooStale` is returned if the bound can't be met, e.g. the group
+    /// is quiesced or partitioned, then the read should be served by `read_index`.
+    pub async fn read_follower(
+        &self,
+        group_id: GroupId,
+        max_staleness: Duration,
+    ) -> Result<ReadState, Error> {
+        let group_id = group_id.0;
+        self.query(|tx| QueryGroup::FollowerRead(group_id, max_staleness, tx))
+            .await?
+    }
+
+    /// Returns the known leader of the group and the time it was last confirmed,
+    /// i.e. the last heartbeat or append received from the leader, or on the
+    /// leader the last heartbeat response. It's lighter than `group_status` and
+    /// meant for the routing layer, which decides by the age whether to trust
+    /// the cached leader or refresh it. `None` is returned if the group isn't
+    /// found, the leader is unknown, e.g. during an election, or the node is
+    /// stopped.
+    pub async fn leader_of(&self, group_id: GroupId) -> Option<(ReplicaDesc, Instant)> {
+        let group_id = group_id.0;
+        self.query(|tx| QueryGroup::LeaderOf(group_id, tx))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// Wait until the applied index of the local replica reaches the index of
+    /// `token`, then the read served by the local state machine observes the
+    /// write of the token, which gives the read-your-writes consistency. Returns
+    /// the applied index, or `Error::TokenNotReached` if it isn't reached within
+    /// `timeout`, then the read should be served by the leader.
+    pub async fn read_at_least(
+        &self,
+        group_id: GroupId,
+        token: &CommitToken,
+        timeout: Duration,
+    ) -> Result<u64, Error> {
+        let group_id = group_id.0;
+        if token.group_id() != group_id {
+            return Err(Error::TokenMismatch(group_id, token.group_id()));
+        }
+
+        let mut applied = self.applied_watch(GroupId(group_id)).await?;
+        let wait = async {
+            loop {
+                let index = *applied.borrow();
+                if index >= token.index() {
+                    return Ok(index);
+                }
+                // the watch is closed if the group is removed.
+                if applied.changed().await.is_err() {
+                    return Err(Error::RaftGroupNotFound(group_id));
+                }
+            }
+        };
+        let res = tokio::time::timeout(timeout, wait).await;
+        match res {
+            Err(_) => Err(Error::TokenNotReached(
+                group_id,
+                token.index(),
+                *applied.borrow(),
+            )),
+            Ok(res) => res,
+        }
+    }
+
+    /// Wait until the local replica has applied everything committed at the
+    /// time of the call, returns the commit index captured then. It's a purely
+    /// local apply barrier, e.g. before serving an operation depending on the
+    /// applied config, so unlike `read_index` the leader isn't involved and it
+    /// works on the followers, but the local commit index may lag behind the
+    /// leader. The entries committed during the wait aren't waited for.
+    pub async fn sync_applied(&self, group_id: GroupId) -> Result<u64, Error> {
+        let group_id = group_id.0;
+        let commit_index = self
+            .query(|tx| QueryGroup::CommitIndex(group_id, tx))
+            .await??;
+        self.wait_applied(group_id, commit_index).await?;
+        Ok(commit_index)
+    }
+
+    /// Wait until the `index` is applied by a quorum of the voters of group,
+    /// it must be called on the leader, and `report_applied_index` must be
+    /// enabled so that the followers report their applied index by the
+    /// heartbeat responses. Returns the voters which have applied the index,
+    /// or `Error::QuorumAppliedNotReached` with them if the quorum isn't
+    /// reached within `timeout`.
+    pub async fn wait_quorum_applied(
+        &self,
+        group_id: GroupId,
+        index: u64,
+        timeout: Duration,
+    ) -> Result<Vec<ReplicaId>, Error> {
+        let group_id = group_id.0;
+        let mut quorum_applied = self
+            .query(|tx| QueryGroup::QuorumAppliedWatch(group_id, tx))
+            .await??;
+        let wait = async {
+            loop {
+                if *quorum_applied.borrow() >= index {
+                    return Ok(());
+                }
+                // the watch is closed if the group is removed.
+                if quorum_applied.changed().await.is_err() {
+                    return Err(Error::RaftGroupNotFound(group_id));
+                }
+            }
+        };
+        let res = tokio::time::timeout(timeout, wait).await;
+        let replicas = self
+            .query(|tx| QueryGroup::AppliedReplicas(group_id, index, tx))
+            .await??;
+        match res {
+            Err(_) => Err(Error::QuorumAppliedNotReached(group_id, index, replicas)),
+            Ok(res) => res.map(|_| replicas.into_iter().map(ReplicaId).collect()),
+        }
+    }
+
+    /// Returns the watch of the commit index of the group, which is updated by
+    /// the actor as the commit index advances. The watch is closed after the
+    /// group is removed from this node.
+    pub async fn commit_watch(&self, group_id: GroupId) -> Result<watch::Receiver<u64>, Error> {
+        let group_id = group_id.0;
+        self.query(|tx| QueryGroup::CommitWatch(group_id, tx))
+            .await?
+    }
+
+    /// Returns the watch of the applied index of the group, which is updated by
+    /// the actor after the committed entries are applied. The watch is closed
+    /// after the group is removed from this node.
+    pub async fn applied_watch(&self, group_id: GroupId) -> Result<watch::Receiver<u64>, Error> {
+        let group_id = group_id.0;
+        self.query(|tx| QueryGroup::AppliedWatch(group_id, tx))
+            .await?
+    }
+
+    /// Set the leader priority of the replica of the group, 0 by default. The
+    /// replica doesn't vote for a candidate of lower priority which is as
+    /// up-to-date as itself, and the leader on this node transfers the
+    /// leadership to the caught-up voter of the highest priority if it's
+    /// higher than its own. The priorities are kept in memory, so they should
+    /// be set on every node of the group and again after restart.
+    pub async fn set_leader_priority(
+        &self,
+        group_id: GroupId,
+        replica_id: ReplicaId,
+        priority: u64,
+    ) -> Result<(), Error> {
+        let group_id = group_id.0;
+        let replica_id = replica_id.0;
+        self.query(|tx| QueryGroup::SetLeaderPriority(group_id, replica_id, priority, tx))
+            .await?
+    }
+
+    /// Set the policy of promoting the caught-up learners of the group to
+    /// voters automatically, `None` disables it, see `AutoPromotePolicy`. The
+    /// leader proposes the promotion of one learner at a time. The policy is
+    /// kept in memory, so it should be set on every node of the group and
+    /// again after restart.
+    pub async fn set_auto_promote(
+        &self,
+        group_id: GroupId,
+        policy: Option<AutoPromotePolicy>,
+    ) -> Result<(), Error> {
+        let group_id = group_id.0;
+        self.query(|tx| QueryGroup::SetAutoPromote(group_id, policy, tx))
+            .await?
+    }
+
+    /// Set the per-group overrides of the config of the group on this node,
+    /// see `GroupConfig`. Like `set_auto_promote` it's kept in memory, use
+    /// `propose_group_config` to set it on every replica of the group.
+    pub async fn set_group_config(
+        &self,
+        group_id: GroupId,
+        config: GroupConfig,
+    ) -> Result<(), Error> {
+        let group_id = group_id.0;
+        self.query(|tx| QueryGroup::SetGroupConfig(group_id, config, tx))
+            .await?
+    }
+
+    /// Returns the effective per-group config of the group on this node.
+    pub async fn group_config(&self, group_id: GroupId) -> Result<GroupConfig, Error> {
+        let group_id = group_id.0;
+        self.query(|tx| QueryGroup::GroupConfig(group_id, tx))
+            .await?
+    }
+
+    /// Propose the per-group config as an admin entry of the group, every
+    /// replica sets the config by `set_group_config` once it applies the entry,
+    /// so the replicas switch to it consistently at the same index. Returns
+    /// after the entry is applied on this node, which must be the leader. The
+    /// config is encoded by `encode_config`, all nodes of the group should be
+    /// built with the `serde-config` feature, otherwise the entry is skipped.
+    #[cfg(feature = "serde-config")]
+    pub async fn propose_group_config(
+        &self,
+        group_id: GroupId,
+        config: &GroupConfig,
+    ) -> Result<(), Error> {
+        let group_id = group_id.0;
+        let (kind, payload) = (ADMIN_GROUP_CONFIG, encode_config(config));
+        self.query(|tx| QueryGroup::ProposeAdmin(group_id, kind, payload, tx))
+            .await?
+    }
+
+    /// Returns the watch of the role of the replica of the group on this node,
+    /// which is updated by the actor whenever the role changes, so a component
+    /// which manages one group reacts to the transitions without filtering all
+    /// events. The initial value is the role at subscription. The watch is
+    /// closed after the group is removed from this node.
+    pub async fn role_watch(&self, group_id: GroupId) -> Result<watch::Receiver<StateRole>, Error> {
+        let group_id = group_id.0;
+        self.query(|tx| QueryGroup::RoleWatch(group_id, tx)).await?
+    }
+
+    /// Propose the membership change to the group, the changes are proposed
+    /// as a ConfChangeV2 so that multiple add/remove are applied atomically
+    /// via joint consensus. If `transition` is explicit, the caller should
+    /// propose a request with empty changes to leave the joint consensus,
+    /// otherwise the leader leaves it automatically after it's applied, the
+    /// leader elected in the middle of the change also finishes it.
+    pub async fn propose_conf_change(&self, request: MembershipChangeData) -> Result<(), Error> {
+        check_not_applying();
+        let (tx, rx) = oneshot::channel();
+        if let Err(_) = self
+            .actor_address
+            .membership_change_tx
+            .send((request, tx))
+            .await
+        {}
+
+        rx.await.map_err(|_| self.dropped_error())?
+    }
+
+    /// Allocate a replica id for a new replica of the group, it's greater than
+    /// the ids of the present and removed replicas, the replicas recorded in
+    /// storage and the ids allocated before, so a removed id is never reused.
+    /// It should be called on the leader, whose conf state is the latest. The
+    /// removed replicas and the allocated id are persisted in the group desc,
+    /// so they survive the restart, and the removed replicas are known to
+    /// every replica which applies the removal, so to the next leader as well.
+    pub async fn allocate_replica_id(&self, group_id: GroupId) -> Result<ReplicaId, Error> {
+        let group_id = group_id.0;
+        self.query(|tx| QueryGroup::AllocateReplicaId(group_id, tx))
+            .await?
+            .map(ReplicaId)
+    }
+
+    /// Add the voter `replica_id` on `node_id` to the group, returns
+    /// `DuplicateReplica` if the id is present in the group or was removed
+    /// from it, see `allocate_replica_id`.
+    pub async fn add_replica(
+        &self,
+        group_id: GroupId,
+        node_id: NodeId,
+        replica_id: ReplicaId,
+    ) -> Result<(), Error> {
+        let group_id = group_id.0;
+        let node_id = node_id.0;
+        let replica_id = replica_id.0;
+        self.query(|tx| QueryGroup::CheckNewReplica(group_id, replica_id, tx))
+            .await??;
+        let mut change = MembershipChangeRequest {
+            group_id,
+            node_id,
+            replica_id,
+            ..Default::default()
+        };
+        change.set_change_type(ConfChangeType::AddNode);
+        self.propose_conf_change(MembershipChangeData {
+            group_id,
+            changes: vec![change],
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Demote the voter `replica_id` to learner, the quorum is computed over
+    /// the remaining voters once the change is applied. The leader must
+    /// transfer the leadership before it's demoted, and the last voter can't
+    /// be demoted.
+    ///
+    /// It must be called on the leader of the group.
+    pub async fn demote_to_learner(
+        &self,
+        group_id: GroupId,
+        replica_id: ReplicaId,
+    ) -> Result<(), Error> {
+        let status = self
+            .group_status(group_id)
+            .await
+            .ok_or(Error::RaftGroupNotFound(group_id.0))?;
+        let group_id = group_id.0;
+        let replica_id = replica_id.0;
+        if status.role != StateRole::Leader {
+            return Err(Error::Raft(RaftError::NotLeader(
+                group_id,
+                status.replica_id.0,
+                status.leader_id.0,
+            )));
+        }
+        if !status.voters.contains(&replica_id) {
+            return Err(Error::ReplicaNotVoter(group_id, replica_id));
+        }
+        if status.voters.len() == 1 {
+            return Err(Error::LastVoter(group_id, replica_id));
+        }
+        if status.leader_id.0 == replica_id {
+            return Err(Error::DemoteLeader(group_id, replica_id));
+        }
+
+        // the replica desc is cached again with the node on apply.
+        let node_id = status
+            .progress
+            .iter()
+            .find(|pr| pr.replica_id == replica_id && pr.node_id != NO_NODE)
+            .map(|pr| pr.node_id)
+            .ok_or_else(|| {
+                Error::BadParameter(format!(
+                    "the node of replica ({}) of group ({}) is unknown",
+                    replica_id, group_id
+                ))
+            })?;
+        let mut change = MembershipChangeRequest {
+            group_id,
+            node_id,
+            replica_id,
+            ..Default::default()
+        };
+        change.set_change_type(ConfChangeType::AddLearnerNode);
+        let mut data = MembershipChangeData {
+            group_id,
+            changes: vec![change],
+            ..Default::default()
+        };
+        data.set_transition(ConfChangeTransition::Auto);
+        self.propose_conf_change(data).await
+    }
+
+    /// Replace the voter `old_replica_id` with `new_replica` atomically via
+    /// joint consensus, so the group never has an even membership or reduced
+    /// fault tolerance in the middle of replacement. If `learner_first` is
+    /// true, the new replica is added as learner and catches up before entering
+    /// the joint consensus. The joint consensus is left after the new replica
+    /// has caught up. Returns `TargetLagging` if the new replica doesn't catch
+    /// up within `timeout`, the group is left in the joint consensus if it's
+    /// entered, which is left by proposing the empty change.
+    ///
+    /// It must be called on the leader of the group.
+    pub async fn replace_replica(
+        &self,
+        group_id: GroupId,
+        old_replica_id: ReplicaId,
+        new_replica: ReplicaDesc,
+        learner_first: bool,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let deadline = self.clock.now() + timeout;
+        let status = self
+            .group_status(group_id)
+            .await
+            .ok_or(Error::RaftGroupNotFound(group_id.0))?;
+        let group_id = group_id.0;
+        let old_replica_id = old_replica_id.0;
+        if !status.voters.contains(&old_replica_id) {
+            return Err(Error::ReplicaNotVoter(group_id, old_replica_id));
+        }
+        // the old replica is removed from the node where it's located.
+        let old_replica = status
+            .progress
+            .iter()
+            .find(|pr| pr.replica_id == old_replica_id && pr.node_id != NO_NODE)
+            .map(|pr| ReplicaDesc {
+                node_id: pr.node_id,
+                replica_id: old_replica_id,
+            })
+            .ok_or_else(|| {
+                Error::BadParameter(format!(
+                    "the node of replica ({}) of group ({}) is unknown",
+                    old_replica_id, group_id
+                ))
+            })?;
+
+        let change = |replica: &ReplicaDesc, change_type: ConfChangeType| {
+            let mut change = MembershipChangeRequest {
+                group_id,
+                node_id: replica.node_id,
+                replica_id: replica.replica_id,
+                ..Default::default()
+            };
+            change.set_change_type(change_type);
+            change
+        };
+
+        let mut data = MembershipChangeData {
+            group_id,
+            ..Default::default()
+        };
+        data.set_transition(ConfChangeTransition::Explicit);
+
+        if learner_first {
+            let mut learner_data = data.clone();
+            learner_data.set_transition(ConfChangeTransition::Auto);
+            learner_data.changes = vec![change(&new_replica, ConfChangeType::AddLearnerNode)];
+            self.propose_conf_change(learner_data).await?;
+            self.wait_replica_caught_up(group_id, new_replica.replica_id, deadline)
+                .await?;
+        }
+
+        // enter the joint consensus.
+        let mut enter_data = data.clone();
+        enter_data.changes = vec![
+            change(&new_replica, ConfChangeType::AddNode),
+            change(&old_replica, ConfChangeType::RemoveNode),
+        ];
+        self.propose_conf_change(enter_data).await?;
+
+        // leave the joint consensus after new replica has caught up.
+        self.wait_replica_caught_up(group_id, new_replica.replica_id, deadline)
+            .await?;
+        self.propose_conf_change(data).await
     }
 
-    pub async fn campagin(&self, group_id: u64) {
-        self.actor_address.campagin_tx.send(group_id).await.unwrap()
+    /// Transfer the leadership of the group to `transferee` replica, it must be
+    /// called on the leader. Returns after the transfer is started, the result
+    /// is observed via the `LederElection` event. The lagging transferee is
+    /// brought up to date before the transfer.
+    pub async fn transfer_leader(
+        &self,
+        group_id: GroupId,
+        transferee: ReplicaId,
+    ) -> Result<(), Error> {
+        self.transfer_leader_with_policy(group_id, transferee, TransferLeaderPolicy::CatchUp)
+            .await
     }
 
-    pub async fn initial_raft_group(&self, msg: RaftGroupManagementMessage) -> Result<(), Error> {
+    /// Transfer the leadership like `transfer_leader`, the `policy` decides
+    /// whether the lagging transferee is caught up first, which emits the
+    /// `LeaderTransfer` event, or `Error::TargetLagging` is returned.
+    pub async fn transfer_leader_with_policy(
+        &self,
+        group_id: GroupId,
+        transferee: ReplicaId,
+        policy: TransferLeaderPolicy,
+    ) -> Result<(), Error> {
+        check_not_applying();
+        let group_id = group_id.0;
+        let transferee = transferee.0;
+        let (tx, rx) = oneshot::channel();
+        if let Err(_error) = self
+            .actor_address
+            .transfer_leader_tx
+            .send((group_id, transferee, policy, tx))
+            .await
+        {
+            return Err(self.dropped_error());
+        }
+
+        match rx.await {
+            Err(_error) => Err(self.dropped_error()),
+            Ok(res) => res,
+        }
+    }
+
+    /// Returns the status snapshot of the group, include term, leader, commit
+    /// and applied index, the `ConfState` and the progress of peers if leader.
+    /// `None` is returned if the group is not on this node or the node is
+    /// stopped.
+    pub async fn group_status(&self, group_id: GroupId) -> Option<GroupStatus> {
+        let group_id = group_id.0;
+        self.query(|tx| QueryGroup::Status(group_id, tx))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// Returns all groups hosted by this node and the role of the local replica
+    /// for each group, the quiesced group reports its last known role. It's
+    /// empty if the node is stopped.
+    pub async fn list_groups(&self) -> Vec<(GroupId, ReplicaRole)> {
+        let mut groups = self
+            .query(QueryGroup::ListGroups)
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(group_id, role)| (GroupId(group_id), role))
+            .collect::<Vec<_>>();
+        groups.sort_by_key(|(group_id, _)| *group_id);
+        groups
+    }
+
+    /// Returns every group hosted by this node with its `ConfState`, the leader
+    /// known locally and its state. It's read in one pass of the actor loop,
+    /// so it's a consistent point-in-time view of the node.
+    pub async fn topology(&self) -> Result<Topology, Error> {
+        self.query(QueryGroup::Topology).await
+    }
+
+    /// Returns the current `ConfState` of the group, `None` is returned if the
+    /// group is not on this node or the node is stopped.
+    pub async fn conf_state(&self, group_id: GroupId) -> Option<ConfState> {
+        let group_id = group_id.0;
+        self.query(|tx| QueryGroup::ConfState(group_id, tx))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// Check the conf change against the current conf state of the group
+    /// without proposing it, so that the admin tooling can reject an unsafe
+    /// change up front. It fails if the change can't be applied on the
+    /// current config, removes the last voter, or adds a replica whose node
+    /// isn't carried in the `MembershipChangeData` context or can't be
+    /// resolved by the `NodeResolver` of the extensions. An even number of
+    /// voters after the change is only warned, see `membership_conf_change`
+    /// to build the conf change from the `MembershipChangeData`.
+    pub async fn validate_conf_change(
+        &self,
+        group_id: GroupId,
+        cc: &ConfChangeV2,
+    ) -> Result<(), ConfChangeError> {
+        let conf_state = self
+            .conf_state(group_id)
+            .await
+            .ok_or(ConfChangeError::GroupNotFound(group_id.0))?;
+        let group_id = group_id.0;
+        validate_conf_change(group_id, &conf_state, cc, self.node_resolver.as_ref()).map(|_| ())
+    }
+
+    /// Wait until the replica matches the commit index of the leader, returns
+    /// `TargetLagging` if it doesn't catch up before `deadline`.
+    async fn wait_replica_caught_up(
+        &self,
+        group_id: u64,
+        replica_id: u64,
+        deadline: Instant,
+    ) -> Result<(), Error> {
+        let interval = Duration::from_millis(self.config.tick_interval);
+        loop {
+            let caught_up = self
+                .query(|tx| QueryGroup::ReplicaCaughtUp(group_id, replica_id, tx))
+                .await??;
+            if caught_up {
+                return Ok(());
+            }
+            if self.clock.now() >= deadline {
+                let status = self
+                    .group_status(GroupId(group_id))
+                    .await
+                    .ok_or(Error::RaftGroupNotFound(group_id))?;
+                let matched = status
+                    .progress
+                    .iter()
+                    .find(|pr| pr.replica_id == replica_id)
+                    .map_or(0, |pr| pr.matched);
+                return Err(Error::TargetLagging(
+                    group_id,
+                    replica_id,
+                    matched,
+                    status.commit_index,
+                ));
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    pub async fn campagin(&self, group_id: GroupId) -> Result<(), Error> {
+        check_not_applying();
+        let group_id = group_id.0;
+        self.actor_address
+            .campagin_tx
+            .send(group_id)
+            .await
+            .map_err(|_| Error::Shutdown)
+    }
+
+    /// Initialize the local replica of the group described by `msg`. Returns
+    /// the `InitResult`, whose leader is known right away if the replica is
+    /// the single voter and campaigns on initialization, so the caller doesn't
+    /// need to wait for the leader.
+    pub async fn initial_raft_group(
+        &self,
+        msg: RaftGroupManagementMessage,
+    ) -> Result<InitResult, Error> {
         assert_eq!(
             msg.msg_type(),
             RaftGroupManagementMessageType::MsgInitialGroup
         );
-        let (tx, rx) = oneshot::channel();
-        if let Err(_error) = self.actor_address.manager_group_tx.send((msg, tx)).await {
-            panic!("manager group receiver dropped")
+        match self.initial_raft_groups(vec![msg]).await.pop() {
+            None => panic!("initial group result lost"),
+            Some((_, res)) => res,
+        }
+    }
+
+    /// Initialize the group from the snapshot, e.g. taken earlier as a backup,
+    /// rather than an empty log. `msg` describes the replica like
+    /// `initial_raft_group`, the storage of the replica is seeded with the
+    /// snapshot, so the commit and applied index start at the snapshot index,
+    /// and the membership is the conf state of the snapshot. Returns
+    /// `BadParameter` if the conf state doesn't contain the replica or mismatch
+    /// `msg.replicas`, or the storage of the replica is not empty.
+    pub async fn restore_group(
+        &self,
+        mut msg: RaftGroupManagementMessage,
+        snapshot: Snapshot,
+    ) -> Result<InitResult, Error> {
+        msg.snapshot = Some(snapshot);
+        self.initial_raft_group(msg).await
+    }
+
+    /// Bootstrap the local replica of a new group whose initial voters are
+    /// `replicas`, each node of the group bootstraps its replica with the same
+    /// `replicas`. The storage of the replica is seeded with the bootstrap
+    /// snapshot at index 1 and term 1, which sets up the initial hard state and
+    /// conf state, then the replica is created like `restore_group`. Returns
+    /// `BadParameter` if none of `replicas` is on this node.
+    ///
+    /// The bootstrap is idempotent, retrying it with the same `replicas` after
+    /// the group is created succeeds, but with the different `replicas`
+    /// returns `GroupConflict`.
+    pub async fn bootstrap_group(
+        &self,
+        group_id: GroupId,
+        replicas: Vec<ReplicaDesc>,
+        campaign: bool,
+    ) -> Result<InitResult, Error> {
+        let group_id = group_id.0;
+        let msg = self.bootstrap_message(group_id, replicas, campaign)?;
+        self.initial_raft_group(msg).await
+    }
+
+    /// Returns the message which bootstraps the local replica of the group,
+    /// see `bootstrap_group`.
+    fn bootstrap_message(
+        &self,
+        group_id: u64,
+        replicas: Vec<ReplicaDesc>,
+        campaign: bool,
+    ) -> Result<RaftGroupManagementMessage, Error> {
+        let replica_id = match replicas.iter().find(|replica| replica.node_id == self.node_id) {
+            Some(replica) => replica.replica_id,
+            None => {
+                return Err(Error::BadParameter(format!(
+                    "the replicas {:?} of group {} don't contain the replica on node {}",
+                    replicas, group_id, self.node_id
+                )))
+            }
+        };
+
+        let mut snapshot = Snapshot::default();
+        let metadata = snapshot.mut_metadata();
+        metadata.index = 1;
+        metadata.term = 1;
+        metadata.mut_conf_state().voters =
+            replicas.iter().map(|replica| replica.replica_id).collect();
+
+        let mut msg = RaftGroupManagementMessage::default();
+        msg.set_msg_type(RaftGroupManagementMessageType::MsgInitialGroup);
+        msg.group_id = group_id;
+        msg.replica_id = replica_id;
+        msg.replicas = replicas;
+        msg.campaign = campaign;
+        msg.snapshot = Some(snapshot);
+        Ok(msg)
+    }
+
+    /// Place the `desired_count` replicas of the new group by the
+    /// `ReplicaPlacer` of the extensions, or the default `RoundRobinPlacer`.
+    /// Returns `BadParameter` if the placement doesn't have `desired_count`
+    /// replicas on distinct nodes with distinct replica ids.
+    pub fn place_replicas(
+        &self,
+        group_id: GroupId,
+        desired_count: usize,
+    ) -> Result<Vec<ReplicaDesc>, Error> {
+        let group_id = group_id.0;
+        let replicas = self.replica_placer.place(group_id, desired_count);
+        let nodes = replicas
+            .iter()
+            .map(|replica| replica.node_id)
+            .collect::<HashSet<_>>();
+        let replica_ids = replicas
+            .iter()
+            .map(|replica| replica.replica_id)
+            .collect::<HashSet<_>>();
+        if replicas.len() != desired_count
+            || nodes.len() != desired_count
+            || replica_ids.len() != desired_count
+            || nodes.contains(&NO_NODE)
+            || replica_ids.contains(&0)
+        {
+            return Err(Error::BadParameter(format!(
+                "bad placement {:?} of group {}, {} replicas are desired",
+                replicas, group_id, desired_count
+            )));
+        }
+        Ok(replicas)
+    }
+
+    /// Create many new groups of `desired_count` replicas, whose nodes are
+    /// decided by the `ReplicaPlacer`, see `place_replicas`. The local
+    /// replicas of the groups placed on this node are bootstrapped in one
+    /// round-trip of the actor like `initial_raft_groups`, the groups placed
+    /// elsewhere are not created on this node.
+    ///
+    /// Returns the `(group_id, replicas)` of each group in order, the caller
+    /// bootstraps the replicas on the other nodes by `bootstrap_group` with
+    /// the same replicas.
+    pub async fn bootstrap_groups(
+        &self,
+        group_ids: Vec<GroupId>,
+        desired_count: usize,
+        campaign: bool,
+    ) -> Vec<(GroupId, Result<Vec<ReplicaDesc>, Error>)> {
+        let mut results = Vec::with_capacity(group_ids.len());
+        let mut msgs = vec![];
+        for group_id in group_ids {
+            let res = self.place_replicas(group_id, desired_count);
+            // the message fails to be built if the group isn't placed on this node.
+            if let Ok(Ok(msg)) = res
+                .as_ref()
+                .map(|replicas| self.bootstrap_message(group_id.0, replicas.clone(), campaign))
+            {
+                msgs.push(msg);
+            }
+            results.push((group_id, res));
+        }
+
+        let mut created = self
+            .initial_raft_groups(msgs)
+            .await
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        for (group_id, res) in results.iter_mut() {
+            if let Some(Err(err)) = created.remove(group_id) {
+                *res = Err(err);
+            }
         }
+        results
+    }
 
+    /// Initialize many groups in one round-trip of the actor, which is used to
+    /// bring the groups of the node online quickly on startup or recovery. The
+    /// failure of a group doesn't abort the others, returns the `(group_id,
+    /// result)` of each group in order. The `GroupCreated` events of the groups
+    /// are emitted in one event set. Every group fails with `Shutdown` if the
+    /// node is stopped.
+    pub async fn initial_raft_groups(
+        &self,
+        msgs: Vec<RaftGroupManagementMessage>,
+    ) -> Vec<(GroupId, Result<InitResult, Error>)> {
+        check_not_applying();
+        let group_ids = msgs.iter().map(|msg| msg.group_id).collect::<Vec<_>>();
+        let shutdown = || {
+            group_ids
+                .iter()
+                .map(|group_id| (GroupId(*group_id), Err(Error::Shutdown)))
+                .collect()
+        };
+        let (tx, rx) = oneshot::channel();
+        if let Err(_error) = self.actor_address.initial_groups_tx.send((msgs, tx)).await {
+            return shutdown();
+        }
         match rx.await {
-            Err(_error) => panic!("sender dopped"),
-            Ok(res) => res,
+            Err(_) => shutdown(),
+            Ok(results) => results
+                .into_iter()
+                .map(|(group_id, res)| (GroupId(group_id), res))
+                .collect(),
         }
     }
 
     /// Bootstrap a new raft consensus group.
-    pub async fn bootstrap_raft_group(&self, group_id: u64, replica_id: u64) -> Result<(), Error> {
+    pub async fn bootstrap_raft_group(
+        &self,
+        group_id: GroupId,
+        replica_id: ReplicaId,
+    ) -> Result<(), Error> {
+        check_not_applying();
+        let group_id = group_id.0;
+        let replica_id = replica_id.0;
         let (tx, rx) = oneshot::channel();
         let mut msg = RaftGroupManagementMessage::default();
         msg.group_id = group_id;
@@ -142,12 +1537,467 @@ where
         msg.set_msg_type(RaftGroupManagementMessageType::MsgCreateGroup);
 
         if let Err(_error) = self.actor_address.manager_group_tx.send((msg, tx)).await {
-            panic!("manager group receiver dropped")
+            return Err(Error::Shutdown);
         }
+        rx.await.map_err(|_| Error::Shutdown)?
+    }
 
-        match rx.await {
-            Err(_error) => panic!("sender dopped"),
-            Ok(res) => res,
+    /// Drain the proposals of the group before it's removed or merged. The
+    /// group stops accepting proposals, which fail with `GroupDraining`, then
+    /// it waits until the pending proposals are applied. The proposals still
+    /// pending after `timeout` are failed with `GroupDraining`, like a timed
+    /// out proposal they may be committed later. The membership changes are
+    /// still accepted, so the drained replica can be removed from the group.
+    ///
+    /// Returns the number of the failed proposals, once it returns every
+    /// pending proposal is either handed to the state machine or failed. The
+    /// group stays draining until it's removed, the group which isn't running
+    /// or quiesced, e.g. the poisoned one, fails with `InvalidGroupState`.
+    ///
+    /// The draining state is local to the replica on this node and it isn't
+    /// persisted, the other replicas still accept the proposals, e.g. after
+    /// the leadership moves, and the group runs again after restart. It should
+    /// be drained on the node which proposes to the group, before the group
+    /// is removed on that node.
+    pub async fn drain_group(&self, group_id: GroupId, timeout: Duration) -> Result<usize, Error> {
+        let group_id = group_id.0;
+        let index = self.query(|tx| QueryGroup::Drain(group_id, tx)).await??;
+        let mut applied = self.applied_watch(GroupId(group_id)).await?;
+        let wait = async {
+            loop {
+                if *applied.borrow() >= index {
+                    return Ok(());
+                }
+                // the watch is closed if the group is removed.
+                if applied.changed().await.is_err() {
+                    return Err(Error::RaftGroupNotFound(group_id));
+                }
+            }
+        };
+        if let Ok(res) = tokio::time::timeout(timeout, wait).await {
+            res?;
+        }
+        self.query(|tx| QueryGroup::FailPendingProposals(group_id, tx))
+            .await?
+    }
+
+    /// Stop serving the group on this node without removing it, e.g. while its
+    /// data is being moved. The local replica stops campaigning and rejects
+    /// the proposals and reads with `GroupFrozen`, the leader transfers the
+    /// leadership to the most up-to-date voter. If there is no such voter, the
+    /// frozen leader stops ticking so that the others elect a new leader once
+    /// the election times out. The replica keeps applying the replicated
+    /// entries so it stays caught up, and the membership changes are still
+    /// accepted so the replica can be moved.
+    ///
+    /// Unlike quiescing, which is automatic and transparent, the group stays
+    /// frozen until `unfreeze_group`. The group which isn't running or
+    /// quiesced, e.g. the draining one, fails with `InvalidGroupState`.
+    pub async fn freeze_group(&self, group_id: GroupId) -> Result<(), Error> {
+        let group_id = group_id.0;
+        let transferee = self.query(|tx| QueryGroup::Freeze(group_id, tx)).await??;
+        if let Some(transferee) = transferee {
+            if let Err(err) = self
+                .transfer_leader(GroupId(group_id), ReplicaId(transferee))
+                .await
+            {
+                warn!(
+                    "node {} transfer leader of frozen group {} to replica {} error: {}",
+                    self.node_id, group_id, transferee, err
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Serve the group frozen by `freeze_group` again, it's a no-op if the
+    /// group isn't frozen.
+    pub async fn unfreeze_group(&self, group_id: GroupId) -> Result<(), Error> {
+        let group_id = group_id.0;
+        self.query(|tx| QueryGroup::Unfreeze(group_id, tx)).await?
+    }
+
+    /// Remove the replica of the group from this node and delete its persisted
+    /// state from storage. The messages of the removed group received later are
+    /// dropped, unless the group is created explicitly again.
+    pub async fn remove_group(&self, group_id: GroupId) -> Result<(), Error> {
+        check_not_applying();
+        let group_id = group_id.0;
+        let (tx, rx) = oneshot::channel();
+        let mut msg = RaftGroupManagementMessage::default();
+        msg.group_id = group_id;
+        msg.set_msg_type(RaftGroupManagementMessageType::MsgRemoveGoup);
+
+        if let Err(_error) = self.actor_address.manager_group_tx.send((msg, tx)).await {
+            return Err(Error::Shutdown);
         }
+        let res = rx.await.map_err(|_| Error::Shutdown)?;
+        if res.is_ok() {
+            self.dedup_tables.remove(group_id);
+            self.applied_entries.remove(group_id);
+        }
+        res
+    }
+
+    /// Returns all groups which have a replica on the `node_id`, it is read
+    /// from the node-to-group index maintained by this node. It's empty if the
+    /// node is stopped.
+    pub async fn groups_on_node(&self, node_id: NodeId) -> Vec<GroupId> {
+        let node_id = node_id.0;
+        self.query(|tx| QueryGroup::GroupsOnNode(node_id, tx))
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(GroupId)
+            .collect()
+    }
+
+    /// Set the `SyncPolicy` of the writes of the group by the storage, e.g.
+    /// before the group is created by `bootstrap_group`, so that the metadata
+    /// group is always fsynced while the bulk data groups are synced in
+    /// batches. `SyncPolicy::Never` is refused unless with the test-util
+    /// feature. The policy isn't persisted, it's set again after the node
+    /// restarts or configured by the storage, e.g.
+    /// `SegmentConfig::group_sync_policies`.
+    pub fn set_group_sync_policy(
+        &self,
+        group_id: GroupId,
+        policy: SyncPolicy,
+    ) -> Result<(), Error> {
+        check_sync_policy(policy, cfg!(feature = "test-util"))?;
+        self.storage.set_group_sync_policy(group_id.0, policy)?;
+        Ok(())
     }
+
+    /// Returns the number of quiesced groups on this node, 0 if the node is
+    /// stopped.
+    pub async fn quiesced_group_count(&self) -> usize {
+        self.query(|tx| QueryGroup::QuiescedGroupCount(tx))
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Returns the statistics of the replica metadata cache of this node, which
+    /// are used to size `replica_cache_capacity`. They are zero if the node is
+    /// stopped.
+    pub async fn replica_cache_stats(&self) -> ReplicaCacheStats {
+        self.query(|tx| QueryGroup::ReplicaCacheStats(tx))
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Snapshot the group at its applied index and compact the log up to it,
+    /// regardless of any threshold. The snapshot is taken after the committed
+    /// entries handed to the apply actor are applied, it carries the conf state,
+    /// the dedup table and the state of `StateMachine::snapshot` at the index,
+    /// which is restored by `StateMachine::restore` on the replica installing
+    /// it. Without the `StateMachine` the state of the application isn't
+    /// carried, the application consuming the `Apply` events must catch up the
+    /// replica installing the snapshot by itself. It's used to shrink a
+    /// bloated log on demand or to take a fresh snapshot before
+    /// decommissioning. If the applied index doesn't advance since the latest
+    /// snapshot, nothing is done and the metadata of the latest snapshot is
+    /// returned.
+    pub async fn trigger_snapshot(&self, group_id: GroupId) -> Result<SnapshotMetadata, Error> {
+        let group_id = group_id.0;
+        self.query(|tx| QueryGroup::TriggerSnapshot(group_id, tx))
+            .await?
+    }
+
+    /// Export the snapshot of the applied state of the group for the external
+    /// backup, returns its metadata and data. It's taken like
+    /// `trigger_snapshot` at the applied index of the local replica, which is
+    /// the leader or any up-to-date replica, so it's always fresh. Unlike
+    /// `trigger_snapshot`, the log isn't compacted and the snapshot isn't
+    /// saved, so the replication isn't affected. The snapshot can be restored
+    /// into a fresh group by `restore_group`.
+    pub async fn export_snapshot(
+        &self,
+        group_id: GroupId,
+    ) -> Result<(SnapshotMetadata, Vec<u8>), Error> {
+        let group_id = group_id.0;
+        let snapshot = self
+            .query(|tx| QueryGroup::ExportSnapshot(group_id, tx))
+            .await??;
+        Ok((snapshot.metadata.unwrap_or_default(), snapshot.data))
+    }
+
+    /// Returns the committed entries of the group in `[from_index, to_index)`
+    /// read from the log in the storage rather than the state machine, e.g. for
+    /// the external consumer replicating the log, the data of the entries is
+    /// framed, see `entry::decode_entry`. The range is truncated to the
+    /// commit index of the local replica, and the entries are limited by
+    /// `max_read_committed_size` bytes but at least one entry is returned, the
+    /// consumer continues from the index after the last returned entry. Returns
+    /// `LogCompacted` with the snapshot index if the log from `from_index` is
+    /// compacted, then the consumer catches up by `export_snapshot` first.
+    ///
+    /// The entries are returned as is, including the internal ones, so the
+    /// indexes are contiguous and the consumer skips what it doesn't replicate:
+    /// the conf change entries, the empty no-op entry of the new leader and the
+    /// admin entries (`entry::EntryPayload::Admin`, e.g. `ADMIN_GROUP_CONFIG`).
+    /// The leadership epoch entry of the `LeaderHook` is a data entry, as it's
+    /// applied to the `StateMachine`.
+    pub async fn read_committed(
+        &self,
+        group_id: GroupId,
+        from_index: u64,
+        to_index: u64,
+    ) -> Result<Vec<Entry>, Error> {
+        let group_id = group_id.0;
+        self.query(|tx| QueryGroup::ReadCommitted(group_id, from_index, to_index, tx))
+            .await?
+    }
+
+    /// Wipe and re-sync the replica of the group, e.g. it's suspected to be
+    /// corrupt. It's issued on the leader, which asks the replica to request
+    /// a fresh snapshot at least at the commit index, the replica ignores the
+    /// appends until the snapshot is installed, which discards its log and
+    /// replaces its state, i.e. the state of the `StateMachine` is restored
+    /// from the snapshot, then it catches up as usual. Returns `NotLeader` if
+    /// the group isn't led by this node, `BadParameter` if the replica is the
+    /// leader itself and `UnsafeReset` if the other up-to-date voters don't
+    /// form a quorum.
+    pub async fn reset_replica(
+        &self,
+        group_id: GroupId,
+        replica_id: ReplicaId,
+    ) -> Result<(), Error> {
+        let group_id = group_id.0;
+        let replica_id = replica_id.0;
+        self.query(|tx| QueryGroup::ResetReplica(group_id, replica_id, tx))
+            .await?
+    }
+
+    /// Returns the liveness summary of the node. The actor is considered wedged
+    /// if it has exited, hasn't ticked or doesn't respond within the max election
+    /// timeout, then the returned health is not running.
+    pub async fn health(&self) -> NodeHealth {
+        let mut health = self.actor_health().await;
+        health.mailboxes = self.mailbox_stats();
+        health
+    }
+
+    /// Returns the depth of the mailboxes of the actor, the actor falls behind
+    /// if they are near the capacity, see `MailboxDepth::is_near_full`.
+    pub fn mailbox_stats(&self) -> MailboxStats {
+        MailboxStats {
+            writes: MailboxDepth::of(&self.actor_address.write_propose_tx),
+            read_indexes: MailboxDepth::of(&self.actor_address.read_index_propose_tx),
+            membership_changes: MailboxDepth::of(&self.actor_address.membership_change_tx),
+            messages: MailboxDepth::of(&self.actor_address.raft_message_tx),
+        }
+    }
+
+    async fn actor_health(&self) -> NodeHealth {
+        check_not_applying();
+        let last_tick_elapsed = self
+            .clock
+            .elapsed(*self.actor_address.last_tick.lock().unwrap());
+        if self.actor_join_handle.is_finished() {
+            return NodeHealth::not_running(last_tick_elapsed);
+        }
+
+        let (_, max_election_tick) = self.config.election_tick_range();
+        let wedge_timeout =
+            Duration::from_millis(self.config.tick_interval * max_election_tick as u64);
+        #[cfg(feature = "test-util")]
+        let check_tick = !self.config.manual_tick;
+        #[cfg(not(feature = "test-util"))]
+        let check_tick = true;
+        if check_tick && last_tick_elapsed > wedge_timeout {
+            return NodeHealth::not_running(last_tick_elapsed);
+        }
+
+        let (tx, rx) = oneshot::channel();
+        if let Err(_) = self
+            .actor_address
+            .query_group_tx
+            .send(QueryGroup::Health(tx))
+            .await
+        {
+            return NodeHealth::not_running(last_tick_elapsed);
+        }
+
+        match tokio::time::timeout(wedge_timeout, rx).await {
+            Ok(Ok(health)) => health,
+            _ => NodeHealth::not_running(last_tick_elapsed),
+        }
+    }
+
+    /// Transfer the leadership of groups led by this node to the most
+    /// up-to-date peers and then stop the actors, so that the groups don't
+    /// wait for the election timeout to elect new leaders. It waits at most
+    /// `shutdown_transfer_timeout` ms for the transfers, the groups without
+    /// a suitable transferee or not transferred in time elect normally.
+    pub async fn prepare_shutdown(&self) {
+        let transferees = self
+            .query(QueryGroup::ShutdownTransferees)
+            .await
+            .unwrap_or_default();
+        let mut pending = HashSet::new();
+        for (group_id, transferee) in transferees {
+            match self
+                .transfer_leader_with_policy(
+                    GroupId(group_id),
+                    ReplicaId(transferee),
+                    TransferLeaderPolicy::CatchUp,
+                )
+                .await
+            {
+                Ok(_) => {
+                    pending.insert(group_id);
+                }
+                Err(err) => warn!(
+                    "node {} transfer leader of group {} to replica {} before shutdown error: {}",
+                    self.node_id, group_id, transferee, err
+                ),
+            }
+        }
+
+        let timeout = Duration::from_millis(self.config.shutdown_transfer_timeout);
+        let deadline = self.clock.now() + timeout;
+        while !pending.is_empty() && self.clock.now() < deadline {
+            let leaders = self
+                .list_groups()
+                .await
+                .into_iter()
+                .filter(|(_, role)| *role == ReplicaRole::Leader)
+                .map(|(group_id, _)| group_id)
+                .collect::<HashSet<_>>();
+            pending.retain(|group_id| leaders.contains(group_id));
+            if !pending.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+        if !pending.is_empty() {
+            warn!(
+                "node {} shutdown with {} groups not transferred",
+                self.node_id,
+                pending.len()
+            );
+        }
+
+        let _ = self.stop_tx.send(true);
+    }
+
+    /// Advance one tick pass of groups and wait until the ready of groups
+    /// are handled, it is only used in manual tick mode. The pass ticks the
+    /// groups of one slot if `tick_stagger_slots` > 1. It's a no-op if the
+    /// node is stopped.
+    #[cfg(feature = "test-util")]
+    pub async fn tick(&self) {
+        check_not_applying();
+        let (tx, rx) = oneshot::channel();
+        if self.actor_address.tick_tx.send(tx).await.is_ok() {
+            let _ = rx.await;
+        }
+    }
+
+    /// Wait until the campaigns and the raft messages received by this node are
+    /// stepped and the ready of groups are handled, so the messages sent in
+    /// response have been sent when it returns. It's used to drive `SimNetwork`
+    /// in lockstep. It's a no-op if the node is stopped.
+    #[cfg(feature = "test-util")]
+    pub async fn flush(&self) {
+        check_not_applying();
+        let (tx, rx) = oneshot::channel();
+        if self.actor_address.flush_tx.send(tx).await.is_ok() {
+            let _ = rx.await;
+        }
+    }
+
+    /// Returns the number of ticks, steps and ready cycles processed by the
+    /// group, `None` is returned if the group is not on this node or the node
+    /// is stopped.
+    #[cfg(feature = "test-util")]
+    pub async fn group_counters(&self, group_id: GroupId) -> Option<GroupCounters> {
+        let group_id = group_id.0;
+        self.query(|tx| QueryGroup::Counters(group_id, tx))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// Panic the actor of the node, e.g. to test `restart_actor_on_panic`.
+    #[cfg(feature = "test-util")]
+    pub async fn panic_actor(&self) {
+        let _ = self
+            .actor_address
+            .query_group_tx
+            .send(QueryGroup::Panic)
+            .await;
+    }
+
+    /// Returns the error of the operation whose response is dropped by the
+    /// actors, it's `Shutdown` if the node is stopped, which drops the pending
+    /// operations, otherwise the operation is dropped.
+    fn dropped_error(&self) -> Error {
+        if *self.stop_tx.borrow() {
+            Error::Shutdown
+        } else {
+            Error::Proposal(ProposalError::Dropped)
+        }
+    }
+
+    /// Send the query to the actor and wait for the result, returns
+    /// `Shutdown` if the actor is stopped.
+    async fn query<R, F>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(oneshot::Sender<R>) -> QueryGroup,
+    {
+        check_not_applying();
+        let (tx, rx) = oneshot::channel();
+        if let Err(_error) = self.actor_address.query_group_tx.send(f(tx)).await {
+            return Err(Error::Shutdown);
+        }
+        rx.await.map_err(|_| Error::Shutdown)
+    }
+}
+
+/// The call of `StateMachine::apply` back into `MultiRaft` deadlocks against
+/// the actors, it panics instead, see `ApplyHandle`.
+fn check_not_applying() {
+    assert!(
+        !state_machine::is_applying(),
+        "MultiRaft is called by StateMachine::apply, enqueue the write by ApplyHandle instead"
+    );
+}
+
+/// The write whose data and context exceed `max_entry_size` is rejected before
+/// it's proposed, 0 means unlimited.
+pub(crate) fn check_entry_size(
+    request: &AppWriteRequest,
+    max_entry_size: u64,
+) -> Result<(), Error> {
+    let size = (request.data.len() + request.context.len()) as u64;
+    if max_entry_size != 0 && size > max_entry_size {
+        return Err(Error::Proposal(ProposalError::EntryTooLarge(
+            size,
+            max_entry_size,
+        )));
+    }
+    Ok(())
+}
+
+/// Never fsync breaks the durability of raft, it's refused unless `allow_never`,
+/// i.e. with the test-util feature.
+fn check_sync_policy(policy: SyncPolicy, allow_never: bool) -> Result<(), Error> {
+    if policy == SyncPolicy::Never && !allow_never {
+        return Err(Error::BadParameter(
+            "SyncPolicy::Never of storage is only allowed with the test-util feature".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_check_sync_policy() {
+    assert!(check_sync_policy(SyncPolicy::Always, false).is_ok());
+    assert!(check_sync_policy(SyncPolicy::Periodic(Duration::from_secs(1)), false).is_ok());
+    assert!(matches!(
+        check_sync_policy(SyncPolicy::Never, false),
+        Err(Error::BadParameter(_))
+    ));
+    assert!(check_sync_policy(SyncPolicy::Never, true).is_ok());
 }
@@ -1,19 +1,29 @@
+use std::any::Any;
 use std::collections::hash_map::HashMap;
 use std::collections::HashSet;
 use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::hash::Hash;
 use std::marker::PhantomData;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::time::Duration;
+use std::time::Instant;
 
+use futures::FutureExt;
 use prost::Message as ProstMessage;
 use raft::LightReady;
 use raft::RawNode;
 use raft::Ready;
+use raft::CAMPAIGN_TRANSFER;
 use smallvec::SmallVec;
 use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::unbounded_channel;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::oneshot;
 use tokio::sync::watch;
 use tokio::task::JoinHandle;
@@ -35,69 +45,352 @@ use super::apply::ApplyTask;
 use super::apply::ApplyTaskRequest;
 use super::apply::ApplyTaskResponse;
 use super::apply::MembershipChangeResult;
+use super::apply::SnapshotTarget;
+use super::apply::SnapshotTask;
+use super::clock::Clock;
+use super::config::GroupConfig;
 use super::config::MultiRaftConfig;
+use super::config::MAX_SIZE_PER_MSG;
+use super::conf_state::reconcile_conf_state;
+use super::correlation::CorrelationIds;
+use super::dropped::DropReason;
+use super::dropped::DroppedMessage;
+use super::dropped::DroppedMessages;
 use super::error::Error;
 use super::error::ProposalError;
 use super::event::Event;
+use super::event::GroupCreatedEvent;
+use super::event::GroupFailedEvent;
+use super::event::GroupRecoveredEvent;
+use super::event::GroupStateChangedEvent;
+use super::event::GroupStorageErrorEvent;
+use super::event::GroupUnhealthyEvent;
 use super::event::LeaderElectionEvent;
+use super::event::LeaderEpochEvent;
+use super::event::LeaderTransferEvent;
+use super::event::LogPinnedEvent;
+use super::forward;
+use super::forward::ProposalForwards;
+use super::multiraft::check_entry_size;
 use super::multiraft::NO_GORUP;
 use super::multiraft::NO_NODE;
 use super::node::NodeManager;
+use super::pending::PendingMessages;
 use super::proposal::GroupProposalQueue;
 use super::proposal::Proposal;
 use super::proposal::ProposalQueueManager;
 use super::proposal::ReadIndexProposal;
+use super::balancer::GroupLeadership;
+use super::health::MailboxStats;
+use super::health::NodeHealth;
+use super::ids::GroupId;
+use super::ids::NodeId;
+use super::ids::ReplicaId;
+use super::latency::NodeLatencies;
+use super::leader_hook::LeaderHook;
+use super::leaders::LocalLeaders;
+use super::log_size::LogSize;
+use super::raft_group::GroupCounters;
+use super::raft_group::GroupState;
+use super::raft_group::GroupStatus;
+use super::raft_group::InitResult;
+use super::multiraft::MultiRaftExtensions;
+use super::ready_hook::ReadyHook;
+use super::ready_hook::ReadyStage;
+use super::ready_worker::ReadyWorkers;
+use super::ready_worker::ReadyWrite;
+use super::raft_group::startup_jitter_ticks;
+use super::raft_group::AutoPromotePolicy;
 use super::raft_group::RaftGroup;
+use super::raft_group::ReadState;
+use super::raft_group::ReplicaRole;
+use super::raft_group::TransferLeaderPolicy;
+use super::raft_group::UnpersistedReady;
+use super::snapshot;
+use super::snapshot::IncomingSnapshots;
+use super::snapshot::OutgoingSnapshots;
+use super::topology::GroupTopology;
+use super::topology::Topology;
 use super::replica_cache::ReplicaCache;
+use super::replica_cache::ReplicaCacheStats;
 use super::transport;
 use super::transport::MessageInterface;
+use super::transport::Outbox;
 use super::transport::Transport;
 
 use crate::proto::transmute_entries;
 use crate::proto::transmute_raft_entries;
-use crate::proto::transmute_raft_entries_ref;
 use crate::proto::transmute_raft_hard_state;
+use crate::proto::transmute_raft_conf_state;
 use crate::proto::transmute_raft_messages;
 use crate::proto::transmute_raft_snapshot;
 use crate::proto::AppReadIndexRequest;
 use crate::proto::AppReadIndexResponse;
 use crate::proto::AppWriteRequest;
 use crate::proto::AppWriteResponse;
+use crate::proto::CoalescedHeartbeat;
 use crate::proto::ConfState;
 use crate::proto::Entry;
+use crate::proto::ForwardedProposal;
+use crate::proto::ForwardedProposalResponse;
+use crate::proto::HardState;
+use crate::proto::MembershipChangeData;
 use crate::proto::Message;
 use crate::proto::MessageType;
+use crate::proto::RaftGroupDesc;
 use crate::proto::RaftGroupManagementMessage;
 use crate::proto::RaftGroupManagementMessageType;
 use crate::proto::RaftMessage;
 use crate::proto::ReplicaDesc;
+use crate::proto::ResetReplica;
 use crate::proto::Snapshot;
+use crate::proto::SnapshotChunkAck;
+use crate::proto::SnapshotMetadata;
 use crate::storage::transmute_message;
 
 use crate::storage::MultiRaftStorage;
+use crate::storage::RaftState;
 use crate::storage::RaftStorage;
 use crate::storage::RaftStorageImpl;
 use crate::storage::StorageError;
+use crate::storage::WriteBatch;
 // use crate::proto::Error;
 
+/// The backoff of the retried write of the ready is capped at 64 times of
+/// `storage_write_retry_backoff`.
+const MAX_WRITE_BACKOFF_SHIFT: usize = 6;
+
 #[derive(Default, Debug)]
 pub struct GroupWriteRequest {
     replica_id: u64,
     ready: Option<Ready>,
     light_ready: Option<LightReady>,
+    // false if the ready failed to persist, then the messages depend on
+    // the persistence must not be sent.
+    persisted: bool,
+    // the index of the last entry of the retried ready, see `UnpersistedReady`.
+    unpersisted_last_index: Option<u64>,
+    // true if the ready is written with `defer_sync` and waits for the sync
+    // of the batch, the `ready` is kept until then.
+    unsynced: bool,
+}
+
+/// QueryGroup is used to read the state tracked by the MultiRaftActor, the
+/// query is handled in the actor loop and the result is sent via tx.
+pub enum QueryGroup {
+    /// Query all groups which have a replica on the node.
+    GroupsOnNode(u64, oneshot::Sender<Vec<u64>>),
+    /// Query the number of quiesced groups on this node.
+    QuiescedGroupCount(oneshot::Sender<usize>),
+    /// Query the current `ConfState` of the group.
+    ConfState(u64, oneshot::Sender<Option<ConfState>>),
+    /// Query whether the replica has caught up the commit index of the
+    /// group, it must be queried on the leader.
+    ReplicaCaughtUp(u64, u64, oneshot::Sender<Result<bool, Error>>),
+    /// Query the status snapshot of the group.
+    Status(u64, oneshot::Sender<Option<GroupStatus>>),
+    /// Query all groups on this node with the role of local replica.
+    ListGroups(oneshot::Sender<Vec<(u64, ReplicaRole)>>),
+    /// Query the leader and voters of all groups on this node, which is
+    /// used by the `LeaderBalancer`.
+    Leaderships(oneshot::Sender<Vec<GroupLeadership>>),
+    /// Query the read state of the group served by the local replica if the
+    /// last contact with the leader is within the staleness.
+    FollowerRead(u64, Duration, oneshot::Sender<Result<ReadState, Error>>),
+    /// Query the known leader of the group and the time it was last confirmed.
+    LeaderOf(u64, oneshot::Sender<Option<(ReplicaDesc, Instant)>>),
+    /// Query the health of groups on this node.
+    Health(oneshot::Sender<NodeHealth>),
+    /// Query the commit index of the group.
+    CommitIndex(u64, oneshot::Sender<Result<u64, Error>>),
+    /// Watch the commit index of the group.
+    CommitWatch(u64, oneshot::Sender<Result<watch::Receiver<u64>, Error>>),
+    /// Watch the applied index of the group.
+    AppliedWatch(u64, oneshot::Sender<Result<watch::Receiver<u64>, Error>>),
+    /// Set the leader priority of the replica of the group, the tuple is
+    /// (group_id, replica_id, priority).
+    SetLeaderPriority(u64, u64, u64, oneshot::Sender<Result<(), Error>>),
+    /// Set the policy of promoting the caught-up learners of the group.
+    SetAutoPromote(u64, Option<AutoPromotePolicy>, oneshot::Sender<Result<(), Error>>),
+    /// Set the per-group overrides of the config of the group.
+    SetGroupConfig(u64, GroupConfig, oneshot::Sender<Result<(), Error>>),
+    /// Query the effective per-group config of the group.
+    GroupConfig(u64, oneshot::Sender<Result<GroupConfig, Error>>),
+    /// Propose the internal admin command of the group, the tuple is
+    /// (group_id, kind, payload), the result is responded after it's applied.
+    #[cfg(feature = "serde-config")]
+    ProposeAdmin(u64, u8, Vec<u8>, oneshot::Sender<Result<(), Error>>),
+    /// Watch the role of the replica of the group.
+    RoleWatch(u64, oneshot::Sender<Result<watch::Receiver<raft::StateRole>, Error>>),
+    /// Watch the index applied by a quorum of the group, it must be queried
+    /// on the leader.
+    QuorumAppliedWatch(u64, oneshot::Sender<Result<watch::Receiver<u64>, Error>>),
+    /// Query the voters of the group which have applied the index.
+    AppliedReplicas(u64, u64, oneshot::Sender<Result<Vec<u64>, Error>>),
+    /// Query the `(group_id, transferee)` of groups led by this node, the
+    /// leadership is transferred to the transferee before shutdown.
+    ShutdownTransferees(oneshot::Sender<Vec<(u64, u64)>>),
+    /// Query the groups hosted by this node at a point in time.
+    Topology(oneshot::Sender<Topology>),
+    /// Query the statistics of the replica cache.
+    ReplicaCacheStats(oneshot::Sender<ReplicaCacheStats>),
+    /// Allocate a new replica id of the group, see `MultiRaft::allocate_replica_id`.
+    AllocateReplicaId(u64, oneshot::Sender<Result<u64, Error>>),
+    /// Check the replica id can be added to the group, the tuple is
+    /// (group_id, replica_id).
+    CheckNewReplica(u64, u64, oneshot::Sender<Result<(), Error>>),
+    /// Stop accepting the proposals of the group, returns the index applied
+    /// after the pending proposals are resolved, see `MultiRaft::drain_group`.
+    Drain(u64, oneshot::Sender<Result<u64, Error>>),
+    /// Freeze the group, returns the transferee of the leadership if the
+    /// replica is leader, see `MultiRaft::freeze_group`.
+    Freeze(u64, oneshot::Sender<Result<Option<u64>, Error>>),
+    /// Unfreeze the group, see `MultiRaft::unfreeze_group`.
+    Unfreeze(u64, oneshot::Sender<Result<(), Error>>),
+    /// Fail the pending proposals of the drained group, returns the number
+    /// of them.
+    FailPendingProposals(u64, oneshot::Sender<Result<usize, Error>>),
+    /// Snapshot the group at the applied index and compact the log, see
+    /// `MultiRaft::trigger_snapshot`.
+    TriggerSnapshot(u64, oneshot::Sender<Result<SnapshotMetadata, Error>>),
+    /// Build the snapshot of the group at the applied index without
+    /// compacting the log, see `MultiRaft::export_snapshot`.
+    ExportSnapshot(u64, oneshot::Sender<Result<Snapshot, Error>>),
+    /// Read the committed entries of the group from the storage, the tuple is
+    /// (group_id, from_index, to_index), see `MultiRaft::read_committed`.
+    ReadCommitted(u64, u64, u64, oneshot::Sender<Result<Vec<Entry>, Error>>),
+    /// Reset the replica of the group by a fresh snapshot, the tuple is
+    /// (group_id, replica_id), see `MultiRaft::reset_replica`.
+    ResetReplica(u64, u64, oneshot::Sender<Result<(), Error>>),
+    /// Query the tick, step and ready counters of the group.
+    #[cfg(feature = "test-util")]
+    Counters(u64, oneshot::Sender<Option<GroupCounters>>),
+    /// Panic the actor, see `MultiRaft::panic_actor`.
+    #[cfg(feature = "test-util")]
+    Panic,
 }
 
 /// MultiRaftAddress is used to communicate with MultiRaftActor
 #[derive(Clone)]
 pub struct MultiRaftActorAddress {
     pub write_propose_tx: Sender<(AppWriteRequest, oneshot::Sender<Result<(), Error>>)>,
-    pub read_index_propose_tx: Sender<(AppReadIndexRequest, oneshot::Sender<Result<(), Error>>)>,
+    pub read_index_propose_tx: Sender<(AppReadIndexRequest, oneshot::Sender<Result<u64, Error>>)>,
+    pub membership_change_tx:
+        Sender<(MembershipChangeData, oneshot::Sender<Result<(), Error>>)>,
     pub campagin_tx: Sender<u64>,
     pub raft_message_tx: Sender<RaftMessage>,
     pub manager_group_tx: Sender<(
         RaftGroupManagementMessage,
         oneshot::Sender<Result<(), Error>>,
     )>,
+    pub initial_groups_tx: Sender<(
+        Vec<RaftGroupManagementMessage>,
+        oneshot::Sender<Vec<(u64, Result<InitResult, Error>)>>,
+    )>,
+    pub query_group_tx: Sender<QueryGroup>,
+    pub tick_tx: Sender<oneshot::Sender<()>>,
+    pub flush_tx: Sender<oneshot::Sender<()>>,
+    pub transfer_leader_tx: Sender<(
+        u64,
+        u64,
+        TransferLeaderPolicy,
+        oneshot::Sender<Result<(), Error>>,
+    )>,
+    // the time of the last tick of groups, which is used to detect the wedged actor.
+    pub last_tick: Arc<Mutex<Instant>>,
+}
+
+/// The receivers of the mailboxes of the actor, whose senders are in the
+/// `MultiRaftActorAddress`.
+pub struct ActorMailboxes {
+    write_propose_rx: Receiver<(AppWriteRequest, oneshot::Sender<Result<(), Error>>)>,
+    read_index_propose_rx: Receiver<(AppReadIndexRequest, oneshot::Sender<Result<u64, Error>>)>,
+    membership_change_rx: Receiver<(MembershipChangeData, oneshot::Sender<Result<(), Error>>)>,
+    campagin_rx: Receiver<u64>,
+    raft_message_rx: Receiver<RaftMessage>,
+    manager_group_rx: Receiver<(
+        RaftGroupManagementMessage,
+        oneshot::Sender<Result<(), Error>>,
+    )>,
+    initial_groups_rx: Receiver<(
+        Vec<RaftGroupManagementMessage>,
+        oneshot::Sender<Vec<(u64, Result<InitResult, Error>)>>,
+    )>,
+    query_group_rx: Receiver<QueryGroup>,
+    tick_rx: Receiver<oneshot::Sender<()>>,
+    flush_rx: Receiver<oneshot::Sender<()>>,
+    transfer_leader_rx: Receiver<(
+        u64,
+        u64,
+        TransferLeaderPolicy,
+        oneshot::Sender<Result<(), Error>>,
+    )>,
+    last_tick: Arc<Mutex<Instant>>,
+    apply_actor_address: ApplyActorAddress,
+}
+
+impl ActorMailboxes {
+    /// Create the mailboxes of the actor and the address which sends to them.
+    pub fn new(
+        cfg: &MultiRaftConfig,
+        apply_actor_address: ApplyActorAddress,
+        clock: &dyn Clock,
+    ) -> (Self, MultiRaftActorAddress) {
+        let (raft_message_tx, raft_message_rx) = channel(cfg.message_mailbox_capacity);
+        let (campagin_tx, campagin_rx) = channel(1);
+        let (manager_group_tx, manager_group_rx) = channel(1);
+        let (initial_groups_tx, initial_groups_rx) = channel(1);
+        let (query_group_tx, query_group_rx) = channel(1);
+        let (tick_tx, tick_rx) = channel(1);
+        let (flush_tx, flush_rx) = channel(1);
+        let last_tick = Arc::new(Mutex::new(clock.now()));
+        let (transfer_leader_tx, transfer_leader_rx) = channel(1);
+
+        // create write propose channel
+        let (write_propose_tx, write_propose_rx) = channel(cfg.proposal_mailbox_capacity);
+        let (read_index_propose_tx, read_index_propose_rx) = channel(cfg.proposal_mailbox_capacity);
+        let (membership_change_tx, membership_change_rx) = channel(cfg.proposal_mailbox_capacity);
+
+        let mailboxes = ActorMailboxes {
+            write_propose_rx,
+            read_index_propose_rx,
+            membership_change_rx,
+            campagin_rx,
+            raft_message_rx,
+            manager_group_rx,
+            initial_groups_rx,
+            query_group_rx,
+            tick_rx,
+            flush_rx,
+            transfer_leader_rx,
+            last_tick: last_tick.clone(),
+            apply_actor_address,
+        };
+        let address = MultiRaftActorAddress {
+            campagin_tx,
+            raft_message_tx,
+            manager_group_tx,
+            initial_groups_tx,
+            write_propose_tx,
+            read_index_propose_tx,
+            membership_change_tx,
+            query_group_tx,
+            tick_tx,
+            flush_tx,
+            transfer_leader_tx,
+            last_tick,
+        };
+        (mailboxes, address)
+    }
+}
+
+/// ActorRemains is handed back by the actor when it's dropped, e.g. it dies of
+/// a panic, so the actor restarted by the `ActorWatchdog` serves the same
+/// mailboxes and recreates the replicas from the storage.
+pub struct ActorRemains {
+    pub mailboxes: ActorMailboxes,
+    // the (group_id, replica_id) of the replicas hosted by the actor.
+    pub replicas: Vec<(u64, u64)>,
 }
 
 pub struct MultiRaftActor<MI, T, RS, MRS>
@@ -112,11 +405,83 @@ where
     //  nodes: HashMap<u64, Node>,
     node_manager: NodeManager,
     groups: HashMap<u64, RaftGroup<RS>>,
+    // the groups removed from this node, messages of them are dropped.
+    removed_groups: HashSet<u64>,
+    outgoing_snapshots: OutgoingSnapshots,
+    incoming_snapshots: IncomingSnapshots,
+    // if true, the appends and votes are suppressed while the replica
+    // installs a snapshot.
+    snapshot_install_suppression: bool,
+    // the ticks which the replicas stick to the newly elected leader.
+    min_leader_tenure: usize,
+    // if true, the write proposed to the follower is forwarded to the leader.
+    proposal_forwarding: bool,
+    // the replicas of the observer node never campaign.
+    observer: bool,
+    proposal_forwards: ProposalForwards,
+    // the responses of the proposals forwarded from other nodes.
+    forward_response_tx: UnboundedSender<RaftMessage>,
+    forward_response_rx: UnboundedReceiver<RaftMessage>,
+    // the messages of the groups which aren't created yet.
+    pending_messages: PendingMessages,
+    // the interval of tick passes, each pass ticks the groups of one slot.
     tick_interval: Duration,
-    election_tick: usize,
+    tick_slots: u64,
+    tick_passes: u64,
+    last_tick_groups: usize,
+    last_tick_cost: Duration,
+    // the inclusive range of the randomized election timeout ticks.
+    election_tick_range: (usize, usize),
+    startup_election_jitter: usize,
+    election_extension_window: usize,
+    max_election_extension: usize,
+    // the group is reported unhealthy beyond the ticks, 0 disables it.
+    unhealthy_ticks: usize,
+    ready_groups_budget: usize,
+    // the messages of the readies buffered in the iteration of the loop.
+    outbox: Outbox,
+    batch_ready_messages: bool,
+    max_pending_proposals: usize,
+    max_client_pending_proposals: usize,
+    // the max bytes of committed entries in a ready of group.
+    max_committed_size_per_ready: u64,
+    // the max bytes of the entries returned by a read_committed.
+    max_read_committed_size: u64,
+    // the max bytes of uncommitted entries of the leader.
+    max_uncommitted_size: u64,
+    // the max unapplied entries of the leader, 0 is unlimited.
+    max_apply_backlog: u64,
+    // the max bytes of data and context of a write, 0 is unlimited.
+    max_entry_size: u64,
+    max_log_memory: u64,
+    // the max uncompacted entries of the log of a group, 0 is unlimited.
+    max_log_entries: u64,
+    // the logs are compacted automatically only if the snapshot carries the
+    // state of the state machine, otherwise the compacted entries are lost.
+    has_state_machine: bool,
     heartbeat_tick: usize,
+    enable_quiesce: bool,
+    quiesce_ticks: usize,
+    // if true, the empty coalesced heartbeats are sent to the peer nodes by
+    // this iteration, which keep the quiesced followers of the local leaders
+    // asleep.
+    keepalive_due: bool,
+    follower_light_tick: bool,
+    // if true, the heartbeat responses report the applied index of the replica.
+    report_applied_index: bool,
+    // the follower reports the applied index on tick after applying the batch.
+    applied_report_batch: u64,
+    // the retries and the initial backoff of the ready which fails to be persisted.
+    storage_write_retries: usize,
+    storage_write_retry_backoff: u64,
+    // the readies of one iteration are synced together if not 0.
+    write_sync_batch_latency: Duration,
+    // persists the readies of the groups concurrently if `ready_workers` > 1.
+    ready_workers: Option<ReadyWorkers<RS>>,
+    entry_cache_size: usize,
     write_propose_rx: Receiver<(AppWriteRequest, oneshot::Sender<Result<(), Error>>)>,
-    read_index_propose_rx: Receiver<(AppReadIndexRequest, oneshot::Sender<Result<(), Error>>)>,
+    read_index_propose_rx: Receiver<(AppReadIndexRequest, oneshot::Sender<Result<u64, Error>>)>,
+    membership_change_rx: Receiver<(MembershipChangeData, oneshot::Sender<Result<(), Error>>)>,
     raft_message_rx: Receiver<RaftMessage>,
 
     campagin_rx: Receiver<u64>,
@@ -125,21 +490,101 @@ where
         RaftGroupManagementMessage,
         oneshot::Sender<Result<(), Error>>,
     )>,
+    initial_groups_rx: Receiver<(
+        Vec<RaftGroupManagementMessage>,
+        oneshot::Sender<Vec<(u64, Result<InitResult, Error>)>>,
+    )>,
+
+    query_group_rx: Receiver<QueryGroup>,
+
+    // if true, groups are ticked only via tick_rx.
+    manual_tick: bool,
+    tick_rx: Receiver<oneshot::Sender<()>>,
+    // the received campaigns and raft messages are stepped and acked via flush_rx.
+    flush_rx: Receiver<oneshot::Sender<()>>,
+
+    transfer_leader_rx: Receiver<(
+        u64,
+        u64,
+        TransferLeaderPolicy,
+        oneshot::Sender<Result<(), Error>>,
+    )>,
+    last_tick: Arc<Mutex<Instant>>,
+    clock: Arc<dyn Clock>,
+    ready_hook: Option<Arc<dyn ReadyHook>>,
+    leader_hook: Option<Arc<dyn LeaderHook>>,
+    dropped_messages: DroppedMessages,
+    node_latencies: NodeLatencies,
+    local_leaders: LocalLeaders,
+    // the groups whose watermarks may advance in this iteration, the watches
+    // are updated once with the latest index at the end of the iteration.
+    watermark_groups: HashSet<u64>,
 
     pending_events: Vec<Event>,
     event_tx: Sender<Vec<Event>>,
+    group_state_tx: UnboundedSender<GroupStateChangedEvent>,
+    group_state_rx: UnboundedReceiver<GroupStateChangedEvent>,
     // write_actor_address: WriteAddress,
     apply_actor_address: ApplyActorAddress,
     storage: MRS,
-    transport: T,
+    transport: Arc<T>,
     // waiting_ready_groups: VecDeque<HashMap<u64, Ready>>,
     // proposals: ProposalQueueManager,
     replica_cache: ReplicaCache<RS, MRS>,
     sync_replica_cache: bool,
+    // the replicas of the dead actor which are recreated on start.
+    restored_replicas: Vec<(u64, u64)>,
+    // the mailboxes and replicas are handed back here when the actor is dropped.
+    remains: Arc<Mutex<Option<ActorRemains>>>,
     _m1: PhantomData<RS>,
     _m2: PhantomData<MI>,
 }
 
+impl<MI, T, RS, MRS> Drop for MultiRaftActor<MI, T, RS, MRS>
+where
+    MI: MessageInterface,
+    T: Transport<MI>,
+    RS: RaftStorage,
+    MRS: MultiRaftStorage<RS>,
+{
+    fn drop(&mut self) {
+        // the receivers are replaced by the closed ones, the messages queued
+        // in the mailboxes are kept for the restarted actor.
+        fn closed<M>() -> Receiver<M> {
+            channel(1).1
+        }
+        let mailboxes = ActorMailboxes {
+            write_propose_rx: std::mem::replace(&mut self.write_propose_rx, closed()),
+            read_index_propose_rx: std::mem::replace(&mut self.read_index_propose_rx, closed()),
+            membership_change_rx: std::mem::replace(&mut self.membership_change_rx, closed()),
+            campagin_rx: std::mem::replace(&mut self.campagin_rx, closed()),
+            raft_message_rx: std::mem::replace(&mut self.raft_message_rx, closed()),
+            manager_group_rx: std::mem::replace(&mut self.manager_group_rx, closed()),
+            initial_groups_rx: std::mem::replace(&mut self.initial_groups_rx, closed()),
+            query_group_rx: std::mem::replace(&mut self.query_group_rx, closed()),
+            tick_rx: std::mem::replace(&mut self.tick_rx, closed()),
+            flush_rx: std::mem::replace(&mut self.flush_rx, closed()),
+            transfer_leader_rx: std::mem::replace(&mut self.transfer_leader_rx, closed()),
+            last_tick: self.last_tick.clone(),
+            apply_actor_address: ApplyActorAddress {
+                tx: std::mem::replace(&mut self.apply_actor_address.tx, channel(1).0),
+                rx: std::mem::replace(&mut self.apply_actor_address.rx, unbounded_channel().1),
+            },
+        };
+        let replicas = self
+            .groups
+            .iter()
+            .map(|(group_id, group)| (*group_id, group.replica_id))
+            .collect();
+        if let Ok(mut remains) = self.remains.lock() {
+            *remains = Some(ActorRemains {
+                mailboxes,
+                replicas,
+            });
+        }
+    }
+}
+
 impl<MI, T, RS, MRS> MultiRaftActor<MI, T, RS, MRS>
 where
     MI: MessageInterface,
@@ -152,22 +597,40 @@ where
         cfg: &MultiRaftConfig,
         node_id: u64,
         store_id: u64,
-        transport: T,
-        apply_actor_address: ApplyActorAddress,
+        transport: Arc<T>,
         event_tx: Sender<Vec<Event>>,
         storage: MRS,
+        extensions: MultiRaftExtensions,
+        dropped_messages: DroppedMessages,
+        node_latencies: NodeLatencies,
+        local_leaders: LocalLeaders,
+        clock: Arc<dyn Clock>,
+        mailboxes: ActorMailboxes,
+        replicas: Vec<(u64, u64)>,
+        remains: Arc<Mutex<Option<ActorRemains>>>,
         stop: watch::Receiver<bool>,
-    ) -> (JoinHandle<()>, MultiRaftActorAddress) {
-        let (raft_message_tx, raft_message_rx) = channel(1);
-        let (campagin_tx, campagin_rx) = channel(1);
-        let (manager_group_tx, manager_group_rx) = channel(1);
+    ) -> JoinHandle<()> {
+        let (forward_response_tx, forward_response_rx) = unbounded_channel();
+        let (group_state_tx, group_state_rx) = unbounded_channel();
 
         // let (write_actor_join, write_actor_address) =
         //     WriterActor::spawn(storage.clone(), stop.clone());
 
-        // create write propose channel
-        let (write_propose_tx, write_propose_rx) = channel(1);
-        let (read_index_propose_tx, read_index_propose_rx) = channel(1);
+        let ActorMailboxes {
+            write_propose_rx,
+            read_index_propose_rx,
+            membership_change_rx,
+            campagin_rx,
+            raft_message_rx,
+            manager_group_rx,
+            initial_groups_rx,
+            query_group_rx,
+            tick_rx,
+            flush_rx,
+            transfer_leader_rx,
+            last_tick,
+            apply_actor_address,
+        } = mailboxes;
 
         let actor = MultiRaftActor {
             store_id,
@@ -176,21 +639,120 @@ where
             node_manager: NodeManager::new(),
             event_tx,
             groups: HashMap::new(),
-            tick_interval: Duration::from_millis(cfg.tick_interval),
-            election_tick: cfg.election_tick,
+            removed_groups: HashSet::new(),
+            outgoing_snapshots: OutgoingSnapshots::new(
+                cfg.snapshot_chunk_size,
+                cfg.snapshot_chunk_window,
+                Duration::from_millis(cfg.snapshot_inflight_timeout),
+                cfg.max_concurrent_snapshots,
+                clock.clone(),
+            )
+            .with_install_suppression(cfg.snapshot_install_suppression),
+            incoming_snapshots: IncomingSnapshots::new(
+                cfg.snapshot_chunk_window,
+                cfg.max_snapshot_size,
+                Duration::from_millis(cfg.snapshot_inflight_timeout),
+                clock.clone(),
+            ),
+            snapshot_install_suppression: cfg.snapshot_install_suppression,
+            min_leader_tenure: cfg.min_leader_tenure,
+            proposal_forwarding: cfg.proposal_forwarding,
+            observer: cfg.observer,
+            // the forwarded proposal is failed if it isn't responded within
+            // two max election timeouts.
+            proposal_forwards: ProposalForwards::new(
+                Duration::from_millis(2 * cfg.tick_interval * cfg.election_tick_range().1 as u64),
+                clock.clone(),
+            ),
+            forward_response_tx,
+            forward_response_rx,
+            pending_messages: PendingMessages::new(
+                cfg.pending_group_messages,
+                cfg.pending_message_groups,
+                Duration::from_millis(cfg.pending_group_message_ttl),
+                clock.clone(),
+            ),
+            tick_interval: cfg.tick_pass_interval().0,
+            tick_slots: cfg.tick_pass_interval().1 as u64,
+            tick_passes: 0,
+            last_tick_groups: 0,
+            last_tick_cost: Duration::ZERO,
+            election_tick_range: cfg.election_tick_range(),
+            startup_election_jitter: cfg.startup_election_jitter,
+            election_extension_window: cfg.election_extension_window,
+            max_election_extension: cfg.max_election_extension,
+            unhealthy_ticks: cfg.group_unhealthy_multiple * cfg.election_tick_range().1,
+            ready_groups_budget: cfg.ready_groups_budget,
+            outbox: Outbox::default(),
+            batch_ready_messages: cfg.batch_ready_messages,
+            max_pending_proposals: cfg.max_pending_proposals,
+            max_client_pending_proposals: cfg.max_client_pending_proposals,
+            max_committed_size_per_ready: if cfg.max_committed_size_per_ready == 0 {
+                raft::util::NO_LIMIT
+            } else {
+                cfg.max_committed_size_per_ready
+            },
+            max_read_committed_size: if cfg.max_read_committed_size == 0 {
+                raft::util::NO_LIMIT
+            } else {
+                cfg.max_read_committed_size
+            },
+            max_uncommitted_size: if cfg.max_uncommitted_size == 0 {
+                raft::util::NO_LIMIT
+            } else {
+                cfg.max_uncommitted_size
+            },
+            max_apply_backlog: cfg.max_apply_backlog,
+            max_entry_size: cfg.max_entry_size,
+            max_log_memory: cfg.max_log_memory,
+            max_log_entries: cfg.max_log_entries,
             heartbeat_tick: cfg.heartbeat_tick,
+            enable_quiesce: cfg.enable_quiesce,
+            quiesce_ticks: cfg.quiesce_ticks,
+            keepalive_due: false,
+            follower_light_tick: cfg.follower_light_tick,
+            report_applied_index: cfg.report_applied_index,
+            applied_report_batch: cfg.applied_report_batch as u64,
+            storage_write_retries: cfg.storage_write_retries,
+            storage_write_retry_backoff: cfg.storage_write_retry_backoff,
+            write_sync_batch_latency: Duration::from_millis(cfg.write_sync_batch_latency),
+            ready_workers: (cfg.ready_workers > 1).then(|| ReadyWorkers::spawn(cfg.ready_workers)),
+            entry_cache_size: cfg.entry_cache_size,
             write_propose_rx,
             read_index_propose_rx,
+            membership_change_rx,
             campagin_rx,
             raft_message_rx,
             manager_group_rx,
+            initial_groups_rx,
+            query_group_rx,
+            #[cfg(feature = "test-util")]
+            manual_tick: cfg.manual_tick,
+            #[cfg(not(feature = "test-util"))]
+            manual_tick: false,
+            tick_rx,
+            flush_rx,
+            transfer_leader_rx,
+            last_tick,
+            clock,
+            has_state_machine: extensions.state_machine.is_some(),
+            ready_hook: extensions.ready_hook,
+            leader_hook: extensions.leader_hook,
+            dropped_messages,
+            node_latencies,
+            local_leaders,
             storage: storage.clone(),
             transport,
             // write_actor_address,
             apply_actor_address,
             sync_replica_cache: true,
-            replica_cache: ReplicaCache::new(storage.clone()),
+            replica_cache: ReplicaCache::new(storage.clone(), cfg.replica_cache_capacity),
+            watermark_groups: HashSet::new(),
             pending_events: Vec::new(),
+            group_state_tx,
+            group_state_rx,
+            restored_replicas: replicas,
+            remains,
             // waiting_ready_groups: VecDeque::default(),
             _m1: PhantomData,
             _m2: PhantomData,
@@ -200,28 +762,42 @@ where
             actor.start(stop).await;
         };
 
-        let join = tokio::spawn(main_loop);
-
-        let address = MultiRaftActorAddress {
-            campagin_tx,
-            raft_message_tx,
-            manager_group_tx,
-            write_propose_tx,
-            read_index_propose_tx,
-        };
-
-        (join, address)
+        tokio::spawn(main_loop)
     }
 
     /// start actor.
+    ///
+    /// Scheduling: each iteration handles one event of the select (tick,
+    /// message, proposal, apply response, ...), then the ready of at most
+    /// `ready_groups_budget` activity groups. The activity groups beyond the
+    /// budget are queued and handled in the next iterations in round-robin,
+    /// and the loop yields to the runtime between the iterations, so that the
+    /// busy groups can't starve the ticks of others and cause spurious elections.
     // #[tracing::instrument(name = "MultiRaftActor::start", skip(self))]
     async fn start(mut self, mut stop: watch::Receiver<bool>) {
-        // Each time ticker expires, the ticks increments,
-        // when ticks >= heartbeat_tick triggers the merged heartbeat.
-        let mut ticks = 0;
         let mut ticker = interval(self.tick_interval);
         let mut activity_groups = HashSet::new();
+        // the activity groups waiting for handling the ready, in round-robin order.
+        let mut ready_queue = VecDeque::new();
+        let mut queued_groups = HashSet::new();
+        // the manual ticks and flushes are acked after the ready of groups are handled.
+        let mut tick_acks = vec![];
+        for (group_id, replica_id) in std::mem::take(&mut self.restored_replicas) {
+            match self.create_raft_group(group_id, replica_id).await {
+                Ok(_) => {
+                    activity_groups.insert(group_id);
+                }
+                Err(err) => error!(
+                    "node {} restore replica {} of group {} error: {}",
+                    self.node_id, replica_id, group_id, err
+                ),
+            }
+        }
         loop {
+            while let Ok(changed) = self.group_state_rx.try_recv() {
+                self.pending_events.push(Event::GroupStateChanged(changed));
+            }
+
             // handle events
             if !self.pending_events.is_empty() {
                 for pending_event in self.pending_events.iter_mut() {
@@ -251,275 +827,1911 @@ where
                     }
                 }
 
-                _ = ticker.tick() => {
-                    // tick all groups
-                    self.groups.iter_mut().for_each(|(_, group)| {
-                        if group.raft_group.tick() {
-                            activity_groups.insert(group.group_id);
-                        }
-                    });
+                _ = ticker.tick(), if !self.manual_tick => self.tick_groups(&mut activity_groups).await,
+
+                Some(tx) = self.tick_rx.recv() => {
+                    self.tick_groups(&mut activity_groups).await;
+                    tick_acks.push(tx);
+                },
 
-                    ticks += 1;
-                    if ticks >= self.heartbeat_tick {
-                        ticks = 0;
-                        self.coalesced_heratbeat().await;
+                Some(tx) = self.flush_rx.recv() => {
+                    while let Ok(group_id) = self.campagin_rx.try_recv() {
+                        self.campagin_raft(group_id).await;
+                        activity_groups.insert(group_id);
                     }
+                    while let Ok(msg) = self.raft_message_rx.try_recv() {
+                        self.handle_raft_message(msg, &mut activity_groups).await;
+                    }
+                    tick_acks.push(tx);
                 },
 
                 Some(msg) = self.raft_message_rx.recv() => self.handle_raft_message(msg, &mut activity_groups).await,
 
-                Some(group_id) = self.campagin_rx.recv() => self.campagin_raft(group_id).await,
+                Some(group_id) = self.campagin_rx.recv() => {
+                    self.campagin_raft(group_id).await;
+                    activity_groups.insert(group_id);
+                },
 
                 Some((request, tx)) = self.write_propose_rx.recv() => self.handle_write_request(request, tx),
 
-                Some((request, tx)) = self.read_index_propose_rx.recv() => self.handle_read_index_request(request, tx),
+                Some((request, tx)) = self.read_index_propose_rx.recv() => self.handle_read_index_request(request, tx, &mut activity_groups),
+
+                Some((data, tx)) = self.membership_change_rx.recv() => self.handle_membership_change_request(data, tx, &mut activity_groups),
+
+                Some(response) = self.apply_actor_address.rx.recv() => {
+                    for group_id in response.groups.keys() {
+                        activity_groups.insert(*group_id);
+                    }
+                    self.handle_apply_task_response(response).await;
+                },
 
                 Some((msg, tx)) = self.manager_group_rx.recv() => {
                     self.handle_manager_group_message(msg, tx, &mut activity_groups).await;
                 },
 
-                else => {
-                    self.on_groups_ready(&activity_groups).await;
-                    activity_groups.clear();
+                Some((msgs, tx)) = self.initial_groups_rx.recv() => {
+                    self.handle_initial_groups(msgs, tx, &mut activity_groups).await;
                 },
-            }
-        }
-    }
 
-    /// The node sends heartbeats to other nodes instead
-    /// of all raft groups on that node.
-    async fn coalesced_heratbeat(&self) {
-        for (node_id, node) in self.node_manager.iter() {
-            if *node_id == self.node_id {
-                continue;
-            }
+                Some((group_id, transferee, policy, tx)) = self.transfer_leader_rx.recv() => {
+                    self.handle_transfer_leader(group_id, transferee, policy, tx, &mut activity_groups);
+                },
 
-            debug!(
-                "trigger node coalesced heartbeta {} -> {} ",
-                self.node_id, *node_id
-            );
+                Some(query) = self.query_group_rx.recv() => self.handle_query_group(query).await,
 
-            // coalesced heartbeat to all nodes. the heartbeat message is node
-            // level message so from and to set 0 when sending, and the specific
-            // value is set by message receiver.
-            let mut raft_msg = Message::default();
-            raft_msg.set_msg_type(MessageType::MsgHeartbeat);
-            let msg = RaftMessage {
-                group_id: NO_GORUP,
-                from_node: self.node_id,
-                to_node: *node_id,
-                msg: Some(raft_msg),
-            };
+                Some(response) = self.forward_response_rx.recv() => {
+                    if let Err(err) = transport::send_raft_message(
+                        self.transport.as_ref(),
+                        &self.dropped_messages,
+                        response,
+                    ) {
+                        error!("node {} send forwarded proposal response error: {}", self.node_id, err);
+                    }
+                },
 
-            if let Err(_error) = self.transport.send(msg) {}
-        }
-    }
+                // continue to handle the queued ready groups.
+                _ = tokio::task::yield_now(), if !ready_queue.is_empty() => {},
+            }
 
-    /// Fanout node heartbeat and handle raft messages.
-    async fn handle_raft_message(
-        &mut self,
-        mut msg: RaftMessage,
-        activity_groups: &mut HashSet<u64>,
-    ) {
-        let raft_msg = msg.msg.take().expect("invalid message");
-        match raft_msg.msg_type() {
-            MessageType::MsgHeartbeat => {
-                self.fanout_heartbeat(msg, activity_groups).await;
-                return;
+            for group_id in activity_groups.drain() {
+                if queued_groups.insert(group_id) {
+                    ready_queue.push_back(group_id);
+                }
             }
-            MessageType::MsgHeartbeatResponse => {
-                self.fanout_heartbeat_response(msg, activity_groups).await;
-                return;
+
+            if !ready_queue.is_empty() {
+                let budget = if self.ready_groups_budget == 0 {
+                    ready_queue.len()
+                } else {
+                    std::cmp::min(self.ready_groups_budget, ready_queue.len())
+                };
+                let ready_groups = ready_queue.drain(..budget).collect::<HashSet<u64>>();
+                for group_id in ready_groups.iter() {
+                    queued_groups.remove(group_id);
+                }
+                self.on_groups_ready(&ready_groups).await;
             }
-            _ => {}
-        }
 
-        // processing messages between replicas from other nodes to self node.
-        let group_id = msg.group_id;
+            self.update_watermarks();
 
-        let from_replica = ReplicaDesc {
-            node_id: msg.from_node,
-            replica_id: raft_msg.from,
-        };
+            // the messages of the readies are sent in one batch, and the
+            // heartbeats generated by them are sent in one message per node.
+            self.flush_outbox();
+            self.coalesced_heratbeat().await;
 
-        let to_replica = ReplicaDesc {
-            node_id: msg.to_node,
-            replica_id: raft_msg.to,
-        };
+            for tx in tick_acks.drain(..) {
+                let _ = tx.send(());
+            }
+        }
 
-        self.replica_cache
-            .cache_replica_desc(group_id, from_replica.clone(), self.sync_replica_cache)
-            .await
-            .unwrap();
+        self.fail_proposals_on_shutdown();
+    }
 
-        self.replica_cache
-            .cache_replica_desc(group_id, to_replica.clone(), self.sync_replica_cache)
-            .await
-            .unwrap();
+    /// Publish the commit and applied index of the groups advanced in this
+    /// iteration to their watches, so a burst of appends and applies notifies
+    /// the watchers once rather than per index. The watchers compare the index
+    /// by `>=`, so they don't miss the skipped intermediate ones.
+    fn update_watermarks(&mut self) {
+        for group_id in self.watermark_groups.drain() {
+            if let Some(group) = self.groups.get_mut(&group_id) {
+                group.update_watermarks();
+            }
+        }
+    }
 
-        if !self.node_manager.contains_node(&from_replica.node_id) {
-            self.node_manager.add_node(from_replica.node_id, group_id);
+    /// Fail the pending proposals of the groups with `Shutdown` once the actor
+    /// is stopped, the proposals in flight to the apply actor are dropped with
+    /// it, which is observed as `Shutdown` as well.
+    fn fail_proposals_on_shutdown(&mut self) {
+        for group in self.groups.values_mut() {
+            for proposal in group.proposals.take_all() {
+                proposal.tx.map(|tx| tx.send(Err(Error::Shutdown)));
+            }
         }
+    }
 
-        // if a group exists, try to maintain groups on the node
-        if self.groups.contains_key(&group_id)
-            && !self.node_manager.contains_node(&to_replica.node_id)
+    /// Tick the groups of the current slot which are not quiesced in one pass,
+    /// all groups are in one slot unless `tick_stagger_slots` > 1. If quiesce
+    /// is enabled, the leader which can quiesce for `quiesce_ticks` ticks is
+    /// quiesced.
+    async fn tick_groups(&mut self, activity_groups: &mut HashSet<u64>) {
+        let start = self.clock.now();
+        *self.last_tick.lock().unwrap() = start;
+        let slot = self.tick_passes % self.tick_slots;
+        self.tick_passes += 1;
+        // the trailing writes skipped by `SyncPolicy::Periodic` are synced once
+        // the period elapses, even if nothing is written after them.
+        if let Err(err) = self.storage.sync_writes().await {
+            error!("node {} sync the writes error: {}", self.node_id, err);
+        }
+        // the quiesced groups don't heartbeat, the nodes keep in contact by
+        // the empty coalesced heartbeats once per heartbeat interval.
+        if self.enable_quiesce
+            && self.tick_passes % (self.heartbeat_tick as u64 * self.tick_slots) == 0
         {
-            self.node_manager.add_node(to_replica.node_id, group_id);
+            self.keepalive_due = true;
         }
+        // the passes of the max election timeout, each group is ticked once
+        // per `tick_slots` passes.
+        let contact_timeout = self.election_tick_range.1 * self.tick_slots as usize;
+        let mut ticked = 0;
+        let mut quiesce_groups = vec![];
+        // the caught-up learners to be promoted, see `AutoPromotePolicy`.
+        let mut promotions = vec![];
+        for (group_id, group) in self.groups.iter_mut() {
+            // the ready which fails to be persisted is retried after the backoff.
+            if group.unpersisted_ready.is_some() {
+                activity_groups.insert(*group_id);
+            }
 
-        let group = match self.groups.get_mut(&group_id) {
-            Some(group) => group,
-            None => {
-                self.create_raft_group(group_id, to_replica.replica_id)
-                    .await
-                    .unwrap();
-                self.groups.get_mut(&group_id).unwrap()
+            // the applied index is reported once per tick at most, with the
+            // coalesced heartbeat responses.
+            if self.report_applied_index && !group.is_poisoned() && !group.is_quiesced() {
+                if let Some((node_id, report)) = group.applied_report(self.applied_report_batch) {
+                    self.node_manager.add_node(node_id, *group_id);
+                    let node = self.node_manager.get_mut_node(&node_id).unwrap();
+                    node.heartbeat_responses.push(report);
+                }
             }
-        };
 
-        group.raft_group.step(transmute_message(raft_msg)).unwrap();
-        activity_groups.insert(group_id);
-    }
+            // the read index requests dropped by raft are failed after an
+            // election timeout.
+            group.expire_read_index_proposals(contact_timeout);
+
+            // the quiesced follower wakes once the node of its leader is out of
+            // contact for an election timeout, so that a new leader is elected
+            // if the leader is dead.
+            if group.is_quiesced()
+                && !group.is_leader()
+                && self.node_manager.contact_lost(
+                    group.leader.node_id,
+                    self.tick_passes,
+                    contact_timeout,
+                )
+            {
+                info!(
+                    "group {} wake the quiesced replica {}, lost contact with leader node {}",
+                    group_id, group.replica_id, group.leader.node_id
+                );
+                group.wake();
+                activity_groups.insert(*group_id);
+            }
 
-    /// Fanout heartbeats from other nodes to all raft groups on this node.
-    async fn fanout_heartbeat(&mut self, msg: RaftMessage, activity_groups: &mut HashSet<u64>) {
-        if let Some(from_node) = self.node_manager.get_node(&msg.from_node) {
-            let mut fanouted_groups = 0;
-            let mut fanouted_followers = 0;
-            for (group_id, _) in from_node.group_map.iter() {
-                let group = match self.groups.get_mut(group_id) {
-                    None => {
-                        warn!(
-                            "missing group {} at from_node {} fanout heartbeat",
-                            *group_id, msg.from_node
-                        );
-                        continue;
-                    }
-                    Some(group) => group,
-                };
+            // the witness, the observer and the frozen replica are not ticked,
+            // so they never start an election.
+            if *group_id % self.tick_slots != slot
+                || group.is_quiesced()
+                || !group.can_campaign()
+                || group.is_poisoned()
+            {
+                continue;
+            }
 
-                fanouted_groups += 1;
-                activity_groups.insert(*group_id);
+            ticked += 1;
 
-                if group.leader.node_id == 0
-                    || group.leader.node_id != msg.from_node
-                    || msg.from_node == self.node_id
-                {
+            group.counters.ticks += 1;
+            group.leader_ticks = group.leader_ticks.saturating_add(1);
+            if group.skip_startup_tick() {
+                continue;
+            }
+            // the replica installing a snapshot defers its election until the
+            // snapshot is installed.
+            if self.snapshot_install_suppression && self.incoming_snapshots.is_installing(*group_id)
+            {
+                continue;
+            }
+            // the follower which heard from the leader recently extends its
+            // election timeout under the jittery heartbeats.
+            if group.extend_election_timeout(
+                self.election_extension_window,
+                self.max_election_extension,
+            ) {
+                group.counters.extended_ticks += 1;
+                continue;
+            }
+            // the tick of the follower of a live leader is skipped, raft and
+            // the bookkeeping catch up once the heartbeat is missed, which is
+            // well within the election timeout.
+            if self.follower_light_tick {
+                if group.is_following_live_leader(self.heartbeat_tick) {
+                    group.counters.light_ticks += 1;
+                    group.idle_ticks = 0;
+                    group.skipped_ticks += 1;
                     continue;
                 }
-
-                // gets the replica stored in this node.
-                let from_replica = self
-                    .storage
-                    .replica_for_node(*group_id, msg.from_node)
-                    .await
-                    .unwrap()
-                    .unwrap();
-                let to_replica = self
-                    .storage
-                    .replica_for_node(*group_id, msg.to_node)
-                    .await
-                    .unwrap()
-                    .unwrap();
-
-                fanouted_followers += 1;
-
-                let mut msg = raft::prelude::Message::default();
-                msg.set_msg_type(raft::prelude::MessageType::MsgHeartbeat);
-                msg.from = from_replica.replica_id;
-                msg.to = to_replica.replica_id;
-                group.raft_group.step(msg).unwrap();
+                if group.replay_skipped_ticks() {
+                    activity_groups.insert(*group_id);
+                }
             }
-        } else {
-            // In this point, we receive heartbeat message from other nodes,
-            // but we don't have the from_node raft group information, so we
-            // don't know which raft group's replica we should fanout to.
-            warn!(
-                "missing raft groups at from_node {} fanout heartbeat",
-                msg.from_node
-            );
-            self.node_manager.add_node(msg.from_node, NO_GORUP);
-        }
 
-        let response_msg = {
-            let mut raft_msg = Message::default();
-            raft_msg.set_msg_type(MessageType::MsgHeartbeatResponse);
-            RaftMessage {
-                group_id: NO_GORUP,
-                from_node: self.node_id,
-                to_node: msg.to_node,
-                msg: Some(raft_msg),
+            if group.raft_group.tick() {
+                activity_groups.insert(*group_id);
+            }
+            propose_leader_epoch(&self.leader_hook, &mut self.pending_events, group);
+
+            group.update_progress_ticks();
+            if self.unhealthy_ticks != 0 {
+                let unhealthy = group.check_unhealthy(self.unhealthy_ticks);
+                if unhealthy != group.unhealthy {
+                    if let Some(reason) = unhealthy {
+                        warn!("group {} is unhealthy: {:?}", group_id, reason);
+                        self.pending_events
+                            .push(Event::GroupUnhealthy(GroupUnhealthyEvent {
+                                group_id: *group_id,
+                                reason,
+                            }));
+                    } else {
+                        info!("group {} is recovered", group_id);
+                        self.pending_events
+                            .push(Event::GroupRecovered(GroupRecoveredEvent {
+                                group_id: *group_id,
+                            }));
+                    }
+                    group.unhealthy = unhealthy;
+                }
             }
-        };
 
-        self.transport.send(response_msg).unwrap();
-    }
+            // the timeout or cancelled proposals are not tracked anymore.
+            group.proposals.remove_cancelled();
 
-    /// Fanout heartbeats response from other nodes to all raft groups on this node.
-    async fn fanout_heartbeat_response(
-        &mut self,
-        msg: RaftMessage,
-        activity_groups: &mut HashSet<u64>,
-    ) {
-        if let Some(node) = self.node_manager.get_node(&msg.from_node) {
-            for (group_id, _) in node.group_map.iter() {
-                let group = match self.groups.get_mut(group_id) {
-                    None => {
-                        warn!(
-                            "missing group {} at from_node {} fanout heartbeat response",
-                            *group_id, msg.from_node
-                        );
-                        continue;
-                    }
-                    Some(group) => group,
-                };
+            // retry leaving the auto joint consensus, e.g. the leave proposal is
+            // dropped during the leadership transfer.
+            if group.maybe_auto_leave_joint() {
+                activity_groups.insert(*group_id);
+            }
 
+            // the leadership converges to the caught-up voter of the highest
+            // leader priority once the leader is out of its tenure.
+            if let Some(transferee) = group
+                .preferred_transferee()
+                .filter(|_| !group.in_leader_tenure(self.min_leader_tenure))
+            {
+                info!(
+                    "group {} leader {} transfer leader to replica {} of higher priority",
+                    group_id, group.replica_id, transferee
+                );
+                group.raft_group.transfer_leader(transferee);
                 activity_groups.insert(*group_id);
+            }
 
-                // let leader = match group.leader.as_ref() {
-                //     None => continue,
-                //     Some(leader) => leader,
-                // };
+            if let Some(learner) = group.auto_promote_learner() {
+                promotions.push((*group_id, learner));
+            }
 
-                // TODO: check leader is valid
-                if group.leader.node_id != msg.from_node || msg.from_node == self.node_id {
-                    continue;
+            if self.enable_quiesce && group.can_quiesce() {
+                group.idle_ticks += 1;
+                if group.idle_ticks >= self.quiesce_ticks {
+                    quiesce_groups.push(*group_id);
                 }
+            } else {
+                group.idle_ticks = 0;
+            }
+        }
 
-                // gets the replica stored in this node.
-                let from_replica = self
-                    .storage
-                    .replica_for_node(*group_id, msg.from_node)
-                    .await
-                    .unwrap()
-                    .unwrap();
-
-                let to_replica = self
-                    .storage
-                    .replica_for_node(*group_id, msg.to_node)
-                    .await
-                    .unwrap()
-                    .unwrap();
+        for group_id in quiesce_groups {
+            self.quiesce_group(group_id).await;
+        }
 
-                let mut msg = raft::prelude::Message::default();
-                msg.set_msg_type(raft::prelude::MessageType::MsgHeartbeatResponse);
-                msg.from = from_replica.replica_id;
-                msg.to = to_replica.replica_id;
-                group.raft_group.step(msg).unwrap();
+        for (group_id, learner) in promotions {
+            if self.promote_learner(group_id, learner).await {
+                activity_groups.insert(group_id);
             }
-        } else {
-            warn!(
-                "missing raft groups at from_node {} fanout heartbeat response",
-                msg.from_node
-            );
-            self.node_manager.add_node(msg.from_node, NO_GORUP);
         }
-    }
 
-    async fn campagin_raft(&mut self, group_id: u64) {
+        self.check_snapshot_inflights();
+        self.send_queued_snapshots().await;
+        self.proposal_forwards.expire();
+        for msg in self.pending_messages.expire() {
+            self.dropped_messages
+                .record(DropReason::PendingExpired, &DroppedMessage::from_raft_message(&msg));
+        }
+
+        self.compact_over_log_memory().await;
+        self.compact_over_log_entries().await;
+        self.snapshot_for_requests().await;
+
+        self.last_tick_groups = ticked;
+        self.last_tick_cost = self.clock.elapsed(start);
+        trace!(
+            "node {} tick pass {} ticked {} groups in {:?}",
+            self.node_id,
+            self.tick_passes,
+            ticked,
+            self.last_tick_cost
+        );
+    }
+
+    /// Stop tracking the inflight snapshots which are installed or timeout, the
+    /// timeout one is reported as failure to raft, so that it can be resent.
+    fn check_snapshot_inflights(&mut self) {
+        for (group_id, to_replica, timeout) in self.outgoing_snapshots.inflights() {
+            let group = match self.groups.get_mut(&group_id) {
+                None => {
+                    self.outgoing_snapshots.finish_inflight(group_id, to_replica);
+                    continue;
+                }
+                Some(group) => group,
+            };
+
+            let pending = group
+                .raft_group
+                .raft
+                .prs()
+                .get(to_replica)
+                .map_or(false, |pr| pr.state == raft::ProgressState::Snapshot);
+            if !pending {
+                self.outgoing_snapshots.finish_inflight(group_id, to_replica);
+                continue;
+            }
+
+            if timeout {
+                warn!(
+                    "group {} snapshot to replica {} is not finished within the timeout",
+                    group_id, to_replica
+                );
+                group
+                    .raft_group
+                    .report_snapshot(to_replica, raft::SnapshotStatus::Failure);
+                self.outgoing_snapshots.finish_inflight(group_id, to_replica);
+            }
+        }
+    }
+
+    /// Allocate the replica id which is greater than any id the group has ever
+    /// had, including the replicas recorded in storage.
+    async fn allocate_replica_id(&mut self, group_id: u64) -> Result<u64, Error> {
+        if !self.groups.contains_key(&group_id) {
+            return Err(Error::RaftGroupNotFound(group_id));
+        }
+        let desc = self
+            .storage
+            .group_desc(group_id)
+            .await
+            .map_err(|err| Error::Store(err))?;
+        let known_max = desc
+            .replicas
+            .iter()
+            .map(|replica| replica.replica_id)
+            .max()
+            .unwrap_or(0);
+        let group = self.groups.get_mut(&group_id).unwrap();
+        let replica_id = group.allocate_replica_id(known_max);
+        // the id is persisted before it's handed out, so it isn't allocated
+        // again after restart.
+        self.storage
+            .set_group_desc(
+                group_id,
+                RaftGroupDesc {
+                    allocated_replica_id: replica_id,
+                    ..desc
+                },
+            )
+            .await
+            .map_err(|err| Error::Store(err))?;
+        Ok(replica_id)
+    }
+
+    /// Send the queued snapshots after the inflight ones are finished, the
+    /// snapshot which raft no longer waits for is discarded.
+    async fn send_queued_snapshots(&mut self) {
+        while let Some((group_id, msg)) = self.outgoing_snapshots.pop_queued() {
+            let group = match self.groups.get(&group_id) {
+                None => continue,
+                Some(group) => group,
+            };
+            let pending = group
+                .raft_group
+                .raft
+                .prs()
+                .get(msg.to)
+                .map_or(false, |pr| pr.state == raft::ProgressState::Snapshot);
+            if !pending {
+                continue;
+            }
+
+            transport::send_messages(
+                self.node_id,
+                &self.storage,
+                &mut self.outbox,
+                &mut self.node_manager,
+                &mut self.outgoing_snapshots,
+                group_id,
+                0,
+                &group.correlation_ids,
+                vec![msg],
+            )
+            .await;
+        }
+    }
+
+    /// Send the messages buffered by the readies of this iteration, the
+    /// failures are reported to raft as unreachable.
+    fn flush_outbox(&mut self) {
+        if self.outbox.is_empty() {
+            return;
+        }
+        let failures = self.outbox.flush(
+            self.transport.as_ref(),
+            &self.dropped_messages,
+            self.batch_ready_messages,
+        );
+        for (group_id, to_replica, is_snapshot) in failures {
+            if let Some(group) = self.groups.get_mut(&group_id) {
+                report_send_failure(group, &mut self.outgoing_snapshots, to_replica, is_snapshot);
+            }
+        }
+    }
+
+    /// Propose to promote the caught-up learner of the group, the node of
+    /// the learner is looked up by the replica cache. Returns true if it's
+    /// proposed.
+    async fn promote_learner(&mut self, group_id: u64, learner: u64) -> bool {
+        let replica_desc = match self.replica_cache.replica_desc(group_id, learner).await {
+            Ok(Some(replica_desc)) => replica_desc,
+            Ok(None) => {
+                warn!(
+                    "group {} promote learner {} error: replica not found",
+                    group_id, learner
+                );
+                return false;
+            }
+            Err(err) => {
+                warn!(
+                    "group {} promote learner {} error: {}",
+                    group_id, learner, err
+                );
+                return false;
+            }
+        };
+        match self.groups.get_mut(&group_id) {
+            None => false,
+            Some(group) => group.propose_promote_learner(replica_desc),
+        }
+    }
+
+    /// Quiesce the group of which the local replica is leader, and notify
+    /// followers to quiesce by the coalesced heartbeat with quiesce flag.
+    async fn quiesce_group(&mut self, group_id: u64) {
+        let group = self.groups.get_mut(&group_id).unwrap();
+        group.quiesce();
+
+        let from_replica = group.replica_id;
+        let term = group.term();
+        let commit = group.raft_group.raft.raft_log.committed;
+        let peers = group
+            .raft_group
+            .raft
+            .prs()
+            .iter()
+            .map(|(id, _)| *id)
+            .filter(|id| *id != from_replica)
+            .collect::<Vec<_>>();
+        debug!(
+            "node {} quiesce group {} at term {}, commit {}",
+            self.node_id, group_id, term, commit
+        );
+
+        for to in peers {
+            let to_replica = match self.replica_cache.replica_desc(group_id, to).await {
+                Ok(Some(replica)) => replica,
+                _ => {
+                    warn!(
+                        "group {} quiesce missing replica {} description",
+                        group_id, to
+                    );
+                    continue;
+                }
+            };
+
+            self.node_manager.add_node(to_replica.node_id, group_id);
+            let node = self
+                .node_manager
+                .get_mut_node(&to_replica.node_id)
+                .unwrap();
+            node.heartbeats.push(CoalescedHeartbeat {
+                group_id,
+                from_replica,
+                to_replica: to,
+                term,
+                commit,
+                context: vec![],
+                quiesce: true,
+                applied: 0,
+            });
+        }
+    }
+
+    /// The node sends heartbeats to other nodes instead
+    /// of all raft groups on that node. The heartbeats (and responses) of
+    /// groups are buffered per node when sending messages of `Ready`, and
+    /// sent in one node level message which carries each group's term and
+    /// commit.
+    async fn coalesced_heratbeat(&mut self) {
+        // the (group_id, to_replica) of heartbeats failed to be sent.
+        let mut unreachables = vec![];
+        let keepalive = std::mem::take(&mut self.keepalive_due);
+        for (node_id, node) in self.node_manager.nodes.iter_mut() {
+            if *node_id == self.node_id {
+                continue;
+            }
+
+            if !node.heartbeats.is_empty() || keepalive {
+                debug!(
+                    "trigger node coalesced heartbeta {} -> {}, groups = {}",
+                    self.node_id,
+                    *node_id,
+                    node.heartbeats.len()
+                );
+                let heartbeats = std::mem::take(&mut node.heartbeats);
+                let replicas = heartbeats
+                    .iter()
+                    .map(|hb| (hb.group_id, hb.to_replica))
+                    .collect::<Vec<_>>();
+                let mut msg = coalesced_message(
+                    self.node_id,
+                    *node_id,
+                    MessageType::MsgHeartbeat,
+                    heartbeats,
+                );
+                // the stamp is echoed by the response to measure the round trip.
+                msg.heartbeat_sent_at = self.node_latencies.now_micros();
+                if let Err(err) = transport::send_raft_message(
+                    self.transport.as_ref(),
+                    &self.dropped_messages,
+                    msg,
+                ) {
+                    error!("node {} send coalesced heartbeat error: {}", self.node_id, err);
+                    unreachables.extend(replicas);
+                }
+            }
+
+            if !node.heartbeat_responses.is_empty() {
+                let mut msg = coalesced_message(
+                    self.node_id,
+                    *node_id,
+                    MessageType::MsgHeartbeatResponse,
+                    std::mem::take(&mut node.heartbeat_responses),
+                );
+                msg.heartbeat_sent_at = std::mem::take(&mut node.heartbeat_echo);
+                if let Err(err) = transport::send_raft_message(
+                    self.transport.as_ref(),
+                    &self.dropped_messages,
+                    msg,
+                ) {
+                    error!("node {} send coalesced heartbeat error: {}", self.node_id, err);
+                }
+            }
+        }
+
+        for (group_id, to_replica) in unreachables {
+            if let Some(group) = self.groups.get_mut(&group_id) {
+                group.raft_group.report_unreachable(to_replica);
+            }
+        }
+    }
+
+    /// Reject the corrupt snapshot from the leader rather than install it, the
+    /// leader sends a fresh snapshot after it receives the reject.
+    fn reject_snapshot(&self, msg: &RaftMessage, reject: SnapshotChunkAck, err: Error) {
+        error!(
+            "group {} reject snapshot of index {} from replica {}: {}",
+            reject.group_id, reject.snapshot_index, reject.to_replica, err
+        );
+        let reject = RaftMessage {
+            group_id: msg.group_id,
+            from_node: msg.to_node,
+            to_node: msg.from_node,
+            msg: None,
+            heartbeats: vec![],
+            snapshot_chunk: None,
+            snapshot_chunk_ack: Some(reject),
+            snapshot_checksum: 0,
+            forward_proposal: None,
+            forward_response: None,
+            heartbeat_sent_at: 0,
+            reset_replica: None,
+            correlation_ids: vec![],
+        };
+        if let Err(err) =
+            transport::send_raft_message(self.transport.as_ref(), &self.dropped_messages, reject)
+        {
+            error!("group {} send snapshot reject error: {}", msg.group_id, err);
+        }
+    }
+
+    /// The snapshot sent to the replica is rejected as corrupt, it's reported
+    /// as failure to raft, so that a fresh snapshot is sent.
+    fn handle_snapshot_reject(&mut self, ack: SnapshotChunkAck) {
+        warn!(
+            "group {} snapshot of index {} is rejected by replica {}",
+            ack.group_id, ack.snapshot_index, ack.from_replica
+        );
+        self.outgoing_snapshots
+            .finish_inflight(ack.group_id, ack.from_replica);
+        if let Some(group) = self.groups.get_mut(&ack.group_id) {
+            group
+                .raft_group
+                .report_snapshot(ack.from_replica, raft::SnapshotStatus::Failure);
+        }
+    }
+
+    /// The replica is reset by the leader, see `MultiRaft::reset_replica`.
+    fn handle_reset_replica(&mut self, reset: ResetReplica) {
+        let group = match self.groups.get_mut(&reset.group_id) {
+            Some(group) if group.replica_id == reset.to_replica => group,
+            _ => {
+                warn!(
+                    "group {} replica {} to reset not found",
+                    reset.group_id, reset.to_replica
+                );
+                return;
+            }
+        };
+        match group.request_reset(reset.from_replica, reset.term, reset.index) {
+            Ok(_) => info!(
+                "group {} replica {} is reset by leader {}, request snapshot at {}",
+                reset.group_id, reset.to_replica, reset.from_replica, reset.index
+            ),
+            Err(err) => warn!(
+                "group {} replica {} reset error: {}",
+                reset.group_id, reset.to_replica, err
+            ),
+        }
+    }
+
+    /// Fanout node heartbeat and handle raft messages. A panic in handling the
+    /// message of a group poisons the group only, the other groups keep
+    /// running.
+    async fn handle_raft_message(&mut self, msg: RaftMessage, activity_groups: &mut HashSet<u64>) {
+        let group_id = msg.group_id;
+        let res = AssertUnwindSafe(self.handle_group_message(msg, activity_groups))
+            .catch_unwind()
+            .await;
+        if let Err(payload) = res {
+            self.poison_group(group_id, panic_message(payload.as_ref()));
+        }
+    }
+
+    async fn handle_group_message(
+        &mut self,
+        mut msg: RaftMessage,
+        activity_groups: &mut HashSet<u64>,
+    ) {
+        // any message tells the node is alive, see `NodeManager::contact_lost`.
+        self.node_manager
+            .record_contact(msg.from_node, self.tick_passes);
+
+        if let Some(forward) = msg.forward_proposal.take() {
+            self.handle_forwarded_proposal(msg.from_node, forward);
+            return;
+        }
+
+        if let Some(response) = msg.forward_response.take() {
+            self.handle_forwarded_response(msg.group_id, response);
+            return;
+        }
+
+        if let Some(reset) = msg.reset_replica.take() {
+            self.handle_reset_replica(reset);
+            return;
+        }
+
+        if !msg.correlation_ids.is_empty() {
+            debug!(
+                correlation_ids = ?msg.correlation_ids,
+                "group {} receive {:?} from node {}",
+                msg.group_id,
+                msg.msg.as_ref().map(|msg| msg.msg_type()),
+                msg.from_node
+            );
+        }
+
+        if let Some(ack) = msg.snapshot_chunk_ack.take() {
+            if ack.reject {
+                self.handle_snapshot_reject(ack);
+                return;
+            }
+            for chunk in self.outgoing_snapshots.ack(&ack) {
+                if let Err(err) = transport::send_raft_message(
+                    self.transport.as_ref(),
+                    &self.dropped_messages,
+                    chunk,
+                ) {
+                    error!("group {} send snapshot chunk error: {}", ack.group_id, err);
+                }
+            }
+            return;
+        }
+
+        if self
+            .groups
+            .get(&msg.group_id)
+            .map_or(false, |group| group.is_poisoned())
+        {
+            self.dropped_messages
+                .record(DropReason::PoisonedGroup, &DroppedMessage::from_raft_message(&msg));
+            return;
+        }
+
+        // the message of the group which isn't created yet is buffered rather
+        // than creating the group, it's replayed after the group is created.
+        if self.pending_messages.enabled()
+            && !self.groups.contains_key(&msg.group_id)
+            && !self.removed_groups.contains(&msg.group_id)
+            && msg.msg.as_ref().map_or(false, |m| {
+                m.msg_type() != MessageType::MsgHeartbeat
+                    && m.msg_type() != MessageType::MsgHeartbeatResponse
+            })
+        {
+            if let Some(evicted) = self.pending_messages.push(msg) {
+                self.dropped_messages.record(
+                    DropReason::PendingOverflow,
+                    &DroppedMessage::from_raft_message(&evicted),
+                );
+            }
+            return;
+        }
+
+        let raft_msg = match msg.snapshot_chunk.take() {
+            Some(chunk) => {
+                // ack the chunk to the sender whatever it's in order or not, the
+                // out of order chunk is buffered until the gap is filled.
+                let ack = RaftMessage {
+                    group_id: msg.group_id,
+                    from_node: msg.to_node,
+                    to_node: msg.from_node,
+                    msg: None,
+                    heartbeats: vec![],
+                    snapshot_chunk: None,
+                    snapshot_chunk_ack: Some(snapshot::chunk_ack(&chunk)),
+                    snapshot_checksum: 0,
+                    forward_proposal: None,
+                    forward_response: None,
+                    heartbeat_sent_at: 0,
+                    reset_replica: None,
+                    correlation_ids: vec![],
+                };
+                if let Err(err) = transport::send_raft_message(
+                    self.transport.as_ref(),
+                    &self.dropped_messages,
+                    ack,
+                ) {
+                    error!("group {} send snapshot chunk ack error: {}", msg.group_id, err);
+                }
+                let reject = snapshot::reject_ack(
+                    chunk.group_id,
+                    chunk.to_replica,
+                    chunk.from_replica,
+                    chunk.snapshot_index,
+                );
+                match self.incoming_snapshots.receive(chunk) {
+                    Ok(None) => return,
+                    // step the snapshot message after reassembled.
+                    Ok(Some(raft_msg)) => raft_msg,
+                    Err(err) => {
+                        self.reject_snapshot(&msg, reject, err);
+                        return;
+                    }
+                }
+            }
+            None => {
+                let raft_msg = msg.msg.take().expect("invalid message");
+                if raft_msg.msg_type() == MessageType::MsgSnapshot {
+                    if let Err(err) = snapshot::verify_snapshot(&raft_msg, msg.snapshot_checksum) {
+                        let snapshot_index = raft_msg
+                            .snapshot
+                            .as_ref()
+                            .and_then(|snap| snap.metadata.as_ref())
+                            .map_or(0, |meta| meta.index);
+                        let reject = snapshot::reject_ack(
+                            msg.group_id,
+                            raft_msg.to,
+                            raft_msg.from,
+                            snapshot_index,
+                        );
+                        self.reject_snapshot(&msg, reject, err);
+                        return;
+                    }
+                }
+                raft_msg
+            }
+        };
+        match raft_msg.msg_type() {
+            MessageType::MsgHeartbeat => {
+                self.fanout_heartbeat(msg, activity_groups).await;
+                return;
+            }
+            MessageType::MsgHeartbeatResponse => {
+                self.fanout_heartbeat_response(msg, activity_groups).await;
+                return;
+            }
+            _ => {}
+        }
+
+        // processing messages between replicas from other nodes to self node.
+        let group_id = msg.group_id;
+        if self.removed_groups.contains(&group_id) {
+            self.dropped_messages.record(
+                DropReason::RemovedGroup,
+                &DroppedMessage::from_message(group_id, msg.from_node, msg.to_node, &raft_msg),
+            );
+            return;
+        }
+
+        // the late message of the removed replica is dropped before its
+        // replica desc is cached again.
+        if let Some(group) = self.groups.get(&group_id) {
+            if group.is_from_removed_replica(&raft_msg) {
+                self.dropped_messages.record(
+                    DropReason::StaleReplica,
+                    &DroppedMessage::from_message(group_id, msg.from_node, msg.to_node, &raft_msg),
+                );
+                return;
+            }
+        }
+
+        let from_replica = ReplicaDesc {
+            node_id: msg.from_node,
+            replica_id: raft_msg.from,
+        };
+
+        let to_replica = ReplicaDesc {
+            node_id: msg.to_node,
+            replica_id: raft_msg.to,
+        };
+
+        for replica_desc in [&from_replica, &to_replica] {
+            if let Err(err) = self
+                .replica_cache
+                .cache_replica_desc(group_id, replica_desc.clone(), self.sync_replica_cache)
+                .await
+            {
+                warn!(
+                    "group {} cache replica {} of node {} error: {}",
+                    group_id, replica_desc.replica_id, replica_desc.node_id, err
+                );
+                self.dropped_messages.record(
+                    DropReason::Rejected,
+                    &DroppedMessage::from_message(group_id, msg.from_node, msg.to_node, &raft_msg),
+                );
+                return;
+            }
+        }
+
+        if !self.node_manager.contains_node(&from_replica.node_id) {
+            self.node_manager.add_node(from_replica.node_id, group_id);
+        }
+
+        // if a group exists, try to maintain groups on the node
+        if self.groups.contains_key(&group_id)
+            && !self.node_manager.contains_node(&to_replica.node_id)
+        {
+            self.node_manager.add_node(to_replica.node_id, group_id);
+        }
+
+        let group = match self.groups.get_mut(&group_id) {
+            Some(group) => group,
+            // the group must be created explicitly if the messages are buffered.
+            None if self.pending_messages.enabled() => {
+                self.dropped_messages.record(
+                    DropReason::UnknownGroup,
+                    &DroppedMessage::from_message(group_id, msg.from_node, msg.to_node, &raft_msg),
+                );
+                return;
+            }
+            None => {
+                if let Err(err) = self
+                    .create_raft_group(group_id, to_replica.replica_id)
+                    .await
+                {
+                    error!(
+                        "node {} create replica {} of group {} error: {}",
+                        self.node_id, to_replica.replica_id, group_id, err
+                    );
+                    self.dropped_messages.record(
+                        DropReason::GroupCreationFailed,
+                        &DroppedMessage::from_message(
+                            group_id,
+                            msg.from_node,
+                            msg.to_node,
+                            &raft_msg,
+                        ),
+                    );
+                    return;
+                }
+                self.groups.get_mut(&group_id).unwrap()
+            }
+        };
+
+        // the witness and the observer never become leader, even if the
+        // leadership is transferred.
+        if !group.can_campaign() && raft_msg.msg_type() == MessageType::MsgTimeoutNow {
+            warn!(
+                "group {} replica {} can't campaign, drop MsgTimeoutNow",
+                group_id, group.replica_id
+            );
+            self.dropped_messages.record(
+                DropReason::Rejected,
+                &DroppedMessage::from_message(group_id, msg.from_node, msg.to_node, &raft_msg),
+            );
+            return;
+        }
+
+        // the replica installing a snapshot can't use the appends, and it
+        // doesn't vote until the snapshot is installed.
+        if self.snapshot_install_suppression
+            && matches!(
+                raft_msg.msg_type(),
+                MessageType::MsgAppend
+                    | MessageType::MsgRequestVote
+                    | MessageType::MsgRequestPreVote
+            )
+            && self.incoming_snapshots.is_installing(group_id)
+        {
+            self.dropped_messages.record(
+                DropReason::InstallingSnapshot,
+                &DroppedMessage::from_message(group_id, msg.from_node, msg.to_node, &raft_msg),
+            );
+            return;
+        }
+
+        // the replica sticks to the newly elected leader, except the vote of
+        // the leadership transfer.
+        if matches!(
+            raft_msg.msg_type(),
+            MessageType::MsgRequestVote | MessageType::MsgRequestPreVote
+        ) && raft_msg.context != CAMPAIGN_TRANSFER
+            && group.in_leader_tenure(self.min_leader_tenure)
+        {
+            self.dropped_messages.record(
+                DropReason::LeaderTenure,
+                &DroppedMessage::from_message(group_id, msg.from_node, msg.to_node, &raft_msg),
+            );
+            return;
+        }
+
+        group.wake();
+        let from_replica = raft_msg.from;
+        let msg_type = raft_msg.msg_type();
+        let dropped = DroppedMessage::from_message(group_id, msg.from_node, msg.to_node, &raft_msg);
+        group.counters.steps += 1;
+        if let Err(err) = group.raft_group.step(transmute_message(raft_msg)) {
+            // e.g. `StepPeerNotFound` of the message from the replica which
+            // isn't in the group yet or has been removed.
+            warn!(
+                "group {} step {:?} from replica {} error: {}",
+                group_id, msg_type, from_replica, err
+            );
+            self.dropped_messages.record(DropReason::Rejected, &dropped);
+            return;
+        }
+        propose_leader_epoch(&self.leader_hook, &mut self.pending_events, group);
+        group.record_leader_contact(from_replica, self.clock.now());
+        activity_groups.insert(group_id);
+
+        // stop tracking the snapshot once the replica responds the append
+        // after it's installed, the appends to it are no longer paused.
+        if msg_type == MessageType::MsgAppendResponse
+            && self.outgoing_snapshots.is_inflight(group_id, from_replica)
+        {
+            let installed = group
+                .raft_group
+                .raft
+                .prs()
+                .get(from_replica)
+                .map_or(true, |pr| pr.state != raft::ProgressState::Snapshot);
+            if installed {
+                self.outgoing_snapshots.finish_inflight(group_id, from_replica);
+            }
+        }
+    }
+
+    /// Fanout coalesced heartbeats from other nodes to the raft groups on this node.
+    async fn fanout_heartbeat(&mut self, msg: RaftMessage, activity_groups: &mut HashSet<u64>) {
+        // echo the stamp by the next coalesced heartbeat response to the node.
+        if msg.heartbeat_sent_at != 0 {
+            self.node_manager.add_node(msg.from_node, NO_GORUP);
+            if let Some(node) = self.node_manager.get_mut_node(&msg.from_node) {
+                node.heartbeat_echo = msg.heartbeat_sent_at;
+            }
+        }
+        self.fanout_coalesced(msg, raft::prelude::MessageType::MsgHeartbeat, activity_groups)
+    }
+
+    /// Fanout coalesced heartbeats response from other nodes to the raft groups on this node.
+    async fn fanout_heartbeat_response(
+        &mut self,
+        msg: RaftMessage,
+        activity_groups: &mut HashSet<u64>,
+    ) {
+        self.node_latencies.observe(msg.from_node, msg.heartbeat_sent_at);
+        self.fanout_coalesced(
+            msg,
+            raft::prelude::MessageType::MsgHeartbeatResponse,
+            activity_groups,
+        )
+    }
+
+    /// Demultiplex the coalesced heartbeats into per-group `RawNode::step` calls.
+    fn fanout_coalesced(
+        &mut self,
+        msg: RaftMessage,
+        msg_type: raft::prelude::MessageType,
+        activity_groups: &mut HashSet<u64>,
+    ) {
+        if !self.node_manager.contains_node(&msg.from_node) {
+            self.node_manager.add_node(msg.from_node, NO_GORUP);
+        }
+
+        let proto_msg_type = msg.msg.as_ref().map(|m| m.msg_type());
+        for heartbeat in msg.heartbeats.into_iter() {
+            let dropped = DroppedMessage {
+                group_id: heartbeat.group_id,
+                from_node: msg.from_node,
+                to_node: msg.to_node,
+                from_replica: heartbeat.from_replica,
+                to_replica: heartbeat.to_replica,
+                msg_type: proto_msg_type,
+                correlation_ids: vec![],
+            };
+            let group = match self.groups.get_mut(&heartbeat.group_id) {
+                None => {
+                    warn!(
+                        "missing group {} at from_node {} fanout {:?}",
+                        heartbeat.group_id, msg.from_node, msg_type
+                    );
+                    self.dropped_messages.record(DropReason::UnknownGroup, &dropped);
+                    continue;
+                }
+                Some(group) if group.is_poisoned() => {
+                    self.dropped_messages.record(DropReason::PoisonedGroup, &dropped);
+                    continue;
+                }
+                Some(group) => group,
+            };
+
+            self.node_manager.add_node(msg.from_node, heartbeat.group_id);
+            activity_groups.insert(heartbeat.group_id);
+
+            let mut raft_msg = raft::prelude::Message::default();
+            raft_msg.set_msg_type(msg_type);
+            raft_msg.from = heartbeat.from_replica;
+            raft_msg.to = heartbeat.to_replica;
+            raft_msg.term = heartbeat.term;
+            raft_msg.commit = heartbeat.commit;
+            raft_msg.context = heartbeat.context.into();
+            group.counters.steps += 1;
+            if let Err(error) = group.raft_group.step(raft_msg) {
+                warn!(
+                    "group {} step {:?} from node {} error: {}",
+                    heartbeat.group_id, msg_type, msg.from_node, error
+                );
+            }
+
+            if msg_type == raft::prelude::MessageType::MsgHeartbeatResponse {
+                group.record_leadership_confirmed(self.clock.now());
+            }
+            if msg_type == raft::prelude::MessageType::MsgHeartbeatResponse
+                && heartbeat.applied > 0
+                && group.is_leader()
+                && group.record_peer_applied(heartbeat.from_replica, heartbeat.applied)
+            {
+                self.watermark_groups.insert(heartbeat.group_id);
+            }
+
+            // the heartbeat response does not change the quiesce state, because the
+            // quiesced leader also receives responses of the quiesce heartbeat.
+            if msg_type == raft::prelude::MessageType::MsgHeartbeat {
+                group.record_leader_contact(heartbeat.from_replica, self.clock.now());
+                // the response to the heartbeat reports the applied index.
+                if self.report_applied_index {
+                    group.reported_applied = group.raft_group.raft.raft_log.applied;
+                }
+                if heartbeat.quiesce
+                    && group.raft_group.raft.raft_log.committed >= heartbeat.commit
+                {
+                    group.quiesce();
+                } else {
+                    group.wake();
+                }
+            }
+        }
+    }
+
+    async fn handle_query_group(&mut self, query: QueryGroup) {
+        match query {
+            QueryGroup::GroupsOnNode(node_id, tx) => {
+                let _ = tx.send(self.node_manager.groups_on_node(node_id));
+            }
+            QueryGroup::QuiescedGroupCount(tx) => {
+                let count = self
+                    .groups
+                    .iter()
+                    .filter(|(_, group)| group.is_quiesced())
+                    .count();
+                let _ = tx.send(count);
+            }
+            QueryGroup::ConfState(group_id, tx) => {
+                let cs = self.groups.get(&group_id).map(|group| {
+                    transmute_raft_conf_state(group.raft_group.raft.prs().conf().to_conf_state())
+                });
+                let _ = tx.send(cs);
+            }
+            QueryGroup::AllocateReplicaId(group_id, tx) => {
+                let _ = tx.send(self.allocate_replica_id(group_id).await);
+            }
+            QueryGroup::CheckNewReplica(group_id, replica_id, tx) => {
+                let res = match self.groups.get(&group_id) {
+                    None => Err(Error::RaftGroupNotFound(group_id)),
+                    Some(group) if group.replica_id_used(replica_id) => {
+                        Err(Error::DuplicateReplica(group_id, replica_id))
+                    }
+                    Some(_) => Ok(()),
+                };
+                let _ = tx.send(res);
+            }
+            QueryGroup::Drain(group_id, tx) => {
+                let res = match self.groups.get_mut(&group_id) {
+                    None => Err(Error::RaftGroupNotFound(group_id)),
+                    Some(group) => group.drain(),
+                };
+                let _ = tx.send(res);
+            }
+            QueryGroup::Freeze(group_id, tx) => {
+                let res = match self.groups.get_mut(&group_id) {
+                    None => Err(Error::RaftGroupNotFound(group_id)),
+                    Some(group) => group.freeze(),
+                };
+                let _ = tx.send(res);
+            }
+            QueryGroup::Unfreeze(group_id, tx) => {
+                let res = match self.groups.get_mut(&group_id) {
+                    None => Err(Error::RaftGroupNotFound(group_id)),
+                    Some(group) => group.unfreeze(),
+                };
+                let _ = tx.send(res);
+            }
+            QueryGroup::FailPendingProposals(group_id, tx) => {
+                let res = match self.groups.get_mut(&group_id) {
+                    None => Err(Error::RaftGroupNotFound(group_id)),
+                    Some(group) => Ok(group.fail_pending_proposals()),
+                };
+                let _ = tx.send(res);
+            }
+            QueryGroup::ReplicaCaughtUp(group_id, replica_id, tx) => {
+                let res = match self.groups.get(&group_id) {
+                    None => Err(Error::RaftGroupNotFound(group_id)),
+                    Some(group) => group.replica_caught_up(replica_id),
+                };
+                let _ = tx.send(res);
+            }
+            QueryGroup::Status(group_id, tx) => {
+                let mut status = self.groups.get(&group_id).map(|group| group.status());
+                for pr in status.iter_mut().flat_map(|status| status.progress.iter_mut()) {
+                    pr.snapshot_inflight =
+                        self.outgoing_snapshots.is_inflight(group_id, pr.replica_id);
+                    let replica = self.replica_cache.replica_desc(group_id, pr.replica_id).await;
+                    pr.node_id = match replica {
+                        Ok(Some(replica)) => replica.node_id,
+                        _ => NO_NODE,
+                    };
+                    pr.rtt = self.node_latencies.rtt(pr.node_id);
+                }
+                let _ = tx.send(status);
+            }
+            QueryGroup::ListGroups(tx) => {
+                let groups = self
+                    .groups
+                    .iter()
+                    .map(|(group_id, group)| (*group_id, group.role()))
+                    .collect();
+                let _ = tx.send(groups);
+            }
+            QueryGroup::Leaderships(tx) => {
+                let mut leaderships = vec![];
+                for (group_id, group) in self.groups.iter() {
+                    let cs = group.raft_group.raft.prs().conf().to_conf_state();
+                    let mut voters = vec![];
+                    for replica_id in cs.voters {
+                        match self.replica_cache.replica_desc(*group_id, replica_id).await {
+                            Ok(Some(replica)) => voters.push(replica),
+                            _ => continue,
+                        }
+                    }
+                    leaderships.push(GroupLeadership {
+                        group_id: *group_id,
+                        leader: group.leader.clone(),
+                        voters,
+                        in_tenure: group.in_leader_tenure(self.min_leader_tenure),
+                    });
+                }
+                let _ = tx.send(leaderships);
+            }
+            QueryGroup::Topology(tx) => {
+                let mut groups = vec![];
+                for (group_id, group) in self.groups.iter() {
+                    let raft = &group.raft_group.raft;
+                    let conf_state = transmute_raft_conf_state(raft.prs().conf().to_conf_state());
+                    let mut replica_ids = conf_state
+                        .voters
+                        .iter()
+                        .chain(conf_state.learners.iter())
+                        .chain(conf_state.voters_outgoing.iter())
+                        .chain(conf_state.learners_next.iter())
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    replica_ids.sort();
+                    replica_ids.dedup();
+                    let mut replicas = vec![];
+                    for replica_id in replica_ids {
+                        match self.replica_cache.replica_desc(*group_id, replica_id).await {
+                            Ok(Some(replica)) => replicas.push(replica),
+                            _ => continue,
+                        }
+                    }
+                    groups.push(GroupTopology {
+                        group_id: GroupId(*group_id),
+                        replica_id: ReplicaId(group.replica_id),
+                        role: group.role(),
+                        state: group.state,
+                        term: raft.term,
+                        commit_index: raft.raft_log.committed,
+                        leader: group.has_leader().then(|| group.leader.clone()),
+                        conf_state,
+                        replicas,
+                    });
+                }
+                groups.sort_by_key(|group| group.group_id);
+                let _ = tx.send(Topology {
+                    node_id: NodeId(self.node_id),
+                    groups,
+                });
+            }
+            QueryGroup::ReplicaCacheStats(tx) => {
+                let _ = tx.send(self.replica_cache.stats());
+            }
+            QueryGroup::TriggerSnapshot(group_id, tx) => {
+                self.trigger_snapshot(group_id, Some(tx)).await;
+            }
+            QueryGroup::ExportSnapshot(group_id, tx) => {
+                self.export_snapshot(group_id, tx).await;
+            }
+            QueryGroup::ReadCommitted(group_id, from_index, to_index, tx) => {
+                let _ = tx.send(self.read_committed(group_id, from_index, to_index).await);
+            }
+            QueryGroup::ResetReplica(group_id, replica_id, tx) => {
+                let _ = tx.send(self.reset_replica(group_id, replica_id).await);
+            }
+            #[cfg(feature = "test-util")]
+            QueryGroup::Counters(group_id, tx) => {
+                let _ = tx.send(self.groups.get(&group_id).map(|group| group.counters));
+            }
+            #[cfg(feature = "test-util")]
+            QueryGroup::Panic => panic!("node {} actor panicked on purpose", self.node_id),
+            QueryGroup::ShutdownTransferees(tx) => {
+                let transferees = self
+                    .groups
+                    .iter()
+                    .filter_map(|(group_id, group)| {
+                        group
+                            .leader_transferee()
+                            .map(|transferee| (*group_id, transferee))
+                    })
+                    .collect();
+                let _ = tx.send(transferees);
+            }
+            QueryGroup::LeaderOf(group_id, tx) => {
+                let leader = self
+                    .groups
+                    .get(&group_id)
+                    .and_then(|group| group.leader_of());
+                let _ = tx.send(leader);
+            }
+            QueryGroup::FollowerRead(group_id, max_staleness, tx) => {
+                let res = match self.groups.get(&group_id) {
+                    None => Err(Error::RaftGroupNotFound(group_id)),
+                    Some(group) => group
+                        .check_frozen()
+                        .and_then(|_| group.follower_read(max_staleness, self.clock.now())),
+                };
+                let _ = tx.send(res);
+            }
+            QueryGroup::CommitIndex(group_id, tx) => {
+                let res = match self.groups.get(&group_id) {
+                    None => Err(Error::RaftGroupNotFound(group_id)),
+                    Some(group) => Ok(group.raft_group.raft.raft_log.committed),
+                };
+                let _ = tx.send(res);
+            }
+            QueryGroup::CommitWatch(group_id, tx) => {
+                let res = match self.groups.get_mut(&group_id) {
+                    None => Err(Error::RaftGroupNotFound(group_id)),
+                    Some(group) => Ok(group.watch_commit()),
+                };
+                let _ = tx.send(res);
+            }
+            QueryGroup::AppliedWatch(group_id, tx) => {
+                let res = match self.groups.get_mut(&group_id) {
+                    None => Err(Error::RaftGroupNotFound(group_id)),
+                    Some(group) => Ok(group.watch_applied()),
+                };
+                let _ = tx.send(res);
+            }
+            QueryGroup::SetLeaderPriority(group_id, replica_id, priority, tx) => {
+                let res = match self.groups.get_mut(&group_id) {
+                    None => Err(Error::RaftGroupNotFound(group_id)),
+                    Some(group) => {
+                        group.wake();
+                        group.set_leader_priority(replica_id, priority);
+                        Ok(())
+                    }
+                };
+                let _ = tx.send(res);
+            }
+            QueryGroup::SetAutoPromote(group_id, policy, tx) => {
+                let res = match self.groups.get_mut(&group_id) {
+                    None => Err(Error::RaftGroupNotFound(group_id)),
+                    Some(group) => {
+                        group.wake();
+                        group.auto_promote = policy;
+                        group.caught_up_ticks.clear();
+                        Ok(())
+                    }
+                };
+                let _ = tx.send(res);
+            }
+            QueryGroup::SetGroupConfig(group_id, config, tx) => {
+                let res = match self.groups.get_mut(&group_id) {
+                    None => Err(Error::RaftGroupNotFound(group_id)),
+                    Some(group) => {
+                        group.set_config(config, self.max_pending_proposals);
+                        Ok(())
+                    }
+                };
+                let _ = tx.send(res);
+            }
+            QueryGroup::GroupConfig(group_id, tx) => {
+                let res = match self.groups.get(&group_id) {
+                    None => Err(Error::RaftGroupNotFound(group_id)),
+                    Some(group) => Ok(group.config()),
+                };
+                let _ = tx.send(res);
+            }
+            #[cfg(feature = "serde-config")]
+            QueryGroup::ProposeAdmin(group_id, kind, payload, tx) => {
+                let group = match self.groups.get_mut(&group_id) {
+                    None => {
+                        let _ = tx.send(Err(Error::RaftGroupNotFound(group_id)));
+                        return;
+                    }
+                    Some(group) => group,
+                };
+                if let Err(err) = group
+                    .check_poisoned()
+                    .and_then(|_| group.check_draining())
+                    .and_then(|_| group.check_frozen())
+                {
+                    let _ = tx.send(Err(err));
+                    return;
+                }
+                group.wake();
+                group.admin_propose(kind, payload, tx);
+            }
+            QueryGroup::RoleWatch(group_id, tx) => {
+                let res = match self.groups.get_mut(&group_id) {
+                    None => Err(Error::RaftGroupNotFound(group_id)),
+                    Some(group) => Ok(group.watch_role()),
+                };
+                let _ = tx.send(res);
+            }
+            QueryGroup::QuorumAppliedWatch(group_id, tx) => {
+                let res = match self.groups.get_mut(&group_id) {
+                    None => Err(Error::RaftGroupNotFound(group_id)),
+                    Some(group) => group.watch_quorum_applied(),
+                };
+                let _ = tx.send(res);
+            }
+            QueryGroup::AppliedReplicas(group_id, index, tx) => {
+                let res = match self.groups.get(&group_id) {
+                    None => Err(Error::RaftGroupNotFound(group_id)),
+                    Some(group) => Ok(group.applied_replicas(index)),
+                };
+                let _ = tx.send(res);
+            }
+            QueryGroup::Health(tx) => {
+                let (inflight_snapshots, queued_snapshots) = self.outgoing_snapshots.counts();
+                let mut health = NodeHealth {
+                    actor_running: true,
+                    last_tick_elapsed: self.clock.elapsed(*self.last_tick.lock().unwrap()),
+                    last_tick_groups: self.last_tick_groups,
+                    last_tick_cost: self.last_tick_cost,
+                    group_count: self.groups.len(),
+                    leader_known_count: 0,
+                    quiesced_count: 0,
+                    stuck_groups: vec![],
+                    // the mailboxes are measured by the senders.
+                    mailboxes: MailboxStats::default(),
+                    inflight_snapshots,
+                    queued_snapshots,
+                    log_bytes: 0,
+                };
+                for (group_id, group) in self.groups.iter() {
+                    health.log_bytes += group.log_size.total();
+                    if group.has_leader() {
+                        health.leader_known_count += 1;
+                    }
+                    if group.is_quiesced() {
+                        health.quiesced_count += 1;
+                    }
+                    // the group has no leader beyond the max election timeout.
+                    if group.leaderless_ticks > self.election_tick_range.1 {
+                        health.stuck_groups.push(*group_id);
+                    }
+                }
+                health.stuck_groups.sort();
+                let _ = tx.send(health);
+            }
+        }
+    }
+
+    fn handle_transfer_leader(
+        &mut self,
+        group_id: u64,
+        transferee: u64,
+        policy: TransferLeaderPolicy,
+        tx: oneshot::Sender<Result<(), Error>>,
+        activity_groups: &mut HashSet<u64>,
+    ) {
+        let group = match self.groups.get_mut(&group_id) {
+            None => {
+                let _ = tx.send(Err(Error::RaftGroupNotFound(group_id)));
+                return;
+            }
+            Some(group) => group,
+        };
+
+        if let Err(err) = group.check_poisoned().and_then(|_| group.check_leader()) {
+            let _ = tx.send(Err(err));
+            return;
+        }
+
+        // raft doesn't transfer to the lagging transferee until it catches up,
+        // and the transfer is aborted if it can't catch up within the election
+        // timeout.
+        match group.replica_lag(transferee) {
+            Err(err) => {
+                let _ = tx.send(Err(err));
+                return;
+            }
+            Ok(None) => {}
+            Ok(Some((matched, last_index))) => match policy {
+                TransferLeaderPolicy::RejectLagging => {
+                    let _ = tx.send(Err(Error::TargetLagging(
+                        group_id, transferee, matched, last_index,
+                    )));
+                    return;
+                }
+                TransferLeaderPolicy::CatchUp => {
+                    info!(
+                        "group {} transferee {} lags behind, matched {} but last index {}, catch up before transfer",
+                        group_id, transferee, matched, last_index
+                    );
+                    self.pending_events
+                        .push(Event::LeaderTransfer(LeaderTransferEvent {
+                            group_id,
+                            transferee,
+                            matched,
+                            last_index,
+                        }));
+                }
+            },
+        }
+
+        group.wake();
+        group.raft_group.transfer_leader(transferee);
+        activity_groups.insert(group_id);
+        let _ = tx.send(Ok(()));
+    }
+
+    /// Reset the replica of the group on the leader and ask the replica to
+    /// request a fresh snapshot by `ResetReplica`.
+    async fn reset_replica(&mut self, group_id: u64, replica_id: u64) -> Result<(), Error> {
+        if !self.groups.contains_key(&group_id) {
+            return Err(Error::RaftGroupNotFound(group_id));
+        }
+        let to_node = match self.replica_cache.replica_desc(group_id, replica_id).await {
+            Ok(Some(replica)) => replica.node_id,
+            _ => return Err(Error::ReplicaNotFound(group_id, replica_id)),
+        };
+
+        let group = self.groups.get_mut(&group_id).unwrap();
+        let index = group.reset_replica(replica_id)?;
+        let reset = ResetReplica {
+            group_id,
+            from_replica: group.replica_id,
+            to_replica: replica_id,
+            term: group.term(),
+            index,
+        };
+        info!(
+            "group {} reset replica {} on node {}, snapshot at {}",
+            group_id, replica_id, to_node, index
+        );
+        let msg = RaftMessage {
+            group_id,
+            from_node: self.node_id,
+            to_node,
+            msg: None,
+            heartbeats: vec![],
+            snapshot_chunk: None,
+            snapshot_chunk_ack: None,
+            snapshot_checksum: 0,
+            forward_proposal: None,
+            forward_response: None,
+            heartbeat_sent_at: 0,
+            reset_replica: Some(reset),
+            correlation_ids: vec![],
+        };
+        transport::send_raft_message(self.transport.as_ref(), &self.dropped_messages, msg)
+    }
+
+    /// Snapshot the group at the index of the last entry sent to be applied,
+    /// the state is snapshotted by the apply actor once the entries are
+    /// applied, then the snapshot is saved and the log is compacted up to it,
+    /// see `complete_snapshot`. Nothing is done if the index doesn't advance
+    /// since the latest snapshot, whose metadata is responded.
+    async fn trigger_snapshot(
+        &mut self,
+        group_id: u64,
+        tx: Option<oneshot::Sender<Result<SnapshotMetadata, Error>>>,
+    ) {
+        match self.unchanged_snapshot(group_id).await {
+            Ok(None) => {
+                self.send_snapshot_task(group_id, SnapshotTarget::Compact(tx))
+                    .await
+            }
+            Ok(Some(latest)) => {
+                // the automatic compaction retries the group on every tick, so
+                // it's logged once per snapshot.
+                let group = self.groups.get_mut(&group_id).unwrap();
+                if tx.is_some() || group.unadvanced_snapshot != latest.index {
+                    group.unadvanced_snapshot = latest.index;
+                    info!(
+                        "group {} applied index {} doesn't advance since the snapshot at {}, skip",
+                        group_id, group.apply_index, latest.index
+                    );
+                }
+                tx.map(|tx| tx.send(Ok(latest)));
+            }
+            Err(err) => match tx {
+                Some(tx) => {
+                    let _ = tx.send(Err(err));
+                }
+                None => warn!("group {} trigger snapshot error: {}", group_id, err),
+            },
+        }
+    }
+
+    /// Returns the metadata of the latest snapshot of the group if the index
+    /// to snapshot at doesn't advance since it.
+    async fn unchanged_snapshot(&self, group_id: u64) -> Result<Option<SnapshotMetadata>, Error> {
+        let group = self
+            .groups
+            .get(&group_id)
+            .ok_or(Error::RaftGroupNotFound(group_id))?;
+        group.check_poisoned()?;
+        let index = group.apply_index;
+        let latest = self.storage.snapshot_metadata(group_id).await?;
+        if index <= latest.index {
+            return Ok(Some(latest));
+        }
+        Ok(None)
+    }
+
+    async fn send_snapshot_task(&mut self, group_id: u64, target: SnapshotTarget) {
+        let group = self.groups.get_mut(&group_id).unwrap();
+        if let SnapshotTarget::Compact(_) | SnapshotTarget::CompactMatched = target {
+            group.snapshotting = true;
+        }
+        let task = SnapshotTask {
+            index: group.apply_index,
+            target,
+        };
+        self.send_apply_task(group_id, ApplyTask::Snapshot(task))
+            .await;
+    }
+
+    /// Complete the snapshot whose `data` is taken by the apply actor at
+    /// `task.index`. The membership changes up to the index are applied to raft
+    /// before it, and the ones after it are applied after it, because the apply
+    /// results of the group are handled in the order of the log, so the conf
+    /// state of raft is the one at the index.
+    async fn complete_snapshot(
+        group: &mut RaftGroup<RS>,
+        storage: &MRS,
+        task: SnapshotTask,
+        data: Result<Vec<u8>, Error>,
+    ) {
+        let gs = group.raft_group.store().clone();
+        let snapshot = data.and_then(|data| {
+            let term = RaftStorage::term(&gs, task.index).map_err(|err| Error::Store(err))?;
+            let mut snapshot = Snapshot::default();
+            let meta = snapshot.mut_metadata();
+            meta.index = task.index;
+            meta.term = term;
+            meta.set_conf_state(transmute_raft_conf_state(
+                group.raft_group.raft.prs().conf().to_conf_state(),
+            ));
+            snapshot.data = data;
+            Ok(snapshot)
+        });
+
+        let (tx, keep_unmatched) = match task.target {
+            SnapshotTarget::Export(tx) => {
+                let _ = tx.send(snapshot);
+                return;
+            }
+            SnapshotTarget::Compact(tx) => (tx, false),
+            SnapshotTarget::CompactMatched => (None, true),
+        };
+        group.snapshotting = false;
+        let res = match snapshot {
+            Err(err) => Err(err),
+            Ok(snapshot) => {
+                let compact_index = if keep_unmatched {
+                    group.log_compact_index(task.index)
+                } else {
+                    task.index.saturating_add(1)
+                };
+                Self::compact_to_snapshot(group, storage, &gs, snapshot, compact_index).await
+            }
+        };
+        if let Err(err) = res.as_ref() {
+            warn!(
+                "group {} snapshot at {} error: {}",
+                group.group_id, task.index, err
+            );
+        }
+        tx.map(|tx| tx.send(res));
+    }
+
+    /// Save the snapshot and compact the log before `compact_index`, which is
+    /// at most the index after the snapshot, unless a snapshot at or after it
+    /// is saved meanwhile, whose metadata is returned.
+    async fn compact_to_snapshot(
+        group: &mut RaftGroup<RS>,
+        storage: &MRS,
+        gs: &RaftStorageImpl<RS>,
+        snapshot: Snapshot,
+        compact_index: u64,
+    ) -> Result<SnapshotMetadata, Error> {
+        let meta = snapshot.get_metadata().clone();
+        let latest = storage.snapshot_metadata(group.group_id).await?;
+        if meta.index <= latest.index {
+            return Ok(latest);
+        }
+        // the storage of raft, so that its entry cache is compacted too.
+        let compact_index = std::cmp::min(compact_index, meta.index.saturating_add(1));
+        gs.compact_to_snapshot(snapshot, compact_index).await?;
+        group.log_size.compact_to(compact_index - 1);
+        info!(
+            "group {} snapshot at index {} term {}, the log is compacted before {}",
+            group.group_id, meta.index, meta.term, compact_index
+        );
+        Ok(meta)
+    }
+
+    /// Take a fresh snapshot of the led groups whose replicas request one
+    /// after the latest snapshot, e.g. the promoted witness or the reset
+    /// replica, raft sends the snapshot to them once it's saved.
+    async fn snapshot_for_requests(&mut self) {
+        let mut groups = vec![];
+        for (group_id, group) in self.groups.iter() {
+            if !group.is_leader() || group.snapshotting || group.is_poisoned() {
+                continue;
+            }
+            let requested = group
+                .raft_group
+                .raft
+                .prs()
+                .iter()
+                .map(|(_, pr)| pr.pending_request_snapshot)
+                .max()
+                .unwrap_or(0);
+            // the snapshot at the requested index is taken after it's applied.
+            if requested != 0 && requested <= group.apply_index {
+                groups.push((*group_id, requested));
+            }
+        }
+        for (group_id, requested) in groups {
+            match self.storage.snapshot_metadata(group_id).await {
+                Ok(latest) if latest.index >= requested => continue,
+                Ok(_) => self.trigger_snapshot(group_id, None).await,
+                Err(err) => warn!("group {} snapshot metadata error: {}", group_id, err),
+            }
+        }
+    }
+
+    /// Snapshot and compact the groups with the largest logs until the bytes
+    /// of the logs of all groups fit in `max_log_memory`. The group whose
+    /// applied index doesn't advance since its latest snapshot can't be
+    /// compacted further, it's skipped. Nothing is compacted without the state
+    /// machine, whose state the snapshot carries.
+    async fn compact_over_log_memory(&mut self) {
+        if self.max_log_memory == 0 || !self.has_state_machine {
+            return;
+        }
+        let mut total = self
+            .groups
+            .values()
+            .map(|group| group.log_size.total())
+            .sum::<u64>();
+        if total <= self.max_log_memory {
+            return;
+        }
+
+        let mut groups = self
+            .groups
+            .iter()
+            .filter(|(_, group)| !group.is_poisoned() && group.log_size.total() > 0)
+            .map(|(group_id, group)| (group.log_size.total(), *group_id))
+            .collect::<Vec<_>>();
+        groups.sort_unstable_by(|a, b| b.cmp(a));
+        for (size, group_id) in groups {
+            if total <= self.max_log_memory {
+                break;
+            }
+            // the log is compacted up to the apply index once the snapshot
+            // is saved.
+            let group = self.groups.get(&group_id).unwrap();
+            let remaining = group.log_size.total_after(group.apply_index);
+            if !group.snapshotting {
+                self.trigger_snapshot(group_id, None).await;
+            }
+            total -= size.saturating_sub(remaining);
+        }
+        if total > self.max_log_memory {
+            debug!(
+                "node {} logs use {} bytes over max_log_memory {} after compaction",
+                self.node_id, total, self.max_log_memory
+            );
+        }
+    }
+
+    /// Snapshot and compact the groups whose logs reach `max_log_entries`
+    /// up to their applied index, the leaders keep the entries which a replica
+    /// hasn't matched, see `RaftGroup::log_compact_index`. The leader whose
+    /// log can't be compacted further because of a lagging replica rejects the
+    /// proposals by `LogFull` until the replica catches up. Nothing is
+    /// compacted without the state machine, whose state the snapshot carries.
+    async fn compact_over_log_entries(&mut self) {
+        if self.max_log_entries == 0 || !self.has_state_machine {
+            return;
+        }
+        let mut groups = vec![];
+        for (group_id, group) in self.groups.iter_mut() {
+            if group.log_entries() < self.max_log_entries {
+                group.log_pinned_by = None;
+                continue;
+            }
+            let first_index = group.raft_group.raft.raft_log.first_index();
+            if group.is_poisoned()
+                || group.snapshotting
+                || group.log_compact_index(group.apply_index) <= first_index
+            {
+                continue;
+            }
+            groups.push(*group_id);
+        }
+        for group_id in groups {
+            // the group whose applied index doesn't advance since the latest
+            // snapshot is retried once it's applied further.
+            match self.unchanged_snapshot(group_id).await {
+                Ok(None) => {
+                    self.send_snapshot_task(group_id, SnapshotTarget::CompactMatched)
+                        .await
+                }
+                Ok(Some(_)) => {}
+                Err(err) => warn!("group {} compact the log error: {}", group_id, err),
+            }
+        }
+    }
+
+    /// Snapshot the group like `trigger_snapshot`, the snapshot is responded
+    /// rather than saved, so the log of the storage is untouched and the
+    /// replication isn't affected.
+    async fn export_snapshot(
+        &mut self,
+        group_id: u64,
+        tx: oneshot::Sender<Result<Snapshot, Error>>,
+    ) {
+        let checked = self
+            .groups
+            .get(&group_id)
+            .ok_or(Error::RaftGroupNotFound(group_id))
+            .and_then(|group| group.check_poisoned());
+        match checked {
+            Err(err) => {
+                let _ = tx.send(Err(err));
+            }
+            Ok(_) => {
+                self.send_snapshot_task(group_id, SnapshotTarget::Export(tx))
+                    .await
+            }
+        }
+    }
+
+    /// Read the committed entries in `[from_index, to_index)` from the log in
+    /// the storage. The range is truncated to the commit index and the last
+    /// persisted index, and the entries are limited by `max_read_committed_size`.
+    async fn read_committed(
+        &self,
+        group_id: u64,
+        from_index: u64,
+        to_index: u64,
+    ) -> Result<Vec<Entry>, Error> {
+        let group = self
+            .groups
+            .get(&group_id)
+            .ok_or(Error::RaftGroupNotFound(group_id))?;
+        group.check_poisoned()?;
+        let bounds = self.storage.log_bounds(group_id).await?;
+        if from_index < bounds.first_index {
+            return Err(Error::LogCompacted(
+                group_id,
+                from_index,
+                bounds.snapshot_index,
+            ));
+        }
+
+        // the follower may commit the entries which aren't persisted yet.
+        let committed = group.raft_group.raft.raft_log.committed;
+        let high = std::cmp::min(to_index, std::cmp::min(committed, bounds.last_index) + 1);
+        if from_index >= high {
+            return Ok(vec![]);
+        }
+        match RaftStorage::entries(
+            group.raft_group.store(),
+            from_index,
+            high,
+            self.max_read_committed_size,
+        ) {
+            Err(StorageError::Compacted) | Err(StorageError::LogCompacted(_)) => Err(
+                Error::LogCompacted(group_id, from_index, bounds.snapshot_index),
+            ),
+            res => Ok(res?),
+        }
+    }
+
+    async fn campagin_raft(&mut self, group_id: u64) {
         if let Some(group) = self.groups.get_mut(&group_id) {
-            group.raft_group.campaign().unwrap()
+            if !group.can_campaign() {
+                warn!("group {} replica {} can't campaign", group_id, group.replica_id);
+                return;
+            }
+            group.wake();
+            group.raft_group.campaign().unwrap();
+            propose_leader_epoch(&self.leader_hook, &mut self.pending_events, group);
         }
     }
 
@@ -532,22 +2744,125 @@ where
     ) {
         // let mut activity_groups = vec![];
         let res = match msg.msg_type() {
-            RaftGroupManagementMessageType::MsgInitialGroup => {
-                activity_groups.insert(msg.group_id);
-                self.initial_group(msg).await
-            }
+            RaftGroupManagementMessageType::MsgInitialGroup => self
+                .initial_group_with_event(msg, activity_groups)
+                .await
+                .map(|_| ()),
             RaftGroupManagementMessageType::MsgCreateGroup => {
+                self.removed_groups.remove(&msg.group_id);
                 activity_groups.insert(msg.group_id);
-                self.create_raft_group(msg.group_id, msg.replica_id).await
+                let res = self.create_raft_group(msg.group_id, msg.replica_id).await;
+                if res.is_ok() {
+                    self.replay_pending_messages(msg.group_id, activity_groups).await;
+                }
+                res
+            }
+            RaftGroupManagementMessageType::MsgRemoveGoup => {
+                activity_groups.remove(&msg.group_id);
+                self.remove_group(msg.group_id).await
             }
-            RaftGroupManagementMessageType::MsgRemoveGoup => todo!(),
         };
 
         if let Err(_error) = tx.send(res) {}
     }
 
+    /// Initialize the groups in one round-trip of the actor, the failure of a
+    /// group doesn't abort the others, the result of each group is responded.
+    async fn handle_initial_groups(
+        &mut self,
+        msgs: Vec<RaftGroupManagementMessage>,
+        tx: oneshot::Sender<Vec<(u64, Result<InitResult, Error>)>>,
+        activity_groups: &mut HashSet<u64>,
+    ) {
+        let mut results = Vec::with_capacity(msgs.len());
+        for msg in msgs {
+            let group_id = msg.group_id;
+            let res = if msg.msg_type() != RaftGroupManagementMessageType::MsgInitialGroup {
+                Err(Error::BadParameter(format!(
+                    "bad message type {:?} of group {} for initial",
+                    msg.msg_type(),
+                    group_id
+                )))
+            } else {
+                self.initial_group_with_event(msg, activity_groups).await
+            };
+            results.push((group_id, res));
+        }
+
+        if let Err(_error) = tx.send(results) {}
+    }
+
+    /// Initialize the group and emit the `GroupCreated` event if success.
+    async fn initial_group_with_event(
+        &mut self,
+        msg: RaftGroupManagementMessage,
+        activity_groups: &mut HashSet<u64>,
+    ) -> Result<InitResult, Error> {
+        let (group_id, replica_id) = (msg.group_id, msg.replica_id);
+        // the initialization is retried, e.g. after the response is lost.
+        if let Some(group) = self.groups.get(&group_id) {
+            return check_initial_group_retry(group, &msg).map(|_| group.init_result(self.node_id));
+        }
+        // the group is created explicitly again after removed.
+        self.removed_groups.remove(&group_id);
+        self.initial_group(msg).await?;
+        activity_groups.insert(group_id);
+        self.pending_events
+            .push(Event::GroupCreated(GroupCreatedEvent {
+                group_id,
+                replica_id,
+            }));
+        let result = self.groups[&group_id].init_result(self.node_id);
+        self.replay_pending_messages(group_id, activity_groups).await;
+        Ok(result)
+    }
+
+    /// Replay the messages buffered before the group is created.
+    async fn replay_pending_messages(&mut self, group_id: u64, activity_groups: &mut HashSet<u64>) {
+        let msgs = self.pending_messages.take(group_id);
+        if !msgs.is_empty() {
+            info!(
+                "group {} replay {} messages received before it's created",
+                group_id,
+                msgs.len()
+            );
+        }
+        for msg in msgs {
+            self.handle_raft_message(msg, activity_groups).await;
+        }
+    }
+
+    /// Remove the replica of the group from this node. The group is no longer
+    /// ticked, the pending proposals are responded with an error and all persisted
+    /// state of the group is deleted from storage. The group is recorded as removed,
+    /// so the in-flight messages of it are dropped rather than resurrecting it.
+    async fn remove_group(&mut self, group_id: u64) -> Result<(), Error> {
+        let mut group = match self.groups.remove(&group_id) {
+            None => return Err(Error::RaftGroupNotFound(group_id)),
+            Some(group) => group,
+        };
+        let _ = group.transition(GroupState::Removed);
+
+        for proposal in group.proposals.take_all() {
+            proposal
+                .tx
+                .map(|tx| tx.send(Err(Error::RaftGroupNotFound(group_id))));
+        }
+
+        self.removed_groups.insert(group_id);
+        self.outgoing_snapshots.remove_group(group_id);
+        self.incoming_snapshots.remove_group(group_id);
+        self.node_manager.remove_group_from_all(group_id);
+        self.replica_cache.remove_group(group_id);
+        self.local_leaders.remove(group_id);
+        self.storage
+            .remove_group_storage(group_id)
+            .await
+            .map_err(|err| Error::Store(err))
+    }
+
     /// Initial the raft consensus group and start a replica in current node.
-    async fn initial_group(&mut self, msg: RaftGroupManagementMessage) -> Result<(), Error> {
+    async fn initial_group(&mut self, mut msg: RaftGroupManagementMessage) -> Result<(), Error> {
         assert_eq!(
             msg.msg_type(),
             RaftGroupManagementMessageType::MsgInitialGroup
@@ -572,6 +2887,30 @@ where
             .await
             .map_err(|err| Error::Store(err))?;
 
+        // the replica starts from the snapshot rather than an empty log. The
+        // storage created for it is removed if the snapshot is rejected.
+        if let Some(snapshot) = msg.snapshot.take() {
+            let created = is_empty_storage(&gs)?;
+            if let Err(err) = restore_group_storage(&msg, &gs, snapshot).await {
+                if created {
+                    if let Err(err) = self.storage.remove_group_storage(msg.group_id).await {
+                        warn!(
+                            "node {} remove storage of unrestored group {} error: {}",
+                            self.node_id, msg.group_id, err
+                        );
+                    }
+                }
+                return Err(err);
+            }
+        }
+        // the applied state starts from the snapshot in the storage, the
+        // entries after it are applied again.
+        let snapshot = RaftStorage::snapshot(&gs, 0).map_err(|err| Error::Store(err))?;
+        let applied = snapshot.get_metadata().index;
+
+        let rs = gs.initial_state().map_err(|err| Error::Store(err))?;
+        self.reconcile_conf_state(msg.group_id, &gs, &rs).await?;
+
         for replica_metadata in msg.replicas.into_iter() {
             if replica_metadata.node_id != NO_NODE {
                 self.node_manager
@@ -607,39 +2946,174 @@ where
         // };
 
         // create raft consensus group with default logger and group storage.
-        let applied = 0;
         let raft_cfg = raft::Config {
             id: msg.replica_id,
             applied,
-            election_tick: self.election_tick,
+            // raft requires election_tick <= min_election_tick < max_election_tick.
+            election_tick: self.election_tick_range.0,
+            min_election_tick: self.election_tick_range.0,
+            max_election_tick: self.election_tick_range.1 + 1,
             heartbeat_tick: self.heartbeat_tick,
-            max_size_per_msg: 1024 * 1024,
+            max_size_per_msg: MAX_SIZE_PER_MSG,
             max_inflight_msgs: 256,
+            max_committed_size_per_ready: self.max_committed_size_per_ready,
+            max_uncommitted_size: self.max_uncommitted_size,
             ..Default::default()
         };
 
-        let raft_store = gs.clone();
+        let raft_store = gs.clone().with_entry_cache(self.entry_cache_size);
         let raft_group = raft::RawNode::with_default_logger(&raft_cfg, raft_store)
             .map_err(|err| Error::RaftGroup(err))?;
 
         // add group to node map
         self.node_manager.add_node(self.node_id, msg.group_id);
 
+        let witnesses = msg.witnesses.iter().cloned().collect::<HashSet<u64>>();
+        let mut desc = self
+            .storage
+            .group_desc(msg.group_id)
+            .await
+            .map_err(|err| Error::Store(err))?;
+        if !witnesses.is_empty() {
+            desc.witnesses = msg.witnesses.clone();
+            self.storage
+                .set_group_desc(msg.group_id, desc.clone())
+                .await
+                .map_err(|err| Error::Store(err))?;
+        }
+
         // insert raft_group to group map
-        let group = RaftGroup {
+        let mut group = RaftGroup {
             group_id: msg.group_id,
             replica_id: msg.replica_id,
             raft_group,
             committed_term: 0, // TODO: init committed term
             node_ids: vec![self.node_id],
-            proposals: GroupProposalQueue::new(msg.replica_id),
+            proposals: GroupProposalQueue::with_max_pending(
+                msg.replica_id,
+                self.max_pending_proposals,
+            )
+            .with_max_client_pending(self.max_client_pending_proposals),
             leader: ReplicaDesc::default(),
+            quiesced: false,
+            idle_ticks: 0,
+            witnesses,
+            observer: self.observer,
+            last_leader_contact: None,
+            leaderless_ticks: 0,
+            skipped_ticks: 0,
+            stalled_ticks: 0,
+            last_tick_commit: 0,
+            unhealthy: None,
+            commit_watch: None,
+            applied_watch: None,
+            peer_applied: HashMap::new(),
+            reported_applied: 0,
+            quorum_applied_watch: None,
+            role_watch: None,
+            leader_priorities: HashMap::new(),
+            counters: GroupCounters::default(),
+            state: GroupState::Uninitialized,
+            state_tx: self.group_state_tx.clone(),
+            auto_promote: None,
+            caught_up_ticks: HashMap::new(),
+            auto_promoted: HashSet::new(),
+            leader_ticks: 0,
+            correlation_ids: CorrelationIds::default(),
+            log_size: stored_log_size(&gs)?,
+            read_index_proposals: HashMap::new(),
+            log_pinned_by: None,
+            apply_index: applied,
+            snapshotting: false,
+            unadvanced_snapshot: 0,
+            leadership_term: 0,
+            contact_ticks: 0,
+            extended_ticks: 0,
+            removed_replicas: desc.removed_replicas.into_iter().collect(),
+            allocated_replica_id: desc.allocated_replica_id,
+            startup_delay_ticks: startup_jitter_ticks(self.startup_election_jitter),
+            unpersisted_ready: None,
+            write_failures: 0,
         };
+        group.transition(GroupState::Initializing)?;
+        group.transition(GroupState::Running)?;
         self.groups.insert(msg.group_id, group);
+        self.restore_applied_state(msg.group_id, snapshot).await;
+
+        if msg.campaign {
+            self.campaign_single_voter(msg.group_id);
+        }
 
         Ok(())
     }
 
+    /// Campaign right away if the replica is the only voter of the group, so
+    /// it becomes leader without waiting an election timeout. It's skipped in
+    /// the multi-voter group, where campaigning on initialization by every
+    /// replica could cause split votes.
+    fn campaign_single_voter(&mut self, group_id: u64) {
+        let group = self.groups.get_mut(&group_id).unwrap();
+        let cs = group.raft_group.raft.prs().conf().to_conf_state();
+        if cs.voters != [group.replica_id]
+            || !cs.voters_outgoing.is_empty()
+            || group.observer
+        {
+            warn!(
+                "group {} replica {} skip campaign on initial, voters {:?}",
+                group_id, group.replica_id, cs.voters
+            );
+            return;
+        }
+
+        if let Err(err) = group.raft_group.campaign() {
+            error!("group {} campaign on initial error: {}", group_id, err);
+        }
+        propose_leader_epoch(&self.leader_hook, &mut self.pending_events, group);
+    }
+
+    /// Verify the stored conf state of the group with its snapshot and the
+    /// committed conf changes before the raft of the group is created from the
+    /// storage. Returns the conf state if it is repaired.
+    async fn reconcile_conf_state(
+        &self,
+        group_id: u64,
+        gs: &RaftStorageImpl<RS>,
+        rs: &RaftState,
+    ) -> Result<Option<ConfState>, Error> {
+        let snapshot_metadata = self
+            .storage
+            .snapshot_metadata(group_id)
+            .await
+            .map_err(|err| Error::Store(err))?;
+        let first_index = gs.first_index().map_err(|err| Error::Store(err))?;
+        let commit = rs.hard_state.commit;
+        let committed = || {
+            if commit < first_index {
+                return Ok(vec![]);
+            }
+            gs.entries(first_index, commit.saturating_add(1), raft::util::NO_LIMIT)
+                .map_err(|err| Error::Store(err))
+        };
+
+        let repaired = reconcile_conf_state(
+            group_id,
+            &rs.conf_state,
+            &snapshot_metadata,
+            first_index,
+            committed,
+        )?;
+        if let Some(cs) = repaired.as_ref() {
+            warn!(
+                "node {}: group {} repair the lost conf state to {:?} of the snapshot at {}",
+                self.node_id, group_id, cs, snapshot_metadata.index
+            );
+            gs.set_confstate(cs.clone())
+                .await
+                .map_err(|err| Error::Store(err))?;
+        }
+        Ok(repaired)
+    }
+
     /// Create a replica of the raft consensus group on this node.
     #[tracing::instrument(name = "MultiRaftActor::bootstrap_group", skip(self))]
     async fn create_raft_group(&mut self, group_id: u64, replica_id: u64) -> Result<(), Error> {
@@ -663,35 +3137,96 @@ where
             .await
             .map_err(|err| Error::Store(err))?;
 
-        let rs = group_storage
+        let mut rs = group_storage
             .initial_state()
             .map_err(|err| Error::Store(err))?;
 
+        // the stored conf state must agree with the snapshot and the committed
+        // conf changes, otherwise the group would start with the wrong membership.
+        if let Some(cs) = self.reconcile_conf_state(group_id, &group_storage, &rs).await? {
+            rs.conf_state = cs;
+        }
+
         let voters = rs.conf_state.voters;
 
-        let applied = 0;
+        // the applied state starts from the snapshot in the storage, the
+        // entries after it are applied again.
+        let snapshot = RaftStorage::snapshot(&group_storage, 0).map_err(|err| Error::Store(err))?;
+        let applied = snapshot.get_metadata().index;
         let raft_cfg = raft::Config {
             id: replica_id,
             applied,
-            election_tick: self.election_tick,
+            // raft requires election_tick <= min_election_tick < max_election_tick.
+            election_tick: self.election_tick_range.0,
+            min_election_tick: self.election_tick_range.0,
+            max_election_tick: self.election_tick_range.1 + 1,
             heartbeat_tick: self.heartbeat_tick,
-            max_size_per_msg: 1024 * 1024,
+            max_size_per_msg: MAX_SIZE_PER_MSG,
             max_inflight_msgs: 256,
+            max_committed_size_per_ready: self.max_committed_size_per_ready,
+            max_uncommitted_size: self.max_uncommitted_size,
             ..Default::default()
         };
 
-        let raft_store = group_storage.clone();
+        let raft_store = group_storage.clone().with_entry_cache(self.entry_cache_size);
         let raft_group = raft::RawNode::with_default_logger(&raft_cfg, raft_store)
             .map_err(|err| Error::RaftGroup(err))?;
 
+        let desc = self
+            .storage
+            .group_desc(group_id)
+            .await
+            .map_err(|err| Error::Store(err))?;
+        let witnesses = desc.witnesses.into_iter().collect::<HashSet<u64>>();
+
         let mut group = RaftGroup {
             group_id,
             replica_id,
             raft_group,
             node_ids: Vec::new(),
-            proposals: GroupProposalQueue::new(replica_id),
+            proposals: GroupProposalQueue::with_max_pending(replica_id, self.max_pending_proposals)
+                .with_max_client_pending(self.max_client_pending_proposals),
             leader: ReplicaDesc::default(), // TODO: init leader from storage
             committed_term: 0,              // TODO: init committed term from storage
+            quiesced: false,
+            idle_ticks: 0,
+            witnesses,
+            observer: self.observer,
+            last_leader_contact: None,
+            leaderless_ticks: 0,
+            skipped_ticks: 0,
+            stalled_ticks: 0,
+            last_tick_commit: 0,
+            unhealthy: None,
+            commit_watch: None,
+            applied_watch: None,
+            peer_applied: HashMap::new(),
+            reported_applied: 0,
+            quorum_applied_watch: None,
+            role_watch: None,
+            leader_priorities: HashMap::new(),
+            counters: GroupCounters::default(),
+            state: GroupState::Uninitialized,
+            state_tx: self.group_state_tx.clone(),
+            auto_promote: None,
+            caught_up_ticks: HashMap::new(),
+            auto_promoted: HashSet::new(),
+            leader_ticks: 0,
+            correlation_ids: CorrelationIds::default(),
+            log_size: stored_log_size(&group_storage)?,
+            read_index_proposals: HashMap::new(),
+            log_pinned_by: None,
+            apply_index: applied,
+            snapshotting: false,
+            unadvanced_snapshot: 0,
+            leadership_term: 0,
+            contact_ticks: 0,
+            extended_ticks: 0,
+            removed_replicas: desc.removed_replicas.into_iter().collect(),
+            allocated_replica_id: desc.allocated_replica_id,
+            startup_delay_ticks: startup_jitter_ticks(self.startup_election_jitter),
+            unpersisted_ready: None,
+            write_failures: 0,
         };
 
         for voter_id in voters.iter() {
@@ -716,11 +3251,41 @@ where
             group.node_ids.push(replica_desc.node_id);
             self.node_manager.add_node(replica_desc.node_id, group_id);
         }
+        group.transition(GroupState::Initializing)?;
+        group.transition(GroupState::Running)?;
         self.groups.insert(group_id, group);
+        self.restore_applied_state(group_id, snapshot).await;
 
         Ok(())
     }
 
+    /// Restore the applied state of the new replica from the snapshot in its
+    /// storage, e.g. the dedup table, before the entries after it are applied.
+    async fn restore_applied_state(&mut self, group_id: u64, snapshot: Snapshot) {
+        if snapshot.get_metadata().index == 0 {
+            return;
+        }
+        let group = self.groups.get_mut(&group_id).unwrap();
+        let mut apply =
+            MultiRaftActor::<MI, T, RS, MRS>::create_apply(group.replica_id, group, vec![]);
+        apply.snapshot = Some(snapshot);
+        self.send_apply_task(group_id, ApplyTask::Apply(apply))
+            .await;
+    }
+
+    async fn send_apply_task(&mut self, group_id: u64, task: ApplyTask) {
+        let mut groups = HashMap::new();
+        groups.insert(group_id, task);
+        if let Err(_error) = self
+            .apply_actor_address
+            .tx
+            .send(ApplyTaskRequest { groups })
+            .await
+        {
+            warn!("group {} apply task receiver dropped", group_id);
+        }
+    }
+
     // fn add_node(&mut self, node_id: u64, group_id: u64) {
     //     let node = match self.nodes.get_mut(&node_id) {
     //         None => {
@@ -745,37 +3310,260 @@ where
         &mut self,
         request: AppWriteRequest,
         tx: oneshot::Sender<Result<(), Error>>,
+    ) {
+        self.propose_or_forward(request, tx, 0);
+    }
+
+    /// Propose the write to the group, if the local replica is a follower and
+    /// `proposal_forwarding` is enabled, the write is forwarded to the known
+    /// leader and the result is relayed back. The write is failed with
+    /// `NotLeader` if the leader is unknown or the write has been forwarded
+    /// `MAX_FORWARD_HOPS` times.
+    fn propose_or_forward(
+        &mut self,
+        request: AppWriteRequest,
+        tx: oneshot::Sender<Result<(), Error>>,
+        hops: u32,
     ) {
         let group_id = request.group_id;
-        let group = self.groups.get_mut(&group_id).unwrap();
-        group.write_propose(request, tx);
+        let group = match self.groups.get_mut(&group_id) {
+            None => {
+                let _ = tx.send(Err(Error::RaftGroupNotFound(group_id)));
+                return;
+            }
+            Some(group) => group,
+        };
+        if let Err(err) = group
+            .check_poisoned()
+            .and_then(|_| group.check_draining())
+            .and_then(|_| group.check_frozen())
+        {
+            let _ = tx.send(Err(err));
+            return;
+        }
+        group.wake();
+
+        let leader = group.leader.clone();
+        if !self.proposal_forwarding
+            || group.is_leader()
+            || leader.replica_id != group.raft_group.raft.leader_id
+            || leader.node_id == NO_NODE
+            || leader.node_id == self.node_id
+            || hops >= forward::MAX_FORWARD_HOPS
+        {
+            if group.is_leader()
+                && self.max_apply_backlog != 0
+                && group.apply_backlog() >= self.max_apply_backlog
+            {
+                let err = ProposalError::ApplyBacklogFull(self.max_apply_backlog);
+                let _ = tx.send(Err(Error::Proposal(err)));
+                return;
+            }
+            if group.is_leader()
+                && self.max_log_entries != 0
+                && self.has_state_machine
+                && group.log_entries() >= self.max_log_entries
+            {
+                // the log is full once the compaction can't advance its first
+                // index, the least matched replica pins it if it's behind the
+                // applied index.
+                let raft_log = &group.raft_group.raft.raft_log;
+                let (applied, first_index) = (raft_log.applied, raft_log.first_index());
+                let compactable = group.log_compact_index(applied) > first_index;
+                let pinning = group
+                    .least_matched_replica()
+                    .filter(|(_, matched)| *matched < applied);
+                if !compactable {
+                    if let Some((replica_id, matched)) = pinning {
+                        if group.log_pinned_by != Some(replica_id) {
+                            warn!(
+                                "group {} log reaches the limit {} pinned by replica {} matched {}",
+                                group_id, self.max_log_entries, replica_id, matched
+                            );
+                            group.log_pinned_by = Some(replica_id);
+                            self.pending_events.push(Event::LogPinned(LogPinnedEvent {
+                                group_id,
+                                replica_id,
+                                matched,
+                                log_entries: group.log_entries(),
+                            }));
+                        }
+                    }
+                    let err = ProposalError::LogFull(self.max_log_entries);
+                    let _ = tx.send(Err(Error::Proposal(err)));
+                    return;
+                }
+            }
+            group.write_propose(request, tx);
+            return;
+        }
+
+        let id = self.proposal_forwards.register(group_id, group.replica_id, tx);
+        let msg = forward::forward_message(self.node_id, leader.node_id, id, request, hops + 1);
+        if let Err(err) =
+            transport::send_raft_message(self.transport.as_ref(), &self.dropped_messages, msg)
+        {
+            self.proposal_forwards.fail(id, err);
+        }
+    }
+
+    /// Propose the write forwarded from `from_node`, the result is relayed
+    /// back with the commit index of the local replica after it's applied.
+    fn handle_forwarded_proposal(&mut self, from_node: u64, forward: ForwardedProposal) {
+        let request = forward.request.unwrap_or_default();
+        let group_id = request.group_id;
+        let commit_rx = self.groups.get_mut(&group_id).map(|group| group.watch_commit());
+        let (tx, rx) = oneshot::channel();
+        self.propose_or_forward(request, tx, forward.hops);
+
+        let node_id = self.node_id;
+        let response_tx = self.forward_response_tx.clone();
+        tokio::spawn(async move {
+            let res = match rx.await {
+                Err(_) => Err(Error::Proposal(ProposalError::Other(
+                    "the forwarded proposal is dropped".into(),
+                ))),
+                Ok(res) => res,
+            };
+            let commit_index = commit_rx.map_or(0, |rx| *rx.borrow());
+            let msg = forward::response_message(group_id, node_id, from_node, forward.id, res, commit_index);
+            let _ = response_tx.send(msg);
+        });
+    }
+
+    /// Relay the result of the forwarded proposal to the proposer, the success
+    /// is relayed after the local commit index reaches the commit index of the
+    /// leader, so that the `CommitToken` taken on this node covers the write.
+    fn handle_forwarded_response(&mut self, group_id: u64, response: ForwardedProposalResponse) {
+        let (tx, res) = match self.proposal_forwards.complete(&response) {
+            None => return,
+            Some(completed) => completed,
+        };
+
+        let mut commit_rx = match (&res, self.groups.get_mut(&group_id)) {
+            (Ok(_), Some(group)) => group.watch_commit(),
+            _ => {
+                let _ = tx.send(res);
+                return;
+            }
+        };
+        let commit_index = response.commit_index;
+        // the local commit index may stall, e.g. the node is partitioned from
+        // the leader, the response is replied anyway after the forward timeout.
+        let timeout = self.proposal_forwards.timeout();
+        tokio::spawn(async move {
+            let wait = async {
+                while *commit_rx.borrow() < commit_index {
+                    if commit_rx.changed().await.is_err() {
+                        break;
+                    }
+                }
+            };
+            if tokio::time::timeout(timeout, wait).await.is_err() {
+                warn!(
+                    "group {} forwarded proposal isn't committed locally to index {} within {:?}",
+                    group_id, commit_index, timeout
+                );
+            }
+            let _ = tx.send(res);
+        });
     }
 
     fn handle_read_index_request(
         &mut self,
         request: AppReadIndexRequest,
-        tx: oneshot::Sender<Result<(), Error>>,
+        tx: oneshot::Sender<Result<u64, Error>>,
+        activity_groups: &mut HashSet<u64>,
     ) {
         let group_id = request.group_id;
-        let group = self.groups.get_mut(&group_id).unwrap();
+        let group = match self.groups.get_mut(&group_id) {
+            None => {
+                let _ = tx.send(Err(Error::RaftGroupNotFound(group_id)));
+                return;
+            }
+            Some(group) => group,
+        };
+        if let Err(err) = group.check_poisoned().and_then(|_| group.check_frozen()) {
+            let _ = tx.send(Err(err));
+            return;
+        }
+        group.wake();
         group.read_index_propose(request, tx);
+        // the single voter confirms the read index at once.
+        activity_groups.insert(group_id);
+    }
+
+    fn handle_membership_change_request(
+        &mut self,
+        data: MembershipChangeData,
+        tx: oneshot::Sender<Result<(), Error>>,
+        activity_groups: &mut HashSet<u64>,
+    ) {
+        let group_id = data.group_id;
+        let group = match self.groups.get_mut(&group_id) {
+            Some(group) => group,
+            None => {
+                let _ = tx.send(Err(Error::BadParameter(format!(
+                    "group ({}) not found",
+                    group_id
+                ))));
+                return;
+            }
+        };
+        // the draining group still accepts the membership changes, so the
+        // drained replica can be removed.
+        if let Err(err) = group.check_poisoned() {
+            let _ = tx.send(Err(err));
+            return;
+        }
+        // the id of the removed replica can't be reused.
+        if let Some(change) = data.changes.iter().find(|change| {
+            change.change_type() != crate::proto::ConfChangeType::RemoveNode
+                && group.removed_replicas.contains(&change.replica_id)
+        }) {
+            let _ = tx.send(Err(Error::DuplicateReplica(group_id, change.replica_id)));
+            return;
+        }
+        group.wake();
+        group.membership_change_propose(data, tx);
+        activity_groups.insert(group_id);
     }
 
     async fn handle_apply_task_response(&mut self, response: ApplyTaskResponse) {
+        let mut proposals = vec![];
         for (group_id, results) in response.groups {
             let group = match self.groups.get_mut(&group_id) {
                 Some(group) => group,
                 None => {
                     warn!("group {} removed, skip apply", group_id);
+                    fail_snapshot_results(group_id, results, Error::RaftGroupNotFound);
                     continue;
                 }
             };
+            if group.is_poisoned() {
+                fail_snapshot_results(group_id, results, Error::GroupPoisoned);
+                continue;
+            }
 
-            for res in results {
+            let mut poisoned = None;
+            let mut results = results.into_iter();
+            for res in results.by_ref() {
                 match res {
+                    ApplyResult::Failed(reason) => {
+                        poisoned = Some(reason);
+                        break;
+                    }
+                    ApplyResult::Propose(request, tx) => proposals.push((request, tx)),
+                    #[cfg(feature = "serde-config")]
+                    ApplyResult::GroupConfig(config, tx) => {
+                        info!("group {} set config {:?} by admin entry", group_id, config);
+                        group.set_config(config, self.max_pending_proposals);
+                        tx.map(|tx| tx.send(Ok(())));
+                    }
                     ApplyResult::MembershipChange(result) => {
                         MultiRaftActor::<MI, T, RS, MRS>::apply_membership_change(
                             group,
+                            &self.storage,
                             &mut self.node_manager,
                             &mut self.replica_cache,
                             self.sync_replica_cache,
@@ -783,23 +3571,107 @@ where
                         )
                         .await;
                     }
+                    ApplyResult::Snapshot(task, data) => {
+                        MultiRaftActor::<MI, T, RS, MRS>::complete_snapshot(
+                            group,
+                            &self.storage,
+                            task,
+                            data,
+                        )
+                        .await;
+                    }
                 }
             }
 
-            group.raft_group.advance_apply();
+            if let Some(reason) = poisoned {
+                fail_snapshot_results(group_id, results, Error::GroupPoisoned);
+                self.poison_group(group_id, reason);
+                continue;
+            }
+
+            group.raft_group.advance_apply();
+            self.watermark_groups.insert(group_id);
+            group.maybe_auto_leave_joint();
+        }
+
+        // the follow-up writes of the state machine are proposed after the
+        // applied indexes advance, like the writes of `MultiRaft::write`.
+        for (request, tx) in proposals {
+            if let Err(err) = check_entry_size(&request, self.max_entry_size) {
+                let _ = tx.send(Err(err));
+                continue;
+            }
+            self.propose_or_forward(request, tx, 0);
+        }
+    }
+
+    /// Mark the group as poisoned after a panic in handling it, the poisoned
+    /// group is no longer ticked or driven, its pending and new proposals are
+    /// failed with `GroupPoisoned` until it's removed and reinitialized.
+    fn poison_group(&mut self, group_id: u64, reason: String) {
+        let group = match self.groups.get_mut(&group_id) {
+            None => return,
+            Some(group) => group,
+        };
+        if group.is_poisoned() {
+            return;
         }
+        error!("group {} is poisoned: {}", group_id, reason);
+        let _ = group.transition(GroupState::Failed);
+        group.unpersisted_ready = None;
+        fail_poisoned_proposals(group_id, group.proposals.take_all());
+        self.pending_events
+            .push(Event::GroupFailed(GroupFailedEvent { group_id, reason }));
     }
 
+    /// Apply the membership change to the raft group, then update the
+    /// `ConfState` in storage and the replica cache in one step. If the
+    /// change enters the joint consensus, the `ConfState` with outgoing
+    /// voters is persisted, so the interrupted change can be recovered
+    /// and left by the leader after restart.
     async fn apply_membership_change(
         group: &mut RaftGroup<RS>,
+        storage: &MRS,
         node_mgr: &mut NodeManager,
         replica_cache: &mut ReplicaCache<RS, MRS>,
         sync_replica_cache: bool,
         result: MembershipChangeResult,
     ) {
+        group.wake();
+        // the replicas are tracked only if raft accepts the change, so the
+        // rejected change leaves no stale node or replica desc behind.
+        let cs = match group.raft_group.apply_conf_change(&result.conf_change) {
+            Ok(cs) => cs,
+            Err(err) => {
+                error!(
+                    "group {} apply conf change at index {} error: {}",
+                    group.group_id, result.index, err
+                );
+                return;
+            }
+        };
+
+        let witnesses = group.witnesses.clone();
+        let removed_replicas = group.removed_replicas.clone();
         for change in result.changes.iter() {
             match change.change_type() {
-                crate::proto::ConfChangeType::AddNode => {
+                crate::proto::ConfChangeType::AddNode
+                | crate::proto::ConfChangeType::AddLearnerNode => {
+                    if change.witness {
+                        group.witnesses.insert(change.replica_id);
+                    } else if group.witnesses.remove(&change.replica_id)
+                        && change.replica_id == group.replica_id
+                    {
+                        // the witness is promoted, request a full snapshot from leader.
+                        let committed = group.raft_group.raft.raft_log.committed;
+                        if let Err(err) = group.raft_group.request_snapshot(committed) {
+                            warn!(
+                                "group {} promoted witness {} request snapshot error: {}",
+                                group.group_id, group.replica_id, err
+                            );
+                        }
+                    }
+                    group.removed_replicas.remove(&change.replica_id);
                     let replica_metadata = ReplicaDesc {
                         node_id: change.node_id,
                         replica_id: change.replica_id,
@@ -810,15 +3682,63 @@ where
                         .await
                         .unwrap();
                 }
-                crate::proto::ConfChangeType::RemoveNode => unimplemented!(),
-                crate::proto::ConfChangeType::AddLearnerNode => unimplemented!(),
+                crate::proto::ConfChangeType::RemoveNode => {
+                    group.witnesses.remove(&change.replica_id);
+                    group.removed_replicas.insert(change.replica_id);
+                    node_mgr.remove_group(change.node_id, change.group_id);
+                }
             }
         }
 
-        let cs = group
-            .raft_group
-            .apply_conf_change(&result.conf_change)
+        if witnesses != group.witnesses || removed_replicas != group.removed_replicas {
+            match storage.group_desc(group.group_id).await {
+                Ok(mut desc) => {
+                    desc.witnesses = group.witnesses.iter().cloned().collect();
+                    desc.removed_replicas = group.removed_replicas.iter().cloned().collect();
+                    desc.removed_replicas.sort_unstable();
+                    if let Err(err) = storage.set_group_desc(group.group_id, desc).await {
+                        error!(
+                            "group {} save witnesses and removed replicas error: {}",
+                            group.group_id, err
+                        );
+                    }
+                }
+                Err(err) => error!("group {} load group desc error: {}", group.group_id, err),
+            }
+        }
+
+        // the leader which removed itself hands over the leadership to the most
+        // up-to-date remaining voter right away, rather than leaving the group
+        // to wait for the election timeout after it stops heartbeating.
+        if group.is_leader()
+            && !cs.voters.contains(&group.replica_id)
+            && !cs.voters_outgoing.contains(&group.replica_id)
+        {
+            match group.leader_transferee() {
+                Some(transferee) => {
+                    info!(
+                        "group {} removed leader {} transfer leader to replica {}",
+                        group.group_id, group.replica_id, transferee
+                    );
+                    group.raft_group.transfer_leader(transferee);
+                }
+                None => warn!(
+                    "group {} removed leader {} has no up-to-date voter to transfer to",
+                    group.group_id, group.replica_id
+                ),
+            }
+        }
+
+        let gs = storage
+            .group_storage(group.group_id, group.replica_id)
+            .await
             .unwrap();
+        if let Err(err) = gs.set_confstate(transmute_raft_conf_state(cs)).await {
+            error!(
+                "group {} save conf state at index {} error: {}",
+                group.group_id, result.index, err
+            );
+        }
     }
 
     pub(crate) async fn on_groups_ready(&mut self, activity_groups: &HashSet<u64>) {
@@ -841,90 +3761,25 @@ where
             //     Some(group) => group,
             // };
 
-            if let Some(group) = self.groups.get_mut(group_id) {
-                if !group.raft_group.has_ready() {
-                    continue;
-                }
-
-                let mut group_ready = group.raft_group.ready();
-
-                // we need to know which replica in raft group is ready.
-                let replica_id = match self.storage.replica_for_node(*group_id, self.node_id).await
-                {
-                    Err(error) => {
-                        error!(
-                            "write is error, got {} group replica  of storage error {}",
-                            group_id, error
-                        );
-                        continue;
-                    }
-                    Ok(replica_desc) => match replica_desc {
-                        Some(replica_desc) => replica_desc.replica_id,
-                        None => {
-                            // if we can't look up the replica in storage, but the group is ready,
-                            // we know that one of the replicas must be ready, so we can repair the
-                            // storage to store this replica.
-                            let replica_id = group.raft_group.raft.id;
-                            // TODO: store replica id
-                            replica_id
-                        }
-                    },
-                };
-
-                if let Some(ss) = group_ready.ss() {
-                    if ss.leader_id != 0 && ss.leader_id != group.leader.replica_id {
-                        let replica_desc = self
-                            .replica_cache
-                            .replica_desc(*group_id, ss.leader_id)
-                            .await
-                            .unwrap()
-                            .unwrap();
-                        group.leader = replica_desc;
-                        self.pending_events
-                            .push(Event::LederElection(LeaderElectionEvent {
-                                group_id: *group_id,
-                                leader_id: ss.leader_id,
-                                committed_term: group.committed_term,
-                            }))
-                    }
-                }
-
-                // send out messages
-                if !group_ready.messages().is_empty() {
-                    transport::send_messages(
-                        self.node_id,
-                        &self.storage,
-                        &self.transport,
-                        &mut self.node_manager,
-                        *group_id,
-                        transmute_raft_messages(group_ready.take_messages()),
-                    )
-                    .await;
-                }
-
-                // make apply task if need to apply commit entries
-                if !group_ready.committed_entries().is_empty() {
-                    let last_term = group_ready.committed_entries()
-                        [group_ready.committed_entries().len() - 1]
-                        .term;
-                    group.maybe_update_committed_term(last_term);
-
-                    let entries = transmute_raft_entries(group_ready.take_committed_entries());
-                    let apply =
-                        MultiRaftActor::<MI, T, RS, MRS>::create_apply(replica_id, group, entries);
-
-                    apply_task_groups.insert(*group_id, ApplyTask::Apply(apply));
+            if !self.groups.contains_key(group_id) {
+                continue;
+            }
+            // a panic in handling the ready of a group poisons the group only,
+            // the other groups keep running.
+            let res = AssertUnwindSafe(self.handle_group_ready(
+                *group_id,
+                &mut apply_task_groups,
+                &mut ready_write_groups,
+            ))
+            .catch_unwind()
+            .await;
+            if let Err(payload) = res {
+                // the proposals taken by the apply task are failed as well.
+                if let Some(ApplyTask::Apply(apply)) = apply_task_groups.remove(group_id) {
+                    fail_poisoned_proposals(*group_id, apply.proposals);
                 }
-
-                // make write task if need to write disk.
-                ready_write_groups.insert(
-                    *group_id,
-                    GroupWriteRequest {
-                        replica_id,
-                        ready: Some(group_ready),
-                        light_ready: None,
-                    },
-                );
+                ready_write_groups.remove(group_id);
+                self.poison_group(*group_id, panic_message(payload.as_ref()));
             }
         }
 
@@ -941,59 +3796,538 @@ where
         self.handle_write_finish(gwrs).await;
     }
 
+    /// Take the ready of the group, send the messages which don't depend on
+    /// the persistence, make the apply task and the write request of it.
+    async fn handle_group_ready(
+        &mut self,
+        group_id: u64,
+        apply_task_groups: &mut HashMap<u64, ApplyTask>,
+        ready_write_groups: &mut HashMap<u64, GroupWriteRequest>,
+    ) {
+        let group = match self.groups.get_mut(&group_id) {
+            None => return,
+            Some(group) => group,
+        };
+        if group.is_poisoned() {
+            return;
+        }
+
+        // the ready which fails to be persisted is retried before the next
+        // ready is taken, raft never advances past the unpersisted entries.
+        if let Some(unpersisted) = group.unpersisted_ready.as_ref() {
+            if unpersisted.retry_at > self.clock.now() {
+                return;
+            }
+            let unpersisted = group.unpersisted_ready.take().unwrap();
+            ready_write_groups.insert(
+                group_id,
+                GroupWriteRequest {
+                    replica_id: group.replica_id,
+                    ready: Some(unpersisted.ready),
+                    light_ready: None,
+                    persisted: false,
+                    unpersisted_last_index: unpersisted.last_index,
+                    unsynced: false,
+                },
+            );
+            return;
+        }
+
+        if !group.raft_group.has_ready() {
+            return;
+        }
+
+        group.counters.readies += 1;
+        let mut group_ready = group.raft_group.ready();
+        after_ready_stage(&self.ready_hook, group_id, ReadyStage::Ready);
+
+        // we need to know which replica in raft group is ready.
+        let replica_id = match self.storage.replica_for_node(group_id, self.node_id).await {
+            Err(error) => {
+                error!(
+                    "write is error, got {} group replica  of storage error {}",
+                    group_id, error
+                );
+                return;
+            }
+            Ok(replica_desc) => match replica_desc {
+                Some(replica_desc) => replica_desc.replica_id,
+                None => {
+                    // if we can't look up the replica in storage, but the group is ready,
+                    // we know that one of the replicas must be ready, so we can repair the
+                    // storage to store this replica.
+                    let replica_id = group.raft_group.raft.id;
+                    // TODO: store replica id
+                    replica_id
+                }
+            },
+        };
+
+        if !group_ready.read_states().is_empty() {
+            group.handle_read_states(group_ready.take_read_states());
+        }
+
+        if let Some(ss) = group_ready.ss() {
+            // the unconfirmed read index requests are dropped by raft.
+            group.fail_read_index_proposals();
+            // the indexes of the correlated entries may be re-proposed by the
+            // new leader.
+            group.correlation_ids.clear();
+            // the replica metadata of the group led by this node is hot, so
+            // it's never evicted from the cache.
+            let is_leader = ss.raft_state == raft::StateRole::Leader;
+            self.replica_cache.set_pinned(group_id, is_leader);
+            self.local_leaders.update(group_id, is_leader);
+            group.update_role_watch();
+            if ss.leader_id != 0 && ss.leader_id != group.leader.replica_id {
+                // the node of the leader is unknown until its replica desc is
+                // cached, e.g. the proposals aren't forwarded to it.
+                group.leader = match self
+                    .replica_cache
+                    .replica_desc(group_id, ss.leader_id)
+                    .await
+                {
+                    Ok(Some(replica_desc)) => replica_desc,
+                    res => {
+                        warn!(
+                            "group {} replica desc of leader {} not found: {:?}",
+                            group_id, ss.leader_id, res
+                        );
+                        ReplicaDesc {
+                            node_id: NO_NODE,
+                            replica_id: ss.leader_id,
+                        }
+                    }
+                };
+                group.leader_ticks = 0;
+                // the new leader is confirmed when it's learned.
+                group.last_leader_contact = Some(self.clock.now());
+                self.pending_events
+                    .push(Event::LederElection(LeaderElectionEvent {
+                        group_id,
+                        leader_id: ss.leader_id,
+                        committed_term: group.committed_term,
+                    }))
+            }
+        }
+
+        // send out messages
+        if !group_ready.messages().is_empty() {
+            let mut msgs = transmute_raft_messages(group_ready.take_messages());
+            group.strip_witness_snapshots(&mut msgs);
+            let applied = reported_applied(self.report_applied_index, group);
+            transport::send_messages(
+                self.node_id,
+                &self.storage,
+                &mut self.outbox,
+                &mut self.node_manager,
+                &mut self.outgoing_snapshots,
+                group_id,
+                applied,
+                &group.correlation_ids,
+                msgs,
+            )
+            .await;
+            after_ready_stage(&self.ready_hook, group_id, ReadyStage::SendMessages);
+        }
+
+        // the installed snapshot replaces the applied state of the group, it's
+        // restored before the committed entries after it are applied.
+        let snapshot = (*group_ready.snapshot() != raft::prelude::Snapshot::default())
+            .then(|| transmute_raft_snapshot(group_ready.snapshot().clone()));
+
+        // make apply task if need to apply commit entries
+        if !group_ready.committed_entries().is_empty() || snapshot.is_some() {
+            if let Some(entry) = group_ready.committed_entries().last() {
+                group.maybe_update_committed_term(entry.term);
+            }
+            if let Some(snapshot) = snapshot.as_ref() {
+                group.apply_index = snapshot.get_metadata().index;
+            }
+
+            let entries = transmute_raft_entries(group_ready.take_committed_entries());
+            let mut apply =
+                MultiRaftActor::<MI, T, RS, MRS>::create_apply(replica_id, group, entries);
+            apply.snapshot = snapshot;
+
+            apply_task_groups.insert(group_id, ApplyTask::Apply(apply));
+            after_ready_stage(&self.ready_hook, group_id, ReadyStage::Apply);
+        }
+
+        // make write task if need to write disk.
+        ready_write_groups.insert(
+            group_id,
+            GroupWriteRequest {
+                replica_id,
+                ready: Some(group_ready),
+                light_ready: None,
+                persisted: false,
+                unpersisted_last_index: None,
+                unsynced: false,
+            },
+        );
+    }
+
     async fn handle_write(
         &mut self,
         mut ready_write_groups: HashMap<u64, GroupWriteRequest>,
     ) -> HashMap<u64, GroupWriteRequest> {
         // TODO(yuanchang.xu) Disk write flow control
         // let mut light_readys = HashMap::new();
+        let defer_sync = !self.write_sync_batch_latency.is_zero();
+        let mut poisoned = vec![];
+        if self.ready_workers.is_some() && ready_write_groups.len() > 1 {
+            self.write_group_readies_concurrently(
+                &mut ready_write_groups,
+                defer_sync,
+                &mut poisoned,
+            )
+            .await;
+        } else {
+            self.write_group_readies(&mut ready_write_groups, defer_sync, &mut poisoned)
+                .await;
+        }
+
+        for (group_id, reason) in poisoned {
+            ready_write_groups.remove(&group_id);
+            self.poison_group(group_id, reason);
+        }
+
+        ready_write_groups
+    }
+
+    /// Persist the readies of the groups one by one in the actor. With
+    /// `defer_sync`, the groups written are synced in batches bounded by
+    /// `write_sync_batch_latency`.
+    async fn write_group_readies(
+        &mut self,
+        ready_write_groups: &mut HashMap<u64, GroupWriteRequest>,
+        defer_sync: bool,
+        poisoned: &mut Vec<(u64, String)>,
+    ) {
+        // the groups written with `defer_sync` since the last sync, and the
+        // time of the first write of them.
+        let mut unsynced = vec![];
+        let mut batch_start = None;
+        let group_ids = ready_write_groups.keys().cloned().collect::<Vec<_>>();
+        for group_id in group_ids {
+            let group_write_request = ready_write_groups.get_mut(&group_id).unwrap();
+            let res =
+                AssertUnwindSafe(self.write_group_ready(group_id, group_write_request, defer_sync))
+                    .catch_unwind()
+                    .await;
+            match res {
+                Err(payload) => poisoned.push((group_id, panic_message(payload.as_ref()))),
+                Ok(Some(reason)) => poisoned.push((group_id, reason)),
+                Ok(None) => {}
+            }
+            if group_write_request.unsynced {
+                unsynced.push(group_id);
+                batch_start.get_or_insert(self.clock.now());
+            }
+
+            let elapsed = batch_start.map_or(Duration::ZERO, |start| {
+                self.clock.now().saturating_duration_since(start)
+            });
+            if !unsynced.is_empty() && elapsed >= self.write_sync_batch_latency {
+                let batch = std::mem::take(&mut unsynced);
+                self.sync_group_writes(ready_write_groups, batch, poisoned)
+                    .await;
+                batch_start = None;
+            }
+        }
+        if !unsynced.is_empty() {
+            self.sync_group_writes(ready_write_groups, unsynced, poisoned)
+                .await;
+        }
+    }
+
+    /// Persist the readies of the groups on the ready workers concurrently,
+    /// the write batches are taken and the written readies are finished in the
+    /// actor in the same way as `write_group_ready`. With `defer_sync`, the
+    /// groups written are synced together after all the workers are done.
+    async fn write_group_readies_concurrently(
+        &mut self,
+        ready_write_groups: &mut HashMap<u64, GroupWriteRequest>,
+        defer_sync: bool,
+        poisoned: &mut Vec<(u64, String)>,
+    ) {
+        let mut readies = Vec::with_capacity(ready_write_groups.len());
+        let mut writes = vec![];
         for (group_id, group_write_request) in ready_write_groups.iter_mut() {
-            let group = self.groups.get_mut(&group_id).unwrap();
-            let gs = match self
-                .storage
-                .group_storage(*group_id, group_write_request.replica_id)
-                .await
-            {
-                Ok(gs) => gs,
-                Err(error) => {
+            let res = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                self.take_ready_write(*group_id, group_write_request, defer_sync)
+            }));
+            match res {
+                Err(payload) => poisoned.push((*group_id, panic_message(payload.as_ref()))),
+                Ok((ready, write)) => {
+                    readies.push((*group_id, ready));
+                    writes.extend(write);
+                }
+            }
+        }
+
+        let mut results = self.ready_workers.as_ref().unwrap().write(writes).await;
+        let mut unsynced = vec![];
+        for (group_id, ready) in readies {
+            let group_write_request = ready_write_groups.get_mut(&group_id).unwrap();
+            let written = match results.remove(&group_id) {
+                None => None,
+                Some(Ok(res)) => Some(res),
+                Some(Err(reason)) => {
+                    poisoned.push((group_id, reason));
                     continue;
                 }
             };
-
-            let mut ready = group_write_request.ready.take().unwrap();
-            if *ready.snapshot() != raft::prelude::Snapshot::default() {
-                let snapshot = ready.snapshot().clone();
-                if let Err(_error) = gs.apply_snapshot(transmute_raft_snapshot(snapshot)) {}
+            let res = AssertUnwindSafe(self.complete_group_write(
+                group_id,
+                group_write_request,
+                ready,
+                written,
+                defer_sync,
+            ))
+            .catch_unwind()
+            .await;
+            match res {
+                Err(payload) => poisoned.push((group_id, panic_message(payload.as_ref()))),
+                Ok(Some(reason)) => poisoned.push((group_id, reason)),
+                Ok(None) => {}
             }
-
-            if !ready.entries().is_empty() {
-                let entries = ready.take_entries();
-                if let Err(_error) = gs.append_entries(transmute_raft_entries_ref(&entries)) {}
+            if group_write_request.unsynced {
+                unsynced.push(group_id);
             }
+        }
+        if !unsynced.is_empty() {
+            self.sync_group_writes(ready_write_groups, unsynced, poisoned)
+                .await;
+        }
+    }
+
+    /// Persist the ready of the group and send the persisted messages, then
+    /// advance the ready. The ready which fails to be persisted is not advanced,
+    /// so raft never treats the unpersisted entries as stable, it's retried
+    /// with backoff. Returns the reason if the group should be poisoned after
+    /// `storage_write_retries` failures.
+    ///
+    /// If `defer_sync`, the ready is written without fsync and kept in the
+    /// request, it's finished by `sync_group_writes` after the batch is synced.
+    async fn write_group_ready(
+        &mut self,
+        group_id: u64,
+        group_write_request: &mut GroupWriteRequest,
+        defer_sync: bool,
+    ) -> Option<String> {
+        let (ready, write) = self.take_ready_write(group_id, group_write_request, defer_sync);
+        let written = match write {
+            None => None,
+            Some(write) => Some(write.storage.write_ready(write.batch).await),
+        };
+        self.complete_group_write(group_id, group_write_request, ready, written, defer_sync)
+            .await
+    }
+
+    /// Take the ready of the group and make the write batch of it, the write
+    /// is `None` if nothing needs to be persisted.
+    fn take_ready_write(
+        &mut self,
+        group_id: u64,
+        group_write_request: &mut GroupWriteRequest,
+        defer_sync: bool,
+    ) -> (Ready, Option<ReadyWrite<RS>>) {
+        let group = self.groups.get_mut(&group_id).unwrap();
+        // write through the store of raft group, so that the entry cache of
+        // it is populated.
+        let gs = group.raft_group.store().clone();
+
+        let mut ready = group_write_request.ready.take().unwrap();
+        let mut batch = WriteBatch::default();
+        if *ready.snapshot() != raft::prelude::Snapshot::default() {
+            batch.snapshot = Some(transmute_raft_snapshot(ready.snapshot().clone()));
+        }
+
+        if !ready.entries().is_empty() {
+            batch.entries = transmute_raft_entries(ready.take_entries());
+        } else if let Some(last_index) = group_write_request.unpersisted_last_index {
+            batch.entries = transmute_raft_entries(group.unstable_entries_to(last_index));
+        }
+        group.strip_witness_entries(&mut batch.entries);
+
+        if let Some(hs) = ready.hs() {
+            batch.hard_state = Some(transmute_raft_hard_state(hs.clone()));
+        }
+
+        if let Some(snapshot) = batch.snapshot.as_ref() {
+            group.log_size.clear(snapshot.get_metadata().index + 1);
+        }
+        group.log_size.append(&batch.entries);
+
+        if batch.is_empty() {
+            return (ready, None);
+        }
+        // the entries are unpersisted until the write is finished.
+        let last_index = batch.entries.last().map(|entry| entry.index);
+        group_write_request.unpersisted_last_index =
+            last_index.or(group_write_request.unpersisted_last_index);
+        batch.defer_sync = defer_sync;
+        let write = ReadyWrite {
+            group_id,
+            storage: gs,
+            batch,
+        };
+        (ready, Some(write))
+    }
 
-            if let Some(hs) = ready.hs() {
-                let hs = transmute_raft_hard_state(hs.clone());
-                if let Err(_error) = gs.set_hardstate(hs) {}
+    /// The ready is `written` (`None` if nothing is persisted), finish it or
+    /// fail it to be retried. Returns the reason if the group should be
+    /// poisoned.
+    async fn complete_group_write(
+        &mut self,
+        group_id: u64,
+        group_write_request: &mut GroupWriteRequest,
+        ready: Ready,
+        written: Option<Result<(), StorageError>>,
+        defer_sync: bool,
+    ) -> Option<String> {
+        match written {
+            Some(Err(err)) => {
+                return self.fail_group_write(group_id, group_write_request, ready, &err)
+            }
+            Some(Ok(())) if defer_sync => {
+                group_write_request.ready = Some(ready);
+                group_write_request.unsynced = true;
+                return None;
             }
+            _ => {}
+        }
+        self.finish_group_write(group_id, group_write_request, ready)
+            .await;
+        None
+    }
 
-            if !ready.persisted_messages().is_empty() {
-                let persistent_msgs = ready.take_persisted_messages();
-                transport::send_messages(
-                    self.node_id,
-                    &self.storage,
-                    &self.transport,
-                    &mut self.node_manager,
-                    *group_id,
-                    transmute_raft_messages(persistent_msgs),
-                )
-                .await;
+    /// Sync the readies of the groups written with `defer_sync` by one
+    /// `MultiRaftStorage::sync_writes`, then finish them. If the sync fails,
+    /// the readies are retried like the ones failed to be written.
+    async fn sync_group_writes(
+        &mut self,
+        ready_write_groups: &mut HashMap<u64, GroupWriteRequest>,
+        group_ids: Vec<u64>,
+        poisoned: &mut Vec<(u64, String)>,
+    ) {
+        let res = self.storage.sync_writes().await;
+        if let Err(err) = res.as_ref() {
+            error!(
+                "node {} sync the writes of {} groups error: {}",
+                self.node_id,
+                group_ids.len(),
+                err
+            );
+        }
+        for group_id in group_ids {
+            let group_write_request = ready_write_groups.get_mut(&group_id).unwrap();
+            group_write_request.unsynced = false;
+            let ready = group_write_request.ready.take().unwrap();
+            let reason = match res.as_ref() {
+                Err(err) => self.fail_group_write(group_id, group_write_request, ready, err),
+                Ok(()) => {
+                    match AssertUnwindSafe(self.finish_group_write(
+                        group_id,
+                        group_write_request,
+                        ready,
+                    ))
+                    .catch_unwind()
+                    .await
+                    {
+                        Err(payload) => Some(panic_message(payload.as_ref())),
+                        Ok(()) => None,
+                    }
+                }
+            };
+            if let Some(reason) = reason {
+                poisoned.push((group_id, reason));
             }
+        }
+    }
 
-            let light_ready = group.raft_group.advance(ready);
-            group_write_request.light_ready = Some(light_ready);
+    /// The ready is persisted, send the persisted messages and advance it.
+    async fn finish_group_write(
+        &mut self,
+        group_id: u64,
+        group_write_request: &mut GroupWriteRequest,
+        mut ready: Ready,
+    ) {
+        let group = self.groups.get_mut(&group_id).unwrap();
+        group.write_failures = 0;
+        group_write_request.persisted = true;
+        after_ready_stage(&self.ready_hook, group_id, ReadyStage::Persist);
+
+        // the persisted messages (e.g. the vote and append responses) are
+        // sent only after the entries and hard state are durable.
+        if !ready.persisted_messages().is_empty() {
+            let mut persistent_msgs = transmute_raft_messages(ready.take_persisted_messages());
+            group.strip_witness_snapshots(&mut persistent_msgs);
+            let applied = reported_applied(self.report_applied_index, group);
+            transport::send_messages(
+                self.node_id,
+                &self.storage,
+                &mut self.outbox,
+                &mut self.node_manager,
+                &mut self.outgoing_snapshots,
+                group_id,
+                applied,
+                &group.correlation_ids,
+                persistent_msgs,
+            )
+            .await;
+            after_ready_stage(&self.ready_hook, group_id, ReadyStage::SendPersistedMessages);
         }
 
-        ready_write_groups
+        let light_ready = group.raft_group.advance(ready);
+        group_write_request.light_ready = Some(light_ready);
+    }
+
+    /// The ready failed to be persisted, it's retried after the backoff.
+    /// Returns the reason if the group should be poisoned after
+    /// `storage_write_retries` failures.
+    fn fail_group_write(
+        &mut self,
+        group_id: u64,
+        group_write_request: &GroupWriteRequest,
+        mut ready: Ready,
+        err: &StorageError,
+    ) -> Option<String> {
+        let group = self.groups.get_mut(&group_id).unwrap();
+        group.write_failures += 1;
+        error!(
+            "group {} write ready error ({} failures): {}",
+            group_id, group.write_failures, err
+        );
+        self.pending_events
+            .push(Event::GroupStorageError(GroupStorageErrorEvent {
+                group_id,
+                error: err.to_string(),
+                failures: group.write_failures,
+            }));
+
+        if group.write_failures > self.storage_write_retries {
+            // the persisted messages (e.g. the vote and append responses)
+            // are never sent because the ready is never persisted.
+            for m in transmute_raft_messages(ready.take_persisted_messages()) {
+                self.dropped_messages.record(
+                    DropReason::NotPersisted,
+                    &DroppedMessage::from_message(group_id, self.node_id, NO_NODE, &m),
+                );
+            }
+            return Some(format!("write ready error: {}", err));
+        }
+
+        let backoff = self.storage_write_retry_backoff
+            * (1 << (group.write_failures - 1).min(MAX_WRITE_BACKOFF_SHIFT));
+        group.unpersisted_ready = Some(UnpersistedReady {
+            ready,
+            last_index: group_write_request.unpersisted_last_index,
+            retry_at: self.clock.now() + Duration::from_millis(backoff),
+        });
+        None
     }
 
     async fn handle_write_finish(&mut self, ready_groups: HashMap<u64, GroupWriteRequest>) {
@@ -1004,7 +4338,11 @@ where
                 Some(g) => g,
             };
 
-            let mut light_ready = gwr.light_ready.take().unwrap();
+            // the ready which fails to be persisted is not advanced.
+            let mut light_ready = match gwr.light_ready.take() {
+                None => continue,
+                Some(light_ready) => light_ready,
+            };
             let group_storage = self
                 .storage
                 .group_storage(group_id, gwr.replica_id)
@@ -1012,20 +4350,32 @@ where
                 .unwrap();
 
             if let Some(commit) = light_ready.commit_index() {
-                group_storage.set_commit(commit);
+                if let Err(err) = group_storage.set_commit(commit).await {
+                    error!("group {} save commit {} error: {}", group_id, commit, err);
+                }
+            }
+
+            if !light_ready.messages().is_empty() && !gwr.persisted {
+                light_ready.take_messages();
             }
 
             if !light_ready.messages().is_empty() {
-                let messages = light_ready.take_messages();
+                let mut messages = transmute_raft_messages(light_ready.take_messages());
+                mut_group.strip_witness_snapshots(&mut messages);
+                let applied = reported_applied(self.report_applied_index, mut_group);
                 transport::send_messages(
                     self.node_id,
                     &self.storage,
-                    &self.transport,
+                    &mut self.outbox,
                     &mut self.node_manager,
+                    &mut self.outgoing_snapshots,
                     group_id,
-                    transmute_raft_messages(messages),
+                    applied,
+                    &mut_group.correlation_ids,
+                    messages,
                 )
                 .await;
+                after_ready_stage(&self.ready_hook, group_id, ReadyStage::SendPersistedMessages);
             }
 
             if !light_ready.committed_entries().is_empty() {
@@ -1041,7 +4391,10 @@ where
                 );
 
                 apply_task_groups.insert(group_id, ApplyTask::Apply(apply));
+                after_ready_stage(&self.ready_hook, group_id, ReadyStage::Apply);
             }
+
+            self.watermark_groups.insert(group_id);
         }
 
         if !apply_task_groups.is_empty() {
@@ -1058,7 +4411,7 @@ where
         let current_term = group.raft_group.raft.term;
         let commit_index = group.raft_group.raft.raft_log.committed;
         let mut proposals = VecDeque::new();
-        if !proposals.is_empty() {
+        if !group.proposals.is_empty() {
             for entry in entries.iter() {
                 match group
                     .proposals
@@ -1080,6 +4433,9 @@ where
             .iter()
             .map(|ent| ent.compute_size() as usize)
             .sum::<usize>();
+        if let Some(entry) = entries.last() {
+            group.apply_index = entry.index;
+        }
         Apply {
             replica_id,
             group_id: group.group_id,
@@ -1089,6 +4445,268 @@ where
             entries,
             entries_size,
             proposals,
+            witness: group.is_witness(),
+            snapshot: None,
+        }
+    }
+}
+
+#[inline]
+fn coalesced_message(
+    from_node: u64,
+    to_node: u64,
+    msg_type: MessageType,
+    heartbeats: Vec<CoalescedHeartbeat>,
+) -> RaftMessage {
+    let mut raft_msg = Message::default();
+    raft_msg.set_msg_type(msg_type);
+    RaftMessage {
+        group_id: NO_GORUP,
+        from_node,
+        to_node,
+        msg: Some(raft_msg),
+        heartbeats,
+        snapshot_chunk: None,
+        snapshot_chunk_ack: None,
+        snapshot_checksum: 0,
+        forward_proposal: None,
+        forward_response: None,
+        heartbeat_sent_at: 0,
+        reset_replica: None,
+        correlation_ids: vec![],
+    }
+}
+
+/// Report the replica which the message failed to be sent to as unreachable,
+/// so that raft stops flooding it with appends until it responds. The failed
+/// snapshot is reported as failure, so that it can be resent.
+fn report_send_failure<RS: RaftStorage>(
+    group: &mut RaftGroup<RS>,
+    snapshots: &mut OutgoingSnapshots,
+    to_replica: u64,
+    is_snapshot: bool,
+) {
+    group.raft_group.report_unreachable(to_replica);
+    if is_snapshot {
+        group
+            .raft_group
+            .report_snapshot(to_replica, raft::SnapshotStatus::Failure);
+        snapshots.finish_inflight(group.group_id, to_replica);
+    }
+}
+
+/// Returns the applied index reported in the heartbeat responses of the group,
+/// 0 if the report is disabled.
+#[inline]
+fn reported_applied<RS: RaftStorage>(report: bool, group: &RaftGroup<RS>) -> u64 {
+    if report {
+        group.raft_group.raft.raft_log.applied
+    } else {
+        0
+    }
+}
+
+/// Propose the leadership epoch entry right after the no-op and emit the
+/// `LeaderEpoch` event if the step, tick or campaign has just made the
+/// replica the leader, so the writes proposed since then land after both.
+fn propose_leader_epoch<RS: RaftStorage>(
+    hook: &Option<Arc<dyn LeaderHook>>,
+    pending_events: &mut Vec<Event>,
+    group: &mut RaftGroup<RS>,
+) {
+    let (term, noop_index) = match group.take_new_leadership() {
+        Some(leadership) => leadership,
+        None => return,
+    };
+    let group_id = group.group_id;
+    let epoch_index = hook
+        .as_ref()
+        .and_then(|hook| hook.on_leader(group_id, term))
+        .and_then(|data| group.propose_epoch(&data));
+    pending_events.push(Event::LeaderEpoch(LeaderEpochEvent {
+        group_id,
+        term,
+        noop_index,
+        epoch_index,
+    }));
+}
+
+#[inline]
+fn after_ready_stage(hook: &Option<Arc<dyn ReadyHook>>, group_id: u64, stage: ReadyStage) {
+    if let Some(hook) = hook.as_ref() {
+        hook.after_stage(group_id, stage);
+    }
+}
+
+/// Fail the snapshots of the apply results which are dropped, e.g. the group
+/// is removed or poisoned.
+fn fail_snapshot_results(
+    group_id: u64,
+    results: impl IntoIterator<Item = ApplyResult>,
+    err: fn(u64) -> Error,
+) {
+    for res in results {
+        if let ApplyResult::Snapshot(task, _) = res {
+            task.target.fail(err(group_id));
         }
     }
 }
+
+fn fail_poisoned_proposals(group_id: u64, proposals: impl IntoIterator<Item = Proposal>) {
+    for proposal in proposals {
+        proposal
+            .tx
+            .map(|tx| tx.send(Err(Error::GroupPoisoned(group_id))));
+    }
+}
+
+/// Returns `Ok` if the initialization of the existing group is a retry of the
+/// same one, i.e. the replica id and the replicas of `msg` are the same as the
+/// group's, otherwise `GroupConflict`. The replicas of `msg` are compared with
+/// the voters and learners of the group.
+fn check_initial_group_retry<RS: RaftStorage>(
+    group: &RaftGroup<RS>,
+    msg: &RaftGroupManagementMessage,
+) -> Result<(), Error> {
+    if msg.replica_id != group.replica_id {
+        return Err(Error::GroupConflict(
+            msg.group_id,
+            format!(
+                "replica {} exists, but replica {} is initialized",
+                group.replica_id, msg.replica_id
+            ),
+        ));
+    }
+
+    let mut expected = msg
+        .replicas
+        .iter()
+        .map(|replica| replica.replica_id)
+        .collect::<HashSet<_>>();
+    if expected.is_empty() {
+        if let Some(snapshot) = msg.snapshot.as_ref() {
+            let cs = snapshot.get_metadata().get_conf_state();
+            expected.extend(cs.voters.iter().chain(cs.learners.iter()));
+        }
+    }
+    if expected.is_empty() {
+        return Ok(());
+    }
+
+    let cs = group.raft_group.raft.prs().conf().to_conf_state();
+    let existing = cs
+        .voters
+        .iter()
+        .chain(cs.learners.iter())
+        .cloned()
+        .collect::<HashSet<_>>();
+    if expected != existing {
+        return Err(Error::GroupConflict(
+            msg.group_id,
+            format!(
+                "replicas {:?} exist, but replicas {:?} are initialized",
+                existing, expected
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns true if nothing is written to the storage of the replica, e.g. it's
+/// just created.
+fn is_empty_storage<RS: RaftStorage>(gs: &RS) -> Result<bool, Error> {
+    let rs = gs.initial_state().map_err(|err| Error::Store(err))?;
+    let last_index = gs.last_index().map_err(|err| Error::Store(err))?;
+    Ok(last_index == 0
+        && rs.hard_state == HardState::default()
+        && rs.conf_state == ConfState::default())
+}
+
+/// Returns the size of the entries in the log of the storage, e.g. the ones
+/// persisted before the restart, see `LogSize`.
+fn stored_log_size<RS: RaftStorage>(gs: &RS) -> Result<LogSize, Error> {
+    let first_index = gs.first_index().map_err(|err| Error::Store(err))?;
+    let last_index = gs.last_index().map_err(|err| Error::Store(err))?;
+    let mut log_size = LogSize::default();
+    log_size.clear(first_index);
+    let mut low = first_index;
+    while low <= last_index {
+        // at least one entry is returned by each read.
+        let entries = gs
+            .entries(low, last_index + 1, MAX_SIZE_PER_MSG)
+            .map_err(|err| Error::Store(err))?;
+        low += entries.len() as u64;
+        log_size.append(&entries);
+    }
+    Ok(log_size)
+}
+
+/// Seed the storage of the replica which is initialized by `msg` with the
+/// snapshot, e.g. restored from a backup. The conf state of the snapshot must contain the
+/// replica and match the replicas of `msg` if they are given, and the storage
+/// must be empty.
+async fn restore_group_storage<RS: RaftStorage>(
+    msg: &RaftGroupManagementMessage,
+    gs: &RS,
+    snapshot: Snapshot,
+) -> Result<(), Error> {
+    let meta = snapshot.get_metadata();
+    if meta.index == 0 {
+        return Err(Error::BadParameter(format!(
+            "the snapshot to restore group {} is empty",
+            msg.group_id
+        )));
+    }
+
+    let cs = meta.get_conf_state();
+    let mut members = cs
+        .voters
+        .iter()
+        .chain(cs.learners.iter())
+        .cloned()
+        .collect::<Vec<_>>();
+    members.sort();
+    if !members.contains(&msg.replica_id) {
+        return Err(Error::BadParameter(format!(
+            "replica {} is not in the conf state {:?} of the snapshot to restore group {}",
+            msg.replica_id, members, msg.group_id
+        )));
+    }
+    if !msg.replicas.is_empty() {
+        let mut replicas = msg
+            .replicas
+            .iter()
+            .map(|replica| replica.replica_id)
+            .collect::<Vec<_>>();
+        replicas.sort();
+        if replicas != members {
+            return Err(Error::BadParameter(format!(
+                "the replicas {:?} mismatch the conf state {:?} of the snapshot to restore group {}",
+                replicas, members, msg.group_id
+            )));
+        }
+    }
+
+    if gs.last_index().map_err(|err| Error::Store(err))? != 0 {
+        return Err(Error::BadParameter(format!(
+            "the storage of group {} is not empty, it can't be restored from the snapshot",
+            msg.group_id
+        )));
+    }
+
+    gs.apply_snapshot(snapshot)
+        .await
+        .map_err(|err| Error::Store(err))
+}
+
+/// Returns the message of the panic payload, which is the argument of the
+/// `panic!` in most cases.
+pub(super) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
@@ -8,6 +8,7 @@ use crate::proto::RaftMessageResponse;
 
 use super::error::Error;
 use super::multiraft_actor::MultiRaftActorAddress;
+use super::raft_group::InitResult;
 use super::transport::MessageInterface;
 
 pub struct MultiRaftMessageSender {
@@ -23,19 +24,32 @@ impl Clone for MultiRaftMessageSender {
 }
 
 impl MultiRaftMessageSender {
-    pub async fn initial_raft_group(&self, msg: RaftGroupManagementMessage) -> Result<(), Error> {
+    pub(crate) fn new(actor_address: MultiRaftActorAddress) -> Self {
+        Self { actor_address }
+    }
+
+    pub async fn initial_raft_group(
+        &self,
+        msg: RaftGroupManagementMessage,
+    ) -> Result<InitResult, Error> {
         assert_eq!(
             msg.msg_type(),
             RaftGroupManagementMessageType::MsgInitialGroup
         );
         let (tx, rx) = oneshot::channel();
-        if let Err(_error) = self.actor_address.manager_group_tx.send((msg, tx)).await {
-            panic!("manager group receiver dropped")
+        if let Err(_error) = self
+            .actor_address
+            .initial_groups_tx
+            .send((vec![msg], tx))
+            .await
+        {
+            panic!("initial groups receiver dropped")
         }
 
-        match rx.await {
+        match rx.await.map(|mut results| results.pop()) {
             Err(_error) => panic!("sender dopped"),
-            Ok(res) => res,
+            Ok(None) => panic!("initial group result lost"),
+            Ok(Some((_, res))) => res,
         }
     }
 }
@@ -1,17 +1,33 @@
 // nodes: HashMap<u64, Node>,
 // groups: HashMap<u64, RaftGroup<RS>>,
 
-use std::collections::HashMap;
+use std::collections::hash_map::HashMap;
 use std::collections::hash_map::Iter;
+use std::collections::HashSet;
 
-use prost::encoding::group;
+use crate::proto::CoalescedHeartbeat;
+
+use super::multiraft::NO_GORUP;
 
 /// Node represents a physical node and contains a group of rafts.
 pub struct Node {
     pub node_id: u64,
-    pub group_map: HashMap<u64, ()>,
+    pub group_map: HashSet<u64>,
+    /// Heartbeats of groups waiting to be coalesced and sent to this node.
+    pub heartbeats: Vec<CoalescedHeartbeat>,
+    /// Heartbeat responses of groups waiting to be coalesced and sent to this node.
+    pub heartbeat_responses: Vec<CoalescedHeartbeat>,
+    /// The stamp of the latest heartbeat received from this node, which is
+    /// echoed by the next coalesced heartbeat response, 0 if there is none.
+    pub heartbeat_echo: u64,
+    /// The tick pass of the latest message received from this node, None if
+    /// there is none yet.
+    pub contact_pass: Option<u64>,
 }
 
+/// NodeManager maintains a `node_id -> Set<group_id>` reverse index, so that
+/// the groups which have a replica on a node can be found without scanning
+/// the conf state of every group.
 pub struct NodeManager {
     pub nodes: HashMap<u64, Node>,
 }
@@ -25,7 +41,7 @@ impl NodeManager {
 
     #[inline]
     pub fn iter(&self) -> Iter<'_, u64, Node> {
-       self.nodes.iter()
+        self.nodes.iter()
     }
 
     #[inline]
@@ -38,22 +54,117 @@ impl NodeManager {
         self.nodes.get(node_id)
     }
 
+    #[inline]
+    pub fn get_mut_node(&mut self, node_id: &u64) -> Option<&mut Node> {
+        self.nodes.get_mut(node_id)
+    }
+
+    /// Track the node and record that the node hosts a replica of `group_id`.
+    /// If `group_id` is `NO_GORUP`, only the node itself is tracked.
     pub fn add_node(&mut self, node_id: u64, group_id: u64) {
-        let node = match self.nodes.get_mut(&node_id) {
-            None => {
-                self.nodes.insert(
-                    node_id,
-                    Node {
-                        node_id,
-                        group_map: HashMap::new(),
-                    },
-                );
-                self.nodes.get_mut(&node_id).unwrap()
-            }
-            Some(node) => node,
-        };
-
-        assert_ne!(group_id, 0);
-        node.group_map.insert(group_id, ());
+        let node = self.nodes.entry(node_id).or_insert_with(|| Node {
+            node_id,
+            group_map: HashSet::new(),
+            heartbeats: Vec::new(),
+            heartbeat_responses: Vec::new(),
+            heartbeat_echo: 0,
+            contact_pass: None,
+        });
+
+        if group_id != NO_GORUP {
+            node.group_map.insert(group_id);
+        }
+    }
+
+    /// Record that a message is received from the node at the tick pass.
+    pub fn record_contact(&mut self, node_id: u64, pass: u64) {
+        self.add_node(node_id, NO_GORUP);
+        if let Some(node) = self.nodes.get_mut(&node_id) {
+            node.contact_pass = Some(pass);
+        }
     }
+
+    /// Returns true if nothing is received from the node for more than
+    /// `ticks` tick passes. The node which never contacts is not lost, e.g.
+    /// the quiesced group is quiesced by a heartbeat from its leader.
+    pub fn contact_lost(&self, node_id: u64, pass: u64, ticks: usize) -> bool {
+        match self.nodes.get(&node_id).and_then(|node| node.contact_pass) {
+            None => false,
+            Some(contact_pass) => pass.saturating_sub(contact_pass) > ticks as u64,
+        }
+    }
+
+    /// Remove `group_id` from the groups of the node. the node is still
+    /// tracked even if it no longer hosts any group, because the coalesced
+    /// heartbeat is sent at node level.
+    pub fn remove_group(&mut self, node_id: u64, group_id: u64) {
+        if let Some(node) = self.nodes.get_mut(&node_id) {
+            node.group_map.remove(&group_id);
+        }
+    }
+
+    /// Remove `group_id` from all nodes, used when the group is removed
+    /// from this node.
+    pub fn remove_group_from_all(&mut self, group_id: u64) {
+        for (_, node) in self.nodes.iter_mut() {
+            node.group_map.remove(&group_id);
+        }
+    }
+
+    /// Stop tracking the node and return the groups it hosted.
+    pub fn remove_node(&mut self, node_id: u64) -> Vec<u64> {
+        match self.nodes.remove(&node_id) {
+            None => vec![],
+            Some(node) => node.group_map.into_iter().collect(),
+        }
+    }
+
+    /// Returns all groups which have a replica on the node.
+    pub fn groups_on_node(&self, node_id: u64) -> Vec<u64> {
+        match self.nodes.get(&node_id) {
+            None => vec![],
+            Some(node) => node.group_map.iter().cloned().collect(),
+        }
+    }
+}
+
+#[test]
+fn test_node_manager_reverse_index() {
+    let mut mgr = NodeManager::new();
+    mgr.add_node(1, 1);
+    mgr.add_node(1, 2);
+    mgr.add_node(2, 1);
+    mgr.add_node(3, NO_GORUP);
+
+    let mut groups = mgr.groups_on_node(1);
+    groups.sort();
+    assert_eq!(groups, vec![1, 2]);
+    assert_eq!(mgr.groups_on_node(2), vec![1]);
+    assert!(mgr.contains_node(&3));
+    assert!(mgr.groups_on_node(3).is_empty());
+
+    // replica of group 1 removed from node 1
+    mgr.remove_group(1, 1);
+    assert_eq!(mgr.groups_on_node(1), vec![2]);
+
+    // group 2 removed from this node
+    mgr.remove_group_from_all(2);
+    assert!(mgr.groups_on_node(1).is_empty());
+
+    assert_eq!(mgr.remove_node(2), vec![1]);
+    assert!(!mgr.contains_node(&2));
+    assert!(mgr.groups_on_node(4).is_empty());
+}
+
+#[test]
+fn test_node_manager_contact_lost() {
+    let mut mgr = NodeManager::new();
+    // the node which never contacts isn't lost.
+    mgr.add_node(1, 1);
+    assert!(!mgr.contact_lost(1, 100, 3));
+    assert!(!mgr.contact_lost(2, 100, 3));
+
+    mgr.record_contact(1, 10);
+    assert!(!mgr.contact_lost(1, 13, 3));
+    assert!(mgr.contact_lost(1, 14, 3));
 }
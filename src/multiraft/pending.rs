@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use super::clock::Clock;
+
+use crate::proto::RaftMessage;
+
+/// PendingMessages buffers the raft messages of the groups which aren't
+/// created on this node yet, e.g. the leader of a new group reaches the node
+/// before the group is initialized. The messages are replayed after the group
+/// is created, at most `capacity` messages are buffered per group for `ttl`,
+/// and the messages of at most `max_groups` groups are buffered.
+pub struct PendingMessages {
+    capacity: usize,
+    max_groups: usize,
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+    groups: HashMap<u64, VecDeque<(Instant, RaftMessage)>>,
+}
+
+impl PendingMessages {
+    pub fn new(capacity: usize, max_groups: usize, ttl: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            capacity,
+            max_groups,
+            ttl,
+            clock,
+            groups: HashMap::new(),
+        }
+    }
+
+    /// Returns false if the buffering is disabled by the zero capacity.
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.capacity != 0
+    }
+
+    /// Buffer the message of its group, returns the oldest message of the
+    /// group which is evicted if the buffer of the group is full, or the
+    /// message itself if it's of a new group but `max_groups` are buffered.
+    pub fn push(&mut self, msg: RaftMessage) -> Option<RaftMessage> {
+        if !self.groups.contains_key(&msg.group_id) && self.groups.len() >= self.max_groups {
+            return Some(msg);
+        }
+        let pending = self.groups.entry(msg.group_id).or_default();
+        pending.push_back((self.clock.now(), msg));
+        if pending.len() > self.capacity {
+            return pending.pop_front().map(|(_, msg)| msg);
+        }
+        None
+    }
+
+    /// Take the messages buffered for the group in the order they are received.
+    pub fn take(&mut self, group_id: u64) -> Vec<RaftMessage> {
+        self.groups.remove(&group_id).map_or(vec![], |pending| {
+            pending.into_iter().map(|(_, msg)| msg).collect()
+        })
+    }
+
+    /// Remove the messages buffered longer than `ttl`, which are returned.
+    pub fn expire(&mut self) -> Vec<RaftMessage> {
+        let now = self.clock.now();
+        let mut expired = vec![];
+        for pending in self.groups.values_mut() {
+            while let Some((received_at, _)) = pending.front() {
+                if now.saturating_duration_since(*received_at) < self.ttl {
+                    break;
+                }
+                expired.push(pending.pop_front().unwrap().1);
+            }
+        }
+        self.groups.retain(|_, pending| !pending.is_empty());
+        expired
+    }
+
+    /// Returns the number of the buffered messages.
+    pub fn len(&self) -> usize {
+        self.groups.values().map(|pending| pending.len()).sum()
+    }
+}
+
+#[test]
+fn test_pending_messages_overflow_and_expire() {
+    use super::clock::MockClock;
+
+    let clock = MockClock::new();
+    let msg = |group_id: u64, from_node: u64| RaftMessage {
+        group_id,
+        from_node,
+        ..Default::default()
+    };
+    let mut pending = PendingMessages::new(2, 2, Duration::from_secs(60), Arc::new(clock.clone()));
+    assert!(pending.push(msg(1, 1)).is_none());
+    assert!(pending.push(msg(1, 2)).is_none());
+    assert!(pending.push(msg(2, 1)).is_none());
+    // the oldest message of the group is evicted.
+    assert_eq!(pending.push(msg(1, 3)).unwrap().from_node, 1);
+    // the message of the third group is dropped.
+    assert_eq!(pending.push(msg(3, 1)).unwrap().group_id, 3);
+    assert_eq!(pending.len(), 3);
+    assert!(pending.expire().is_empty());
+
+    let nodes = pending
+        .take(1)
+        .iter()
+        .map(|msg| msg.from_node)
+        .collect::<Vec<_>>();
+    assert_eq!(nodes, vec![2, 3]);
+    assert!(pending.take(1).is_empty());
+
+    // the messages are expired after the ttl.
+    pending.push(msg(1, 1));
+    clock.advance(Duration::from_secs(30));
+    pending.push(msg(1, 2));
+    clock.advance(Duration::from_secs(30));
+    assert_eq!(pending.expire().len(), 2);
+    assert_eq!(pending.len(), 1);
+}
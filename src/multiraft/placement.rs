@@ -0,0 +1,121 @@
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use crate::proto::ReplicaDesc;
+
+use super::resolver::NodeResolver;
+
+/// ReplicaPlacer decides which nodes host the replicas of a new group, it's
+/// consulted by `MultiRaft::bootstrap_groups`. The policy, e.g. rack-aware or
+/// capacity-aware placement, is decoupled from the raft machinery.
+pub trait ReplicaPlacer: Send + Sync + 'static {
+    /// Returns the `desired_count` replicas of the group on distinct nodes.
+    fn place(&self, group_id: u64, desired_count: usize) -> Vec<ReplicaDesc>;
+}
+
+/// RoundRobinPlacer places the replicas of each new group on the next nodes
+/// of `nodes` in turn, so the replicas are spread evenly across the nodes.
+/// The replica ids of the group are numbered from 1. It's the default placer
+/// of `MultiRaft`, see `MultiRaftExtensions::replica_placer`.
+pub struct RoundRobinPlacer {
+    nodes: Vec<u64>,
+    // the nodes are read from the resolver on each placement if it's set, so
+    // the nodes joining the cluster are placed on.
+    resolver: Option<Arc<dyn NodeResolver>>,
+    next: AtomicUsize,
+}
+
+impl RoundRobinPlacer {
+    pub fn new(nodes: Vec<u64>) -> Self {
+        Self {
+            nodes,
+            resolver: None,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Place the replicas on the nodes known by the resolver, see
+    /// `NodeResolver::node_ids`.
+    pub fn with_resolver(resolver: Arc<dyn NodeResolver>) -> Self {
+        Self {
+            nodes: vec![],
+            resolver: Some(resolver),
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl ReplicaPlacer for RoundRobinPlacer {
+    /// Returns fewer replicas than `desired_count` if there are not enough
+    /// nodes.
+    fn place(&self, _group_id: u64, desired_count: usize) -> Vec<ReplicaDesc> {
+        let nodes = match self.resolver.as_ref() {
+            None => self.nodes.clone(),
+            Some(resolver) => resolver.node_ids(),
+        };
+        if nodes.is_empty() {
+            return vec![];
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..std::cmp::min(desired_count, nodes.len()))
+            .map(|n| ReplicaDesc {
+                node_id: nodes[(start + n) % nodes.len()],
+                replica_id: n as u64 + 1,
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_round_robin_placer() {
+    let placer = RoundRobinPlacer::new(vec![1, 2, 3, 4]);
+    let nodes = |replicas: Vec<ReplicaDesc>| {
+        replicas
+            .into_iter()
+            .map(|replica| replica.node_id)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(nodes(placer.place(1, 3)), vec![1, 2, 3]);
+    assert_eq!(nodes(placer.place(2, 3)), vec![2, 3, 4]);
+    assert_eq!(nodes(placer.place(3, 3)), vec![3, 4, 1]);
+
+    let replicas = placer.place(4, 3);
+    assert_eq!(
+        replicas
+            .iter()
+            .map(|replica| replica.replica_id)
+            .collect::<Vec<_>>(),
+        vec![1, 2, 3]
+    );
+
+    // the replicas are on distinct nodes.
+    assert_eq!(placer.place(5, 5).len(), 4);
+    assert!(RoundRobinPlacer::new(vec![]).place(6, 3).is_empty());
+}
+
+#[test]
+fn test_round_robin_placer_with_resolver() {
+    use super::resolver::MemNodeResolver;
+    use super::resolver::NodeAddress;
+
+    let resolver = MemNodeResolver::new();
+    let placer = RoundRobinPlacer::with_resolver(Arc::new(resolver.clone()));
+    assert!(placer.place(1, 3).is_empty());
+
+    // the nodes joining the cluster are placed on.
+    for node_id in 1..=3 {
+        resolver.update(NodeAddress {
+            node_id,
+            store_id: node_id,
+            addr: format!("local://{}", node_id),
+        });
+    }
+    let nodes = |replicas: Vec<ReplicaDesc>| {
+        replicas
+            .into_iter()
+            .map(|replica| replica.node_id)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(nodes(placer.place(2, 3)), vec![2, 3, 1]);
+}
@@ -14,8 +14,11 @@ pub struct ReadIndexProposal {
     pub uuid: Uuid,
     pub read_index: Option<u64>,
     pub context: Option<ReadIndexContext>,
-    // if some, the R is sent to client via tx.
-    pub tx: Option<oneshot::Sender<Result<(), Error>>>,
+    // if some, the read index is sent to client via tx.
+    pub tx: Option<oneshot::Sender<Result<u64, Error>>>,
+    // the ticks since the request, it's failed once it exceeds the election
+    // timeout.
+    pub ticks: usize,
 }
 
 const SHRINK_CACHE_CAPACITY: usize = 64;
@@ -28,6 +31,8 @@ pub struct Proposal {
     pub term: u64,
     // true if proposal is conf change type.
     pub is_conf_change: bool,
+    // the client of the idempotent proposal, 0 if it has no client.
+    pub client_id: u64,
     // if some, the R is sent to client via tx.
     pub tx: Option<oneshot::Sender<Result<(), Error>>>,
 }
@@ -36,16 +41,63 @@ pub struct Proposal {
 pub struct GroupProposalQueue {
     pub replica_id: u64,
     pub queue: VecDeque<Proposal>,
+    // the max number of pending proposals, 0 means unlimited.
+    pub max_pending: usize,
+    // the max number of pending proposals of a client, 0 means unlimited.
+    pub max_client_pending: usize,
+    // the number of pending proposals of each client in the queue, which is
+    // maintained on push and removal, so the quota is checked in O(1).
+    client_pending: HashMap<u64, usize>,
 }
 
 impl GroupProposalQueue {
     pub fn new(replica_id: u64) -> Self {
+        Self::with_max_pending(replica_id, 0)
+    }
+
+    pub fn with_max_pending(replica_id: u64, max_pending: usize) -> Self {
         GroupProposalQueue {
             replica_id,
             queue: VecDeque::new(),
+            max_pending,
+            max_client_pending: 0,
+            client_pending: HashMap::new(),
         }
     }
 
+    pub fn with_max_client_pending(mut self, max_client_pending: usize) -> Self {
+        self.max_client_pending = max_client_pending;
+        self
+    }
+
+    /// Returns `ProposalQueueFull` if the number of pending proposals reaches
+    /// the limit, it must be checked before proposing to the raft.
+    pub fn check_capacity(&self) -> Result<(), Error> {
+        if self.max_pending != 0 && self.queue.len() >= self.max_pending {
+            return Err(Error::Proposal(ProposalError::ProposalQueueFull(
+                self.max_pending,
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns `ClientQuotaExceeded` if the number of pending proposals of the
+    /// client reaches the limit, so that a client doesn't monopolize the group.
+    /// The proposals without client are not limited.
+    pub fn check_client_quota(&self, client_id: u64) -> Result<(), Error> {
+        if self.max_client_pending == 0 || client_id == 0 {
+            return Ok(());
+        }
+        let pending = self.client_pending.get(&client_id).copied().unwrap_or(0);
+        if pending >= self.max_client_pending {
+            return Err(Error::Proposal(ProposalError::ClientQuotaExceeded(
+                client_id,
+                self.max_client_pending,
+            )));
+        }
+        Ok(())
+    }
+
     pub fn push(&mut self, proposal: Proposal) -> Result<(), Error> {
         if let Some(last) = self.queue.back() {
             // The term must be increasing among all log entries and the index
@@ -59,10 +111,19 @@ impl GroupProposalQueue {
             }
         }
 
+        if proposal.client_id != 0 {
+            *self.client_pending.entry(proposal.client_id).or_default() += 1;
+        }
         self.queue.push_back(proposal);
         Ok(())
     }
 
+    /// Take all the pending proposals out of the queue, e.g. to fail them.
+    pub fn take_all(&mut self) -> VecDeque<Proposal> {
+        self.client_pending.clear();
+        std::mem::take(&mut self.queue)
+    }
+
     /// Find proposal from the queue front according to the term and index. 
     /// If the proposal (term, ndex) of the queue front is greater than the 
     /// (term, index) parameter, None is returned. 
@@ -78,6 +139,7 @@ impl GroupProposalQueue {
                 return None;
             }
 
+            release_client_pending(&mut self.client_pending, p.client_id);
             Some(p)
         })
     }
@@ -116,6 +178,21 @@ impl GroupProposalQueue {
         self.queue.is_empty()
     }
 
+    /// Remove the proposals whose receiver has been dropped, e.g. the proposal
+    /// is timeout or cancelled by the client. The entry of a removed proposal
+    /// is still applied if it's committed later, but nobody is responded.
+    pub fn remove_cancelled(&mut self) {
+        let client_pending = &mut self.client_pending;
+        self.queue.retain(|p| match p.tx.as_ref() {
+            Some(tx) if tx.is_closed() => {
+                release_client_pending(client_pending, p.client_id);
+                false
+            }
+            _ => true,
+        });
+        self.shrink();
+    }
+
     pub fn shrink(&mut self) {
         if self.queue.capacity() > SHRINK_CACHE_CAPACITY && self.queue.len() < SHRINK_CACHE_CAPACITY
         {
@@ -124,6 +201,15 @@ impl GroupProposalQueue {
     }
 }
 
+fn release_client_pending(client_pending: &mut HashMap<u64, usize>, client_id: u64) {
+    if let Some(pending) = client_pending.get_mut(&client_id) {
+        *pending -= 1;
+        if *pending == 0 {
+            client_pending.remove(&client_id);
+        }
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct ProposalQueueManager {
     groups: HashMap<u64, GroupProposalQueue>,
@@ -205,6 +291,7 @@ fn test_proposal_queue() {
                 index,
                 term,
                 is_conf_change,
+                client_id: 0,
                 tx,
             }),
             result
@@ -239,6 +326,7 @@ fn test_proposal_queue_find() {
             index,
             term,
             is_conf_change,
+            client_id: 0,
             tx,
         })
         .unwrap();
@@ -262,3 +350,116 @@ fn test_proposal_queue_find() {
         assert_eq!(proposal, *result);
     }
 }
+
+#[test]
+fn test_proposal_queue_remove_cancelled() {
+    let mut gq = GroupProposalQueue::new(1);
+    let (tx1, rx1) = oneshot::channel();
+    let (tx2, rx2) = oneshot::channel();
+    gq.push(Proposal {
+        index: 1,
+        term: 1,
+        is_conf_change: false,
+        client_id: 0,
+        tx: Some(tx1),
+    })
+    .unwrap();
+    gq.push(Proposal {
+        index: 2,
+        term: 1,
+        is_conf_change: false,
+        client_id: 0,
+        tx: Some(tx2),
+    })
+    .unwrap();
+
+    // timeout
+    drop(rx1);
+    gq.remove_cancelled();
+    assert_eq!(gq.queue.len(), 1);
+
+    // cancel
+    drop(rx2);
+    gq.remove_cancelled();
+    assert!(gq.is_empty());
+
+    // the removed proposal committed later is not found.
+    assert!(gq.find_proposal(1, 2, 1).unwrap().is_none());
+}
+
+#[test]
+fn test_proposal_queue_max_pending() {
+    let mut gq = GroupProposalQueue::with_max_pending(1, 2);
+    for index in 1..=2 {
+        assert_eq!(gq.check_capacity(), Ok(()));
+        gq.push(Proposal {
+            index,
+            term: 1,
+            is_conf_change: false,
+            client_id: 0,
+            tx: None,
+        })
+        .unwrap();
+    }
+    assert_eq!(
+        gq.check_capacity(),
+        Err(Error::Proposal(ProposalError::ProposalQueueFull(2)))
+    );
+
+    // the applied proposal drains the queue below the limit.
+    assert!(gq.find_proposal(1, 1, 1).unwrap().is_some());
+    assert_eq!(gq.check_capacity(), Ok(()));
+}
+
+#[test]
+fn test_proposal_queue_client_quota() {
+    let mut gq = GroupProposalQueue::new(1).with_max_client_pending(2);
+    for (index, client_id) in [(1, 1), (2, 1), (3, 0), (4, 0), (5, 0)] {
+        assert_eq!(gq.check_client_quota(client_id), Ok(()));
+        gq.push(Proposal {
+            index,
+            term: 1,
+            is_conf_change: false,
+            client_id,
+            tx: None,
+        })
+        .unwrap();
+    }
+    // the other clients and the proposals without client are not limited.
+    assert_eq!(
+        gq.check_client_quota(1),
+        Err(Error::Proposal(ProposalError::ClientQuotaExceeded(1, 2)))
+    );
+    assert_eq!(gq.check_client_quota(2), Ok(()));
+    assert_eq!(gq.check_client_quota(0), Ok(()));
+
+    // the applied proposal of the client releases the quota.
+    assert!(gq.find_proposal(1, 1, 1).unwrap().is_some());
+    assert_eq!(gq.check_client_quota(1), Ok(()));
+
+    // so does the cancelled one, and taking all the proposals.
+    let (tx, rx) = oneshot::channel();
+    gq.push(Proposal {
+        index: 6,
+        term: 1,
+        is_conf_change: false,
+        client_id: 1,
+        tx: Some(tx),
+    })
+    .unwrap();
+    assert!(gq.check_client_quota(1).is_err());
+    drop(rx);
+    gq.remove_cancelled();
+    assert_eq!(gq.check_client_quota(1), Ok(()));
+    gq.push(Proposal {
+        index: 7,
+        term: 1,
+        is_conf_change: false,
+        client_id: 1,
+        tx: None,
+    })
+    .unwrap();
+    assert!(gq.check_client_quota(1).is_err());
+    assert_eq!(gq.take_all().len(), 5);
+    assert!(gq.client_pending.is_empty());
+}
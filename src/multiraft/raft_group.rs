@@ -1,21 +1,248 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::time::Duration;
+use std::time::Instant;
+
+use raft::ProgressState;
 use raft::StateRole;
 use raft::RawNode;
+use raft::Ready;
 use prost::Message;
+use rand::Rng;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::oneshot;
+use tokio::sync::watch;
+use tracing::info;
+use tracing::warn;
 
+use crate::proto::transmute_conf_change_v2;
 use crate::proto::AppWriteRequest;
 use crate::proto::AppReadIndexRequest;
+use crate::proto::CoalescedHeartbeat;
+use crate::proto::ConfChangeTransition;
+use crate::proto::ConfChangeType;
+use crate::proto::MembershipChangeData;
+use crate::proto::MembershipChangeRequest;
+use crate::proto::ProposalContext;
 use crate::proto::ReplicaDesc;
 use crate::storage::RaftStorage;
 use crate::storage::RaftStorageImpl;
 
+use super::conf_state::membership_conf_change;
+use super::config::GroupConfig;
+use super::correlation::CorrelationIds;
+use super::entry;
+use super::multiraft::NO_NODE;
 use super::error::Error;
+use super::event::GroupStateChangedEvent;
+use super::event::UnhealthyReason;
+use super::ids::GroupId;
+use super::ids::ReplicaId;
+use super::log_size::LogSize;
 use super::error::ProposalError;
 use super::error::RaftError;
 use super::proposal::Proposal;
 use super::proposal::ReadIndexProposal;
 use super::proposal::GroupProposalQueue;
 
+/// The role of the local replica in the raft group. raft `StateRole` has
+/// no learner, so the learner is distinguished by the `ConfState`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplicaRole {
+    Leader,
+    Follower,
+    Candidate,
+    PreCandidate,
+    Learner,
+    Witness,
+}
+
+/// The policy of the leadership transfer when the transferee lags behind the leader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferLeaderPolicy {
+    /// The leader first brings the transferee up to date by append (or snapshot),
+    /// then transfers the leadership. The progress is observed via the
+    /// `LeaderTransfer` event.
+    CatchUp,
+    /// Returns `Error::TargetLagging` immediately.
+    RejectLagging,
+}
+
+/// The policy of promoting the caught-up learners of the group to voters
+/// automatically by the leader, see `MultiRaft::set_auto_promote`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde-config", derive(Serialize, Deserialize))]
+pub struct AutoPromotePolicy {
+    /// The learner is caught up if its match index is within `max_lag`
+    /// entries of the commit index of the leader.
+    pub max_lag: u64,
+    /// The learner is promoted after it's caught up for `stable_ticks`
+    /// consecutive ticks, the count restarts once it falls behind, so the
+    /// learner which hovers around `max_lag` isn't promoted.
+    pub stable_ticks: usize,
+}
+
+/// The lifecycle state of the replica of the group on this node, each
+/// transition is emitted as the `GroupStateChanged` event.
+///
+/// The valid transitions are:
+/// - `Uninitialized` -> `Initializing`, the raft of the group is created.
+/// - `Initializing` -> `Running`, the replica is registered to the node.
+/// - `Running` <-> `Quiesced`, the idle group is quiesced until it's woken
+///   by activity.
+/// - `Running` | `Quiesced` -> `Draining`, see `MultiRaft::drain_group`.
+/// - `Running` | `Quiesced` -> `Frozen` -> `Running`, see
+///   `MultiRaft::freeze_group`.
+/// - any state but `Removed` -> `Failed`, the group is poisoned.
+/// - any state but `Removed` -> `Removed`, the replica is removed from this
+///   node, the removed group never changes again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GroupState {
+    Uninitialized,
+    Initializing,
+    Running,
+    Quiesced,
+    Draining,
+    Frozen,
+    Removed,
+    Failed,
+}
+
+impl GroupState {
+    /// Returns true if the group can transit from `self` to `to`.
+    pub fn can_transition_to(self, to: GroupState) -> bool {
+        match (self, to) {
+            (GroupState::Removed, _) => false,
+            (_, GroupState::Failed) | (_, GroupState::Removed) => true,
+            (GroupState::Uninitialized, GroupState::Initializing)
+            | (GroupState::Initializing, GroupState::Running)
+            | (GroupState::Running, GroupState::Quiesced)
+            | (GroupState::Quiesced, GroupState::Running)
+            | (GroupState::Running, GroupState::Draining)
+            | (GroupState::Quiesced, GroupState::Draining)
+            | (GroupState::Running, GroupState::Frozen)
+            | (GroupState::Quiesced, GroupState::Frozen)
+            | (GroupState::Frozen, GroupState::Running) => true,
+            _ => false,
+        }
+    }
+}
+
+/// The replication progress of a peer, it's tracked only on the leader.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplicaProgress {
+    pub replica_id: u64,
+    /// The node of the replica joined from the replica cache, `NO_NODE` if
+    /// it's unknown. The store of the replica isn't tracked by the cache.
+    pub node_id: u64,
+    pub matched: u64,
+    /// The number of committed entries the replica hasn't matched, which is
+    /// the commit index of the leader minus `matched`.
+    pub lag: u64,
+    pub next_idx: u64,
+    /// The applied index reported by the replica, 0 if it's not reported.
+    pub applied: u64,
+    /// The replica is probed rather than replicated after it's reported
+    /// unreachable, the appends to it are paused until it responds.
+    #[serde(with = "serde_progress_state")]
+    pub state: ProgressState,
+    /// True if a snapshot is inflight to the replica.
+    pub snapshot_inflight: bool,
+    /// The EWMA of the heartbeat round trips to the node of the replica, none
+    /// if it's not measured yet.
+    pub rtt: Option<Duration>,
+}
+
+/// A read-only status snapshot of a replica of the raft group.
+#[derive(Debug, Clone)]
+pub struct GroupStatus {
+    pub group_id: GroupId,
+    pub replica_id: ReplicaId,
+    pub role: StateRole,
+    pub state: GroupState,
+    pub term: u64,
+    pub leader_id: ReplicaId,
+    pub commit_index: u64,
+    pub applied_index: u64,
+    /// The number of the committed entries which aren't applied yet, see
+    /// `MultiRaftConfig::max_apply_backlog`.
+    pub apply_backlog: u64,
+    pub voters: Vec<u64>,
+    pub learners: Vec<u64>,
+    /// The progress of peers if the replica is leader, otherwise it's empty.
+    pub progress: Vec<ReplicaProgress>,
+}
+
+/// The result of initializing the local replica of a group.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InitResult {
+    /// The leader of the group known right after the initialization, e.g. the
+    /// single voter which campaigns on initialization, otherwise none.
+    pub leader: Option<ReplicaDesc>,
+    pub replica_id: ReplicaId,
+    pub applied_index: u64,
+}
+
+/// The state of a read served by the replica without going through the
+/// leader, the reader should read the state machine at `applied_index`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadState {
+    pub group_id: GroupId,
+    pub replica_id: ReplicaId,
+    pub applied_index: u64,
+    pub commit_index: u64,
+}
+
+/// The counters of the raft events processed by the group, which are used by
+/// tests to assert the progress without sleeps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GroupCounters {
+    pub ticks: u64,
+    /// The ticks which only advance the election timer of the follower, see
+    /// `MultiRaftConfig::follower_light_tick`.
+    pub light_ticks: u64,
+    /// The ticks withheld from the election timer of the follower, see
+    /// `MultiRaftConfig::election_extension_window`.
+    pub extended_ticks: u64,
+    pub steps: u64,
+    pub readies: u64,
+}
+
+/// CommitToken is returned by the write after it's applied, a read with the
+/// token via `MultiRaft::read_at_least` is guaranteed to observe the write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommitToken {
+    group_id: u64,
+    index: u64,
+}
+
+impl CommitToken {
+    pub(crate) fn new(group_id: u64, index: u64) -> Self {
+        Self { group_id, index }
+    }
+
+    #[inline]
+    pub fn group_id(&self) -> u64 {
+        self.group_id
+    }
+
+    #[inline]
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+}
+
+/// The ready which fails to be persisted, it's retried at `retry_at`.
+pub struct UnpersistedReady {
+    pub ready: Ready,
+    /// The index of the last entry of the ready, the entries are taken by
+    /// the failed write, they're still unstable in the raft log.
+    pub last_index: Option<u64>,
+    pub retry_at: Instant,
+}
+
 /// Represents a replica of a raft group.
 pub struct RaftGroup<RS: RaftStorage> {
     pub group_id: u64,
@@ -26,6 +253,96 @@ pub struct RaftGroup<RS: RaftStorage> {
     pub proposals: GroupProposalQueue,
     pub leader: ReplicaDesc,
     pub committed_term: u64,
+    // the quiesced group is not ticked until it is woken by activity.
+    pub quiesced: bool,
+    // the number of ticks since the last activity of the group.
+    pub idle_ticks: usize,
+    // the replica ids of witnesses in the group.
+    pub witnesses: HashSet<u64>,
+    // the replica is on an observer node, see `MultiRaftConfig::observer`.
+    pub observer: bool,
+    // the time of the last heartbeat or append received from the leader, on
+    // the leader it's the time of the last heartbeat response.
+    pub last_leader_contact: Option<Instant>,
+    // the number of ticks since the group has no leader.
+    pub leaderless_ticks: usize,
+    // the ticks of raft skipped by the light tick, they are replayed once the
+    // heartbeat is missed, or discarded once raft hears from the leader, see
+    // `MultiRaftConfig::follower_light_tick`.
+    pub skipped_ticks: usize,
+    // the number of ticks since the commit index doesn't advance despite proposals.
+    pub stalled_ticks: usize,
+    // the commit index at the last tick.
+    pub last_tick_commit: u64,
+    // the reason if the group has been reported unhealthy.
+    pub unhealthy: Option<UnhealthyReason>,
+    // the watch of commit and applied index, which are created lazily by the
+    // first watcher and dropped after all watchers are dropped.
+    pub commit_watch: Option<watch::Sender<u64>>,
+    pub applied_watch: Option<watch::Sender<u64>>,
+    // the applied index reported by the peers in the heartbeat responses, and
+    // the watch of the index applied by a quorum, which are tracked on the leader.
+    pub peer_applied: HashMap<u64, u64>,
+    pub quorum_applied_watch: Option<watch::Sender<u64>>,
+    // the applied index last reported to the leader by the follower.
+    pub reported_applied: u64,
+    // the watch of the role of the replica, which is created lazily by the
+    // first watcher and dropped after all watchers are dropped.
+    pub role_watch: Option<watch::Sender<StateRole>>,
+    // the leader priority of replicas, the replica not in it has priority 0.
+    pub leader_priorities: HashMap<u64, u64>,
+    pub counters: GroupCounters,
+    // the lifecycle state of the group, it's changed by `transition`.
+    pub state: GroupState,
+    // the transitions of the state are sent to the actor, which emits them
+    // as the `GroupStateChanged` events.
+    pub state_tx: UnboundedSender<GroupStateChangedEvent>,
+    // the replicas removed from the group, their late messages are dropped.
+    pub removed_replicas: HashSet<u64>,
+    // the last replica id allocated by `allocate_replica_id`.
+    pub allocated_replica_id: u64,
+    // the ticks left before the initial election timer starts.
+    pub startup_delay_ticks: usize,
+    // the ticks since the last contact with the leader and the ticks withheld
+    // from the election timer since then, see
+    // `MultiRaftConfig::election_extension_window`.
+    pub contact_ticks: usize,
+    pub extended_ticks: usize,
+    // the ready which fails to be persisted, the group doesn't take the next
+    // ready until it's persisted.
+    pub unpersisted_ready: Option<UnpersistedReady>,
+    // the number of consecutive failures to persist the ready.
+    pub write_failures: usize,
+    // the policy of promoting the caught-up learners, `None` disables it.
+    pub auto_promote: Option<AutoPromotePolicy>,
+    // the number of consecutive ticks each learner has been caught up, and
+    // the learners promoted by the policy, which are tracked on the leader.
+    pub caught_up_ticks: HashMap<u64, usize>,
+    pub auto_promoted: HashSet<u64>,
+    // the number of ticks since the leader known by the replica is elected.
+    pub leader_ticks: usize,
+    // the correlation ids of the entries proposed on this replica, they're
+    // carried by the appends until the entries are applied.
+    pub correlation_ids: CorrelationIds,
+    // the bytes of the entries in the log, see `MultiRaftConfig::max_log_memory`.
+    pub log_size: LogSize,
+    // the read index requests waiting for the read states of raft, keyed by
+    // the uuid carried as the request context.
+    pub read_index_proposals: HashMap<uuid::Uuid, ReadIndexProposal>,
+    // the replica reported by the `LogPinned` event, it's reset after the log
+    // is back under `MultiRaftConfig::max_log_entries`.
+    pub log_pinned_by: Option<u64>,
+    // the index of the last entry sent to the apply actor, the applied state
+    // is at it once the applies in flight are done, see `ApplyTask::Snapshot`.
+    pub apply_index: u64,
+    // true while the snapshot to compact the log is being taken, the
+    // compaction isn't triggered again until it's saved.
+    pub snapshotting: bool,
+    // the index of the latest snapshot since which the applied index doesn't
+    // advance, it's logged once when the compaction is skipped by it.
+    pub unadvanced_snapshot: u64,
+    // the term of which the leadership is taken by `take_new_leadership`.
+    pub leadership_term: u64,
 }
 
 
@@ -38,11 +355,530 @@ where
         self.raft_group.raft.state == StateRole::Leader
     }
 
+    /// Move the group to the state `to` and emit the `GroupStateChanged`
+    /// event, returns `InvalidGroupState` if the transition isn't valid, see
+    /// `GroupState`. It's a no-op if the group is already in the state.
+    pub fn transition(&mut self, to: GroupState) -> Result<(), Error> {
+        let from = self.state;
+        if from == to {
+            return Ok(());
+        }
+        if !from.can_transition_to(to) {
+            return Err(Error::InvalidGroupState(self.group_id, from, to));
+        }
+        self.state = to;
+        let _ = self.state_tx.send(GroupStateChangedEvent {
+            group_id: self.group_id,
+            from,
+            to,
+        });
+        Ok(())
+    }
+
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.state == GroupState::Failed
+    }
+
+    /// Returns `GroupPoisoned` if the group is poisoned.
+    #[inline]
+    pub fn check_poisoned(&self) -> Result<(), Error> {
+        if self.is_poisoned() {
+            return Err(Error::GroupPoisoned(self.group_id));
+        }
+        Ok(())
+    }
+
+    /// Returns `GroupDraining` if the group is drained, see
+    /// `MultiRaft::drain_group`.
+    #[inline]
+    pub fn check_draining(&self) -> Result<(), Error> {
+        if self.state == GroupState::Draining {
+            return Err(Error::GroupDraining(self.group_id));
+        }
+        Ok(())
+    }
+
+    /// Returns `GroupFrozen` if the group is frozen, see
+    /// `MultiRaft::freeze_group`.
+    #[inline]
+    pub fn check_frozen(&self) -> Result<(), Error> {
+        if self.state == GroupState::Frozen {
+            return Err(Error::GroupFrozen(self.group_id));
+        }
+        Ok(())
+    }
+
+    /// Stop serving the proposals and reads, and stop campaigning. Returns the
+    /// voter which the leadership should be transferred to if the replica is
+    /// leader, see `leader_transferee`.
+    pub fn freeze(&mut self) -> Result<Option<u64>, Error> {
+        self.transition(GroupState::Frozen)?;
+        Ok(self.leader_transferee())
+    }
+
+    /// Serve the group again after it's frozen, it's a no-op if the group
+    /// isn't frozen.
+    pub fn unfreeze(&mut self) -> Result<(), Error> {
+        if self.state == GroupState::Frozen {
+            self.transition(GroupState::Running)?;
+            self.wake();
+        }
+        Ok(())
+    }
+
+    /// Stop accepting the proposals, returns the index which is applied after
+    /// the pending proposals are resolved, i.e. the index of the last pending
+    /// proposal or the commit index whose entries may be applying. The group
+    /// which isn't running, e.g. the poisoned one, can't be drained.
+    pub fn drain(&mut self) -> Result<u64, Error> {
+        self.transition(GroupState::Draining)?;
+        let committed = self.raft_group.raft.raft_log.committed;
+        Ok(self
+            .proposals
+            .queue
+            .back()
+            .map_or(committed, |proposal| std::cmp::max(proposal.index, committed)))
+    }
+
+    /// Fail the pending proposals of the drained group with `GroupDraining`,
+    /// returns the number of them.
+    pub fn fail_pending_proposals(&mut self) -> usize {
+        let proposals = self.proposals.take_all();
+        let failed = proposals.len();
+        for proposal in proposals {
+            proposal
+                .tx
+                .map(|tx| tx.send(Err(Error::GroupDraining(self.group_id))));
+        }
+        failed
+    }
+
+    /// Returns the unstable entries of the raft log up to `last_index`.
+    pub fn unstable_entries_to(&self, last_index: u64) -> Vec<raft::prelude::Entry> {
+        self.raft_group
+            .raft
+            .raft_log
+            .unstable_entries()
+            .iter()
+            .take_while(|entry| entry.index <= last_index)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the receiver of the commit index watch of the group.
+    pub fn watch_commit(&mut self) -> watch::Receiver<u64> {
+        let commit = self.raft_group.raft.raft_log.committed;
+        self.commit_watch
+            .get_or_insert_with(|| watch::channel(commit).0)
+            .subscribe()
+    }
+
+    /// Returns the receiver of the applied index watch of the group.
+    pub fn watch_applied(&mut self) -> watch::Receiver<u64> {
+        let applied = self.raft_group.raft.raft_log.applied;
+        self.applied_watch
+            .get_or_insert_with(|| watch::channel(applied).0)
+            .subscribe()
+    }
+
+    /// Publish the commit and applied index to the watchers if they advance.
+    pub fn update_watermarks(&mut self) {
+        let commit = self.raft_group.raft.raft_log.committed;
+        let applied = self.raft_group.raft.raft_log.applied;
+        update_watermark(&mut self.commit_watch, commit);
+        update_watermark(&mut self.applied_watch, applied);
+        self.correlation_ids.applied_to(applied);
+        if self.quorum_applied_watch.is_some() {
+            let quorum_applied = self.quorum_applied();
+            update_watermark(&mut self.quorum_applied_watch, quorum_applied);
+        }
+    }
+
+    /// Returns the receiver of the role watch of the group, the initial value
+    /// is the current role of the replica.
+    pub fn watch_role(&mut self) -> watch::Receiver<StateRole> {
+        let role = self.raft_group.raft.state;
+        self.role_watch
+            .get_or_insert_with(|| watch::channel(role).0)
+            .subscribe()
+    }
+
+    /// Publish the role to the watchers if it changes, the watch without
+    /// watchers is dropped.
+    pub fn update_role_watch(&mut self) {
+        let role = self.raft_group.raft.state;
+        if let Some(tx) = self.role_watch.as_ref() {
+            if tx.receiver_count() == 0 {
+                self.role_watch = None;
+            } else if *tx.borrow() != role {
+                let _ = tx.send(role);
+            }
+        }
+    }
+
+    /// Returns the receiver of the watch of the index applied by a quorum of
+    /// voters, it must be watched on the leader.
+    pub fn watch_quorum_applied(&mut self) -> Result<watch::Receiver<u64>, Error> {
+        self.check_leader()?;
+        let quorum_applied = self.quorum_applied();
+        Ok(self
+            .quorum_applied_watch
+            .get_or_insert_with(|| watch::channel(quorum_applied).0)
+            .subscribe())
+    }
+
+    /// Record the applied index reported by the peer, returns true if it
+    /// advances, then the watermarks should be updated.
+    pub fn record_peer_applied(&mut self, replica_id: u64, applied: u64) -> bool {
+        let reported = self.peer_applied.entry(replica_id).or_insert(0);
+        if *reported < applied {
+            *reported = applied;
+            return true;
+        }
+        false
+    }
+
+    /// Returns true if the replica id is present in the conf state of the
+    /// group, or it was removed from the group, so it can't be added as a new
+    /// replica, otherwise raft would confuse it with the old one.
+    pub fn replica_id_used(&self, replica_id: u64) -> bool {
+        let cs = self.raft_group.raft.prs().conf().to_conf_state();
+        self.removed_replicas.contains(&replica_id)
+            || cs
+                .voters
+                .iter()
+                .chain(cs.learners.iter())
+                .chain(cs.voters_outgoing.iter())
+                .chain(cs.learners_next.iter())
+                .any(|id| *id == replica_id)
+    }
+
+    /// Allocate a replica id which is greater than the ids of the present,
+    /// removed and previously allocated replicas of the group, and the
+    /// `known_max` id, e.g. the max one in the storage.
+    pub fn allocate_replica_id(&mut self, known_max: u64) -> u64 {
+        let cs = self.raft_group.raft.prs().conf().to_conf_state();
+        let max = cs
+            .voters
+            .iter()
+            .chain(cs.learners.iter())
+            .chain(cs.voters_outgoing.iter())
+            .chain(cs.learners_next.iter())
+            .chain(self.removed_replicas.iter())
+            .copied()
+            .fold(known_max.max(self.allocated_replica_id), u64::max);
+        self.allocated_replica_id = max + 1;
+        self.allocated_replica_id
+    }
+
+    /// Returns the leader node and the heartbeat response which reports the
+    /// applied index to the leader, if the follower has applied at least
+    /// `batch` entries since its last report. 0 disables the report.
+    pub fn applied_report(&mut self, batch: u64) -> Option<(u64, CoalescedHeartbeat)> {
+        let applied = self.raft_group.raft.raft_log.applied;
+        if batch == 0
+            || self.is_leader()
+            || self.leader.node_id == NO_NODE
+            || self.leader.replica_id != self.raft_group.raft.leader_id
+            || applied < self.reported_applied.saturating_add(batch)
+        {
+            return None;
+        }
+
+        self.reported_applied = applied;
+        let report = CoalescedHeartbeat {
+            group_id: self.group_id,
+            from_replica: self.replica_id,
+            to_replica: self.leader.replica_id,
+            term: self.raft_group.raft.term,
+            commit: self.raft_group.raft.raft_log.committed,
+            context: vec![],
+            quiesce: false,
+            applied,
+        };
+        Some((self.leader.node_id, report))
+    }
+
+    /// Returns the applied index of the replica, the index of a peer is the
+    /// last one it reported, 0 if it's never reported.
+    pub fn replica_applied(&self, replica_id: u64) -> u64 {
+        if replica_id == self.replica_id {
+            return self.raft_group.raft.raft_log.applied;
+        }
+        self.peer_applied.get(&replica_id).copied().unwrap_or(0)
+    }
+
+    /// Returns the max index applied by a quorum of voters, the quorum of both
+    /// the incoming and outgoing voters is required in the joint consensus.
+    pub fn quorum_applied(&self) -> u64 {
+        let cs = self.raft_group.raft.prs().conf().to_conf_state();
+        let incoming = majority_index(
+            cs.voters
+                .iter()
+                .map(|id| self.replica_applied(*id))
+                .collect(),
+        );
+        if cs.voters_outgoing.is_empty() {
+            return incoming;
+        }
+        let outgoing = majority_index(
+            cs.voters_outgoing
+                .iter()
+                .map(|id| self.replica_applied(*id))
+                .collect(),
+        );
+        incoming.min(outgoing)
+    }
+
+    /// Returns the voters which have applied the `index`.
+    pub fn applied_replicas(&self, index: u64) -> Vec<u64> {
+        let cs = self.raft_group.raft.prs().conf().to_conf_state();
+        let mut replicas = cs
+            .voters
+            .iter()
+            .chain(cs.voters_outgoing.iter())
+            .copied()
+            .filter(|id| self.replica_applied(*id) >= index)
+            .collect::<Vec<_>>();
+        replicas.sort_unstable();
+        replicas.dedup();
+        replicas
+    }
+
+    /// Returns the role of the replica, the quiesced group reports the last
+    /// known role because its raft state is unchanged while quiesced.
+    pub fn role(&self) -> ReplicaRole {
+        if self.is_witness() {
+            return ReplicaRole::Witness;
+        }
+
+        let raft = &self.raft_group.raft;
+        match raft.state {
+            StateRole::Leader => ReplicaRole::Leader,
+            StateRole::Candidate => ReplicaRole::Candidate,
+            StateRole::PreCandidate => ReplicaRole::PreCandidate,
+            StateRole::Follower => {
+                let cs = raft.prs().conf().to_conf_state();
+                if cs.learners.contains(&self.replica_id) {
+                    ReplicaRole::Learner
+                } else {
+                    ReplicaRole::Follower
+                }
+            }
+        }
+    }
+
+    /// Returns the status snapshot of the replica, it's read from `RawNode::status`.
+    pub fn status(&self) -> GroupStatus {
+        let status = self.raft_group.status();
+        let cs = self.raft_group.raft.prs().conf().to_conf_state();
+        let mut progress = vec![];
+        if let Some(prs) = status.progress {
+            for (replica_id, pr) in prs.iter() {
+                progress.push(ReplicaProgress {
+                    replica_id: *replica_id,
+                    node_id: NO_NODE,
+                    matched: pr.matched,
+                    lag: status.hs.commit.saturating_sub(pr.matched),
+                    next_idx: pr.next_idx,
+                    applied: self.replica_applied(*replica_id),
+                    state: pr.state,
+                    snapshot_inflight: false,
+                    rtt: None,
+                });
+            }
+            progress.sort_by_key(|pr| pr.replica_id);
+        }
+
+        GroupStatus {
+            group_id: GroupId(self.group_id),
+            replica_id: ReplicaId(self.replica_id),
+            role: status.ss.raft_state,
+            state: self.state,
+            term: status.hs.term,
+            leader_id: ReplicaId(status.ss.leader_id),
+            commit_index: status.hs.commit,
+            applied_index: status.applied,
+            apply_backlog: self.apply_backlog(),
+            voters: cs.voters,
+            learners: cs.learners,
+            progress,
+        }
+    }
+
+    /// Returns the result of the initialization of the replica on `node_id`.
+    pub fn init_result(&self, node_id: u64) -> InitResult {
+        let leader = if self.is_leader() {
+            Some(ReplicaDesc {
+                node_id,
+                replica_id: self.replica_id,
+            })
+        } else if self.leader.replica_id != 0 {
+            Some(self.leader.clone())
+        } else {
+            None
+        };
+        InitResult {
+            leader,
+            replica_id: ReplicaId(self.replica_id),
+            applied_index: self.raft_group.raft.raft_log.applied,
+        }
+    }
+
+    /// Returns true if the local replica is a witness. The witness votes and
+    /// advances the commit index, but doesn't store the data of state machine,
+    /// so it never becomes leader: it isn't ticked to start an election and
+    /// ignores the campaign and `MsgTimeoutNow`.
+    ///
+    /// Durability: a witness counts towards the quorum without holding data, so
+    /// a committed entry may be held by fewer full replicas than the quorum size.
+    /// e.g. with two full replicas and a witness, losing one full replica leaves
+    /// a single copy of the data.
+    #[inline]
+    pub fn is_witness(&self) -> bool {
+        self.witnesses.contains(&self.replica_id)
+    }
+
+    /// Returns false if the replica never starts an election, i.e. it's a
+    /// witness, on an observer node or frozen. It isn't ticked and ignores the
+    /// campaign and `MsgTimeoutNow`.
+    #[inline]
+    pub fn can_campaign(&self) -> bool {
+        !self.is_witness() && !self.observer && self.state != GroupState::Frozen
+    }
+
+    /// Strip the data of snapshots sent to the witnesses, the witness is
+    /// served the full snapshot only after it's promoted.
+    pub fn strip_witness_snapshots(&self, msgs: &mut Vec<crate::proto::Message>) {
+        if self.witnesses.is_empty() {
+            return;
+        }
+        for msg in msgs.iter_mut() {
+            if msg.msg_type() == crate::proto::MessageType::MsgSnapshot
+                && self.witnesses.contains(&msg.to)
+            {
+                if let Some(snapshot) = msg.snapshot.as_mut() {
+                    snapshot.data.clear();
+                }
+            }
+        }
+    }
+
+    /// Strip the data of normal entries persisted by the witness, so that it
+    /// stores the terms and indexes of the log only. The conf changes are kept
+    /// since they're applied by the witness as well.
+    pub fn strip_witness_entries(&self, entries: &mut Vec<crate::proto::Entry>) {
+        if !self.is_witness() {
+            return;
+        }
+        for entry in entries.iter_mut() {
+            if entry.entry_type() == crate::proto::EntryType::EntryNormal {
+                entry.data.clear();
+            }
+        }
+    }
+
+    /// Returns true if the message is sent by a replica which has been removed
+    /// from the group and isn't in the configuration. The replica which is
+    /// added but not applied locally yet is never removed before, so its
+    /// messages are kept. The catch-up traffic from a leader (e.g. the replica
+    /// is re-added and elected but this replica lags behind) is kept as well,
+    /// the stale one is rejected by raft according to the term.
+    pub fn is_from_removed_replica(&self, msg: &crate::proto::Message) -> bool {
+        if !self.removed_replicas.contains(&msg.from)
+            || self.raft_group.raft.prs().get(msg.from).is_some()
+        {
+            return false;
+        }
+        !matches!(
+            msg.msg_type(),
+            crate::proto::MessageType::MsgAppend
+                | crate::proto::MessageType::MsgSnapshot
+                | crate::proto::MessageType::MsgHeartbeat
+        )
+    }
+
+    /// Record the time of contact `now` if the message is from the current
+    /// leader, it should be called after the message is stepped.
+    #[inline]
+    pub fn record_leader_contact(&mut self, from_replica: u64, now: Instant) {
+        if from_replica != 0 && self.raft_group.raft.leader_id == from_replica {
+            self.last_leader_contact = Some(now);
+            self.contact_ticks = 0;
+            self.extended_ticks = 0;
+            // raft has reset the election timer, the skipped ticks are stale.
+            if self.raft_group.raft.election_elapsed == 0 {
+                self.skipped_ticks = 0;
+            }
+        }
+    }
+
+    /// Record the time `now` when the leadership of the local replica is
+    /// confirmed by a heartbeat response.
+    #[inline]
+    pub fn record_leadership_confirmed(&mut self, now: Instant) {
+        if self.is_leader() {
+            self.last_leader_contact = Some(now);
+        }
+    }
+
+    /// Returns the known leader of the group and the time it was last
+    /// confirmed, `None` if the leader is unknown, e.g. during an election.
+    pub fn leader_of(&self) -> Option<(ReplicaDesc, Instant)> {
+        let leader_id = self.raft_group.raft.leader_id;
+        if leader_id == 0 || leader_id != self.leader.replica_id {
+            return None;
+        }
+        self.last_leader_contact.map(|contact| (self.leader.clone(), contact))
+    }
+
+    /// Returns the state of read served by the applied state of the local
+    /// replica, if the replica is the leader or the last contact with the
+    /// leader is within `max_staleness` before `now`. Otherwise `TooStale` is
+    /// returned and the read should be served by `ReadIndex`. The witness can't
+    /// serve reads because it doesn't store the data.
+    pub fn follower_read(&self, max_staleness: Duration, now: Instant) -> Result<ReadState, Error> {
+        let fresh = match self.role() {
+            ReplicaRole::Leader => true,
+            ReplicaRole::Follower | ReplicaRole::Learner => {
+                self.last_leader_contact.map_or(false, |contact| {
+                    now.saturating_duration_since(contact) <= max_staleness
+                })
+            }
+            _ => false,
+        };
+        if !fresh {
+            return Err(Error::TooStale(self.group_id, self.replica_id));
+        }
+
+        let raft_log = &self.raft_group.raft.raft_log;
+        Ok(ReadState {
+            group_id: GroupId(self.group_id),
+            replica_id: ReplicaId(self.replica_id),
+            applied_index: raft_log.applied,
+            commit_index: raft_log.committed,
+        })
+    }
+
     #[inline]
     pub fn term(&self) -> u64 {
         self.raft_group.raft.term
     }
 
+    /// Returns the term and the index of its no-op entry if the replica has
+    /// become the leader of a new term since the last call. It must be called
+    /// right after the step, tick or campaign which may elect the replica,
+    /// before anything else is proposed, so the no-op appended by raft on
+    /// becoming the leader is still the last entry.
+    pub fn take_new_leadership(&mut self) -> Option<(u64, u64)> {
+        if !self.is_leader() || self.leadership_term == self.term() {
+            return None;
+        }
+        self.leadership_term = self.term();
+        Some((self.term(), self.last_index()))
+    }
+
     #[inline]
     pub fn committed_term(&self) -> u64 {
         self.committed_term
@@ -53,6 +889,268 @@ where
         self.raft_group.raft.raft_log.last_index()
     }
 
+    /// Returns the `(matched, last_index)` if the replica lags behind the
+    /// leader, it must be called on the leader.
+    pub fn replica_lag(&self, replica_id: u64) -> Result<Option<(u64, u64)>, Error> {
+        let raft = &self.raft_group.raft;
+        let pr = match raft.prs().get(replica_id) {
+            None => return Err(Error::ReplicaNotFound(self.group_id, replica_id)),
+            Some(pr) => pr,
+        };
+        let last_index = raft.raft_log.last_index();
+        if pr.matched < last_index {
+            Ok(Some((pr.matched, last_index)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the voter which is the most up-to-date among the replicated
+    /// peers, the leadership is transferred to it before the node shuts down
+    /// or after the leader removed itself. `None` is returned if the local replica isn't leader or there is no
+    /// suitable peer, the witness is never chosen.
+    pub fn leader_transferee(&self) -> Option<u64> {
+        if !self.is_leader() {
+            return None;
+        }
+        let raft = &self.raft_group.raft;
+        let cs = raft.prs().conf().to_conf_state();
+        cs.voters
+            .into_iter()
+            .filter(|replica_id| *replica_id != self.replica_id && !self.witnesses.contains(replica_id))
+            .filter_map(|replica_id| raft.prs().get(replica_id).map(|pr| (replica_id, pr)))
+            .filter(|(_, pr)| pr.state == ProgressState::Replicate && pr.recent_active)
+            .max_by_key(|(_, pr)| pr.matched)
+            .map(|(replica_id, _)| replica_id)
+    }
+
+    /// Set the leader priority of the replica, the priority of the local
+    /// replica is passed to raft, so that it doesn't vote for the candidate of
+    /// lower priority which is as up-to-date as itself.
+    pub fn set_leader_priority(&mut self, replica_id: u64, priority: u64) {
+        if priority == 0 {
+            self.leader_priorities.remove(&replica_id);
+        } else {
+            self.leader_priorities.insert(replica_id, priority);
+        }
+        if replica_id == self.replica_id {
+            self.raft_group.raft.set_priority(priority);
+        }
+    }
+
+    /// Returns the caught-up voter of the highest leader priority if it's
+    /// higher than the priority of the local leader, the leadership is
+    /// transferred to it. `None` is returned if the local replica isn't
+    /// leader or a transfer is in progress, the witness is never chosen.
+    pub fn preferred_transferee(&self) -> Option<u64> {
+        if !self.is_leader() || self.leader_priorities.is_empty() {
+            return None;
+        }
+        let raft = &self.raft_group.raft;
+        if raft.lead_transferee.is_some() {
+            return None;
+        }
+        let priority = |replica_id: &u64| *self.leader_priorities.get(replica_id).unwrap_or(&0);
+        let local_priority = priority(&self.replica_id);
+        let last_index = raft.raft_log.last_index();
+        let cs = raft.prs().conf().to_conf_state();
+        cs.voters
+            .into_iter()
+            .filter(|replica_id| priority(replica_id) > local_priority)
+            .filter(|replica_id| !self.witnesses.contains(replica_id))
+            .filter_map(|replica_id| raft.prs().get(replica_id).map(|pr| (replica_id, pr)))
+            .filter(|(_, pr)| pr.matched == last_index && pr.recent_active)
+            .max_by_key(|(replica_id, _)| (priority(replica_id), *replica_id))
+            .map(|(replica_id, _)| replica_id)
+    }
+
+    /// Returns the number of the committed entries which aren't applied yet,
+    /// the uncommitted ones are not counted since they are bounded by the
+    /// replication rather than the apply.
+    #[inline]
+    pub fn apply_backlog(&self) -> u64 {
+        let raft_log = &self.raft_group.raft.raft_log;
+        raft_log.committed.saturating_sub(raft_log.applied)
+    }
+
+    /// Returns the number of the entries in the log which aren't compacted,
+    /// see `MultiRaftConfig::max_log_entries`.
+    pub fn log_entries(&self) -> u64 {
+        let raft_log = &self.raft_group.raft.raft_log;
+        (raft_log.last_index() + 1).saturating_sub(raft_log.first_index())
+    }
+
+    /// Returns the least matched replica other than the leader and its matched
+    /// index, it must be called on the leader.
+    pub fn least_matched_replica(&self) -> Option<(u64, u64)> {
+        self.raft_group
+            .raft
+            .prs()
+            .iter()
+            .filter(|(replica_id, _)| **replica_id != self.replica_id)
+            .map(|(replica_id, pr)| (pr.matched, *replica_id))
+            .min()
+            .map(|(matched, replica_id)| (replica_id, matched))
+    }
+
+    /// Returns the index before which the log can be compacted by the snapshot
+    /// at `index`. The leader keeps the entries which a replica hasn't matched,
+    /// so a lagging replica catches up by the log rather than the snapshot.
+    pub fn log_compact_index(&self, index: u64) -> u64 {
+        let mut compact_index = index;
+        if self.is_leader() {
+            if let Some((_, matched)) = self.least_matched_replica() {
+                compact_index = std::cmp::min(compact_index, matched);
+            }
+        }
+        compact_index.saturating_add(1)
+    }
+
+    #[inline]
+    pub fn has_leader(&self) -> bool {
+        self.raft_group.raft.leader_id != 0
+    }
+
+    /// Returns true if the leader known by the replica is elected within
+    /// `min_tenure` ticks, see `MultiRaftConfig::min_leader_tenure`.
+    pub fn in_leader_tenure(&self, min_tenure: usize) -> bool {
+        min_tenure != 0 && self.has_leader() && self.leader_ticks < min_tenure
+    }
+
+    /// Update the leaderless and stalled ticks of the group, it's called
+    /// once per tick.
+    pub fn update_progress_ticks(&mut self) {
+        if self.has_leader() {
+            self.leaderless_ticks = 0;
+        } else {
+            self.leaderless_ticks += 1;
+        }
+
+        let committed = self.raft_group.raft.raft_log.committed;
+        if !self.proposals.is_empty() && committed == self.last_tick_commit {
+            self.stalled_ticks += 1;
+        } else {
+            self.stalled_ticks = 0;
+        }
+        self.last_tick_commit = committed;
+    }
+
+    /// Returns the reason if the group has no leader or can't make progress
+    /// beyond the `threshold` ticks.
+    pub fn check_unhealthy(&self, threshold: usize) -> Option<UnhealthyReason> {
+        if self.leaderless_ticks > threshold {
+            Some(UnhealthyReason::Leaderless)
+        } else if self.stalled_ticks > threshold {
+            Some(UnhealthyReason::NoProgress)
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub fn is_quiesced(&self) -> bool {
+        self.quiesced
+    }
+
+    /// Returns true if the tick is skipped to delay the initial election timer,
+    /// the delay ends once the leader is known.
+    pub fn skip_startup_tick(&mut self) -> bool {
+        if self.startup_delay_ticks == 0 {
+            return false;
+        }
+        if self.raft_group.raft.leader_id != 0 {
+            self.startup_delay_ticks = 0;
+            return false;
+        }
+        self.startup_delay_ticks -= 1;
+        true
+    }
+
+    /// Returns true if the tick is withheld from the election timer of the
+    /// follower, which is about to time out but heard from the leader within
+    /// `window` ticks. At most `max` ticks are withheld until the next contact
+    /// with the leader, see `MultiRaftConfig::election_extension_window`.
+    pub fn extend_election_timeout(&mut self, window: usize, max: usize) -> bool {
+        self.contact_ticks = self.contact_ticks.saturating_add(1);
+        let raft = &self.raft_group.raft;
+        if window == 0
+            || raft.state != StateRole::Follower
+            || raft.leader_id == 0
+            || raft.election_elapsed + self.skipped_ticks + 1 < raft.randomized_election_timeout()
+            || self.contact_ticks > window
+            || self.extended_ticks >= max
+        {
+            return false;
+        }
+        self.extended_ticks += 1;
+        true
+    }
+
+    /// Returns true if the replica is a healthy follower which heard from the
+    /// leader within `heartbeat_tick` ticks, counting the skipped ones, and
+    /// has no pending proposals, its tick can be skipped.
+    pub fn is_following_live_leader(&self, heartbeat_tick: usize) -> bool {
+        let raft = &self.raft_group.raft;
+        raft.state == StateRole::Follower
+            && raft.leader_id != 0
+            && raft.election_elapsed + self.skipped_ticks <= heartbeat_tick
+            && self.leaderless_ticks == 0
+            && self.unhealthy.is_none()
+            && self.proposals.is_empty()
+    }
+
+    /// Replay the ticks skipped by the light tick to raft, so the election
+    /// timer catches up once the heartbeat is missed. Returns true if raft
+    /// has the ready.
+    pub fn replay_skipped_ticks(&mut self) -> bool {
+        let mut has_ready = false;
+        for _ in 0..std::mem::take(&mut self.skipped_ticks) {
+            has_ready |= self.raft_group.tick();
+        }
+        has_ready
+    }
+
+    /// Wake the quiesced group and reset the idle ticks, it should be
+    /// called when the group has proposals, membership change or messages
+    /// from peers.
+    #[inline]
+    pub fn wake(&mut self) {
+        self.quiesced = false;
+        self.idle_ticks = 0;
+        if self.state == GroupState::Quiesced {
+            let _ = self.transition(GroupState::Running);
+        }
+    }
+
+    /// Stop ticking the group until it's woken, the draining or frozen group
+    /// is quiesced too but it stays in its state.
+    #[inline]
+    pub fn quiesce(&mut self) {
+        self.quiesced = true;
+        if self.state == GroupState::Running {
+            let _ = self.transition(GroupState::Quiesced);
+        }
+    }
+
+    /// Returns true if the leader can quiesce the group, which requires
+    /// there is no pending proposal and all entries are committed and
+    /// replicated to all peers.
+    pub fn can_quiesce(&self) -> bool {
+        if !self.is_leader() || !self.proposals.is_empty() {
+            return false;
+        }
+
+        let raft = &self.raft_group.raft;
+        let last_index = raft.raft_log.last_index();
+        if raft.raft_log.committed != last_index || raft.has_pending_conf() {
+            return false;
+        }
+
+        raft.prs()
+            .iter()
+            .all(|(_, progress)| progress.matched == last_index)
+    }
+
     #[inline]
     pub fn maybe_update_committed_term(&mut self, term: u64) {
         if self.committed_term != term && self.leader.replica_id != 0 {
@@ -60,6 +1158,19 @@ where
         }
     }
 
+    #[inline]
+    pub fn check_leader(&self) -> Result<(), Error> {
+        if !self.is_leader() {
+            return Err(Error::Raft(RaftError::NotLeader(
+                self.group_id,
+                self.replica_id,
+                self.raft_group.raft.leader_id,
+            )));
+        }
+
+        Ok(())
+    }
+
     fn write_pre_propose(&mut self, request: &AppWriteRequest) -> Result<(), Error>
     where
         RS: RaftStorage,
@@ -68,13 +1179,10 @@ where
             return Err(Error::BadParameter(format!("write request data is empty")));
         }
 
-        if !self.is_leader() {
-            return Err(Error::Raft(RaftError::NotLeader(
-                self.group_id,
-                self.replica_id,
-                self.raft_group.raft.leader_id,
-            )));
-        }
+        self.check_leader()?;
+
+        self.proposals.check_capacity()?;
+        self.proposals.check_client_quota(request.client_id)?;
 
         if request.term != 0 && self.term() > request.term {
             return Err(Error::Proposal(ProposalError::Stale(request.term)));
@@ -89,21 +1197,214 @@ where
         tx: oneshot::Sender<Result<(), Error>>,
     ) {
         if let Err(err) = self.write_pre_propose(&request) {
-            tx.send(Err(err)).unwrap();
+            // the receiver may be dropped if the proposal is timeout or cancelled.
+            let _ = tx.send(Err(err));
+            return;
+        }
+
+        // the client request id is carried with the entry, so that the apply
+        // path of every replica deduplicates the retries.
+        let correlation_id = request.correlation_id;
+        let client_id = request.client_id;
+        let context = ProposalContext {
+            client_id: request.client_id,
+            sequence: request.sequence,
+            context: request.context,
+            precondition: request.precondition,
+            correlation_id,
+        };
+        let index = self.propose_entry(
+            context.encode_to_vec(),
+            entry::encode_data(&request.data),
+            client_id,
+            tx,
+        );
+        if let Some(index) = index.filter(|_| correlation_id != 0) {
+            let term = self.term();
+            self.correlation_ids.insert(term, index, correlation_id);
+        }
+    }
+
+    /// Propose the internal admin command of `kind`, the result is responded
+    /// after it's applied, see `entry::encode_admin`.
+    #[cfg(feature = "serde-config")]
+    pub fn admin_propose(
+        &mut self,
+        kind: u8,
+        payload: Vec<u8>,
+        tx: oneshot::Sender<Result<(), Error>>,
+    ) {
+        if let Err(err) = self
+            .check_leader()
+            .and_then(|_| self.proposals.check_capacity())
+        {
+            let _ = tx.send(Err(err));
             return;
         }
+        self.propose_entry(vec![], entry::encode_admin(kind, &payload), 0, tx);
+    }
+
+    /// Propose the leadership epoch data returned by the `LeaderHook` right
+    /// after the no-op of the term, returns the index of the entry if it's
+    /// proposed. Nobody waits for its result.
+    pub fn propose_epoch(&mut self, data: &[u8]) -> Option<u64> {
+        let (tx, _) = oneshot::channel();
+        self.propose_entry(vec![], entry::encode_data(data), 0, tx)
+    }
+
+    /// Propose the framed data of the normal entry and track the proposal of
+    /// the client, returns the index of the entry if it's proposed.
+    fn propose_entry(
+        &mut self,
+        context: Vec<u8>,
+        data: Vec<u8>,
+        client_id: u64,
+        tx: oneshot::Sender<Result<(), Error>>,
+    ) -> Option<u64> {
         let term = self.term();
 
         // propose to raft gorup
         let expected_next_index = self.last_index() + 1;
+        if let Err(err) = self.raft_group.propose(context, data) {
+            let err = match err {
+                raft::Error::ProposalDropped => ProposalError::Dropped,
+                err => ProposalError::Other(Box::new(err)),
+            };
+            let _ = tx.send(Err(Error::Proposal(err)));
+            return None;
+        }
+
+        let index = self.last_index();
+        if expected_next_index != index {
+            let _ = tx.send(Err(Error::Proposal(ProposalError::Unexpected(index))));
+            return None;
+        }
+
+        let proposal = Proposal {
+            index,
+            term,
+            is_conf_change: false,
+            client_id,
+            tx: Some(tx),
+        };
+
+        self.proposals.push(proposal).unwrap();
+        Some(index)
+    }
+
+    /// Set the per-group overrides of the config, `max_pending_proposals` is the
+    /// one of the node, which is followed if it isn't overridden.
+    pub fn set_config(&mut self, config: GroupConfig, max_pending_proposals: usize) {
+        self.wake();
+        self.proposals.max_pending = config
+            .max_pending_proposals
+            .unwrap_or(max_pending_proposals);
+        if self.auto_promote != config.auto_promote {
+            self.auto_promote = config.auto_promote;
+            self.caught_up_ticks.clear();
+        }
+    }
+
+    /// Returns the effective per-group config, the overridden settings and the
+    /// ones followed from the node are not told apart.
+    pub fn config(&self) -> GroupConfig {
+        GroupConfig {
+            max_pending_proposals: Some(self.proposals.max_pending),
+            auto_promote: self.auto_promote,
+        }
+    }
+
+    /// Returns true if the match index of the replica has reached the
+    /// commit index, it must be called on the leader.
+    pub fn replica_caught_up(&self, replica_id: u64) -> Result<bool, Error> {
+        self.check_leader()?;
+        let raft = &self.raft_group.raft;
+        match raft.prs().get(replica_id) {
+            None => Err(Error::ReplicaNotFound(self.group_id, replica_id)),
+            Some(progress) => Ok(progress.matched >= raft.raft_log.committed),
+        }
+    }
+
+    /// Reset the replica by the leader, the progress of the replica is probed
+    /// again so that the snapshot requested by the replica is sent rather than
+    /// being rejected as stale. Returns the commit index, which the requested
+    /// snapshot should cover. Returns `UnsafeReset` if the other up-to-date
+    /// voters don't form a quorum, since the group couldn't commit while the
+    /// replica reinstalls.
+    pub fn reset_replica(&mut self, replica_id: u64) -> Result<u64, Error> {
+        self.check_leader()?;
+        if replica_id == self.replica_id {
+            return Err(Error::BadParameter(format!(
+                "can't reset the leader replica {} of group {}",
+                replica_id, self.group_id
+            )));
+        }
+
+        let raft = &mut self.raft_group.raft;
+        if raft.prs().get(replica_id).is_none() {
+            return Err(Error::ReplicaNotFound(self.group_id, replica_id));
+        }
+        let committed = raft.raft_log.committed;
+        let voters = raft.prs().conf().to_conf_state().voters;
+        let up_to_date = voters
+            .iter()
+            .filter(|id| **id != replica_id)
+            .filter(|id| {
+                raft.prs()
+                    .get(**id)
+                    .map_or(false, |pr| pr.matched >= committed)
+            })
+            .count();
+        if up_to_date < voters.len() / 2 + 1 {
+            return Err(Error::UnsafeReset(self.group_id, replica_id));
+        }
+
+        raft.mut_prs().get_mut(replica_id).unwrap().become_probe();
+        Ok(committed)
+    }
+
+    /// Reset the replica on receiving `ResetReplica` from the leader of the
+    /// current term, the replica requests a snapshot at least at `index` and
+    /// ignores the appends until the snapshot is installed, which discards the
+    /// log and replaces the state of the replica.
+    pub fn request_reset(&mut self, from_replica: u64, term: u64, index: u64) -> Result<(), Error> {
+        let raft = &self.raft_group.raft;
+        if raft.term != term || raft.leader_id != from_replica {
+            return Err(Error::BadParameter(format!(
+                "stale reset of group {} from replica {} at term {}, the leader is {} at term {}",
+                self.group_id, from_replica, term, raft.leader_id, raft.term
+            )));
+        }
+        self.raft_group
+            .request_snapshot(index)
+            .map_err(|err| Error::RaftGroup(err))
+    }
+
+    /// Propose the membership change as ConfChangeV2, the `data` is encoded
+    /// into the context of ConfChangeV2 so that the nodes of the changed
+    /// replicas are replicated with the entry. multiple changes are applied
+    /// atomically via joint consensus.
+    pub fn membership_change_propose(
+        &mut self,
+        data: MembershipChangeData,
+        tx: oneshot::Sender<Result<(), Error>>,
+    ) {
+        if let Err(err) = self.check_leader() {
+            tx.send(Err(err)).unwrap();
+            return;
+        }
+
+        let term = self.term();
+        let expected_next_index = self.last_index() + 1;
 
-        if let Err(err) = self.raft_group.propose(request.context, request.data) {
+        let cc = transmute_conf_change_v2(membership_conf_change(&data));
+        if let Err(err) = self.raft_group.propose_conf_change(vec![], cc) {
             tx.send(Err(Error::Proposal(ProposalError::Other(Box::new(err)))))
                 .unwrap();
             return;
         }
 
-        let index = self.last_index() + 1;
+        let index = self.last_index();
         if expected_next_index != index {
             tx.send(Err(Error::Proposal(ProposalError::Unexpected(index))))
                 .unwrap();
@@ -113,31 +1414,357 @@ where
         let proposal = Proposal {
             index,
             term,
-            is_conf_change: false,
+            is_conf_change: true,
+            client_id: 0,
             tx: Some(tx),
         };
 
         self.proposals.push(proposal).unwrap();
     }
 
-    pub fn read_index_propose(&mut self,
-            request: AppReadIndexRequest,
-        tx: oneshot::Sender<Result<(), Error>>
-    ) {
-        let uuid = uuid::Uuid::new_v4();
-        let term = self.term();
-        let read_context = match request.context {
-            None => vec![],
-            Some(ctx) => ctx.encode_length_delimited_to_vec(),
+    /// Returns the learner to be promoted to voter by the `auto_promote`
+    /// policy, it's called once per tick. The learner is promoted after it's
+    /// caught up for `stable_ticks` consecutive ticks, and only when no conf
+    /// change is pending and the config isn't joint. The learner promoted
+    /// before is never promoted again by the leader, so the one demoted after
+    /// the promotion (e.g. it falls behind) doesn't oscillate.
+    pub fn auto_promote_learner(&mut self) -> Option<u64> {
+        let policy = self.auto_promote?;
+        if !self.is_leader() {
+            self.caught_up_ticks.clear();
+            self.auto_promoted.clear();
+            return None;
+        }
+
+        let raft = &self.raft_group.raft;
+        let cs = raft.prs().conf().to_conf_state();
+        let committed = raft.raft_log.committed;
+        let mut caught_up_ticks = HashMap::new();
+        for learner in cs.learners.iter() {
+            if self.auto_promoted.contains(learner) || self.witnesses.contains(learner) {
+                continue;
+            }
+            let caught_up = raft.prs().get(*learner).map_or(false, |pr| {
+                pr.matched.saturating_add(policy.max_lag) >= committed
+            });
+            if caught_up {
+                let ticks = self
+                    .caught_up_ticks
+                    .get(learner)
+                    .map_or(1, |ticks| ticks + 1);
+                caught_up_ticks.insert(*learner, ticks);
+            }
+        }
+        self.caught_up_ticks = caught_up_ticks;
+
+        if raft.pending_conf_index > raft.raft_log.applied || !cs.voters_outgoing.is_empty() {
+            return None;
+        }
+        self.caught_up_ticks
+            .iter()
+            .filter(|(_, ticks)| **ticks >= policy.stable_ticks)
+            .map(|(learner, _)| *learner)
+            .min()
+    }
+
+    /// Propose to promote the learner to voter, returns true if it's proposed.
+    pub fn propose_promote_learner(&mut self, learner: ReplicaDesc) -> bool {
+        let mut change = MembershipChangeRequest {
+            group_id: self.group_id,
+            node_id: learner.node_id,
+            replica_id: learner.replica_id,
+            ..Default::default()
+        };
+        change.set_change_type(ConfChangeType::AddNode);
+        let mut data = MembershipChangeData {
+            group_id: self.group_id,
+            changes: vec![change],
+            ..Default::default()
         };
+        data.set_transition(ConfChangeTransition::Auto);
 
-        self.raft_group.read_index(read_context);
+        let cc = transmute_conf_change_v2(membership_conf_change(&data));
+        if let Err(err) = self.raft_group.propose_conf_change(vec![], cc) {
+            warn!(
+                "group {} replica {} propose to promote learner {} error: {}",
+                self.group_id, self.replica_id, learner.replica_id, err
+            );
+            return false;
+        }
+        info!(
+            "group {} replica {} propose to promote learner {} at index {}",
+            self.group_id,
+            self.replica_id,
+            learner.replica_id,
+            self.last_index()
+        );
+        self.caught_up_ticks.remove(&learner.replica_id);
+        self.auto_promoted.insert(learner.replica_id);
+        true
+    }
+
+    /// Propose the empty ConfChangeV2 to leave the joint consensus if it was
+    /// entered in auto mode and no conf change is pending. It's checked by the
+    /// leader after apply and on tick rather than only by raft when the joint
+    /// entry is applied, so the leader elected in the middle of the change,
+    /// or the one whose leave proposal is dropped, also finishes it. Returns
+    /// true if the leave is proposed.
+    pub fn maybe_auto_leave_joint(&mut self) -> bool {
+        if !self.is_leader() {
+            return false;
+        }
+        let raft = &self.raft_group.raft;
+        if raft.pending_conf_index > raft.raft_log.applied {
+            return false;
+        }
+        let cs = raft.prs().conf().to_conf_state();
+        if !cs.auto_leave || cs.voters_outgoing.is_empty() {
+            return false;
+        }
+
+        let data = MembershipChangeData {
+            group_id: self.group_id,
+            ..Default::default()
+        };
+        let cc = transmute_conf_change_v2(membership_conf_change(&data));
+        if let Err(err) = self.raft_group.propose_conf_change(vec![], cc) {
+            warn!(
+                "group {} replica {} propose leave joint error: {}",
+                self.group_id, self.replica_id, err
+            );
+            return false;
+        }
+        info!(
+            "group {} replica {} propose leave joint at index {}",
+            self.group_id,
+            self.replica_id,
+            self.last_index()
+        );
+        true
+    }
 
+    /// Request the read index of raft, which is responded by the read state
+    /// once the leadership is confirmed, see `handle_read_states`. The request
+    /// of the follower is forwarded to the leader by raft.
+    pub fn read_index_propose(
+        &mut self,
+        request: AppReadIndexRequest,
+        tx: oneshot::Sender<Result<u64, Error>>,
+    ) {
+        // raft drops the request silently if the leader is unknown or it
+        // hasn't committed an entry of its term yet.
+        let raft_log = &self.raft_group.raft.raft_log;
+        if self.raft_group.raft.leader_id == 0 {
+            let _ = tx.send(Err(Error::Raft(RaftError::NotLeader(
+                self.group_id,
+                self.replica_id,
+                0,
+            ))));
+            return;
+        }
+        if self.is_leader() && raft_log.term(raft_log.committed).unwrap_or(0) != self.term() {
+            let _ = tx.send(Err(Error::Proposal(ProposalError::Dropped)));
+            return;
+        }
+
+        let uuid = uuid::Uuid::new_v4();
+        self.raft_group.read_index(uuid.as_bytes().to_vec());
         let proposal = ReadIndexProposal {
             uuid,
             read_index: None,
-            context: None,
+            context: request.context,
             tx: Some(tx),
+            ticks: 0,
         };
+        self.read_index_proposals.insert(uuid, proposal);
+    }
+
+    /// Respond the read index requests confirmed by the read states of ready.
+    pub fn handle_read_states(&mut self, read_states: Vec<raft::ReadState>) {
+        for read_state in read_states {
+            let uuid = match uuid::Uuid::from_slice(&read_state.request_ctx) {
+                Ok(uuid) => uuid,
+                Err(_) => continue,
+            };
+            if let Some(mut proposal) = self.read_index_proposals.remove(&uuid) {
+                proposal.read_index = Some(read_state.index);
+                if let Some(tx) = proposal.tx.take() {
+                    let _ = tx.send(Ok(read_state.index));
+                }
+            }
+        }
     }
+
+    /// Fail the read index requests which aren't confirmed within `timeout`
+    /// ticks. raft drops the request silently if it can't be served, e.g. the
+    /// forwarded request is lost, so its caller would wait forever.
+    pub fn expire_read_index_proposals(&mut self, timeout: usize) {
+        if self.read_index_proposals.is_empty() {
+            return;
+        }
+        self.read_index_proposals.retain(|_, proposal| {
+            proposal.ticks += 1;
+            if proposal.ticks <= timeout {
+                return true;
+            }
+            if let Some(tx) = proposal.tx.take() {
+                let _ = tx.send(Err(Error::Proposal(ProposalError::Dropped)));
+            }
+            false
+        });
+    }
+
+    /// Fail the pending read index requests, raft drops the unconfirmed ones
+    /// when the leader changes.
+    pub fn fail_read_index_proposals(&mut self) {
+        let leader_id = self.raft_group.raft.leader_id;
+        for (_, mut proposal) in self.read_index_proposals.drain() {
+            if let Some(tx) = proposal.tx.take() {
+                let _ = tx.send(Err(Error::Raft(RaftError::NotLeader(
+                    self.group_id,
+                    self.replica_id,
+                    leader_id,
+                ))));
+            }
+        }
+    }
+}
+
+/// Returns the random delay ticks in `[0, jitter]` of the initial election
+/// timer of a new group.
+pub fn startup_jitter_ticks(jitter: usize) -> usize {
+    if jitter == 0 {
+        return 0;
+    }
+    rand::thread_rng().gen_range(0..=jitter)
+}
+
+/// Returns the max index reached by a majority of the `indexes`.
+fn majority_index(mut indexes: Vec<u64>) -> u64 {
+    if indexes.is_empty() {
+        return 0;
+    }
+    indexes.sort_unstable_by(|a, b| b.cmp(a));
+    indexes[indexes.len() / 2]
+}
+
+/// Send the index to the watchers if it advances, the watch without watchers
+/// is dropped.
+fn update_watermark(watch: &mut Option<watch::Sender<u64>>, index: u64) {
+    if let Some(tx) = watch.as_ref() {
+        if tx.receiver_count() == 0 {
+            *watch = None;
+        } else if *tx.borrow() < index {
+            let _ = tx.send(index);
+        }
+    }
+}
+
+/// Serialize the raft `ProgressState` by its name, so that the serialized
+/// progress doesn't depend on the representation of raft.
+mod serde_progress_state {
+    use raft::ProgressState;
+    use serde::de::Error;
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(state: &ProgressState, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(match state {
+            ProgressState::Probe => "Probe",
+            ProgressState::Replicate => "Replicate",
+            ProgressState::Snapshot => "Snapshot",
+        })
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<ProgressState, D::Error> {
+        match String::deserialize(d)?.as_str() {
+            "Probe" => Ok(ProgressState::Probe),
+            "Replicate" => Ok(ProgressState::Replicate),
+            "Snapshot" => Ok(ProgressState::Snapshot),
+            other => Err(D::Error::custom(format!("unknown progress state {}", other))),
+        }
+    }
+}
+
+#[test]
+fn test_replica_progress_serde() {
+    let pr = ReplicaProgress {
+        replica_id: 2,
+        node_id: 3,
+        matched: 5,
+        lag: 2,
+        next_idx: 6,
+        applied: 4,
+        state: ProgressState::Snapshot,
+        snapshot_inflight: true,
+        rtt: Some(Duration::from_millis(3)),
+    };
+    let json = serde_json::to_string(&pr).unwrap();
+    assert!(json.contains("\"state\":\"Snapshot\""));
+    assert_eq!(serde_json::from_str::<ReplicaProgress>(&json).unwrap(), pr);
+}
+
+#[test]
+fn test_startup_jitter_ticks() {
+    assert_eq!(startup_jitter_ticks(0), 0);
+    let delays = (0..100).map(|_| startup_jitter_ticks(10)).collect::<HashSet<_>>();
+    assert!(delays.iter().all(|delay| *delay <= 10));
+    assert!(delays.len() > 1);
+}
+
+#[test]
+fn test_majority_index() {
+    assert_eq!(majority_index(vec![]), 0);
+    assert_eq!(majority_index(vec![5]), 5);
+    assert_eq!(majority_index(vec![1, 5, 4]), 4);
+    assert_eq!(majority_index(vec![5, 1, 3, 4]), 3);
+}
+
+#[test]
+fn test_group_state_transitions() {
+    use GroupState::*;
+    let states = [
+        Uninitialized,
+        Initializing,
+        Running,
+        Quiesced,
+        Draining,
+        Frozen,
+        Removed,
+        Failed,
+    ];
+    let valid = [
+        (Uninitialized, Initializing),
+        (Initializing, Running),
+        (Running, Quiesced),
+        (Quiesced, Running),
+        (Running, Draining),
+        (Quiesced, Draining),
+        (Running, Frozen),
+        (Quiesced, Frozen),
+        (Frozen, Running),
+    ];
+    for from in states {
+        for to in states {
+            let expected = if from == Removed {
+                false
+            } else {
+                to == Failed || to == Removed || valid.contains(&(from, to))
+            };
+            assert_eq!(
+                from.can_transition_to(to),
+                expected,
+                "{:?} -> {:?}",
+                from,
+                to
+            );
+        }
+    }
+
+    // the drained group never runs again, and the removed one never changes.
+    assert!(!Draining.can_transition_to(Running));
+    assert!(!Frozen.can_transition_to(Quiesced));
+    assert!(!Running.can_transition_to(Initializing));
+    assert!(!Removed.can_transition_to(Failed));
 }
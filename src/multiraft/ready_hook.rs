@@ -0,0 +1,35 @@
+/// The stages of handling the `Ready` of a group by the actor, the stages of
+/// a ready are strictly ordered:
+///
+/// 1. `Ready`: the ready is taken from the raft group.
+/// 2. `SendMessages`: the messages which don't depend on the persistence
+///    (e.g. the appends of leader) are sent.
+/// 3. `Apply`: the committed entries are handed to the apply actor, they are
+///    already persisted by the previous readies.
+/// 4. `Persist`: the snapshot, entries and hard state are written to storage
+///    by `RaftStorage::write_ready`, which must be durable on return.
+/// 5. `SendPersistedMessages`: the messages which must be sent after the
+///    persistence (e.g. the vote and append responses) are sent.
+///
+/// The order of `Persist` before `SendPersistedMessages` guarantees the raft
+/// invariant that the entries are durable before they are acked and the hard
+/// state is durable before the vote is sent.
+///
+/// The messages are buffered by the send stages and handed to the transport at
+/// the end of the iteration of the actor loop, which keeps the order above.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadyStage {
+    Ready,
+    SendMessages,
+    Apply,
+    Persist,
+    SendPersistedMessages,
+}
+
+/// ReadyHook is called by the actor after each stage of handling the `Ready`
+/// of a group, an implementer can extend the pipeline, e.g. `fsync` after the
+/// `Persist` stage before the persisted messages are sent. The hook is called
+/// in the actor loop, so it should not block for long.
+pub trait ReadyHook: Send + Sync + 'static {
+    fn after_stage(&self, group_id: u64, stage: ReadyStage);
+}
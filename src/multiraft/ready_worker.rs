@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+
+use futures::future::join_all;
+use futures::FutureExt;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+
+use crate::storage::RaftStorage;
+use crate::storage::RaftStorageImpl;
+use crate::storage::StorageError;
+use crate::storage::WriteBatch;
+
+use super::multiraft_actor::panic_message;
+
+/// The write batch of the ready of a group persisted by a ready worker.
+pub(crate) struct ReadyWrite<RS: RaftStorage> {
+    pub(crate) group_id: u64,
+    pub(crate) storage: RaftStorageImpl<RS>,
+    pub(crate) batch: WriteBatch,
+}
+
+/// The result of a ready write, the outer error is the message of the panic
+/// in writing it.
+pub(crate) type ReadyWriteResult = Result<Result<(), StorageError>, String>;
+
+type ReadyWriteJob<RS> = (
+    Vec<ReadyWrite<RS>>,
+    oneshot::Sender<Vec<(u64, ReadyWriteResult)>>,
+);
+
+/// ReadyWorkers persists the readies of the groups on a bounded pool of
+/// workers, the group is always assigned to the same worker by its id, which
+/// writes the readies assigned to it one by one.
+///
+/// The workers stop once the pool is dropped.
+pub(crate) struct ReadyWorkers<RS: RaftStorage> {
+    txs: Vec<UnboundedSender<ReadyWriteJob<RS>>>,
+}
+
+impl<RS: RaftStorage> ReadyWorkers<RS> {
+    pub(crate) fn spawn(workers: usize) -> Self {
+        let mut txs = Vec::with_capacity(workers);
+        for _ in 0..workers {
+            let (tx, mut rx) = unbounded_channel::<ReadyWriteJob<RS>>();
+            tokio::spawn(async move {
+                while let Some((writes, done)) = rx.recv().await {
+                    let mut results = Vec::with_capacity(writes.len());
+                    for write in writes {
+                        let res = AssertUnwindSafe(write.storage.write_ready(write.batch))
+                            .catch_unwind()
+                            .await
+                            .map_err(|payload| panic_message(payload.as_ref()));
+                        results.push((write.group_id, res));
+                    }
+                    let _ = done.send(results);
+                }
+            });
+            txs.push(tx);
+        }
+        Self { txs }
+    }
+
+    /// Persist the writes on the workers concurrently, returns the result of
+    /// each write by the group id once all of them are done.
+    pub(crate) async fn write(
+        &self,
+        writes: Vec<ReadyWrite<RS>>,
+    ) -> HashMap<u64, ReadyWriteResult> {
+        let mut shards = (0..self.txs.len()).map(|_| vec![]).collect::<Vec<_>>();
+        for write in writes {
+            let worker = write.group_id as usize % self.txs.len();
+            shards[worker].push(write);
+        }
+
+        let mut waits = vec![];
+        for (worker, writes) in shards.into_iter().enumerate() {
+            if writes.is_empty() {
+                continue;
+            }
+            let group_ids = writes
+                .iter()
+                .map(|write| write.group_id)
+                .collect::<Vec<_>>();
+            let (tx, rx) = oneshot::channel();
+            // the worker is never stopped before the pool, the writes of the
+            // worker which is gone are failed like it panics.
+            let _ = self.txs[worker].send((writes, tx));
+            waits.push(async move {
+                match rx.await {
+                    Ok(results) => results,
+                    Err(_) => group_ids
+                        .into_iter()
+                        .map(|group_id| (group_id, Err("ready worker stopped".to_owned())))
+                        .collect(),
+                }
+            });
+        }
+
+        join_all(waits).await.into_iter().flatten().collect()
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::marker::PhantomData;
 
 use crate::proto::RaftGroupDesc;
@@ -8,9 +9,23 @@ use crate::storage::RaftStorage;
 
 use super::error::Error;
 
+/// The statistics of the replica cache, which are used to size the cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplicaCacheStats {
+    /// The number of cached groups.
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
 /// ReplicaCache cache replica metadatas
 /// from read storage and messages and write the replica metadata the storage
 /// when cache miss.
+///
+/// At most `capacity` groups are cached, the least recently used group is
+/// evicted beyond it, except the groups led by this node which are pinned.
+/// The evicted group is read from storage again at the next lookup.
 pub struct ReplicaCache<RS, MRS>
 where
     RS: RaftStorage,
@@ -18,6 +33,14 @@ where
 {
     storage: MRS,
     groups: HashMap<u64, RaftGroupDesc>,
+    // the max number of cached groups, 0 means unlimited.
+    capacity: usize,
+    // the logical time of the last access of the cached groups.
+    accessed: HashMap<u64, u64>,
+    clock: u64,
+    // the groups led by this node, they're never evicted.
+    pinned: HashSet<u64>,
+    stats: ReplicaCacheStats,
     _m: PhantomData<RS>,
 }
 
@@ -26,14 +49,37 @@ where
     RS: RaftStorage,
     MRS: MultiRaftStorage<RS>,
 {
-    pub fn new(storage: MRS) -> Self {
+    pub fn new(storage: MRS, capacity: usize) -> Self {
         Self {
             storage,
             groups: Default::default(),
+            capacity,
+            accessed: Default::default(),
+            clock: 0,
+            pinned: Default::default(),
+            stats: Default::default(),
             _m: PhantomData,
         }
     }
 
+    /// Returns the statistics of the cache.
+    pub fn stats(&self) -> ReplicaCacheStats {
+        ReplicaCacheStats {
+            entries: self.groups.len(),
+            ..self.stats
+        }
+    }
+
+    /// Pin the group if the local replica is the leader of it, otherwise
+    /// unpin it so that it can be evicted.
+    pub fn set_pinned(&mut self, group_id: u64, pinned: bool) {
+        if pinned {
+            self.pinned.insert(group_id);
+        } else {
+            self.pinned.remove(&group_id);
+        }
+    }
+
     /// Get replica description from this cache when replica_id equals, 
     /// return `Error` if storage error occurs. if group is not in the 
     /// cache, it is read from storage and if storage is not found too, 
@@ -73,6 +119,8 @@ where
         sync: bool
     ) -> Result<(), Error>{
         if let Some(group_desc) = self.groups.get_mut(&group_id) {
+            self.clock += 1;
+            self.accessed.insert(group_id, self.clock);
             if group_desc.replicas.iter().find(|replica| **replica == replica_desc).is_some() {
                 return Ok(())
             }
@@ -92,24 +140,65 @@ where
             // set new group_desc in storage
             let _ = self.storage.set_group_desc(group_id, group_desc.clone()).await?;
         }
-        self.groups.insert(group_id, group_desc);
+        self.insert_group(group_id, group_desc);
         return Ok(())
     }
 
+    /// Remove the cached replicas of the group.
+    pub fn remove_group(&mut self, group_id: u64) {
+        self.groups.remove(&group_id);
+        self.accessed.remove(&group_id);
+        self.pinned.remove(&group_id);
+    }
+
     #[inline]
     async fn ensure_cache_group(&mut self, group_id: u64) -> Result<(), Error> {
         if self.groups.get(&group_id).is_none() {
+            self.stats.misses += 1;
             let group_desc = self
                 .storage
                 .group_desc(group_id)
                 .await
                 .map_err(|err| Error::Store(err))?;
-            self.groups.insert(group_id, group_desc);
+            self.insert_group(group_id, group_desc);
+        } else {
+            self.stats.hits += 1;
+            self.clock += 1;
+            self.accessed.insert(group_id, self.clock);
         }
 
         Ok(())
     }
 
+    /// Cache the group, then evict the least recently used groups which are
+    /// not pinned beyond the capacity.
+    fn insert_group(&mut self, group_id: u64, group_desc: RaftGroupDesc) {
+        self.clock += 1;
+        self.accessed.insert(group_id, self.clock);
+        self.groups.insert(group_id, group_desc);
+        if self.capacity == 0 {
+            return;
+        }
+
+        while self.groups.len() > self.capacity {
+            let coldest = self
+                .accessed
+                .iter()
+                .filter(|(id, _)| **id != group_id && !self.pinned.contains(id))
+                .min_by_key(|(_, accessed)| **accessed)
+                .map(|(id, _)| *id);
+            match coldest {
+                // all other groups are pinned, the cache grows beyond capacity.
+                None => break,
+                Some(id) => {
+                    self.groups.remove(&id);
+                    self.accessed.remove(&id);
+                    self.stats.evictions += 1;
+                }
+            }
+        }
+    }
+
     #[inline]
     async fn find<P>(group_desc: &RaftGroupDesc, predicate: P) -> Option<ReplicaDesc>
     where
@@ -126,3 +215,40 @@ where
         None
     }
 }
+
+#[test]
+fn test_replica_cache_evict_cold_groups() {
+    use crate::storage::MultiRaftMemoryStorage;
+    use crate::storage::MemStorage;
+
+    futures::executor::block_on(async {
+        let storage = MultiRaftMemoryStorage::new(1, 1);
+        let mut cache = ReplicaCache::<MemStorage, _>::new(storage, 4);
+        let leader = ReplicaDesc {
+            node_id: 1,
+            replica_id: 1,
+        };
+        cache.cache_replica_desc(1, leader.clone(), true).await.unwrap();
+        cache.set_pinned(1, true);
+
+        for group_id in 2..100 {
+            let replica = ReplicaDesc {
+                node_id: 2,
+                replica_id: group_id,
+            };
+            cache.cache_replica_desc(group_id, replica, true).await.unwrap();
+            cache.replica_desc(group_id, group_id).await.unwrap();
+            assert!(cache.stats().entries <= 4);
+        }
+
+        let stats = cache.stats();
+        assert_eq!(stats.evictions, 95);
+        // the group led by this node is still cached.
+        assert_eq!(cache.replica_desc(1, 1).await.unwrap(), Some(leader));
+        assert_eq!(cache.stats().hits, stats.hits + 1);
+
+        // the evicted group is read from storage.
+        assert!(cache.replica_desc(2, 2).await.unwrap().is_some());
+        assert_eq!(cache.stats().misses, stats.misses + 1);
+    });
+}
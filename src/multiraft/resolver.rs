@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+/// The network address of a node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeAddress {
+    pub node_id: u64,
+    pub store_id: u64,
+    pub addr: String,
+}
+
+/// NodeResolver maps the `node_id` to the network address of the node, it's
+/// used by the multiraft and the transport to address the messages. The node
+/// which can't be resolved is unreachable, the messages to it are failed with
+/// `TransportError::UnknownNode`.
+pub trait NodeResolver: Send + Sync + 'static {
+    fn resolve(&self, node_id: u64) -> Option<NodeAddress>;
+
+    /// Returns the ids of the nodes known by the resolver, e.g. the nodes
+    /// which the default `RoundRobinPlacer` places the replicas on. The
+    /// default is none.
+    fn node_ids(&self) -> Vec<u64> {
+        vec![]
+    }
+}
+
+/// MemNodeResolver is an in-memory `NodeResolver` which can be updated at
+/// runtime, so that the nodes joining the cluster become reachable. The
+/// clones share the same entries.
+#[derive(Debug, Clone, Default)]
+pub struct MemNodeResolver {
+    nodes: Arc<RwLock<HashMap<u64, NodeAddress>>>,
+}
+
+impl MemNodeResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add or update the address of the node.
+    pub fn update(&self, address: NodeAddress) {
+        self.nodes.write().unwrap().insert(address.node_id, address);
+    }
+
+    /// Remove the address of the node, the node becomes unreachable.
+    pub fn remove(&self, node_id: u64) -> Option<NodeAddress> {
+        self.nodes.write().unwrap().remove(&node_id)
+    }
+}
+
+impl NodeResolver for MemNodeResolver {
+    fn resolve(&self, node_id: u64) -> Option<NodeAddress> {
+        self.nodes.read().unwrap().get(&node_id).cloned()
+    }
+
+    /// Returns the ids of the nodes in ascending order.
+    fn node_ids(&self) -> Vec<u64> {
+        let mut node_ids = self
+            .nodes
+            .read()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        node_ids.sort_unstable();
+        node_ids
+    }
+}
+
+#[test]
+fn test_mem_node_resolver_update() {
+    let resolver = MemNodeResolver::new();
+    assert_eq!(resolver.resolve(1), None);
+
+    let address = NodeAddress {
+        node_id: 1,
+        store_id: 1,
+        addr: "local://1".to_owned(),
+    };
+    resolver.clone().update(address.clone());
+    assert_eq!(resolver.resolve(1), Some(address));
+    assert_eq!(resolver.node_ids(), vec![1]);
+
+    resolver.remove(1);
+    assert_eq!(resolver.resolve(1), None);
+    assert!(resolver.node_ids().is_empty());
+}
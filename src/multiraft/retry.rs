@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+use super::error::Error;
+use super::error::ProposalError;
+use super::error::RaftError;
+
+/// The policy of `MultiRaft::propose_with_retry`. The backoff before the
+/// n-th retry is `initial_backoff * 2^(n-1)` capped by `max_backoff`, and a
+/// random jitter of up to half of it is subtracted, so that the clients
+/// failed together don't retry together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The max attempts including the first one.
+    pub max_attempts: usize,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Each attempt fails with `ProposalError::Timeout` if it isn't applied
+    /// within `attempt_timeout`.
+    pub attempt_timeout: Duration,
+    /// The proposal gives up once the `deadline` since the first attempt is
+    /// passed, no matter how many attempts are left.
+    pub deadline: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            attempt_timeout: Duration::from_secs(3),
+            deadline: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the backoff before the retry after `attempt` attempts failed.
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let exp = attempt.saturating_sub(1).min(31) as u32;
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << exp)
+            .min(self.max_backoff);
+        let jitter = rand::thread_rng().gen_range(0.0..0.5);
+        backoff.mul_f64(1.0 - jitter)
+    }
+}
+
+/// What to do after an attempt of the proposal failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryAction {
+    /// Retry after the backoff.
+    Backoff,
+    /// Retry without the backoff, the proposal is forwarded to the hinted
+    /// leader.
+    Immediately,
+    /// Give up with the error.
+    GiveUp,
+}
+
+/// Returns the action after an attempt of the proposal failed with `err`. The
+/// `NotLeader` which hints the leader is retried immediately if the proposal is
+/// forwarded to the leader, otherwise it's returned, so the caller can redirect
+/// the proposal to the node of the leader.
+pub fn retry_action(err: &Error, proposal_forwarding: bool) -> RetryAction {
+    match err {
+        // the leader is being elected.
+        Error::Raft(RaftError::NotLeader(_, _, 0)) => RetryAction::Backoff,
+        Error::Raft(RaftError::NotLeader(..)) if proposal_forwarding => RetryAction::Immediately,
+        Error::Proposal(ProposalError::ProposalQueueFull(_))
+        | Error::Proposal(ProposalError::ClientQuotaExceeded(..))
+        | Error::Proposal(ProposalError::ApplyBacklogFull(_))
+        | Error::Proposal(ProposalError::LogFull(_))
+        | Error::Proposal(ProposalError::Dropped)
+        | Error::Proposal(ProposalError::Stale(_))
+        | Error::Proposal(ProposalError::Timeout) => RetryAction::Backoff,
+        _ => RetryAction::GiveUp,
+    }
+}
+
+#[test]
+fn test_retry_policy_backoff() {
+    let policy = RetryPolicy {
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(100),
+        ..Default::default()
+    };
+    for (attempt, max) in [(1, 10), (2, 20), (3, 40), (4, 80), (5, 100), (64, 100)] {
+        let max = Duration::from_millis(max);
+        let backoff = policy.backoff(attempt);
+        assert!(backoff <= max && backoff > max / 2, "{:?}", backoff);
+    }
+}
+
+#[test]
+fn test_retry_action() {
+    let not_leader = |leader_id| Error::Raft(RaftError::NotLeader(1, 2, leader_id));
+    assert_eq!(retry_action(&not_leader(0), false), RetryAction::Backoff);
+    assert_eq!(retry_action(&not_leader(3), false), RetryAction::GiveUp);
+    assert_eq!(retry_action(&not_leader(3), true), RetryAction::Immediately);
+    assert_eq!(
+        retry_action(&Error::Proposal(ProposalError::ProposalQueueFull(8)), false),
+        RetryAction::Backoff
+    );
+    assert_eq!(
+        retry_action(&Error::Proposal(ProposalError::ApplyBacklogFull(8)), false),
+        RetryAction::Backoff
+    );
+    assert_eq!(
+        retry_action(&Error::RaftGroupNotFound(1), true),
+        RetryAction::GiveUp
+    );
+    // the stopped node never serves the retry.
+    assert_eq!(retry_action(&Error::Shutdown, true), RetryAction::GiveUp);
+}
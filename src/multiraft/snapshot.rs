@@ -0,0 +1,846 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use tracing::debug;
+use tracing::warn;
+
+use super::clock::Clock;
+use super::error::Error;
+
+use crate::proto::Message;
+use crate::proto::MessageType;
+use crate::proto::RaftMessage;
+use crate::proto::SnapshotChunk;
+use crate::proto::SnapshotChunkAck;
+use crate::storage::snapshot_checksum;
+use crate::storage::StorageError;
+
+struct SnapshotStream {
+    snapshot_index: u64,
+    pending: VecDeque<RaftMessage>,
+    inflight: usize,
+}
+
+struct SnapshotInflight {
+    snapshot_index: u64,
+    sent_at: Instant,
+}
+
+/// OutgoingSnapshots streams the large snapshots in fixed-size chunks out-of-band
+/// from the normal raft message flow. Each stream to a replica is flow-controlled
+/// independently by a window of unacked chunks, so that a lagging follower's
+/// snapshot doesn't block others.
+///
+/// At most one snapshot is inflight to a replica, the other snapshots to the
+/// replica are dropped until the inflight one is installed or `inflight_timeout`
+/// elapsed, so that a slow follower isn't hammered with repeated snapshots.
+///
+/// At most `max_inflight` snapshots are inflight from the node, the others are
+/// queued and sent in order once the inflight ones are finished, so that many
+/// lagging followers don't saturate the disk and network at once.
+///
+/// With the install suppression, the appends to the replica with an inflight
+/// snapshot are dropped, and each acked chunk restarts the inflight timeout.
+pub struct OutgoingSnapshots {
+    chunk_size: usize,
+    window: usize,
+    inflight_timeout: Duration,
+    max_inflight: usize,
+    suppress_appends: bool,
+    clock: Arc<dyn Clock>,
+    // (group_id, to_replica) -> stream
+    streams: HashMap<(u64, u64), SnapshotStream>,
+    // (group_id, to_replica) -> inflight snapshot
+    inflights: HashMap<(u64, u64), SnapshotInflight>,
+    // the (group_id, snapshot message) waiting for the limit of inflights.
+    queued: VecDeque<(u64, Message)>,
+}
+
+impl OutgoingSnapshots {
+    pub fn new(
+        chunk_size: usize,
+        window: usize,
+        inflight_timeout: Duration,
+        max_inflight: usize,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            chunk_size,
+            window: std::cmp::max(window, 1),
+            inflight_timeout,
+            max_inflight,
+            suppress_appends: false,
+            clock,
+            streams: HashMap::new(),
+            inflights: HashMap::new(),
+            queued: VecDeque::new(),
+        }
+    }
+
+    /// Enable the install suppression, see
+    /// `MultiRaftConfig::snapshot_install_suppression`.
+    pub fn with_install_suppression(mut self, suppress: bool) -> Self {
+        self.suppress_appends = suppress;
+        self
+    }
+
+    /// Track the snapshot message as inflight to the replica, returns false if
+    /// there is another snapshot inflight to the replica and not timeout, then
+    /// the message should be dropped, or the node reaches `max_inflight`, then
+    /// the message is queued, replacing the queued one to the same replica.
+    /// The append to the replica with an inflight snapshot is dropped if the
+    /// install suppression is enabled, the other messages are always allowed.
+    pub fn begin_inflight(&mut self, group_id: u64, msg: &Message) -> bool {
+        if msg.msg_type() == MessageType::MsgAppend
+            && self.suppress_appends
+            && self.inflights.contains_key(&(group_id, msg.to))
+        {
+            debug!(
+                "group {} drop append to replica {} which is installing a snapshot",
+                group_id, msg.to
+            );
+            return false;
+        }
+        if msg.msg_type() != MessageType::MsgSnapshot {
+            return true;
+        }
+
+        let key = (group_id, msg.to);
+        let snapshot_index = msg
+            .snapshot
+            .as_ref()
+            .and_then(|snap| snap.metadata.as_ref())
+            .map_or(0, |meta| meta.index);
+        if let Some(inflight) = self.inflights.get(&key) {
+            if self.clock.elapsed(inflight.sent_at) < self.inflight_timeout {
+                warn!(
+                    "group {} drop snapshot of index {} to replica {}, the snapshot of index {} is inflight",
+                    group_id, snapshot_index, msg.to, inflight.snapshot_index
+                );
+                return false;
+            }
+        }
+
+        if !self.has_capacity() {
+            debug!(
+                "group {} queue snapshot of index {} to replica {}, {} snapshots are inflight",
+                group_id,
+                snapshot_index,
+                msg.to,
+                self.inflights.len()
+            );
+            self.queued
+                .retain(|(id, queued)| *id != group_id || queued.to != msg.to);
+            self.queued.push_back((group_id, msg.clone()));
+            return false;
+        }
+
+        self.inflights.insert(
+            key,
+            SnapshotInflight {
+                snapshot_index,
+                sent_at: self.clock.now(),
+            },
+        );
+        true
+    }
+
+    #[inline]
+    fn has_capacity(&self) -> bool {
+        self.max_inflight == 0 || self.inflights.len() < self.max_inflight
+    }
+
+    /// Pop the first queued snapshot if the node is below `max_inflight`, it
+    /// should be sent again, then it's tracked by `begin_inflight`.
+    pub fn pop_queued(&mut self) -> Option<(u64, Message)> {
+        if !self.has_capacity() {
+            return None;
+        }
+        self.queued.pop_front()
+    }
+
+    /// Returns the number of the inflight and queued snapshots of the node.
+    pub fn counts(&self) -> (usize, usize) {
+        (self.inflights.len(), self.queued.len())
+    }
+
+    /// Returns true if a snapshot is inflight to the replica.
+    pub fn is_inflight(&self, group_id: u64, to_replica: u64) -> bool {
+        self.inflights.contains_key(&(group_id, to_replica))
+    }
+
+    /// Returns the `(group_id, to_replica, timeout)` of all inflight snapshots.
+    pub fn inflights(&self) -> Vec<(u64, u64, bool)> {
+        self.inflights
+            .iter()
+            .map(|((group_id, to_replica), inflight)| {
+                (
+                    *group_id,
+                    *to_replica,
+                    self.clock.elapsed(inflight.sent_at) >= self.inflight_timeout,
+                )
+            })
+            .collect()
+    }
+
+    /// Stop tracking the inflight snapshot to the replica, it's called after
+    /// the snapshot is installed or failed.
+    pub fn finish_inflight(&mut self, group_id: u64, to_replica: u64) {
+        self.inflights.remove(&(group_id, to_replica));
+        self.streams.remove(&(group_id, to_replica));
+    }
+
+    /// Returns true if the message is a snapshot which exceeds the chunk size.
+    pub fn need_chunk(&self, msg: &Message) -> bool {
+        self.chunk_size != 0
+            && msg.msg_type() == MessageType::MsgSnapshot
+            && msg
+                .snapshot
+                .as_ref()
+                .map_or(false, |snap| snap.data.len() > self.chunk_size)
+    }
+
+    /// Split the snapshot message into chunks and start a stream to the replica,
+    /// the previous stream to the same replica is replaced. Returns the chunks can
+    /// be sent right now.
+    pub fn start(
+        &mut self,
+        group_id: u64,
+        from_node: u64,
+        to_node: u64,
+        msg: Message,
+    ) -> Vec<RaftMessage> {
+        let to_replica = msg.to;
+        let chunks = split_snapshot(group_id, from_node, to_node, msg, self.chunk_size);
+        let snapshot_index = chunks[0].snapshot_chunk.as_ref().unwrap().snapshot_index;
+        let stream = SnapshotStream {
+            snapshot_index,
+            pending: chunks.into(),
+            inflight: 0,
+        };
+        self.streams.insert((group_id, to_replica), stream);
+        self.poll(group_id, to_replica)
+    }
+
+    /// Handle the ack of chunk from the receiver, returns the next chunks of the
+    /// stream can be sent.
+    pub fn ack(&mut self, ack: &SnapshotChunkAck) -> Vec<RaftMessage> {
+        let key = (ack.group_id, ack.from_replica);
+        match self.streams.get_mut(&key) {
+            Some(stream) if stream.snapshot_index == ack.snapshot_index => {
+                stream.inflight = stream.inflight.saturating_sub(1);
+            }
+            // stale ack of the replaced stream.
+            _ => return vec![],
+        }
+        // the receiver is still installing the snapshot.
+        if self.suppress_appends {
+            if let Some(inflight) = self.inflights.get_mut(&key) {
+                inflight.sent_at = self.clock.now();
+            }
+        }
+        self.poll(ack.group_id, ack.from_replica)
+    }
+
+    fn poll(&mut self, group_id: u64, to_replica: u64) -> Vec<RaftMessage> {
+        let key = (group_id, to_replica);
+        let stream = match self.streams.get_mut(&key) {
+            None => return vec![],
+            Some(stream) => stream,
+        };
+
+        let mut chunks = vec![];
+        while stream.inflight < self.window {
+            match stream.pending.pop_front() {
+                None => break,
+                Some(chunk) => {
+                    stream.inflight += 1;
+                    chunks.push(chunk);
+                }
+            }
+        }
+
+        if stream.pending.is_empty() && stream.inflight == 0 {
+            self.streams.remove(&key);
+        }
+        chunks
+    }
+
+    /// Drop the streams and inflight snapshots of the group.
+    pub fn remove_group(&mut self, group_id: u64) {
+        self.streams.retain(|(id, _), _| *id != group_id);
+        self.inflights.retain(|(id, _), _| *id != group_id);
+        self.queued.retain(|(id, _)| *id != group_id);
+    }
+}
+
+/// Split the snapshot message into chunks of `chunk_size` bytes. The first chunk
+/// carries the raft message without snapshot data, which is stepped by the
+/// receiver after the snapshot data is reassembled.
+pub fn split_snapshot(
+    group_id: u64,
+    from_node: u64,
+    to_node: u64,
+    mut msg: Message,
+    chunk_size: usize,
+) -> Vec<RaftMessage> {
+    let mut snapshot = msg.snapshot.take().unwrap_or_default();
+    let data = std::mem::take(&mut snapshot.data);
+    let snapshot_index = snapshot.metadata.as_ref().map_or(0, |meta| meta.index);
+    msg.snapshot = Some(snapshot);
+
+    let from_replica = msg.from;
+    let to_replica = msg.to;
+    let total_size = data.len() as u64;
+    let checksum = snapshot_checksum(&data);
+    let mut chunks = data
+        .chunks(std::cmp::max(chunk_size, 1))
+        .map(|chunk| chunk.to_vec())
+        .collect::<Vec<_>>();
+    if chunks.is_empty() {
+        chunks.push(vec![]);
+    }
+
+    let last_seq = chunks.len() as u64 - 1;
+    let mut msg = Some(msg);
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(seq, data)| {
+            let seq = seq as u64;
+            RaftMessage {
+                group_id,
+                from_node,
+                to_node,
+                msg: None,
+                heartbeats: vec![],
+                snapshot_chunk: Some(SnapshotChunk {
+                    group_id,
+                    from_replica,
+                    to_replica,
+                    snapshot_index,
+                    seq,
+                    total_size,
+                    data,
+                    last: seq == last_seq,
+                    msg: msg.take(),
+                    checksum,
+                }),
+                snapshot_chunk_ack: None,
+                snapshot_checksum: 0,
+                forward_proposal: None,
+                forward_response: None,
+                heartbeat_sent_at: 0,
+                reset_replica: None,
+                correlation_ids: vec![],
+            }
+        })
+        .collect()
+}
+
+/// Verify the data of the snapshot message against the `checksum` carried by
+/// the transfer, returns `SnapshotCorrupt` on mismatch.
+pub fn verify_snapshot(msg: &Message, checksum: u32) -> Result<(), Error> {
+    let data = msg.snapshot.as_ref().map_or(&[][..], |snap| &snap.data[..]);
+    if snapshot_checksum(data) != checksum {
+        return Err(Error::Store(StorageError::SnapshotCorrupt));
+    }
+    Ok(())
+}
+
+struct SnapshotAssembly {
+    snapshot_index: u64,
+    next_seq: u64,
+    checksum: u32,
+    data: Vec<u8>,
+    // the raft message carried by the first chunk.
+    msg: Option<Message>,
+    // the chunks received ahead of `next_seq`, at most the window of the
+    // sender, the chunk further ahead is rejected.
+    buffered: BTreeMap<u64, SnapshotChunk>,
+    received_at: Instant,
+}
+
+impl SnapshotAssembly {
+    fn new(chunk: &SnapshotChunk, received_at: Instant) -> Self {
+        Self {
+            snapshot_index: chunk.snapshot_index,
+            next_seq: 0,
+            checksum: chunk.checksum,
+            data: Vec::with_capacity(chunk.total_size as usize),
+            msg: None,
+            buffered: BTreeMap::new(),
+            received_at,
+        }
+    }
+}
+
+/// IncomingSnapshots reassembles the chunks of snapshots from the leaders.
+///
+/// The replica is installing a snapshot while its chunks are reassembled,
+/// the assembly which doesn't receive a chunk within `install_timeout` is
+/// abandoned by the leader, e.g. the leader is down.
+///
+/// The chunks are checked before they are buffered, the snapshot larger than
+/// `max_snapshot_size` or the chunk more than `window` ahead of the expected
+/// one is rejected, so that a corrupt chunk can't exhaust the memory.
+pub struct IncomingSnapshots {
+    window: u64,
+    max_snapshot_size: u64,
+    install_timeout: Duration,
+    clock: Arc<dyn Clock>,
+    // (group_id, from_replica) -> assembly
+    assemblies: HashMap<(u64, u64), SnapshotAssembly>,
+}
+
+impl IncomingSnapshots {
+    pub fn new(
+        window: usize,
+        max_snapshot_size: u64,
+        install_timeout: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            window: std::cmp::max(window, 1) as u64,
+            max_snapshot_size,
+            install_timeout,
+            clock,
+            assemblies: HashMap::new(),
+        }
+    }
+
+    /// Returns true if the replica of the group is installing a snapshot,
+    /// i.e. it received a chunk of the snapshot within `install_timeout`.
+    pub fn is_installing(&self, group_id: u64) -> bool {
+        self.assemblies.iter().any(|((id, _), assembly)| {
+            *id == group_id && self.clock.elapsed(assembly.received_at) < self.install_timeout
+        })
+    }
+
+    /// Receive a chunk, returns the snapshot message with reassembled data
+    /// once all chunks are received. The chunk ahead of the expected one is
+    /// buffered until the chunks before it are received, the first chunk of a
+    /// restarted stream of the same snapshot starts over. Returns
+    /// `SnapshotCorrupt` if the reassembled data mismatches the checksum, the
+    /// snapshot exceeds `max_snapshot_size` or the chunk is beyond the window.
+    pub fn receive(&mut self, mut chunk: SnapshotChunk) -> Result<Option<Message>, Error> {
+        let key = (chunk.group_id, chunk.from_replica);
+        if chunk.seq == 0 && chunk.msg.is_none() {
+            warn!(
+                "group {} drop the first snapshot chunk without message from replica {}",
+                chunk.group_id, chunk.from_replica
+            );
+            return Ok(None);
+        }
+        if chunk.total_size > self.max_snapshot_size {
+            warn!(
+                "group {} reject snapshot of index {} from replica {}, size {} exceeds {}",
+                chunk.group_id,
+                chunk.snapshot_index,
+                chunk.from_replica,
+                chunk.total_size,
+                self.max_snapshot_size
+            );
+            self.assemblies.remove(&key);
+            return Err(Error::Store(StorageError::SnapshotCorrupt));
+        }
+
+        let now = self.clock.now();
+        let assembly = self
+            .assemblies
+            .entry(key)
+            .or_insert_with(|| SnapshotAssembly::new(&chunk, now));
+        if chunk.snapshot_index < assembly.snapshot_index {
+            warn!(
+                "group {} drop stale snapshot chunk {} of index {} from replica {}",
+                chunk.group_id, chunk.seq, chunk.snapshot_index, chunk.from_replica
+            );
+            return Ok(None);
+        }
+        if chunk.snapshot_index > assembly.snapshot_index
+            || (chunk.seq == 0 && assembly.next_seq != 0)
+        {
+            *assembly = SnapshotAssembly::new(&chunk, now);
+        }
+        assembly.received_at = now;
+
+        if chunk.seq < assembly.next_seq {
+            debug!(
+                "group {} drop duplicate snapshot chunk {} of index {} from replica {}",
+                chunk.group_id, chunk.seq, chunk.snapshot_index, chunk.from_replica
+            );
+            return Ok(None);
+        }
+        if chunk.seq - assembly.next_seq > self.window {
+            warn!(
+                "group {} reject snapshot chunk {} of index {} from replica {}, expect chunk {}",
+                chunk.group_id,
+                chunk.seq,
+                chunk.snapshot_index,
+                chunk.from_replica,
+                assembly.next_seq
+            );
+            self.assemblies.remove(&key);
+            return Err(Error::Store(StorageError::SnapshotCorrupt));
+        }
+        if chunk.seq > assembly.next_seq {
+            assembly.buffered.insert(chunk.seq, chunk);
+            return Ok(None);
+        }
+
+        loop {
+            if chunk.seq == 0 {
+                assembly.msg = chunk.msg.take();
+            }
+            if assembly.data.len() + chunk.data.len() > chunk.total_size as usize {
+                warn!(
+                    "group {} reject snapshot of index {} from replica {}, data exceeds size {}",
+                    chunk.group_id, chunk.snapshot_index, chunk.from_replica, chunk.total_size
+                );
+                self.assemblies.remove(&key);
+                return Err(Error::Store(StorageError::SnapshotCorrupt));
+            }
+            assembly.data.extend_from_slice(&chunk.data);
+            assembly.next_seq += 1;
+            if chunk.last {
+                break;
+            }
+            chunk = match assembly.buffered.remove(&assembly.next_seq) {
+                None => return Ok(None),
+                Some(chunk) => chunk,
+            };
+        }
+
+        let assembly = self.assemblies.remove(&key).unwrap();
+        let mut msg = assembly.msg.unwrap_or_default();
+        if let Some(snapshot) = msg.snapshot.as_mut() {
+            snapshot.data = assembly.data;
+        }
+        verify_snapshot(&msg, assembly.checksum)?;
+        Ok(Some(msg))
+    }
+
+    /// Drop the assemblies of the group.
+    pub fn remove_group(&mut self, group_id: u64) {
+        self.assemblies.retain(|(id, _), _| *id != group_id);
+    }
+}
+
+/// Returns the ack of chunk which is sent back to the leader.
+pub fn chunk_ack(chunk: &SnapshotChunk) -> SnapshotChunkAck {
+    SnapshotChunkAck {
+        group_id: chunk.group_id,
+        from_replica: chunk.to_replica,
+        to_replica: chunk.from_replica,
+        snapshot_index: chunk.snapshot_index,
+        seq: chunk.seq,
+        reject: false,
+    }
+}
+
+/// Returns the ack which rejects the corrupt snapshot, it's sent back to the
+/// leader so that a fresh snapshot is sent.
+pub fn reject_ack(
+    group_id: u64,
+    from_replica: u64,
+    to_replica: u64,
+    snapshot_index: u64,
+) -> SnapshotChunkAck {
+    SnapshotChunkAck {
+        group_id,
+        from_replica,
+        to_replica,
+        snapshot_index,
+        seq: 0,
+        reject: true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::proto::Message;
+    use crate::proto::MessageType;
+    use crate::proto::Snapshot;
+
+    use crate::multiraft::clock::MockClock;
+    use crate::multiraft::clock::SystemClock;
+    use crate::multiraft::error::Error;
+    use crate::storage::snapshot_checksum;
+    use crate::storage::StorageError;
+
+    use super::chunk_ack;
+    use super::verify_snapshot;
+    use super::IncomingSnapshots;
+    use super::OutgoingSnapshots;
+
+    fn snapshot_message(index: u64, size: usize) -> Message {
+        let mut snapshot = Snapshot::default();
+        snapshot.mut_metadata().index = index;
+        snapshot.data = (0..size).map(|i| (i % 251) as u8).collect();
+
+        let mut msg = Message::default();
+        msg.set_msg_type(MessageType::MsgSnapshot);
+        msg.from = 1;
+        msg.to = 2;
+        msg.snapshot = Some(snapshot);
+        msg
+    }
+
+    #[test]
+    fn test_snapshot_chunks_reassemble() {
+        let size = 5 * 1024 * 1024 + 7;
+        let msg = snapshot_message(10, size);
+        let expected = msg.clone();
+
+        let mut outgoing = OutgoingSnapshots::new(
+            64 * 1024,
+            4,
+            Duration::from_secs(60),
+            0,
+            Arc::new(SystemClock),
+        );
+        let mut incoming =
+            IncomingSnapshots::new(8, 1 << 30, Duration::from_secs(60), Arc::new(SystemClock));
+        assert!(outgoing.need_chunk(&msg));
+
+        let mut inflight: VecDeque<_> = outgoing.start(1, 1, 2, msg).into();
+        assert_eq!(inflight.len(), 4);
+
+        let mut received = None;
+        let mut chunks = 0;
+        while let Some(raft_msg) = inflight.pop_front() {
+            let chunk = raft_msg.snapshot_chunk.unwrap();
+            chunks += 1;
+            let ack = chunk_ack(&chunk);
+            if let Some(msg) = incoming.receive(chunk).unwrap() {
+                received = Some(msg);
+            }
+            inflight.extend(outgoing.ack(&ack));
+        }
+
+        assert_eq!(chunks, (size + 64 * 1024 - 1) / (64 * 1024));
+        assert_eq!(received.unwrap(), expected);
+    }
+
+    #[test]
+    fn test_snapshot_chunks_window_per_replica() {
+        let mut outgoing =
+            OutgoingSnapshots::new(1024, 2, Duration::from_secs(60), 0, Arc::new(SystemClock));
+        let first = outgoing.start(1, 1, 2, snapshot_message(10, 10 * 1024));
+        assert_eq!(first.len(), 2);
+
+        // the lagging replica doesn't block the stream to others.
+        let mut msg = snapshot_message(10, 10 * 1024);
+        msg.to = 3;
+        let second = outgoing.start(1, 1, 3, msg);
+        assert_eq!(second.len(), 2);
+
+        // the stale ack is ignored.
+        let mut ack = chunk_ack(first[0].snapshot_chunk.as_ref().unwrap());
+        ack.snapshot_index = 9;
+        assert!(outgoing.ack(&ack).is_empty());
+
+        ack.snapshot_index = 10;
+        assert_eq!(outgoing.ack(&ack).len(), 1);
+    }
+
+    #[test]
+    fn test_snapshot_chunk_out_of_order_buffered() {
+        let msg = snapshot_message(10, 4 * 1024);
+        let expected = msg.clone();
+        let mut outgoing =
+            OutgoingSnapshots::new(1024, 8, Duration::from_secs(60), 0, Arc::new(SystemClock));
+        let mut incoming =
+            IncomingSnapshots::new(8, 1 << 30, Duration::from_secs(60), Arc::new(SystemClock));
+        let mut chunks = outgoing
+            .start(1, 1, 2, msg)
+            .into_iter()
+            .map(|raft_msg| raft_msg.snapshot_chunk.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(chunks.len(), 4);
+
+        // the chunks ahead of the first one and the duplicate are buffered or
+        // dropped, the snapshot is reassembled once the gaps are filled.
+        let first = chunks.remove(0);
+        assert_eq!(incoming.receive(chunks[2].clone()), Ok(None));
+        assert_eq!(incoming.receive(chunks[0].clone()), Ok(None));
+        assert_eq!(incoming.receive(first), Ok(None));
+        assert_eq!(incoming.receive(chunks[0].clone()), Ok(None));
+        assert_eq!(incoming.receive(chunks[1].clone()), Ok(Some(expected)));
+    }
+
+    #[test]
+    fn test_snapshot_chunk_corrupt_rejected() {
+        let mut outgoing =
+            OutgoingSnapshots::new(1024, 8, Duration::from_secs(60), 0, Arc::new(SystemClock));
+        let mut incoming =
+            IncomingSnapshots::new(8, 1 << 30, Duration::from_secs(60), Arc::new(SystemClock));
+        let mut chunks = outgoing.start(1, 1, 2, snapshot_message(10, 3 * 1024));
+        assert_eq!(chunks.len(), 3);
+
+        // flip a byte of the snapshot data in transfer.
+        chunks[1].snapshot_chunk.as_mut().unwrap().data[0] ^= 0xff;
+        let mut result = Ok(None);
+        for chunk in chunks {
+            result = incoming.receive(chunk.snapshot_chunk.unwrap());
+        }
+        assert_eq!(result, Err(Error::Store(StorageError::SnapshotCorrupt)));
+
+        let msg = snapshot_message(10, 1024);
+        let checksum = snapshot_checksum(&msg.snapshot.as_ref().unwrap().data);
+        assert!(verify_snapshot(&msg, checksum).is_ok());
+        assert!(verify_snapshot(&msg, checksum ^ 1).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_chunk_oversize_rejected() {
+        let mut outgoing =
+            OutgoingSnapshots::new(1024, 8, Duration::from_secs(60), 0, Arc::new(SystemClock));
+        let mut incoming =
+            IncomingSnapshots::new(1, 4 * 1024, Duration::from_secs(60), Arc::new(SystemClock));
+        let mut chunks = outgoing
+            .start(1, 1, 2, snapshot_message(10, 4 * 1024))
+            .into_iter()
+            .map(|raft_msg| raft_msg.snapshot_chunk.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(chunks.len(), 4);
+        let corrupt = Err(Error::Store(StorageError::SnapshotCorrupt));
+
+        // the size on the wire exceeds the limit.
+        let mut chunk = chunks[0].clone();
+        chunk.total_size = u64::MAX;
+        assert_eq!(incoming.receive(chunk), corrupt);
+
+        // the chunk beyond the window of the expected one.
+        assert_eq!(incoming.receive(chunks[0].clone()), Ok(None));
+        assert_eq!(incoming.receive(chunks[3].clone()), corrupt);
+
+        // the data beyond the total size.
+        assert_eq!(incoming.receive(chunks[0].clone()), Ok(None));
+        chunks[1].data.extend_from_slice(&[0; 4 * 1024]);
+        assert_eq!(incoming.receive(chunks[1].clone()), corrupt);
+    }
+
+    #[test]
+    fn test_snapshot_one_inflight_per_replica() {
+        let mut outgoing =
+            OutgoingSnapshots::new(1024, 2, Duration::from_secs(60), 0, Arc::new(SystemClock));
+        let msg = snapshot_message(10, 1024);
+        assert!(outgoing.begin_inflight(1, &msg));
+        assert!(outgoing.is_inflight(1, 2));
+
+        // the repeated snapshot to the recovering replica is dropped.
+        assert!(!outgoing.begin_inflight(1, &snapshot_message(11, 1024)));
+
+        // the other replica and non-snapshot messages are not affected.
+        let mut other = snapshot_message(10, 1024);
+        other.to = 3;
+        assert!(outgoing.begin_inflight(1, &other));
+        assert!(outgoing.begin_inflight(1, &Message::default()));
+        assert_eq!(outgoing.inflights().len(), 2);
+
+        // a new snapshot can be sent after the inflight one is finished.
+        outgoing.finish_inflight(1, 2);
+        assert!(outgoing.begin_inflight(1, &snapshot_message(11, 1024)));
+
+        // or timeout.
+        let clock = MockClock::new();
+        let mut outgoing =
+            OutgoingSnapshots::new(1024, 2, Duration::from_secs(60), 0, Arc::new(clock.clone()));
+        assert!(outgoing.begin_inflight(1, &msg));
+        assert_eq!(outgoing.inflights(), vec![(1, 2, false)]);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(outgoing.inflights(), vec![(1, 2, true)]);
+        assert!(outgoing.begin_inflight(1, &msg));
+    }
+
+    #[test]
+    fn test_snapshot_max_inflight_queued() {
+        let mut outgoing =
+            OutgoingSnapshots::new(1024, 2, Duration::from_secs(60), 1, Arc::new(SystemClock));
+        let to = |group_id: u64, to: u64, index: u64| {
+            let mut msg = snapshot_message(index, 1024);
+            msg.to = to;
+            (group_id, msg)
+        };
+        let (group_id, msg) = to(1, 2, 10);
+        assert!(outgoing.begin_inflight(group_id, &msg));
+        assert!(outgoing.pop_queued().is_none());
+
+        // the snapshots beyond the limit are queued, the later one to the same
+        // replica replaces the queued one.
+        for (group_id, msg) in [to(2, 2, 10), to(3, 2, 10), to(2, 2, 11)] {
+            assert!(!outgoing.begin_inflight(group_id, &msg));
+        }
+        assert_eq!(outgoing.counts(), (1, 2));
+        assert!(outgoing.pop_queued().is_none());
+
+        // the queued ones are popped in order once the inflight one finished.
+        outgoing.finish_inflight(1, 2);
+        let (group_id, msg) = outgoing.pop_queued().unwrap();
+        assert_eq!(group_id, 3);
+        assert!(outgoing.begin_inflight(group_id, &msg));
+        assert!(outgoing.pop_queued().is_none());
+        outgoing.finish_inflight(3, 2);
+        let (group_id, msg) = outgoing.pop_queued().unwrap();
+        assert_eq!(group_id, 2);
+        assert_eq!(msg.snapshot.unwrap().metadata.unwrap().index, 11);
+        assert_eq!(outgoing.counts(), (0, 0));
+    }
+
+    #[test]
+    fn test_snapshot_install_suppression() {
+        let clock = MockClock::new();
+        let mut outgoing =
+            OutgoingSnapshots::new(1024, 1, Duration::from_secs(60), 0, Arc::new(clock.clone()))
+                .with_install_suppression(true);
+        let mut incoming =
+            IncomingSnapshots::new(8, 1 << 30, Duration::from_secs(60), Arc::new(clock.clone()));
+        let mut append = Message::default();
+        append.set_msg_type(MessageType::MsgAppend);
+        append.from = 1;
+        append.to = 2;
+        assert!(outgoing.begin_inflight(1, &append));
+
+        // the follower installs the slow snapshot chunk by chunk, the appends
+        // to it are paused meanwhile.
+        let msg = snapshot_message(10, 3 * 1024);
+        assert!(outgoing.begin_inflight(1, &msg));
+        let mut inflight: VecDeque<_> = outgoing.start(1, 1, 2, msg).into();
+        let mut installed = None;
+        while let Some(raft_msg) = inflight.pop_front() {
+            assert!(!outgoing.begin_inflight(1, &append));
+            // each chunk takes most of the inflight timeout.
+            clock.advance(Duration::from_secs(50));
+            let chunk = raft_msg.snapshot_chunk.unwrap();
+            let ack = chunk_ack(&chunk);
+            installed = incoming.receive(chunk).unwrap();
+            assert_eq!(incoming.is_installing(1), installed.is_none());
+            inflight.extend(outgoing.ack(&ack));
+            // the progressing snapshot doesn't timeout.
+            assert_eq!(outgoing.inflights(), vec![(1, 2, false)]);
+        }
+        assert!(installed.is_some());
+
+        // the appends resume after the snapshot is installed.
+        outgoing.finish_inflight(1, 2);
+        assert!(outgoing.begin_inflight(1, &append));
+
+        // the assembly abandoned by the leader is no longer installing.
+        let mut chunks = outgoing.start(1, 1, 2, snapshot_message(11, 3 * 1024));
+        let first = chunks.remove(0).snapshot_chunk.unwrap();
+        assert_eq!(incoming.receive(first), Ok(None));
+        assert!(incoming.is_installing(1));
+        assert!(!incoming.is_installing(2));
+        clock.advance(Duration::from_secs(60));
+        assert!(!incoming.is_installing(1));
+    }
+}
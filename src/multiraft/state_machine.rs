@@ -0,0 +1,150 @@
+use std::cell::Cell;
+
+use tokio::sync::oneshot;
+
+use crate::proto::AppWriteRequest;
+
+use super::error::Error;
+use super::event::ApplyEvent;
+
+/// The output of the entry applied by the `StateMachine`, the proposal of the
+/// entry is responded with success.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ApplyOutput;
+
+/// The error of applying an entry by the `StateMachine`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApplyError {
+    /// The entry is rejected by the logic of the state machine, e.g. its
+    /// precondition doesn't hold. The proposal of the entry fails with
+    /// `ProposalError::Rejected`, but the entry still counts as applied and
+    /// the applied index advances. Every replica must reject the same entry.
+    Reject(String),
+    /// The state machine can no longer apply, e.g. its on-disk state is
+    /// corrupted. The entry and the entries after it aren't applied, the group
+    /// is poisoned with the `GroupFailed` event and its applied index stays
+    /// before the entry.
+    Fatal(String),
+}
+
+/// StateMachine applies the committed normal entries of the groups in the
+/// apply actor, rather than the application consuming the `Apply` events.
+/// The `Apply` events of the data entries aren't emitted if it's set, the
+/// proposal of the entry is responded by the result of `apply`. It's called
+/// in the apply actor in the order of the log of each group, so it should not
+/// block for long.
+///
+/// `apply`, `snapshot` and `restore` must not call back into `MultiRaft`, e.g.
+/// block on `write` or `group_status`: the call waits for the actors, which
+/// wait for the apply to return, so it deadlocks. Such a call panics, which poisons the group. The
+/// write which follows up the applied entry is enqueued by `ApplyHandle`
+/// instead, it's proposed after the current batch of the group is applied.
+pub trait StateMachine: Send + Sync + 'static {
+    /// Apply the committed entry, `apply.tx` is responded by the actor. The
+    /// follow-up writes are enqueued by `handle`.
+    fn apply(
+        &self,
+        apply: &ApplyEvent,
+        handle: &mut ApplyHandle,
+    ) -> Result<ApplyOutput, ApplyError>;
+
+    /// Returns the state of the group at the last applied entry, it's carried
+    /// by the snapshot of the group, which is saved to compact the log or sent
+    /// to the lagging replicas, see `MultiRaft::trigger_snapshot`. The
+    /// snapshot fails with `SnapshotFailed` if it returns the error, and the
+    /// log isn't compacted. The default is the empty state, which is only
+    /// correct for the state machine without state.
+    fn snapshot(&self, _group_id: u64) -> Result<Vec<u8>, ApplyError> {
+        Ok(vec![])
+    }
+
+    /// Replace the state of the group by the `state` returned by `snapshot`,
+    /// it's called when the replica installs the snapshot, before the entries
+    /// after the snapshot are applied. The empty state is the state of a new
+    /// group. The group is poisoned if it returns the error.
+    fn restore(&self, _group_id: u64, _state: &[u8]) -> Result<(), ApplyError> {
+        Ok(())
+    }
+}
+
+/// ApplyHandle is provided to `StateMachine::apply` to propose the follow-up
+/// writes without awaiting the actors. The writes are proposed by the local
+/// replica like `MultiRaft::write` after the batch of the group is applied,
+/// in the order they are enqueued.
+///
+/// Every replica applies the entry, so a follow-up enqueued unconditionally
+/// is proposed once by each replica, the ones of the followers fail with
+/// `NotLeader` unless `proposal_forwarding` is enabled. The follow-up should
+/// carry the `client_id` and the `sequence` derived from the entry, e.g. its
+/// index, so the duplicates are applied once.
+#[derive(Default)]
+pub struct ApplyHandle {
+    proposals: Vec<(AppWriteRequest, oneshot::Sender<Result<(), Error>>)>,
+}
+
+impl ApplyHandle {
+    /// Enqueue the write, returns the receiver of its result, which is
+    /// awaited outside of `apply`, e.g. by a spawned task.
+    pub fn propose(&mut self, request: AppWriteRequest) -> oneshot::Receiver<Result<(), Error>> {
+        let (tx, rx) = oneshot::channel();
+        self.proposals.push((request, tx));
+        rx
+    }
+
+    pub(crate) fn take_proposals(
+        &mut self,
+    ) -> Vec<(AppWriteRequest, oneshot::Sender<Result<(), Error>>)> {
+        std::mem::take(&mut self.proposals)
+    }
+}
+
+thread_local! {
+    // set while the state machine applies an entry on the thread.
+    static APPLYING: Cell<bool> = Cell::new(false);
+}
+
+/// Returns true if it's called by `StateMachine::apply`.
+pub(crate) fn is_applying() -> bool {
+    APPLYING.with(|applying| applying.get())
+}
+
+/// Call the state machine, `is_applying` is true during the call, even if the
+/// state machine panics it's reset.
+fn call_applying<R>(f: impl FnOnce() -> R) -> R {
+    struct Reset;
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            APPLYING.with(|applying| applying.set(false));
+        }
+    }
+
+    APPLYING.with(|applying| applying.set(true));
+    let _reset = Reset;
+    f()
+}
+
+/// Apply the entry by the state machine, see `call_applying`.
+pub(crate) fn apply_entry(
+    state_machine: &dyn StateMachine,
+    apply: &ApplyEvent,
+    handle: &mut ApplyHandle,
+) -> Result<ApplyOutput, ApplyError> {
+    call_applying(|| state_machine.apply(apply, handle))
+}
+
+/// Snapshot the state of the group by the state machine, see `call_applying`.
+pub(crate) fn snapshot_state(
+    state_machine: &dyn StateMachine,
+    group_id: u64,
+) -> Result<Vec<u8>, ApplyError> {
+    call_applying(|| state_machine.snapshot(group_id))
+}
+
+/// Restore the state of the group by the state machine, see `call_applying`.
+pub(crate) fn restore_state(
+    state_machine: &dyn StateMachine,
+    group_id: u64,
+    state: &[u8],
+) -> Result<(), ApplyError> {
+    call_applying(|| state_machine.restore(group_id, state))
+}
@@ -0,0 +1,48 @@
+use crate::proto::ConfState;
+use crate::proto::ReplicaDesc;
+
+use super::ids::GroupId;
+use super::ids::NodeId;
+use super::ids::ReplicaId;
+use super::raft_group::GroupState;
+use super::raft_group::ReplicaRole;
+
+/// The groups hosted by the node at a point in time, it's collected in one
+/// pass of the actor loop, so the groups are consistent with each other. It's
+/// used by the admin tooling, e.g. to reconcile the desired placement of the
+/// replicas with the actual one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topology {
+    pub node_id: NodeId,
+    /// The groups sorted by the group id.
+    pub groups: Vec<GroupTopology>,
+}
+
+impl Topology {
+    pub fn group(&self, group_id: GroupId) -> Option<&GroupTopology> {
+        self.groups
+            .binary_search_by_key(&group_id, |group| group.group_id)
+            .ok()
+            .map(|index| &self.groups[index])
+    }
+}
+
+/// The membership and leadership of a group seen by the local replica.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupTopology {
+    pub group_id: GroupId,
+    pub replica_id: ReplicaId,
+    pub role: ReplicaRole,
+    /// The lifecycle state of the group, e.g. quiesced, frozen or failed.
+    pub state: GroupState,
+    pub term: u64,
+    pub commit_index: u64,
+    /// The leader known by the local replica, none if it's unknown.
+    pub leader: Option<ReplicaDesc>,
+    /// The voters and learners of the group, including the outgoing voters
+    /// in the joint consensus.
+    pub conf_state: ConfState,
+    /// The replicas of `conf_state` sorted by the replica id, the replica
+    /// missing in the replica cache is absent.
+    pub replicas: Vec<ReplicaDesc>,
+}
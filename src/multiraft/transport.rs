@@ -1,14 +1,28 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
 use futures::Future;
 
-use tracing::info;
+use tracing::error;
+use tracing::trace;
 
+use super::correlation::CorrelationIds;
+use super::dropped::DropReason;
+use super::dropped::DroppedMessage;
+use super::dropped::DroppedMessageObserver;
+use super::dropped::DroppedMessages;
 use super::error::Error;
+use super::multiraft::NO_NODE;
 use super::node::NodeManager;
+use super::resolver::NodeResolver;
+use super::snapshot::OutgoingSnapshots;
 
+use crate::proto::CoalescedHeartbeat;
 use crate::proto::Message;
 use crate::proto::MessageType;
 use crate::proto::RaftMessage;
 use crate::proto::RaftMessageResponse;
+use crate::storage::snapshot_checksum;
 use crate::storage::MultiRaftStorage;
 use crate::storage::RaftStorage;
 
@@ -35,58 +49,222 @@ where
         msg_impl: M,
     ) -> Self::ListenFuture<'life0>;
 
+    /// Send the message to `msg.to_node`. The transport should deliver the
+    /// high priority messages, see `message_priority`, ahead of the low
+    /// priority ones queued for the same node, so that the elections and the
+    /// heartbeats aren't delayed by the bulk appends under the heavy write
+    /// load. The messages of the same priority are delivered in order.
     fn send(&self, msg: RaftMessage) -> Result<(), Error>;
 
+    /// Send the messages in one call, e.g. the messages of the readies handled
+    /// in one iteration of the actor, so the transport can amortize the locks
+    /// and the channel sends. Returns the index in `msgs` and the error of the
+    /// messages failed to be sent. The default sends them one by one.
+    fn send_batch(&self, msgs: Vec<RaftMessage>) -> Vec<(usize, Error)> {
+        msgs.into_iter()
+            .enumerate()
+            .filter_map(|(index, msg)| self.send(msg).err().map(|err| (index, err)))
+            .collect()
+    }
+
     type StopFuture<'life0>: Future<Output = Result<(), Error>>
     where
         Self: 'life0;
 
     fn stop(&self, node_id: u64) -> Self::StopFuture<'_>;
 
+    /// Returns the counters of the messages sent and received by the transport
+    /// since it's created or the counters are reset. The default has no
+    /// counters.
+    fn stats(&self) -> TransportStats {
+        TransportStats::default()
+    }
+
+    /// Reset the counters of `stats`.
+    fn reset_stats(&self) {}
+
+    /// Inject the resolver with which the node `node_id` addresses the
+    /// messages, the message to a node which can't be resolved must be failed
+    /// with `TransportError::UnknownNode` rather than dropped. It's called by
+    /// `MultiRaft::new` with the `node_resolver` of the extensions. The
+    /// default ignores the resolver.
+    fn set_resolver(&self, _node_id: u64, _resolver: Arc<dyn NodeResolver>) {}
+
+    /// Inject the observer of the messages from the node `node_id` which are
+    /// dropped by the transport after `send` returns, e.g. by the partition,
+    /// the overflow of the queue or the failure of the delivery. It's called
+    /// by `MultiRaft::new`, so the drops are counted by
+    /// `MultiRaft::dropped_message_counts`. The default ignores the observer.
+    fn set_dropped_observer(&self, _node_id: u64, _observer: Arc<dyn DroppedMessageObserver>) {}
+
     // fn close();
 }
 
-pub async fn send_messages<MI, TR, RS, MRS>(
+/// The counters of the messages of a transport, see `Transport::stats`.
+/// Comparing the messages sent to a node with the ones received by it tells
+/// where the messages are lost, e.g. an asymmetric partition.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransportStats {
+    /// The number of the messages sent by the message type, the type is None
+    /// if the message doesn't carry a raft message, e.g. the snapshot chunk
+    /// ack or the forwarded proposal.
+    pub sent: HashMap<Option<MessageType>, u64>,
+    /// The number of the messages received by the message type.
+    pub received: HashMap<Option<MessageType>, u64>,
+    /// The bytes of the messages sent by the destination node.
+    pub bytes_sent: HashMap<u64, u64>,
+    /// The bytes of the messages received by the destination node.
+    pub bytes_received: HashMap<u64, u64>,
+    /// The number of the messages failed to be sent, e.g. the destination is
+    /// unreachable.
+    pub send_errors: u64,
+    /// The number of the messages dropped after they are sent, e.g. by the
+    /// partition or because the destination isn't listening.
+    pub drops: u64,
+}
+
+/// Returns the type of the message counted by `TransportStats`, the snapshot
+/// chunk is counted as `MsgSnapshot`.
+pub(crate) fn stats_message_type(msg: &RaftMessage) -> Option<MessageType> {
+    match (msg.msg.as_ref(), msg.snapshot_chunk.as_ref()) {
+        (Some(msg), _) => Some(msg.msg_type()),
+        (None, Some(_)) => Some(MessageType::MsgSnapshot),
+        (None, None) => None,
+    }
+}
+
+/// The priority of the message in the queue of its destination node, see
+/// `Transport::send`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessagePriority {
+    /// The control messages, e.g. the votes, the heartbeats, the responses
+    /// and the acks of snapshot chunks.
+    High,
+    /// The bulk messages, i.e. the appends, the snapshots with data and their
+    /// chunks, and the forwarded proposals.
+    Low,
+}
+
+/// Returns the priority of the message.
+pub fn message_priority(msg: &RaftMessage) -> MessagePriority {
+    if msg.snapshot_chunk.is_some() || msg.forward_proposal.is_some() {
+        return MessagePriority::Low;
+    }
+    match msg.msg.as_ref().map(|msg| msg.msg_type()) {
+        Some(MessageType::MsgAppend) | Some(MessageType::MsgSnapshot) => MessagePriority::Low,
+        _ => MessagePriority::High,
+    }
+}
+
+/// Buffer the messages of the group to `outbox`, the heartbeats are coalesced
+/// to be sent by node, the heartbeat responses report the `applied` index of
+/// the local replica unless it's 0. The appends carry the correlation ids of
+/// their entries tracked by `correlation_ids`. The buffered messages are sent
+/// by `Outbox::flush`.
+pub async fn send_messages<RS, MRS>(
     from_node_id: u64,
     storage: &MRS,
-    transport: &TR,
+    outbox: &mut Outbox,
     node_mgr: &mut NodeManager,
+    snapshots: &mut OutgoingSnapshots,
     group_id: u64,
+    applied: u64,
+    correlation_ids: &CorrelationIds,
     msgs: Vec<Message>,
 ) where
-    MI: MessageInterface,
-    TR: Transport<MI>,
     RS: RaftStorage,
     MRS: MultiRaftStorage<RS>,
 {
     for msg in msgs {
         match msg.msg_type() {
-            MessageType::MsgHeartbeat => {
-                info!(
-                    "node {} drop indvidual heartbeat message to replica {}",
-                    from_node_id, msg.to
+            MessageType::MsgHeartbeat | MessageType::MsgHeartbeatResponse => {
+                trace!(
+                    "node {} coalesce indvidual {:?} message to replica {}",
+                    from_node_id,
+                    msg.msg_type(),
+                    msg.to
                 );
+                coalesce_heartbeat(storage, node_mgr, group_id, applied, msg).await
             }
-            MessageType::MsgHeartbeatResponse => {
-                info!(
-                    "node {} drop indvidual heartbeat response message to replica {}",
-                    from_node_id, msg.to
-                );
+            _ => {
+                let correlation_ids = correlation_ids.of_append(&msg);
+                send_message(
+                    storage,
+                    outbox,
+                    node_mgr,
+                    snapshots,
+                    group_id,
+                    correlation_ids,
+                    msg,
+                )
+                .await
             }
-            _ => send_message(storage, transport, node_mgr, group_id, msg).await,
         }
     }
 }
 
-pub async fn send_message<MI, TR, RS, MRS>(
+/// Buffer the heartbeat (or heartbeat response) of the group to the node
+/// where the `msg.to` replica is located, the buffered heartbeats are sent
+/// in one node level message by the actor. The `applied` index is reported
+/// in the heartbeat response.
+async fn coalesce_heartbeat<RS, MRS>(
     storage: &MRS,
-    transport: &TR,
     node_mgr: &mut NodeManager,
     group_id: u64,
+    applied: u64,
+    msg: Message,
+) where
+    RS: RaftStorage,
+    MRS: MultiRaftStorage<RS>,
+{
+    // the heartbeat to the replica which isn't known yet, e.g. removed
+    // concurrently, is dropped, raft sends it again by the next heartbeat.
+    let to_replica = match storage.replica_desc(group_id, msg.to).await {
+        Ok(Some(replica)) if replica.node_id != NO_NODE => replica,
+        res => {
+            error!(
+                "group {} drop {:?} to replica {}, the replica desc is {:?}",
+                group_id,
+                msg.msg_type(),
+                msg.to,
+                res
+            );
+            return;
+        }
+    };
+
+    let msg_type = msg.msg_type();
+    let heartbeat = CoalescedHeartbeat {
+        group_id,
+        from_replica: msg.from,
+        to_replica: msg.to,
+        term: msg.term,
+        commit: msg.commit,
+        context: msg.context,
+        quiesce: false,
+        applied: match msg_type {
+            MessageType::MsgHeartbeatResponse => applied,
+            _ => 0,
+        },
+    };
+
+    node_mgr.add_node(to_replica.node_id, group_id);
+    let node = node_mgr.get_mut_node(&to_replica.node_id).unwrap();
+    match msg_type {
+        MessageType::MsgHeartbeat => node.heartbeats.push(heartbeat),
+        _ => node.heartbeat_responses.push(heartbeat),
+    }
+}
+
+async fn send_message<RS, MRS>(
+    storage: &MRS,
+    outbox: &mut Outbox,
+    node_mgr: &mut NodeManager,
+    snapshots: &mut OutgoingSnapshots,
+    group_id: u64,
+    correlation_ids: Vec<u64>,
     msg: Message,
 ) where
-    MI: MessageInterface,
-    TR: Transport<MI>,
     RS: RaftStorage,
     MRS: MultiRaftStorage<RS>,
 {
@@ -108,11 +286,124 @@ pub async fn send_message<MI, TR, RS, MRS>(
         node_mgr.add_node(to_replica.node_id, group_id);
     }
 
+    // at most one snapshot is inflight to the replica.
+    if !snapshots.begin_inflight(group_id, &msg) {
+        return;
+    }
+
+    // the large snapshot is streamed in chunks.
+    let (to, is_snapshot) = (msg.to, msg.msg_type() == MessageType::MsgSnapshot);
+    if snapshots.need_chunk(&msg) {
+        let chunks = snapshots.start(group_id, from_replica.node_id, to_replica.node_id, msg);
+        for chunk in chunks {
+            outbox.push(group_id, to, true, chunk);
+        }
+        return;
+    }
+
+    // the snapshot is verified by the receiver before it's installed.
+    let checksum = msg
+        .snapshot
+        .as_ref()
+        .map_or(0, |snap| snapshot_checksum(&snap.data));
     let msg = RaftMessage {
         group_id,
         from_node: from_replica.node_id,
         to_node: to_replica.node_id,
         msg: Some(msg),
+        heartbeats: vec![],
+        snapshot_chunk: None,
+        snapshot_chunk_ack: None,
+        snapshot_checksum: checksum,
+        forward_proposal: None,
+        forward_response: None,
+        heartbeat_sent_at: 0,
+        reset_replica: None,
+        correlation_ids,
     };
-    transport.send(msg).unwrap();
+    outbox.push(group_id, to, is_snapshot, msg);
+}
+
+/// Send the message by the transport, the failed message is recorded to
+/// `dropped`.
+pub fn send_raft_message<MI, TR>(
+    transport: &TR,
+    dropped: &DroppedMessages,
+    msg: RaftMessage,
+) -> Result<(), Error>
+where
+    MI: MessageInterface,
+    TR: Transport<MI>,
+{
+    let meta = DroppedMessage::from_raft_message(&msg);
+    transport.send(msg).map_err(|err| {
+        dropped.record(DropReason::SendFailed, &meta);
+        err
+    })
+}
+
+/// Outbox buffers the raft messages of the readies handled in one iteration of
+/// the actor, they are handed to the transport by `flush` in one `send_batch`
+/// rather than one `send` per message.
+#[derive(Default)]
+pub struct Outbox {
+    msgs: Vec<RaftMessage>,
+    // the tuple is (group_id, to_replica, is_snapshot) and the metadata of
+    // the buffered messages, which are used to report the failures.
+    targets: Vec<((u64, u64, bool), DroppedMessage)>,
+}
+
+impl Outbox {
+    pub fn push(&mut self, group_id: u64, to_replica: u64, is_snapshot: bool, msg: RaftMessage) {
+        let meta = DroppedMessage::from_raft_message(&msg);
+        self.targets
+            .push(((group_id, to_replica, is_snapshot), meta));
+        self.msgs.push(msg);
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.msgs.is_empty()
+    }
+
+    /// Send the buffered messages by the transport, in one `send_batch` if
+    /// `batch`, otherwise one by one. Returns the `(group_id, to_replica,
+    /// is_snapshot)` of the messages failed to be sent, which should be
+    /// reported to raft, the failures are recorded to `dropped`.
+    pub fn flush<MI, TR>(
+        &mut self,
+        transport: &TR,
+        dropped: &DroppedMessages,
+        batch: bool,
+    ) -> Vec<(u64, u64, bool)>
+    where
+        MI: MessageInterface,
+        TR: Transport<MI>,
+    {
+        let mut failures = vec![];
+        let msgs = std::mem::take(&mut self.msgs);
+        let targets = std::mem::take(&mut self.targets);
+
+        let errors = if batch {
+            transport.send_batch(msgs)
+        } else {
+            msgs.into_iter()
+                .enumerate()
+                .filter_map(|(index, msg)| transport.send(msg).err().map(|err| (index, err)))
+                .collect()
+        };
+        for (index, err) in errors {
+            let (target, meta) = &targets[index];
+            error!(
+                "group {} send message to replica {} error: {}",
+                target.0, target.1, err
+            );
+            dropped.record(DropReason::SendFailed, meta);
+            failures.push(*target);
+        }
+        // the chunks of a snapshot fail together.
+        failures.sort_unstable();
+        failures.dedup();
+        failures
+    }
 }
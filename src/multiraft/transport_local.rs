@@ -1,8 +1,22 @@
 use std::collections::hash_map::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::marker::PhantomData;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::sync::Mutex as SyncMutex;
+use std::sync::RwLock as SyncRwLock;
+use std::time::Duration;
 
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
 use tracing::info;
+use tracing::trace;
+use tracing::warn;
 
 use tokio::sync::mpsc::channel;
 use tokio::sync::mpsc::Receiver;
@@ -13,14 +27,24 @@ use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
 
 use futures::Future;
+use prost::Message as ProstMessage;
 
+use crate::proto::MessageType;
 use crate::proto::RaftMessage;
 use crate::proto::RaftMessageResponse;
 
+use super::dropped::DropReason;
+use super::dropped::DroppedMessage;
+use super::dropped::DroppedMessageObserver;
 use super::error::Error;
 use super::error::TransportError;
+use super::resolver::NodeResolver;
+use super::transport::message_priority;
+use super::transport::stats_message_type;
 use super::transport::MessageInterface;
+use super::transport::MessagePriority;
 use super::transport::Transport;
+use super::transport::TransportStats;
 
 struct LocalServer<M: MessageInterface> {
     tx: Sender<(
@@ -66,16 +90,394 @@ impl<M: MessageInterface> LocalServer<M> {
     }
 }
 
+/// The action of the message which is decided by the filter of `LocalTransport`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterAction {
+    /// Deliver the message.
+    Pass,
+    /// Drop the message.
+    Drop,
+    /// Deliver the message after the duration.
+    Delay(Duration),
+    /// Fail the delivery, `send` returns `TransportError::Unreachable`.
+    Fail,
+}
+
+type MessageFilter = Arc<dyn Fn(&RaftMessage) -> FilterAction + Send + Sync>;
+
+type LocalServers<M> = Arc<RwLock<HashMap<u64, LocalServer<M>>>>;
+
+/// The messages sent by the transport are buffered here while a `SimNetwork`
+/// is attached, until they are delivered by the simulator.
+struct SimBuffer {
+    msgs: Vec<RaftMessage>,
+    // the messages are delivered in the order they are sent if none.
+    rng: Option<StdRng>,
+}
+
+/// The messages waiting to be delivered to a node.
+#[derive(Default)]
+struct NodeQueue {
+    high: VecDeque<RaftMessage>,
+    low: VecDeque<RaftMessage>,
+    // a task is delivering the messages of the queue.
+    delivering: bool,
+    // the messages are queued but not delivered until the node is released.
+    held: bool,
+}
+
+type NodeQueues = Arc<SyncMutex<HashMap<u64, NodeQueue>>>;
+
+/// How the messages are queued to a node.
+#[derive(Clone, Copy)]
+struct QueueOptions {
+    // if false, the messages to a node are delivered in the order they are sent.
+    prioritized: bool,
+    // the messages to a node beyond it are dropped, unbounded if 0.
+    capacity: usize,
+}
+
+/// The observers of the dropped messages by the node which sends them.
+type DroppedObservers = Arc<SyncRwLock<HashMap<u64, Arc<dyn DroppedMessageObserver>>>>;
+
+/// Notify the observer of the node which sends the dropped message.
+fn report_dropped(observers: &DroppedObservers, reason: DropReason, msg: &DroppedMessage) {
+    if let Some(observer) = observers.read().unwrap().get(&msg.from_node) {
+        observer.on_dropped(reason, msg);
+    }
+}
+
+type Counters<K> = SyncRwLock<HashMap<K, AtomicU64>>;
+
+/// Add `n` to the counter of `key`, the counter is created by the first add.
+fn add_counter<K: std::hash::Hash + Eq>(counters: &Counters<K>, key: K, n: u64) {
+    if let Some(counter) = counters.read().unwrap().get(&key) {
+        counter.fetch_add(n, Ordering::Relaxed);
+        return;
+    }
+    counters
+        .write()
+        .unwrap()
+        .entry(key)
+        .or_default()
+        .fetch_add(n, Ordering::Relaxed);
+}
+
+fn load_counters<K: std::hash::Hash + Eq + Copy>(counters: &Counters<K>) -> HashMap<K, u64> {
+    counters
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(key, counter)| (*key, counter.load(Ordering::Relaxed)))
+        .collect()
+}
+
+/// The counters of `Transport::stats` shared by the clones of the transport.
+#[derive(Default)]
+struct LocalStats {
+    sent: Counters<Option<MessageType>>,
+    received: Counters<Option<MessageType>>,
+    bytes_sent: Counters<u64>,
+    bytes_received: Counters<u64>,
+    send_errors: AtomicU64,
+    drops: AtomicU64,
+}
+
+impl LocalStats {
+    fn record_sent(&self, msg: &RaftMessage) {
+        add_counter(&self.sent, stats_message_type(msg), 1);
+        add_counter(&self.bytes_sent, msg.to_node, msg.encoded_len() as u64);
+    }
+
+    fn record_received(&self, msg_type: Option<MessageType>, to_node: u64, bytes: u64) {
+        add_counter(&self.received, msg_type, 1);
+        add_counter(&self.bytes_received, to_node, bytes);
+    }
+
+    fn load(&self) -> TransportStats {
+        TransportStats {
+            sent: load_counters(&self.sent),
+            received: load_counters(&self.received),
+            bytes_sent: load_counters(&self.bytes_sent),
+            bytes_received: load_counters(&self.bytes_received),
+            send_errors: self.send_errors.load(Ordering::Relaxed),
+            drops: self.drops.load(Ordering::Relaxed),
+        }
+    }
+
+    fn reset(&self) {
+        self.sent.write().unwrap().clear();
+        self.received.write().unwrap().clear();
+        self.bytes_sent.write().unwrap().clear();
+        self.bytes_received.write().unwrap().clear();
+        self.send_errors.store(0, Ordering::Relaxed);
+        self.drops.store(0, Ordering::Relaxed);
+    }
+}
+
 pub struct LocalTransport<M: MessageInterface> {
-    servers: Arc<RwLock<HashMap<u64, LocalServer<M>>>>,
+    servers: LocalServers<M>,
+    queues: NodeQueues,
+    // if false, the messages to a node are delivered in the order they are sent.
+    prioritized: Arc<AtomicBool>,
+    // the messages to a node beyond it are dropped, unbounded if 0.
+    queue_capacity: Arc<AtomicUsize>,
+    filter: Arc<SyncRwLock<Option<MessageFilter>>>,
+    isolated: Arc<SyncRwLock<HashSet<u64>>>,
+    dropped_observers: DroppedObservers,
+    sim: Arc<SyncMutex<Option<SimBuffer>>>,
+    // the simulated latency of the link (from_node, to_node).
+    link_latencies: Arc<SyncRwLock<HashMap<(u64, u64), Duration>>>,
+    // the resolver of the node by which the messages from it are addressed.
+    resolvers: Arc<SyncRwLock<HashMap<u64, Arc<dyn NodeResolver>>>>,
+    stats: Arc<LocalStats>,
+}
+
+impl<M: MessageInterface> Clone for LocalTransport<M> {
+    fn clone(&self) -> Self {
+        Self {
+            servers: self.servers.clone(),
+            queues: self.queues.clone(),
+            prioritized: self.prioritized.clone(),
+            queue_capacity: self.queue_capacity.clone(),
+            filter: self.filter.clone(),
+            isolated: self.isolated.clone(),
+            dropped_observers: self.dropped_observers.clone(),
+            sim: self.sim.clone(),
+            link_latencies: self.link_latencies.clone(),
+            resolvers: self.resolvers.clone(),
+            stats: self.stats.clone(),
+        }
+    }
 }
 
 impl<M: MessageInterface> LocalTransport<M> {
     pub fn new() -> Self {
         Self {
             servers: Default::default(),
+            queues: Default::default(),
+            prioritized: Arc::new(AtomicBool::new(true)),
+            queue_capacity: Default::default(),
+            filter: Default::default(),
+            isolated: Default::default(),
+            dropped_observers: Default::default(),
+            sim: Default::default(),
+            link_latencies: Default::default(),
+            resolvers: Default::default(),
+            stats: Default::default(),
+        }
+    }
+
+    /// Set the filter which decides to pass, drop or delay the message
+    /// sent by this transport. It is used to test partition scenarios.
+    pub fn set_filter<F>(&self, filter: F)
+    where
+        F: Fn(&RaftMessage) -> FilterAction + Send + Sync + 'static,
+    {
+        *self.filter.write().unwrap() = Some(Arc::new(filter));
+    }
+
+    /// Enable or disable the message priority, it's enabled by default. The
+    /// high priority messages to a node jump ahead of the low priority ones
+    /// which are waiting to be delivered, see `message_priority`.
+    pub fn set_prioritized(&self, prioritized: bool) {
+        self.prioritized.store(prioritized, Ordering::Relaxed);
+    }
+
+    /// Remove the filter, all messages are passed.
+    pub fn clear_filter(&self) {
+        *self.filter.write().unwrap() = None;
+    }
+
+    /// Bound the number of the messages waiting to be delivered to each node,
+    /// the messages beyond it are dropped by `DropReason::QueueOverflow`. The
+    /// queues are unbounded if 0, which is the default.
+    pub fn set_queue_capacity(&self, capacity: usize) {
+        self.queue_capacity.store(capacity, Ordering::Relaxed);
+    }
+
+    fn queue_options(&self) -> QueueOptions {
+        QueueOptions {
+            prioritized: self.prioritized.load(Ordering::Relaxed),
+            capacity: self.queue_capacity.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Hold the messages to the node, they are queued but not delivered until
+    /// `release` is called. It simulates a slow node, the deliveries to the
+    /// other nodes are not blocked by it.
+    pub fn hold(&self, node_id: u64) {
+        self.queues.lock().unwrap().entry(node_id).or_default().held = true;
+    }
+
+    /// Deliver the messages held to the node and the ones sent afterwards.
+    pub fn release(&self, node_id: u64) {
+        {
+            let mut queues = self.queues.lock().unwrap();
+            let queue = queues.entry(node_id).or_default();
+            queue.held = false;
+            if queue.delivering || (queue.high.is_empty() && queue.low.is_empty()) {
+                return;
+            }
+            queue.delivering = true;
         }
+        let (servers, queues) = (self.servers.clone(), self.queues.clone());
+        let (stats, dropped) = (self.stats.clone(), self.dropped_observers.clone());
+        spawn_delivery(servers, queues, stats, dropped, node_id);
     }
+
+    /// Partition the node from all other nodes, messages from or to
+    /// the node are dropped until `reconnect` is called.
+    pub fn isolate(&self, node_id: u64) {
+        self.isolated.write().unwrap().insert(node_id);
+    }
+
+    /// Heal the partition of the node.
+    pub fn reconnect(&self, node_id: u64) {
+        self.isolated.write().unwrap().remove(&node_id);
+    }
+
+    /// Inject the simulated latency to the messages sent from `from_node` to
+    /// `to_node`, it's added to the delay of the filter.
+    pub fn set_link_latency(&self, from_node: u64, to_node: u64, latency: Duration) {
+        self.link_latencies
+            .write()
+            .unwrap()
+            .insert((from_node, to_node), latency);
+    }
+
+    /// Remove the simulated latencies of all links.
+    pub fn clear_link_latencies(&self) {
+        self.link_latencies.write().unwrap().clear();
+    }
+
+    fn filter_action(&self, msg: &RaftMessage) -> FilterAction {
+        {
+            let isolated = self.isolated.read().unwrap();
+            if isolated.contains(&msg.from_node) || isolated.contains(&msg.to_node) {
+                return FilterAction::Drop;
+            }
+        }
+
+        match self.filter.read().unwrap().as_ref() {
+            None => FilterAction::Pass,
+            Some(filter) => filter(msg),
+        }
+    }
+}
+
+/// Deliver the message to the server of `msg.to_node` and wait for the
+/// response, the message has been received by the node once it returns. The
+/// servers are not locked while waiting, so a slow node doesn't block the
+/// deliveries to the others.
+async fn deliver<M: MessageInterface>(
+    servers: &LocalServers<M>,
+    stats: &LocalStats,
+    dropped_observers: &DroppedObservers,
+    msg: RaftMessage,
+) -> Result<RaftMessageResponse, Error> {
+    let to_node = msg.to_node;
+    let meta = DroppedMessage::from_raft_message(&msg);
+    // get server by to
+    let server_tx = match servers.read().await.get(&to_node) {
+        Some(local_server) => local_server.tx.clone(),
+        None => {
+            stats.drops.fetch_add(1, Ordering::Relaxed);
+            report_dropped(dropped_observers, DropReason::SendFailed, &meta);
+            return Err(Error::Transport(TransportError::ServerNodeFound(to_node)));
+        }
+    };
+    stats.record_received(stats_message_type(&msg), to_node, msg.encoded_len() as u64);
+
+    let (tx, rx) = oneshot::channel();
+    // send reqeust and receive response
+    let res = match server_tx.send((msg, tx)).await {
+        Ok(_) => rx.await.ok(),
+        Err(_) => None,
+    };
+    let res = res.unwrap_or_else(|| {
+        Err(Error::Transport(TransportError::Server(format!(
+            "server ({}) stopped",
+            to_node
+        ))))
+    });
+    if res.is_err() {
+        report_dropped(dropped_observers, DropReason::SendFailed, &meta);
+    }
+    res
+}
+
+/// Queue the messages to the node, and spawn the task which delivers the
+/// queued messages one by one if there is none. The high priority messages
+/// are queued ahead of the low priority ones if prioritized, the messages
+/// beyond the capacity of the queue are dropped.
+fn enqueue<M: MessageInterface>(
+    servers: LocalServers<M>,
+    queues: NodeQueues,
+    stats: Arc<LocalStats>,
+    dropped_observers: DroppedObservers,
+    options: QueueOptions,
+    to_node: u64,
+    msgs: Vec<RaftMessage>,
+) {
+    {
+        let mut queues = queues.lock().unwrap();
+        let queue = queues.entry(to_node).or_default();
+        for msg in msgs {
+            if options.capacity != 0 && queue.high.len() + queue.low.len() >= options.capacity {
+                trace!("drop message {} -> {} by overflow", msg.from_node, to_node);
+                stats.drops.fetch_add(1, Ordering::Relaxed);
+                let meta = DroppedMessage::from_raft_message(&msg);
+                report_dropped(&dropped_observers, DropReason::QueueOverflow, &meta);
+                continue;
+            }
+            if options.prioritized && message_priority(&msg) == MessagePriority::High {
+                queue.high.push_back(msg);
+            } else {
+                queue.low.push_back(msg);
+            }
+        }
+        if queue.delivering || queue.held {
+            return;
+        }
+        queue.delivering = true;
+    }
+
+    spawn_delivery(servers, queues, stats, dropped_observers, to_node);
+}
+
+/// Spawn the task which delivers the queued messages to the node one by one,
+/// the task exits once the queue is empty or the node is held.
+fn spawn_delivery<M: MessageInterface>(
+    servers: LocalServers<M>,
+    queues: NodeQueues,
+    stats: Arc<LocalStats>,
+    dropped_observers: DroppedObservers,
+    to_node: u64,
+) {
+    tokio::spawn(async move {
+        loop {
+            let msg = {
+                let mut queues = queues.lock().unwrap();
+                let queue = queues.get_mut(&to_node).unwrap();
+                let msg = if queue.held {
+                    None
+                } else {
+                    queue.high.pop_front().or_else(|| queue.low.pop_front())
+                };
+                match msg {
+                    Some(msg) => msg,
+                    None => {
+                        queue.delivering = false;
+                        return;
+                    }
+                }
+            };
+            if let Err(err) = deliver(&servers, &stats, &dropped_observers, msg).await {
+                trace!("deliver message error: {}", err);
+            }
+        }
+    });
 }
 
 impl<M: MessageInterface> Transport<M> for LocalTransport<M> {
@@ -122,35 +524,94 @@ impl<M: MessageInterface> Transport<M> for LocalTransport<M> {
 
     #[tracing::instrument(name = "LocalTransport::send", skip(self, msg))]
     fn send(&self, msg: RaftMessage) -> Result<(), Error> {
-        let (_from_node, to_node) = (msg.from_node, msg.to_node);
-
-        let servers = self.servers.clone();
+        match self.send_batch(vec![msg]).pop() {
+            None => Ok(()),
+            Some((_, err)) => Err(err),
+        }
+    }
 
-        // get client
-        let send_fn = async move {
-            // get server by to
-            let rl = servers.read().await;
-            if !rl.contains_key(&to_node) {
-                return Err(Error::Transport(TransportError::ServerNodeFound(to_node)));
+    /// Send the messages with one lock of the simulator and the latencies, the
+    /// messages to the same node are queued and delivered by one task, the
+    /// high priority ones first, the delayed ones are queued after the delay.
+    #[tracing::instrument(name = "LocalTransport::send_batch", skip(self, msgs))]
+    fn send_batch(&self, msgs: Vec<RaftMessage>) -> Vec<(usize, Error)> {
+        let mut failures = vec![];
+        let mut passed = Vec::with_capacity(msgs.len());
+        let resolvers = self.resolvers.read().unwrap();
+        for (index, msg) in msgs.into_iter().enumerate() {
+            let (from_node, to_node) = (msg.from_node, msg.to_node);
+            if let Some(resolver) = resolvers.get(&from_node) {
+                if resolver.resolve(to_node).is_none() {
+                    let err = Error::Transport(TransportError::UnknownNode(to_node));
+                    self.stats.send_errors.fetch_add(1, Ordering::Relaxed);
+                    failures.push((index, err));
+                    continue;
+                }
             }
+            let delay = match self.filter_action(&msg) {
+                FilterAction::Pass => None,
+                FilterAction::Drop => {
+                    trace!("drop message {} -> {} by filter", from_node, to_node);
+                    self.stats.record_sent(&msg);
+                    self.stats.drops.fetch_add(1, Ordering::Relaxed);
+                    let meta = DroppedMessage::from_raft_message(&msg);
+                    report_dropped(&self.dropped_observers, DropReason::Filtered, &meta);
+                    continue;
+                }
+                FilterAction::Delay(delay) => Some(delay),
+                FilterAction::Fail => {
+                    trace!("fail message {} -> {} by filter", from_node, to_node);
+                    let err = Error::Transport(TransportError::Unreachable(to_node));
+                    self.stats.send_errors.fetch_add(1, Ordering::Relaxed);
+                    failures.push((index, err));
+                    continue;
+                }
+            };
+            self.stats.record_sent(&msg);
+            passed.push((msg, delay));
+        }
+        drop(resolvers);
+        if passed.is_empty() {
+            return failures;
+        }
 
-            let (tx, rx) = oneshot::channel();
-            // send reqeust
-            let local_server = rl.get(&to_node).unwrap();
-            local_server.tx.send((msg, tx)).await.unwrap();
+        // the delay is decided by the simulator if it's attached.
+        if let Some(sim) = self.sim.lock().unwrap().as_mut() {
+            sim.msgs.extend(passed.into_iter().map(|(msg, _)| msg));
+            return failures;
+        }
 
-            // and receive response
-            if let Ok(res) = rx.await {
-                res
-            } else {
-                Err(Error::Transport(TransportError::Server(format!(
-                    "server ({}) stopped",
-                    to_node
-                ))))
+        let mut deliveries: HashMap<(u64, Option<Duration>), Vec<RaftMessage>> = HashMap::new();
+        {
+            let latencies = self.link_latencies.read().unwrap();
+            for (msg, delay) in passed {
+                let latency = latencies.get(&(msg.from_node, msg.to_node)).cloned();
+                let delay = match (delay, latency) {
+                    (Some(delay), Some(latency)) => Some(delay + latency),
+                    (delay, latency) => delay.or(latency),
+                };
+                deliveries
+                    .entry((msg.to_node, delay))
+                    .or_default()
+                    .push(msg);
             }
-        };
-        tokio::spawn(send_fn);
-        Ok(())
+        }
+
+        let options = self.queue_options();
+        for ((to_node, delay), msgs) in deliveries {
+            let (servers, queues) = (self.servers.clone(), self.queues.clone());
+            let (stats, dropped) = (self.stats.clone(), self.dropped_observers.clone());
+            match delay {
+                None => enqueue(servers, queues, stats, dropped, options, to_node, msgs),
+                Some(delay) => {
+                    tokio::spawn(async move {
+                        tokio::time::sleep(delay).await;
+                        enqueue(servers, queues, stats, dropped, options, to_node, msgs);
+                    });
+                }
+            }
+        }
+        failures
     }
 
     type StopFuture<'life0> = impl Future<Output = Result<(), Error>> + 'life0
@@ -167,4 +628,123 @@ impl<M: MessageInterface> Transport<M> for LocalTransport<M> {
             Ok(())
         }
     }
+
+    /// The messages dropped by the filter or the partition are counted as sent
+    /// and dropped, the ones failed by the filter are counted as the errors.
+    fn stats(&self) -> TransportStats {
+        self.stats.load()
+    }
+
+    fn reset_stats(&self) {
+        self.stats.reset()
+    }
+
+    fn set_resolver(&self, node_id: u64, resolver: Arc<dyn NodeResolver>) {
+        self.resolvers.write().unwrap().insert(node_id, resolver);
+    }
+
+    /// The messages dropped by the filter or the partition, the overflow of
+    /// the queue and the failed deliveries are reported to the observer of
+    /// the node which sends them.
+    fn set_dropped_observer(&self, node_id: u64, observer: Arc<dyn DroppedMessageObserver>) {
+        self.dropped_observers
+            .write()
+            .unwrap()
+            .insert(node_id, observer);
+    }
+}
+
+/// SimNetwork is a discrete-event simulator of the network of `LocalTransport`.
+///
+/// While it's attached, the messages sent by the transport are buffered rather
+/// than delivered, and they are delivered only by `deliver_n` or `deliver_all`.
+/// The partitions and the filters of the transport are still applied when the
+/// messages are sent, the delays of the filters are ignored since the simulator
+/// decides when the messages arrive. With a seed the buffered messages are
+/// delivered in a random order derived from the seed, otherwise in the order
+/// they are sent.
+///
+/// Combined with the manual tick and `MultiRaft::flush`, which waits until the
+/// delivered messages are stepped, the ticks and the deliveries are driven in
+/// lockstep, so a scenario is reproducible from the seed. The election timeout
+/// should not be randomized, e.g. `min_election_tick == max_election_tick`.
+pub struct SimNetwork<M: MessageInterface> {
+    transport: LocalTransport<M>,
+}
+
+impl<M: MessageInterface> SimNetwork<M> {
+    /// Attach the simulator to the transport, it's detached when the simulator
+    /// is dropped and the buffered messages are discarded.
+    pub fn attach(transport: &LocalTransport<M>, seed: Option<u64>) -> Self {
+        *transport.sim.lock().unwrap() = Some(SimBuffer {
+            msgs: vec![],
+            rng: seed.map(StdRng::seed_from_u64),
+        });
+        Self {
+            transport: transport.clone(),
+        }
+    }
+
+    /// Returns the number of the buffered messages.
+    pub fn pending(&self) -> usize {
+        self.transport
+            .sim
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |sim| sim.msgs.len())
+    }
+
+    /// Deliver at most `n` of the messages buffered when it's called, the
+    /// messages are delivered one by one, each one is received by the node
+    /// before the next one is delivered. Returns the number of the delivered
+    /// messages.
+    ///
+    /// The messages of different nodes may be buffered in any order while the
+    /// nodes are handling, so with a seed they are ordered by the sender before
+    /// they are picked, and it should be called after the nodes are flushed.
+    pub async fn deliver_n(&self, n: usize) -> usize {
+        let msgs = match self.transport.sim.lock().unwrap().as_mut() {
+            None => return 0,
+            Some(sim) => {
+                let n = std::cmp::min(n, sim.msgs.len());
+                match sim.rng.as_mut() {
+                    None => sim.msgs.drain(..n).collect::<Vec<_>>(),
+                    Some(rng) => {
+                        // the sort is stable, the messages of a node keep the
+                        // order they are sent.
+                        sim.msgs.sort_by_key(|msg| msg.from_node);
+                        (0..n)
+                            .map(|_| {
+                                let i = rng.gen_range(0..sim.msgs.len());
+                                sim.msgs.remove(i)
+                            })
+                            .collect()
+                    }
+                }
+            }
+        };
+
+        let delivered = msgs.len();
+        for msg in msgs {
+            let transport = &self.transport;
+            let dropped = &transport.dropped_observers;
+            if let Err(err) = deliver(&transport.servers, &transport.stats, dropped, msg).await {
+                warn!("simulator deliver message error: {}", err);
+            }
+        }
+        delivered
+    }
+
+    /// Deliver all messages buffered when it's called, the messages sent
+    /// during the delivery are left for the next one.
+    pub async fn deliver_all(&self) -> usize {
+        self.deliver_n(usize::MAX).await
+    }
+}
+
+impl<M: MessageInterface> Drop for SimNetwork<M> {
+    fn drop(&mut self) {
+        *self.transport.sim.lock().unwrap() = None;
+    }
 }
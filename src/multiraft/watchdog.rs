@@ -0,0 +1,121 @@
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+use tokio::task::JoinError;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tracing::error;
+
+use super::config::MultiRaftConfig;
+use super::event::Event;
+use super::event::NodeFailureEvent;
+use super::leaders::LocalLeaders;
+use super::multiraft_actor::panic_message;
+use super::multiraft_actor::ActorRemains;
+
+/// ActorWatchdog supervises the actor of the node. If the actor exits before
+/// the node is stopped, e.g. it dies of a panic which isn't isolated to a
+/// group, the watchdog emits `Event::NodeFailure`, and if `restart` it spawns
+/// a new actor with the remains of the dead one, see `ActorRemains`. The
+/// restarts are delayed by an exponential backoff and bounded by
+/// `max_restarts`, so a deterministic panic doesn't restart forever.
+///
+/// The watchdog exits once the node is stopped or the actor isn't restarted,
+/// so its task is finished if and only if no actor is running.
+pub struct ActorWatchdog<F> {
+    node_id: u64,
+    restart: bool,
+    restart_backoff: Duration,
+    max_restarts: usize,
+    spawn_actor: F,
+    remains: Arc<Mutex<Option<ActorRemains>>>,
+    local_leaders: LocalLeaders,
+    event_tx: Sender<Vec<Event>>,
+}
+
+impl<F> ActorWatchdog<F>
+where
+    F: Fn(ActorRemains) -> JoinHandle<()> + Send + 'static,
+{
+    pub fn spawn(
+        cfg: &MultiRaftConfig,
+        node_id: u64,
+        actor: JoinHandle<()>,
+        spawn_actor: F,
+        remains: Arc<Mutex<Option<ActorRemains>>>,
+        local_leaders: LocalLeaders,
+        event_tx: Sender<Vec<Event>>,
+        stop: watch::Receiver<bool>,
+    ) -> JoinHandle<()> {
+        let watchdog = ActorWatchdog {
+            node_id,
+            restart: cfg.restart_actor_on_panic,
+            restart_backoff: Duration::from_millis(cfg.actor_restart_backoff),
+            max_restarts: cfg.max_actor_restarts,
+            spawn_actor,
+            remains,
+            local_leaders,
+            event_tx,
+        };
+
+        tokio::spawn(async move {
+            watchdog.start(actor, stop).await;
+        })
+    }
+
+    async fn start(self, mut actor: JoinHandle<()>, mut stop: watch::Receiver<bool>) {
+        let mut restarts = 0;
+        loop {
+            let res = (&mut actor).await;
+            if *stop.borrow() {
+                break;
+            }
+
+            // the replicas of the dead actor are recreated as followers, the
+            // leaderships it reported are stale.
+            self.local_leaders.clear();
+            let reason = exit_reason(res);
+            let remains = self.remains.lock().unwrap().take();
+            let restarted = self.restart && restarts < self.max_restarts && remains.is_some();
+            error!(
+                "node {} actor died unexpectedly: {}, restarted: {}, restarts: {}",
+                self.node_id, reason, restarted, restarts
+            );
+            let event = Event::NodeFailure(NodeFailureEvent {
+                node_id: self.node_id,
+                reason,
+                restarted,
+            });
+            let _ = self.event_tx.send(vec![event]).await;
+
+            let remains = match remains {
+                Some(remains) if restarted => remains,
+                _ => break,
+            };
+            let backoff = self
+                .restart_backoff
+                .saturating_mul(2u32.saturating_pow(restarts as u32));
+            tokio::select! {
+                _ = stop.changed() => {
+                    if *stop.borrow() {
+                        break;
+                    }
+                }
+                _ = sleep(backoff) => {}
+            }
+            restarts += 1;
+            actor = (self.spawn_actor)(remains);
+        }
+    }
+}
+
+fn exit_reason(res: Result<(), JoinError>) -> String {
+    match res {
+        Ok(_) => "the actor exited".to_owned(),
+        Err(err) if err.is_panic() => panic_message(err.into_panic().as_ref()),
+        Err(err) => err.to_string(),
+    }
+}
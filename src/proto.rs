@@ -411,11 +411,21 @@ pub fn transmute_raft_hard_state(hs: raft::prelude::HardState) -> HardState {
     unsafe { transmute(hs) }
 }
 
+#[inline]
+pub fn transmute_raft_conf_state(cs: raft::prelude::ConfState) -> ConfState {
+    unsafe { transmute(cs) }
+}
+
 #[inline]
 pub fn transmute_raft_snapshot(snapshot: raft::prelude::Snapshot) -> Snapshot {
     unsafe { transmute(snapshot) }
 }
 
+#[inline]
+pub fn transmute_conf_change_v2(cc: ConfChangeV2) -> raft::prelude::ConfChangeV2 {
+    unsafe { transmute(cc) }
+}
+
 #[inline]
 pub fn transmute_entries(entries: Vec<Entry>) -> Vec<raft::prelude::Entry> {
     unsafe { transmute(entries) }
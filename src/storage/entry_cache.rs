@@ -0,0 +1,160 @@
+use std::collections::VecDeque;
+
+use crate::proto::Entry;
+
+/// EntryCache holds the recently appended entries of a group in memory, the
+/// leader and followers read them repeatedly (e.g. for retransmission) right after
+/// they are written, so that these reads don't need to hit the `RaftStorage`.
+///
+/// The cached entries are always continuous, and the total size is bounded by
+/// `max_size`, the oldest entries are evicted if exceeded.
+pub struct EntryCache {
+    entries: VecDeque<Entry>,
+    size: usize,
+    max_size: usize,
+}
+
+impl EntryCache {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            size: 0,
+            max_size,
+        }
+    }
+
+    #[inline]
+    fn first_index(&self) -> Option<u64> {
+        self.entries.front().map(|e| e.index)
+    }
+
+    #[inline]
+    fn last_index(&self) -> Option<u64> {
+        self.entries.back().map(|e| e.index)
+    }
+
+    /// Append the entries which have been persisted to the cache, the cached
+    /// entries conflict with `entries` are truncated.
+    pub fn append(&mut self, entries: Vec<Entry>) {
+        if entries.is_empty() {
+            return;
+        }
+
+        let first = entries[0].index;
+        match (self.first_index(), self.last_index()) {
+            (Some(cache_first), Some(cache_last)) => {
+                if first <= cache_first || first > cache_last.saturating_add(1) {
+                    // overwrite the whole cache or there is a gap.
+                    self.clear();
+                } else {
+                    while self.last_index().map_or(false, |last| last >= first) {
+                        let entry = self.entries.pop_back().unwrap();
+                        self.size -= entry.compute_size() as usize;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        for entry in entries {
+            self.size += entry.compute_size() as usize;
+            self.entries.push_back(entry);
+        }
+
+        while self.size > self.max_size {
+            match self.entries.pop_front() {
+                None => break,
+                Some(entry) => self.size -= entry.compute_size() as usize,
+            }
+        }
+    }
+
+    /// Returns the entries in the range `[low, high)` if they are all cached.
+    pub fn entries(&self, low: u64, high: u64) -> Option<Vec<Entry>> {
+        let (first, last) = match (self.first_index(), self.last_index()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return None,
+        };
+
+        if low < first || high > last.saturating_add(1) || low >= high {
+            return None;
+        }
+
+        let lo = (low - first) as usize;
+        let hi = (high - first) as usize;
+        Some(self.entries.range(lo..hi).cloned().collect())
+    }
+
+    /// Returns the term of entry `idx` if it is cached.
+    pub fn term(&self, idx: u64) -> Option<u64> {
+        let first = self.first_index()?;
+        if idx < first {
+            return None;
+        }
+        self.entries.get((idx - first) as usize).map(|e| e.term)
+    }
+
+    /// Discards the cached entries prior to `compact_index`.
+    pub fn compact_to(&mut self, compact_index: u64) {
+        while self.first_index().map_or(false, |first| first < compact_index) {
+            let entry = self.entries.pop_front().unwrap();
+            self.size -= entry.compute_size() as usize;
+        }
+    }
+
+    /// Discards all cached entries, it is called when a snapshot is installed.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.size = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::proto::Entry;
+
+    use super::EntryCache;
+
+    fn new_entry(index: u64, term: u64) -> Entry {
+        let mut e = Entry::default();
+        e.term = term;
+        e.index = index;
+        e
+    }
+
+    #[test]
+    fn test_entry_cache_append_and_truncate() {
+        let mut cache = EntryCache::new(usize::MAX);
+        cache.append(vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)]);
+        assert_eq!(cache.entries(3, 6).unwrap().len(), 3);
+        assert_eq!(cache.entries(2, 4), None);
+        assert_eq!(cache.entries(4, 7), None);
+        assert_eq!(cache.term(4), Some(4));
+
+        // conflict entries are truncated.
+        cache.append(vec![new_entry(4, 6)]);
+        assert_eq!(cache.entries(3, 5).unwrap(), vec![new_entry(3, 3), new_entry(4, 6)]);
+        assert_eq!(cache.term(5), None);
+
+        // gap clears the cache.
+        cache.append(vec![new_entry(7, 6)]);
+        assert_eq!(cache.entries(3, 4), None);
+        assert_eq!(cache.entries(7, 8).unwrap(), vec![new_entry(7, 6)]);
+    }
+
+    #[test]
+    fn test_entry_cache_bounded_and_compact() {
+        let size = new_entry(1, 1).compute_size() as usize;
+        let mut cache = EntryCache::new(size * 2);
+        cache.append(vec![new_entry(1, 1), new_entry(2, 1), new_entry(3, 1)]);
+        assert_eq!(cache.entries(1, 2), None);
+        assert_eq!(cache.entries(2, 4).unwrap().len(), 2);
+
+        cache.compact_to(3);
+        assert_eq!(cache.entries(2, 3), None);
+        assert_eq!(cache.term(3), Some(1));
+
+        cache.clear();
+        assert_eq!(cache.term(3), None);
+    }
+}
@@ -5,6 +5,8 @@ use std::sync::RwLock;
 use std::sync::RwLockReadGuard;
 use std::sync::RwLockWriteGuard;
 
+use futures::future::ready;
+use futures::future::Ready;
 use futures::Future;
 use tokio::sync::RwLock as AsyncRwLock;
 
@@ -17,11 +19,14 @@ use crate::proto::ReplicaDesc;
 use crate::proto::Snapshot;
 use crate::proto::SnapshotMetadata;
 
+use crate::storage::LogBounds;
 use crate::storage::MultiRaftStorage;
 use crate::storage::RaftSnapshotBuilder;
 use crate::storage::RaftState;
+use crate::storage::snapshot_checksum;
 use crate::storage::RaftStorage;
 use crate::storage::StorageError;
+use crate::storage::WriteBatch;
 
 use super::storage::Result;
 use super::RaftStorageImpl;
@@ -36,11 +41,16 @@ pub struct MemStorageCore {
     entries: Vec<Entry>,
     // Metadata of the last snapshot received.
     snapshot_metadata: SnapshotMetadata,
+    // Data of the last snapshot received and its checksum.
+    snapshot_data: Vec<u8>,
+    snapshot_checksum: u32,
     // If it is true, the next snapshot will return a
     // SnapshotTemporarilyUnavailable error.
     trigger_snap_unavailable: bool,
     // Peers that are fetching entries asynchronously.
     trigger_log_unavailable: bool,
+    // The number of the next write batches which fail before any state is modified.
+    trigger_write_errors: usize,
     // Stores get entries context.
 }
 
@@ -78,6 +88,11 @@ impl MemStorageCore {
         Ok(())
     }
 
+    /// Get the metadata of the last snapshot received.
+    pub fn snapshot_metadata(&self) -> &SnapshotMetadata {
+        &self.snapshot_metadata
+    }
+
     /// Saves the current conf state.
     pub fn set_conf_state(&mut self, cs: ConfState) {
         self.raft_state.conf_state = cs;
@@ -91,7 +106,7 @@ impl MemStorageCore {
     fn first_index(&self) -> u64 {
         match self.entries.first() {
             Some(e) => e.index,
-            None => self.snapshot_metadata.index + 1,
+            None => self.snapshot_metadata.index.saturating_add(1),
         }
     }
 
@@ -116,6 +131,8 @@ impl MemStorageCore {
         }
 
         self.snapshot_metadata = meta.clone();
+        self.snapshot_checksum = snapshot_checksum(&snapshot.data);
+        self.snapshot_data = std::mem::take(&mut snapshot.data);
 
         self.raft_state.hard_state.term = cmp::max(self.raft_state.hard_state.term, meta.term);
         self.raft_state.hard_state.commit = index;
@@ -126,32 +143,24 @@ impl MemStorageCore {
         Ok(())
     }
 
+    /// Returns the latest snapshot saved by `apply_snapshot` or
+    /// `compact_to_snapshot` with its data, the state at the snapshot index is
+    /// only known by the snapshot saved at it.
     fn snapshot(&self) -> Snapshot {
         let mut snapshot = Snapshot::default();
-
-        // We assume all entries whose indexes are less than `hard_state.commit`
-        // have been applied, so use the latest commit index to construct the snapshot.
-        // TODO: This is not true for async ready.
-        let meta = snapshot.mut_metadata();
-        meta.index = self.raft_state.hard_state.commit;
-        meta.term = match meta.index.cmp(&self.snapshot_metadata.index) {
-            cmp::Ordering::Equal => self.snapshot_metadata.term,
-            cmp::Ordering::Greater => {
-                let offset = self.entries[0].index;
-                self.entries[(meta.index - offset) as usize].term
-            }
-            cmp::Ordering::Less => {
-                panic!(
-                    "commit {} < snapshot_metadata.index {}",
-                    meta.index, self.snapshot_metadata.index
-                );
-            }
-        };
-
-        meta.set_conf_state(self.raft_state.conf_state.clone());
+        snapshot.set_metadata(self.snapshot_metadata.clone());
+        snapshot.data = self.snapshot_data.clone();
         snapshot
     }
 
+    /// Returns an error if the data of the persisted snapshot mismatches its checksum.
+    fn check_snapshot(&self) -> Result<()> {
+        if snapshot_checksum(&self.snapshot_data) != self.snapshot_checksum {
+            return Err(StorageError::SnapshotCorrupt);
+        }
+        Ok(())
+    }
+
     /// Discards all log entries prior to compact_index.
     /// It is the application's responsibility to not attempt to compact an index
     /// greater than RaftLog.applied.
@@ -165,7 +174,7 @@ impl MemStorageCore {
             return Ok(());
         }
 
-        if compact_index > self.last_index() + 1 {
+        if compact_index > self.last_index().saturating_add(1) {
             panic!(
                 "compact not received raft logs: {}, last index: {}",
                 compact_index,
@@ -180,6 +189,30 @@ impl MemStorageCore {
         Ok(())
     }
 
+    /// Save the snapshot as the latest snapshot and discard the entries before
+    /// `compact_index` up to its index, the following entries and the hard
+    /// state are kept.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the snapshot index is higher than the last index.
+    pub fn compact_to_snapshot(
+        &mut self,
+        mut snapshot: Snapshot,
+        compact_index: u64,
+    ) -> Result<()> {
+        let meta = snapshot.take_metadata();
+        if meta.index <= self.snapshot_metadata.index {
+            return Err(StorageError::SnapshotOutOfDate);
+        }
+
+        self.compact(std::cmp::min(compact_index, meta.index.saturating_add(1)))?;
+        self.snapshot_metadata = meta;
+        self.snapshot_checksum = snapshot_checksum(&snapshot.data);
+        self.snapshot_data = std::mem::take(&mut snapshot.data);
+        Ok(())
+    }
+
     /// Append the new entries to storage.
     ///
     /// # Panics
@@ -197,7 +230,7 @@ impl MemStorageCore {
                 ents[0].index,
             );
         }
-        if self.last_index() + 1 < ents[0].index {
+        if self.last_index().saturating_add(1) < ents[0].index {
             panic!(
                 "raft logs should be continuous, last index: {}, new appended: {}",
                 self.last_index(),
@@ -212,6 +245,30 @@ impl MemStorageCore {
         Ok(())
     }
 
+    /// Commit the `WriteBatch` atomically. The batch is validated before any
+    /// state is modified, so if it fails the storage is left unchanged.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if let Some(snapshot) = batch.snapshot.as_ref() {
+            if self.first_index() > snapshot.get_metadata().index {
+                return Err(StorageError::SnapshotOutOfDate);
+            }
+        }
+
+        if self.trigger_write_errors > 0 {
+            self.trigger_write_errors -= 1;
+            return Err(StorageError::Unavailable);
+        }
+
+        if let Some(snapshot) = batch.snapshot {
+            self.apply_snapshot(snapshot)?;
+        }
+        self.append(&batch.entries)?;
+        if let Some(hs) = batch.hard_state {
+            self.set_hardstate(hs);
+        }
+        Ok(())
+    }
+
     /// Commit to `idx` and set configuration to the given states. Only used for tests.
     pub fn commit_to_and_set_conf_states(&mut self, idx: u64, cs: Option<ConfState>) -> Result<()> {
         self.commit_to(idx)?;
@@ -230,10 +287,25 @@ impl MemStorageCore {
     pub fn trigger_log_unavailable(&mut self, v: bool) {
         self.trigger_log_unavailable = v;
     }
+
+    /// Trigger an error of the next write batch, which simulates a crash
+    /// in the middle of writing.
+    pub fn trigger_write_error(&mut self) {
+        self.trigger_write_errors(1);
+    }
+
+    /// Trigger errors of the next `n` write batches, which simulates the
+    /// failing disk, e.g. it's full.
+    pub fn trigger_write_errors(&mut self, n: usize) {
+        self.trigger_write_errors = n;
+    }
 }
 
 /// `MemStorage` is a thread-safe but incomplete implementation of `Storage`, mainly for tests.
 ///
+/// All operations of `MemStorage` complete immediately under the lock, so the async
+/// methods of `RaftStorage` are adapted by returning a `Ready` future.
+///
 /// A real `Storage` should save both raft logs and applied data. However `MemStorage` only
 /// contains raft logs. So you can call `MemStorage::append` to persist new received unstable raft
 /// logs and then access them with `Storage` APIs. The only exception is `Storage::snapshot`. There
@@ -310,14 +382,19 @@ impl RaftStorage for MemStorage {
             return Err(StorageError::Compacted);
         }
 
-        if high > core.last_index() + 1 {
+        if high > core.last_index().saturating_add(1) {
             panic!(
                 "index out of bound (last: {}, high: {})",
-                core.last_index() + 1,
+                core.last_index().saturating_add(1),
                 high
             );
         }
 
+        // there is no entry after the snapshot is applied.
+        if low >= high {
+            return Ok(vec![]);
+        }
+
         // if core.trigger_log_unavailable && context.can_async() {
         //     core.get_entries_context = Some(context);
         //     return Err(StorageError::LogTemporarilyUnavailable);
@@ -366,57 +443,123 @@ impl RaftStorage for MemStorage {
             core.trigger_snap_unavailable = false;
             Err(StorageError::SnapshotTemporarilyUnavailable)
         } else {
-            let mut snap = core.snapshot();
+            core.check_snapshot()?;
+            let snap = core.snapshot();
+            // a fresher snapshot is taken by the actor, see
+            // `MultiRaft::trigger_snapshot`.
             if snap.get_metadata().index < request_index {
-                snap.mut_metadata().index = request_index;
+                return Err(StorageError::SnapshotTemporarilyUnavailable);
             }
             Ok(snap)
         }
     }
 
-    fn append_entries(&self, entries: &Vec<Entry>) -> Result<()> {
-        let mut wl = self.wl();
-        wl.append(&entries)
+    type AppendEntriesFuture<'life0> = Ready<Result<()>>
+    where
+        Self: 'life0;
+    fn append_entries(&self, entries: Vec<Entry>) -> Self::AppendEntriesFuture<'_> {
+        ready(self.wl().append(&entries))
     }
 
-    fn get_hard_state(&self) -> Result<HardState> {
-        Ok(self.rl().hard_state().clone())
+    type GetHardStateFuture<'life0> = Ready<Result<HardState>>
+    where
+        Self: 'life0;
+    fn get_hard_state(&self) -> Self::GetHardStateFuture<'_> {
+        ready(Ok(self.rl().hard_state().clone()))
     }
 
-    fn set_hardstate(&self, hs: HardState) -> Result<()> {
-        Ok(self.wl().set_hardstate(hs))
+    type SetHardStateFuture<'life0> = Ready<Result<()>>
+    where
+        Self: 'life0;
+    fn set_hardstate(&self, hs: HardState) -> Self::SetHardStateFuture<'_> {
+        ready(Ok(self.wl().set_hardstate(hs)))
     }
 
-    fn set_confstate(&self, cs: ConfState) -> Result<()> {
-        Ok(self.wl().set_conf_state(cs))
+    type SetConfStateFuture<'life0> = Ready<Result<()>>
+    where
+        Self: 'life0;
+    fn set_confstate(&self, cs: ConfState) -> Self::SetConfStateFuture<'_> {
+        ready(Ok(self.wl().set_conf_state(cs)))
     }
 
-    fn get_confstate(&self) -> Result<ConfState> {
-        let rl = self.rl();
-        Ok(rl.raft_state.conf_state.clone())
+    type GetConfStateFuture<'life0> = Ready<Result<ConfState>>
+    where
+        Self: 'life0;
+    fn get_confstate(&self) -> Self::GetConfStateFuture<'_> {
+        ready(Ok(self.rl().raft_state.conf_state.clone()))
     }
 
-    fn set_commit(&self, commit: u64) {
-        self.wl().mut_hard_state().commit = commit
+    type SetCommitFuture<'life0> = Ready<Result<()>>
+    where
+        Self: 'life0;
+    fn set_commit(&self, commit: u64) -> Self::SetCommitFuture<'_> {
+        self.wl().mut_hard_state().commit = commit;
+        ready(Ok(()))
     }
 
-    fn apply_snapshot(&self, snapshot: Snapshot) -> Result<()> {
-        self.wl().apply_snapshot(snapshot)
+    type ApplySnapshotFuture<'life0> = Ready<Result<()>>
+    where
+        Self: 'life0;
+    fn apply_snapshot(&self, snapshot: Snapshot) -> Self::ApplySnapshotFuture<'_> {
+        ready(self.wl().apply_snapshot(snapshot))
+    }
+
+    type CompactToSnapshotFuture<'life0> = Ready<Result<()>>
+    where
+        Self: 'life0;
+    fn compact_to_snapshot(
+        &self,
+        snapshot: Snapshot,
+        compact_index: u64,
+    ) -> Self::CompactToSnapshotFuture<'_> {
+        ready(self.wl().compact_to_snapshot(snapshot, compact_index))
+    }
+
+    type WriteReadyFuture<'life0> = Ready<Result<()>>
+    where
+        Self: 'life0;
+    fn write_ready(&self, batch: WriteBatch) -> Self::WriteReadyFuture<'_> {
+        ready(self.wl().write_batch(batch))
     }
 }
 
 impl RaftSnapshotBuilder for MemStorage {
+    /// `MemStorage` has no state machine, the snapshot is built with the
+    /// metadata at `applied` and without data. The conf state is the latest
+    /// one rather than the one at `applied`, so it's only for the tests, the
+    /// actor snapshots the groups by the apply actor, see
+    /// `MultiRaft::trigger_snapshot`.
     fn build_snapshot(&self, applied: u64) -> Result<Snapshot> {
-        unimplemented!()
+        let term = self.term(applied)?;
+        let mut snapshot = Snapshot::default();
+        let meta = snapshot.mut_metadata();
+        meta.index = applied;
+        meta.term = term;
+        meta.set_conf_state(self.rl().raft_state.conf_state.clone());
+        Ok(snapshot)
     }
 }
 
+/// The number of the shards of `MultiRaftMemoryStorage`.
+const MEMORY_STORAGE_SHARDS: usize = 16;
+
+/// The storages and descriptions of the groups in a shard.
+#[derive(Default)]
+struct MemoryShard {
+    groups: HashMap<u64, MemStorage>,
+    group_descs: HashMap<u64, RaftGroupDesc>,
+}
+
+/// `MultiRaftMemoryStorage` holds the `MemStorage` of each group. The groups
+/// are sharded by the group id, each shard is guarded by its own lock which
+/// is only held to look up or insert the group, and the state of a group is
+/// guarded by the lock of its `MemStorage`, so the operations on the different
+/// groups don't contend on one lock.
 #[derive(Clone)]
 pub struct MultiRaftMemoryStorage {
     node_id: u64,
     store_id: u64,
-    groups: Arc<AsyncRwLock<HashMap<u64, MemStorage>>>,
-    group_desc_map: Arc<AsyncRwLock<HashMap<u64, RaftGroupDesc>>>,
+    shards: Arc<Vec<AsyncRwLock<MemoryShard>>>,
 }
 
 impl MultiRaftMemoryStorage {
@@ -424,17 +567,36 @@ impl MultiRaftMemoryStorage {
         Self {
             node_id,
             store_id,
-            groups: Default::default(),
-            group_desc_map: Default::default(),
+            shards: Arc::new(
+                (0..MEMORY_STORAGE_SHARDS)
+                    .map(|_| Default::default())
+                    .collect(),
+            ),
         }
     }
 
+    #[inline]
+    fn shard(&self, group_id: u64) -> &AsyncRwLock<MemoryShard> {
+        &self.shards[(group_id % self.shards.len() as u64) as usize]
+    }
+
     pub async fn insert_memory_storage(&self, group_id: u64) {
-        let mut wl = self.groups.write().await;
-        if wl.contains_key(&group_id) {
+        let mut wl = self.shard(group_id).write().await;
+        if wl.groups.contains_key(&group_id) {
             panic!("raft group ({}) already exists", group_id)
         }
-        wl.insert(group_id, MemStorage::new());
+        wl.groups.insert(group_id, MemStorage::new());
+    }
+
+    /// Returns the memory storage of the group, which is used by tests to
+    /// inject the failures.
+    pub async fn memory_storage(&self, group_id: u64) -> Option<MemStorage> {
+        self.shard(group_id)
+            .read()
+            .await
+            .groups
+            .get(&group_id)
+            .cloned()
     }
 
     pub async fn insert_replica_memory_storage_with_conf_state<T>(
@@ -445,12 +607,13 @@ impl MultiRaftMemoryStorage {
     ) where
         ConfState: From<T>,
     {
-        let mut wl = self.groups.write().await;
-        if wl.contains_key(&group_id) {
+        let mut wl = self.shard(group_id).write().await;
+        if wl.groups.contains_key(&group_id) {
             panic!("raft group ({}) already exists", group_id)
         }
 
-        wl.insert(group_id, MemStorage::new_with_conf_state(conf_state));
+        wl.groups
+            .insert(group_id, MemStorage::new_with_conf_state(conf_state));
     }
 }
 
@@ -472,10 +635,10 @@ impl MultiRaftStorage<MemStorage> for MultiRaftMemoryStorage {
         T: Send,
     {
         async move {
-            let mut wl = self.groups.write().await;
-            assert_ne!(wl.contains_key(&group_id), true);
+            let mut wl = self.shard(group_id).write().await;
+            assert_ne!(wl.groups.contains_key(&group_id), true);
             let storage = MemStorage::new_with_conf_state(conf_state);
-            wl.insert(group_id, storage.clone());
+            wl.groups.insert(group_id, storage.clone());
             Ok(RaftStorageImpl::new(storage))
         }
     }
@@ -486,15 +649,20 @@ impl MultiRaftStorage<MemStorage> for MultiRaftMemoryStorage {
     #[allow(unused)]
     fn group_storage(&self, group_id: u64, replica_id: u64) -> Self::GroupStorageFuture<'_> {
         async move {
-            let mut wl = self.groups.write().await;
-            match wl.get_mut(&group_id) {
-                None => {
-                    let storage = MemStorage::new();
-                    wl.insert(group_id, storage.clone());
-                    Ok(RaftStorageImpl::new(storage))
-                }
-                Some(store) => Ok(RaftStorageImpl::new(store.clone())),
+            let shard = self.shard(group_id);
+            if let Some(store) = shard.read().await.groups.get(&group_id) {
+                return Ok(RaftStorageImpl::new(store.clone()));
             }
+
+            // the group may be inserted by others before the write lock.
+            let storage = shard
+                .write()
+                .await
+                .groups
+                .entry(group_id)
+                .or_insert_with(MemStorage::new)
+                .clone();
+            Ok(RaftStorageImpl::new(storage))
         }
     }
 
@@ -507,8 +675,8 @@ impl MultiRaftStorage<MemStorage> for MultiRaftMemoryStorage {
         group_desc: RaftGroupDesc,
     ) -> Self::SetGroupDescFuture<'_> {
         async move {
-            let mut wl = self.group_desc_map.write().await;
-            wl.insert(group_id, group_desc);
+            let mut wl = self.shard(group_id).write().await;
+            wl.group_descs.insert(group_id, group_desc);
             return Ok(());
         }
     }
@@ -518,16 +686,18 @@ impl MultiRaftStorage<MemStorage> for MultiRaftMemoryStorage {
         Self: 'life0;
     fn group_desc(&self, group_id: u64) -> Self::GroupDescFuture<'_> {
         async move {
-            let mut wl = self.group_desc_map.write().await;
-            return match wl.get_mut(&group_id) {
-                Some(desc) => Ok(desc.clone()),
-                None => {
-                    let mut desc = RaftGroupDesc::default();
-                    desc.group_id = group_id;
-                    wl.insert(group_id, desc.clone());
-                    Ok(desc)
-                }
-            };
+            let shard = self.shard(group_id);
+            if let Some(desc) = shard.read().await.group_descs.get(&group_id) {
+                return Ok(desc.clone());
+            }
+
+            let mut wl = shard.write().await;
+            let desc = wl.group_descs.entry(group_id).or_insert_with(|| {
+                let mut desc = RaftGroupDesc::default();
+                desc.group_id = group_id;
+                desc
+            });
+            Ok(desc.clone())
         }
     }
 
@@ -540,8 +710,8 @@ impl MultiRaftStorage<MemStorage> for MultiRaftMemoryStorage {
         replica_desc: ReplicaDesc,
     ) -> Self::SetReplicaDescFuture<'_> {
         async move {
-            let mut wl = self.group_desc_map.write().await;
-            return match wl.get_mut(&group_id) {
+            let mut wl = self.shard(group_id).write().await;
+            return match wl.group_descs.get_mut(&group_id) {
                 Some(desc) => {
                     if desc.replicas.iter().find(|r| **r == replica_desc).is_some() {
                         return Ok(());
@@ -558,7 +728,7 @@ impl MultiRaftStorage<MemStorage> for MultiRaftMemoryStorage {
                     desc.group_id = group_id;
                     desc.nodes.push(replica_desc.node_id);
                     desc.replicas.push(replica_desc);
-                    wl.insert(group_id, desc.clone());
+                    wl.group_descs.insert(group_id, desc.clone());
                     Ok(())
                 }
             };
@@ -570,8 +740,8 @@ impl MultiRaftStorage<MemStorage> for MultiRaftMemoryStorage {
         Self: 'life0;
     fn replica_desc(&self, group_id: u64, replica_id: u64) -> Self::ReplicaDescFuture<'_> {
         async move {
-            let mut wl = self.group_desc_map.write().await;
-            return match wl.get_mut(&group_id) {
+            let rl = self.shard(group_id).read().await;
+            return match rl.group_descs.get(&group_id) {
                 Some(desc) => {
                     if let Some(replica) = desc.replicas.iter().find(|r| r.replica_id == replica_id)
                     {
@@ -590,8 +760,8 @@ impl MultiRaftStorage<MemStorage> for MultiRaftMemoryStorage {
 
     fn replica_for_node(&self, group_id: u64, node_id: u64) -> Self::ReplicaForNodeFuture<'_> {
         async move {
-            let mut wl = self.group_desc_map.write().await;
-            return match wl.get_mut(&group_id) {
+            let rl = self.shard(group_id).read().await;
+            return match rl.group_descs.get(&group_id) {
                 Some(desc) => {
                     if let Some(replica) = desc.replicas.iter().find(|r| r.node_id == node_id) {
                         return Ok(Some(replica.clone()));
@@ -602,18 +772,82 @@ impl MultiRaftStorage<MemStorage> for MultiRaftMemoryStorage {
             };
         }
     }
+
+    type RemoveGroupStorageFuture<'life0> = impl Future<Output = Result<()>> + 'life0
+    where
+        Self: 'life0;
+    fn remove_group_storage(&self, group_id: u64) -> Self::RemoveGroupStorageFuture<'_> {
+        async move {
+            let mut wl = self.shard(group_id).write().await;
+            wl.groups.remove(&group_id);
+            wl.group_descs.remove(&group_id);
+            Ok(())
+        }
+    }
+
+    type SnapshotMetadataFuture<'life0> = impl Future<Output = Result<SnapshotMetadata>> + 'life0
+    where
+        Self: 'life0;
+    fn snapshot_metadata(&self, group_id: u64) -> Self::SnapshotMetadataFuture<'_> {
+        async move {
+            match self.memory_storage(group_id).await {
+                None => Ok(SnapshotMetadata::default()),
+                Some(store) => Ok(store.rl().snapshot_metadata().clone()),
+            }
+        }
+    }
+
+    type LogBoundsFuture<'life0> = impl Future<Output = Result<LogBounds>> + 'life0
+    where
+        Self: 'life0;
+    fn log_bounds(&self, group_id: u64) -> Self::LogBoundsFuture<'_> {
+        async move {
+            match self.memory_storage(group_id).await {
+                None => Ok(LogBounds::default()),
+                Some(store) => {
+                    let core = store.rl();
+                    Ok(LogBounds {
+                        first_index: core.first_index(),
+                        last_index: core.last_index(),
+                        snapshot_index: core.snapshot_metadata().index,
+                    })
+                }
+            }
+        }
+    }
+    type SyncWritesFuture<'life0> = Ready<Result<()>>
+    where
+        Self: 'life0;
+    fn sync_writes(&self) -> Self::SyncWritesFuture<'_> {
+        // the writes are never synced.
+        ready(Ok(()))
+    }
 }
 
 #[cfg(test)]
 mod test {
     use crate::proto::ConfState;
     use crate::proto::Entry;
+    use crate::proto::HardState;
+    use crate::proto::ReplicaDesc;
     use crate::proto::Snapshot;
+    use crate::proto::SnapshotMetadata;
     use std::panic::{self, AssertUnwindSafe};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::executor::block_on;
+    use futures::StreamExt;
 
+    use super::LogBounds;
     use super::MemStorage;
+    use super::MultiRaftMemoryStorage;
+    use super::MultiRaftStorage;
+    use super::RaftSnapshotBuilder;
     use super::RaftStorage;
     use super::StorageError;
+    use super::WriteBatch;
 
     fn new_entry(index: u64, term: u64) -> Entry {
         let mut e = Entry::default();
@@ -715,6 +949,24 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_storage_entry_stream() {
+        let ents = (3..=200).map(|i| new_entry(i, 3)).collect::<Vec<_>>();
+        let storage = MemStorage::new();
+        storage.wl().entries = ents.clone();
+        storage.wl().mut_hard_state().commit = 150;
+
+        // the stream ends at the commit index, across batches.
+        let streamed = block_on(storage.entry_stream(4).collect::<Vec<_>>());
+        let expected = ents[1..148].iter().cloned().map(Ok).collect::<Vec<_>>();
+        assert_eq!(streamed, expected);
+
+        // the stream of the compacted log yields the earliest available index.
+        storage.wl().compact(10).unwrap();
+        let streamed = block_on(storage.entry_stream(4).collect::<Vec<_>>());
+        assert_eq!(streamed, vec![Err(StorageError::LogCompacted(10))]);
+    }
+
     #[test]
     fn test_storage_last_index() {
         let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
@@ -775,28 +1027,62 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_storage_compact_to_snapshot() {
+        let storage = MemStorage::new();
+        storage.wl().entries = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
+        storage.wl().mut_hard_state().commit = 5;
+
+        let mut snap = storage.build_snapshot(4).unwrap();
+        assert_eq!(snap.get_metadata().term, 4);
+        snap.data = b"data".to_vec();
+        block_on(storage.compact_to_snapshot(snap.clone(), u64::MAX)).unwrap();
+        assert_eq!(storage.first_index(), Ok(5));
+        assert_eq!(storage.last_index(), Ok(5));
+        assert_eq!(storage.term(4), Ok(4));
+        assert_eq!(storage.initial_state().unwrap().hard_state.commit, 5);
+        assert_eq!(
+            block_on(storage.compact_to_snapshot(snap, u64::MAX)),
+            Err(StorageError::SnapshotOutOfDate)
+        );
+
+        // the entries from `compact_index` are kept for the lagging replica.
+        let storage = MemStorage::new();
+        storage.wl().entries = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
+        storage.wl().mut_hard_state().commit = 5;
+        let snap = storage.build_snapshot(5).unwrap();
+        block_on(storage.compact_to_snapshot(snap, 4)).unwrap();
+        assert_eq!(storage.first_index(), Ok(4));
+        assert_eq!(storage.term(4), Ok(4));
+        assert_eq!(storage.rl().snapshot_metadata.index, 5);
+    }
+
     #[test]
     fn test_storage_create_snapshot() {
         let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
-        let nodes = vec![1, 2, 3];
-        let mut conf_state = ConfState::default();
-        conf_state.voters = nodes.clone();
+        let mut snap = new_snapshot(4, 4, vec![1, 2, 3]);
+        snap.data = b"snapshot data".to_vec();
 
-        let unavailable = Err(StorageError::SnapshotTemporarilyUnavailable);
+        let unavailable = || Err(StorageError::SnapshotTemporarilyUnavailable);
         let mut tests = vec![
-            (4, Ok(new_snapshot(4, 4, nodes.clone())), 0),
-            (5, Ok(new_snapshot(5, 5, nodes.clone())), 5),
-            (5, Ok(new_snapshot(6, 5, nodes)), 6),
-            (5, unavailable, 6),
+            // the saved snapshot is returned regardless of the commit index.
+            (0, Ok(snap.clone()), false),
+            (4, Ok(snap.clone()), false),
+            // the snapshot at the requested index isn't taken yet.
+            (5, unavailable(), false),
+            (4, unavailable(), true),
         ];
-        for (i, (idx, wresult, windex)) in tests.drain(..).enumerate() {
+        for (i, (windex, wresult, trigger_unavailable)) in tests.drain(..).enumerate() {
             let storage = MemStorage::new();
             storage.wl().entries = ents.clone();
-            storage.wl().raft_state.hard_state.commit = idx;
-            storage.wl().raft_state.hard_state.term = idx;
-            storage.wl().raft_state.conf_state = conf_state.clone();
-
-            if wresult.is_err() {
+            storage.wl().raft_state.hard_state.commit = 5;
+            storage.wl().raft_state.hard_state.term = 5;
+            storage
+                .wl()
+                .compact_to_snapshot(snap.clone(), u64::MAX)
+                .unwrap();
+
+            if trigger_unavailable {
                 storage.wl().trigger_snap_unavailable();
             }
 
@@ -883,4 +1169,242 @@ mod test {
         let snap = new_snapshot(3, 3, nodes);
         storage.wl().apply_snapshot(snap).unwrap_err();
     }
+
+    #[test]
+    fn test_storage_snapshot_metadata() {
+        block_on(async {
+            let storage = MultiRaftMemoryStorage::new(1, 1);
+            assert_eq!(
+                storage.snapshot_metadata(1).await.unwrap(),
+                SnapshotMetadata::default()
+            );
+
+            let group_storage = storage.group_storage(1, 1).await.unwrap();
+            let mut snap = new_snapshot(4, 4, vec![1, 2, 3]);
+            snap.data = vec![0; 1024];
+            group_storage.apply_snapshot(snap.clone()).await.unwrap();
+
+            // the metadata is read without the data.
+            let metadata = storage.snapshot_metadata(1).await.unwrap();
+            assert_eq!(&metadata, snap.get_metadata());
+            assert_eq!(metadata.conf_state.unwrap().voters, vec![1, 2, 3]);
+        });
+    }
+
+    #[test]
+    fn test_storage_log_bounds() {
+        block_on(async {
+            let storage = MultiRaftMemoryStorage::new(1, 1);
+            assert_eq!(storage.log_bounds(1).await.unwrap(), LogBounds::default());
+
+            let group_storage = storage.group_storage(1, 1).await.unwrap();
+            let mut hs = HardState::default();
+            hs.term = 1;
+            hs.commit = 9;
+            let batch = WriteBatch {
+                snapshot: None,
+                entries: (1..10).map(|index| new_entry(index, 1)).collect(),
+                hard_state: Some(hs),
+                defer_sync: false,
+            };
+            group_storage.write_ready(batch).await.unwrap();
+            let bounds = storage.log_bounds(1).await.unwrap();
+            assert_eq!(
+                bounds,
+                LogBounds {
+                    first_index: 1,
+                    last_index: 9,
+                    snapshot_index: 0,
+                }
+            );
+
+            // the log up to the snapshot is truncated by the compaction.
+            let snap = new_snapshot(5, 1, vec![1, 2, 3]);
+            group_storage
+                .compact_to_snapshot(snap, u64::MAX)
+                .await
+                .unwrap();
+            let bounds = storage.log_bounds(1).await.unwrap();
+            assert_eq!(
+                bounds,
+                LogBounds {
+                    first_index: 6,
+                    last_index: 9,
+                    snapshot_index: 5,
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn test_multi_raft_storage_concurrent_groups() {
+        const THREADS: u64 = 8;
+        const GROUPS: u64 = 16;
+        const ROUNDS: u64 = 50;
+        let storage = MultiRaftMemoryStorage::new(1, 1);
+        let (done_tx, done_rx) = mpsc::channel();
+        for t in 0..THREADS {
+            let storage = storage.clone();
+            let done_tx = done_tx.clone();
+            thread::spawn(move || {
+                block_on(async {
+                    for round in 1..=ROUNDS {
+                        // the groups of the threads interleave across the shards.
+                        for n in 0..GROUPS {
+                            let group_id = n * THREADS + t + 1;
+                            let group_storage = storage.group_storage(group_id, 1).await.unwrap();
+                            group_storage
+                                .append_entries(vec![new_entry(round, group_id)])
+                                .await
+                                .unwrap();
+                            let replica = ReplicaDesc {
+                                node_id: round,
+                                replica_id: round,
+                            };
+                            storage.set_replica_desc(group_id, replica).await.unwrap();
+                            let bounds = storage.log_bounds(group_id).await.unwrap();
+                            assert_eq!(bounds.last_index, round);
+                        }
+                    }
+                    // all threads create the same group at the same time.
+                    storage.group_storage(0, 1).await.unwrap();
+                });
+                done_tx.send(()).unwrap();
+            });
+        }
+        for _ in 0..THREADS {
+            done_rx
+                .recv_timeout(Duration::from_secs(30))
+                .expect("the storage deadlocks");
+        }
+
+        block_on(async {
+            for group_id in 1..=THREADS * GROUPS {
+                let group_storage = storage.group_storage(group_id, 1).await.unwrap();
+                let ents = group_storage.entries(1, ROUNDS + 1, u64::MAX).unwrap();
+                assert_eq!(ents.len() as u64, ROUNDS);
+                assert!(ents.iter().all(|ent| ent.term == group_id));
+                let desc = storage.group_desc(group_id).await.unwrap();
+                assert_eq!(desc.replicas.len() as u64, ROUNDS);
+            }
+            assert!(storage.memory_storage(0).await.is_some());
+        });
+    }
+
+    #[test]
+    fn test_storage_snapshot_corrupt() {
+        let storage = MemStorage::new();
+        let mut snap = new_snapshot(4, 4, vec![1, 2, 3]);
+        snap.data = b"snapshot data".to_vec();
+        storage.wl().apply_snapshot(snap.clone()).unwrap();
+        assert_eq!(storage.snapshot(4), Ok(snap));
+
+        // flip a byte of the persisted snapshot.
+        storage.wl().snapshot_data[0] ^= 0xff;
+        assert_eq!(storage.snapshot(4), Err(StorageError::SnapshotCorrupt));
+    }
+
+    #[test]
+    fn test_storage_write_batch_atomic() {
+        let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
+        let storage = MemStorage::new();
+        storage.wl().entries = ents.clone();
+
+        let mut hs = HardState::default();
+        hs.term = 6;
+        hs.commit = 5;
+        let batch = WriteBatch {
+            snapshot: None,
+            entries: vec![new_entry(6, 6), new_entry(7, 6)],
+            hard_state: Some(hs.clone()),
+            defer_sync: false,
+        };
+
+        // crash in the middle of writing, nothing is persisted.
+        storage.wl().trigger_write_error();
+        assert_eq!(
+            storage.wl().write_batch(batch.clone()),
+            Err(StorageError::Unavailable)
+        );
+        assert_eq!(storage.wl().entries, ents);
+        assert_eq!(*storage.rl().hard_state(), HardState::default());
+
+        // both entries and hard state are persisted.
+        storage.wl().write_batch(batch).unwrap();
+        assert_eq!(storage.last_index(), Ok(7));
+        assert_eq!(*storage.rl().hard_state(), hs);
+
+        // out of date snapshot is rejected before entries appended.
+        let batch = WriteBatch {
+            snapshot: Some(new_snapshot(2, 2, vec![1, 2, 3])),
+            entries: vec![new_entry(8, 6)],
+            hard_state: None,
+            defer_sync: false,
+        };
+        assert_eq!(
+            storage.wl().write_batch(batch),
+            Err(StorageError::SnapshotOutOfDate)
+        );
+        assert_eq!(storage.last_index(), Ok(7));
+    }
+
+    #[test]
+    fn test_storage_high_base_index() {
+        let base = u64::MAX / 2;
+        let storage = MemStorage::new();
+        storage
+            .wl()
+            .apply_snapshot(new_snapshot(base, base, vec![1, 2, 3]))
+            .unwrap();
+        assert_eq!(storage.first_index(), Ok(base + 1));
+        assert_eq!(storage.last_index(), Ok(base));
+        assert_eq!(storage.term(base), Ok(base));
+        assert_eq!(storage.entries(base + 1, base + 1, u64::MAX), Ok(vec![]));
+
+        // append, commit, snapshot and compact in cycles.
+        let mut last = base;
+        for cycle in 0..10u64 {
+            let term = base + cycle + 1;
+            let ents = (last + 1..=last + 100)
+                .map(|index| new_entry(index, term))
+                .collect::<Vec<_>>();
+            storage.wl().append(&ents).unwrap();
+            last += 100;
+            assert_eq!(storage.last_index(), Ok(last));
+            assert_eq!(storage.entries(last - 99, last + 1, u64::MAX).unwrap(), ents);
+
+            // the tail of the entries is overwritten by a higher term.
+            storage.wl().append(&[new_entry(last, term + 1)]).unwrap();
+            assert_eq!(storage.term(last), Ok(term + 1));
+
+            storage.wl().commit_to(last - 50).unwrap();
+            let snap = storage.build_snapshot(last - 50).unwrap();
+            assert_eq!(snap.get_metadata().term, term);
+            block_on(storage.compact_to_snapshot(snap, u64::MAX)).unwrap();
+            assert_eq!(storage.first_index(), Ok(last - 49));
+            assert_eq!(storage.term(last - 50), Ok(term));
+            assert_eq!(storage.term(last - 51), Err(StorageError::Compacted));
+            assert_eq!(
+                storage.entries(last - 49, last + 1, u64::MAX).unwrap().len(),
+                50
+            );
+
+            let snap = storage.snapshot(0).unwrap();
+            assert_eq!(snap.get_metadata().index, last - 50);
+            assert_eq!(snap.get_metadata().term, term);
+        }
+
+        // the bounds at the max index saturate rather than wrap around.
+        let storage = MemStorage::new();
+        storage
+            .wl()
+            .apply_snapshot(new_snapshot(u64::MAX, base, vec![1, 2, 3]))
+            .unwrap();
+        assert_eq!(storage.first_index(), Ok(u64::MAX));
+        assert_eq!(storage.last_index(), Ok(u64::MAX));
+        assert_eq!(storage.entries(u64::MAX, u64::MAX, u64::MAX), Ok(vec![]));
+        storage.wl().compact(u64::MAX).unwrap();
+        block_on(storage.compact_to_snapshot(new_snapshot(u64::MAX, base, vec![]), u64::MAX))
+            .unwrap_err();
+    }
 }
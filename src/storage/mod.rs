@@ -1,10 +1,14 @@
+mod entry_cache;
 mod memory;
+mod segment;
 mod storage;
 // mod rocksdb;
 
+pub use self::entry_cache::EntryCache;
 pub use self::storage::transmute_message;
 pub use self::storage::transmute_entry;
 pub use self::storage::transmute_error;
+pub use self::storage::snapshot_checksum;
 pub use self::storage::transmute_raft_state;
 pub use self::storage::transmute_snapshot;
 pub use self::storage::transmute_snapshot_metadata;
@@ -14,8 +18,16 @@ pub use self::storage::MultiRaftStorage;
 pub use self::storage::RaftSnapshotBuilder;
 pub use self::storage::RaftState;
 pub use self::storage::RaftStorage;
+pub use self::storage::EntryStream;
+pub use self::storage::LogBounds;
 pub use self::storage::Result;
 pub use self::storage::StorageError;
+pub use self::storage::SyncPolicy;
+pub use self::storage::WriteBatch;
 pub use self::memory::MemStorage;
 pub use self::memory::MemStorageCore;
 pub use self::memory::MultiRaftMemoryStorage;
+pub use self::segment::SegmentConfig;
+pub use self::segment::SegmentStats;
+pub use self::segment::SegmentStorage;
+pub use self::segment::SegmentedStorage;
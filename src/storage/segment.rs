@@ -0,0 +1,1865 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Instant;
+
+use futures::future::ready;
+use futures::future::Ready;
+use futures::Future;
+use prost::Message;
+use tracing::warn;
+
+use crate::multiraft::Clock;
+use crate::multiraft::SystemClock;
+use crate::proto::ConfState;
+use crate::proto::Entry;
+use crate::proto::HardState;
+use crate::proto::RaftGroupDesc;
+use crate::proto::ReplicaDesc;
+use crate::proto::Snapshot;
+use crate::proto::SnapshotMetadata;
+
+use super::storage::Result;
+use super::LogBounds;
+use super::MultiRaftStorage;
+use super::RaftSnapshotBuilder;
+use super::RaftState;
+use super::RaftStorage;
+use super::RaftStorageImpl;
+use super::StorageError;
+use super::SyncPolicy;
+use super::WriteBatch;
+
+const SEGMENT_EXT: &str = "seg";
+const REWRITE_EXT: &str = "tmp";
+
+/// The record is `len: u32 | crc32c: u32 | items`, the items of a record are
+/// written atomically, the torn record at the tail is discarded on open.
+const RECORD_HEADER_LEN: usize = 8;
+/// The item is `kind: u8 | group_id: u64 | len: u32 | payload`.
+const ITEM_HEADER_LEN: usize = 13;
+
+const ITEM_ENTRY: u8 = 1;
+const ITEM_HARD_STATE: u8 = 2;
+const ITEM_CONF_STATE: u8 = 3;
+const ITEM_SNAPSHOT: u8 = 4;
+const ITEM_COMPACT: u8 = 5;
+const ITEM_GROUP_DESC: u8 = 6;
+const ITEM_REMOVE_GROUP: u8 = 7;
+const ITEM_SAVED_SNAPSHOT: u8 = 8;
+
+/// The config of `SegmentedStorage`.
+#[derive(Debug, Clone)]
+pub struct SegmentConfig {
+    /// The directory of the segment files.
+    pub dir: PathBuf,
+    /// The active segment is sealed and a new one is created once it grows
+    /// beyond `segment_size` bytes.
+    pub segment_size: u64,
+    /// The sealed segment whose live bytes are less than `rewrite_ratio` of its
+    /// size is rewritten by `SegmentedStorage::rewrite_segments`.
+    pub rewrite_ratio: f64,
+    /// Whether the writes are fsynced, see `SyncPolicy`.
+    pub sync_policy: SyncPolicy,
+    /// The `SyncPolicy` of the groups which overrides `sync_policy`, e.g. the
+    /// writes of the metadata group are always fsynced while the data groups
+    /// are fsynced periodically. A record is fsynced if any group written in
+    /// it needs, so a group never gets less durability than its policy. The
+    /// policy of a group is also set when it's created by
+    /// `MultiRaft::set_group_sync_policy`, which isn't persisted.
+    pub group_sync_policies: HashMap<u64, SyncPolicy>,
+}
+
+impl SegmentConfig {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            segment_size: 64 * 1024 * 1024,
+            rewrite_ratio: 0.5,
+            sync_policy: SyncPolicy::Always,
+            group_sync_policies: HashMap::new(),
+        }
+    }
+
+    /// Returns the `SyncPolicy` of the writes of the group.
+    pub fn group_sync_policy(&self, group_id: u64) -> SyncPolicy {
+        self.group_sync_policies
+            .get(&group_id)
+            .copied()
+            .unwrap_or(self.sync_policy)
+    }
+
+    /// Returns true if no group fsyncs its writes.
+    fn never_sync(&self) -> bool {
+        self.sync_policy == SyncPolicy::Never
+            && self
+                .group_sync_policies
+                .values()
+                .all(|policy| *policy == SyncPolicy::Never)
+    }
+}
+
+/// The space and write statistics of `SegmentedStorage`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SegmentStats {
+    pub segments: usize,
+    pub groups: usize,
+    /// The bytes of all segment files.
+    pub total_bytes: u64,
+    /// The bytes of the items which are still referenced by the groups.
+    pub live_bytes: u64,
+    /// The bytes appended by the writes since open.
+    pub appended_bytes: u64,
+    /// The bytes written by the segment rewriting since open.
+    pub rewritten_bytes: u64,
+    /// The fsyncs of the segments by the writes since open.
+    pub write_syncs: u64,
+    /// The items read from the segments since open, e.g. the entries which
+    /// miss the entry cache.
+    pub reads: u64,
+}
+
+impl SegmentStats {
+    /// Returns the bytes written to the segments per byte appended.
+    pub fn write_amplification(&self) -> f64 {
+        if self.appended_bytes == 0 {
+            return 1.0;
+        }
+        (self.appended_bytes + self.rewritten_bytes) as f64 / self.appended_bytes as f64
+    }
+}
+
+/// The location of the item payload in the segments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Location {
+    segment: u64,
+    offset: u64,
+    len: u32,
+}
+
+impl Location {
+    /// Returns the size of the item, include the item header.
+    #[inline]
+    fn size(&self) -> u64 {
+        ITEM_HEADER_LEN as u64 + self.len as u64
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct EntryLocation {
+    index: u64,
+    term: u64,
+    loc: Location,
+}
+
+/// The decoded item, the entry and the snapshot data are not decoded, they
+/// are read from the segments on demand.
+enum ItemValue {
+    Entry { index: u64, term: u64 },
+    HardState(HardState),
+    ConfState(ConfState),
+    Snapshot(SnapshotMetadata),
+    // the snapshot saved by `compact_to_snapshot`, the entries are kept.
+    SavedSnapshot(SnapshotMetadata),
+    Compact { index: u64, term: u64 },
+    GroupDesc(RaftGroupDesc),
+    RemoveGroup,
+}
+
+/// The state of a group rebuilt from the items, it indexes the live items of
+/// the group in the segments.
+#[derive(Default)]
+struct GroupLog {
+    raft_state: RaftState,
+    snapshot_metadata: SnapshotMetadata,
+    // the index and term of the entry before the first entry.
+    truncated: (u64, u64),
+    entries: VecDeque<EntryLocation>,
+    desc: Option<RaftGroupDesc>,
+    hard_state_loc: Option<Location>,
+    conf_state_loc: Option<Location>,
+    snapshot_loc: Option<Location>,
+    compact_loc: Option<Location>,
+    desc_loc: Option<Location>,
+}
+
+impl GroupLog {
+    fn first_index(&self) -> u64 {
+        match self.entries.front() {
+            Some(e) => e.index,
+            None => self.truncated.0 + 1,
+        }
+    }
+
+    fn last_index(&self) -> u64 {
+        match self.entries.back() {
+            Some(e) => e.index,
+            None => self.truncated.0,
+        }
+    }
+
+    fn entry(&self, index: u64) -> Option<&EntryLocation> {
+        let first = self.entries.front()?.index;
+        if index < first {
+            return None;
+        }
+        self.entries.get((index - first) as usize)
+    }
+
+    fn term(&self, idx: u64) -> Result<u64> {
+        if idx == self.truncated.0 {
+            return Ok(self.truncated.1);
+        }
+        if idx < self.first_index() {
+            return Err(StorageError::Compacted);
+        }
+        self.entry(idx)
+            .map(|e| e.term)
+            .ok_or(StorageError::Unavailable)
+    }
+
+    fn locations(&self) -> Vec<Location> {
+        let mut locs = self.entries.iter().map(|e| e.loc).collect::<Vec<_>>();
+        locs.extend(
+            [
+                self.hard_state_loc,
+                self.conf_state_loc,
+                self.snapshot_loc,
+                self.compact_loc,
+                self.desc_loc,
+            ]
+            .into_iter()
+            .flatten(),
+        );
+        locs
+    }
+
+    /// Returns the slot which references the item of `value`.
+    fn slot(&mut self, value: &ItemValue) -> Option<&mut Location> {
+        match value {
+            ItemValue::Entry { index, .. } => {
+                let first = self.entries.front()?.index;
+                if *index < first {
+                    return None;
+                }
+                self.entries
+                    .get_mut((*index - first) as usize)
+                    .map(|e| &mut e.loc)
+            }
+            ItemValue::HardState(_) => self.hard_state_loc.as_mut(),
+            ItemValue::ConfState(_) => self.conf_state_loc.as_mut(),
+            ItemValue::Snapshot(_) | ItemValue::SavedSnapshot(_) => self.snapshot_loc.as_mut(),
+            ItemValue::Compact { .. } => self.compact_loc.as_mut(),
+            ItemValue::GroupDesc(_) => self.desc_loc.as_mut(),
+            ItemValue::RemoveGroup => None,
+        }
+    }
+}
+
+struct SegmentMeta {
+    file: Arc<File>,
+    size: u64,
+    live: u64,
+}
+
+/// The fsync of an appended record, it's done after the lock of the log is
+/// released, so the writes of the other groups are not blocked by the fsync.
+#[must_use]
+struct PendingSync {
+    file: Arc<File>,
+    write_syncs: Arc<AtomicU64>,
+}
+
+impl PendingSync {
+    fn sync(self) -> Result<()> {
+        self.file.sync_data().map_err(io_error)?;
+        self.write_syncs.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Sync the record appended by `SegmentLog::append` if it needs.
+#[inline]
+fn sync_pending(pending: Option<PendingSync>) -> Result<()> {
+    pending.map_or(Ok(()), PendingSync::sync)
+}
+
+/// The item to be written, the `value` is applied to the group after the
+/// payload is written.
+struct PendingItem {
+    group_id: u64,
+    kind: u8,
+    payload: Vec<u8>,
+    value: ItemValue,
+}
+
+/// RecordBuilder frames the items of a record.
+struct RecordBuilder {
+    buf: Vec<u8>,
+    // the offsets of the payload of items in the record.
+    offsets: Vec<u64>,
+}
+
+impl RecordBuilder {
+    fn new() -> Self {
+        Self {
+            buf: vec![0; RECORD_HEADER_LEN],
+            offsets: vec![],
+        }
+    }
+
+    fn push(&mut self, kind: u8, group_id: u64, payload: &[u8]) {
+        self.buf.push(kind);
+        self.buf.extend_from_slice(&group_id.to_le_bytes());
+        self.buf
+            .extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        self.offsets.push(self.buf.len() as u64);
+        self.buf.extend_from_slice(payload);
+    }
+
+    fn finish(&mut self) -> &[u8] {
+        let body = &self.buf[RECORD_HEADER_LEN..];
+        let len = (body.len() as u32).to_le_bytes();
+        let crc = crc32c::crc32c(body).to_le_bytes();
+        self.buf[..4].copy_from_slice(&len);
+        self.buf[4..RECORD_HEADER_LEN].copy_from_slice(&crc);
+        &self.buf
+    }
+}
+
+struct RawItem {
+    kind: u8,
+    group_id: u64,
+    offset: u64,
+    len: u32,
+}
+
+/// Parse the records of the segment, returns the items of the complete records
+/// and the length of them, the torn or corrupt tail is not included.
+fn parse_records(data: &[u8]) -> (Vec<RawItem>, u64) {
+    let mut items = vec![];
+    let mut pos = 0;
+    while pos + RECORD_HEADER_LEN <= data.len() {
+        let len = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(data[pos + 4..pos + RECORD_HEADER_LEN].try_into().unwrap());
+        let start = pos + RECORD_HEADER_LEN;
+        let end = start + len;
+        if end > data.len() || crc32c::crc32c(&data[start..end]) != crc {
+            break;
+        }
+
+        let mut record_items = vec![];
+        let mut p = start;
+        while p + ITEM_HEADER_LEN <= end {
+            let kind = data[p];
+            let group_id = u64::from_le_bytes(data[p + 1..p + 9].try_into().unwrap());
+            let len = u32::from_le_bytes(data[p + 9..p + ITEM_HEADER_LEN].try_into().unwrap());
+            p += ITEM_HEADER_LEN;
+            record_items.push(RawItem {
+                kind,
+                group_id,
+                offset: p as u64,
+                len,
+            });
+            p += len as usize;
+        }
+        if p != end {
+            break;
+        }
+        items.extend(record_items);
+        pos = end;
+    }
+    (items, pos as u64)
+}
+
+fn decode_item(kind: u8, payload: &[u8]) -> Result<ItemValue> {
+    let value = match kind {
+        ITEM_ENTRY => {
+            let entry = Entry::decode(payload).map_err(decode_error)?;
+            ItemValue::Entry {
+                index: entry.index,
+                term: entry.term,
+            }
+        }
+        ITEM_HARD_STATE => ItemValue::HardState(HardState::decode(payload).map_err(decode_error)?),
+        ITEM_CONF_STATE => ItemValue::ConfState(ConfState::decode(payload).map_err(decode_error)?),
+        ITEM_SNAPSHOT => {
+            let mut snapshot = Snapshot::decode(payload).map_err(decode_error)?;
+            ItemValue::Snapshot(snapshot.take_metadata())
+        }
+        ITEM_SAVED_SNAPSHOT => {
+            let mut snapshot = Snapshot::decode(payload).map_err(decode_error)?;
+            ItemValue::SavedSnapshot(snapshot.take_metadata())
+        }
+        ITEM_COMPACT if payload.len() == 16 => ItemValue::Compact {
+            index: u64::from_le_bytes(payload[..8].try_into().unwrap()),
+            term: u64::from_le_bytes(payload[8..].try_into().unwrap()),
+        },
+        ITEM_GROUP_DESC => {
+            ItemValue::GroupDesc(RaftGroupDesc::decode(payload).map_err(decode_error)?)
+        }
+        ITEM_REMOVE_GROUP => ItemValue::RemoveGroup,
+        _ => {
+            return Err(StorageError::Other(
+                format!("unknown segment item kind {}", kind).into(),
+            ))
+        }
+    };
+    Ok(value)
+}
+
+#[inline]
+fn io_error(err: std::io::Error) -> StorageError {
+    StorageError::Other(Box::new(err))
+}
+
+#[inline]
+fn decode_error(err: prost::DecodeError) -> StorageError {
+    StorageError::Other(Box::new(err))
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", id, SEGMENT_EXT))
+}
+
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(io_error)
+}
+
+fn open_segment(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)
+        .map_err(io_error)
+}
+
+#[inline]
+fn revive(segments: &mut BTreeMap<u64, SegmentMeta>, loc: Location) {
+    if let Some(segment) = segments.get_mut(&loc.segment) {
+        segment.live += loc.size();
+    }
+}
+
+#[inline]
+fn kill(segments: &mut BTreeMap<u64, SegmentMeta>, loc: Location) {
+    if let Some(segment) = segments.get_mut(&loc.segment) {
+        segment.live = segment.live.saturating_sub(loc.size());
+    }
+}
+
+#[inline]
+fn replace(segments: &mut BTreeMap<u64, SegmentMeta>, slot: &mut Option<Location>, loc: Location) {
+    if let Some(old) = slot.replace(loc) {
+        kill(segments, old);
+    }
+}
+
+/// SegmentLog multiplexes the items of all groups into the shared append-only
+/// segments, and keeps the index of the live items of each group in memory.
+struct SegmentLog {
+    config: SegmentConfig,
+    segments: BTreeMap<u64, SegmentMeta>,
+    active: u64,
+    groups: HashMap<u64, GroupLog>,
+    // the clock of `SyncPolicy::Periodic`.
+    clock: Arc<dyn Clock>,
+    last_sync: Mutex<Instant>,
+    // the groups whose records are not synced yet, i.e. written with
+    // `defer_sync` or within the period of `SyncPolicy::Periodic`.
+    unsynced: HashSet<u64>,
+    appended_bytes: u64,
+    rewritten_bytes: u64,
+    // the fsyncs are counted out of the lock of the log.
+    write_syncs: Arc<AtomicU64>,
+    // the reads are counted under the shared lock of the log.
+    reads: AtomicU64,
+}
+
+impl SegmentLog {
+    fn open(config: SegmentConfig, clock: Arc<dyn Clock>) -> Result<Self> {
+        fs::create_dir_all(&config.dir).map_err(io_error)?;
+        let mut ids = vec![];
+        for dir_entry in fs::read_dir(&config.dir).map_err(io_error)? {
+            let path = dir_entry.map_err(io_error)?.path();
+            match path.extension().and_then(|ext| ext.to_str()) {
+                // the rewriting is interrupted, the original segment is intact.
+                Some(REWRITE_EXT) => fs::remove_file(&path).map_err(io_error)?,
+                Some(SEGMENT_EXT) => {
+                    if let Some(id) = path
+                        .file_stem()
+                        .and_then(|stem| stem.to_str())
+                        .and_then(|stem| stem.parse::<u64>().ok())
+                    {
+                        ids.push(id);
+                    }
+                }
+                _ => {}
+            }
+        }
+        ids.sort();
+
+        let mut log = Self {
+            config,
+            segments: BTreeMap::new(),
+            active: ids.last().cloned().unwrap_or(1),
+            groups: HashMap::new(),
+            last_sync: Mutex::new(clock.now()),
+            clock,
+            unsynced: HashSet::new(),
+            appended_bytes: 0,
+            rewritten_bytes: 0,
+            write_syncs: Arc::new(AtomicU64::new(0)),
+            reads: AtomicU64::new(0),
+        };
+        for id in ids.iter() {
+            log.replay_segment(*id)?;
+        }
+        if ids.is_empty() {
+            log.create_segment(log.active)?;
+        }
+        Ok(log)
+    }
+
+    /// Replay the items of the segment, the torn tail of the last segment is
+    /// truncated, which is left by a crash in the middle of writing.
+    fn replay_segment(&mut self, id: u64) -> Result<()> {
+        let path = segment_path(&self.config.dir, id);
+        let data = fs::read(&path).map_err(io_error)?;
+        let (items, valid) = parse_records(&data);
+        let file = open_segment(&path)?;
+        if valid != data.len() as u64 {
+            if id != self.active {
+                return Err(StorageError::Other(
+                    format!("segment {} is corrupt at offset {}", path.display(), valid).into(),
+                ));
+            }
+            warn!(
+                "truncate the torn tail of segment {} from {} to {}",
+                path.display(),
+                data.len(),
+                valid
+            );
+            // the truncation is synced, so the following records are never
+            // appended after the torn one.
+            file.set_len(valid)
+                .and_then(|_| file.sync_all())
+                .map_err(io_error)?;
+        }
+
+        self.segments.insert(
+            id,
+            SegmentMeta {
+                file: Arc::new(file),
+                size: valid,
+                live: 0,
+            },
+        );
+        for item in items {
+            let payload = &data[item.offset as usize..item.offset as usize + item.len as usize];
+            let value = decode_item(item.kind, payload)?;
+            let loc = Location {
+                segment: id,
+                offset: item.offset,
+                len: item.len,
+            };
+            self.apply(item.group_id, value, loc);
+        }
+        Ok(())
+    }
+
+    fn create_segment(&mut self, id: u64) -> Result<()> {
+        let file = open_segment(&segment_path(&self.config.dir, id))?;
+        sync_dir(&self.config.dir)?;
+        self.segments.insert(
+            id,
+            SegmentMeta {
+                file: Arc::new(file),
+                size: 0,
+                live: 0,
+            },
+        );
+        self.active = id;
+        Ok(())
+    }
+
+    fn group(&self, group_id: u64) -> Result<&GroupLog> {
+        self.groups.get(&group_id).ok_or(StorageError::Unavailable)
+    }
+
+    /// Apply the item at `loc` to the group, the items referenced by the group
+    /// no longer are killed.
+    fn apply(&mut self, group_id: u64, value: ItemValue, loc: Location) {
+        let segments = &mut self.segments;
+        revive(segments, loc);
+        if let ItemValue::RemoveGroup = value {
+            // the remove item is always live, so that the items of the group in
+            // the preceding segments are never replayed without it.
+            if let Some(group) = self.groups.remove(&group_id) {
+                for loc in group.locations() {
+                    kill(segments, loc);
+                }
+            }
+            return;
+        }
+
+        let group = self.groups.entry(group_id).or_default();
+        match value {
+            ItemValue::Entry { index, term } => {
+                // the conflicting entries are overwritten.
+                while group.entries.back().map_or(false, |e| e.index >= index) {
+                    kill(segments, group.entries.pop_back().unwrap().loc);
+                }
+                group.entries.push_back(EntryLocation { index, term, loc });
+            }
+            ItemValue::HardState(hs) => {
+                group.raft_state.hard_state = hs;
+                replace(segments, &mut group.hard_state_loc, loc);
+            }
+            ItemValue::ConfState(cs) => {
+                group.raft_state.conf_state = cs;
+                replace(segments, &mut group.conf_state_loc, loc);
+            }
+            ItemValue::Snapshot(mut meta) => {
+                let hs = &mut group.raft_state.hard_state;
+                hs.term = cmp::max(hs.term, meta.term);
+                hs.commit = meta.index;
+                for e in group.entries.drain(..) {
+                    kill(segments, e.loc);
+                }
+                group.truncated = (meta.index, meta.term);
+                // the conf state is carried by the snapshot.
+                group.raft_state.conf_state = meta.take_conf_state();
+                if let Some(old) = group.conf_state_loc.take() {
+                    kill(segments, old);
+                }
+                meta.set_conf_state(group.raft_state.conf_state.clone());
+                group.snapshot_metadata = meta;
+                replace(segments, &mut group.snapshot_loc, loc);
+            }
+            ItemValue::SavedSnapshot(meta) => {
+                group.snapshot_metadata = meta;
+                replace(segments, &mut group.snapshot_loc, loc);
+            }
+            ItemValue::Compact { index, term } => {
+                while group.entries.front().map_or(false, |e| e.index < index) {
+                    kill(segments, group.entries.pop_front().unwrap().loc);
+                }
+                if index.saturating_sub(1) > group.truncated.0 {
+                    group.truncated = (index.saturating_sub(1), term);
+                }
+                replace(segments, &mut group.compact_loc, loc);
+            }
+            ItemValue::GroupDesc(desc) => {
+                group.desc = Some(desc);
+                replace(segments, &mut group.desc_loc, loc);
+            }
+            ItemValue::RemoveGroup => unreachable!(),
+        }
+    }
+
+    /// Returns true if the item at `loc` is still referenced by the group.
+    fn is_live(&mut self, group_id: u64, value: &ItemValue, loc: Location) -> bool {
+        if let ItemValue::RemoveGroup = value {
+            return true;
+        }
+        self.groups
+            .get_mut(&group_id)
+            .and_then(|group| group.slot(value))
+            .map_or(false, |slot| *slot == loc)
+    }
+
+    #[inline]
+    fn write(&mut self, items: Vec<PendingItem>) -> Result<Option<PendingSync>> {
+        self.append(items, false)
+    }
+
+    /// Append the items in one record to the active segment, then apply them
+    /// to the groups. Nothing is applied if the write fails.
+    ///
+    /// The record isn't synced here, the returned `PendingSync` must be synced
+    /// after the lock of the log is released. The items are visible to the
+    /// readers before they're synced, and they stay applied if the fsync fails,
+    /// so the error of the fsync must fail the write of the caller. None is
+    /// returned if the record needn't be synced, e.g. it's written with
+    /// `defer_sync`, which is synced by the next `sync`.
+    fn append(&mut self, items: Vec<PendingItem>, defer_sync: bool) -> Result<Option<PendingSync>> {
+        let mut builder = RecordBuilder::new();
+        for item in items.iter() {
+            builder.push(item.kind, item.group_id, &item.payload);
+        }
+        let record = builder.finish();
+
+        let active_size = self.segments[&self.active].size;
+        if active_size > 0 && active_size + record.len() as u64 > self.config.segment_size {
+            // the sealed segment is synced under the lock, so no record in the
+            // new segment is synced before the preceding ones. It's rare.
+            if !self.config.never_sync() {
+                self.segments[&self.active]
+                    .file
+                    .sync_data()
+                    .map_err(io_error)?;
+                self.write_syncs.fetch_add(1, Ordering::Relaxed);
+            }
+            self.create_segment(self.active + 1)?;
+        }
+
+        let need_sync = !defer_sync && self.need_sync(items.iter().map(|item| item.group_id));
+        let segment = self.segments.get_mut(&self.active).unwrap();
+        let base = segment.size;
+        if let Err(err) = segment.file.write_all_at(record, base) {
+            // drop the partial record, so that the following records are not
+            // appended after a torn one.
+            let _ = segment.file.set_len(base);
+            return Err(io_error(err));
+        }
+        segment.size += record.len() as u64;
+        self.appended_bytes += record.len() as u64;
+        let pending = need_sync.then(|| self.pending_sync());
+        if !need_sync {
+            // the groups which never sync are not tracked.
+            let config = &self.config;
+            self.unsynced.extend(
+                items
+                    .iter()
+                    .map(|item| item.group_id)
+                    .filter(|group_id| config.group_sync_policy(*group_id) != SyncPolicy::Never),
+            );
+        }
+
+        let active = self.active;
+        for (item, offset) in items.into_iter().zip(builder.offsets.iter()) {
+            let loc = Location {
+                segment: active,
+                offset: base + offset,
+                len: item.payload.len() as u32,
+            };
+            self.apply(item.group_id, item.value, loc);
+        }
+        Ok(pending)
+    }
+
+    /// Returns the fsync of the records written to the active segment so far.
+    fn pending_sync(&self) -> PendingSync {
+        PendingSync {
+            file: self.segments[&self.active].file.clone(),
+            write_syncs: self.write_syncs.clone(),
+        }
+    }
+
+    /// Returns one fsync of the active segment which syncs the records written
+    /// with `defer_sync` or skipped by the period of `SyncPolicy::Periodic`,
+    /// the sealed segments are synced when they're sealed.
+    fn sync(&mut self) -> Option<PendingSync> {
+        if !self.need_sync(self.unsynced.iter().copied()) {
+            return None;
+        }
+        self.unsynced.clear();
+        Some(self.pending_sync())
+    }
+
+    /// Returns true if the records written by the groups should be fsynced
+    /// according to their `SyncPolicy`.
+    fn need_sync(&self, group_ids: impl Iterator<Item = u64>) -> bool {
+        let mut policies = HashSet::new();
+        for group_id in group_ids {
+            policies.insert(self.config.group_sync_policy(group_id));
+        }
+        // the policies are checked all, so the time of last fsync is updated.
+        policies.into_iter().fold(false, |need, policy| {
+            policy.need_sync(&self.last_sync, self.clock.as_ref()) || need
+        })
+    }
+
+    fn read(&self, loc: Location) -> Result<Vec<u8>> {
+        let segment = self
+            .segments
+            .get(&loc.segment)
+            .ok_or(StorageError::Unavailable)?;
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        let mut buf = vec![0; loc.len as usize];
+        segment
+            .file
+            .read_exact_at(&mut buf, loc.offset)
+            .map_err(io_error)?;
+        Ok(buf)
+    }
+
+    /// Rewrite the sealed segments whose live ratio is below `rewrite_ratio`,
+    /// returns the number of the rewritten segments.
+    fn rewrite_segments(&mut self) -> Result<usize> {
+        let ratio = self.config.rewrite_ratio;
+        let candidates = self
+            .segments
+            .iter()
+            .filter(|(id, segment)| {
+                **id != self.active && (segment.live as f64) < segment.size as f64 * ratio
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in candidates.iter() {
+            self.rewrite_segment(*id)?;
+        }
+        Ok(candidates.len())
+    }
+
+    /// Replace the segment by a new one with only the live items, the order of
+    /// items is kept, so the replay of the segments rebuilds the same state.
+    /// The new segment is renamed over the old one after it's fsynced, so the
+    /// old one is intact if the rewriting is interrupted.
+    fn rewrite_segment(&mut self, id: u64) -> Result<()> {
+        let path = segment_path(&self.config.dir, id);
+        let data = fs::read(&path).map_err(io_error)?;
+        let (items, _) = parse_records(&data);
+        let mut builder = RecordBuilder::new();
+        let mut moved = vec![];
+        for item in items {
+            let payload = &data[item.offset as usize..item.offset as usize + item.len as usize];
+            let value = decode_item(item.kind, payload)?;
+            let loc = Location {
+                segment: id,
+                offset: item.offset,
+                len: item.len,
+            };
+            if self.is_live(item.group_id, &value, loc) {
+                builder.push(item.kind, item.group_id, payload);
+                moved.push((item.group_id, value, loc));
+            }
+        }
+
+        if moved.is_empty() {
+            self.segments.remove(&id);
+            fs::remove_file(&path).map_err(io_error)?;
+            return sync_dir(&self.config.dir);
+        }
+
+        let record = builder.finish();
+        let tmp = path.with_extension(REWRITE_EXT);
+        File::create(&tmp)
+            .and_then(|mut file| {
+                file.write_all(record)?;
+                file.sync_all()
+            })
+            .map_err(io_error)?;
+        fs::rename(&tmp, &path).map_err(io_error)?;
+        sync_dir(&self.config.dir)?;
+
+        self.segments.insert(
+            id,
+            SegmentMeta {
+                file: Arc::new(open_segment(&path)?),
+                size: record.len() as u64,
+                live: 0,
+            },
+        );
+        self.rewritten_bytes += record.len() as u64;
+        for ((group_id, value, old), offset) in moved.into_iter().zip(builder.offsets.iter()) {
+            let loc = Location {
+                segment: id,
+                offset: *offset,
+                len: old.len,
+            };
+            if let Some(slot) = self
+                .groups
+                .get_mut(&group_id)
+                .and_then(|group| group.slot(&value))
+            {
+                *slot = loc;
+            }
+            revive(&mut self.segments, loc);
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> SegmentStats {
+        SegmentStats {
+            segments: self.segments.len(),
+            groups: self.groups.len(),
+            total_bytes: self.segments.values().map(|s| s.size).sum(),
+            live_bytes: self.segments.values().map(|s| s.live).sum(),
+            appended_bytes: self.appended_bytes,
+            rewritten_bytes: self.rewritten_bytes,
+            write_syncs: self.write_syncs.load(Ordering::Relaxed),
+            reads: self.reads.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// `SegmentStorage` is the `RaftStorage` of a group in `SegmentedStorage`.
+///
+/// The index and term of entries are kept in memory, the entries are read from
+/// the segments by positional reads, which are served by the page cache in
+/// common, and the `RaftStorageImpl` caches the recent entries.
+#[derive(Clone)]
+pub struct SegmentStorage {
+    group_id: u64,
+    log: Arc<Mutex<SegmentLog>>,
+}
+
+impl SegmentStorage {
+    fn lock(&self) -> MutexGuard<'_, SegmentLog> {
+        self.log.lock().unwrap()
+    }
+
+    fn compact(&self, compact_index: u64) -> Result<()> {
+        let mut log = self.lock();
+        let group = log.group(self.group_id)?;
+        if compact_index <= group.first_index() {
+            return Ok(());
+        }
+        if compact_index > group.last_index().saturating_add(1) {
+            panic!(
+                "compact not received raft logs: {}, last index: {}",
+                compact_index,
+                group.last_index()
+            );
+        }
+
+        let term = group.term(compact_index - 1)?;
+        let mut payload = compact_index.to_le_bytes().to_vec();
+        payload.extend_from_slice(&term.to_le_bytes());
+        let pending = log.write(vec![PendingItem {
+            group_id: self.group_id,
+            kind: ITEM_COMPACT,
+            payload,
+            value: ItemValue::Compact {
+                index: compact_index,
+                term,
+            },
+        }])?;
+        drop(log);
+        sync_pending(pending)
+    }
+
+    fn save_snapshot(&self, mut snapshot: Snapshot, compact_index: u64) -> Result<()> {
+        let mut log = self.lock();
+        let group = log.group(self.group_id)?;
+        let meta = snapshot.get_metadata();
+        if meta.index <= group.snapshot_metadata.index {
+            return Err(StorageError::SnapshotOutOfDate);
+        }
+        if meta.index > group.last_index() {
+            panic!(
+                "compact not received raft logs: {}, last index: {}",
+                meta.index.saturating_add(1),
+                group.last_index()
+            );
+        }
+
+        // the snapshot and the compaction are written in one record.
+        let compact_index = cmp::min(compact_index, meta.index.saturating_add(1));
+        let compact = if compact_index > group.first_index() {
+            Some((compact_index, group.term(compact_index - 1)?))
+        } else {
+            None
+        };
+        let mut items = vec![PendingItem {
+            group_id: self.group_id,
+            kind: ITEM_SAVED_SNAPSHOT,
+            payload: snapshot.encode_to_vec(),
+            value: ItemValue::SavedSnapshot(snapshot.take_metadata()),
+        }];
+        if let Some((index, term)) = compact {
+            let mut payload = index.to_le_bytes().to_vec();
+            payload.extend_from_slice(&term.to_le_bytes());
+            items.push(PendingItem {
+                group_id: self.group_id,
+                kind: ITEM_COMPACT,
+                payload,
+                value: ItemValue::Compact { index, term },
+            });
+        }
+        let pending = log.write(items)?;
+        drop(log);
+        sync_pending(pending)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let defer_sync = batch.defer_sync;
+        let mut log = self.lock();
+        let group = log.group(self.group_id)?;
+        let (mut first, mut last) = (group.first_index(), group.last_index());
+        let mut items = vec![];
+        if let Some(mut snapshot) = batch.snapshot {
+            let index = snapshot.get_metadata().index;
+            if first > index {
+                return Err(StorageError::SnapshotOutOfDate);
+            }
+            (first, last) = (index.saturating_add(1), index);
+            items.push(PendingItem {
+                group_id: self.group_id,
+                kind: ITEM_SNAPSHOT,
+                payload: snapshot.encode_to_vec(),
+                value: ItemValue::Snapshot(snapshot.take_metadata()),
+            });
+        }
+
+        if let Some(entry) = batch.entries.first() {
+            if first > entry.index {
+                panic!(
+                    "overwrite compacted raft logs, compacted: {}, append: {}",
+                    first - 1,
+                    entry.index,
+                );
+            }
+            if last + 1 < entry.index {
+                panic!(
+                    "raft logs should be continuous, last index: {}, new appended: {}",
+                    last, entry.index,
+                );
+            }
+        }
+        for entry in batch.entries.iter() {
+            items.push(PendingItem {
+                group_id: self.group_id,
+                kind: ITEM_ENTRY,
+                payload: entry.encode_to_vec(),
+                value: ItemValue::Entry {
+                    index: entry.index,
+                    term: entry.term,
+                },
+            });
+        }
+
+        if let Some(hs) = batch.hard_state {
+            items.push(PendingItem {
+                group_id: self.group_id,
+                kind: ITEM_HARD_STATE,
+                payload: hs.encode_to_vec(),
+                value: ItemValue::HardState(hs),
+            });
+        }
+
+        if items.is_empty() {
+            return Ok(());
+        }
+        let pending = log.append(items, defer_sync)?;
+        drop(log);
+        sync_pending(pending)
+    }
+
+    fn write_item(&self, kind: u8, payload: Vec<u8>, value: ItemValue) -> Result<()> {
+        let pending = self.lock().write(vec![PendingItem {
+            group_id: self.group_id,
+            kind,
+            payload,
+            value,
+        }])?;
+        sync_pending(pending)
+    }
+}
+
+impl RaftStorage for SegmentStorage {
+    fn initial_state(&self) -> Result<RaftState> {
+        Ok(self.lock().group(self.group_id)?.raft_state.clone())
+    }
+
+    fn entries(&self, low: u64, high: u64, max_size: impl Into<Option<u64>>) -> Result<Vec<Entry>> {
+        let max_size = max_size.into();
+        let log = self.lock();
+        let group = log.group(self.group_id)?;
+        if low < group.first_index() {
+            return Err(StorageError::Compacted);
+        }
+
+        if high > group.last_index().saturating_add(1) {
+            panic!(
+                "index out of bound (last: {}, high: {})",
+                group.last_index().saturating_add(1),
+                high
+            );
+        }
+
+        // the entries beyond `max_size` are not read, at least one entry is
+        // returned.
+        let mut ents = vec![];
+        let mut size = 0;
+        for index in low..high {
+            let loc = group.entry(index).unwrap().loc;
+            let entry = Entry::decode(log.read(loc)?.as_slice()).map_err(decode_error)?;
+            size += entry.compute_size();
+            if !ents.is_empty() && max_size.map_or(false, |max| size > max) {
+                break;
+            }
+            ents.push(entry);
+        }
+        Ok(ents)
+    }
+
+    fn term(&self, idx: u64) -> Result<u64> {
+        self.lock().group(self.group_id)?.term(idx)
+    }
+
+    fn first_index(&self) -> Result<u64> {
+        Ok(self.lock().group(self.group_id)?.first_index())
+    }
+
+    fn last_index(&self) -> Result<u64> {
+        Ok(self.lock().group(self.group_id)?.last_index())
+    }
+
+    fn snapshot(&self, request_index: u64) -> Result<Snapshot> {
+        let log = self.lock();
+        let group = log.group(self.group_id)?;
+        // like `MemStorage`, the latest saved snapshot is returned, a fresher
+        // one is taken by the actor.
+        if group.snapshot_metadata.index < request_index {
+            return Err(StorageError::SnapshotTemporarilyUnavailable);
+        }
+        let mut snapshot = Snapshot::default();
+        snapshot.set_metadata(group.snapshot_metadata.clone());
+        if let Some(loc) = group.snapshot_loc {
+            let stored = Snapshot::decode(log.read(loc)?.as_slice()).map_err(decode_error)?;
+            snapshot.data = stored.data;
+        }
+        Ok(snapshot)
+    }
+
+    type AppendEntriesFuture<'life0> = Ready<Result<()>>
+    where
+        Self: 'life0;
+    fn append_entries(&self, entries: Vec<Entry>) -> Self::AppendEntriesFuture<'_> {
+        ready(self.write_batch(WriteBatch {
+            entries,
+            ..Default::default()
+        }))
+    }
+
+    type GetHardStateFuture<'life0> = Ready<Result<HardState>>
+    where
+        Self: 'life0;
+    fn get_hard_state(&self) -> Self::GetHardStateFuture<'_> {
+        ready(self.initial_state().map(|rs| rs.hard_state))
+    }
+
+    type SetHardStateFuture<'life0> = Ready<Result<()>>
+    where
+        Self: 'life0;
+    fn set_hardstate(&self, hs: HardState) -> Self::SetHardStateFuture<'_> {
+        ready(self.write_item(
+            ITEM_HARD_STATE,
+            hs.encode_to_vec(),
+            ItemValue::HardState(hs),
+        ))
+    }
+
+    type SetConfStateFuture<'life0> = Ready<Result<()>>
+    where
+        Self: 'life0;
+    fn set_confstate(&self, cs: ConfState) -> Self::SetConfStateFuture<'_> {
+        ready(self.write_item(
+            ITEM_CONF_STATE,
+            cs.encode_to_vec(),
+            ItemValue::ConfState(cs),
+        ))
+    }
+
+    type GetConfStateFuture<'life0> = Ready<Result<ConfState>>
+    where
+        Self: 'life0;
+    fn get_confstate(&self) -> Self::GetConfStateFuture<'_> {
+        ready(self.initial_state().map(|rs| rs.conf_state))
+    }
+
+    type SetCommitFuture<'life0> = Ready<Result<()>>
+    where
+        Self: 'life0;
+    fn set_commit(&self, commit: u64) -> Self::SetCommitFuture<'_> {
+        let res = self.initial_state().and_then(|rs| {
+            let mut hs = rs.hard_state;
+            hs.commit = commit;
+            self.write_item(ITEM_HARD_STATE, hs.encode_to_vec(), ItemValue::HardState(hs))
+        });
+        ready(res)
+    }
+
+    type ApplySnapshotFuture<'life0> = Ready<Result<()>>
+    where
+        Self: 'life0;
+    fn apply_snapshot(&self, snapshot: Snapshot) -> Self::ApplySnapshotFuture<'_> {
+        ready(self.write_batch(WriteBatch {
+            snapshot: Some(snapshot),
+            ..Default::default()
+        }))
+    }
+
+    type CompactToSnapshotFuture<'life0> = Ready<Result<()>>
+    where
+        Self: 'life0;
+    fn compact_to_snapshot(
+        &self,
+        snapshot: Snapshot,
+        compact_index: u64,
+    ) -> Self::CompactToSnapshotFuture<'_> {
+        ready(self.save_snapshot(snapshot, compact_index))
+    }
+
+    type WriteReadyFuture<'life0> = Ready<Result<()>>
+    where
+        Self: 'life0;
+    fn write_ready(&self, batch: WriteBatch) -> Self::WriteReadyFuture<'_> {
+        ready(self.write_batch(batch))
+    }
+}
+
+impl RaftSnapshotBuilder for SegmentStorage {
+    fn build_snapshot(&self, _applied: u64) -> Result<Snapshot> {
+        // the snapshot data is built by the state machine.
+        Err(StorageError::SnapshotTemporarilyUnavailable)
+    }
+}
+
+/// `SegmentedStorage` multiplexes the raft logs and states of all groups into
+/// shared append-only segment files, rather than a file or column family per
+/// group, which keeps the number of files and fsyncs independent of the number
+/// of groups.
+///
+/// Each write is framed as a record of items keyed by the group, e.g. the
+/// entries and hard state of a `WriteBatch`, the record is checksummed so the
+/// torn record left by a crash at the tail of the active segment is truncated
+/// on open, while a corrupt record in a sealed segment fails the open. The
+/// fsync of a write is issued after the shared lock of the log is released,
+/// so the writes of other groups are not blocked by it. The written items are
+/// visible to the readers before the fsync returns and they're not rolled
+/// back if the fsync fails, the write returns the error then, after which the
+/// durability of the storage is unknown. The index of the live
+/// items of each group is rebuilt by replaying the segments on open. The space
+/// of the compacted, overwritten or removed items is reclaimed by
+/// `rewrite_segments`, which rewrites the sealed segments with few live items.
+#[derive(Clone)]
+pub struct SegmentedStorage {
+    log: Arc<Mutex<SegmentLog>>,
+    sync_policy: SyncPolicy,
+}
+
+impl SegmentedStorage {
+    /// Open the storage in `config.dir`, the segments are replayed to rebuild
+    /// the state of groups.
+    pub fn open(config: SegmentConfig) -> Result<Self> {
+        SegmentedStorage::open_with_clock(config, Arc::new(SystemClock))
+    }
+
+    /// Open the storage like `open`, the period of `SyncPolicy::Periodic` is
+    /// measured by `clock`, e.g. the `MockClock` shared with the `MultiRaft`
+    /// by `MultiRaftExtensions::clock`.
+    pub fn open_with_clock(config: SegmentConfig, clock: Arc<dyn Clock>) -> Result<Self> {
+        let sync_policy = config.sync_policy;
+        Ok(Self {
+            log: Arc::new(Mutex::new(SegmentLog::open(config, clock)?)),
+            sync_policy,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SegmentLog> {
+        self.log.lock().unwrap()
+    }
+
+    fn storage(&self, group_id: u64) -> RaftStorageImpl<SegmentStorage> {
+        RaftStorageImpl::new(SegmentStorage {
+            group_id,
+            log: self.log.clone(),
+        })
+    }
+
+    /// Discards all log entries of the group prior to `compact_index`, the
+    /// space is reclaimed after the segments are rewritten.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `compact_index` is higher than `last_index + 1` of the group.
+    pub fn compact(&self, group_id: u64, compact_index: u64) -> Result<()> {
+        SegmentStorage {
+            group_id,
+            log: self.log.clone(),
+        }
+        .compact(compact_index)
+    }
+
+    /// Rewrite the sealed segments whose live bytes are less than
+    /// `rewrite_ratio` of their size, the segments without live items are
+    /// deleted. Returns the number of the rewritten segments.
+    pub fn rewrite_segments(&self) -> Result<usize> {
+        self.lock().rewrite_segments()
+    }
+
+    pub fn stats(&self) -> SegmentStats {
+        self.lock().stats()
+    }
+
+    fn write_group_desc(&self, group_id: u64, desc: RaftGroupDesc) -> Result<()> {
+        let pending = self.lock().write(vec![PendingItem {
+            group_id,
+            kind: ITEM_GROUP_DESC,
+            payload: desc.encode_to_vec(),
+            value: ItemValue::GroupDesc(desc),
+        }])?;
+        sync_pending(pending)
+    }
+
+    fn find_replica<F>(&self, group_id: u64, f: F) -> Result<Option<ReplicaDesc>>
+    where
+        F: Fn(&ReplicaDesc) -> bool,
+    {
+        let log = self.lock();
+        Ok(log
+            .groups
+            .get(&group_id)
+            .and_then(|group| group.desc.as_ref())
+            .and_then(|desc| desc.replicas.iter().find(|r| f(r)).cloned()))
+    }
+}
+
+impl MultiRaftStorage<SegmentStorage> for SegmentedStorage {
+    type GroupStorageFuture<'life0> = Ready<Result<RaftStorageImpl<SegmentStorage>>>
+    where
+        Self: 'life0;
+    fn group_storage(&self, group_id: u64, _replica_id: u64) -> Self::GroupStorageFuture<'_> {
+        self.lock().groups.entry(group_id).or_default();
+        ready(Ok(self.storage(group_id)))
+    }
+
+    type CreateGroupStorageWithConfStateFuture<'life0, T> =
+        Ready<Result<RaftStorageImpl<SegmentStorage>>>
+    where
+        Self: 'life0,
+        ConfState: From<T>,
+        T: Send + 'life0;
+    fn create_group_storage_with_conf_state<T>(
+        &self,
+        group_id: u64,
+        _replica_id: u64,
+        conf_state: T,
+    ) -> Self::CreateGroupStorageWithConfStateFuture<'_, T>
+    where
+        ConfState: From<T>,
+        T: Send,
+    {
+        let cs = ConfState::from(conf_state);
+        // the guard of the log is dropped before the fsync.
+        let res = self.lock().write(vec![PendingItem {
+            group_id,
+            kind: ITEM_CONF_STATE,
+            payload: cs.encode_to_vec(),
+            value: ItemValue::ConfState(cs),
+        }]);
+        ready(res.and_then(sync_pending).map(|_| self.storage(group_id)))
+    }
+
+    type GroupDescFuture<'life0> = Ready<Result<RaftGroupDesc>>
+    where
+        Self: 'life0;
+    fn group_desc(&self, group_id: u64) -> Self::GroupDescFuture<'_> {
+        let desc = self
+            .lock()
+            .groups
+            .get(&group_id)
+            .and_then(|group| group.desc.clone())
+            .unwrap_or_else(|| RaftGroupDesc {
+                group_id,
+                ..Default::default()
+            });
+        ready(Ok(desc))
+    }
+
+    type SetGroupDescFuture<'life0> = Ready<Result<()>>
+    where
+        Self: 'life0;
+    fn set_group_desc(
+        &self,
+        group_id: u64,
+        group_desc: RaftGroupDesc,
+    ) -> Self::SetGroupDescFuture<'_> {
+        ready(self.write_group_desc(group_id, group_desc))
+    }
+
+    type ReplicaDescFuture<'life0> = Ready<Result<Option<ReplicaDesc>>>
+    where
+        Self: 'life0;
+    fn replica_desc(&self, group_id: u64, replica_id: u64) -> Self::ReplicaDescFuture<'_> {
+        ready(self.find_replica(group_id, |r| r.replica_id == replica_id))
+    }
+
+    type SetReplicaDescFuture<'life0> = Ready<Result<()>>
+    where
+        Self: 'life0;
+    fn set_replica_desc(
+        &self,
+        group_id: u64,
+        replica_desc: ReplicaDesc,
+    ) -> Self::SetReplicaDescFuture<'_> {
+        let mut desc = self
+            .lock()
+            .groups
+            .get(&group_id)
+            .and_then(|group| group.desc.clone())
+            .unwrap_or_else(|| RaftGroupDesc {
+                group_id,
+                ..Default::default()
+            });
+        if desc.replicas.iter().any(|r| *r == replica_desc) {
+            return ready(Ok(()));
+        }
+        desc.nodes.push(replica_desc.node_id);
+        desc.replicas.push(replica_desc);
+        ready(self.write_group_desc(group_id, desc))
+    }
+
+    type ReplicaForNodeFuture<'life0> = Ready<Result<Option<ReplicaDesc>>>
+    where
+        Self: 'life0;
+    fn replica_for_node(&self, group_id: u64, node_id: u64) -> Self::ReplicaForNodeFuture<'_> {
+        ready(self.find_replica(group_id, |r| r.node_id == node_id))
+    }
+
+    type RemoveGroupStorageFuture<'life0> = Ready<Result<()>>
+    where
+        Self: 'life0;
+    fn remove_group_storage(&self, group_id: u64) -> Self::RemoveGroupStorageFuture<'_> {
+        let res = self.lock().write(vec![PendingItem {
+            group_id,
+            kind: ITEM_REMOVE_GROUP,
+            payload: vec![],
+            value: ItemValue::RemoveGroup,
+        }]);
+        ready(res.and_then(sync_pending))
+    }
+
+    type SnapshotMetadataFuture<'life0> = Ready<Result<SnapshotMetadata>>
+    where
+        Self: 'life0;
+    fn snapshot_metadata(&self, group_id: u64) -> Self::SnapshotMetadataFuture<'_> {
+        let meta = self
+            .lock()
+            .groups
+            .get(&group_id)
+            .map(|group| group.snapshot_metadata.clone())
+            .unwrap_or_default();
+        ready(Ok(meta))
+    }
+
+    type LogBoundsFuture<'life0> = Ready<Result<LogBounds>>
+    where
+        Self: 'life0;
+    fn log_bounds(&self, group_id: u64) -> Self::LogBoundsFuture<'_> {
+        let bounds = self
+            .lock()
+            .groups
+            .get(&group_id)
+            .map(|group| LogBounds {
+                first_index: group.first_index(),
+                last_index: group.last_index(),
+                snapshot_index: group.snapshot_metadata.index,
+            })
+            .unwrap_or_default();
+        ready(Ok(bounds))
+    }
+
+    fn sync_policy(&self) -> SyncPolicy {
+        self.sync_policy
+    }
+
+    fn group_sync_policy(&self, group_id: u64) -> SyncPolicy {
+        self.lock().config.group_sync_policy(group_id)
+    }
+
+    fn group_sync_policies(&self) -> Vec<(u64, SyncPolicy)> {
+        self.lock()
+            .config
+            .group_sync_policies
+            .iter()
+            .map(|(group_id, policy)| (*group_id, *policy))
+            .collect()
+    }
+
+    fn set_group_sync_policy(&self, group_id: u64, policy: SyncPolicy) -> Result<()> {
+        self.lock()
+            .config
+            .group_sync_policies
+            .insert(group_id, policy);
+        Ok(())
+    }
+
+    type SyncWritesFuture<'life0> = impl Future<Output = Result<()>> + Send + 'life0
+    where
+        Self: 'life0;
+    fn sync_writes(&self) -> Self::SyncWritesFuture<'_> {
+        let pending = self.lock().sync();
+        async move {
+            match pending {
+                None => Ok(()),
+                // the fsync runs on the blocking thread rather than the
+                // thread of the caller, e.g. the actor of the node.
+                Some(pending) => tokio::task::spawn_blocking(move || pending.sync())
+                    .await
+                    .map_err(|err| StorageError::Other(Box::new(err)))?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::executor::block_on;
+
+    use crate::multiraft::MockClock;
+    use crate::proto::ConfState;
+    use crate::proto::Entry;
+    use crate::proto::HardState;
+    use crate::proto::Snapshot;
+    use crate::storage::SyncPolicy;
+
+    use super::MultiRaftStorage;
+    use super::RaftStorage;
+    use super::SegmentConfig;
+    use super::SegmentedStorage;
+    use super::StorageError;
+    use super::WriteBatch;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "smol-raft-segment-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn test_config(dir: &PathBuf) -> SegmentConfig {
+        let mut config = SegmentConfig::new(dir);
+        config.segment_size = 4096;
+        config.sync_policy = SyncPolicy::Never;
+        config
+    }
+
+    fn new_entry(index: u64, term: u64) -> Entry {
+        let mut e = Entry::default();
+        e.term = term;
+        e.index = index;
+        e.data = vec![index as u8; 64];
+        e
+    }
+
+    fn write_entries(storage: &SegmentedStorage, group_id: u64, low: u64, high: u64) {
+        block_on(async {
+            let gs = storage.group_storage(group_id, 1).await.unwrap();
+            let mut hs = HardState::default();
+            hs.term = 1;
+            hs.commit = high - 1;
+            gs.write_ready(WriteBatch {
+                snapshot: None,
+                entries: (low..high).map(|index| new_entry(index, 1)).collect(),
+                hard_state: Some(hs),
+                defer_sync: false,
+            })
+            .await
+            .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_segmented_storage_reopen() {
+        let dir = test_dir("reopen");
+        let storage = SegmentedStorage::open(test_config(&dir)).unwrap();
+        for group_id in 1..=10 {
+            write_entries(&storage, group_id, 1, 20);
+        }
+        // the conflicting entries are overwritten.
+        block_on(async {
+            let gs = storage.group_storage(1, 1).await.unwrap();
+            gs.append_entries(vec![new_entry(15, 2)]).await.unwrap();
+        });
+        assert!(storage.stats().segments > 1);
+        drop(storage);
+
+        let storage = SegmentedStorage::open(test_config(&dir)).unwrap();
+        assert_eq!(storage.stats().groups, 10);
+        block_on(async {
+            let gs = storage.group_storage(1, 1).await.unwrap();
+            assert_eq!(gs.first_index(), Ok(1));
+            assert_eq!(gs.last_index(), Ok(15));
+            assert_eq!(gs.term(15), Ok(2));
+            assert_eq!(gs.initial_state().unwrap().hard_state.commit, 19);
+
+            let gs = storage.group_storage(10, 1).await.unwrap();
+            let ents = gs.entries(5, 10, u64::MAX).unwrap();
+            assert_eq!(ents, (5..10).map(|i| new_entry(i, 1)).collect::<Vec<_>>());
+            // at least one entry is returned.
+            assert_eq!(gs.entries(5, 10, 0u64).unwrap().len(), 1);
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_segmented_storage_compact_to_snapshot() {
+        let dir = test_dir("compact-snapshot");
+        let storage = SegmentedStorage::open(test_config(&dir)).unwrap();
+        write_entries(&storage, 1, 1, 20);
+        block_on(async {
+            let mut snap = Snapshot::default();
+            snap.mut_metadata().index = 15;
+            snap.mut_metadata().term = 1;
+            let gs = storage.group_storage(1, 1).await.unwrap();
+            // the entries from 10 are kept for the lagging replica.
+            gs.compact_to_snapshot(snap, 10).await.unwrap();
+            assert_eq!(gs.first_index(), Ok(10));
+        });
+        drop(storage);
+
+        let storage = SegmentedStorage::open(test_config(&dir)).unwrap();
+        block_on(async {
+            let gs = storage.group_storage(1, 1).await.unwrap();
+            assert_eq!(gs.first_index(), Ok(10));
+            assert_eq!(gs.term(9), Ok(1));
+            assert_eq!(storage.snapshot_metadata(1).await.unwrap().index, 15);
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_segmented_storage_torn_tail() {
+        let dir = test_dir("torn");
+        let storage = SegmentedStorage::open(test_config(&dir)).unwrap();
+        write_entries(&storage, 1, 1, 5);
+        drop(storage);
+
+        // a crash in the middle of writing leaves a partial record.
+        let path = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .max()
+            .unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 10).unwrap();
+
+        let storage = SegmentedStorage::open(test_config(&dir)).unwrap();
+        block_on(async {
+            let gs = storage.group_storage(1, 1).await.unwrap();
+            // the whole batch is discarded, include the hard state.
+            assert_eq!(gs.last_index(), Ok(0));
+            assert_eq!(gs.initial_state().unwrap().hard_state, HardState::default());
+        });
+        write_entries(&storage, 1, 1, 3);
+        drop(storage);
+        let storage = SegmentedStorage::open(test_config(&dir)).unwrap();
+        block_on(async {
+            let gs = storage.group_storage(1, 1).await.unwrap();
+            assert_eq!(gs.last_index(), Ok(2));
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_segmented_storage_deferred_sync() {
+        let dir = test_dir("deferred");
+        let mut config = test_config(&dir);
+        config.sync_policy = SyncPolicy::Always;
+        let storage = SegmentedStorage::open(config.clone()).unwrap();
+        for group_id in 1..=3 {
+            let gs = storage.group_storage(group_id, 1).await.unwrap();
+            gs.write_ready(WriteBatch {
+                entries: vec![new_entry(1, 1), new_entry(2, 1)],
+                defer_sync: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        }
+        // the deferred batches of all groups are synced by one fsync.
+        assert_eq!(storage.lock().unsynced.len(), 3);
+        storage.sync_writes().await.unwrap();
+        assert!(storage.lock().unsynced.is_empty());
+        drop(storage);
+
+        let storage = SegmentedStorage::open(config).unwrap();
+        for group_id in 1..=3 {
+            let gs = storage.group_storage(group_id, 1).await.unwrap();
+            assert_eq!(gs.last_index(), Ok(2));
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_segmented_storage_concurrent_sync() {
+        let dir = test_dir("concurrent");
+        let mut config = test_config(&dir);
+        config.sync_policy = SyncPolicy::Always;
+        let storage = SegmentedStorage::open(config.clone()).unwrap();
+        // the writes of the groups are fsynced out of the lock of the log.
+        let writers = (1..=4)
+            .map(|group_id| {
+                let storage = storage.clone();
+                std::thread::spawn(move || {
+                    for index in 1..=20 {
+                        write_entries(&storage, group_id, index, index + 1);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert!(storage.stats().write_syncs >= 80);
+        drop(storage);
+
+        let storage = SegmentedStorage::open(config).unwrap();
+        block_on(async {
+            for group_id in 1..=4 {
+                let gs = storage.group_storage(group_id, 1).await.unwrap();
+                assert_eq!(gs.last_index(), Ok(20));
+            }
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_segmented_storage_group_sync_policy() {
+        let dir = test_dir("group-sync");
+        let mut config = test_config(&dir);
+        // the metadata group 1 is always synced, the data group 2 isn't.
+        config.group_sync_policies.insert(1, SyncPolicy::Always);
+        let storage = SegmentedStorage::open(config).unwrap();
+        assert_eq!(storage.group_sync_policy(1), SyncPolicy::Always);
+        assert_eq!(storage.group_sync_policy(2), SyncPolicy::Never);
+
+        write_entries(&storage, 2, 1, 10);
+        assert_eq!(storage.stats().write_syncs, 0);
+        write_entries(&storage, 1, 1, 10);
+        assert_eq!(storage.stats().write_syncs, 1);
+
+        // the deferred writes are synced only if the group needs.
+        for group_id in [2, 1] {
+            let gs = storage.group_storage(group_id, 1).await.unwrap();
+            gs.write_ready(WriteBatch {
+                entries: vec![new_entry(10, 1)],
+                defer_sync: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+            storage.sync_writes().await.unwrap();
+        }
+        assert_eq!(storage.stats().write_syncs, 2);
+
+        // the policy of the group 3 is set when it's created.
+        storage
+            .set_group_sync_policy(3, SyncPolicy::Always)
+            .unwrap();
+        let mut policies = storage.group_sync_policies();
+        policies.sort_by_key(|(group_id, _)| *group_id);
+        assert_eq!(
+            policies,
+            vec![(1, SyncPolicy::Always), (3, SyncPolicy::Always)]
+        );
+        write_entries(&storage, 3, 1, 10);
+        assert_eq!(storage.stats().write_syncs, 3);
+        write_entries(&storage, 2, 11, 20);
+        assert_eq!(storage.stats().write_syncs, 3);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_segmented_storage_periodic_sync_trailing_write() {
+        let dir = test_dir("periodic");
+        let mut config = test_config(&dir);
+        config.sync_policy = SyncPolicy::Periodic(Duration::from_millis(50));
+        let clock = MockClock::new();
+        let storage = SegmentedStorage::open_with_clock(config, Arc::new(clock.clone())).unwrap();
+
+        // the write within the period isn't synced, it's synced by the next
+        // `sync_writes` after the period though nothing is written since.
+        write_entries(&storage, 1, 1, 10);
+        assert_eq!(storage.stats().write_syncs, 0);
+        storage.sync_writes().await.unwrap();
+        assert_eq!(storage.stats().write_syncs, 0);
+        clock.advance(Duration::from_millis(60));
+        storage.sync_writes().await.unwrap();
+        assert_eq!(storage.stats().write_syncs, 1);
+        assert!(storage.lock().unsynced.is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_segmented_storage_compact_rewrite() {
+        let dir = test_dir("rewrite");
+        let storage = SegmentedStorage::open(test_config(&dir)).unwrap();
+        for group_id in 1..=4 {
+            write_entries(&storage, group_id, 1, 100);
+        }
+        block_on(async {
+            let mut snap = Snapshot::default();
+            snap.mut_metadata().index = 10;
+            snap.mut_metadata().term = 1;
+            snap.mut_metadata().mut_conf_state().voters = vec![1, 2, 3];
+            snap.data = b"snapshot".to_vec();
+            let gs = storage.group_storage(1, 1).await.unwrap();
+            gs.apply_snapshot(snap).await.unwrap();
+            storage.remove_group_storage(4).await.unwrap();
+        });
+        for group_id in 2..=3 {
+            storage.compact(group_id, 90).unwrap();
+        }
+        // fill the active segment, so that the written segments are sealed.
+        write_entries(&storage, 5, 1, 100);
+
+        let before = storage.stats();
+        assert!(storage.rewrite_segments().unwrap() > 0);
+        let after = storage.stats();
+        assert!(after.total_bytes < before.total_bytes);
+        assert_eq!(after.live_bytes, before.live_bytes);
+        assert!(after.write_amplification() > 1.0);
+        drop(storage);
+
+        // the rewritten segments rebuild the same state.
+        let storage = SegmentedStorage::open(test_config(&dir)).unwrap();
+        block_on(async {
+            let gs = storage.group_storage(1, 1).await.unwrap();
+            assert_eq!(gs.first_index(), Ok(11));
+            assert_eq!(gs.term(10), Ok(1));
+            assert_eq!(gs.snapshot(10).unwrap().data, b"snapshot".to_vec());
+            assert_eq!(
+                gs.initial_state().unwrap().conf_state,
+                ConfState {
+                    voters: vec![1, 2, 3],
+                    ..Default::default()
+                }
+            );
+
+            let gs = storage.group_storage(2, 1).await.unwrap();
+            assert_eq!(gs.first_index(), Ok(90));
+            assert_eq!(gs.entries(1, 2, u64::MAX), Err(StorageError::Compacted));
+            assert_eq!(gs.entries(90, 100, u64::MAX).unwrap().len(), 10);
+
+            assert!(storage.snapshot_metadata(4).await.unwrap().index == 0);
+
+            let bounds = storage.log_bounds(2).await.unwrap();
+            assert_eq!((bounds.first_index, bounds.last_index), (90, 99));
+            let bounds = storage.log_bounds(1).await.unwrap();
+            assert_eq!((bounds.first_index, bounds.snapshot_index), (11, 10));
+            assert_eq!(storage.stats().groups, 4);
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
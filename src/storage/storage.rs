@@ -1,5 +1,18 @@
+use std::collections::VecDeque;
 use std::mem::transmute;
-
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::multiraft::Clock;
+#[cfg(test)]
+use crate::multiraft::MockClock;
+use crate::proto::limit_entry_size;
 use crate::proto::transmute_entries;
 use crate::proto::ConfState;
 use crate::proto::Entry;
@@ -11,6 +24,9 @@ use crate::proto::Snapshot;
 use crate::proto::SnapshotMetadata;
 
 use futures::Future;
+use futures::Stream;
+
+use super::entry_cache::EntryCache;
 
 /// An error with the storage.
 #[derive(Debug, thiserror::Error)]
@@ -18,6 +34,10 @@ pub enum StorageError {
     /// The storage was compacted and not accessible
     #[error("log compacted")]
     Compacted,
+    /// The log before the index was compacted, the index is the earliest
+    /// available index.
+    #[error("log compacted, the earliest available index is {0}")]
+    LogCompacted(u64),
     /// The log is not available.
     #[error("log unavailable")]
     Unavailable,
@@ -30,6 +50,9 @@ pub enum StorageError {
     /// The snapshot is being created.
     #[error("snapshot is temporarily unavailable")]
     SnapshotTemporarilyUnavailable,
+    /// The data of snapshot mismatches its checksum.
+    #[error("snapshot is corrupt")]
+    SnapshotCorrupt,
     /// Some other error occurred.
     #[error("unknown error {0}")]
     Other(#[from] Box<dyn std::error::Error + Sync + Send>),
@@ -38,6 +61,9 @@ pub enum StorageError {
 impl PartialEq for StorageError {
     // #[cfg_attr(feature = "cargo-clippy", allow(clippy::match_same_arms))]
     fn eq(&self, other: &StorageError) -> bool {
+        if let (StorageError::LogCompacted(a), StorageError::LogCompacted(b)) = (self, other) {
+            return a == b;
+        }
         matches!(
             (self, other),
             (StorageError::Compacted, StorageError::Compacted)
@@ -54,15 +80,36 @@ impl PartialEq for StorageError {
                     StorageError::SnapshotTemporarilyUnavailable,
                     StorageError::SnapshotTemporarilyUnavailable,
                 )
+                | (StorageError::SnapshotCorrupt, StorageError::SnapshotCorrupt)
         )
     }
 }
 
 pub type Result<T> = std::result::Result<T, StorageError>;
 
+/// Converts the `StorageError` to `raft::StorageError`. The `SnapshotCorrupt`
+/// which raft doesn't know is reported as `SnapshotTemporarilyUnavailable`, so that
+/// raft retries to send the snapshot later rather than panics, and the `LogCompacted`
+/// is reported as `Compacted`.
 #[inline]
 pub fn transmute_error(error: StorageError) -> raft::StorageError {
-    unsafe { transmute::<StorageError, raft::StorageError>(error) }
+    match error {
+        StorageError::Compacted | StorageError::LogCompacted(_) => raft::StorageError::Compacted,
+        StorageError::Unavailable => raft::StorageError::Unavailable,
+        StorageError::LogTemporarilyUnavailable => raft::StorageError::LogTemporarilyUnavailable,
+        StorageError::SnapshotOutOfDate => raft::StorageError::SnapshotOutOfDate,
+        StorageError::SnapshotTemporarilyUnavailable | StorageError::SnapshotCorrupt => {
+            raft::StorageError::SnapshotTemporarilyUnavailable
+        }
+        StorageError::Other(error) => raft::StorageError::Other(error),
+    }
+}
+
+/// Returns the CRC32C checksum of the snapshot data, which is persisted with the
+/// snapshot and carried by the snapshot transfer, then verified on install.
+#[inline]
+pub fn snapshot_checksum(data: &[u8]) -> u32 {
+    crc32c::crc32c(data)
 }
 
 #[inline]
@@ -135,6 +182,76 @@ impl RaftState {
     }
 }
 
+/// WriteBatch holds the state of a raft `Ready` that needs to be persisted, which
+/// is committed by `RaftStorage::write_ready` atomically.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    /// The snapshot to be installed, it is installed before the entries appended.
+    pub snapshot: Option<Snapshot>,
+    /// The unstable entries to be appended.
+    pub entries: Vec<Entry>,
+    /// The hard state to be saved.
+    pub hard_state: Option<HardState>,
+    /// If true, the batch isn't fsynced by `write_ready`, it's made durable by
+    /// the following `MultiRaftStorage::sync_writes`. The storage which always
+    /// syncs its writes may ignore it.
+    pub defer_sync: bool,
+}
+
+impl WriteBatch {
+    /// Returns true if there is nothing to be written.
+    pub fn is_empty(&self) -> bool {
+        self.snapshot.is_none() && self.entries.is_empty() && self.hard_state.is_none()
+    }
+}
+
+/// The bounds of the log of a group, see `MultiRaftStorage::log_bounds`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LogBounds {
+    /// The first index of the log which is available after the compaction, the
+    /// state before it is only available by the snapshot.
+    pub first_index: u64,
+    /// The last index of the log, it's `first_index - 1` if the log is empty.
+    pub last_index: u64,
+    /// The index of the latest snapshot, 0 if the group has no snapshot.
+    pub snapshot_index: u64,
+}
+
+/// SyncPolicy controls whether `RaftStorage::write_ready` fsyncs the batch,
+/// which trades the durability for the throughput.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum SyncPolicy {
+    /// Each batch is fsynced before `write_ready` returns. It's safe but slow.
+    #[default]
+    Always,
+    /// The batches are fsynced at most once per duration. On crash the batches
+    /// written within the last duration may be lost, which breaks the durability
+    /// of raft, e.g. the acked entries or votes are lost.
+    Periodic(Duration),
+    /// The batches are never fsynced, it's only for tests and caches.
+    Never,
+}
+
+impl SyncPolicy {
+    /// Returns true if the batch being written should be fsynced, `last_sync`
+    /// is the time of last fsync read from `clock`, which is updated if
+    /// returns true.
+    pub fn need_sync(&self, last_sync: &Mutex<Instant>, clock: &dyn Clock) -> bool {
+        match self {
+            SyncPolicy::Always => true,
+            SyncPolicy::Never => false,
+            SyncPolicy::Periodic(period) => {
+                let mut last_sync = last_sync.lock().unwrap();
+                if clock.elapsed(*last_sync) < *period {
+                    return false;
+                }
+                *last_sync = clock.now();
+                true
+            }
+        }
+    }
+}
+
 pub trait RaftSnapshotBuilder: Clone + Send + Sync + 'static {
     fn build_snapshot(&self, applied: u64) -> Result<Snapshot>;
 }
@@ -143,7 +260,13 @@ pub trait RaftSnapshotBuilder: Clone + Send + Sync + 'static {
 // MultiRaft storage trait
 //----------------------------------------------------------------------
 
-/// RaftStorage per replica
+/// RaftStorage per replica.
+///
+/// The read methods (`initial_state`, `entries`, `term`, `first_index`, `last_index`
+/// and `snapshot`) are called synchronously by raft-rs inside the event loop of
+/// `MultiRaftActor`, so they must be served without blocking, e.g. from memory or
+/// a cache. The write methods may touch the disk or network, so they are async and
+/// the actor awaits them instead of blocking the reactor.
 pub trait RaftStorage: RaftSnapshotBuilder + Clone + Send + Sync + 'static {
     /// `initial_state` is called when Raft is initialized. This interface will return a `RaftState`
     /// which contains `HardState` and `ConfState`.
@@ -162,25 +285,45 @@ pub trait RaftStorage: RaftSnapshotBuilder + Clone + Send + Sync + 'static {
     /// Panics if `high` is higher than `Storage::last_index(&self) + 1`.
     fn entries(&self, low: u64, high: u64, max_size: impl Into<Option<u64>>) -> Result<Vec<Entry>>;
 
+    /// GAT trait for `append_entries`.
+    type AppendEntriesFuture<'life0>: Send + Future<Output = Result<()>>
+    where
+        Self: 'life0;
     /// Append the new entries to storage.
     ///
     /// # Panics
     ///
     /// Panics if `ents` contains compacted entries, or there's a gap between `ents` and the last
     /// received entry in the storage.
-    fn append_entries(&self, entries: &Vec<Entry>) -> Result<()>;
+    fn append_entries(&self, entries: Vec<Entry>) -> Self::AppendEntriesFuture<'_>;
 
+    /// GAT trait for `get_hard_state`.
+    type GetHardStateFuture<'life0>: Send + Future<Output = Result<HardState>>
+    where
+        Self: 'life0;
     /// Get the current HardState.
-    fn get_hard_state(&self) -> Result<HardState>;
+    fn get_hard_state(&self) -> Self::GetHardStateFuture<'_>;
 
+    /// GAT trait for `set_hardstate`.
+    type SetHardStateFuture<'life0>: Send + Future<Output = Result<()>>
+    where
+        Self: 'life0;
     /// Saves the current HardState.
-    fn set_hardstate(&self, hs: HardState) -> Result<()>;
+    fn set_hardstate(&self, hs: HardState) -> Self::SetHardStateFuture<'_>;
 
-    /// Get the current HardState.
-    fn get_confstate(&self) -> Result<ConfState>;
+    /// GAT trait for `get_confstate`.
+    type GetConfStateFuture<'life0>: Send + Future<Output = Result<ConfState>>
+    where
+        Self: 'life0;
+    /// Get the current ConfState.
+    fn get_confstate(&self) -> Self::GetConfStateFuture<'_>;
 
-    /// Saves the current HardState.
-    fn set_confstate(&self, cs: ConfState) -> Result<()>;
+    /// GAT trait for `set_confstate`.
+    type SetConfStateFuture<'life0>: Send + Future<Output = Result<()>>
+    where
+        Self: 'life0;
+    /// Saves the current ConfState.
+    fn set_confstate(&self, cs: ConfState) -> Self::SetConfStateFuture<'_>;
 
     /// Returns the term of entry idx, which must be in the range
     /// [first_index()-1, last_index()]. The term of the entry before
@@ -188,7 +331,12 @@ pub trait RaftStorage: RaftSnapshotBuilder + Clone + Send + Sync + 'static {
     /// rest of that entry may not be available.
     fn term(&self, idx: u64) -> Result<u64>;
 
-    fn set_commit(&self, commit: u64);
+    /// GAT trait for `set_commit`.
+    type SetCommitFuture<'life0>: Send + Future<Output = Result<()>>
+    where
+        Self: 'life0;
+    /// Saves the commit index to the HardState.
+    fn set_commit(&self, commit: u64) -> Self::SetCommitFuture<'_>;
 
     /// Returns the index of the first log entry that is possible available via entries, which will
     /// always equal to `truncated index` plus 1.
@@ -208,29 +356,213 @@ pub trait RaftStorage: RaftSnapshotBuilder + Clone + Send + Sync + 'static {
     /// A snapshot's index must not less than the `request_index`.
     fn snapshot(&self, request_index: u64) -> Result<Snapshot>;
 
+    /// GAT trait for `apply_snapshot`.
+    type ApplySnapshotFuture<'life0>: Send + Future<Output = Result<()>>
+    where
+        Self: 'life0;
     /// install snapshot
-    fn apply_snapshot(&self, snapshot: Snapshot) -> Result<()>;
+    fn apply_snapshot(&self, snapshot: Snapshot) -> Self::ApplySnapshotFuture<'_>;
+
+    /// GAT trait for `compact_to_snapshot`.
+    type CompactToSnapshotFuture<'life0>: Send + Future<Output = Result<()>>
+    where
+        Self: 'life0;
+    /// Save the snapshot built by `build_snapshot` as the latest snapshot and
+    /// discard the log entries before `compact_index`, which is capped at the
+    /// index after the snapshot, e.g. the entries still needed by a lagging
+    /// replica are kept. Unlike `apply_snapshot`, the entries after the index
+    /// and the hard state are kept. Returns `SnapshotOutOfDate` if the index
+    /// is not greater than the latest one.
+    fn compact_to_snapshot(
+        &self,
+        snapshot: Snapshot,
+        compact_index: u64,
+    ) -> Self::CompactToSnapshotFuture<'_>;
+
+    /// GAT trait for `write_ready`.
+    type WriteReadyFuture<'life0>: Send + Future<Output = Result<()>>
+    where
+        Self: 'life0;
+    /// Commit the snapshot, entries and hard state of a `Ready` in one atomic batch,
+    /// either all of them are persisted or none of them, so that a crash can't leave
+    /// the log inconsistent with the hard state.
+    fn write_ready(&self, batch: WriteBatch) -> Self::WriteReadyFuture<'_>;
+
+    /// Returns the stream of the committed log entries from `from_index`, the
+    /// entries are paged through `entries` in bounded batches, so the log is
+    /// never loaded into memory at once. The stream ends at the commit index
+    /// when it is reached, and yields `LogCompacted` if the entries from
+    /// `from_index` have been compacted.
+    fn entry_stream(&self, from_index: u64) -> EntryStream<Self> {
+        EntryStream::new(self.clone(), from_index)
+    }
+}
+
+/// The max number and total size of the entries fetched per batch by `EntryStream`.
+const ENTRY_STREAM_BATCH_LEN: u64 = 64;
+const ENTRY_STREAM_BATCH_SIZE: u64 = 1024 * 1024;
+
+/// EntryStream scans the committed log of a replica, see `RaftStorage::entry_stream`.
+pub struct EntryStream<S: RaftStorage> {
+    storage: S,
+    next_index: u64,
+    batch: VecDeque<Entry>,
+    done: bool,
+}
+
+// the fields are never pinned.
+impl<S: RaftStorage> Unpin for EntryStream<S> {}
+
+impl<S: RaftStorage> EntryStream<S> {
+    pub fn new(storage: S, from_index: u64) -> Self {
+        Self {
+            storage,
+            next_index: from_index,
+            batch: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Fetch the next batch of entries, returns false if the commit index is
+    /// reached.
+    fn fetch(&mut self) -> Result<bool> {
+        let first_index = self.storage.first_index()?;
+        if self.next_index < first_index {
+            return Err(StorageError::LogCompacted(first_index));
+        }
+
+        let commit = std::cmp::min(
+            self.storage.initial_state()?.hard_state.commit,
+            self.storage.last_index()?,
+        );
+        if self.next_index > commit {
+            return Ok(false);
+        }
+
+        let high = std::cmp::min(
+            commit.saturating_add(1),
+            self.next_index.saturating_add(ENTRY_STREAM_BATCH_LEN),
+        );
+        let entries = match self
+            .storage
+            .entries(self.next_index, high, ENTRY_STREAM_BATCH_SIZE)
+        {
+            // the log is compacted concurrently.
+            Err(StorageError::Compacted) => {
+                return Err(StorageError::LogCompacted(self.storage.first_index()?))
+            }
+            res => res?,
+        };
+        self.next_index += entries.len() as u64;
+        self.batch.extend(entries);
+        Ok(!self.batch.is_empty())
+    }
+}
+
+impl<S: RaftStorage> Stream for EntryStream<S> {
+    type Item = Result<Entry>;
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Some(entry) = this.batch.pop_front() {
+            return Poll::Ready(Some(Ok(entry)));
+        }
+        if this.done {
+            return Poll::Ready(None);
+        }
+
+        match this.fetch() {
+            Ok(true) => Poll::Ready(this.batch.pop_front().map(Ok)),
+            Ok(false) => {
+                this.done = true;
+                Poll::Ready(None)
+            }
+            Err(err) => {
+                this.done = true;
+                Poll::Ready(Some(Err(err)))
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct RaftStorageImpl<S: RaftStorage> {
     storage_impl: S,
+    entry_cache: Option<Arc<RwLock<EntryCache>>>,
 }
 
 impl<S: RaftStorage> RaftStorageImpl<S> {
     pub fn new(storage_impl: S) -> Self {
-        Self { storage_impl }
+        Self {
+            storage_impl,
+            entry_cache: None,
+        }
+    }
+
+    /// Put an `EntryCache` bounded by `max_size` bytes in front of the storage,
+    /// the cache is disabled if `max_size` is 0.
+    pub fn with_entry_cache(mut self, max_size: usize) -> Self {
+        if max_size != 0 {
+            self.entry_cache = Some(Arc::new(RwLock::new(EntryCache::new(max_size))));
+        }
+        self
+    }
+
+    /// Discards the cached entries prior to `compact_index`, it should be called
+    /// when the log of storage is compacted.
+    pub fn compact_entry_cache(&self, compact_index: u64) {
+        if let Some(cache) = self.entry_cache.as_ref() {
+            cache.write().unwrap().compact_to(compact_index);
+        }
+    }
+
+    fn update_entry_cache(&self, snapshot_installed: bool, entries: Option<Vec<Entry>>) {
+        if let Some(cache) = self.entry_cache.as_ref() {
+            let mut cache = cache.write().unwrap();
+            if snapshot_installed {
+                cache.clear();
+            }
+            if let Some(entries) = entries {
+                cache.append(entries);
+            }
+        }
+    }
+
+    #[inline]
+    fn entries_to_cache(&self, entries: &Vec<Entry>) -> Option<Vec<Entry>> {
+        match self.entry_cache {
+            Some(_) if !entries.is_empty() => Some(entries.clone()),
+            _ => None,
+        }
     }
 }
 
 impl<S: RaftStorage> RaftStorage for RaftStorageImpl<S> {
+    type AppendEntriesFuture<'life0> = impl Future<Output = Result<()>> + Send + 'life0
+    where
+        Self: 'life0;
     #[inline]
-    fn append_entries(&self, entries: &Vec<Entry>) -> Result<()> {
-        self.storage_impl.append_entries(entries)
+    fn append_entries(&self, entries: Vec<Entry>) -> Self::AppendEntriesFuture<'_> {
+        async move {
+            let cache_entries = self.entries_to_cache(&entries);
+            self.storage_impl.append_entries(entries).await?;
+            self.update_entry_cache(false, cache_entries);
+            Ok(())
+        }
     }
 
     #[inline]
     fn entries(&self, low: u64, high: u64, max_size: impl Into<Option<u64>>) -> Result<Vec<Entry>> {
+        let max_size = max_size.into();
+        if let Some(cache) = self.entry_cache.as_ref() {
+            // the compacted entries are never returned from the cache.
+            if low >= self.storage_impl.first_index()? {
+                if let Some(mut entries) = cache.read().unwrap().entries(low, high) {
+                    limit_entry_size(&mut entries, max_size);
+                    return Ok(entries);
+                }
+            }
+        }
         self.storage_impl.entries(low, high, max_size)
     }
 
@@ -239,8 +571,11 @@ impl<S: RaftStorage> RaftStorage for RaftStorageImpl<S> {
         self.storage_impl.first_index()
     }
 
+    type GetHardStateFuture<'life0> = S::GetHardStateFuture<'life0>
+    where
+        Self: 'life0;
     #[inline]
-    fn get_hard_state(&self) -> Result<HardState> {
+    fn get_hard_state(&self) -> Self::GetHardStateFuture<'_> {
         self.storage_impl.get_hard_state()
     }
 
@@ -254,23 +589,35 @@ impl<S: RaftStorage> RaftStorage for RaftStorageImpl<S> {
         self.storage_impl.last_index()
     }
 
+    type SetCommitFuture<'life0> = S::SetCommitFuture<'life0>
+    where
+        Self: 'life0;
     #[inline]
-    fn set_commit(&self, commit: u64) {
+    fn set_commit(&self, commit: u64) -> Self::SetCommitFuture<'_> {
         self.storage_impl.set_commit(commit)
     }
 
+    type SetHardStateFuture<'life0> = S::SetHardStateFuture<'life0>
+    where
+        Self: 'life0;
     #[inline]
-    fn set_hardstate(&self, hs: HardState) -> Result<()> {
+    fn set_hardstate(&self, hs: HardState) -> Self::SetHardStateFuture<'_> {
         self.storage_impl.set_hardstate(hs)
     }
 
+    type GetConfStateFuture<'life0> = S::GetConfStateFuture<'life0>
+    where
+        Self: 'life0;
     #[inline]
-    fn get_confstate(&self) -> Result<ConfState> {
+    fn get_confstate(&self) -> Self::GetConfStateFuture<'_> {
         self.storage_impl.get_confstate()
     }
 
+    type SetConfStateFuture<'life0> = S::SetConfStateFuture<'life0>
+    where
+        Self: 'life0;
     #[inline]
-    fn set_confstate(&self, cs: ConfState) -> Result<()> {
+    fn set_confstate(&self, cs: ConfState) -> Self::SetConfStateFuture<'_> {
         self.storage_impl.set_confstate(cs)
     }
 
@@ -281,18 +628,69 @@ impl<S: RaftStorage> RaftStorage for RaftStorageImpl<S> {
 
     #[inline]
     fn term(&self, idx: u64) -> Result<u64> {
+        if let Some(cache) = self.entry_cache.as_ref() {
+            if idx >= self.storage_impl.first_index()? {
+                if let Some(term) = cache.read().unwrap().term(idx) {
+                    return Ok(term);
+                }
+            }
+        }
         self.storage_impl.term(idx)
     }
 
+    type ApplySnapshotFuture<'life0> = impl Future<Output = Result<()>> + Send + 'life0
+    where
+        Self: 'life0;
+    #[inline]
+    fn apply_snapshot(&self, snapshot: Snapshot) -> Self::ApplySnapshotFuture<'_> {
+        async move {
+            self.storage_impl.apply_snapshot(snapshot).await?;
+            self.update_entry_cache(true, None);
+            Ok(())
+        }
+    }
+
+    type CompactToSnapshotFuture<'life0> = impl Future<Output = Result<()>> + Send + 'life0
+    where
+        Self: 'life0;
     #[inline]
-    fn apply_snapshot(&self, snapshot: Snapshot) -> Result<()> {
-        self.storage_impl.apply_snapshot(snapshot)
+    fn compact_to_snapshot(
+        &self,
+        snapshot: Snapshot,
+        compact_index: u64,
+    ) -> Self::CompactToSnapshotFuture<'_> {
+        async move {
+            let compact_index = std::cmp::min(
+                compact_index,
+                snapshot.get_metadata().index.saturating_add(1),
+            );
+            self.storage_impl
+                .compact_to_snapshot(snapshot, compact_index)
+                .await?;
+            self.compact_entry_cache(compact_index);
+            Ok(())
+        }
+    }
+
+    type WriteReadyFuture<'life0> = impl Future<Output = Result<()>> + Send + 'life0
+    where
+        Self: 'life0;
+    #[inline]
+    fn write_ready(&self, batch: WriteBatch) -> Self::WriteReadyFuture<'_> {
+        async move {
+            let snapshot_installed = batch.snapshot.is_some();
+            let cache_entries = self.entries_to_cache(&batch.entries);
+            self.storage_impl.write_ready(batch).await?;
+            self.update_entry_cache(snapshot_installed, cache_entries);
+            Ok(())
+        }
     }
 }
 
 impl<S: RaftStorage> RaftSnapshotBuilder for RaftStorageImpl<S> {
-    fn build_snapshot(&self, _applied: u64) -> Result<Snapshot> {
-        unimplemented!()
+    #[inline]
+    fn build_snapshot(&self, applied: u64) -> Result<Snapshot> {
+        self.storage_impl.build_snapshot(applied)
     }
 }
 
@@ -310,15 +708,14 @@ impl<S: RaftStorage> raft::storage::Storage for RaftStorageImpl<S> {
         high: u64,
         max_size: impl Into<Option<u64>>,
     ) -> raft::Result<Vec<raft::prelude::Entry>> {
-        match self.storage_impl.entries(low, high, max_size) {
+        match RaftStorage::entries(self, low, high, max_size) {
             Err(error) => Err(raft::Error::Store(transmute_error(error))),
             Ok(entries) => Ok(transmute_entries(entries)),
         }
     }
 
     fn term(&self, idx: u64) -> raft::Result<u64> {
-        self.storage_impl
-            .term(idx)
+        RaftStorage::term(self, idx)
             .map_err(|error| raft::Error::Store(transmute_error(error)))
     }
 
@@ -408,4 +805,98 @@ pub trait MultiRaftStorage<S: RaftStorage>: Clone + Send + Sync + 'static {
         Self: 'life0;
     // Get the `ReplicaDesc` by `group_id` and `node_id`.
     fn replica_for_node(&self, group_id: u64, node_id: u64) -> Self::ReplicaForNodeFuture<'_>;
+
+    /// GAT trait for `remove_group_storage`.
+    type RemoveGroupStorageFuture<'life0>: Send + Future<Output = Result<()>>
+    where
+        Self: 'life0;
+    /// Delete all persisted state of the group, include log, hard state,
+    /// conf state, snapshot and `RaftGroupDesc`.
+    fn remove_group_storage(&self, group_id: u64) -> Self::RemoveGroupStorageFuture<'_>;
+
+    /// GAT trait for `snapshot_metadata`.
+    type SnapshotMetadataFuture<'life0>: Send + Future<Output = Result<SnapshotMetadata>>
+    where
+        Self: 'life0;
+    /// Returns the metadata of the latest snapshot of the group without reading
+    /// the snapshot data, the metadata of index 0 is returned if the group has
+    /// no snapshot. The metadata must be stored with the data in the same write
+    /// of `apply_snapshot`, so that it is consistent with the stored data.
+    fn snapshot_metadata(&self, group_id: u64) -> Self::SnapshotMetadataFuture<'_>;
+
+    /// GAT trait for `log_bounds`.
+    type LogBoundsFuture<'life0>: Send + Future<Output = Result<LogBounds>>
+    where
+        Self: 'life0;
+    /// Returns the first and last index of the log and the snapshot index of
+    /// the group read together, e.g. the consumer tailing the log whose cursor
+    /// is below `first_index` must catch up by the snapshot first. The default
+    /// bounds are returned if the group has no storage.
+    fn log_bounds(&self, group_id: u64) -> Self::LogBoundsFuture<'_>;
+
+    /// Returns the `SyncPolicy` of `write_ready` of the group storages.
+    fn sync_policy(&self) -> SyncPolicy {
+        SyncPolicy::Always
+    }
+
+    /// Returns the `SyncPolicy` of `write_ready` of the group storage, which
+    /// may differ between the groups, e.g. the metadata group is always synced
+    /// while the bulk data groups are synced in batches. The default is the
+    /// `sync_policy` of all groups.
+    fn group_sync_policy(&self, _group_id: u64) -> SyncPolicy {
+        self.sync_policy()
+    }
+
+    /// Returns the groups whose `SyncPolicy` overrides `sync_policy`, see
+    /// `group_sync_policy`. The default is none.
+    fn group_sync_policies(&self) -> Vec<(u64, SyncPolicy)> {
+        vec![]
+    }
+
+    /// Set the `SyncPolicy` of `write_ready` of the group storage, e.g. when
+    /// the group is created, see `MultiRaft::set_group_sync_policy`. The
+    /// default fails unless the policy is `sync_policy`, which is shared by
+    /// all groups.
+    fn set_group_sync_policy(&self, group_id: u64, policy: SyncPolicy) -> Result<()> {
+        if policy == self.sync_policy() {
+            return Ok(());
+        }
+        Err(StorageError::Other(
+            format!(
+                "the storage doesn't support {:?} of group {}, its policy is {:?}",
+                policy,
+                group_id,
+                self.sync_policy()
+            )
+            .into(),
+        ))
+    }
+
+    /// GAT trait for `sync_writes`.
+    type SyncWritesFuture<'life0>: Send + Future<Output = Result<()>>
+    where
+        Self: 'life0;
+    /// Make the batches written with `defer_sync` by the group storages
+    /// durable according to the `SyncPolicy`, e.g. by one fsync covering the
+    /// batches of all groups. It's also called once per tick pass, so that the
+    /// trailing batches skipped by `SyncPolicy::Periodic` are synced once the
+    /// period elapses even if nothing is written after them. It's awaited by
+    /// the actor of the node, so the fsync must not block the calling thread.
+    /// The storage that ignores `defer_sync` and `Periodic` returns at once.
+    fn sync_writes(&self) -> Self::SyncWritesFuture<'_>;
+}
+
+#[test]
+fn test_sync_policy_need_sync() {
+    let clock = MockClock::new();
+    let last_sync = Mutex::new(clock.now());
+    assert!(SyncPolicy::Always.need_sync(&last_sync, &clock));
+    assert!(!SyncPolicy::Never.need_sync(&last_sync, &clock));
+
+    let periodic = SyncPolicy::Periodic(Duration::from_secs(60));
+    assert!(!periodic.need_sync(&last_sync, &clock));
+    clock.advance(Duration::from_secs(61));
+    assert!(periodic.need_sync(&last_sync, &clock));
+    // the batches within the period after the fsync are not fsynced.
+    assert!(!periodic.need_sync(&last_sync, &clock));
 }
@@ -0,0 +1,351 @@
+//! The cluster of multiraft nodes over the local transport and the memory
+//! storage, which is shared by the integration tests and the benchmarks.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::time::Duration;
+use std::time::Instant;
+
+use smol_raft::multiraft::Event;
+use smol_raft::multiraft::GroupId;
+#[cfg(feature = "test-util")]
+use smol_raft::multiraft::GroupStatus;
+use smol_raft::multiraft::MultiRaftExtensions;
+use smol_raft::multiraft::NodeId;
+use smol_raft::multiraft::StoreId;
+use smol_raft::proto::ReplicaDesc;
+use smol_raft::storage::MemStorage;
+use smol_raft::storage::MultiRaftMemoryStorage;
+use smol_raft::LocalTransport;
+use smol_raft::MultiRaft;
+use smol_raft::MultiRaftConfig;
+use smol_raft::MultiRaftMessageSender;
+
+use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::watch;
+
+pub type FixtureMultiRaft = MultiRaft<
+    MultiRaftMessageSender,
+    LocalTransport<MultiRaftMessageSender>,
+    MemStorage,
+    MultiRaftMemoryStorage,
+>;
+
+pub struct FixtureCluster {
+    pub transport: LocalTransport<MultiRaftMessageSender>,
+    pub storages: Vec<MultiRaftMemoryStorage>,
+    pub multirafts: Vec<FixtureMultiRaft>,
+    pub events: Vec<Receiver<Vec<Event>>>,
+    pub groups: HashMap<u64, Vec<u64>>, // track group which nodes, group_id -> nodes
+}
+
+impl FixtureCluster {
+    pub async fn make(num: u64, stop: watch::Receiver<bool>) -> FixtureCluster {
+        let config = MultiRaftConfig {
+            election_tick: 2,
+            heartbeat_tick: 1,
+            tick_interval: 1000,
+            ..Default::default()
+        };
+        FixtureCluster::make_with_config(num, config, stop).await
+    }
+
+    /// Make the 3-replica group on a 3-node cluster, returns the cluster and
+    /// the leader id. The apply events are acked.
+    pub async fn make_acked_group(
+        group_id: u64,
+        stop: watch::Receiver<bool>,
+    ) -> (FixtureCluster, u64) {
+        let mut cluster = FixtureCluster::make_with_manual_tick(3, stop).await;
+        cluster.make_group(group_id, 0, 3).await;
+        let leader_id = cluster
+            .tick_until_leader(group_id, &[0, 1, 2])
+            .await
+            .unwrap();
+        cluster.ack_applies();
+        (cluster, leader_id)
+    }
+
+    pub async fn make_with_config(
+        num: u64,
+        config: MultiRaftConfig,
+        stop: watch::Receiver<bool>,
+    ) -> FixtureCluster {
+        FixtureCluster::make_with_extensions(num, config, vec![], stop).await
+    }
+
+    /// Make the cluster, the node i is injected with `extensions[i]` if any.
+    pub async fn make_with_extensions(
+        num: u64,
+        config: MultiRaftConfig,
+        extensions: Vec<MultiRaftExtensions>,
+        stop: watch::Receiver<bool>,
+    ) -> FixtureCluster {
+        let configs = vec![config; num as usize];
+        FixtureCluster::make_with_configs(configs, extensions, stop).await
+    }
+
+    /// Make the cluster of `configs.len()` nodes, the node i is configured by
+    /// `configs[i]` and injected with `extensions[i]` if any.
+    pub async fn make_with_configs(
+        configs: Vec<MultiRaftConfig>,
+        extensions: Vec<MultiRaftExtensions>,
+        stop: watch::Receiver<bool>,
+    ) -> FixtureCluster {
+        let mut multirafts = vec![];
+        let mut storages = vec![];
+        let mut events = vec![];
+        // all nodes share the same local transport.
+        let transport = LocalTransport::new();
+        for (n, config) in configs.into_iter().enumerate() {
+            let n = n as u64;
+            let node_id = n + 1;
+            let store_id = n + 1;
+
+            let (event_tx, event_rx) = channel(1);
+            let storage = MultiRaftMemoryStorage::new(node_id, store_id);
+            storages.push(storage.clone());
+            let multiraft = FixtureMultiRaft::new_with_extensions(
+                config,
+                NodeId(node_id),
+                StoreId(store_id),
+                transport.clone(),
+                storage,
+                stop.clone(),
+                event_tx,
+                extensions.get(n as usize).cloned().unwrap_or_default(),
+            );
+            transport
+                .listen(node_id, &format!("local://{}", node_id), multiraft.message_sender())
+                .await
+                .unwrap();
+            multirafts.push(multiraft);
+            events.push(event_rx);
+        }
+        Self {
+            transport,
+            events,
+            storages,
+            multirafts,
+            groups: HashMap::new(),
+        }
+    }
+
+    pub async fn make_group(&mut self, group_id: u64, first_node: u64, replica_num: usize) {
+        self.make_group_with_campaign(group_id, first_node, replica_num, false)
+            .await
+    }
+
+    pub async fn make_group_with_campaign(
+        &mut self,
+        group_id: u64,
+        first_node: u64,
+        replica_num: usize,
+        campaign: bool,
+    ) {
+        for i in 0..replica_num {
+            self.make_group_replica(group_id, first_node, replica_num, i, campaign)
+                .await;
+        }
+    }
+
+    /// Initialize the `i`-th replica of the group whose `replica_num` replicas
+    /// are located on the nodes from `first_node`.
+    pub async fn make_group_replica(
+        &mut self,
+        group_id: u64,
+        first_node: u64,
+        replica_num: usize,
+        i: usize,
+        campaign: bool,
+    ) {
+        let mut replicas = vec![];
+        for n in 0..replica_num {
+            // the node id of the node at index `n` is `n + 1`.
+            replicas.push(ReplicaDesc {
+                node_id: first_node + n as u64 + 1,
+                replica_id: (n + 1) as u64,
+            });
+        }
+
+        let node_index = first_node as usize + i;
+        self.multirafts[node_index]
+            .bootstrap_group(GroupId(group_id), replicas, campaign)
+            .await
+            .unwrap();
+
+        match self.groups.get_mut(&group_id) {
+            None => {
+                self.groups.insert(group_id, vec![node_index as u64]);
+            }
+            Some(nodes) => nodes.push(node_index as u64),
+        };
+    }
+
+    /// Take the events of all nodes and ack the apply events in the
+    /// background, the other events are dropped.
+    pub fn ack_applies(&mut self) {
+        for events in std::mem::take(&mut self.events) {
+            spawn_ack_applies(events);
+        }
+    }
+
+    /// Wait until the replicas of the group agree on the same leader and the
+    /// same commit index, returns the leader replica id. The nodes are ticked
+    /// in the loop if the `test-util` feature is enabled, for the clusters in
+    /// the manual tick mode. The
+    /// error lists the status of each replica if the group doesn't converge
+    /// within `timeout`. The events not taken by the test are drained, so that
+    /// the actors aren't blocked by the full event channels.
+    pub async fn wait_converged(
+        &mut self,
+        group_id: u64,
+        timeout: Duration,
+    ) -> Result<u64, String> {
+        let nodes = self.groups.get(&group_id).cloned().unwrap_or_default();
+        let deadline = Instant::now() + timeout;
+        loop {
+            for events in self.events.iter_mut() {
+                while events.try_recv().is_ok() {}
+            }
+            let mut states = vec![];
+            for node_index in nodes.iter() {
+                let status = self.multirafts[*node_index as usize]
+                    .group_status(GroupId(group_id))
+                    .await
+                    .map(|status| (status.leader_id.0, status.commit_index));
+                states.push((*node_index, status));
+            }
+            match states.first() {
+                Some((_, Some((leader_id, commit))))
+                    if *leader_id != 0
+                        && states
+                            .iter()
+                            .all(|(_, state)| *state == Some((*leader_id, *commit))) =>
+                {
+                    return Ok(*leader_id);
+                }
+                _ => {}
+            }
+
+            if Instant::now() >= deadline {
+                let replicas = states
+                    .iter()
+                    .map(|(node_index, state)| match state {
+                        None => format!("node {}: no replica", node_index + 1),
+                        Some((leader_id, commit)) => format!(
+                            "node {}: leader {}, commit {}",
+                            node_index + 1,
+                            leader_id,
+                            commit
+                        ),
+                    })
+                    .collect::<Vec<_>>();
+                return Err(format!(
+                    "group {} isn't converged within {:?}, {}",
+                    group_id,
+                    timeout,
+                    replicas.join("; ")
+                ));
+            }
+            #[cfg(feature = "test-util")]
+            self.tick_all().await;
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+/// Ack the apply events of `events` in the background, so that the apply
+/// actor isn't blocked by the pending applies.
+pub fn spawn_ack_applies(mut events: Receiver<Vec<Event>>) {
+    tokio::spawn(async move {
+        while let Some(events) = events.recv().await {
+            for event in events {
+                if let Event::Apply(apply) = event {
+                    if let Some(tx) = apply.tx {
+                        let _ = tx.send(Ok(()));
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(feature = "test-util")]
+impl FixtureCluster {
+    pub async fn make_with_manual_tick(num: u64, stop: watch::Receiver<bool>) -> FixtureCluster {
+        let config = MultiRaftConfig {
+            election_tick: 2,
+            heartbeat_tick: 1,
+            manual_tick: true,
+            ..Default::default()
+        };
+        FixtureCluster::make_with_config(num, config, stop).await
+    }
+
+    /// Tick all nodes until every node in `nodes` knows the same leader
+    /// of the group, returns the leader replica id.
+    pub async fn tick_until_leader(&mut self, group_id: u64, nodes: &[usize]) -> Option<u64> {
+        let mut leaders = HashMap::new();
+        for _ in 0..100 {
+            self.tick_all().await;
+            tokio::task::yield_now().await;
+            self.drain_leaders(group_id, &mut leaders);
+            let known = nodes
+                .iter()
+                .filter_map(|node_index| leaders.get(node_index))
+                .collect::<Vec<_>>();
+            if known.len() == nodes.len() && known.iter().all(|id| **id == *known[0]) {
+                return Some(*known[0]);
+            }
+        }
+        None
+    }
+
+    /// Tick the cluster until the status of the group on the node
+    /// `node_index` satisfies `cond`, returns the last status.
+    pub async fn tick_until_status<F>(
+        &self,
+        group_id: u64,
+        node_index: usize,
+        cond: F,
+    ) -> GroupStatus
+    where
+        F: Fn(&GroupStatus) -> bool,
+    {
+        let multiraft = &self.multirafts[node_index];
+        for _ in 0..100 {
+            let status = multiraft.group_status(GroupId(group_id)).await.unwrap();
+            if cond(&status) {
+                return status;
+            }
+            self.tick_all().await;
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        multiraft.group_status(GroupId(group_id)).await.unwrap()
+    }
+
+    /// Advance one tick for all nodes of the cluster.
+    pub async fn tick_all(&self) {
+        for multiraft in self.multirafts.iter() {
+            multiraft.tick().await;
+        }
+    }
+
+    /// Drain the events of all nodes and returns the last leader of group
+    /// seen by each node.
+    pub fn drain_leaders(&mut self, group_id: u64, leaders: &mut HashMap<usize, u64>) {
+        for (node_index, events) in self.events.iter_mut().enumerate() {
+            while let Ok(events) = events.try_recv() {
+                for event in events {
+                    if let Event::LederElection(election) = event {
+                        if election.group_id == group_id {
+                            leaders.insert(node_index, election.leader_id);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,177 +1,7067 @@
 use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
 
+use futures::StreamExt;
+use raft::ProgressState;
+use raft::StateRole;
+use smol_raft::multiraft::ApplyError;
+use smol_raft::multiraft::ApplyEvent;
+use smol_raft::multiraft::ApplyHandle;
+use smol_raft::multiraft::ApplyOutput;
+use smol_raft::multiraft::AutoPromotePolicy;
+use smol_raft::multiraft::Clock;
+use smol_raft::multiraft::Command;
+use smol_raft::multiraft::DropReason;
+use smol_raft::multiraft::DroppedMessage;
+use smol_raft::multiraft::DroppedMessageObserver;
+use smol_raft::multiraft::entry;
+use smol_raft::multiraft::entry::EntryPayload;
+use smol_raft::multiraft::Error;
 use smol_raft::multiraft::Event;
-use smol_raft::multiraft::LeaderElectionEvent;
+use smol_raft::multiraft::FilterAction;
+use smol_raft::multiraft::GroupState;
+use smol_raft::multiraft::GroupId;
+use smol_raft::multiraft::LeaderHook;
+use smol_raft::multiraft::MailboxDepth;
+use smol_raft::multiraft::MemNodeResolver;
+use smol_raft::multiraft::MockClock;
+use smol_raft::multiraft::MultiRaftExtensions;
+use smol_raft::multiraft::NodeAddress;
+use smol_raft::multiraft::NodeId;
+use smol_raft::multiraft::NodeResolver;
+use smol_raft::multiraft::ProposalError;
+use smol_raft::multiraft::ReadyHook;
+use smol_raft::multiraft::ReadyStage;
+use smol_raft::multiraft::ReplicaId;
+use smol_raft::multiraft::ReplicaPlacer;
+use smol_raft::multiraft::ReplicaRole;
+use smol_raft::multiraft::RetryPolicy;
+use smol_raft::multiraft::SimNetwork;
+use smol_raft::multiraft::StateMachine;
+use smol_raft::multiraft::TransferLeaderPolicy;
+use smol_raft::multiraft::Transport;
+use smol_raft::multiraft::UnhealthyReason;
+use smol_raft::multiraft::value_hash;
+use smol_raft::proto::AppReadIndexRequest;
+use smol_raft::proto::AppWriteRequest;
+use smol_raft::proto::ConfChangeTransition;
+use smol_raft::proto::ConfChangeV2;
+use smol_raft::proto::ConfChangeType;
 use smol_raft::proto::ConfState;
+use smol_raft::proto::EntryType;
 use smol_raft::proto::HardState;
+use smol_raft::proto::MembershipChangeData;
+use smol_raft::proto::MembershipChangeRequest;
+use smol_raft::proto::Precondition;
 use smol_raft::proto::RaftGroupManagementMessage;
 use smol_raft::proto::RaftGroupManagementMessageType;
-use smol_raft::proto::ReplicaMetadata;
+use smol_raft::proto::RaftMessage;
+use smol_raft::proto::ReplicaDesc;
 use smol_raft::proto::Snapshot;
-use smol_raft::storage::MemStorage;
-use smol_raft::storage::MultiRaftMemoryStorage;
+use smol_raft::proto::SnapshotData;
 use smol_raft::storage::MultiRaftStorage;
 use smol_raft::storage::RaftStorage;
-use smol_raft::LocalTransport;
 use smol_raft::MultiRaft;
 use smol_raft::MultiRaftConfig;
-use smol_raft::MultiRaftMessageSender;
 
-use tokio::sync::mpsc::channel;
-use tokio::sync::mpsc::Receiver;
+use tokio::sync::oneshot;
 use tokio::sync::watch;
 
-type FixtureMultiRaft = MultiRaft<
-    MultiRaftMessageSender,
-    LocalTransport<MultiRaftMessageSender>,
-    MemStorage,
-    MultiRaftMemoryStorage,
->;
+mod fixture;
 
-pub struct FixtureCluster {
-    storages: Vec<MultiRaftMemoryStorage>,
-    multirafts: Vec<FixtureMultiRaft>,
-    events: Vec<Receiver<Vec<Event>>>,
-    groups: HashMap<u64, Vec<u64>>, // track group which nodes, group_id -> nodes
-}
+use fixture::spawn_ack_applies;
+use fixture::FixtureCluster;
 
 impl FixtureCluster {
-    pub fn make(num: u64, stop: watch::Receiver<bool>) -> FixtureCluster {
-        let mut multirafts = vec![];
-        let mut storages = vec![];
-        let mut events = vec![];
-        for n in 0..num {
-            let node_id = n + 1;
-            let store_id = n + 1;
-            let config = MultiRaftConfig {
-                election_tick: 2,
-                heartbeat_tick: 1,
-                tick_interval: 1000,
+    pub async fn check_elect(&mut self, node_index: u64, group_id: u64) {
+        // trigger an election for the replica in the group of the node where leader nodes.
+        self.trigger_elect(node_index, group_id).await;
+
+        let storage = &self.storages[node_index as usize];
+        let replica = storage
+            .replica_for_node(group_id, node_index + 1)
+            .await
+            .unwrap()
+            .unwrap();
+        let leader_id = self.wait_for_leader_elect(group_id).await;
+        assert_eq!(leader_id, replica.replica_id);
+        for node_index in self.groups.get(&group_id).unwrap().iter() {
+            let status = self.multirafts[*node_index as usize]
+                .group_status(GroupId(group_id))
+                .await
+                .unwrap();
+            assert_eq!(status.leader_id, ReplicaId(leader_id));
+        }
+    }
+
+    async fn trigger_elect(&self, node_index: u64, group_id: u64) {
+        self.multirafts[node_index as usize]
+            .campagin(GroupId(group_id))
+            .await
+            .unwrap()
+    }
+
+    /// Wait until all replicas of the group know the same leader and commit
+    /// index, returns the leader replica id.
+    async fn wait_for_leader_elect(&mut self, group_id: u64) -> u64 {
+        self.wait_converged(group_id, Duration::from_secs(10))
+            .await
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_initial_leader_elect() {
+    for leader_id in 0..3 {
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let mut cluster = FixtureCluster::make(3, stop_rx).await;
+        let group_id = 1;
+        cluster.make_group(group_id, 0, 3).await;
+
+        cluster.check_elect(leader_id, group_id).await;
+        let _ = stop_tx.send(true);
+    }
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_group_counters_reflect_election() {
+    for leader_index in 0..3 {
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+        let group_id = 1;
+        cluster.make_group(group_id, 0, 3).await;
+
+        // tick until the counters reflect the election, the votes are
+        // stepped and the readies are handled.
+        let candidate = &cluster.multirafts[leader_index];
+        candidate.campagin(GroupId(group_id)).await.unwrap();
+        let mut leaders = HashMap::new();
+        for _ in 0..100 {
+            cluster.tick_all().await;
+            tokio::task::yield_now().await;
+            cluster.drain_leaders(group_id, &mut leaders);
+            let counters = cluster.multirafts[leader_index]
+                .group_counters(GroupId(group_id))
+                .await
+                .unwrap();
+            if counters.steps > 0 && counters.readies > 0 && leaders.len() == 3 {
+                break;
+            }
+        }
+
+        let counters = cluster.multirafts[leader_index]
+            .group_counters(GroupId(group_id))
+            .await
+            .unwrap();
+        assert!(counters.ticks > 0);
+        assert!(counters.steps > 0);
+        assert!(counters.readies > 0);
+        let status = cluster.multirafts[leader_index]
+            .group_status(GroupId(group_id))
+            .await
+            .unwrap();
+        assert_eq!(status.role, raft::StateRole::Leader);
+        for node_index in 0..3 {
+            assert_eq!(leaders.get(&node_index), Some(&status.replica_id.0));
+        }
+        let _ = stop_tx.send(true);
+    }
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_initial_leader_elect_manual_tick() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+
+    // tick until election timeout and all nodes know the leader.
+    let leader_id = cluster
+        .wait_converged(group_id, Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert_ne!(leader_id, 0);
+    let status = cluster.multirafts[leader_id as usize - 1]
+        .group_status(GroupId(group_id))
+        .await
+        .unwrap();
+    assert_eq!(status.role, StateRole::Leader);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_leader_partition_elect_new_leader() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+
+    // replica id i + 1 is located at node index i and node id i + 1.
+    let leader_node_index = (leader_id - 1) as usize;
+    cluster.transport.isolate(leader_id);
+
+    let majority = (0..3)
+        .filter(|node_index| *node_index != leader_node_index)
+        .collect::<Vec<_>>();
+    let new_leader_id = cluster
+        .tick_until_leader(group_id, &majority)
+        .await
+        .unwrap();
+    assert_ne!(new_leader_id, leader_id);
+
+    // the partitioned leader doesn't agree with the majority.
+    let err = cluster
+        .wait_converged(group_id, Duration::from_millis(200))
+        .await
+        .unwrap_err();
+    assert!(
+        err.contains(&format!("node {}: leader {}", leader_id, leader_id)),
+        "{}",
+        err
+    );
+
+    // the old leader steps down once it's reconnected.
+    cluster.transport.reconnect(leader_id);
+    let converged = cluster
+        .wait_converged(group_id, Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert_eq!(converged, new_leader_id);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_partition_by_multiraft_transport() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+
+    // the transport of the multiraft is the one shared by the cluster.
+    let leader_node_index = (leader_id - 1) as usize;
+    cluster.multirafts[leader_node_index]
+        .transport()
+        .isolate(leader_id);
+    let majority = (0..3)
+        .filter(|node_index| *node_index != leader_node_index)
+        .collect::<Vec<_>>();
+    let new_leader_id = cluster
+        .tick_until_leader(group_id, &majority)
+        .await
+        .unwrap();
+    assert_ne!(new_leader_id, leader_id);
+
+    cluster.multirafts[majority[0]]
+        .transport()
+        .reconnect(leader_id);
+    let converged = cluster
+        .wait_converged(group_id, Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert_eq!(converged, new_leader_id);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_local_transport_stats() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let cluster = FixtureCluster::make_with_manual_tick(2, stop_rx).await;
+    let transport = &cluster.transport;
+    transport.reset_stats();
+
+    // the coalesced heartbeat without groups is received and ignored.
+    let heartbeat = || {
+        let mut msg = smol_raft::proto::Message::default();
+        msg.set_msg_type(smol_raft::proto::MessageType::MsgHeartbeat);
+        RaftMessage {
+            from_node: 1,
+            to_node: 2,
+            msg: Some(msg),
+            ..Default::default()
+        }
+    };
+    let msg_type = Some(smol_raft::proto::MessageType::MsgHeartbeat);
+    let bytes = prost::Message::encoded_len(&heartbeat()) as u64;
+    for _ in 0..10 {
+        transport.send(heartbeat()).unwrap();
+    }
+    let timeout = Instant::now() + Duration::from_secs(5);
+    while transport.stats().received.get(&msg_type) != Some(&10) {
+        assert!(Instant::now() < timeout, "{:?}", transport.stats());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let stats = transport.stats();
+    assert_eq!(stats.sent.get(&msg_type), Some(&10));
+    assert_eq!(stats.bytes_sent.get(&2), Some(&(10 * bytes)));
+    assert_eq!(stats.bytes_received.get(&2), Some(&(10 * bytes)));
+    assert_eq!((stats.send_errors, stats.drops), (0, 0));
+
+    // the messages dropped by the partition are sent but not received.
+    transport.isolate(2);
+    for _ in 0..3 {
+        transport.send(heartbeat()).unwrap();
+    }
+    transport.reconnect(2);
+    let stats = transport.stats();
+    assert_eq!(stats.sent.get(&msg_type), Some(&13));
+    assert_eq!(stats.received.get(&msg_type), Some(&10));
+    assert_eq!(stats.drops, 3);
+
+    // the messages failed by the filter are the send errors.
+    transport.set_filter(|_| FilterAction::Fail);
+    for _ in 0..2 {
+        assert!(transport.send(heartbeat()).is_err());
+    }
+    transport.clear_filter();
+    let stats = transport.stats();
+    assert_eq!(stats.sent.get(&msg_type), Some(&13));
+    assert_eq!(stats.send_errors, 2);
+
+    transport.reset_stats();
+    let stats = transport.stats();
+    assert_eq!(stats.sent.get(&msg_type), None);
+    assert_eq!((stats.send_errors, stats.drops), (0, 0));
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_single_voter_campaign_on_initial() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(1, stop_rx).await;
+    let group_id = 1;
+    cluster
+        .make_group_with_campaign(group_id, 0, 1, true)
+        .await;
+
+    // the only voter becomes leader within one tick.
+    cluster.tick_all().await;
+    tokio::task::yield_now().await;
+    let mut leaders = HashMap::new();
+    cluster.drain_leaders(group_id, &mut leaders);
+    assert_eq!(leaders.get(&0), Some(&1));
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_follower_read_bounded_staleness() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    let follower_index = (0..3)
+        .find(|node_index| *node_index as u64 + 1 != leader_id)
+        .unwrap();
+    let follower = &cluster.multirafts[follower_index];
+
+    let state = follower
+        .read_follower(GroupId(group_id), Duration::from_secs(60))
+        .await
+        .unwrap();
+    assert_eq!(state.group_id, GroupId(group_id));
+
+    // no tick, so there is no more heartbeat from the leader.
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(follower
+        .read_follower(GroupId(group_id), Duration::from_millis(10))
+        .await
+        .is_err());
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_follower_read_staleness_mock_clock() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let clock = MockClock::new();
+    let extensions = (0..3)
+        .map(|_| MultiRaftExtensions {
+            clock: Some(Arc::new(clock.clone())),
+            ..Default::default()
+        })
+        .collect();
+    let mut cluster = FixtureCluster::make_with_extensions(3, config, extensions, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    let follower_index = (0..3)
+        .find(|node_index| *node_index as u64 + 1 != leader_id)
+        .unwrap();
+    let follower = &cluster.multirafts[follower_index];
+    let max_staleness = Duration::from_secs(10);
+
+    // no tick, so there is no more heartbeat from the leader once the ones
+    // inflight are received. The time doesn't move without the clock advanced,
+    // however long it takes.
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    clock.advance(max_staleness);
+    assert!(follower
+        .read_follower(GroupId(group_id), max_staleness)
+        .await
+        .is_ok());
+
+    // the contact with the leader expires exactly after the staleness.
+    clock.advance(Duration::from_millis(1));
+    assert_eq!(
+        follower
+            .read_follower(GroupId(group_id), max_staleness)
+            .await,
+        Err(Error::TooStale(group_id, follower_index as u64 + 1))
+    );
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_leader_of_confirmed_by_heartbeats() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let clock = MockClock::new();
+    let extensions = (0..3)
+        .map(|_| MultiRaftExtensions {
+            clock: Some(Arc::new(clock.clone())),
+            ..Default::default()
+        })
+        .collect();
+    let mut cluster = FixtureCluster::make_with_extensions(3, config, extensions, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+
+    // there is no leader before the election.
+    for multiraft in cluster.multirafts.iter() {
+        assert_eq!(multiraft.leader_of(GroupId(group_id)).await, None);
+    }
+
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    for multiraft in cluster.multirafts.iter() {
+        let (leader, _) = multiraft.leader_of(GroupId(group_id)).await.unwrap();
+        assert_eq!(leader.replica_id, leader_id);
+    }
+
+    // the heartbeats of the tick confirm the leadership at the advanced time
+    // on all replicas, the leader is confirmed by the heartbeat responses.
+    for _ in 0..3 {
+        clock.advance(Duration::from_secs(1));
+        let now = clock.now();
+        cluster.tick_all().await;
+        for multiraft in cluster.multirafts.iter() {
+            loop {
+                let (leader, confirmed) = multiraft.leader_of(GroupId(group_id)).await.unwrap();
+                assert_eq!(leader.replica_id, leader_id);
+                if confirmed >= now {
+                    assert_eq!(confirmed, now);
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    }
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_observer_never_campaigns() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let observer_id = 3;
+    let configs = (1..=3)
+        .map(|node_id| MultiRaftConfig {
+            election_tick: 2,
+            heartbeat_tick: 1,
+            manual_tick: true,
+            observer: node_id == observer_id,
+            ..Default::default()
+        })
+        .collect();
+    let mut cluster = FixtureCluster::make_with_configs(configs, vec![], stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    assert_ne!(leader_id, observer_id);
+
+    // the observer doesn't start an election even if it's isolated from the
+    // leader for many election timeouts, or it's asked to campaign.
+    let observer = &cluster.multirafts[observer_id as usize - 1];
+    cluster.transport.isolate(observer_id);
+    observer.campagin(GroupId(group_id)).await.unwrap();
+    for _ in 0..20 {
+        cluster.tick_all().await;
+        tokio::task::yield_now().await;
+        let status = observer.group_status(GroupId(group_id)).await.unwrap();
+        assert_eq!(status.role, StateRole::Follower);
+        assert_eq!(status.leader_id.0, leader_id);
+    }
+
+    // the leadership isn't transferred to the observer.
+    cluster.transport.reconnect(observer_id);
+    let _ = cluster.multirafts[leader_id as usize - 1]
+        .transfer_leader(GroupId(group_id), ReplicaId(observer_id))
+        .await;
+    for _ in 0..10 {
+        cluster.tick_all().await;
+        tokio::task::yield_now().await;
+        let status = observer.group_status(GroupId(group_id)).await.unwrap();
+        assert_eq!(status.role, StateRole::Follower);
+    }
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_election_tick_range_elect() {
+    for (min_election_tick, max_election_tick) in [(2, 3), (4, 8)] {
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let config = MultiRaftConfig {
+            election_tick: 2,
+            heartbeat_tick: 1,
+            min_election_tick,
+            max_election_tick,
+            manual_tick: true,
+            ..Default::default()
+        };
+        let mut cluster = FixtureCluster::make_with_config(3, config, stop_rx).await;
+        cluster.make_group(1, 0, 3).await;
+
+        // the group elects the leader instead of perpetual split votes.
+        assert!(cluster.tick_until_leader(1, &[0, 1, 2]).await.is_some());
+        let _ = stop_tx.send(true);
+    }
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_health_leaderless_group() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    cluster.tick_until_leader(group_id, &[0, 1, 2]).await.unwrap();
+
+    let health = cluster.multirafts[0].health().await;
+    assert!(health.is_healthy());
+    assert_eq!(health.group_count, 1);
+    assert_eq!(health.leader_known_count, 1);
+
+    // the group without quorum has no leader beyond the election timeout.
+    let group_id = 2;
+    cluster.make_group(group_id, 0, 3).await;
+    for node_id in 1..=3 {
+        cluster.transport.isolate(node_id);
+    }
+    let mut leaders = HashMap::new();
+    for _ in 0..10 {
+        cluster.tick_all().await;
+        tokio::task::yield_now().await;
+        cluster.drain_leaders(group_id, &mut leaders);
+    }
+
+    let health = cluster.multirafts[0].health().await;
+    assert!(health.actor_running);
+    assert!(!health.is_healthy());
+    assert!(health.stuck_groups.contains(&group_id));
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_group_unhealthy_and_recovered_event() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        group_unhealthy_multiple: 2,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(3, config, stop_rx).await;
+    let group_id = 1;
+    for node_id in 1..=3 {
+        cluster.transport.isolate(node_id);
+    }
+    cluster.make_group(group_id, 0, 3).await;
+
+    let mut unhealthy = false;
+    for _ in 0..20 {
+        cluster.tick_all().await;
+        tokio::task::yield_now().await;
+        // drain the events of all nodes, the full event channel blocks the actor.
+        for events in cluster.events.iter_mut() {
+            while let Ok(events) = events.try_recv() {
+                for event in events {
+                    if let Event::GroupUnhealthy(event) = event {
+                        assert_eq!(event.group_id, group_id);
+                        assert_eq!(event.reason, UnhealthyReason::Leaderless);
+                        unhealthy = true;
+                    }
+                }
+            }
+        }
+    }
+    assert!(unhealthy);
+
+    for node_id in 1..=3 {
+        cluster.transport.reconnect(node_id);
+    }
+    let mut recovered = false;
+    for _ in 0..100 {
+        cluster.tick_all().await;
+        tokio::task::yield_now().await;
+        for events in cluster.events.iter_mut() {
+            while let Ok(events) = events.try_recv() {
+                for event in events {
+                    if let Event::GroupRecovered(event) = event {
+                        assert_eq!(event.group_id, group_id);
+                        recovered = true;
+                    }
+                }
+            }
+        }
+        if recovered {
+            break;
+        }
+    }
+    assert!(recovered);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_hot_group_not_starve_ticks() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    // the state machine acks the applied entries, the events are drained so
+    // that the actors are not blocked.
+    cluster.ack_applies();
+
+    let idle_groups = 100;
+    for group_id in 1..=idle_groups + 1 {
+        // only the first replica campaigns, so the votes aren't split.
+        for i in 0..3 {
+            cluster.make_group_replica(group_id, 0, 3, i, i == 0).await;
+        }
+    }
+    let mut terms = HashMap::new();
+    for group_id in 2..=idle_groups + 1 {
+        let status = cluster
+            .tick_until_status(group_id, 0, |status| status.leader_id.0 == 1)
+            .await;
+        terms.insert(group_id, status.term);
+    }
+
+    let multiraft = &cluster.multirafts[0];
+    let hot = async {
+        for _ in 0..100000 {
+            let request = AppWriteRequest {
+                group_id: 1,
+                data: vec![0; 64 * 1024],
+                ..Default::default()
             };
+            let _ = multiraft.write(request).await;
+        }
+    };
+    // the followers of the idle groups campaign if the heartbeats of the
+    // leader are starved by the hot group.
+    let ticks = async {
+        for _ in 0..100 {
+            tokio::time::timeout(Duration::from_secs(1), cluster.tick_all())
+                .await
+                .expect("the tick is starved by the hot group");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::select! {
+        _ = hot => {},
+        _ = ticks => {},
+    }
 
-            let (event_tx, event_rx) = channel(1);
-            let transport = LocalTransport::new();
-            let storage = MultiRaftMemoryStorage::new(node_id, store_id);
-            storages.push(storage.clone());
-            let multiraft = FixtureMultiRaft::new(
-                config,
-                node_id,
-                store_id,
-                transport,
-                storage,
-                stop.clone(),
-                event_tx,
+    // the idle groups keep their leaders without any election.
+    for node_index in 0..3 {
+        for (group_id, term) in terms.iter() {
+            let status = cluster.multirafts[node_index]
+                .group_status(GroupId(*group_id))
+                .await
+                .unwrap();
+            assert_eq!(
+                (status.leader_id.0, status.term),
+                (1, *term),
+                "group {}",
+                group_id
             );
-            multirafts.push(multiraft);
-            events.push(event_rx);
-        }
-        Self {
-            events,
-            storages,
-            multirafts,
-            groups: HashMap::new(),
-        }
-    }
-
-    pub async fn make_group(&mut self, group_id: u64, first_node: u64, replica_num: usize) {
-        let mut voters = vec![];
-        let mut replicas = vec![];
-        for i in 0..replica_num {
-            let replica_id = (i + 1) as u64;
-            let node_id = first_node + i as u64;
-            voters.push(replica_id);
-            replicas.push(ReplicaMetadata {
-                node_id,
-                replica_id,
-                store_id: 0,
-            });
         }
+    }
+    let _ = stop_tx.send(true);
+}
 
-        for i in 0..replica_num {
-            let node_index = first_node as usize + i;
-            let replica_id = (i + 1) as u64;
-            let storage = &self.storages[node_index];
-            let gs = storage.group_storage(group_id, replica_id).await.unwrap();
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_transfer_leader_to_lagging_follower() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    let leader_index = (leader_id - 1) as usize;
+    let transferee = (1..=3).find(|id| *id != leader_id).unwrap();
 
-            // init hardstate
-            let mut hs = HardState::default();
-            hs.commit = 1;
-            hs.term = 1;
-            gs.set_hardstate(hs).unwrap();
+    // the isolated follower lags behind after the write is committed by others.
+    cluster.transport.isolate(transferee);
+    let request = AppWriteRequest {
+        group_id,
+        term: 0,
+        data: b"data".to_vec(),
+        context: vec![],
+        ..Default::default()
+    };
+    let _ = tokio::time::timeout(
+        Duration::from_millis(100),
+        cluster.multirafts[leader_index].write(request),
+    )
+    .await;
+    let mut leaders = HashMap::new();
+    for _ in 0..5 {
+        cluster.tick_all().await;
+        tokio::task::yield_now().await;
+        cluster.drain_leaders(group_id, &mut leaders);
+    }
 
-            // init confstate
-            let mut cs = ConfState::default();
-            cs.voters = voters.clone();
-            gs.set_confstate(cs).unwrap();
+    let res = cluster.multirafts[leader_index]
+        .transfer_leader_with_policy(
+            GroupId(group_id),
+            ReplicaId(transferee),
+            TransferLeaderPolicy::RejectLagging,
+        )
+        .await;
+    match res {
+        Err(Error::TargetLagging(id, replica_id, matched, last_index)) => {
+            assert_eq!((id, replica_id), (group_id, transferee));
+            assert!(matched < last_index);
+        }
+        res => panic!("expected the lagging transferee, got {:?}", res),
+    }
 
-            // apply snapshot
-            let mut ss = Snapshot::default();
-            ss.mut_metadata().mut_conf_state().voters = voters.clone();
-            ss.mut_metadata().index = 1;
-            ss.mut_metadata().term = 1;
-            gs.apply_snapshot(ss).unwrap();
+    // the transferee catches up and becomes the leader.
+    cluster.transport.reconnect(transferee);
+    cluster.multirafts[leader_index]
+        .transfer_leader_with_policy(
+            GroupId(group_id),
+            ReplicaId(transferee),
+            TransferLeaderPolicy::CatchUp,
+        )
+        .await
+        .unwrap();
+    let new_leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    assert_eq!(new_leader_id, transferee);
+    let _ = stop_tx.send(true);
+}
+
+/// Records the stages of handling the ready of all groups on one node.
+#[derive(Default)]
+struct RecordReadyHook {
+    stages: Mutex<Vec<(u64, ReadyStage)>>,
+}
+
+impl ReadyHook for RecordReadyHook {
+    fn after_stage(&self, group_id: u64, stage: ReadyStage) {
+        self.stages.lock().unwrap().push((group_id, stage));
+    }
+}
 
-            let multiraft = &self.multirafts[node_index];
-            let mut msg = RaftGroupManagementMessage::default();
-            msg.set_msg_type(RaftGroupManagementMessageType::MsgInitialGroup);
-            msg.group_id = group_id;
-            msg.replica_id = replica_id;
-            msg.replicas = replicas.clone();
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_ready_persist_before_send_persisted_messages() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let hooks = (0..3)
+        .map(|_| Arc::new(RecordReadyHook::default()))
+        .collect::<Vec<_>>();
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let extensions = hooks
+        .iter()
+        .map(|hook| MultiRaftExtensions {
+            ready_hook: Some(hook.clone() as Arc<dyn ReadyHook>),
+            ..Default::default()
+        })
+        .collect();
+    let mut cluster = FixtureCluster::make_with_extensions(3, config, extensions, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    cluster.tick_until_leader(group_id, &[0, 1, 2]).await.unwrap();
+
+    // the votes and append responses of a ready are sent only after the hard
+    // state and entries of the ready are persisted.
+    let mut sent_persisted = false;
+    for hook in hooks.iter() {
+        let mut persisted = false;
+        for (_, stage) in hook.stages.lock().unwrap().iter() {
+            match stage {
+                ReadyStage::Ready => persisted = false,
+                ReadyStage::Persist => persisted = true,
+                ReadyStage::SendPersistedMessages => {
+                    assert!(persisted);
+                    sent_persisted = true;
+                }
+                _ => {}
+            }
+        }
+    }
+    assert!(sent_persisted);
+    let _ = stop_tx.send(true);
+}
+
+/// Returns the term as the leadership epoch data and records the calls.
+#[derive(Default)]
+struct EpochLeaderHook {
+    calls: Mutex<Vec<(u64, u64)>>,
+}
 
-            multiraft.initial_raft_group(msg).await.unwrap();
+impl LeaderHook for EpochLeaderHook {
+    fn on_leader(&self, group_id: u64, term: u64) -> Option<Vec<u8>> {
+        self.calls.lock().unwrap().push((group_id, term));
+        Some(term.to_be_bytes().to_vec())
+    }
+}
 
-            match self.groups.get_mut(&group_id) {
-                None => {
-                    self.groups.insert(group_id, vec![node_index as u64]);
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_leader_hook_epoch_entry() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let hooks = (0..3)
+        .map(|_| Arc::new(EpochLeaderHook::default()))
+        .collect::<Vec<_>>();
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let extensions = hooks
+        .iter()
+        .map(|hook| MultiRaftExtensions {
+            leader_hook: Some(hook.clone() as Arc<dyn LeaderHook>),
+            ..Default::default()
+        })
+        .collect();
+    let mut cluster = FixtureCluster::make_with_extensions(3, config, extensions, stop_rx).await;
+    // the `LeaderEpoch` events of the nodes are collected, the other events
+    // are passed through to the cluster.
+    let epochs = Arc::new(Mutex::new(vec![]));
+    for (node_index, mut events) in std::mem::take(&mut cluster.events).into_iter().enumerate() {
+        let (tx, rx) = tokio::sync::mpsc::channel(1024);
+        cluster.events.push(rx);
+        let epochs = epochs.clone();
+        tokio::spawn(async move {
+            while let Some(events) = events.recv().await {
+                let mut others = vec![];
+                for event in events {
+                    match event {
+                        Event::LeaderEpoch(epoch) => {
+                            epochs.lock().unwrap().push((node_index, epoch))
+                        }
+                        event => others.push(event),
+                    }
                 }
-                Some(nodes) => nodes.push(node_index as u64),
-            };
+                if tx.send(others).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+
+    // the leadership is acquired again by the transferee.
+    let transferee = leader_id % 3 + 1;
+    cluster.multirafts[leader_id as usize - 1]
+        .transfer_leader(GroupId(group_id), ReplicaId(transferee))
+        .await
+        .unwrap();
+    let mut new_leader_id = 0;
+    for _ in 0..10 {
+        new_leader_id = cluster
+            .tick_until_leader(group_id, &[0, 1, 2])
+            .await
+            .unwrap();
+        if new_leader_id == transferee {
+            break;
         }
     }
+    assert_eq!(new_leader_id, transferee);
 
-    pub async fn check_elect(&mut self, node_index: u64, group_id: u64) {
-        // trigger an election for the replica in the group of the node where leader nodes.
-        self.trigger_elect(node_index, group_id).await;
+    // the hook fires exactly once per leadership acquisition.
+    let mut terms = vec![];
+    for (node_index, hook) in hooks.iter().enumerate() {
+        let calls = hook.calls.lock().unwrap().clone();
+        let replica_id = node_index as u64 + 1;
+        if replica_id == leader_id || replica_id == transferee {
+            assert_eq!(calls.len(), 1);
+            assert_eq!(calls[0].0, group_id);
+            terms.push(calls[0].1);
+        } else {
+            assert!(calls.is_empty());
+        }
+    }
+    terms.sort();
+    assert!(terms[0] < terms[1]);
 
-        for node_id in self.groups.get(&group_id).unwrap().iter() {
-            let election = FixtureCluster::wait_for_leader_elect(&mut self.events, *node_id).await.unwrap();
-            assert_ne!(election.leader_id, 0);
-            assert_eq!(election.group_id, group_id);
-            let storage = &self.storages[node_index as usize];
-            let replica_id = storage.replica_in_node(group_id, *node_id).await.unwrap();
-            assert_eq!(Some(election.leader_id), replica_id);
+    // the epoch data is the first entry of the term behind the no-op.
+    let new_leader = &cluster.multirafts[transferee as usize - 1];
+    let mut entries = vec![];
+    for _ in 0..20 {
+        cluster.tick_all().await;
+        tokio::task::yield_now().await;
+        entries = new_leader
+            .read_committed(GroupId(group_id), 1, u64::MAX)
+            .await
+            .unwrap();
+        if entries.iter().filter(|e| e.term == terms[1]).count() >= 2 {
+            break;
         }
     }
+    for term in terms {
+        let mut of_term = entries.iter().filter(|e| e.term == term);
+        let noop = of_term.next().unwrap();
+        assert_eq!(entry::decode_entry(&noop.data), EntryPayload::Empty);
+        let epoch = of_term.next().unwrap();
+        assert_eq!(epoch.index, noop.index + 1);
+        assert_eq!(
+            entry::decode_entry(&epoch.data),
+            EntryPayload::Data(&term.to_be_bytes())
+        );
+    }
 
-    async fn trigger_elect(&self, node_index: u64, group_id: u64) {
-        self.multirafts[node_index as usize].campagin(group_id).await
+    // the `LeaderEpoch` event of each leadership reports the indexes of its
+    // no-op and epoch entry.
+    let epochs = std::mem::take(&mut *epochs.lock().unwrap());
+    assert_eq!(epochs.len(), 2);
+    for (node_index, epoch) in epochs {
+        let replica_id = node_index as u64 + 1;
+        assert!(replica_id == leader_id || replica_id == transferee);
+        assert_eq!(epoch.group_id, group_id);
+        let noop = entries.iter().find(|e| e.term == epoch.term).unwrap();
+        assert_eq!(epoch.noop_index, noop.index);
+        assert_eq!(epoch.epoch_index, Some(noop.index + 1));
     }
+    let _ = stop_tx.send(true);
+}
 
-    async fn wait_for_leader_elect(events: &mut Vec<Receiver<Vec<Event>>>, node_id: u64) -> Option<LeaderElectionEvent> {
-        let event = &mut events[node_id as usize];
-        loop {
-            let events = event.recv().await.unwrap();
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_max_committed_size_per_ready_bounds_apply() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let hooks = (0..3)
+        .map(|_| Arc::new(RecordReadyHook::default()))
+        .collect::<Vec<_>>();
+    // every ready delivers at most one committed entry.
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        max_committed_size_per_ready: 1,
+        ..Default::default()
+    };
+    let extensions = hooks
+        .iter()
+        .map(|hook| MultiRaftExtensions {
+            ready_hook: Some(hook.clone() as Arc<dyn ReadyHook>),
+            ..Default::default()
+        })
+        .collect();
+    let mut cluster = FixtureCluster::make_with_extensions(3, config, extensions, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+
+    // the state machines ack the applied entries.
+    cluster.ack_applies();
+
+    // the isolated follower builds up a committed backlog.
+    let follower_id = (1..=3).find(|id| *id != leader_id).unwrap();
+    cluster.transport.isolate(follower_id);
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let writes = 10;
+    for _ in 0..writes {
+        let request = AppWriteRequest {
+            group_id,
+            term: 0,
+            data: b"data".to_vec(),
+            context: vec![],
+            ..Default::default()
+        };
+        leader.write(request).await.unwrap();
+    }
+    let commit_index = leader
+        .group_status(GroupId(group_id))
+        .await
+        .unwrap()
+        .commit_index;
+
+    let follower = &cluster.multirafts[follower_id as usize - 1];
+    let hook = &hooks[follower_id as usize - 1];
+    hook.stages.lock().unwrap().clear();
+    let ticks = follower
+        .group_counters(GroupId(group_id))
+        .await
+        .unwrap()
+        .ticks;
+    cluster.transport.reconnect(follower_id);
+
+    // the backlog is applied in bounded readies while the ticks go on.
+    let mut applied = 0;
+    for _ in 0..100 {
+        cluster.tick_all().await;
+        tokio::task::yield_now().await;
+        applied = follower
+            .group_status(GroupId(group_id))
+            .await
+            .unwrap()
+            .applied_index;
+        if applied >= commit_index {
+            break;
+        }
+    }
+    assert!(applied >= commit_index);
+    let applies = hook
+        .stages
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, stage)| *stage == ReadyStage::Apply)
+        .count();
+    assert!(applies >= writes);
+    assert!(
+        follower
+            .group_counters(GroupId(group_id))
+            .await
+            .unwrap()
+            .ticks
+            > ticks
+    );
+    let _ = stop_tx.send(true);
+}
+
+/// Panics after the `Apply` stage of the armed group.
+#[derive(Default)]
+struct PanicReadyHook {
+    group_id: AtomicU64,
+}
+
+impl ReadyHook for PanicReadyHook {
+    fn after_stage(&self, group_id: u64, stage: ReadyStage) {
+        if stage == ReadyStage::Apply && group_id == self.group_id.load(Ordering::SeqCst) {
+            panic!("injected panic of group {}", group_id);
+        }
+    }
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_group_panic_poisons_only_the_group() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let hook = Arc::new(PanicReadyHook::default());
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let extensions = vec![MultiRaftExtensions {
+        ready_hook: Some(hook.clone() as Arc<dyn ReadyHook>),
+        ..Default::default()
+    }];
+    let mut cluster = FixtureCluster::make_with_extensions(1, config, extensions, stop_rx).await;
+    let (failed_tx, mut failed_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut events = cluster.events.remove(0);
+    tokio::spawn(async move {
+        while let Some(events) = events.recv().await {
             for event in events {
                 match event {
-                    Event::LederElection(leader_elect) => return Some(leader_elect),
+                    Event::Apply(apply) => {
+                        if let Some(tx) = apply.tx {
+                            let _ = tx.send(Ok(()));
+                        }
+                    }
+                    Event::GroupFailed(failed) => {
+                        let _ = failed_tx.send(failed.group_id);
+                    }
                     _ => {}
                 }
             }
         }
+    });
+
+    for group_id in 1..=2 {
+        cluster.make_group_with_campaign(group_id, 0, 1, true).await;
     }
-}
+    cluster.tick_all().await;
+
+    let multiraft = &cluster.multirafts[0];
+    let request = |group_id| AppWriteRequest {
+        group_id,
+        term: 0,
+        data: b"data".to_vec(),
+        context: vec![],
+        ..Default::default()
+    };
+    multiraft.write(request(1)).await.unwrap();
+    multiraft.write(request(2)).await.unwrap();
 
-impl FixtureCluster {}
+    // the panic in handling the ready of group 2 fails its pending write.
+    hook.group_id.store(2, Ordering::SeqCst);
+    assert!(multiraft.write(request(2)).await.is_err());
+    assert_eq!(failed_rx.recv().await, Some(2));
+    assert!(multiraft.write(request(2)).await.is_err());
+
+    // the other groups keep running.
+    cluster.tick_all().await;
+    multiraft.write(request(1)).await.unwrap();
+    let _ = stop_tx.send(true);
+}
 
+#[cfg(feature = "test-util")]
 #[tokio::test(flavor = "multi_thread")]
-async fn test_initial_leader_elect() {
-    for leader_id in 0..3 {
+async fn test_actor_watchdog_on_panic() {
+    for restart in [false, true] {
         let (stop_tx, stop_rx) = watch::channel(false);
-        let mut cluster = FixtureCluster::make(3, stop_rx);
+        let config = MultiRaftConfig {
+            election_tick: 2,
+            heartbeat_tick: 1,
+            manual_tick: true,
+            restart_actor_on_panic: restart,
+            actor_restart_backoff: 10,
+            max_actor_restarts: 1,
+            ..Default::default()
+        };
+        let mut cluster = FixtureCluster::make_with_config(1, config, stop_rx).await;
+        let (failure_tx, mut failure_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut events = cluster.events.remove(0);
+        tokio::spawn(async move {
+            while let Some(events) = events.recv().await {
+                for event in events {
+                    match event {
+                        Event::Apply(apply) => {
+                            if let Some(tx) = apply.tx {
+                                let _ = tx.send(Ok(()));
+                            }
+                        }
+                        Event::NodeFailure(failure) => {
+                            let _ = failure_tx.send(failure);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        });
+
         let group_id = 1;
-        cluster.make_group(group_id, 0, 3).await;
+        cluster.make_group_with_campaign(group_id, 0, 1, true).await;
+        cluster.tick_all().await;
+        let timeout = Duration::from_secs(5);
+        cluster.multirafts[0]
+            .propose_timeout(GroupId(group_id), vec![1], vec![], timeout)
+            .await
+            .unwrap();
+        let log_bytes = cluster.multirafts[0].health().await.log_bytes;
+        assert!(log_bytes > 0);
 
-        cluster.check_elect(leader_id, group_id).await;
+        // the panic which isn't isolated to a group kills the actor, the
+        // leaderships reported by the dead actor are forgotten.
+        assert!(cluster.multirafts[0].is_leader(GroupId(group_id)));
+        cluster.multirafts[0].panic_actor().await;
+        let failure = tokio::time::timeout(timeout, failure_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!cluster.multirafts[0].is_leader(GroupId(group_id)));
+        assert_eq!(failure.node_id, 1);
+        assert!(
+            failure.reason.contains("panicked on purpose"),
+            "{}",
+            failure.reason
+        );
+        assert_eq!(failure.restarted, restart);
+        if !restart {
+            assert!(!cluster.multirafts[0].health().await.actor_running);
+            let _ = stop_tx.send(true);
+            continue;
+        }
+
+        // the restarted actor serves the same mailboxes and recreates the
+        // replica from the storage, the size of its log is read back.
+        assert_eq!(cluster.tick_until_leader(group_id, &[0]).await, Some(1));
+        assert_eq!(cluster.multirafts[0].health().await.log_bytes, log_bytes);
+        cluster.multirafts[0]
+            .propose_timeout(GroupId(group_id), vec![2], vec![], timeout)
+            .await
+            .unwrap();
+        assert!(cluster.multirafts[0].health().await.actor_running);
+
+        // the actor is restarted at most `max_actor_restarts` times.
+        cluster.multirafts[0].panic_actor().await;
+        let failure = tokio::time::timeout(timeout, failure_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!failure.restarted);
+        assert!(!cluster.multirafts[0].health().await.actor_running);
         let _ = stop_tx.send(true);
     }
 }
+
+/// Rejects the entries of data "reject" and fails on the entries of data
+/// "fatal".
+struct RejectStateMachine;
+
+impl StateMachine for RejectStateMachine {
+    fn apply(&self, apply: &ApplyEvent, _: &mut ApplyHandle) -> Result<ApplyOutput, ApplyError> {
+        match &apply.entry.data[..] {
+            b"reject" => Err(ApplyError::Reject("rejected".to_owned())),
+            b"fatal" => Err(ApplyError::Fatal("corrupted".to_owned())),
+            _ => Ok(ApplyOutput),
+        }
+    }
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_state_machine_reject_and_fatal() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let extensions = vec![MultiRaftExtensions {
+        state_machine: Some(Arc::new(RejectStateMachine) as Arc<dyn StateMachine>),
+        ..Default::default()
+    }];
+    let mut cluster = FixtureCluster::make_with_extensions(1, config, extensions, stop_rx).await;
+    let (failed_tx, mut failed_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut events = cluster.events.remove(0);
+    tokio::spawn(async move {
+        while let Some(events) = events.recv().await {
+            for event in events {
+                match event {
+                    Event::Apply(_) => panic!("the data entries are applied by the state machine"),
+                    Event::GroupFailed(failed) => {
+                        let _ = failed_tx.send(failed.group_id);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    });
+
+    let group_id = 1;
+    cluster.make_group_with_campaign(group_id, 0, 1, true).await;
+    cluster.tick_all().await;
+
+    let multiraft = &cluster.multirafts[0];
+    let request = |data: &[u8]| AppWriteRequest {
+        group_id,
+        term: 0,
+        data: data.to_vec(),
+        context: vec![],
+        ..Default::default()
+    };
+    multiraft.write(request(b"data")).await.unwrap();
+
+    // the rejected entry fails its proposal only, the group keeps running.
+    match multiraft.write(request(b"reject")).await {
+        Err(Error::Proposal(ProposalError::Rejected(reason))) => assert_eq!(reason, "rejected"),
+        res => panic!("expected the rejected proposal, got {:?}", res),
+    }
+    let status = multiraft.group_status(GroupId(group_id)).await.unwrap();
+    assert_eq!(status.state, GroupState::Running);
+    multiraft.write(request(b"data")).await.unwrap();
+
+    // the fatal error poisons the group.
+    match multiraft.write(request(b"fatal")).await {
+        Err(Error::GroupPoisoned(id)) => assert_eq!(id, group_id),
+        res => panic!("expected the poisoned group, got {:?}", res),
+    }
+    assert_eq!(failed_rx.recv().await, Some(group_id));
+    let status = multiraft.group_status(GroupId(group_id)).await.unwrap();
+    assert_eq!(status.state, GroupState::Failed);
+    assert!(multiraft.write(request(b"data")).await.is_err());
+    let _ = stop_tx.send(true);
+}
+
+/// Counts the entries applied by the state machine.
+struct CountingStateMachine(Arc<AtomicUsize>);
+
+impl StateMachine for CountingStateMachine {
+    fn apply(&self, _: &ApplyEvent, _: &mut ApplyHandle) -> Result<ApplyOutput, ApplyError> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Ok(ApplyOutput)
+    }
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_leader_noop_entry_skips_state_machine() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let applies = Arc::new(AtomicUsize::new(0));
+    let extensions = vec![MultiRaftExtensions {
+        state_machine: Some(
+            Arc::new(CountingStateMachine(applies.clone())) as Arc<dyn StateMachine>
+        ),
+        ..Default::default()
+    }];
+    let mut cluster = FixtureCluster::make_with_extensions(1, config, extensions, stop_rx).await;
+    let mut events = cluster.events.remove(0);
+    tokio::spawn(async move {
+        while let Some(events) = events.recv().await {
+            for event in events {
+                if let Event::Apply(_) = event {
+                    panic!("the entries are applied by the state machine");
+                }
+            }
+        }
+    });
+
+    let group_id = 1;
+    cluster.make_group_with_campaign(group_id, 0, 1, true).await;
+    cluster.tick_all().await;
+
+    // the no-op entry of the new leader is committed and applied without
+    // being passed to the state machine.
+    let multiraft = &cluster.multirafts[0];
+    loop {
+        let status = multiraft.group_status(GroupId(group_id)).await.unwrap();
+        if status.role == StateRole::Leader
+            && status.commit_index > 0
+            && status.applied_index == status.commit_index
+        {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(applies.load(Ordering::SeqCst), 0);
+
+    // the data entry after it is passed to the state machine.
+    multiraft
+        .propose_timeout(
+            GroupId(group_id),
+            b"data".to_vec(),
+            vec![],
+            Duration::from_secs(10),
+        )
+        .await
+        .unwrap();
+    assert_eq!(applies.load(Ordering::SeqCst), 1);
+    let _ = stop_tx.send(true);
+}
+
+/// Records the applied data, the entry of data "ping" follows up a write of
+/// data "pong" by the handle, and the entry of data "ping-large" follows up a
+/// write of 64 bytes.
+#[derive(Default)]
+struct FollowUpStateMachine {
+    applied: Mutex<Vec<Vec<u8>>>,
+    follow_ups: Mutex<Vec<oneshot::Receiver<Result<(), Error>>>>,
+}
+
+impl StateMachine for FollowUpStateMachine {
+    fn apply(
+        &self,
+        apply: &ApplyEvent,
+        handle: &mut ApplyHandle,
+    ) -> Result<ApplyOutput, ApplyError> {
+        let follow_up = match apply.entry.data.as_slice() {
+            b"ping" => Some(b"pong".to_vec()),
+            b"ping-large" => Some(vec![0; 64]),
+            _ => None,
+        };
+        if let Some(data) = follow_up {
+            let rx = handle.propose(AppWriteRequest {
+                group_id: apply.group_id,
+                term: 0,
+                data,
+                context: vec![],
+                client_id: 1,
+                sequence: apply.entry.index,
+                ..Default::default()
+            });
+            self.follow_ups.lock().unwrap().push(rx);
+        }
+        self.applied.lock().unwrap().push(apply.entry.data.clone());
+        Ok(ApplyOutput)
+    }
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_state_machine_follow_up_write() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        max_entry_size: 32,
+        ..Default::default()
+    };
+    let state_machine = Arc::new(FollowUpStateMachine::default());
+    let extensions = vec![MultiRaftExtensions {
+        state_machine: Some(state_machine.clone() as Arc<dyn StateMachine>),
+        ..Default::default()
+    }];
+    let mut cluster = FixtureCluster::make_with_extensions(1, config, extensions, stop_rx).await;
+    let mut events = cluster.events.remove(0);
+    tokio::spawn(async move { while events.recv().await.is_some() {} });
+
+    let group_id = 1;
+    cluster.make_group_with_campaign(group_id, 0, 1, true).await;
+    cluster.tick_all().await;
+    let multiraft = &cluster.multirafts[0];
+    let timeout = Duration::from_secs(10);
+    multiraft
+        .propose_timeout(GroupId(group_id), b"ping".to_vec(), vec![], timeout)
+        .await
+        .unwrap();
+
+    // the follow-up is proposed after the batch of the ping is applied,
+    // rather than deadlocking the apply against the actor.
+    let rx = state_machine.follow_ups.lock().unwrap().pop().unwrap();
+    tokio::time::timeout(timeout, rx)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        *state_machine.applied.lock().unwrap(),
+        vec![b"ping".to_vec(), b"pong".to_vec()]
+    );
+
+    // the follow-up over `max_entry_size` is rejected like the write.
+    multiraft
+        .propose_timeout(GroupId(group_id), b"ping-large".to_vec(), vec![], timeout)
+        .await
+        .unwrap();
+    let rx = state_machine.follow_ups.lock().unwrap().pop().unwrap();
+    let res = tokio::time::timeout(timeout, rx).await.unwrap().unwrap();
+    assert!(matches!(
+        res,
+        Err(Error::Proposal(ProposalError::EntryTooLarge(64, 32)))
+    ));
+    assert_eq!(state_machine.applied.lock().unwrap().len(), 3);
+
+    // the group keeps serving the writes after the follow-up.
+    multiraft
+        .propose_timeout(GroupId(group_id), b"data".to_vec(), vec![], timeout)
+        .await
+        .unwrap();
+    let _ = stop_tx.send(true);
+}
+
+fn node_address(node_id: u64) -> NodeAddress {
+    NodeAddress {
+        node_id,
+        store_id: node_id,
+        addr: format!("local://{}", node_id),
+    }
+}
+
+/// Records the dropped messages.
+#[derive(Default)]
+struct RecordDroppedObserver {
+    dropped: Mutex<Vec<(DropReason, DroppedMessage)>>,
+}
+
+impl DroppedMessageObserver for RecordDroppedObserver {
+    fn on_dropped(&self, reason: DropReason, msg: &DroppedMessage) {
+        self.dropped.lock().unwrap().push((reason, msg.clone()));
+    }
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_dropped_message_observer() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        ..Default::default()
+    };
+    // the node 1 can't resolve the node 3.
+    let resolver = MemNodeResolver::new();
+    resolver.update(node_address(1));
+    resolver.update(node_address(2));
+    let observer = Arc::new(RecordDroppedObserver::default());
+    let extensions = vec![MultiRaftExtensions {
+        node_resolver: Some(Arc::new(resolver) as Arc<dyn NodeResolver>),
+        dropped_message_observer: Some(observer.clone() as Arc<dyn DroppedMessageObserver>),
+        ..Default::default()
+    }];
+    let mut cluster = FixtureCluster::make_with_extensions(3, config, extensions, stop_rx).await;
+    cluster.transport.isolate(2);
+
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    // the node 1 campaigns after the election timeout.
+    for _ in 0..10 {
+        cluster.tick_all().await;
+    }
+
+    // the messages from node 1 to node 3 are failed by the transport, which
+    // is injected with the resolver of node 1.
+    assert!(cluster.transport.stats().send_errors > 0);
+    let send_failed = cluster.multirafts[0]
+        .dropped_message_counts()
+        .into_iter()
+        .find(|(reason, _)| *reason == DropReason::SendFailed)
+        .map_or(0, |(_, count)| count);
+    assert!(send_failed > 0);
+    assert!(observer
+        .dropped
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|(reason, msg)| *reason == DropReason::SendFailed
+            && msg.group_id == group_id
+            && msg.to_node == 3));
+
+    // the messages to the isolated node 2 are dropped by the transport, which
+    // reports them to the node 1.
+    let count = |node_index: usize, reason: DropReason| {
+        cluster.multirafts[node_index]
+            .dropped_message_counts()
+            .into_iter()
+            .find(|(r, _)| *r == reason)
+            .map_or(0, |(_, count)| count)
+    };
+    assert!(count(0, DropReason::Filtered) > 0);
+    assert!(observer
+        .dropped
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|(reason, msg)| *reason == DropReason::Filtered && msg.to_node == 2));
+
+    // the messages queued beyond the capacity are dropped by the overflow.
+    cluster.transport.reconnect(2);
+    cluster.transport.set_queue_capacity(1);
+    let msg = RaftMessage {
+        group_id: 100,
+        from_node: 1,
+        to_node: 2,
+        ..Default::default()
+    };
+    let failures = cluster.transport.send_batch(vec![msg.clone(), msg]);
+    assert!(failures.is_empty());
+    assert_eq!(count(0, DropReason::QueueOverflow), 1);
+    assert!(observer
+        .dropped
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|(reason, msg)| *reason == DropReason::QueueOverflow && msg.group_id == 100));
+
+    // the message to the node which isn't listening fails after it's sent,
+    // the node 2 has no resolver so it's not failed by the send.
+    let msg = RaftMessage {
+        group_id: 100,
+        from_node: 2,
+        to_node: 4,
+        ..Default::default()
+    };
+    assert!(cluster.transport.send(msg).is_ok());
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while count(1, DropReason::SendFailed) == 0 {
+        assert!(
+            Instant::now() < deadline,
+            "the failed delivery is not counted"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_step_error_dropped() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let group_id = 1;
+    let (cluster, leader_id) = FixtureCluster::make_acked_group(group_id, stop_rx).await;
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let term = leader.group_status(GroupId(group_id)).await.unwrap().term;
+
+    // the leader fails to step the response from the replica which isn't in
+    // the group, the message is dropped and the group keeps running.
+    let mut response = smol_raft::proto::Message::default();
+    response.set_msg_type(smol_raft::proto::MessageType::MsgAppendResponse);
+    response.from = 9;
+    response.to = leader_id;
+    response.term = term;
+    let from_node = (1..=3).find(|id| *id != leader_id).unwrap();
+    cluster
+        .transport
+        .send(RaftMessage {
+            group_id,
+            from_node,
+            to_node: leader_id,
+            msg: Some(response),
+            ..Default::default()
+        })
+        .unwrap();
+    let rejected = || {
+        leader
+            .dropped_message_counts()
+            .into_iter()
+            .find(|(reason, _)| *reason == DropReason::Rejected)
+            .map_or(0, |(_, count)| count)
+    };
+    let timeout = Instant::now() + Duration::from_secs(5);
+    while rejected() == 0 {
+        assert!(Instant::now() < timeout);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let status = leader.group_status(GroupId(group_id)).await.unwrap();
+    assert_ne!(status.state, GroupState::Failed);
+    assert_eq!(status.role, StateRole::Leader);
+    leader
+        .propose_timeout(GroupId(group_id), vec![0], vec![], Duration::from_secs(5))
+        .await
+        .unwrap();
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_removed_replica_late_message_dropped() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+
+    // the state machines ack the applied entries.
+    cluster.ack_applies();
+
+    // the removed replica is isolated, so it doesn't know it's removed.
+    let removed_id = (1..=3).find(|id| *id != leader_id).unwrap();
+    cluster.transport.isolate(removed_id);
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let mut change = MembershipChangeRequest {
+        group_id,
+        node_id: removed_id,
+        replica_id: removed_id,
+        ..Default::default()
+    };
+    change.set_change_type(ConfChangeType::RemoveNode);
+    leader
+        .propose_conf_change(MembershipChangeData {
+            group_id,
+            changes: vec![change],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert!(!leader
+        .conf_state(GroupId(group_id))
+        .await
+        .unwrap()
+        .voters
+        .contains(&removed_id));
+
+    // the removed replica campaigns after the partition heals, its votes
+    // are dropped rather than disrupting the leader.
+    cluster.transport.reconnect(removed_id);
+    for _ in 0..10 {
+        cluster.tick_all().await;
+    }
+    let stale = leader
+        .dropped_message_counts()
+        .into_iter()
+        .find(|(reason, _)| *reason == DropReason::StaleReplica)
+        .map_or(0, |(_, count)| count);
+    assert!(stale > 0);
+    assert!(leader
+        .list_groups()
+        .await
+        .contains(&(GroupId(group_id), ReplicaRole::Leader)));
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_unknown_node_unreachable_until_resolved() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        ..Default::default()
+    };
+    // the node 1 and 2 can't resolve the node 3 at first.
+    let resolver = MemNodeResolver::new();
+    resolver.update(node_address(1));
+    resolver.update(node_address(2));
+    let extensions = (0..2)
+        .map(|_| MultiRaftExtensions {
+            node_resolver: Some(Arc::new(resolver.clone()) as Arc<dyn NodeResolver>),
+            ..Default::default()
+        })
+        .collect();
+    let mut cluster = FixtureCluster::make_with_extensions(3, config, extensions, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+
+    let leader_id = cluster.tick_until_leader(group_id, &[0, 1]).await.unwrap();
+    assert_ne!(leader_id, 3);
+    let mut leaders = HashMap::new();
+    cluster.tick_all().await;
+    cluster.drain_leaders(group_id, &mut leaders);
+    assert!(!leaders.contains_key(&2));
+
+    // the node 3 is reachable after the resolver is updated at runtime.
+    resolver.update(node_address(3));
+    assert!(cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .is_some());
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_tick_stagger_groups() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        tick_stagger_slots: 2,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(3, config, stop_rx).await;
+    cluster.make_group(1, 0, 3).await;
+    cluster.make_group(2, 0, 3).await;
+    cluster.tick_until_leader(1, &[0, 1, 2]).await.unwrap();
+    cluster.tick_until_leader(2, &[0, 1, 2]).await.unwrap();
+
+    // each pass ticks the groups of one slot.
+    cluster.tick_all().await;
+    let health = cluster.multirafts[0].health().await;
+    assert_eq!(health.group_count, 2);
+    assert_eq!(health.last_tick_groups, 1);
+    let _ = stop_tx.send(true);
+}
+
+/// Start `groups` groups on 3 nodes at once, and returns the sum of the terms
+/// of their first leaders, each split vote costs a group one more term.
+#[cfg(feature = "test-util")]
+async fn first_leader_terms(groups: u64, startup_election_jitter: usize) -> u64 {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        startup_election_jitter,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(3, config, stop_rx).await;
+    for group_id in 1..=groups {
+        cluster.make_group(group_id, 0, 3).await;
+    }
+    cluster.ack_applies();
+
+    let mut terms = HashMap::new();
+    for _ in 0..100 {
+        cluster.tick_all().await;
+        // the votes of the tick are settled before the next tick.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        for group_id in 1..=groups {
+            if terms.contains_key(&group_id) {
+                continue;
+            }
+            for multiraft in cluster.multirafts.iter() {
+                let status = multiraft.group_status(GroupId(group_id)).await.unwrap();
+                if status.role == StateRole::Leader {
+                    terms.insert(group_id, status.term);
+                }
+            }
+        }
+        if terms.len() == groups as usize {
+            break;
+        }
+    }
+    assert_eq!(terms.len(), groups as usize);
+    let _ = stop_tx.send(true);
+    terms.values().sum()
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_startup_election_jitter_reduces_split_votes() {
+    // without the jitter, the timers of the replicas of a group start at the
+    // same tick, and the votes are split whenever all of them time out at
+    // once, which the jitter makes rare.
+    let groups = 30;
+    let without_jitter = first_leader_terms(groups, 0).await;
+    let with_jitter = first_leader_terms(groups, 10).await;
+    assert!(
+        with_jitter < without_jitter,
+        "split votes with jitter {}, without jitter {}",
+        with_jitter - groups,
+        without_jitter - groups
+    );
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_commit_and_applied_watch() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(1, stop_rx).await;
+    spawn_ack_applies(cluster.events.remove(0));
+
+    let group_id = 1;
+    cluster.make_group_with_campaign(group_id, 0, 1, true).await;
+    cluster.tick_all().await;
+
+    let multiraft = &cluster.multirafts[0];
+    let mut commit = multiraft.commit_watch(GroupId(group_id)).await.unwrap();
+    let mut applied = multiraft.applied_watch(GroupId(group_id)).await.unwrap();
+    let start = *commit.borrow();
+    let request = AppWriteRequest {
+        group_id,
+        term: 0,
+        data: b"data".to_vec(),
+        context: vec![],
+        ..Default::default()
+    };
+    multiraft.write(request).await.unwrap();
+
+    // the watches are updated without polling the group.
+    for watch in [&mut commit, &mut applied] {
+        while *watch.borrow() <= start {
+            tokio::time::timeout(Duration::from_secs(1), watch.changed())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+    }
+
+    // the watches are closed after the group is removed.
+    multiraft.remove_group(GroupId(group_id)).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(1), async {
+        while applied.changed().await.is_ok() {}
+    })
+    .await
+    .unwrap();
+    assert!(multiraft.commit_watch(GroupId(group_id)).await.is_err());
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_watch_wakes_on_burst() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(1, stop_rx).await;
+    spawn_ack_applies(cluster.events.remove(0));
+
+    let group_id = 1;
+    cluster.make_group_with_campaign(group_id, 0, 1, true).await;
+    cluster.tick_all().await;
+
+    // the waiters of an index in the middle of the burst are woken, although
+    // the watches skip the intermediate indexes advanced in one iteration.
+    let multiraft = &cluster.multirafts[0];
+    let mut commit = multiraft.commit_watch(GroupId(group_id)).await.unwrap();
+    let mut applied = multiraft.applied_watch(GroupId(group_id)).await.unwrap();
+    let target = *applied.borrow() + 32;
+    let waiters = [&mut commit, &mut applied].map(|watch| async move {
+        while *watch.borrow() < target {
+            watch.changed().await.unwrap();
+        }
+        *watch.borrow()
+    });
+    let timeout = Duration::from_secs(5);
+    let burst = futures::future::join_all(
+        (0..64u8).map(|i| multiraft.propose_timeout(GroupId(group_id), vec![i], vec![], timeout)),
+    );
+    let (reached, tokens) = tokio::join!(
+        tokio::time::timeout(timeout, futures::future::join_all(waiters)),
+        burst
+    );
+    for index in reached.unwrap() {
+        assert!(index >= target);
+    }
+
+    let tokens = tokens.into_iter().collect::<Result<Vec<_>, _>>().unwrap();
+    let applied = multiraft
+        .read_at_least(GroupId(group_id), &tokens[31], Duration::from_secs(1))
+        .await
+        .unwrap();
+    assert!(applied >= tokens[31].index());
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_wait_quorum_applied() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        report_applied_index: true,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(3, config, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let token = leader
+        .write(AppWriteRequest {
+            group_id,
+            term: 0,
+            data: b"data".to_vec(),
+            context: vec![],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+    // the followers report the applied index by the heartbeat responses.
+    let mut replicas = vec![];
+    for _ in 0..20 {
+        cluster.tick_all().await;
+        if let Ok(applied) = leader
+            .wait_quorum_applied(GroupId(group_id), token.index(), Duration::from_millis(50))
+            .await
+        {
+            replicas = applied;
+            break;
+        }
+    }
+    assert!(replicas.len() >= 2);
+    assert!(replicas.contains(&ReplicaId(leader_id)));
+    let status = leader.group_status(GroupId(group_id)).await.unwrap();
+    assert!(status
+        .progress
+        .iter()
+        .filter(|pr| pr.replica_id != leader_id)
+        .any(|pr| pr.applied >= token.index()));
+
+    // the index which isn't applied by a quorum times out.
+    assert!(leader
+        .wait_quorum_applied(
+            GroupId(group_id),
+            token.index() + 100,
+            Duration::from_millis(50)
+        )
+        .await
+        .is_err());
+    let follower = &cluster.multirafts[leader_id as usize % 3];
+    assert!(follower
+        .wait_quorum_applied(GroupId(group_id), token.index(), Duration::from_millis(50))
+        .await
+        .is_err());
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_new_leader_auto_leave_joint() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+
+    // the leader never replicates the leave of the joint consensus.
+    cluster.transport.set_filter(move |msg| {
+        let leave_joint = msg.from_node == leader_id
+            && msg.msg.as_ref().map_or(false, |msg| {
+                msg.entries.iter().any(|entry| {
+                    entry.entry_type() == EntryType::EntryConfChangeV2
+                        && <ConfChangeV2 as prost::Message>::decode(entry.data.as_slice())
+                            .map_or(false, |cc| cc.changes.is_empty())
+                })
+            });
+        if leave_joint {
+            FilterAction::Drop
+        } else {
+            FilterAction::Pass
+        }
+    });
+
+    // the leader removes itself in auto mode, then it's killed in the joint consensus.
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let mut change = MembershipChangeRequest {
+        group_id,
+        node_id: leader_id,
+        replica_id: leader_id,
+        ..Default::default()
+    };
+    change.set_change_type(ConfChangeType::RemoveNode);
+    let mut data = MembershipChangeData {
+        group_id,
+        changes: vec![change],
+        ..Default::default()
+    };
+    data.set_transition(ConfChangeTransition::Implicit);
+    leader.propose_conf_change(data).await.unwrap();
+    cluster.transport.isolate(leader_id);
+
+    // the new leader applies the joint consensus and leaves it.
+    let survivors = (1..=3)
+        .filter(|id| *id != leader_id)
+        .collect::<Vec<u64>>();
+    let mut conf_state = None;
+    for _ in 0..100 {
+        cluster.tick_all().await;
+        tokio::task::yield_now().await;
+        for id in survivors.iter() {
+            let multiraft = &cluster.multirafts[*id as usize - 1];
+            if !multiraft
+                .list_groups()
+                .await
+                .contains(&(GroupId(group_id), ReplicaRole::Leader))
+            {
+                continue;
+            }
+            let cs = multiraft.conf_state(GroupId(group_id)).await.unwrap();
+            if cs.voters_outgoing.is_empty() && !cs.voters.contains(&leader_id) {
+                conf_state = Some(cs);
+            }
+        }
+        if conf_state.is_some() {
+            break;
+        }
+    }
+    let mut voters = conf_state.unwrap().voters;
+    voters.sort();
+    assert_eq!(voters, survivors);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_storage_write_error_not_advance() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        storage_write_retries: 5,
+        storage_write_retry_backoff: 1,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(1, config, stop_rx).await;
+    let failures = Arc::new(AtomicUsize::new(0));
+    let counter = failures.clone();
+    let mut events = cluster.events.remove(0);
+    tokio::spawn(async move {
+        while let Some(events) = events.recv().await {
+            for event in events {
+                match event {
+                    Event::Apply(apply) => {
+                        if let Some(tx) = apply.tx {
+                            let _ = tx.send(Ok(()));
+                        }
+                    }
+                    Event::GroupStorageError(_) => {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    });
+
+    let group_id = 1;
+    cluster.make_group_with_campaign(group_id, 0, 1, true).await;
+    cluster.tick_all().await;
+
+    let multiraft = &cluster.multirafts[0];
+    let commit = multiraft.commit_watch(GroupId(group_id)).await.unwrap();
+    let start = *commit.borrow();
+    let storage = cluster.storages[0]
+        .memory_storage(group_id)
+        .await
+        .unwrap();
+    let last_index = storage.last_index().unwrap();
+
+    // the disk fails the first 3 writes of the ready.
+    storage.wl().trigger_write_errors(3);
+    let request = AppWriteRequest {
+        group_id,
+        term: 0,
+        data: b"data".to_vec(),
+        context: vec![],
+        ..Default::default()
+    };
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), multiraft.write(request))
+            .await
+            .is_err()
+    );
+
+    // raft doesn't advance past the unpersisted entry.
+    assert_eq!(storage.last_index().unwrap(), last_index);
+    let status = multiraft.group_status(GroupId(group_id)).await.unwrap();
+    assert_eq!(status.commit_index, start);
+    assert!(failures.load(Ordering::SeqCst) >= 1);
+
+    // the ready is retried with backoff until it's persisted.
+    for _ in 0..20 {
+        if *commit.borrow() > start {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        cluster.tick_all().await;
+    }
+    assert!(*commit.borrow() > start);
+    assert!(storage.last_index().unwrap() > last_index);
+    assert_eq!(failures.load(Ordering::SeqCst), 3);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_read_at_least_commit_token() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(1, stop_rx).await;
+    spawn_ack_applies(cluster.events.remove(0));
+
+    for group_id in 1..=2 {
+        cluster.make_group_with_campaign(group_id, 0, 1, true).await;
+    }
+    cluster.tick_all().await;
+
+    let multiraft = &cluster.multirafts[0];
+    let request = AppWriteRequest {
+        group_id: 1,
+        term: 0,
+        data: b"data".to_vec(),
+        context: vec![],
+        ..Default::default()
+    };
+    let token = multiraft.write(request).await.unwrap();
+    assert_eq!(token.group_id(), 1);
+
+    // the read waits until the write is applied.
+    let applied = multiraft
+        .read_at_least(GroupId(1), &token, Duration::from_secs(1))
+        .await
+        .unwrap();
+    assert!(applied >= token.index());
+
+    // the token can't be used by other groups.
+    assert!(multiraft
+        .read_at_least(GroupId(2), &token, Duration::from_secs(1))
+        .await
+        .is_err());
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_report_unreachable_node() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+
+    // the state machines ack the applied entries.
+    cluster.ack_applies();
+
+    // fail the delivery to a follower and count the appends to it.
+    let unreachable = (1..=3).find(|node_id| *node_id != leader_id).unwrap();
+    let appends = Arc::new(AtomicUsize::new(0));
+    let counter = appends.clone();
+    cluster.transport.set_filter(move |msg| {
+        if msg.to_node != unreachable {
+            return FilterAction::Pass;
+        }
+        if msg.msg.as_ref().map_or(false, |msg| {
+            msg.msg_type() == smol_raft::proto::MessageType::MsgAppend
+        }) {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }
+        FilterAction::Fail
+    });
+
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    for _ in 0..10 {
+        let request = AppWriteRequest {
+            group_id,
+            term: 0,
+            data: b"data".to_vec(),
+            context: vec![],
+            ..Default::default()
+        };
+        leader.write(request).await.unwrap();
+    }
+
+    // the leader backs off to probe the unreachable follower rather than
+    // sending the appends of every write to it.
+    assert!(appends.load(Ordering::SeqCst) <= 2);
+    let status = leader.group_status(GroupId(group_id)).await.unwrap();
+    let progress = status
+        .progress
+        .iter()
+        .find(|pr| pr.replica_id == unreachable)
+        .unwrap();
+    assert_eq!(progress.state, ProgressState::Probe);
+    let _ = stop_tx.send(true);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_initial_raft_groups_in_batch() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make(1, stop_rx).await;
+    let replicas = vec![ReplicaDesc {
+        node_id: 1,
+        replica_id: 1,
+    }];
+
+    let mut msgs = vec![];
+    for group_id in 1..=3 {
+        let gs = cluster.storages[0]
+            .group_storage(group_id, 1)
+            .await
+            .unwrap();
+        let mut ss = Snapshot::default();
+        ss.mut_metadata().mut_conf_state().voters = vec![1];
+        ss.mut_metadata().index = 1;
+        ss.mut_metadata().term = 1;
+        gs.apply_snapshot(ss).await.unwrap();
+
+        let mut msg = RaftGroupManagementMessage::default();
+        msg.set_msg_type(RaftGroupManagementMessageType::MsgInitialGroup);
+        msg.group_id = group_id;
+        msg.replica_id = 1;
+        msg.replicas = replicas.clone();
+        msgs.push(msg);
+    }
+    // the conflicting group fails without aborting the batch.
+    let mut conflict = msgs[0].clone();
+    conflict.replica_id = 2;
+    msgs.push(conflict);
+
+    let results = cluster.multirafts[0].initial_raft_groups(msgs).await;
+    assert_eq!(results.len(), 4);
+    for (i, (group_id, res)) in results.iter().enumerate() {
+        if i < 3 {
+            assert_eq!(*group_id, GroupId(i as u64 + 1));
+            assert!(res.is_ok());
+        } else {
+            assert_eq!(*group_id, GroupId(1));
+            assert!(res.is_err());
+        }
+    }
+
+    // the groups created in the batch are emitted in one event set.
+    let events = cluster.events[0].recv().await.unwrap();
+    let created = events
+        .iter()
+        .filter_map(|event| match event {
+            Event::GroupCreated(created) => Some(created.group_id),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(created, vec![1, 2, 3]);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_propose_idempotent_dedup_retries() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(1, stop_rx).await;
+    let applied = Arc::new(AtomicUsize::new(0));
+    let mut events = cluster.events.remove(0);
+    let counter = applied.clone();
+    tokio::spawn(async move {
+        while let Some(events) = events.recv().await {
+            for event in events {
+                if let Event::Apply(apply) = event {
+                    if !apply.entry.data.is_empty() {
+                        // the proposal context is unwrapped before applying.
+                        assert!(apply.entry.context.is_empty());
+                        counter.fetch_add(1, Ordering::SeqCst);
+                    }
+                    if let Some(tx) = apply.tx {
+                        let _ = tx.send(Ok(()));
+                    }
+                }
+            }
+        }
+    });
+
+    let group_id = 1;
+    cluster.make_group_with_campaign(group_id, 0, 1, true).await;
+    cluster.tick_all().await;
+
+    let multiraft = &cluster.multirafts[0];
+    for _ in 0..3 {
+        multiraft
+            .propose_idempotent(GroupId(group_id), b"data".to_vec(), 7, 1)
+            .await
+            .unwrap();
+    }
+    assert_eq!(applied.load(Ordering::SeqCst), 1);
+
+    multiraft
+        .propose_idempotent(GroupId(group_id), b"data".to_vec(), 7, 2)
+        .await
+        .unwrap();
+    assert_eq!(applied.load(Ordering::SeqCst), 2);
+    let _ = stop_tx.send(true);
+}
+
+/// Counts the entries applied by the state machine, the entries of data
+/// "reject" are rejected.
+struct CountingRejectStateMachine(Arc<AtomicUsize>);
+
+impl StateMachine for CountingRejectStateMachine {
+    fn apply(&self, apply: &ApplyEvent, _: &mut ApplyHandle) -> Result<ApplyOutput, ApplyError> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        match &apply.entry.data[..] {
+            b"reject" => Err(ApplyError::Reject("rejected".to_owned())),
+            _ => Ok(ApplyOutput),
+        }
+    }
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_dedup_table_installed_by_snapshot() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let applies = (0..3)
+        .map(|_| Arc::new(AtomicUsize::new(0)))
+        .collect::<Vec<_>>();
+    let extensions = applies
+        .iter()
+        .map(|applies| MultiRaftExtensions {
+            state_machine: Some(
+                Arc::new(CountingRejectStateMachine(applies.clone())) as Arc<dyn StateMachine>
+            ),
+            ..Default::default()
+        })
+        .collect();
+    let mut cluster = FixtureCluster::make_with_extensions(3, config, extensions, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+    let follower_id = leader_id % 3 + 1;
+    cluster.transport.isolate(follower_id);
+
+    // the retry of the rejected proposal isn't applied again, it's rejected
+    // with the reason of the original.
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    for _ in 0..2 {
+        match leader
+            .propose_idempotent(GroupId(group_id), b"reject".to_vec(), 7, 1)
+            .await
+        {
+            Err(Error::Proposal(ProposalError::Rejected(reason))) => {
+                assert_eq!(reason, "rejected")
+            }
+            res => panic!("expected the rejected proposal, got {:?}", res),
+        }
+    }
+    assert_eq!(applies[leader_id as usize - 1].load(Ordering::SeqCst), 1);
+
+    // the isolated follower catches up by the snapshot, which carries the
+    // dedup table, rather than applying the entry.
+    let meta = leader.trigger_snapshot(GroupId(group_id)).await.unwrap();
+    cluster.transport.reconnect(follower_id);
+    let status = cluster
+        .tick_until_status(group_id, follower_id as usize - 1, |status| {
+            status.applied_index >= meta.index
+        })
+        .await;
+    assert!(status.applied_index >= meta.index);
+    assert_eq!(applies[follower_id as usize - 1].load(Ordering::SeqCst), 0);
+
+    leader
+        .transfer_leader(GroupId(group_id), ReplicaId(follower_id))
+        .await
+        .unwrap();
+    let status = cluster
+        .tick_until_status(group_id, follower_id as usize - 1, |status| {
+            status.role == StateRole::Leader
+        })
+        .await;
+    assert_eq!(status.role, StateRole::Leader);
+
+    // the retry on the new leader is deduplicated by the installed table.
+    match cluster.multirafts[follower_id as usize - 1]
+        .propose_idempotent(GroupId(group_id), b"reject".to_vec(), 7, 1)
+        .await
+    {
+        Err(Error::Proposal(ProposalError::Rejected(reason))) => {
+            assert_eq!(reason, "rejected")
+        }
+        res => panic!("expected the rejected proposal, got {:?}", res),
+    }
+    assert_eq!(applies[follower_id as usize - 1].load(Ordering::SeqCst), 0);
+    let _ = stop_tx.send(true);
+}
+
+/// Appends the data of the applied entries to the state of the group, the
+/// state is carried by the snapshots.
+#[derive(Default)]
+struct LogStateMachine {
+    states: Mutex<HashMap<u64, Vec<u8>>>,
+}
+
+impl LogStateMachine {
+    fn state(&self, group_id: u64) -> Vec<u8> {
+        self.states
+            .lock()
+            .unwrap()
+            .get(&group_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl StateMachine for LogStateMachine {
+    fn apply(&self, apply: &ApplyEvent, _: &mut ApplyHandle) -> Result<ApplyOutput, ApplyError> {
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(apply.group_id).or_default();
+        state.extend_from_slice(&apply.entry.data);
+        state.push(b';');
+        Ok(ApplyOutput)
+    }
+
+    fn snapshot(&self, group_id: u64) -> Result<Vec<u8>, ApplyError> {
+        Ok(self.state(group_id))
+    }
+
+    fn restore(&self, group_id: u64, state: &[u8]) -> Result<(), ApplyError> {
+        self.states.lock().unwrap().insert(group_id, state.to_vec());
+        Ok(())
+    }
+}
+
+/// Returns the extensions of the nodes with the `LogStateMachine`.
+fn log_state_machines(num: usize) -> (Vec<Arc<LogStateMachine>>, Vec<MultiRaftExtensions>) {
+    let state_machines = (0..num)
+        .map(|_| Arc::new(LogStateMachine::default()))
+        .collect::<Vec<_>>();
+    let extensions = state_machines
+        .iter()
+        .map(|state_machine| MultiRaftExtensions {
+            state_machine: Some(state_machine.clone() as Arc<dyn StateMachine>),
+            ..Default::default()
+        })
+        .collect();
+    (state_machines, extensions)
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_state_machine_state_installed_by_snapshot() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let (state_machines, extensions) = log_state_machines(3);
+    let mut cluster = FixtureCluster::make_with_extensions(3, config, extensions, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+    let follower_id = leader_id % 3 + 1;
+    cluster.transport.isolate(follower_id);
+
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    for data in [b"a", b"b"] {
+        leader
+            .propose_timeout(
+                GroupId(group_id),
+                data.to_vec(),
+                vec![],
+                Duration::from_secs(5),
+            )
+            .await
+            .unwrap();
+    }
+
+    // the log is compacted behind the snapshot, the isolated follower catches
+    // up by installing it, which restores the state of the state machine.
+    let meta = leader.trigger_snapshot(GroupId(group_id)).await.unwrap();
+    let storage = &cluster.storages[leader_id as usize - 1];
+    let gs = storage.group_storage(group_id, leader_id).await.unwrap();
+    assert_eq!(gs.first_index().unwrap(), meta.index + 1);
+    cluster.transport.reconnect(follower_id);
+    cluster
+        .tick_until_status(group_id, follower_id as usize - 1, |status| {
+            status.applied_index >= meta.index
+        })
+        .await;
+    let follower = &state_machines[follower_id as usize - 1];
+    assert_eq!(follower.state(group_id), b"a;b;");
+
+    // the entries after the snapshot are applied on top of the state.
+    leader
+        .propose_timeout(
+            GroupId(group_id),
+            b"c".to_vec(),
+            vec![],
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+    let index = leader
+        .group_status(GroupId(group_id))
+        .await
+        .unwrap()
+        .applied_index;
+    cluster
+        .tick_until_status(group_id, follower_id as usize - 1, |status| {
+            status.applied_index >= index
+        })
+        .await;
+    assert_eq!(follower.state(group_id), b"a;b;c;");
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_prepare_shutdown_transfer_leader() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+
+    for mut events in std::mem::take(&mut cluster.events) {
+        tokio::spawn(async move { while let Some(_) = events.recv().await {} });
+    }
+
+    let leader_index = leader_id as usize - 1;
+    cluster.multirafts[leader_index].prepare_shutdown().await;
+
+    // without ticks, the new leader can only be elected by the transfer
+    // rather than the election timeout.
+    let follower_index = (0..3).find(|index| *index != leader_index).unwrap();
+    let mut new_leader = 0;
+    for _ in 0..100 {
+        let status = cluster.multirafts[follower_index]
+            .group_status(GroupId(group_id))
+            .await
+            .unwrap();
+        if status.leader_id.0 != 0 && status.leader_id.0 != leader_id {
+            new_leader = status.leader_id.0;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_ne!(new_leader, 0);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_proposal_forwarding_to_leader() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        proposal_forwarding: true,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(3, config, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+
+    // the state machines ack the applied entries.
+    cluster.ack_applies();
+
+    let follower_index = (0..3).find(|index| *index + 1 != leader_id as usize).unwrap();
+    let follower = &cluster.multirafts[follower_index];
+    let request = AppWriteRequest {
+        group_id,
+        term: 0,
+        data: b"data".to_vec(),
+        context: vec![],
+        ..Default::default()
+    };
+    let token = tokio::time::timeout(Duration::from_secs(1), follower.write(request))
+        .await
+        .unwrap()
+        .unwrap();
+
+    // the forwarded write is committed by the leader and covered by the
+    // token taken on the follower.
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let status = leader.group_status(GroupId(group_id)).await.unwrap();
+    assert!(status.commit_index >= token.index());
+    let applied = follower
+        .read_at_least(GroupId(group_id), &token, Duration::from_secs(1))
+        .await
+        .unwrap();
+    assert!(applied >= token.index());
+    let _ = stop_tx.send(true);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_embedded_single_node() {
+    let mut node = MultiRaft::embedded(1).await.unwrap();
+    let group_id = 1;
+    node.create_group(GroupId(group_id)).await.unwrap();
+    assert!(node.multiraft.is_leader(GroupId(group_id)));
+
+    // the apply events are acked by the node, the write completes without
+    // consuming the events.
+    let mut applied = Box::pin(node.multiraft.apply_results());
+    let request = AppWriteRequest {
+        group_id,
+        term: 0,
+        data: b"data".to_vec(),
+        context: vec![],
+        ..Default::default()
+    };
+    node.multiraft.write(request).await.unwrap();
+    let entry = tokio::time::timeout(Duration::from_secs(1), async {
+        loop {
+            let entry = applied.next().await.unwrap();
+            if entry.data == b"data" {
+                return entry;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(entry.group_id, group_id);
+
+    // the events are still forwarded to the receiver.
+    let events = tokio::time::timeout(Duration::from_secs(1), node.events.recv())
+        .await
+        .unwrap();
+    assert!(events.is_some());
+
+    // the transport is exposed for the partition hooks.
+    node.transport.isolate(1);
+    node.transport.reconnect(1);
+    let _ = node.stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_apply_results_replayed_to_late_subscriber() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        apply_results_retention: 4,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(1, config, stop_rx).await;
+    spawn_ack_applies(cluster.events.remove(0));
+
+    for group_id in 1..=2 {
+        cluster.make_group_with_campaign(group_id, 0, 1, true).await;
+    }
+    cluster.tick_all().await;
+    let multiraft = &cluster.multirafts[0];
+    let timeout = Duration::from_secs(5);
+    let mut indexes = vec![];
+    for i in 0..6u8 {
+        let token = multiraft
+            .propose_timeout(GroupId(1), vec![i], vec![], timeout)
+            .await
+            .unwrap();
+        indexes.push(token.index());
+    }
+
+    // the entries committed before subscribing are replayed from the index,
+    // then the stream switches to the live entries of the group.
+    let applied = multiraft.apply_results_from(GroupId(1), indexes[3]).unwrap();
+    let mut applied = Box::pin(applied);
+    multiraft
+        .propose_timeout(GroupId(2), vec![0], vec![], timeout)
+        .await
+        .unwrap();
+    multiraft
+        .propose_timeout(GroupId(1), vec![6], vec![], timeout)
+        .await
+        .unwrap();
+    let mut data = vec![];
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while data.len() < 4 {
+            let entry = applied.next().await.unwrap();
+            assert_eq!(entry.group_id, 1);
+            data.push(entry.data);
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(data, vec![vec![3], vec![4], vec![5], vec![6]]);
+
+    // only the last 4 entries are retained.
+    match multiraft.apply_results_from(GroupId(1), indexes[0]) {
+        Err(Error::ApplyResultsEvicted(1, from_index, evicted_index)) => {
+            assert_eq!(from_index, indexes[0]);
+            assert!(evicted_index >= indexes[2]);
+        }
+        _ => panic!("expect the results are evicted"),
+    }
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_read_committed_entries() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        // a read returns one entry at most.
+        max_read_committed_size: 1,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(1, config, stop_rx).await;
+    spawn_ack_applies(cluster.events.remove(0));
+
+    cluster.make_group_with_campaign(1, 0, 1, true).await;
+    cluster.tick_all().await;
+    let multiraft = &cluster.multirafts[0];
+    let mut indexes = vec![];
+    for i in 0..6u8 {
+        let token = multiraft
+            .propose_timeout(GroupId(1), vec![i], vec![], Duration::from_secs(5))
+            .await
+            .unwrap();
+        indexes.push(token.index());
+    }
+
+    // read the sub-range back by continuing from the last returned entry.
+    let mut data = vec![];
+    let mut next_index = indexes[1];
+    loop {
+        let entries = multiraft
+            .read_committed(GroupId(1), next_index, indexes[4])
+            .await
+            .unwrap();
+        if entries.is_empty() {
+            break;
+        }
+        assert_eq!(entries.len(), 1);
+        next_index = entries[0].index + 1;
+        match entry::decode_entry(&entries[0].data) {
+            EntryPayload::Data(d) => data.push(d.to_vec()),
+            payload => panic!("expect the data entry, got {:?}", payload),
+        }
+    }
+    assert_eq!(data, vec![vec![1], vec![2], vec![3]]);
+
+    // the range is truncated to the commit index.
+    let entries = multiraft
+        .read_committed(GroupId(1), indexes[5], u64::MAX)
+        .await
+        .unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(
+        entry::decode_entry(&entries[0].data),
+        EntryPayload::Data(&[5])
+    );
+    let entries = multiraft
+        .read_committed(GroupId(1), indexes[5] + 1, u64::MAX)
+        .await
+        .unwrap();
+    assert!(entries.is_empty());
+
+    // the internal entries before the proposals are returned as well, the
+    // no-op of the leader is the empty normal entry.
+    let mut noops = 0;
+    for index in 1..indexes[0] {
+        let entries = multiraft
+            .read_committed(GroupId(1), index, index + 1)
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].index, index);
+        if entries[0].entry_type() != EntryType::EntryNormal {
+            continue;
+        }
+        match entry::decode_entry(&entries[0].data) {
+            EntryPayload::Empty => noops += 1,
+            EntryPayload::Admin { .. } => {}
+            payload => panic!("expect the internal entry, got {:?}", payload),
+        }
+    }
+    assert!(noops > 0);
+
+    // the compacted entries must be caught up by the snapshot.
+    let meta = multiraft.trigger_snapshot(GroupId(1)).await.unwrap();
+    let res = multiraft
+        .read_committed(GroupId(1), indexes[0], u64::MAX)
+        .await;
+    match res {
+        Err(Error::LogCompacted(1, from_index, snapshot_index)) => {
+            assert_eq!(from_index, indexes[0]);
+            assert_eq!(snapshot_index, meta.index);
+        }
+        res => panic!("expect the log is compacted, got {:?}", res),
+    }
+    assert!(matches!(
+        multiraft.read_committed(GroupId(2), 1, 2).await,
+        Err(Error::RaftGroupNotFound(2))
+    ));
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_read_index_expired() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+
+    // the read index forwarded by the follower is lost, which raft never
+    // responds, so it's failed after an election timeout.
+    cluster.transport.set_filter(move |msg| {
+        if msg.msg.as_ref().map_or(false, |msg| {
+            msg.msg_type() == smol_raft::proto::MessageType::MsgReadIndex
+        }) {
+            return FilterAction::Drop;
+        }
+        FilterAction::Pass
+    });
+    let follower_id = (1..=3).find(|id| *id != leader_id).unwrap();
+    let follower = &cluster.multirafts[follower_id as usize - 1];
+    let read = follower.read_index(AppReadIndexRequest {
+        group_id,
+        ..Default::default()
+    });
+    tokio::pin!(read);
+    let mut ticks = 0;
+    let res = loop {
+        assert!(ticks < 100, "the read index is never failed");
+        tokio::select! {
+            res = &mut read => break res,
+            _ = cluster.tick_all() => ticks += 1,
+        }
+    };
+    assert_eq!(res, Err(Error::Proposal(ProposalError::Dropped)));
+    assert!(ticks >= 3, "failed after {} ticks", ticks);
+
+    // the read index is served once the forwarding recovers.
+    cluster.transport.set_filter(|_| FilterAction::Pass);
+    let read = follower.read_index(AppReadIndexRequest {
+        group_id,
+        ..Default::default()
+    });
+    tokio::pin!(read);
+    let mut ticks = 0;
+    let res = loop {
+        assert!(ticks < 100, "the read index is never served");
+        tokio::select! {
+            res = &mut read => break res,
+            _ = cluster.tick_all() => ticks += 1,
+        }
+    };
+    assert!(res.is_ok(), "{:?}", res);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_multi_read_index_colocated_groups() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    for group_id in 1..=2 {
+        cluster.make_group(group_id, 0, 3).await;
+    }
+    let leader_id = cluster.tick_until_leader(1, &[0, 1, 2]).await.unwrap();
+    // the groups are led by the same node.
+    for _ in 0..10 {
+        let group_leader = cluster.tick_until_leader(2, &[0, 1, 2]).await.unwrap();
+        if group_leader == leader_id {
+            break;
+        }
+        let _ = cluster.multirafts[group_leader as usize - 1]
+            .transfer_leader(GroupId(2), ReplicaId(leader_id))
+            .await;
+    }
+    cluster.ack_applies();
+
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    assert!(leader.is_leader(GroupId(2)));
+    let timeout = Duration::from_secs(5);
+    let group_ids = [GroupId(1), GroupId(2)];
+    let mut indexes = vec![];
+    for group_id in group_ids {
+        let token = leader
+            .propose_timeout(group_id, b"data".to_vec(), vec![], timeout)
+            .await
+            .unwrap();
+        indexes.push(token.index());
+    }
+
+    // the read indexes are confirmed by the heartbeats sent at once.
+    let read_states = tokio::time::timeout(timeout, leader.multi_read_index(&group_ids))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read_states.len(), 2);
+    for (i, read_state) in read_states.iter().enumerate() {
+        assert_eq!(read_state.group_id, GroupId(i as u64 + 1));
+        assert_eq!(read_state.replica_id, ReplicaId(leader_id));
+        assert!(read_state.commit_index >= indexes[i]);
+        assert!(read_state.applied_index >= read_state.commit_index);
+    }
+
+    // the whole call fails if any group isn't led by the node.
+    let follower = &cluster.multirafts[leader_id as usize % 3];
+    assert!(matches!(
+        follower.multi_read_index(&group_ids).await,
+        Err(Error::Raft(_))
+    ));
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_follower_apply_proposal_context() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+
+    let (context_tx, mut context_rx) = tokio::sync::mpsc::unbounded_channel();
+    for (node_index, mut events) in std::mem::take(&mut cluster.events)
+        .into_iter()
+        .enumerate()
+    {
+        let context_tx = context_tx.clone();
+        tokio::spawn(async move {
+            while let Some(events) = events.recv().await {
+                for mut event in events {
+                    if let Event::Apply(apply) = &mut event {
+                        if apply.entry.data == b"data" {
+                            let _ = context_tx.send((node_index, apply.context().to_vec()));
+                        }
+                        if let Some(tx) = apply.tx.take() {
+                            let _ = tx.send(Ok(()));
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    let request = AppWriteRequest {
+        group_id,
+        term: 0,
+        data: b"data".to_vec(),
+        context: b"context".to_vec(),
+        ..Default::default()
+    };
+    let leader_index = (leader_id - 1) as usize;
+    cluster.multirafts[leader_index].write(request).await.unwrap();
+
+    // the followers learn the commit index by the following heartbeats.
+    let mut contexts = HashMap::new();
+    for _ in 0..100 {
+        while let Ok((node_index, context)) = context_rx.try_recv() {
+            contexts.insert(node_index, context);
+        }
+        if contexts.len() == 3 {
+            break;
+        }
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(contexts.len(), 3);
+    for node_index in 0..3 {
+        assert_eq!(contexts[&node_index], b"context".to_vec());
+    }
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_follower_light_tick() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 10,
+        heartbeat_tick: 2,
+        follower_light_tick: true,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let (_, max_election_tick) = config.election_tick_range();
+    let mut cluster = FixtureCluster::make_with_config(3, config, stop_rx).await;
+    let groups = 10;
+    for group_id in 1..=groups {
+        cluster.make_group(group_id, 0, 3).await;
+        cluster.multirafts[0]
+            .campagin(GroupId(group_id))
+            .await
+            .unwrap();
+    }
+
+    let mut leaders = HashMap::new();
+    for _ in 0..50 {
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        cluster.drain_leaders(1, &mut leaders);
+    }
+
+    // most ticks of the idle followers only advance the election timer.
+    for node_index in 1..3 {
+        for group_id in 1..=groups {
+            let counters = cluster.multirafts[node_index]
+                .group_counters(GroupId(group_id))
+                .await
+                .unwrap();
+            assert!(
+                counters.light_ticks * 2 > counters.ticks,
+                "group {} node {} counters {:?}",
+                group_id,
+                node_index,
+                counters
+            );
+        }
+    }
+
+    // the followers still detect the leader loss within the election timeout.
+    let group_id = 1;
+    let leader_id = leaders[&1];
+    cluster.transport.isolate(leader_id);
+    let majority = (0..3)
+        .filter(|node_index| *node_index != (leader_id - 1) as usize)
+        .collect::<Vec<_>>();
+    let mut new_leaders = HashMap::new();
+    let mut elected = false;
+    for _ in 0..2 * max_election_tick {
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        cluster.drain_leaders(group_id, &mut new_leaders);
+        elected = majority.iter().all(|node_index| {
+            new_leaders
+                .get(node_index)
+                .map_or(false, |id| *id != leader_id)
+        });
+        if elected {
+            break;
+        }
+    }
+    assert!(elected);
+    cluster.transport.reconnect(leader_id);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_removed_leader_transfer_leader() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 10,
+        heartbeat_tick: 2,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let (min_election_tick, _) = config.election_tick_range();
+    let mut cluster = FixtureCluster::make_with_config(3, config, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    cluster.multirafts[0]
+        .campagin(GroupId(group_id))
+        .await
+        .unwrap();
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+
+    let mut change = MembershipChangeRequest {
+        group_id,
+        node_id: leader_id,
+        replica_id: leader_id,
+        ..Default::default()
+    };
+    change.set_change_type(ConfChangeType::RemoveNode);
+    let data = MembershipChangeData {
+        group_id,
+        changes: vec![change],
+        ..Default::default()
+    };
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    leader.propose_conf_change(data).await.unwrap();
+
+    // a survivor is elected well before the election timeout elapses.
+    let survivors = (0..3)
+        .filter(|node_index| *node_index != leader_id as usize - 1)
+        .collect::<Vec<_>>();
+    let mut elected = false;
+    for _ in 0..min_election_tick / 2 {
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        for node_index in survivors.iter() {
+            let groups = cluster.multirafts[*node_index].list_groups().await;
+            elected |= groups.contains(&(GroupId(group_id), ReplicaRole::Leader));
+        }
+        if elected {
+            break;
+        }
+    }
+    assert!(elected);
+    let _ = stop_tx.send(true);
+}
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_demote_follower_to_learner() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+    let followers = (1..=3)
+        .filter(|replica_id| *replica_id != leader_id)
+        .collect::<Vec<_>>();
+    let (demoted_id, voter_id) = (followers[0], followers[1]);
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let propose = |timeout_ms| {
+        leader.propose_timeout(
+            GroupId(group_id),
+            b"data".to_vec(),
+            vec![],
+            Duration::from_millis(timeout_ms),
+        )
+    };
+
+    // the leader and the replica which isn't a voter can't be demoted, and
+    // the demotion must be called on the leader.
+    assert_eq!(
+        leader
+            .demote_to_learner(GroupId(group_id), ReplicaId(leader_id))
+            .await,
+        Err(Error::DemoteLeader(group_id, leader_id))
+    );
+    assert_eq!(
+        leader
+            .demote_to_learner(GroupId(group_id), ReplicaId(4u64))
+            .await,
+        Err(Error::ReplicaNotVoter(group_id, 4))
+    );
+    let follower = &cluster.multirafts[voter_id as usize - 1];
+    assert!(matches!(
+        follower
+            .demote_to_learner(GroupId(group_id), ReplicaId(demoted_id))
+            .await,
+        Err(Error::Raft(_))
+    ));
+
+    // the leader and the follower to be demoted are a majority of 3 voters.
+    cluster.transport.isolate(voter_id);
+    propose(1000).await.unwrap();
+    cluster.transport.reconnect(voter_id);
+
+    leader
+        .demote_to_learner(GroupId(group_id), ReplicaId(demoted_id))
+        .await
+        .unwrap();
+    let mut demoted = false;
+    for _ in 0..100 {
+        let cs = leader.conf_state(GroupId(group_id)).await.unwrap();
+        let groups = cluster.multirafts[demoted_id as usize - 1]
+            .list_groups()
+            .await;
+        if cs.learners == vec![demoted_id]
+            && groups.contains(&(GroupId(group_id), ReplicaRole::Learner))
+        {
+            demoted = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(demoted);
+    let status = leader.group_status(GroupId(group_id)).await.unwrap();
+    let mut voters = status.voters.clone();
+    voters.sort();
+    let mut expected = vec![leader_id, voter_id];
+    expected.sort();
+    assert_eq!(voters, expected);
+    assert_eq!(status.learners, vec![demoted_id]);
+
+    // the learner doesn't count, the commit requires both remaining voters.
+    cluster.transport.isolate(voter_id);
+    assert_eq!(
+        propose(200).await.unwrap_err(),
+        Error::Proposal(ProposalError::Timeout)
+    );
+    cluster.transport.reconnect(voter_id);
+    cluster.tick_all().await;
+
+    cluster.transport.isolate(demoted_id);
+    propose(1000).await.unwrap();
+    cluster.transport.reconnect(demoted_id);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_group_status_replica_lag() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+
+    // the isolated follower falls behind the writes.
+    let follower_id = (1..=3).find(|id| *id != leader_id).unwrap();
+    cluster.transport.isolate(follower_id);
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    for _ in 0..3 {
+        let request = AppWriteRequest {
+            group_id,
+            term: 0,
+            data: b"data".to_vec(),
+            context: vec![],
+            ..Default::default()
+        };
+        leader.write(request).await.unwrap();
+    }
+
+    let status = leader.group_status(GroupId(group_id)).await.unwrap();
+    assert_eq!(status.progress.len(), 3);
+    for pr in status.progress.iter() {
+        // replica id i is located at node id i.
+        assert_eq!(pr.node_id, pr.replica_id);
+        assert_eq!(pr.lag, status.commit_index - pr.matched);
+    }
+    let lagging = status
+        .progress
+        .iter()
+        .find(|pr| pr.replica_id == follower_id)
+        .unwrap();
+    assert!(lagging.lag >= 3);
+    assert!(!lagging.snapshot_inflight);
+    cluster.transport.reconnect(follower_id);
+    let _ = stop_tx.send(true);
+}
+
+/// Run two candidates campaigning in the same term on the simulator seeded by
+/// `seed`, returns the roles of the group on each node.
+#[cfg(feature = "test-util")]
+async fn run_simulated_split_vote(seed: u64) -> Vec<Vec<(GroupId, ReplicaRole)>> {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 5,
+        heartbeat_tick: 1,
+        min_election_tick: 5,
+        max_election_tick: 5,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(3, config, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    cluster.ack_applies();
+
+    let sim = SimNetwork::attach(&cluster.transport, Some(seed));
+    cluster.multirafts[0]
+        .campagin(GroupId(group_id))
+        .await
+        .unwrap();
+    cluster.multirafts[1]
+        .campagin(GroupId(group_id))
+        .await
+        .unwrap();
+    // the ticks are not needed, the votes and the appends are exchanged by
+    // the deliveries, the nodes are flushed in order after each one.
+    for _ in 0..10 {
+        for multiraft in cluster.multirafts.iter() {
+            multiraft.flush().await;
+        }
+        if sim.deliver_all().await == 0 {
+            break;
+        }
+    }
+
+    let mut roles = vec![];
+    for multiraft in cluster.multirafts.iter() {
+        roles.push(multiraft.list_groups().await);
+    }
+    drop(sim);
+    let _ = stop_tx.send(true);
+    roles
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_simulated_split_vote_reproducible() {
+    let seed = 42;
+    let roles = run_simulated_split_vote(seed).await;
+    // the vote of node 3 decides which candidate wins.
+    let leaders = roles
+        .iter()
+        .enumerate()
+        .filter(|(_, roles)| roles.contains(&(GroupId(1), ReplicaRole::Leader)))
+        .map(|(node_index, _)| node_index)
+        .collect::<Vec<_>>();
+    assert_eq!(leaders.len(), 1);
+    assert!(leaders[0] < 2);
+
+    // the same seed reproduces the same interleaving.
+    for _ in 0..3 {
+        assert_eq!(run_simulated_split_vote(seed).await, roles);
+    }
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_max_uncommitted_size_drop_proposals() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        max_uncommitted_size: 1024,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(3, config, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+
+    // the partitioned leader buffers the proposals until the uncommitted
+    // entries reach the limit, then the proposals are dropped.
+    cluster.transport.isolate(leader_id);
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let mut results = vec![];
+    for _ in 0..20 {
+        let res = leader
+            .propose_timeout(
+                GroupId(group_id),
+                vec![0; 256],
+                vec![],
+                Duration::from_millis(50),
+            )
+            .await;
+        results.push(res.unwrap_err());
+    }
+    assert_eq!(results[0], Error::Proposal(ProposalError::Timeout));
+    let dropped = results
+        .iter()
+        .position(|err| *err == Error::Proposal(ProposalError::Dropped))
+        .unwrap();
+    assert!(dropped <= 5);
+    assert!(results[dropped..]
+        .iter()
+        .all(|err| *err == Error::Proposal(ProposalError::Dropped)));
+
+    cluster.transport.reconnect(leader_id);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_max_client_pending_proposals_throttles_client() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        max_client_pending_proposals: 2,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(3, config, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+
+    // the partitioned leader keeps the proposals pending, the flooding client
+    // is throttled once its pending proposals reach the limit. The futures
+    // are kept, so the pending proposals aren't cancelled.
+    cluster.transport.isolate(leader_id);
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let propose = |client_id: u64, sequence| {
+        let data = vec![client_id as u8];
+        Box::pin(leader.propose_idempotent(GroupId(group_id), data, client_id, sequence))
+    };
+    let wait = Duration::from_millis(50);
+    let mut flooding = vec![];
+    for sequence in 1..=2 {
+        let mut proposal = propose(1, sequence);
+        assert!(tokio::time::timeout(wait, &mut proposal).await.is_err());
+        flooding.push(proposal);
+    }
+    assert_eq!(
+        propose(1, 3).await.unwrap_err(),
+        Error::Proposal(ProposalError::ClientQuotaExceeded(1, 2))
+    );
+
+    // the proposal of the other client is still accepted.
+    let mut other = propose(2, 1);
+    assert!(tokio::time::timeout(wait, &mut other).await.is_err());
+
+    // the pending proposals of both clients are committed after the partition
+    // heals, which releases the quota of the flooding client.
+    cluster.transport.reconnect(leader_id);
+    let mut committed = false;
+    for _ in 0..20 {
+        cluster.tick_all().await;
+        if let Ok(res) = tokio::time::timeout(wait, &mut other).await {
+            assert!(res.is_ok());
+            committed = true;
+            break;
+        }
+    }
+    assert!(committed, "the proposal of the other client is stuck");
+    let timeout = Duration::from_secs(5);
+    for proposal in flooding {
+        assert!(tokio::time::timeout(timeout, proposal)
+            .await
+            .unwrap()
+            .is_ok());
+    }
+    assert!(tokio::time::timeout(timeout, propose(1, 3))
+        .await
+        .unwrap()
+        .is_ok());
+    leader
+        .propose_timeout(GroupId(group_id), vec![2], vec![], timeout)
+        .await
+        .unwrap();
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_max_entry_size_rejects_proposals() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        max_entry_size: 1024,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(1, config, stop_rx).await;
+    spawn_ack_applies(cluster.events.remove(0));
+
+    let group_id = 1;
+    cluster.make_group_with_campaign(group_id, 0, 1, true).await;
+    cluster.tick_all().await;
+    let multiraft = &cluster.multirafts[0];
+    multiraft
+        .propose_timeout(
+            GroupId(group_id),
+            vec![0; 1000],
+            vec![0; 24],
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+    let commit = multiraft
+        .group_status(GroupId(group_id))
+        .await
+        .unwrap()
+        .commit_index;
+
+    // the data and context together exceed the limit.
+    let res = multiraft
+        .propose_timeout(
+            GroupId(group_id),
+            vec![0; 1000],
+            vec![0; 25],
+            Duration::from_secs(5),
+        )
+        .await;
+    assert_eq!(
+        res.unwrap_err(),
+        Error::Proposal(ProposalError::EntryTooLarge(1025, 1024))
+    );
+
+    // the rejected proposal never enters the log.
+    cluster.tick_all().await;
+    let status = multiraft.group_status(GroupId(group_id)).await.unwrap();
+    assert_eq!(status.commit_index, commit);
+    let storage = cluster.storages[0]
+        .memory_storage(group_id)
+        .await
+        .unwrap();
+    assert_eq!(storage.last_index().unwrap(), commit);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_trigger_snapshot_compact_log() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let mut token = None;
+    for i in 0..5u8 {
+        token = Some(
+            leader
+                .propose_timeout(GroupId(group_id), vec![i], vec![], Duration::from_secs(5))
+                .await
+                .unwrap(),
+        );
+    }
+    let index = token.unwrap().index();
+    let mut applied = leader.applied_watch(GroupId(group_id)).await.unwrap();
+    while *applied.borrow() < index {
+        applied.changed().await.unwrap();
+    }
+
+    let meta = leader.trigger_snapshot(GroupId(group_id)).await.unwrap();
+    assert!(meta.index >= index);
+    let storage = &cluster.storages[leader_id as usize - 1];
+    let gs = storage.group_storage(group_id, leader_id).await.unwrap();
+    assert_eq!(gs.first_index().unwrap(), meta.index + 1);
+    assert_eq!(storage.snapshot_metadata(group_id).await.unwrap(), meta);
+
+    // the applied index doesn't advance, the latest snapshot is returned.
+    assert_eq!(
+        leader.trigger_snapshot(GroupId(group_id)).await.unwrap(),
+        meta
+    );
+
+    // the group keeps replicating after the compaction.
+    leader
+        .propose_timeout(GroupId(group_id), vec![5], vec![], Duration::from_secs(5))
+        .await
+        .unwrap();
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_heartbeat_round_trip_latency() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    let follower_id = (1..=3).find(|id| *id != leader_id).unwrap();
+
+    // the replica id is the node id in the fixture.
+    let latency = Duration::from_millis(20);
+    cluster
+        .transport
+        .set_link_latency(leader_id, follower_id, latency);
+    cluster
+        .transport
+        .set_link_latency(follower_id, leader_id, latency);
+    // enough samples for the EWMA to forget the round trips before the
+    // latency is injected.
+    for _ in 0..15 {
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let status = leader.group_status(GroupId(group_id)).await.unwrap();
+    let pr = status
+        .progress
+        .iter()
+        .find(|pr| pr.replica_id == follower_id)
+        .unwrap();
+    assert!(pr.rtt.unwrap() >= 2 * latency);
+
+    let latencies = leader.node_latencies();
+    let to_follower = latencies
+        .iter()
+        .find(|latency| latency.to_node == follower_id)
+        .unwrap();
+    assert_eq!(to_follower.from_node, leader_id);
+    assert!(to_follower.samples > 0);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_ordered_apply_across_groups() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        ordered_apply: true,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(1, config, stop_rx).await;
+    let groups = [1, 2, 3, 4];
+    for group_id in groups {
+        cluster.make_group_with_campaign(group_id, 0, 1, true).await;
+    }
+    for group_id in groups {
+        assert_eq!(cluster.tick_until_leader(group_id, &[0]).await, Some(1));
+    }
+
+    // each batch of the apply events is ordered by (group_id, index).
+    let batches = Arc::new(Mutex::new(vec![]));
+    let mut events = std::mem::take(&mut cluster.events).remove(0);
+    let recorded = batches.clone();
+    tokio::spawn(async move {
+        while let Some(events) = events.recv().await {
+            let mut batch = vec![];
+            for event in events {
+                if let Event::Apply(apply) = event {
+                    batch.push((apply.group_id, apply.entry.index));
+                    if let Some(tx) = apply.tx {
+                        let _ = tx.send(Ok(()));
+                    }
+                }
+            }
+            recorded.lock().unwrap().push(batch);
+        }
+    });
+
+    let multiraft = &cluster.multirafts[0];
+    let mut applied = Box::pin(multiraft.apply_results());
+    let proposals = (0..10u8).flat_map(|i| {
+        groups.iter().map(move |group_id| {
+            multiraft.propose_timeout(GroupId(*group_id), vec![i], vec![], Duration::from_secs(5))
+        })
+    });
+    for result in futures::future::join_all(proposals).await {
+        result.unwrap();
+    }
+
+    let mut stream = vec![];
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while stream.iter().filter(|(_, _, _, data)| *data).count() < 40 {
+            let entry = applied.next().await.unwrap();
+            stream.push((
+                entry.apply_round,
+                entry.group_id,
+                entry.index,
+                !entry.data.is_empty(),
+            ));
+        }
+    })
+    .await
+    .unwrap();
+    assert!(stream
+        .windows(2)
+        .all(|w| (w[0].0, w[0].1, w[0].2) < (w[1].0, w[1].1, w[1].2)));
+
+    for batch in batches.lock().unwrap().iter() {
+        assert!(batch.windows(2).all(|w| w[0] < w[1]));
+    }
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_propose_with_retry_until_leader_elected() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(1, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 1).await;
+    cluster.ack_applies();
+
+    // the proposal fails with `NotLeader` until the group elects the leader.
+    let multiraft = &cluster.multirafts[0];
+    let policy = RetryPolicy {
+        max_attempts: 100,
+        ..Default::default()
+    };
+    let propose = multiraft.propose_with_retry(GroupId(group_id), vec![1], vec![], policy);
+    let tick = async {
+        for _ in 0..10 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            multiraft.tick().await;
+        }
+    };
+    let (res, _) = tokio::join!(propose, tick);
+    assert!(res.unwrap().index() > 0);
+
+    // the non-retryable error is returned immediately.
+    assert_eq!(
+        multiraft
+            .propose_with_retry(GroupId(2), vec![1], vec![], policy)
+            .await
+            .unwrap_err(),
+        Error::RaftGroupNotFound(2)
+    );
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_propose_with_retry_not_applied_twice() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(1, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group_with_campaign(group_id, 0, 1, true).await;
+    assert_eq!(cluster.tick_until_leader(group_id, &[0]).await, Some(1));
+
+    // the first apply is acked later than the attempt timeout, so the
+    // proposal is retried after it has been applied.
+    let applies = Arc::new(AtomicUsize::new(0));
+    let mut events = std::mem::take(&mut cluster.events).remove(0);
+    let counter = applies.clone();
+    tokio::spawn(async move {
+        while let Some(events) = events.recv().await {
+            for event in events {
+                if let Event::Apply(apply) = event {
+                    if apply.entry.data.is_empty() {
+                        continue;
+                    }
+                    if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                    }
+                    if let Some(tx) = apply.tx {
+                        let _ = tx.send(Ok(()));
+                    }
+                }
+            }
+        }
+    });
+
+    let policy = RetryPolicy {
+        attempt_timeout: Duration::from_millis(50),
+        ..Default::default()
+    };
+    cluster.multirafts[0]
+        .propose_with_retry(GroupId(group_id), b"data".to_vec(), vec![], policy)
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(applies.load(Ordering::SeqCst), 1);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_restore_group_from_snapshot() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        proposal_forwarding: true,
+        ..Default::default()
+    };
+    let (state_machines, extensions) = log_state_machines(3);
+    let mut cluster = FixtureCluster::make_with_extensions(3, config, extensions, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+
+    // take the backup of group 1.
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    for i in 0..5u8 {
+        leader
+            .propose_timeout(GroupId(group_id), vec![i], vec![], Duration::from_secs(5))
+            .await
+            .unwrap();
+    }
+    let meta = leader.trigger_snapshot(GroupId(group_id)).await.unwrap();
+    let backup = cluster.storages[leader_id as usize - 1]
+        .group_storage(group_id, leader_id)
+        .await
+        .unwrap()
+        .snapshot(meta.index)
+        .unwrap();
+    let state = state_machines[leader_id as usize - 1].state(group_id);
+    assert_eq!(state, b"\0;\x01;\x02;\x03;\x04;");
+
+    let restored_id = 2;
+    let replicas = (1..=3)
+        .map(|id| ReplicaDesc {
+            node_id: id,
+            replica_id: id,
+        })
+        .collect::<Vec<_>>();
+    let restore_msg = |replica_id: u64, replicas: Vec<ReplicaDesc>| {
+        let mut msg = RaftGroupManagementMessage::default();
+        msg.set_msg_type(RaftGroupManagementMessageType::MsgInitialGroup);
+        msg.group_id = restored_id;
+        msg.replica_id = replica_id;
+        msg.replicas = replicas;
+        msg
+    };
+
+    // the replicas mismatch the conf state of the snapshot.
+    let res = cluster.multirafts[0]
+        .restore_group(restore_msg(1, replicas[..2].to_vec()), backup.clone())
+        .await;
+    assert!(matches!(res, Err(Error::BadParameter(_))));
+    // the storage created for the rejected restore is removed.
+    let storage = cluster.storages[0].memory_storage(restored_id).await;
+    assert!(storage.is_none());
+
+    for (node_index, multiraft) in cluster.multirafts.iter().enumerate() {
+        multiraft
+            .restore_group(
+                restore_msg(node_index as u64 + 1, replicas.clone()),
+                backup.clone(),
+            )
+            .await
+            .unwrap();
+        let gs = cluster.storages[node_index]
+            .group_storage(restored_id, node_index as u64 + 1)
+            .await
+            .unwrap();
+        assert_eq!(gs.first_index().unwrap(), meta.index + 1);
+    }
+
+    // the state of the snapshot is restored to the state machines.
+    for state_machine in state_machines.iter() {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while state_machine.state(restored_id) != state && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(state_machine.state(restored_id), state);
+    }
+
+    // the restored group elects the leader and continues replicating.
+    let multiraft = &cluster.multirafts[0];
+    let policy = RetryPolicy {
+        max_attempts: 100,
+        ..Default::default()
+    };
+    let propose = multiraft.propose_with_retry(GroupId(restored_id), vec![9], vec![], policy);
+    let tick = async {
+        for _ in 0..20 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            cluster.tick_all().await;
+        }
+    };
+    let (res, _) = tokio::join!(propose, tick);
+    assert!(res.unwrap().index() > meta.index);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_export_snapshot_and_restore() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let (state_machines, extensions) = log_state_machines(3);
+    let mut cluster = FixtureCluster::make_with_extensions(3, config, extensions, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    for i in 0..5u8 {
+        leader
+            .propose_timeout(GroupId(group_id), vec![i], vec![], Duration::from_secs(5))
+            .await
+            .unwrap();
+    }
+    let gs = cluster.storages[leader_id as usize - 1]
+        .group_storage(group_id, leader_id)
+        .await
+        .unwrap();
+    let first_index = gs.first_index().unwrap();
+    let latest = cluster.storages[leader_id as usize - 1]
+        .snapshot_metadata(group_id)
+        .await
+        .unwrap();
+
+    let (meta, data) = leader.export_snapshot(GroupId(group_id)).await.unwrap();
+    let status = leader.group_status(GroupId(group_id)).await.unwrap();
+    assert_eq!(meta.index, status.applied_index);
+    let mut voters = meta.conf_state.clone().unwrap_or_default().voters;
+    voters.sort();
+    assert_eq!(voters, vec![1, 2, 3]);
+    // the data carries the state of the state machine at the snapshot index.
+    let state = <SnapshotData as prost::Message>::decode(data.as_slice())
+        .unwrap()
+        .state;
+    assert_eq!(state, b"\0;\x01;\x02;\x03;\x04;".to_vec());
+
+    // exporting neither compacts the log nor replaces the saved snapshot.
+    assert_eq!(gs.first_index().unwrap(), first_index);
+    assert_eq!(
+        cluster.storages[leader_id as usize - 1]
+            .snapshot_metadata(group_id)
+            .await
+            .unwrap(),
+        latest
+    );
+    leader
+        .propose_timeout(GroupId(group_id), vec![5], vec![], Duration::from_secs(5))
+        .await
+        .unwrap();
+
+    // restore the exported snapshot into a fresh group.
+    let restored_id = 2;
+    let replicas = (1..=3)
+        .map(|id| ReplicaDesc {
+            node_id: id,
+            replica_id: id,
+        })
+        .collect::<Vec<_>>();
+    let mut backup = Snapshot::default();
+    *backup.mut_metadata() = meta.clone();
+    backup.data = data.clone();
+    for (node_index, multiraft) in cluster.multirafts.iter().enumerate() {
+        let mut msg = RaftGroupManagementMessage::default();
+        msg.set_msg_type(RaftGroupManagementMessageType::MsgInitialGroup);
+        msg.group_id = restored_id;
+        msg.replica_id = node_index as u64 + 1;
+        msg.replicas = replicas.clone();
+        multiraft.restore_group(msg, backup.clone()).await.unwrap();
+    }
+
+    // the restored group exports the same snapshot, it's taken after the
+    // state machines are restored from the data.
+    for (node_index, multiraft) in cluster.multirafts.iter().enumerate() {
+        let (restored_meta, restored_data) = multiraft
+            .export_snapshot(GroupId(restored_id))
+            .await
+            .unwrap();
+        assert_eq!(restored_meta.index, meta.index);
+        assert_eq!(restored_meta.term, meta.term);
+        assert_eq!(restored_meta.conf_state, meta.conf_state);
+        assert_eq!(restored_data, data);
+        assert_eq!(state_machines[node_index].state(restored_id), state);
+    }
+    assert_eq!(
+        state_machines[leader_id as usize - 1].state(group_id),
+        b"\0;\x01;\x02;\x03;\x04;\x05;".to_vec()
+    );
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_role_watch() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+
+    let mut watches = vec![];
+    for multiraft in cluster.multirafts.iter() {
+        let watch = multiraft.role_watch(GroupId(group_id)).await.unwrap();
+        assert_eq!(*watch.borrow(), StateRole::Follower);
+        watches.push(watch);
+    }
+    assert!(cluster.multirafts[0].role_watch(GroupId(2)).await.is_err());
+
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    let leader_index = leader_id as usize - 1;
+    let leader_watch = &mut watches[leader_index];
+    tokio::time::timeout(Duration::from_secs(1), async {
+        while *leader_watch.borrow() != StateRole::Leader {
+            leader_watch.changed().await.unwrap();
+        }
+    })
+    .await
+    .unwrap();
+
+    // the initial value is the role at subscription.
+    let watch = cluster.multirafts[leader_index]
+        .role_watch(GroupId(group_id))
+        .await
+        .unwrap();
+    assert_eq!(*watch.borrow(), StateRole::Leader);
+
+    // the watch is closed after the group is removed.
+    let follower_index = (0..3).find(|index| *index != leader_index).unwrap();
+    cluster.multirafts[follower_index]
+        .remove_group(GroupId(group_id))
+        .await
+        .unwrap();
+    let follower_watch = &mut watches[follower_index];
+    tokio::time::timeout(Duration::from_secs(1), async {
+        while follower_watch.changed().await.is_ok() {}
+    })
+    .await
+    .unwrap();
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_leader_priority_converge() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+
+    // prefer a follower as the leader.
+    let preferred = (1..=3).find(|id| *id != leader_id).unwrap();
+    for multiraft in cluster.multirafts.iter() {
+        multiraft
+            .set_leader_priority(GroupId(group_id), ReplicaId(preferred), 10)
+            .await
+            .unwrap();
+    }
+    assert!(cluster.multirafts[0]
+        .set_leader_priority(GroupId(2), ReplicaId(preferred), 10)
+        .await
+        .is_err());
+
+    let mut leaders = HashMap::new();
+    for _ in 0..50 {
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        cluster.drain_leaders(group_id, &mut leaders);
+        if (0..3).all(|index| leaders.get(&index) == Some(&preferred)) {
+            break;
+        }
+    }
+    let status = cluster.multirafts[preferred as usize - 1]
+        .group_status(GroupId(group_id))
+        .await
+        .unwrap();
+    assert_eq!(status.role, StateRole::Leader);
+
+    // the leader of the highest priority keeps the leadership.
+    for _ in 0..10 {
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let status = cluster.multirafts[preferred as usize - 1]
+        .group_status(GroupId(group_id))
+        .await
+        .unwrap();
+    assert_eq!(status.role, StateRole::Leader);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pending_messages_replayed_after_group_created() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        pending_group_messages: 16,
+        pending_group_message_ttl: 60 * 1000,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(3, config, stop_rx).await;
+    let group_id = 1;
+    // the replica on node 3 isn't created yet.
+    cluster.make_group_replica(group_id, 0, 3, 0, false).await;
+    cluster.make_group_replica(group_id, 0, 3, 1, false).await;
+    let leader_id = cluster.tick_until_leader(group_id, &[0, 1]).await.unwrap();
+    cluster.ack_applies();
+
+    // the appends to node 3 are buffered rather than creating the group.
+    let token = cluster.multirafts[leader_id as usize - 1]
+        .propose_timeout(GroupId(group_id), vec![1], vec![], Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert!(cluster.multirafts[2]
+        .group_status(GroupId(group_id))
+        .await
+        .is_none());
+
+    cluster.make_group_replica(group_id, 0, 3, 2, false).await;
+    // the buffered append is replayed once the group is created, so node 3
+    // follows the leader before any tick sends the heartbeat.
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let mut status = cluster.multirafts[2]
+        .group_status(GroupId(group_id))
+        .await
+        .unwrap();
+    while status.leader_id.0 != leader_id && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        status = cluster.multirafts[2]
+            .group_status(GroupId(group_id))
+            .await
+            .unwrap();
+    }
+    assert_eq!(status.leader_id.0, leader_id);
+
+    let mut applied = cluster.multirafts[2]
+        .applied_watch(GroupId(group_id))
+        .await
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while *applied.borrow() < token.index() {
+            cluster.tick_all().await;
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            if *applied.borrow() < token.index() {
+                let _ = tokio::time::timeout(Duration::from_millis(10), applied.changed()).await;
+            }
+        }
+    })
+    .await
+    .unwrap();
+
+    let dropped = cluster.multirafts[2].dropped_message_counts();
+    for reason in [DropReason::PendingOverflow, DropReason::PendingExpired] {
+        assert!(dropped.contains(&(reason, 0)));
+    }
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_propose_conditional_compare_and_set() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(1, stop_rx).await;
+    // the value and the index of the entry which wrote it.
+    let kv = Arc::new(Mutex::new(HashMap::<Vec<u8>, (Vec<u8>, u64)>::new()));
+    let applied = Arc::new(AtomicU64::new(0));
+    let mut events = cluster.events.remove(0);
+    let (state, applied_index) = (kv.clone(), applied.clone());
+    tokio::spawn(async move {
+        while let Some(events) = events.recv().await {
+            for event in events {
+                if let Event::Apply(apply) = event {
+                    let mut kv = state.lock().unwrap();
+                    let res = match apply.precondition.as_ref() {
+                        None => Ok(()),
+                        Some(precondition) => match kv.get(&precondition.key) {
+                            Some((value, index)) => apply.check_precondition(Some(value), *index),
+                            None => apply.check_precondition(None, 0),
+                        },
+                    };
+                    // the effect is skipped, but the applied index advances.
+                    if res.is_ok() && !apply.entry.data.is_empty() {
+                        let key = apply.precondition.as_ref().unwrap().key.clone();
+                        kv.insert(key, (apply.entry.data.clone(), apply.entry.index));
+                    }
+                    applied_index.store(apply.entry.index, Ordering::SeqCst);
+                    if let Some(tx) = apply.tx {
+                        let _ = tx.send(res);
+                    }
+                }
+            }
+        }
+    });
+
+    let group_id = 1;
+    cluster.make_group_with_campaign(group_id, 0, 1, true).await;
+    cluster.tick_all().await;
+
+    let multiraft = &cluster.multirafts[0];
+    let precondition_at = |expected_hash, read_index| Precondition {
+        key: b"k".to_vec(),
+        expected_hash,
+        read_index,
+    };
+    let precondition = |expected_hash| precondition_at(expected_hash, 0);
+    let value = || kv.lock().unwrap()[&b"k".to_vec()].0.clone();
+
+    // create if absent.
+    multiraft
+        .propose_conditional(GroupId(group_id), b"v1".to_vec(), vec![], precondition(0))
+        .await
+        .unwrap();
+    assert_eq!(value(), b"v1".to_vec());
+
+    // the key exists now, the entry is committed but takes no effect.
+    let err = multiraft
+        .propose_conditional(GroupId(group_id), b"v2".to_vec(), vec![], precondition(0))
+        .await
+        .unwrap_err();
+    assert_eq!(err, Error::Proposal(ProposalError::PreconditionFailed));
+    assert_eq!(value(), b"v1".to_vec());
+    let failed_index = applied.load(Ordering::SeqCst);
+
+    // compare and set with the hash of the current value.
+    let token = multiraft
+        .propose_conditional(
+            GroupId(group_id),
+            b"v3".to_vec(),
+            vec![],
+            precondition(value_hash(Some(b"v1"))),
+        )
+        .await
+        .unwrap();
+    assert!(token.index() > failed_index);
+    assert_eq!(value(), b"v3".to_vec());
+
+    // v3 is read at the applied index, then the key is changed and changed
+    // back, the hash matches again but the read is stale.
+    let read_index = applied.load(Ordering::SeqCst);
+    let v3_hash = value_hash(Some(b"v3"));
+    for (data, expected_hash) in [(b"v4", v3_hash), (b"v3", value_hash(Some(b"v4")))] {
+        multiraft
+            .propose_conditional(
+                GroupId(group_id),
+                data.to_vec(),
+                vec![],
+                precondition(expected_hash),
+            )
+            .await
+            .unwrap();
+    }
+    let err = multiraft
+        .propose_conditional(
+            GroupId(group_id),
+            b"v5".to_vec(),
+            vec![],
+            precondition_at(v3_hash, read_index),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(err, Error::Proposal(ProposalError::PreconditionFailed));
+    assert_eq!(value(), b"v3".to_vec());
+
+    // the read at the latest applied index holds.
+    let read_index = applied.load(Ordering::SeqCst);
+    multiraft
+        .propose_conditional(
+            GroupId(group_id),
+            b"v5".to_vec(),
+            vec![],
+            precondition_at(v3_hash, read_index),
+        )
+        .await
+        .unwrap();
+    assert_eq!(value(), b"v5".to_vec());
+    let _ = stop_tx.send(true);
+}
+
+/// Blocks the actor at the `Ready` stage once it's armed, until it's released.
+#[derive(Default)]
+struct BlockingReadyHook {
+    // (armed, blocked)
+    state: Mutex<(bool, bool)>,
+    cond: Condvar,
+}
+
+impl BlockingReadyHook {
+    fn arm(&self) {
+        self.state.lock().unwrap().0 = true;
+    }
+
+    fn is_blocked(&self) -> bool {
+        self.state.lock().unwrap().1
+    }
+
+    fn release(&self) {
+        *self.state.lock().unwrap() = (false, false);
+        self.cond.notify_all();
+    }
+}
+
+impl ReadyHook for BlockingReadyHook {
+    fn after_stage(&self, _: u64, stage: ReadyStage) {
+        let mut state = self.state.lock().unwrap();
+        if stage != ReadyStage::Ready || !state.0 {
+            return;
+        }
+        state.1 = true;
+        while state.0 {
+            state = self.cond.wait(state).unwrap();
+        }
+    }
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_full_mailbox_blocks_proposers() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let hook = Arc::new(BlockingReadyHook::default());
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        proposal_mailbox_capacity: 4,
+        ..Default::default()
+    };
+    let extensions = vec![MultiRaftExtensions {
+        ready_hook: Some(hook.clone() as Arc<dyn ReadyHook>),
+        ..Default::default()
+    }];
+    let mut cluster = FixtureCluster::make_with_extensions(1, config, extensions, stop_rx).await;
+    spawn_ack_applies(cluster.events.remove(0));
+
+    let group_id = 1;
+    cluster.make_group_with_campaign(group_id, 0, 1, true).await;
+    cluster.tick_all().await;
+    let multiraft = &cluster.multirafts[0];
+    let request = || AppWriteRequest {
+        group_id,
+        term: 0,
+        data: b"data".to_vec(),
+        context: vec![],
+        ..Default::default()
+    };
+    multiraft.write(request()).await.unwrap();
+    let empty = MailboxDepth {
+        depth: 0,
+        capacity: 4,
+    };
+    assert_eq!(multiraft.mailbox_stats().writes, empty);
+
+    // the actor is blocked in handling the ready of the write.
+    hook.arm();
+    let first = multiraft.write(request());
+    tokio::pin!(first);
+    while !hook.is_blocked() {
+        let _ = tokio::time::timeout(Duration::from_millis(10), &mut first).await;
+    }
+
+    // the mailbox is saturated, the proposers await rather than queue more.
+    let writes = futures::future::join_all((0..16).map(|_| multiraft.write(request())));
+    tokio::pin!(writes);
+    assert!(tokio::time::timeout(Duration::from_millis(200), &mut writes)
+        .await
+        .is_err());
+    let stats = multiraft.mailbox_stats();
+    assert_eq!(stats.writes.depth, 4);
+    assert!(stats.writes.is_near_full());
+    assert!(multiraft.health().await.mailboxes.writes.is_near_full());
+
+    // all writes are done after the actor catches up.
+    hook.release();
+    first.await.unwrap();
+    for res in writes.await {
+        res.unwrap();
+    }
+    assert_eq!(multiraft.mailbox_stats().writes, empty);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_recover_inconsistent_conf_state() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let cluster = FixtureCluster::make_with_manual_tick(1, stop_rx).await;
+    let initial_msg = |group_id: u64| {
+        let mut msg = RaftGroupManagementMessage::default();
+        msg.set_msg_type(RaftGroupManagementMessageType::MsgInitialGroup);
+        msg.group_id = group_id;
+        msg.replica_id = 1;
+        msg.replicas = (1..=3)
+            .map(|replica_id| ReplicaDesc {
+                node_id: replica_id,
+                replica_id,
+            })
+            .collect();
+        msg
+    };
+    let prepare_storage = |group_id: u64, voters: Vec<u64>| {
+        let storage = cluster.storages[0].clone();
+        async move {
+            let gs = storage.group_storage(group_id, 1).await.unwrap();
+            let mut ss = Snapshot::default();
+            ss.mut_metadata().mut_conf_state().voters = vec![1, 2, 3];
+            ss.mut_metadata().index = 5;
+            ss.mut_metadata().term = 1;
+            gs.apply_snapshot(ss).await.unwrap();
+
+            let mut cs = ConfState::default();
+            cs.voters = voters;
+            gs.set_confstate(cs).await.unwrap();
+            gs
+        }
+    };
+
+    // the stored conf state disagrees with the snapshot without any conf change
+    // after it, the group fails to recover rather than losing a voter.
+    prepare_storage(1, vec![1, 2]).await;
+    match cluster.multirafts[0].initial_raft_group(initial_msg(1)).await {
+        Err(Error::ConfStateInconsistent(1, _)) => {}
+        res => panic!("expected ConfStateInconsistent, got {:?}", res),
+    }
+    assert!(cluster.multirafts[0]
+        .group_status(GroupId(1))
+        .await
+        .is_none());
+
+    // the message of the group creates the replica on demand, the failure is
+    // recorded as a dropped message rather than panicking the actor.
+    let mut append = smol_raft::proto::Message::default();
+    append.set_msg_type(smol_raft::proto::MessageType::MsgAppend);
+    append.from = 2;
+    append.to = 1;
+    append.term = 1;
+    cluster
+        .transport
+        .send(RaftMessage {
+            group_id: 1,
+            from_node: 2,
+            to_node: 1,
+            msg: Some(append),
+            ..Default::default()
+        })
+        .unwrap();
+    let creation_failed = || {
+        cluster.multirafts[0]
+            .dropped_message_counts()
+            .into_iter()
+            .find(|(reason, _)| *reason == DropReason::GroupCreationFailed)
+            .map_or(0, |(_, count)| count)
+    };
+    let timeout = Instant::now() + Duration::from_secs(5);
+    while creation_failed() == 0 {
+        assert!(Instant::now() < timeout);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(cluster.multirafts[0]
+        .group_status(GroupId(1))
+        .await
+        .is_none());
+
+    // the lost conf state is repaired from the snapshot.
+    let gs = prepare_storage(2, vec![]).await;
+    cluster.multirafts[0]
+        .initial_raft_group(initial_msg(2))
+        .await
+        .unwrap();
+    assert_eq!(gs.get_confstate().await.unwrap().voters, vec![1, 2, 3]);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_applied_report_batched_per_tick() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        report_applied_index: true,
+        applied_report_batch: 100,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(3, config, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+
+    let follower_id = leader_id % 3 + 1;
+    let reports = Arc::new(AtomicUsize::new(0));
+    let counter = reports.clone();
+    cluster.transport.set_filter(move |msg| {
+        if msg.from_node == follower_id
+            && msg.to_node == leader_id
+            && msg.msg.as_ref().map_or(false, |msg| {
+                msg.msg_type() == smol_raft::proto::MessageType::MsgHeartbeatResponse
+            })
+        {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }
+        FilterAction::Pass
+    });
+
+    // the follower doesn't report per applied entry.
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let mut last_index = 0;
+    for i in 0..1000 {
+        let token = leader
+            .write(AppWriteRequest {
+                group_id,
+                term: 0,
+                data: format!("data-{}", i).into_bytes(),
+                context: vec![],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        last_index = token.index();
+    }
+    assert_eq!(reports.load(Ordering::SeqCst), 0);
+
+    // the reports are sent with the coalesced heartbeat responses per tick.
+    let mut ticks = 0;
+    let mut follower_applied = 0;
+    while ticks < 20 && follower_applied < last_index {
+        cluster.tick_all().await;
+        ticks += 1;
+        let status = leader.group_status(GroupId(group_id)).await.unwrap();
+        follower_applied = status
+            .progress
+            .iter()
+            .find(|pr| pr.replica_id == follower_id)
+            .map_or(0, |pr| pr.applied);
+    }
+    assert!(follower_applied >= last_index);
+    assert!(reports.load(Ordering::SeqCst) <= 2 * ticks);
+    let _ = stop_tx.send(true);
+}
+
+/// The command of the counter state machine in the test of typed proposals.
+#[derive(Debug, Clone, PartialEq)]
+enum CounterCommand {
+    Add(u64),
+    Reset,
+}
+
+impl Command for CounterCommand {
+    fn encode(&self) -> Result<Vec<u8>, Error> {
+        match self {
+            CounterCommand::Add(n) => {
+                let mut data = vec![1];
+                data.extend_from_slice(&n.to_be_bytes());
+                Ok(data)
+            }
+            CounterCommand::Reset => Ok(vec![2]),
+        }
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, Error> {
+        match data {
+            [1, n @ ..] if n.len() == 8 => {
+                Ok(CounterCommand::Add(u64::from_be_bytes(n.try_into().unwrap())))
+            }
+            [2] => Ok(CounterCommand::Reset),
+            _ => Err(Error::Codec(format!("bad counter command {:?}", data))),
+        }
+    }
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_propose_command_counter() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+
+    // the counter of each replica is updated by the decoded commands.
+    let counters = (0..3).map(|_| Arc::new(AtomicU64::new(0))).collect::<Vec<_>>();
+    for (mut events, counter) in std::mem::take(&mut cluster.events)
+        .into_iter()
+        .zip(counters.iter().cloned())
+    {
+        tokio::spawn(async move {
+            while let Some(events) = events.recv().await {
+                for event in events {
+                    let apply = match event {
+                        Event::Apply(apply) => apply,
+                        _ => continue,
+                    };
+                    let res = if apply.is_conf_change || apply.entry.data.is_empty() {
+                        Ok(())
+                    } else {
+                        apply.command::<CounterCommand>().map(|cmd| match cmd {
+                            CounterCommand::Add(n) => {
+                                counter.fetch_add(n, Ordering::SeqCst);
+                            }
+                            CounterCommand::Reset => counter.store(0, Ordering::SeqCst),
+                        })
+                    };
+                    if let Some(tx) = apply.tx {
+                        let _ = tx.send(res);
+                    }
+                }
+            }
+        });
+    }
+
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    for cmd in [
+        CounterCommand::Add(3),
+        CounterCommand::Reset,
+        CounterCommand::Add(5),
+        CounterCommand::Add(7),
+    ] {
+        leader
+            .propose_command(GroupId(group_id), &cmd)
+            .await
+            .unwrap();
+    }
+    assert_eq!(counters[leader_id as usize - 1].load(Ordering::SeqCst), 12);
+
+    // the raw data which isn't a command fails to be decoded on apply.
+    let res = leader
+        .propose_timeout(GroupId(group_id), vec![9], vec![], Duration::from_secs(1))
+        .await;
+    assert!(matches!(res, Err(Error::Codec(_))));
+
+    // the followers apply the same commands.
+    let applied_all = || counters.iter().all(|c| c.load(Ordering::SeqCst) == 12);
+    for _ in 0..20 {
+        if applied_all() {
+            break;
+        }
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(applied_all());
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_max_concurrent_snapshots() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    // the lagging node is an observer, so the leaders are on the other nodes.
+    let lagging_id = 3;
+    let configs = (1..=3)
+        .map(|node_id| MultiRaftConfig {
+            election_tick: 2,
+            heartbeat_tick: 1,
+            manual_tick: true,
+            observer: node_id == lagging_id,
+            max_concurrent_snapshots: 1,
+            ..Default::default()
+        })
+        .collect();
+    let mut cluster = FixtureCluster::make_with_configs(configs, vec![], stop_rx).await;
+    let groups = [1, 2, 3, 4];
+    let mut leaders = HashMap::new();
+    for group_id in groups {
+        cluster.make_group(group_id, 0, 3).await;
+        let leader_id = cluster
+            .tick_until_leader(group_id, &[0, 1, 2])
+            .await
+            .unwrap();
+        leaders.insert(group_id, leader_id);
+    }
+    cluster.ack_applies();
+
+    // the logs are compacted while the node is isolated, so every group
+    // needs a snapshot to catch up the node.
+    cluster.transport.isolate(lagging_id);
+    let mut snapshot_index = HashMap::new();
+    for group_id in groups {
+        let leader = &cluster.multirafts[leaders[&group_id] as usize - 1];
+        for i in 0..3u8 {
+            leader
+                .propose_timeout(GroupId(group_id), vec![i], vec![], Duration::from_secs(5))
+                .await
+                .unwrap();
+        }
+        let meta = leader.trigger_snapshot(GroupId(group_id)).await.unwrap();
+        snapshot_index.insert(group_id, meta.index);
+    }
+    cluster.transport.reconnect(lagging_id);
+
+    let lagging = &cluster.multirafts[lagging_id as usize - 1];
+    let mut caught_up = false;
+    for _ in 0..100 {
+        cluster.tick_all().await;
+        tokio::task::yield_now().await;
+        for node_index in 0..2 {
+            let health = cluster.multirafts[node_index].health().await;
+            assert!(health.inflight_snapshots <= 1, "{:?}", health);
+        }
+
+        let mut all = true;
+        for group_id in groups {
+            let status = lagging.group_status(GroupId(group_id)).await.unwrap();
+            all &= status.commit_index >= snapshot_index[&group_id];
+        }
+        if all {
+            caught_up = true;
+            break;
+        }
+    }
+    assert!(caught_up);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_allocate_replica_id_after_removal() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(4, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let new_node = 4;
+    let replica_id = leader
+        .allocate_replica_id(GroupId(group_id))
+        .await
+        .unwrap()
+        .0;
+    assert_eq!(replica_id, 4);
+    leader
+        .add_replica(GroupId(group_id), NodeId(new_node), ReplicaId(replica_id))
+        .await
+        .unwrap();
+    match leader
+        .add_replica(GroupId(group_id), NodeId(new_node), ReplicaId(leader_id))
+        .await
+    {
+        Err(Error::DuplicateReplica(1, id)) => assert_eq!(id, leader_id),
+        res => panic!("expected DuplicateReplica, got {:?}", res),
+    }
+
+    let mut change = MembershipChangeRequest {
+        group_id,
+        node_id: new_node,
+        replica_id,
+        ..Default::default()
+    };
+    change.set_change_type(ConfChangeType::RemoveNode);
+    leader
+        .propose_conf_change(MembershipChangeData {
+            group_id,
+            changes: vec![change],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+    // the removed id is never reused.
+    match leader
+        .add_replica(GroupId(group_id), NodeId(new_node), ReplicaId(replica_id))
+        .await
+    {
+        Err(Error::DuplicateReplica(1, id)) => assert_eq!(id, replica_id),
+        res => panic!("expected DuplicateReplica, got {:?}", res),
+    }
+    let fresh_id = leader
+        .allocate_replica_id(GroupId(group_id))
+        .await
+        .unwrap()
+        .0;
+    assert_eq!(fresh_id, replica_id + 1);
+    leader
+        .add_replica(GroupId(group_id), NodeId(new_node), ReplicaId(fresh_id))
+        .await
+        .unwrap();
+    let voters = leader.conf_state(GroupId(group_id)).await.unwrap().voters;
+    assert!(voters.contains(&fresh_id) && !voters.contains(&replica_id));
+
+    // the removed replicas and the allocated id are persisted for the restart.
+    let desc = cluster.storages[leader_id as usize - 1]
+        .group_desc(group_id)
+        .await
+        .unwrap();
+    assert_eq!(desc.removed_replicas, vec![replica_id]);
+    assert_eq!(desc.allocated_replica_id, fresh_id);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_apply_in_log_order_around_conf_change() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(4, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+
+    // the (index, is_conf_change, data) of the entries applied on each node.
+    let applied = (0..4)
+        .map(|_| Arc::new(Mutex::new(Vec::new())))
+        .collect::<Vec<_>>();
+    for (mut events, applied) in std::mem::take(&mut cluster.events)
+        .into_iter()
+        .zip(applied.clone())
+    {
+        tokio::spawn(async move {
+            while let Some(events) = events.recv().await {
+                for event in events {
+                    if let Event::Apply(apply) = event {
+                        applied.lock().unwrap().push((
+                            apply.entry.index,
+                            apply.is_conf_change,
+                            apply.entry.data.clone(),
+                        ));
+                        if let Some(tx) = apply.tx {
+                            let _ = tx.send(Ok(()));
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    // the data proposals are interleaved with adding the voter 4 on node 4.
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let timeout = Duration::from_secs(5);
+    let propose = |n: u8| leader.propose_timeout(GroupId(group_id), vec![n], vec![], timeout);
+    let (before, conf_change, after) = tokio::join!(
+        futures::future::join_all((0..5).map(propose)),
+        leader.add_replica(GroupId(group_id), NodeId(4), ReplicaId(4)),
+        futures::future::join_all((5..10).map(propose)),
+    );
+    conf_change.unwrap();
+    for result in before.into_iter().chain(after) {
+        result.unwrap();
+    }
+
+    let mut joined = false;
+    for _ in 0..100 {
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let mut all = true;
+        for multiraft in cluster.multirafts.iter() {
+            let voters = multiraft
+                .conf_state(GroupId(group_id))
+                .await
+                .map_or(vec![], |cs| cs.voters);
+            all &= voters.len() == 4;
+        }
+        if all {
+            joined = true;
+            break;
+        }
+    }
+    assert!(joined);
+
+    // the last entry is applied by every replica, including the new one.
+    propose(10).await.unwrap();
+    let mut converged = false;
+    for _ in 0..100 {
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let expected = applied[leader_id as usize - 1].lock().unwrap().clone();
+        if applied
+            .iter()
+            .all(|applied| applied.lock().unwrap().last() == expected.last())
+        {
+            converged = true;
+            break;
+        }
+    }
+    assert!(converged);
+
+    // every replica applies the same entries in the order of the log, the new
+    // replica may start from a snapshot.
+    let expected = applied[leader_id as usize - 1].lock().unwrap().clone();
+    assert_eq!(expected.len(), 12);
+    let conf_changes = expected.iter().filter(|(_, conf_change, _)| *conf_change);
+    assert_eq!(conf_changes.count(), 1);
+    for (node_index, applied) in applied.iter().enumerate() {
+        let applied = applied.lock().unwrap();
+        assert!(
+            applied.windows(2).all(|pair| pair[0].0 < pair[1].0),
+            "node {} applies out of order: {:?}",
+            node_index + 1,
+            *applied
+        );
+        if node_index < 3 {
+            assert_eq!(*applied, expected);
+        } else {
+            assert!(expected.ends_with(&applied));
+        }
+    }
+    for multiraft in cluster.multirafts.iter() {
+        let mut voters = multiraft
+            .conf_state(GroupId(group_id))
+            .await
+            .unwrap()
+            .voters;
+        voters.sort();
+        assert_eq!(voters, vec![1, 2, 3, 4]);
+    }
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_is_leader_flips_across_election() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    for multiraft in cluster.multirafts.iter() {
+        assert!(!multiraft.is_leader(GroupId(group_id)));
+    }
+
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    tokio::time::timeout(Duration::from_secs(1), async {
+        while !leader.is_leader(GroupId(group_id)) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    })
+    .await
+    .unwrap();
+    for (index, multiraft) in cluster.multirafts.iter().enumerate() {
+        assert_eq!(
+            multiraft.is_leader(GroupId(group_id)),
+            index + 1 == leader_id as usize
+        );
+    }
+
+    // isolate the leader, the new leader is elected by the others and the
+    // old leader steps down after it's reconnected.
+    cluster.transport.isolate(leader_id);
+    let others = (0..3)
+        .filter(|index| *index + 1 != leader_id as usize)
+        .collect::<Vec<_>>();
+    let new_leader_id = cluster.tick_until_leader(group_id, &others).await.unwrap();
+    assert_ne!(new_leader_id, leader_id);
+    let new_leader = &cluster.multirafts[new_leader_id as usize - 1];
+    tokio::time::timeout(Duration::from_secs(1), async {
+        while !new_leader.is_leader(GroupId(group_id)) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    cluster.transport.reconnect(leader_id);
+    let old_leader = &cluster.multirafts[leader_id as usize - 1];
+    for _ in 0..20 {
+        if !old_leader.is_leader(GroupId(group_id)) {
+            break;
+        }
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(!old_leader.is_leader(GroupId(group_id)));
+    assert!(cluster.multirafts[new_leader_id as usize - 1].is_leader(GroupId(group_id)));
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_write_sync_batch_across_groups() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        write_sync_batch_latency: 1,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(3, config, stop_rx).await;
+    let groups = 8u64;
+    let mut leaders = vec![];
+    for group_id in 1..=groups {
+        cluster.make_group(group_id, 0, 3).await;
+        let leader_id = cluster
+            .tick_until_leader(group_id, &[0, 1, 2])
+            .await
+            .unwrap();
+        leaders.push((group_id, leader_id as usize - 1));
+    }
+    cluster.ack_applies();
+
+    // the writes of the groups synced in batches are committed and applied.
+    for round in 0..3u8 {
+        let proposals = leaders.iter().map(|(group_id, leader_index)| {
+            cluster.multirafts[*leader_index].propose_timeout(
+                GroupId(*group_id),
+                vec![round],
+                vec![],
+                Duration::from_secs(5),
+            )
+        });
+        for result in futures::future::join_all(proposals).await {
+            result.unwrap();
+        }
+    }
+    for (group_id, leader_index) in leaders {
+        let status = cluster.multirafts[leader_index]
+            .group_status(GroupId(group_id))
+            .await
+            .unwrap();
+        assert!(status.commit_index >= 4);
+    }
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_drain_group_before_remove() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+
+    // the proposals racing with the drain are either committed or failed
+    // with `GroupDraining`, none of them is left pending.
+    let multiraft = &cluster.multirafts[leader_id as usize - 1];
+    let resolved = Arc::new(AtomicUsize::new(0));
+    let proposals = (0..20u8).map(|i| {
+        let resolved = resolved.clone();
+        async move {
+            let res = multiraft
+                .propose_timeout(GroupId(group_id), vec![i], vec![], Duration::from_secs(5))
+                .await;
+            resolved.fetch_add(1, Ordering::SeqCst);
+            res
+        }
+    });
+    let drain = async {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        multiraft
+            .drain_group(GroupId(group_id), Duration::from_secs(2))
+            .await
+            .unwrap()
+    };
+    let (results, _) = tokio::join!(futures::future::join_all(proposals), drain);
+    assert_eq!(resolved.load(Ordering::SeqCst), 20);
+    for res in results {
+        match res {
+            Ok(_) | Err(Error::GroupDraining(_)) => {}
+            Err(err) => panic!("unexpected proposal error: {}", err),
+        }
+    }
+
+    // the drained group rejects the proposals, then it's removed safely.
+    let res = multiraft
+        .propose_timeout(GroupId(group_id), vec![0], vec![], Duration::from_secs(1))
+        .await;
+    assert!(matches!(res, Err(Error::GroupDraining(1))));
+
+    // the membership change which removes a replica is still accepted.
+    let removed_id = (1..=3).find(|id| *id != leader_id).unwrap();
+    let mut change = MembershipChangeRequest {
+        group_id,
+        node_id: removed_id,
+        replica_id: removed_id,
+        ..Default::default()
+    };
+    change.set_change_type(ConfChangeType::RemoveNode);
+    multiraft
+        .propose_conf_change(MembershipChangeData {
+            group_id,
+            changes: vec![change],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let voters = multiraft
+        .conf_state(GroupId(group_id))
+        .await
+        .unwrap()
+        .voters;
+    assert!(!voters.contains(&removed_id));
+    multiraft.remove_group(GroupId(group_id)).await.unwrap();
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_bootstrap_group_elects_leader() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    let replicas = (1..=3)
+        .map(|id| ReplicaDesc {
+            node_id: id,
+            replica_id: id,
+        })
+        .collect::<Vec<_>>();
+
+    // the replicas don't contain the replica on the node.
+    let err = cluster.multirafts[0]
+        .bootstrap_group(GroupId(group_id), replicas[1..].to_vec(), false)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::BadParameter(_)), "{:?}", err);
+
+    for multiraft in cluster.multirafts.iter() {
+        multiraft
+            .bootstrap_group(GroupId(group_id), replicas.clone(), false)
+            .await
+            .unwrap();
+    }
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    assert!(replicas.iter().any(|replica| replica.replica_id == leader_id));
+
+    let gs = cluster.storages[0].group_storage(group_id, 1).await.unwrap();
+    let rs = gs.initial_state().unwrap();
+    assert_eq!(rs.conf_state.voters, vec![1, 2, 3]);
+    assert!(rs.hard_state.term >= 1);
+
+    stop_tx.send(true).unwrap();
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_ready_workers_across_groups() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        ready_workers: 4,
+        write_sync_batch_latency: 1,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(3, config, stop_rx).await;
+    let groups = 16u64;
+    let mut leaders = vec![];
+    for group_id in 1..=groups {
+        cluster.make_group(group_id, 0, 3).await;
+        let leader_id = cluster
+            .tick_until_leader(group_id, &[0, 1, 2])
+            .await
+            .unwrap();
+        leaders.push((group_id, leader_id as usize - 1));
+    }
+    cluster.ack_applies();
+
+    // the readies of the groups persisted by the workers concurrently are
+    // committed and applied in order.
+    for round in 0..5u8 {
+        let proposals = leaders.iter().map(|(group_id, leader_index)| {
+            cluster.multirafts[*leader_index].propose_timeout(
+                GroupId(*group_id),
+                vec![round],
+                vec![],
+                Duration::from_secs(5),
+            )
+        });
+        for result in futures::future::join_all(proposals).await {
+            result.unwrap();
+        }
+    }
+    for (group_id, leader_index) in leaders {
+        let status = cluster.multirafts[leader_index]
+            .group_status(GroupId(group_id))
+            .await
+            .unwrap();
+        assert!(status.commit_index >= 7);
+        let gs = cluster.storages[leader_index]
+            .group_storage(group_id, leader_index as u64 + 1)
+            .await
+            .unwrap();
+        let entries = gs.entries(2, status.commit_index + 1, u64::MAX).unwrap();
+        assert_eq!(entries.iter().filter(|entry| !entry.data.is_empty()).count(), 5);
+    }
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pending_proposal_on_shutdown() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+
+    // the proposal of the isolated leader is pending until the node stops.
+    cluster.transport.isolate(leader_id);
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let proposal = leader.propose_timeout(
+        GroupId(group_id),
+        b"data".to_vec(),
+        vec![],
+        Duration::from_secs(10),
+    );
+    let stop = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        stop_tx.send(true).unwrap();
+    };
+    let (res, _) = tokio::join!(proposal, stop);
+    assert!(matches!(res, Err(Error::Shutdown)));
+
+    // the operations after the shutdown fail with `Shutdown` as well.
+    let res = leader
+        .transfer_leader(GroupId(group_id), ReplicaId(1))
+        .await;
+    assert!(matches!(res, Err(Error::Shutdown)), "{:?}", res);
+    let res = leader.allocate_replica_id(GroupId(group_id)).await;
+    assert!(matches!(res, Err(Error::Shutdown)), "{:?}", res);
+    let res = leader.remove_group(GroupId(group_id)).await;
+    assert!(matches!(res, Err(Error::Shutdown)), "{:?}", res);
+    assert!(leader.group_status(GroupId(group_id)).await.is_none());
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_auto_promote_caught_up_learner() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(4, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+
+    let policy = AutoPromotePolicy {
+        max_lag: 1,
+        stable_ticks: 2,
+    };
+    for multiraft in cluster.multirafts[..3].iter() {
+        multiraft
+            .set_auto_promote(GroupId(group_id), Some(policy))
+            .await
+            .unwrap();
+    }
+
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    for i in 0..5u8 {
+        leader
+            .propose_timeout(GroupId(group_id), vec![i], vec![], Duration::from_secs(5))
+            .await
+            .unwrap();
+    }
+    let (new_node, learner_id) = (4, 4);
+    let mut change = MembershipChangeRequest {
+        group_id,
+        node_id: new_node,
+        replica_id: learner_id,
+        ..Default::default()
+    };
+    change.set_change_type(ConfChangeType::AddLearnerNode);
+    leader
+        .propose_conf_change(MembershipChangeData {
+            group_id,
+            changes: vec![change],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let cs = leader.conf_state(GroupId(group_id)).await.unwrap();
+    assert!(cs.learners.contains(&learner_id));
+
+    // the learner is promoted by the leader once it's caught up.
+    let mut promoted = false;
+    for _ in 0..100 {
+        cluster.tick_all().await;
+        let cs = leader.conf_state(GroupId(group_id)).await.unwrap();
+        if cs.voters.contains(&learner_id) {
+            assert!(!cs.learners.contains(&learner_id));
+            promoted = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(promoted);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_group_state_changed() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    let (state_tx, mut state_rx) = tokio::sync::mpsc::unbounded_channel();
+    for (i, mut events) in std::mem::take(&mut cluster.events).into_iter().enumerate() {
+        let state_tx = (i + 1 == leader_id as usize).then(|| state_tx.clone());
+        tokio::spawn(async move {
+            while let Some(events) = events.recv().await {
+                for event in events {
+                    if let (Event::GroupStateChanged(changed), Some(state_tx)) =
+                        (event, state_tx.as_ref())
+                    {
+                        let _ = state_tx.send((changed.from, changed.to));
+                    }
+                }
+            }
+        });
+    }
+    async fn next_state(
+        rx: &mut tokio::sync::mpsc::UnboundedReceiver<(GroupState, GroupState)>,
+    ) -> (GroupState, GroupState) {
+        let recv = tokio::time::timeout(Duration::from_secs(1), rx.recv());
+        recv.await.unwrap().unwrap()
+    }
+
+    assert_eq!(
+        next_state(&mut state_rx).await,
+        (GroupState::Uninitialized, GroupState::Initializing)
+    );
+    assert_eq!(
+        next_state(&mut state_rx).await,
+        (GroupState::Initializing, GroupState::Running)
+    );
+    let multiraft = &cluster.multirafts[leader_id as usize - 1];
+    let status = multiraft.group_status(GroupId(group_id)).await.unwrap();
+    assert_eq!(status.state, GroupState::Running);
+
+    // the drained group rejects the proposals until it's removed.
+    multiraft
+        .drain_group(GroupId(group_id), Duration::from_secs(1))
+        .await
+        .unwrap();
+    assert_eq!(next_state(&mut state_rx).await.1, GroupState::Draining);
+    let status = multiraft.group_status(GroupId(group_id)).await.unwrap();
+    assert_eq!(status.state, GroupState::Draining);
+    let res = multiraft
+        .propose_timeout(GroupId(group_id), vec![0], vec![], Duration::from_secs(1))
+        .await;
+    assert!(matches!(res, Err(Error::GroupDraining(1))));
+
+    multiraft.remove_group(GroupId(group_id)).await.unwrap();
+    assert_eq!(
+        next_state(&mut state_rx).await,
+        (GroupState::Draining, GroupState::Removed)
+    );
+    assert!(multiraft.group_status(GroupId(group_id)).await.is_none());
+    let _ = stop_tx.send(true);
+}
+
+/// Places the replicas on the fixed nodes, skipping the node 2.
+struct FixedPlacer {
+    nodes: Vec<u64>,
+}
+
+impl ReplicaPlacer for FixedPlacer {
+    fn place(&self, _group_id: u64, desired_count: usize) -> Vec<ReplicaDesc> {
+        self.nodes
+            .iter()
+            .take(desired_count)
+            .enumerate()
+            .map(|(n, node_id)| ReplicaDesc {
+                node_id: *node_id,
+                replica_id: n as u64 + 1,
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_bootstrap_groups_by_placer() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let extensions = vec![MultiRaftExtensions {
+        replica_placer: Some(Arc::new(FixedPlacer {
+            nodes: vec![1, 3, 4],
+        })),
+        ..Default::default()
+    }];
+    let mut cluster = FixtureCluster::make_with_extensions(4, config, extensions, stop_rx).await;
+
+    // the default placer of the node without a resolver places the replicas
+    // on the node itself only.
+    assert!(matches!(
+        cluster.multirafts[1].place_replicas(GroupId(1), 3),
+        Err(Error::BadParameter(_))
+    ));
+    let replicas = cluster.multirafts[1].place_replicas(GroupId(1), 1).unwrap();
+    assert_eq!(replicas[0].node_id, 2);
+    // the placement with too few replicas is rejected.
+    assert!(matches!(
+        cluster.multirafts[0].place_replicas(GroupId(1), 4),
+        Err(Error::BadParameter(_))
+    ));
+
+    let placements = cluster.multirafts[0]
+        .bootstrap_groups(vec![GroupId(1), GroupId(2)], 3, false)
+        .await;
+    assert_eq!(placements.len(), 2);
+    for (group_id, res) in placements {
+        let replicas = res.unwrap();
+        let nodes = replicas
+            .iter()
+            .map(|replica| replica.node_id)
+            .collect::<Vec<_>>();
+        assert_eq!(nodes, vec![1, 3, 4]);
+
+        // the replicas on the other placed nodes are bootstrapped with the
+        // same placement.
+        for node_id in [3, 4] {
+            cluster.multirafts[node_id as usize - 1]
+                .bootstrap_group(group_id, replicas.clone(), false)
+                .await
+                .unwrap();
+        }
+        assert!(cluster
+            .tick_until_leader(group_id.0, &[0, 2, 3])
+            .await
+            .is_some());
+        assert!(cluster.multirafts[1].group_status(group_id).await.is_none());
+    }
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_bootstrap_group_retry() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    let replica = |node_id, replica_id| ReplicaDesc {
+        node_id,
+        replica_id,
+    };
+    let replicas = (1..=3).map(|id| replica(id, id)).collect::<Vec<_>>();
+    for multiraft in cluster.multirafts.iter() {
+        multiraft
+            .bootstrap_group(GroupId(group_id), replicas.clone(), false)
+            .await
+            .unwrap();
+    }
+    cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+
+    // the identical retry succeeds.
+    cluster.multirafts[0]
+        .bootstrap_group(GroupId(group_id), replicas.clone(), false)
+        .await
+        .unwrap();
+
+    // the replicas differ.
+    let conflict = vec![replica(1, 1), replica(2, 2), replica(3, 4)];
+    let err = cluster.multirafts[0]
+        .bootstrap_group(GroupId(group_id), conflict, false)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::GroupConflict(1, _)), "{:?}", err);
+
+    // the replica id of the node differs.
+    let conflict = vec![replica(1, 4), replica(2, 2), replica(3, 3)];
+    let err = cluster.multirafts[0]
+        .bootstrap_group(GroupId(group_id), conflict, false)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::GroupConflict(1, _)), "{:?}", err);
+
+    let mut voters = cluster.multirafts[0]
+        .conf_state(GroupId(group_id))
+        .await
+        .unwrap()
+        .voters;
+    voters.sort();
+    assert_eq!(voters, vec![1, 2, 3]);
+
+    stop_tx.send(true).unwrap();
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_reset_replica() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let (state_machines, extensions) = log_state_machines(3);
+    let mut cluster = FixtureCluster::make_with_extensions(3, config, extensions, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    // the 2-voter group has no spare up-to-date voter.
+    let small_group_id = 2;
+    cluster.make_group(small_group_id, 0, 2).await;
+    let small_leader_id = cluster
+        .tick_until_leader(small_group_id, &[0, 1])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let mut token = None;
+    for i in 0..5u8 {
+        token = Some(
+            leader
+                .propose_timeout(GroupId(group_id), vec![i], vec![], Duration::from_secs(5))
+                .await
+                .unwrap(),
+        );
+    }
+    let index = token.unwrap().index();
+
+    let follower_id = (1..=3).find(|id| *id != leader_id).unwrap();
+    let follower = &cluster.multirafts[follower_id as usize - 1];
+    let leader_state = &state_machines[leader_id as usize - 1];
+    let follower_state = &state_machines[follower_id as usize - 1];
+    // the state of the follower is corrupted once it's applied.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while follower_state.state(group_id) != leader_state.state(group_id) {
+        assert!(Instant::now() < deadline);
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    follower_state
+        .states
+        .lock()
+        .unwrap()
+        .insert(group_id, b"corrupt".to_vec());
+    let err = follower
+        .reset_replica(GroupId(group_id), ReplicaId(leader_id))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::Raft(_)), "{:?}", err);
+    let err = leader
+        .reset_replica(GroupId(group_id), ReplicaId(leader_id))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::BadParameter(_)), "{:?}", err);
+    let small_follower_id = 3 - small_leader_id;
+    let err = cluster.multirafts[small_leader_id as usize - 1]
+        .reset_replica(GroupId(small_group_id), ReplicaId(small_follower_id))
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, Error::UnsafeReset(2, id) if id == small_follower_id),
+        "{:?}",
+        err
+    );
+
+    // the follower discards its log by installing a fresh snapshot.
+    let storage = &cluster.storages[follower_id as usize - 1];
+    let mut reset = false;
+    for _ in 0..100 {
+        if leader
+            .reset_replica(GroupId(group_id), ReplicaId(follower_id))
+            .await
+            .is_ok()
+        {
+            reset = true;
+            break;
+        }
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(reset);
+    let mut installed = None;
+    for _ in 0..100 {
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let meta = storage.snapshot_metadata(group_id).await.unwrap();
+        if meta.index >= index {
+            installed = Some(meta);
+            break;
+        }
+    }
+    let meta = installed.unwrap();
+    let gs = storage.group_storage(group_id, follower_id).await.unwrap();
+    assert_eq!(gs.first_index().unwrap(), meta.index + 1);
+
+    // the snapshot replaces the corrupt state of the follower.
+    let state = b"\0;\x01;\x02;\x03;\x04;".to_vec();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while follower_state.state(group_id) != state {
+        assert!(
+            Instant::now() < deadline,
+            "{:?}",
+            follower_state.state(group_id)
+        );
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // the follower is back in sync.
+    let index = leader
+        .propose_timeout(GroupId(group_id), vec![5], vec![], Duration::from_secs(5))
+        .await
+        .unwrap()
+        .index();
+    let mut synced = false;
+    for _ in 0..100 {
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        if gs.last_index().unwrap() >= index
+            && follower_state.state(group_id) == leader_state.state(group_id)
+        {
+            synced = true;
+            break;
+        }
+    }
+    assert!(synced);
+    assert_eq!(
+        follower_state.state(group_id),
+        b"\0;\x01;\x02;\x03;\x04;\x05;".to_vec()
+    );
+
+    stop_tx.send(true).unwrap();
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_election_under_append_flood() {
+    use smol_raft::proto::MessageType;
+
+    // the appends held to a slow follower are delivered after the vote request
+    // of a candidate only if the messages are prioritized.
+    for prioritized in [true, false] {
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+        cluster.transport.set_prioritized(prioritized);
+        let group_id = 1;
+        cluster.make_group(group_id, 0, 3).await;
+        let leader_id = cluster
+            .tick_until_leader(group_id, &[0, 1, 2])
+            .await
+            .unwrap();
+        cluster.ack_applies();
+        let mut followers = (1..=3).filter(|id| *id != leader_id);
+        let (candidate_id, slow_id) = (followers.next().unwrap(), followers.next().unwrap());
+
+        // record the messages sent by the slow follower.
+        let sent = Arc::new(Mutex::new(vec![]));
+        let recorder = sent.clone();
+        cluster.transport.set_filter(move |msg| {
+            if msg.from_node == slow_id {
+                if let Some(msg) = msg.msg.as_ref() {
+                    recorder.lock().unwrap().push(msg.msg_type());
+                }
+            }
+            FilterAction::Pass
+        });
+
+        // the slow follower doesn't block the appends to the other one, the
+        // proposals are committed while the appends to it are held.
+        cluster.transport.hold(slow_id);
+        let leader = &cluster.multirafts[leader_id as usize - 1];
+        let flood = futures::future::join_all((0..32).map(|_| {
+            leader.propose_timeout(
+                GroupId(group_id),
+                vec![0; 4 * 1024],
+                vec![],
+                Duration::from_secs(5),
+            )
+        }));
+        for res in flood.await {
+            res.unwrap();
+        }
+
+        cluster.multirafts[candidate_id as usize - 1]
+            .campagin(GroupId(group_id))
+            .await
+            .unwrap();
+        cluster.transport.release(slow_id);
+        let is_vote = |msg_type: &MessageType| {
+            matches!(
+                msg_type,
+                MessageType::MsgRequestVoteResponse | MessageType::MsgRequestPreVoteResponse
+            )
+        };
+        let mut voted = None;
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let sent = sent.lock().unwrap();
+            if let Some(position) = sent.iter().position(is_vote) {
+                voted = Some(sent[..position].to_vec());
+                break;
+            }
+        }
+
+        // the vote request jumps ahead of the held appends.
+        let responded = voted
+            .unwrap()
+            .into_iter()
+            .filter(|msg_type| *msg_type == MessageType::MsgAppendResponse)
+            .count();
+        if prioritized {
+            assert_eq!(responded, 0);
+        } else {
+            assert!(responded > 0);
+        }
+
+        stop_tx.send(true).unwrap();
+    }
+}
+
+#[cfg(all(feature = "test-util", feature = "serde-config"))]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_propose_group_config() {
+    use smol_raft::multiraft::GroupConfig;
+
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+
+    let config = GroupConfig {
+        max_pending_proposals: Some(8),
+        auto_promote: Some(AutoPromotePolicy {
+            max_lag: 1,
+            stable_ticks: 2,
+        }),
+    };
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let propose = leader.propose_group_config(GroupId(group_id), &config);
+    let tick = async {
+        for _ in 0..10 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            cluster.tick_all().await;
+        }
+    };
+    let (res, _) = tokio::join!(propose, tick);
+    res.unwrap();
+
+    // every replica sets the config once it applies the admin entry.
+    for multiraft in cluster.multirafts.iter() {
+        let mut applied = false;
+        for _ in 0..100 {
+            if multiraft.group_config(GroupId(group_id)).await.unwrap() == config {
+                applied = true;
+                break;
+            }
+            cluster.tick_all().await;
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(applied);
+    }
+
+    stop_tx.send(true).unwrap();
+}
+
+/// Campaigns the replicas of a 3-node group in turn every few ticks, returns
+/// the number of the elected leaders seen by the nodes.
+#[cfg(feature = "test-util")]
+async fn count_leader_churn(min_leader_tenure: usize) -> usize {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        min_leader_tenure,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(3, config, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    let (election_tx, mut election_rx) = tokio::sync::mpsc::unbounded_channel();
+    for mut events in std::mem::take(&mut cluster.events) {
+        let election_tx = election_tx.clone();
+        tokio::spawn(async move {
+            while let Some(events) = events.recv().await {
+                for event in events {
+                    if let Event::LederElection(election) = event {
+                        if election.leader_id != 0 {
+                            let _ = election_tx.send(election.leader_id);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    for tick in 0..60 {
+        if tick % 4 == 0 {
+            cluster.multirafts[(tick / 4) % 3]
+                .campagin(GroupId(group_id))
+                .await
+                .unwrap();
+        }
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let mut elections = 0;
+    while election_rx.try_recv().is_ok() {
+        elections += 1;
+    }
+    stop_tx.send(true).unwrap();
+    elections
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_min_leader_tenure_damps_churn() {
+    let churn = count_leader_churn(0).await;
+    let sticky_churn = count_leader_churn(20).await;
+    assert!(sticky_churn < churn, "{} vs {}", sticky_churn, churn);
+}
+
+/// Drops 7 of every 8 heartbeats to a follower of a 3-node group, returns the
+/// number of the terms started by the elections in the meantime.
+#[cfg(feature = "test-util")]
+async fn count_jittery_elections(election_extension_window: usize) -> u64 {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 4,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        election_extension_window,
+        max_election_extension: 8,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(3, config, stop_rx).await;
+    for mut events in std::mem::take(&mut cluster.events) {
+        tokio::spawn(async move { while events.recv().await.is_some() {} });
+    }
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    let follower_id = (1..=3).find(|id| *id != leader_id).unwrap();
+    let start_term = cluster.multirafts[0]
+        .group_status(GroupId(group_id))
+        .await
+        .unwrap()
+        .term;
+
+    let heartbeats = Arc::new(AtomicUsize::new(0));
+    cluster.transport.set_filter(move |msg| {
+        let is_heartbeat = msg.msg.as_ref().map_or(false, |msg| {
+            msg.msg_type() == smol_raft::proto::MessageType::MsgHeartbeat
+        });
+        if msg.to_node != follower_id || !is_heartbeat {
+            return FilterAction::Pass;
+        }
+        match heartbeats.fetch_add(1, Ordering::SeqCst) % 8 {
+            7 => FilterAction::Pass,
+            _ => FilterAction::Drop,
+        }
+    });
+    for _ in 0..64 {
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    let term = cluster.multirafts[0]
+        .group_status(GroupId(group_id))
+        .await
+        .unwrap()
+        .term;
+    stop_tx.send(true).unwrap();
+    term - start_term
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_election_extension_under_jitter() {
+    let elections = count_jittery_elections(0).await;
+    let extended_elections = count_jittery_elections(16).await;
+    assert!(elections > 0);
+    assert!(
+        extended_elections < elections,
+        "{} vs {}",
+        extended_elections,
+        elections
+    );
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_bootstrap_group_init_result() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    for mut events in std::mem::take(&mut cluster.events) {
+        tokio::spawn(async move { while events.recv().await.is_some() {} });
+    }
+    let replica = |node_id, replica_id| ReplicaDesc {
+        node_id,
+        replica_id,
+    };
+
+    // the single voter is leader right after the bootstrap.
+    let result = cluster.multirafts[0]
+        .bootstrap_group(GroupId(1), vec![replica(1, 1)], true)
+        .await
+        .unwrap();
+    let leader = result.leader.unwrap();
+    assert_eq!((leader.node_id, leader.replica_id), (1, 1));
+    assert_eq!(result.replica_id, ReplicaId(1));
+    assert_eq!(result.applied_index, 1);
+
+    // the retry reports the same.
+    let retry = cluster.multirafts[0]
+        .bootstrap_group(GroupId(1), vec![replica(1, 1)], true)
+        .await
+        .unwrap();
+    assert_eq!(retry.leader, result.leader);
+
+    // the group of many voters elects the leader later.
+    let replicas = (1..=3).map(|id| replica(id, id + 1)).collect::<Vec<_>>();
+    let result = cluster.multirafts[0]
+        .bootstrap_group(GroupId(2), replicas, false)
+        .await
+        .unwrap();
+    assert!(result.leader.is_none());
+    assert_eq!(result.replica_id, ReplicaId(2));
+
+    stop_tx.send(true).unwrap();
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_freeze_group_while_leader() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let group_id = 1;
+    let (cluster, leader_id) = FixtureCluster::make_acked_group(group_id, stop_rx).await;
+    let frozen_index = leader_id as usize - 1;
+    let frozen = &cluster.multirafts[frozen_index];
+    frozen.freeze_group(GroupId(group_id)).await.unwrap();
+
+    // the frozen leader transfers the leadership away.
+    let status = cluster
+        .tick_until_status(group_id, frozen_index, |status| {
+            status.leader_id.0 != 0 && status.leader_id.0 != leader_id
+        })
+        .await;
+    assert_eq!(status.role, StateRole::Follower);
+    assert_eq!(status.state, GroupState::Frozen);
+    let new_leader_id = status.leader_id.0;
+    assert_ne!(new_leader_id, leader_id);
+
+    // the proposals and reads are rejected.
+    let res = frozen
+        .propose_timeout(GroupId(group_id), vec![0], vec![], Duration::from_secs(1))
+        .await;
+    assert!(matches!(res, Err(Error::GroupFrozen(1))), "{:?}", res);
+    let res = frozen
+        .read_follower(GroupId(group_id), Duration::from_secs(60))
+        .await;
+    assert!(matches!(res, Err(Error::GroupFrozen(1))), "{:?}", res);
+
+    // the frozen replica stays caught up.
+    let new_leader = &cluster.multirafts[new_leader_id as usize - 1];
+    for i in 0..5u8 {
+        new_leader
+            .propose_timeout(GroupId(group_id), vec![i], vec![], Duration::from_secs(5))
+            .await
+            .unwrap();
+    }
+    let commit = new_leader
+        .group_status(GroupId(group_id))
+        .await
+        .unwrap()
+        .commit_index;
+    let status = cluster.tick_until_status(group_id, frozen_index, |status| {
+        status.applied_index >= commit
+    })
+    .await;
+    assert!(status.applied_index >= commit);
+
+    // the unfrozen replica serves and campaigns again.
+    frozen.unfreeze_group(GroupId(group_id)).await.unwrap();
+    frozen.campagin(GroupId(group_id)).await.unwrap();
+    let status = cluster.tick_until_status(group_id, frozen_index, |status| {
+        status.role == StateRole::Leader
+    })
+    .await;
+    assert_eq!(status.role, StateRole::Leader);
+    assert_ne!(status.state, GroupState::Frozen);
+    frozen
+        .propose_timeout(GroupId(group_id), vec![0], vec![], Duration::from_secs(5))
+        .await
+        .unwrap();
+
+    stop_tx.send(true).unwrap();
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_freeze_group_while_follower() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let group_id = 1;
+    let (cluster, leader_id) = FixtureCluster::make_acked_group(group_id, stop_rx).await;
+    let frozen_id = (1..=3).find(|id| *id != leader_id).unwrap();
+    let frozen_index = frozen_id as usize - 1;
+    let frozen = &cluster.multirafts[frozen_index];
+    frozen.freeze_group(GroupId(group_id)).await.unwrap();
+
+    // the frozen follower doesn't campaign.
+    frozen.campagin(GroupId(group_id)).await.unwrap();
+    for _ in 0..10 {
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let status = frozen.group_status(GroupId(group_id)).await.unwrap();
+    assert_eq!(status.role, StateRole::Follower);
+    assert_eq!(status.leader_id.0, leader_id);
+
+    let res = frozen
+        .propose_timeout(GroupId(group_id), vec![0], vec![], Duration::from_secs(1))
+        .await;
+    assert!(matches!(res, Err(Error::GroupFrozen(1))), "{:?}", res);
+
+    // the frozen follower applies the entries replicated by the leader.
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    for i in 0..5u8 {
+        leader
+            .propose_timeout(GroupId(group_id), vec![i], vec![], Duration::from_secs(5))
+            .await
+            .unwrap();
+    }
+    let commit = leader
+        .group_status(GroupId(group_id))
+        .await
+        .unwrap()
+        .commit_index;
+    let status = cluster
+        .tick_until_status(group_id, frozen_index, |status| {
+            status.applied_index >= commit
+        })
+        .await;
+    assert!(status.applied_index >= commit);
+
+    // the unfrozen follower campaigns again.
+    frozen.unfreeze_group(GroupId(group_id)).await.unwrap();
+    frozen.campagin(GroupId(group_id)).await.unwrap();
+    let status = cluster.tick_until_status(group_id, frozen_index, |status| {
+        status.role == StateRole::Leader
+    })
+    .await;
+    assert_eq!(status.role, StateRole::Leader);
+
+    stop_tx.send(true).unwrap();
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_topology() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let group_id = 1;
+    let (cluster, leader_id) = FixtureCluster::make_acked_group(group_id, stop_rx).await;
+    let frozen_id = (1..=3).find(|id| *id != leader_id).unwrap();
+    cluster.multirafts[frozen_id as usize - 1]
+        .freeze_group(GroupId(group_id))
+        .await
+        .unwrap();
+
+    for (index, multiraft) in cluster.multirafts.iter().enumerate() {
+        let node_id = index as u64 + 1;
+        let topology = multiraft.topology().await.unwrap();
+        assert_eq!(topology.node_id, NodeId(node_id));
+        assert_eq!(topology.groups.len(), 1);
+        assert!(topology.group(GroupId(group_id + 1)).is_none());
+
+        let group = topology.group(GroupId(group_id)).unwrap();
+        let mut voters = group.conf_state.voters.clone();
+        voters.sort();
+        assert_eq!(voters, vec![1, 2, 3]);
+        assert!(group.conf_state.learners.is_empty());
+        assert_eq!(
+            group
+                .replicas
+                .iter()
+                .map(|replica| replica.node_id)
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(group.leader.as_ref().map(|leader| leader.replica_id), Some(leader_id));
+        let state = if node_id == frozen_id {
+            GroupState::Frozen
+        } else {
+            GroupState::Running
+        };
+        assert_eq!(group.state, state);
+    }
+
+    stop_tx.send(true).unwrap();
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_correlation_id_on_every_replica() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    let (correlated_tx, mut correlated_rx) = tokio::sync::mpsc::unbounded_channel();
+    for (node_index, mut events) in std::mem::take(&mut cluster.events).into_iter().enumerate() {
+        let correlated_tx = correlated_tx.clone();
+        tokio::spawn(async move {
+            while let Some(events) = events.recv().await {
+                for event in events {
+                    if let Event::Apply(apply) = event {
+                        if apply.correlation_id != 0 {
+                            let _ = correlated_tx.send((node_index, apply.correlation_id));
+                        }
+                        if let Some(tx) = apply.tx {
+                            let _ = tx.send(Ok(()));
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    let request = |correlation_id| AppWriteRequest {
+        group_id,
+        term: 0,
+        data: b"data".to_vec(),
+        context: vec![],
+        correlation_id,
+        ..Default::default()
+    };
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    leader.write(request(0)).await.unwrap();
+    leader.write(request(42)).await.unwrap();
+
+    // the leader and the followers apply the entry with the same id, the
+    // uncorrelated entry has none.
+    let mut correlated = HashMap::new();
+    for _ in 0..100 {
+        while let Ok((node_index, correlation_id)) = correlated_rx.try_recv() {
+            assert!(correlated.insert(node_index, correlation_id).is_none());
+        }
+        if correlated.len() == 3 {
+            break;
+        }
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(correlated.len(), 3);
+    assert!(correlated.values().all(|id| *id == 42));
+    stop_tx.send(true).unwrap();
+}
+
+/// Takes a while to apply each entry.
+struct SlowStateMachine;
+
+impl StateMachine for SlowStateMachine {
+    fn apply(&self, _: &ApplyEvent, _: &mut ApplyHandle) -> Result<ApplyOutput, ApplyError> {
+        std::thread::sleep(Duration::from_millis(20));
+        Ok(ApplyOutput)
+    }
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_max_apply_backlog_throttles_proposals() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let max_apply_backlog = 4;
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        max_apply_backlog,
+        ..Default::default()
+    };
+    let extensions = vec![MultiRaftExtensions {
+        state_machine: Some(Arc::new(SlowStateMachine) as Arc<dyn StateMachine>),
+        ..Default::default()
+    }];
+    let mut cluster = FixtureCluster::make_with_extensions(1, config, extensions, stop_rx).await;
+    let mut events = cluster.events.remove(0);
+    tokio::spawn(async move { while events.recv().await.is_some() {} });
+
+    let group_id = 1;
+    cluster.make_group_with_campaign(group_id, 0, 1, true).await;
+    cluster.tick_all().await;
+    let multiraft = &cluster.multirafts[0];
+
+    // the backlog is sampled while the proposals are in flight.
+    let proposals = futures::future::join_all((0..32u8).map(|i| {
+        multiraft.propose_timeout(GroupId(group_id), vec![i], vec![], Duration::from_secs(10))
+    }));
+    tokio::pin!(proposals);
+    let mut max_backlog = 0;
+    let results = loop {
+        tokio::select! {
+            results = &mut proposals => break results,
+            _ = tokio::time::sleep(Duration::from_millis(5)) => {
+                let status = multiraft.group_status(GroupId(group_id)).await.unwrap();
+                max_backlog = max_backlog.max(status.apply_backlog);
+            }
+        }
+    };
+
+    // the proposals beyond the backlog are throttled rather than queued.
+    let throttled = results
+        .iter()
+        .filter(|res| {
+            **res == Err(Error::Proposal(ProposalError::ApplyBacklogFull(max_apply_backlog)))
+        })
+        .count();
+    assert!(throttled > 0);
+    assert_eq!(
+        results.iter().filter(|res| res.is_ok()).count() + throttled,
+        results.len()
+    );
+    // the proposals admitted before their entries are committed overshoot the
+    // limit, but the backlog doesn't grow with all of them.
+    assert!(max_backlog < results.len() as u64, "{}", max_backlog);
+
+    // the throttled proposal is accepted once the apply catches up.
+    multiraft
+        .propose_timeout(GroupId(group_id), vec![0], vec![], Duration::from_secs(10))
+        .await
+        .unwrap();
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_max_apply_backlog_ignores_uncommitted() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let max_apply_backlog = 2;
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        max_apply_backlog,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(3, config, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+
+    // the leader which loses the quorum doesn't commit the proposals, they
+    // time out rather than being throttled by the apply backlog.
+    for node_id in (1..=3).filter(|node_id| *node_id != leader_id) {
+        cluster.transport.isolate(node_id);
+    }
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    for i in 0..max_apply_backlog as u8 * 2 {
+        let res = leader
+            .propose_timeout(
+                GroupId(group_id),
+                vec![i],
+                vec![],
+                Duration::from_millis(50),
+            )
+            .await;
+        assert_eq!(res, Err(Error::Proposal(ProposalError::Timeout)));
+    }
+    let status = leader.group_status(GroupId(group_id)).await.unwrap();
+    assert_eq!(status.apply_backlog, 0);
+
+    let _ = stop_tx.send(true);
+}
+
+/// Holds the apply of each entry until the gate is opened.
+#[derive(Default)]
+struct GateStateMachine {
+    open: AtomicBool,
+}
+
+impl StateMachine for GateStateMachine {
+    fn apply(&self, _: &ApplyEvent, _: &mut ApplyHandle) -> Result<ApplyOutput, ApplyError> {
+        while !self.open.load(Ordering::SeqCst) {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        Ok(ApplyOutput)
+    }
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_sync_applied_on_follower_backlog() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        ..Default::default()
+    };
+    let gates = (0..3)
+        .map(|_| Arc::new(GateStateMachine::default()))
+        .collect::<Vec<_>>();
+    let extensions = gates
+        .iter()
+        .map(|gate| MultiRaftExtensions {
+            state_machine: Some(gate.clone() as Arc<dyn StateMachine>),
+            ..Default::default()
+        })
+        .collect();
+    let mut cluster = FixtureCluster::make_with_extensions(3, config, extensions, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    for mut events in std::mem::take(&mut cluster.events) {
+        tokio::spawn(async move { while events.recv().await.is_some() {} });
+    }
+
+    // the apply of the follower is held, so it builds up a backlog.
+    let follower_index = leader_id as usize % 3;
+    for (node_index, gate) in gates.iter().enumerate() {
+        let open = node_index != follower_index;
+        gate.open.store(open, Ordering::SeqCst);
+    }
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let mut last_index = 0;
+    for i in 0..5u8 {
+        let token = leader
+            .propose_timeout(GroupId(group_id), vec![i], vec![], Duration::from_secs(5))
+            .await
+            .unwrap();
+        last_index = token.index();
+    }
+    let follower = &cluster.multirafts[follower_index];
+    for _ in 0..20 {
+        let status = follower.group_status(GroupId(group_id)).await.unwrap();
+        if status.commit_index >= last_index {
+            break;
+        }
+        cluster.tick_all().await;
+    }
+    let status = follower.group_status(GroupId(group_id)).await.unwrap();
+    assert!(status.commit_index >= last_index);
+    assert!(status.applied_index < last_index);
+
+    // the barrier returns once the backlog committed at the call is applied.
+    let sync = follower.sync_applied(GroupId(1));
+    tokio::pin!(sync);
+    tokio::select! {
+        _ = &mut sync => panic!("the backlog isn't applied yet"),
+        _ = tokio::time::sleep(Duration::from_millis(100)) => {}
+    }
+    gates[follower_index].open.store(true, Ordering::SeqCst);
+    let index = tokio::time::timeout(Duration::from_secs(5), sync)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(index >= last_index);
+    let status = follower.group_status(GroupId(group_id)).await.unwrap();
+    assert!(status.applied_index >= index);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_max_log_memory_compacts_largest_logs() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let max_log_memory = 16 * 1024;
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        max_log_memory,
+        ..Default::default()
+    };
+    // the snapshot carries the state of the state machine.
+    let (_, extensions) = log_state_machines(1);
+    let mut cluster = FixtureCluster::make_with_extensions(1, config, extensions, stop_rx).await;
+    spawn_ack_applies(cluster.events.remove(0));
+
+    let group_ids = [1, 2, 3, 4];
+    for group_id in group_ids {
+        cluster.make_group_with_campaign(group_id, 0, 1, true).await;
+    }
+    cluster.tick_all().await;
+    let multiraft = &cluster.multirafts[0];
+    let timeout = Duration::from_secs(10);
+
+    // the logs within the budget are kept.
+    for group_id in group_ids {
+        multiraft
+            .propose_timeout(GroupId(group_id), vec![0; 16], vec![], timeout)
+            .await
+            .unwrap();
+    }
+    let log_bytes = multiraft.health().await.log_bytes;
+    assert!(log_bytes >= 16 * group_ids.len() as u64, "{}", log_bytes);
+    cluster.tick_all().await;
+    for group_id in group_ids {
+        let storage = cluster.storages[0].memory_storage(group_id).await.unwrap();
+        assert_eq!(storage.first_index().unwrap(), 1);
+    }
+
+    // the group 1 writes the most, its log is compacted first.
+    for (group_id, count) in [(1u64, 16), (2, 4), (3, 2), (4, 1)] {
+        for _ in 0..count {
+            multiraft
+                .propose_timeout(GroupId(group_id), vec![0; 1024], vec![], timeout)
+                .await
+                .unwrap();
+        }
+    }
+    let mut log_bytes = multiraft.health().await.log_bytes;
+    for _ in 0..100 {
+        if log_bytes <= max_log_memory {
+            break;
+        }
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        log_bytes = multiraft.health().await.log_bytes;
+    }
+    assert!(log_bytes <= max_log_memory, "{}", log_bytes);
+    let storage = cluster.storages[0].memory_storage(1).await.unwrap();
+    assert!(storage.first_index().unwrap() > 1);
+    let storage = cluster.storages[0].memory_storage(4).await.unwrap();
+    assert_eq!(storage.first_index().unwrap(), 1);
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_max_log_entries_pinned_by_lagging_follower() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let max_log_entries = 10;
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        max_log_entries,
+        ..Default::default()
+    };
+    // the snapshot carries the state of the state machine.
+    let (_, extensions) = log_state_machines(3);
+    let mut cluster = FixtureCluster::make_with_extensions(3, config, extensions, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    let (pinned_tx, mut pinned_rx) = tokio::sync::mpsc::unbounded_channel();
+    for mut events in std::mem::take(&mut cluster.events) {
+        let pinned_tx = pinned_tx.clone();
+        tokio::spawn(async move {
+            while let Some(events) = events.recv().await {
+                for event in events {
+                    match event {
+                        Event::Apply(apply) => {
+                            if let Some(tx) = apply.tx {
+                                let _ = tx.send(Ok(()));
+                            }
+                        }
+                        Event::LogPinned(pinned) => {
+                            let _ = pinned_tx.send(pinned);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        });
+    }
+
+    // the followers keeping up don't pin the log, it's compacted on the tick.
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let leader_storage = cluster.storages[leader_id as usize - 1]
+        .memory_storage(group_id)
+        .await
+        .unwrap();
+    let timeout = Duration::from_secs(10);
+    for _ in 0..2 * max_log_entries {
+        leader
+            .propose_timeout(GroupId(group_id), vec![0; 16], vec![], timeout)
+            .await
+            .unwrap();
+        cluster.tick_all().await;
+    }
+    let mut first_index = leader_storage.first_index().unwrap();
+    for _ in 0..100 {
+        if first_index > 1 {
+            break;
+        }
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        first_index = leader_storage.first_index().unwrap();
+    }
+    assert!(first_index > 1);
+    assert!(pinned_rx.try_recv().is_err());
+
+    // the follower never catches up, so the leader compacts the log up to
+    // the index it matched and rejects the proposals once the log reaches
+    // the limit.
+    let lagging_id = (1..=3).find(|id| *id != leader_id).unwrap();
+    cluster.transport.isolate(lagging_id);
+    let mut rejected = None;
+    for _ in 0..10 * max_log_entries {
+        let res = leader
+            .propose_timeout(GroupId(group_id), vec![0; 16], vec![], timeout)
+            .await;
+        if let Err(err) = res {
+            rejected = Some(err);
+            break;
+        }
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let log_full = Error::Proposal(ProposalError::LogFull(max_log_entries));
+    assert_eq!(rejected.unwrap(), log_full);
+    let pinned = tokio::time::timeout(timeout, pinned_rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(pinned.group_id, group_id);
+    assert_eq!(pinned.replica_id, lagging_id);
+    assert!(pinned.log_entries >= max_log_entries);
+    assert_eq!(leader_storage.first_index().unwrap(), pinned.matched + 1);
+
+    // the tick doesn't compact the pinned log, and the event isn't repeated.
+    cluster.tick_all().await;
+    let res = leader
+        .propose_timeout(GroupId(group_id), vec![0; 16], vec![], timeout)
+        .await;
+    assert_eq!(res.unwrap_err(), log_full);
+    assert!(pinned_rx.try_recv().is_err());
+    assert_eq!(leader_storage.first_index().unwrap(), pinned.matched + 1);
+
+    cluster.transport.reconnect(lagging_id);
+    let _ = stop_tx.send(true);
+}
+
+/// Make the 3-replica group on a 3-node cluster which quiesces after 3 idle
+/// ticks, returns the cluster and the leader id once the group is quiesced on
+/// all nodes. The apply events are acked.
+#[cfg(feature = "test-util")]
+async fn make_quiesced_group(
+    group_id: u64,
+    stop_rx: watch::Receiver<bool>,
+) -> (FixtureCluster, u64) {
+    let config = MultiRaftConfig {
+        election_tick: 2,
+        heartbeat_tick: 1,
+        manual_tick: true,
+        enable_quiesce: true,
+        quiesce_ticks: 3,
+        ..Default::default()
+    };
+    let mut cluster = FixtureCluster::make_with_config(3, config, stop_rx).await;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+    for _ in 0..100 {
+        let mut quiesced = 0;
+        for multiraft in cluster.multirafts.iter() {
+            quiesced += multiraft.quiesced_group_count().await;
+        }
+        if quiesced == 3 {
+            return (cluster, leader_id);
+        }
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("group {} isn't quiesced on all nodes", group_id);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_quiesce_idle_group() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let group_id = 1;
+    let (cluster, leader_id) = make_quiesced_group(group_id, stop_rx).await;
+
+    // the group stays quiesced while the nodes keep in contact.
+    for _ in 0..10 {
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    for multiraft in cluster.multirafts.iter() {
+        assert_eq!(multiraft.quiesced_group_count().await, 1);
+    }
+
+    // the proposal wakes the group.
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    leader
+        .propose_timeout(GroupId(group_id), vec![1], vec![], Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert_eq!(leader.quiesced_group_count().await, 0);
+    stop_tx.send(true).unwrap();
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_quiesced_followers_elect_after_leader_down() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let group_id = 1;
+    let (cluster, leader_id) = make_quiesced_group(group_id, stop_rx).await;
+
+    // the quiesced followers wake once they lose contact with the node of the
+    // leader, and elect a new leader.
+    cluster.transport.isolate(leader_id);
+    let follower_index = (0..3)
+        .find(|index| *index + 1 != leader_id as usize)
+        .unwrap();
+    let status = cluster
+        .tick_until_status(group_id, follower_index, |status| {
+            status.leader_id.0 != 0 && status.leader_id.0 != leader_id
+        })
+        .await;
+    assert_ne!(status.leader_id.0, 0);
+    assert_ne!(status.leader_id.0, leader_id);
+    stop_tx.send(true).unwrap();
+}
+
+/// Returns the ConfChangeV2 which replaces the replica `removed` by the
+/// replica `added` on the node of the same id.
+fn replace_change(group_id: u64, added: u64, removed: u64) -> MembershipChangeData {
+    let mut add = MembershipChangeRequest {
+        group_id,
+        node_id: added,
+        replica_id: added,
+        ..Default::default()
+    };
+    add.set_change_type(ConfChangeType::AddNode);
+    let mut remove = MembershipChangeRequest {
+        group_id,
+        node_id: removed,
+        replica_id: removed,
+        ..Default::default()
+    };
+    remove.set_change_type(ConfChangeType::RemoveNode);
+    let mut data = MembershipChangeData {
+        group_id,
+        changes: vec![add, remove],
+        ..Default::default()
+    };
+    data.set_transition(ConfChangeTransition::Explicit);
+    data
+}
+
+/// Returns the explicit leave of the joint consensus.
+fn leave_joint_change(group_id: u64) -> MembershipChangeData {
+    let mut data = MembershipChangeData {
+        group_id,
+        ..Default::default()
+    };
+    data.set_transition(ConfChangeTransition::Explicit);
+    data
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_joint_conf_change_replace_replica() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(4, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+
+    // the replica 4 replaces a follower in one step of the joint consensus.
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let removed = (1..=3).find(|id| *id != leader_id).unwrap();
+    leader
+        .propose_conf_change(replace_change(group_id, 4, removed))
+        .await
+        .unwrap();
+    let cs = leader.conf_state(GroupId(group_id)).await.unwrap();
+    let mut outgoing = cs.voters_outgoing.clone();
+    outgoing.sort();
+    assert_eq!(outgoing, vec![1, 2, 3]);
+    assert!(cs.voters.contains(&4) && !cs.voters.contains(&removed));
+    assert!(leader
+        .groups_on_node(NodeId(4))
+        .await
+        .contains(&GroupId(group_id)));
+
+    // the explicit joint consensus is left by the empty change.
+    leader
+        .propose_conf_change(leave_joint_change(group_id))
+        .await
+        .unwrap();
+    let cs = leader.conf_state(GroupId(group_id)).await.unwrap();
+    assert!(cs.voters_outgoing.is_empty());
+    let mut voters = cs.voters;
+    voters.sort();
+    let expected = (1..=4).filter(|id| *id != removed).collect::<Vec<u64>>();
+    assert_eq!(voters, expected);
+    assert!(!leader
+        .groups_on_node(NodeId(removed))
+        .await
+        .contains(&GroupId(group_id)));
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_interrupted_joint_conf_change_left_by_new_leader() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(4, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+
+    // the leader enters the joint consensus which replaces itself, then it's
+    // killed before it leaves.
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    leader
+        .propose_conf_change(replace_change(group_id, 4, leader_id))
+        .await
+        .unwrap();
+    cluster.transport.isolate(leader_id);
+
+    // the new leader elected in the joint consensus leaves it on request.
+    let follower_index = (0..3)
+        .find(|index| *index + 1 != leader_id as usize)
+        .unwrap();
+    let status = cluster
+        .tick_until_status(group_id, follower_index, |status| {
+            status.leader_id.0 != 0 && status.leader_id.0 != leader_id
+        })
+        .await;
+    // the joint consensus may be committed by the new leader.
+    let new_leader = &cluster.multirafts[status.leader_id.0 as usize - 1];
+    let mut joint = false;
+    for _ in 0..100 {
+        let cs = new_leader.conf_state(GroupId(group_id)).await.unwrap();
+        if cs.voters_outgoing.contains(&leader_id) {
+            joint = true;
+            break;
+        }
+        cluster.tick_all().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(joint);
+    new_leader
+        .propose_conf_change(leave_joint_change(group_id))
+        .await
+        .unwrap();
+    let cs = new_leader.conf_state(GroupId(group_id)).await.unwrap();
+    assert!(cs.voters_outgoing.is_empty());
+    assert!(cs.voters.contains(&4) && !cs.voters.contains(&leader_id));
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_replace_replica() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(5, stop_rx).await;
+    let group_id = 1;
+    cluster.make_group(group_id, 0, 3).await;
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    cluster.ack_applies();
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let mut followers = (1..=3).filter(|id| *id != leader_id);
+    let ticking = async {
+        loop {
+            cluster.tick_all().await;
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+
+    // the replica 4 catches up as learner and replaces the follower.
+    let replaced = followers.next().unwrap();
+    let new_replica = ReplicaDesc {
+        node_id: 4,
+        replica_id: 4,
+    };
+    let replace = leader.replace_replica(
+        GroupId(group_id),
+        ReplicaId(replaced),
+        new_replica,
+        true,
+        Duration::from_secs(10),
+    );
+    tokio::select! {
+        res = replace => res.unwrap(),
+        _ = ticking => unreachable!(),
+    }
+    let cs = leader.conf_state(GroupId(group_id)).await.unwrap();
+    assert!(cs.voters_outgoing.is_empty());
+    let mut voters = cs.voters;
+    voters.sort();
+    let mut expected = vec![leader_id, 4, followers.next().unwrap()];
+    expected.sort();
+    assert_eq!(voters, expected);
+    assert!(!leader
+        .groups_on_node(NodeId(replaced))
+        .await
+        .contains(&GroupId(group_id)));
+
+    // the isolated replica 5 never catches up, the replacement times out
+    // before the joint consensus is entered.
+    cluster.transport.isolate(5);
+    let new_replica = ReplicaDesc {
+        node_id: 5,
+        replica_id: 5,
+    };
+    let res = leader
+        .replace_replica(
+            GroupId(group_id),
+            ReplicaId(4),
+            new_replica,
+            true,
+            Duration::from_millis(500),
+        )
+        .await;
+    assert!(
+        matches!(res, Err(Error::TargetLagging(1, 5, _, _))),
+        "{:?}",
+        res
+    );
+    let cs = leader.conf_state(GroupId(group_id)).await.unwrap();
+    assert!(cs.voters_outgoing.is_empty() && cs.voters.contains(&4));
+    assert!(cs.learners.contains(&5));
+    let _ = stop_tx.send(true);
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_witness_votes_without_storing_data() {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut cluster = FixtureCluster::make_with_manual_tick(3, stop_rx).await;
+    let group_id = 1;
+    let replicas = (1..=3)
+        .map(|id| ReplicaDesc {
+            node_id: id,
+            replica_id: id,
+        })
+        .collect::<Vec<_>>();
+    // the replica 3 is the witness.
+    for replica in replicas.iter() {
+        let mut snapshot = Snapshot::default();
+        let metadata = snapshot.mut_metadata();
+        metadata.index = 1;
+        metadata.term = 1;
+        metadata.mut_conf_state().voters = vec![1, 2, 3];
+
+        let mut msg = RaftGroupManagementMessage::default();
+        msg.set_msg_type(RaftGroupManagementMessageType::MsgInitialGroup);
+        msg.group_id = group_id;
+        msg.replica_id = replica.replica_id;
+        msg.replicas = replicas.clone();
+        msg.witnesses = vec![3];
+        msg.snapshot = Some(snapshot);
+        msg.campaign = replica.replica_id == 1;
+        cluster.multirafts[replica.node_id as usize - 1]
+            .initial_raft_group(msg)
+            .await
+            .unwrap();
+    }
+    let leader_id = cluster
+        .tick_until_leader(group_id, &[0, 1, 2])
+        .await
+        .unwrap();
+    assert_ne!(leader_id, 3);
+    cluster.ack_applies();
+
+    // the witness completes the quorum of the leader while the other full
+    // replica is isolated.
+    cluster.transport.isolate(3 - leader_id);
+    let leader = &cluster.multirafts[leader_id as usize - 1];
+    let mut index = 0;
+    for data in [b"a", b"b"] {
+        let token = leader
+            .propose_timeout(
+                GroupId(group_id),
+                data.to_vec(),
+                vec![],
+                Duration::from_secs(5),
+            )
+            .await
+            .unwrap();
+        index = token.index();
+    }
+    let status = cluster
+        .tick_until_status(group_id, 2, |status| status.commit_index >= index)
+        .await;
+    assert_eq!(status.role, StateRole::Follower);
+
+    // the witness stores the terms and indexes of the entries only.
+    let gs = cluster.storages[2]
+        .group_storage(group_id, 3)
+        .await
+        .unwrap();
+    let entries = gs.entries(2, index + 1, u64::MAX).unwrap();
+    assert_eq!(entries.last().unwrap().index, index);
+    assert!(entries.iter().all(|entry| entry.data.is_empty()));
+    let gs = cluster.storages[leader_id as usize - 1]
+        .group_storage(group_id, leader_id)
+        .await
+        .unwrap();
+    let entries = gs.entries(2, index + 1, u64::MAX).unwrap();
+    let data_entries = entries.iter().filter(|entry| !entry.data.is_empty());
+    assert_eq!(data_entries.count(), 2);
+    let _ = stop_tx.send(true);
+}